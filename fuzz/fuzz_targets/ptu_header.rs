@@ -0,0 +1,15 @@
+//! PTU files come from other software and other machines, so a
+//! corrupt or truncated header must produce an error rather than a
+//! panic or an unbounded allocation.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use multi_harp_patina::ptu::Header;
+
+fuzz_target!(|data: &[u8]| {
+    let mut reader = data;
+    if let Ok(header) = Header::read(&mut reader) {
+        let _ = header.mode();
+    }
+});
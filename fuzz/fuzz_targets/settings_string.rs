@@ -0,0 +1,18 @@
+//! Settings files in the `SettingsCache` directory can be edited
+//! by hand, so parsing arbitrary text must never panic, and
+//! anything that parses must survive a round trip.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use multi_harp_patina::MultiHarpConfig;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(s) = std::str::from_utf8(data) else { return };
+    if let Ok(config) = MultiHarpConfig::from_settings_string(s) {
+        let written = config.to_settings_string();
+        let reparsed = MultiHarpConfig::from_settings_string(&written)
+            .expect("settings written by `to_settings_string` must parse");
+        assert_eq!(reparsed.to_settings_string(), written);
+    }
+});
@@ -0,0 +1,13 @@
+//! Spools are read back from disk after crashes, so a corrupt or
+//! truncated file must produce an error rather than a panic or an
+//! unbounded allocation.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use multi_harp_patina::SpoolReader;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(mut reader) = SpoolReader::new(data) else { return };
+    while let Ok(Some(_)) = reader.next_block() {}
+});
@@ -0,0 +1,245 @@
+//! The acquisition loop most applications need, ready made. A `Session`
+//! owns the device on a reading thread of its own (a `DeviceActor`), so
+//! the application only starts and stops measurements and receives the
+//! records of each FIFO read, without handling the thread, the read
+//! buffer or the channel itself.
+
+use std::fmt::{Debug, Display};
+use std::time::Duration;
+
+use crate::actor::{DeviceActor, DeviceHandle, NoReply};
+use crate::budget::MemoryBudget;
+use crate::error::{MultiHarpError, PatinaError};
+use crate::transaction::TransactionError;
+use crate::{MultiHarpConfig, MultiHarpDevice};
+
+/// Why a `Session` call failed
+#[derive(Debug, Clone, PartialEq)]
+pub enum SessionError {
+    /// The device reported an error (described in the `String`)
+    Device(String),
+    /// A measurement was started before the records of the last one
+    /// were all received
+    Running,
+    /// The reading thread is gone, after a panic
+    Closed,
+}
+
+impl Display for SessionError {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SessionError::Device(e) => write!(f, "Device error: {}", e),
+            SessionError::Running => write!(f, "The last measurement's records haven't all been received"),
+            SessionError::Closed => write!(f, "The session's reading thread is gone"),
+        }
+    }
+}
+
+impl std::error::Error for SessionError {}
+
+impl<T> From<PatinaError<T>> for SessionError where T : Display + Debug {
+    fn from(e : PatinaError<T>) -> Self {
+        SessionError::Device(e.to_string())
+    }
+}
+
+impl From<MultiHarpError> for SessionError {
+    fn from(e : MultiHarpError) -> Self {
+        SessionError::Device(e.to_string())
+    }
+}
+
+impl From<TransactionError> for SessionError {
+    fn from(e : TransactionError) -> Self {
+        SessionError::Device(e.to_string())
+    }
+}
+
+impl From<NoReply> for SessionError {
+    fn from(_ : NoReply) -> Self {
+        SessionError::Closed
+    }
+}
+
+/// Owns a device and reads its FIFO on a thread of its own while
+/// measurements run. The records of each read are received in order
+/// with `recv`, which returns `None` once the measurement has ended and
+/// every record has been received.
+///
+/// Dropping the session stops any measurement and closes the device.
+///
+/// ## Example
+///
+/// ```
+/// use multi_harp_patina::*;
+/// use multi_harp_patina::acquisition::Session;
+///
+/// let mut mh = DebugMultiHarp150::default();
+/// mh.init(MeasurementMode::T3, ReferenceClock::Internal).unwrap();
+/// let mut session = Session::new(mh);
+///
+/// session.start(100).unwrap();
+/// let mut records = 0;
+/// while let Some(batch) = session.recv() {
+///     records += batch.unwrap().len();
+/// }
+/// assert!(records > 0);
+/// assert_eq!(session.records_received(), records as u64);
+/// let mh = session.close().unwrap();
+/// ```
+pub struct Session<MH> {
+    actor : DeviceActor<MH>,
+    running : bool,
+    records : u64,
+}
+
+impl<MH : MultiHarpDevice + Send + 'static> Session<MH> {
+    /// Moves the initialized `multiharp` onto a reading thread.
+    pub fn new(multiharp : MH) -> Self {
+        Session { actor : DeviceActor::spawn(multiharp), running : false, records : 0 }
+    }
+
+    /// `new`, holding the records not yet received against `budget`
+    /// (see `DeviceActor::spawn_with_budget`).
+    pub fn with_memory_budget(multiharp : MH, budget : &MemoryBudget) -> Self {
+        Session { actor : DeviceActor::spawn_with_budget(multiharp, budget), running : false, records : 0 }
+    }
+
+    /// Applies the settings in `config` as one transaction.
+    pub fn configure(&self, config : MultiHarpConfig) -> Result<(), SessionError> {
+        Ok(self.actor.handle().configure(config).wait()??)
+    }
+
+    /// Calls `query` with the device on the reading thread, between reads.
+    pub fn query<T, F>(&self, query : F) -> Result<T, SessionError>
+    where T : Send + 'static, F : FnOnce(&mut MH) -> T + Send + 'static {
+        Ok(self.actor.handle().query(query).wait()?)
+    }
+
+    /// A handle for sending commands to the device from other threads
+    pub fn handle(&self) -> DeviceHandle<MH> {
+        self.actor.handle()
+    }
+
+    /// Starts a measurement of `acquisition_time` ms.
+    ///
+    /// ## Errors
+    ///
+    /// * `SessionError::Running` - If `recv` hasn't yet returned `None`
+    ///   for the last measurement.
+    /// * `SessionError::Device` - If the device fails to start.
+    pub fn start(&mut self, acquisition_time : i32) -> Result<(), SessionError> {
+        if self.running {
+            return Err(SessionError::Running);
+        }
+        self.actor.handle().start(acquisition_time).wait()??;
+        self.running = true;
+        Ok(())
+    }
+
+    /// Stops the measurement. The records read before it stopped are
+    /// still received by `recv`.
+    pub fn stop(&self) -> Result<(), SessionError> {
+        Ok(self.actor.handle().stop().wait()??)
+    }
+
+    /// Whether a measurement was started whose records haven't all been
+    /// received
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// Records received since the session began
+    pub fn records_received(&self) -> u64 {
+        self.records
+    }
+
+    /// Blocks until the next FIFO read with records. `None` once the
+    /// measurement has ended and its records have all been received, or
+    /// if none was started.
+    pub fn recv(&mut self) -> Option<Result<Vec<u32>, SessionError>> {
+        if !self.running {
+            return None;
+        }
+        let batch = self.actor.records().recv().map_err(|_| SessionError::Closed);
+        self.received(batch)
+    }
+
+    /// Like `recv`, but gives up after `timeout`, returning
+    /// `Some(Ok(Vec::new()))` while the measurement runs.
+    pub fn recv_timeout(&mut self, timeout : Duration) -> Option<Result<Vec<u32>, SessionError>> {
+        if !self.running {
+            return None;
+        }
+        let batch = match self.actor.records().recv_timeout(timeout) {
+            Err(flume::RecvTimeoutError::Timeout) => return Some(Ok(Vec::new())),
+            received => received.map_err(|_| SessionError::Closed),
+        };
+        self.received(batch)
+    }
+
+    fn received(&mut self, batch : Result<Result<Vec<u32>, PatinaError<u32>>, SessionError>) -> Option<Result<Vec<u32>, SessionError>> {
+        match batch {
+            Ok(Ok(records)) if records.is_empty() => {
+                self.running = false;
+                None
+            },
+            Ok(Ok(records)) => {
+                self.records += records.len() as u64;
+                Some(Ok(records))
+            },
+            Ok(Err(e)) => {
+                self.running = false;
+                Some(Err(e.into()))
+            },
+            Err(e) => {
+                self.running = false;
+                Some(Err(e))
+            },
+        }
+    }
+
+    /// Stops any measurement and returns the device. `None` if the
+    /// reading thread panicked.
+    pub fn close(self) -> Option<MH> {
+        self.actor.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DebugMultiHarp150, MeasurementMode, ReferenceClock};
+
+    #[test]
+    fn test_session() {
+        let mut mh = DebugMultiHarp150::default();
+        mh.init(MeasurementMode::T3, ReferenceClock::Internal).unwrap();
+        let mut session = Session::new(mh);
+        assert!(session.recv().is_none());
+
+        session.configure(MultiHarpConfig { sync_div : Some(2), ..Default::default() }).unwrap();
+        assert!(session.configure(MultiHarpConfig { binning : Some(1000), ..Default::default() }).is_err());
+        assert_eq!(session.query(|mh| mh.current_settings().sync_div).unwrap(), Some(2));
+
+        // Stopped early, the records read so far still arrive
+        session.start(10_000).unwrap();
+        assert_eq!(session.start(100), Err(SessionError::Running));
+        std::thread::sleep(Duration::from_millis(50));
+        session.stop().unwrap();
+        let mut batches = 0;
+        while let Some(batch) = session.recv_timeout(Duration::from_secs(1)) {
+            assert!(!batch.unwrap().is_empty());
+            batches += 1;
+        }
+        assert!(batches > 0);
+        assert!(!session.is_running());
+
+        // Started again, ending by itself
+        session.start(50).unwrap();
+        while session.recv().is_some() {}
+        assert!(session.records_received() > 0);
+        let mh = session.close().unwrap();
+        assert_eq!(mh.get_measurement_mode(), MeasurementMode::T3);
+    }
+}
@@ -0,0 +1,439 @@
+//! Thread-confined ownership of a device. A `DeviceActor` moves the
+//! device onto a thread of its own, which reads the FIFO while a
+//! measurement runs and, between reads, carries out the commands sent
+//! through any number of `DeviceHandle`s. No lock is held around the
+//! device, so a slow command from one thread never stalls the reads,
+//! and the reads never starve the commands.
+//!
+//! Every command returns a `Reply`, which can be waited on from a
+//! thread or awaited from async code.
+
+use std::collections::VecDeque;
+use std::future::{Future, IntoFuture};
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::budget::{BudgetAccount, MemoryBudget};
+use crate::error::{CheckedResult, MultiHarpResult};
+use crate::events::{self, DeviceEventKind};
+use crate::mhconsts;
+use crate::transaction::TransactionError;
+use crate::{MultiHarpConfig, MultiHarpDevice};
+
+/// How long the actor waits for a command after an empty FIFO read
+/// before reading again
+const IDLE_READ_INTERVAL : Duration = Duration::from_millis(1);
+
+/// A command never got a reply: the actor has shut down, or the
+/// command panicked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NoReply;
+
+impl std::fmt::Display for NoReply {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "The device actor didn't reply")
+    }
+}
+
+impl std::error::Error for NoReply {}
+
+/// The reply to a command sent to a `DeviceActor`.
+#[derive(Debug)]
+pub struct Reply<T> {
+    receiver : flume::Receiver<T>,
+}
+
+impl<T> Reply<T> {
+    /// Blocks until the actor has carried out the command.
+    pub fn wait(self) -> Result<T, NoReply> {
+        self.receiver.recv().map_err(|_| NoReply)
+    }
+
+    /// Like `wait`, but gives up after `timeout`, returning the `Reply`
+    /// to wait on again.
+    pub fn wait_timeout(self, timeout : Duration) -> Result<Result<T, NoReply>, Self> {
+        match self.receiver.recv_timeout(timeout) {
+            Ok(value) => Ok(Ok(value)),
+            Err(flume::RecvTimeoutError::Disconnected) => Ok(Err(NoReply)),
+            Err(flume::RecvTimeoutError::Timeout) => Err(self),
+        }
+    }
+}
+
+impl<T : 'static> IntoFuture for Reply<T> {
+    type Output = Result<T, NoReply>;
+    type IntoFuture = ReplyFuture<T>;
+
+    fn into_future(self) -> Self::IntoFuture {
+        ReplyFuture(self.receiver.into_recv_async())
+    }
+}
+
+/// Awaits a `Reply`.
+pub struct ReplyFuture<T : 'static>(flume::r#async::RecvFut<'static, T>);
+
+impl<T : 'static> Future for ReplyFuture<T> {
+    type Output = Result<T, NoReply>;
+
+    fn poll(mut self : Pin<&mut Self>, cx : &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.0).poll(cx).map(|r| r.map_err(|_| NoReply))
+    }
+}
+
+enum Command<MH> {
+    Configure(MultiHarpConfig, flume::Sender<Result<(), TransactionError>>),
+    Start(i32, flume::Sender<CheckedResult<(), i32>>),
+    Stop(flume::Sender<MultiHarpResult<()>>),
+    Query(Box<dyn FnOnce(&mut MH) + Send>),
+    Shutdown,
+}
+
+/// Sends commands to a `DeviceActor` from any thread. Cheap to clone.
+pub struct DeviceHandle<MH> {
+    sender : flume::Sender<Command<MH>>,
+}
+
+impl<MH> Clone for DeviceHandle<MH> {
+    fn clone(&self) -> Self {
+        DeviceHandle { sender : self.sender.clone() }
+    }
+}
+
+impl<MH : MultiHarpDevice> DeviceHandle<MH> {
+    fn send<T>(&self, command : impl FnOnce(flume::Sender<T>) -> Command<MH>) -> Reply<T> {
+        let (reply, receiver) = flume::bounded(1);
+        // If the actor is gone, the reply is dropped and `wait` says so
+        let _ = self.sender.send(command(reply));
+        Reply { receiver }
+    }
+
+    /// Applies the settings in `config` as one transaction (see
+    /// `MultiHarpDevice::with_settings_transaction`).
+    pub fn configure(&self, config : MultiHarpConfig) -> Reply<Result<(), TransactionError>> {
+        self.send(|reply| Command::Configure(config, reply))
+    }
+
+    /// Starts a measurement, whose records the actor sends to
+    /// `DeviceActor::records` until it ends or `stop` is called.
+    pub fn start(&self, acquisition_time : i32) -> Reply<CheckedResult<(), i32>> {
+        self.send(|reply| Command::Start(acquisition_time, reply))
+    }
+
+    /// Stops the measurement.
+    pub fn stop(&self) -> Reply<MultiHarpResult<()>> {
+        self.send(Command::Stop)
+    }
+
+    /// Calls `query` with the device on the actor's thread, e.g. to read
+    /// the count rates or change a single setting.
+    pub fn query<T, F>(&self, query : F) -> Reply<T>
+    where T : Send + 'static, F : FnOnce(&mut MH) -> T + Send + 'static {
+        self.send(|reply| Command::Query(Box::new(move |multiharp : &mut MH| {
+            let _ = reply.send(query(multiharp));
+        })))
+    }
+}
+
+/// Owns a device on a thread of its own, reading the FIFO during
+/// measurements and carrying out commands from `DeviceHandle`s in
+/// between reads.
+///
+/// Dropping the actor stops any measurement and closes the device.
+///
+/// ## Example
+///
+/// ```
+/// use multi_harp_patina::*;
+///
+/// let mut mh = DebugMultiHarp150::default();
+/// mh.init(MeasurementMode::T3, ReferenceClock::Internal).unwrap();
+/// let actor = DeviceActor::spawn(mh);
+/// let handle = actor.handle();
+///
+/// let config = MultiHarpConfig { sync_div : Some(2), ..Default::default() };
+/// handle.configure(config).wait().unwrap().unwrap();
+/// handle.start(100).wait().unwrap().unwrap();
+/// let records = actor.records().iter()
+///     .map(|batch| batch.unwrap())
+///     .take_while(|batch| !batch.is_empty())
+///     .map(|batch| batch.len())
+///     .sum::<usize>();
+/// assert!(records > 0);
+///
+/// let sync_div = handle.query(|mh| mh.current_settings().sync_div).wait().unwrap();
+/// assert_eq!(sync_div, Some(2));
+/// let mh = actor.shutdown().unwrap();
+/// ```
+pub struct DeviceActor<MH> {
+    handle : DeviceHandle<MH>,
+    records : flume::Receiver<CheckedResult<Vec<u32>, u32>>,
+    thread : Option<JoinHandle<MH>>,
+}
+
+impl<MH : MultiHarpDevice + Send + 'static> DeviceActor<MH> {
+    /// Moves `multiharp` onto a new thread.
+    pub fn spawn(multiharp : MH) -> Self {
+        Self::spawn_inner(multiharp, None)
+    }
+
+    /// `spawn`, holding the records waiting in `records` against
+    /// `budget`. A batch read while the budget is spent is dropped, with
+    /// a warning in the device's event log. Batches are returned to the
+    /// budget once the actor sees they have been received.
+    pub fn spawn_with_budget(multiharp : MH, budget : &MemoryBudget) -> Self {
+        let in_flight = InFlight { account : budget.account("device_actor"), batches : VecDeque::new() };
+        Self::spawn_inner(multiharp, Some(in_flight))
+    }
+
+    fn spawn_inner(multiharp : MH, in_flight : Option<InFlight>) -> Self {
+        let (sender, commands) = flume::unbounded();
+        let (records_out, records) = flume::unbounded();
+        let thread = std::thread::spawn(move || run(multiharp, commands, records_out, in_flight));
+        DeviceActor { handle : DeviceHandle { sender }, records, thread : Some(thread) }
+    }
+
+    /// A handle for sending commands to the actor
+    pub fn handle(&self) -> DeviceHandle<MH> {
+        self.handle.clone()
+    }
+
+    /// The records of each FIFO read, in order. Each measurement ends
+    /// with an empty batch, or with the error of a read that failed. The
+    /// channel is closed when the actor shuts down.
+    pub fn records(&self) -> flume::Receiver<CheckedResult<Vec<u32>, u32>> {
+        self.records.clone()
+    }
+
+    /// Stops any measurement and returns the device. `None` if the
+    /// actor's thread panicked.
+    pub fn shutdown(mut self) -> Option<MH> {
+        self.join()
+    }
+}
+
+impl<MH> DeviceActor<MH> {
+    fn join(&mut self) -> Option<MH> {
+        let _ = self.handle.sender.send(Command::Shutdown);
+        self.thread.take().and_then(|thread| thread.join().ok())
+    }
+}
+
+impl<MH> Drop for DeviceActor<MH> {
+    fn drop(&mut self) {
+        self.join();
+    }
+}
+
+/// The batches waiting in the actor's records channel, held against a
+/// `MemoryBudget`
+struct InFlight {
+    account : BudgetAccount,
+    /// Records in each batch sent and not yet received, oldest first
+    batches : VecDeque<usize>,
+}
+
+impl InFlight {
+    /// Releases the batches received, leaving the `waiting` newest.
+    fn received(&mut self, waiting : usize) {
+        while self.batches.len() > waiting {
+            let records = self.batches.pop_front().unwrap();
+            self.account.release(records);
+        }
+    }
+}
+
+type RecordSender = flume::Sender<CheckedResult<Vec<u32>, u32>>;
+
+/// Sends `batch` to the records channel, unless it doesn't fit in the
+/// budget of `in_flight`.
+fn send_batch<MH : MultiHarpDevice>(
+    records : &RecordSender,
+    in_flight : &mut Option<InFlight>,
+    multiharp : &MH,
+    batch : CheckedResult<Vec<u32>, u32>,
+) {
+    if let Some(in_flight) = in_flight.as_mut() {
+        in_flight.received(records.len());
+        let len = batch.as_ref().map_or(0, |b| b.len());
+        if !in_flight.account.try_reserve(len) {
+            in_flight.account.record_dropped(len);
+            events::record(&multiharp.get_serial(), DeviceEventKind::Warning,
+                format!("Dropped {} records: the memory budget is spent", len));
+            return;
+        }
+        in_flight.batches.push_back(len);
+    }
+    let _ = records.send(batch);
+}
+
+/// The actor's thread: commands first, then a FIFO read if measuring.
+fn run<MH : MultiHarpDevice>(
+    mut multiharp : MH,
+    commands : flume::Receiver<Command<MH>>,
+    records : RecordSender,
+    mut in_flight : Option<InFlight>,
+) -> MH {
+    let mut measuring = false;
+    let mut idle = false;
+    let mut buffer = vec![0u32; mhconsts::TTREADMAX];
+    loop {
+        if let Some(in_flight) = in_flight.as_mut() {
+            in_flight.received(records.len());
+        }
+        let command = match (measuring, idle) {
+            (false, _) => commands.recv().ok(),
+            (true, false) => match commands.try_recv() {
+                Err(flume::TryRecvError::Empty) => None,
+                received => Some(received.unwrap_or(Command::Shutdown)),
+            },
+            (true, true) => match commands.recv_timeout(IDLE_READ_INTERVAL) {
+                Err(flume::RecvTimeoutError::Timeout) => None,
+                received => Some(received.unwrap_or(Command::Shutdown)),
+            },
+        };
+
+        match command {
+            Some(Command::Configure(config, reply)) => {
+                let _ = reply.send(multiharp.with_settings_transaction(|batch| *batch = config));
+            },
+            Some(Command::Start(acquisition_time, reply)) => {
+                let started = multiharp.start_measurement(acquisition_time);
+                measuring = started.is_ok();
+                idle = false;
+                let _ = reply.send(started);
+            },
+            Some(Command::Stop(reply)) => {
+                let stopped = multiharp.stop_measurement();
+                if std::mem::take(&mut measuring) {
+                    send_batch(&records, &mut in_flight, &multiharp, Ok(Vec::new()));
+                }
+                let _ = reply.send(stopped);
+            },
+            Some(Command::Query(query)) => {
+                if panic::catch_unwind(AssertUnwindSafe(|| query(&mut multiharp))).is_err() {
+                    events::record(&multiharp.get_serial(), DeviceEventKind::Error, "Query to the device actor panicked".to_string());
+                }
+            },
+            Some(Command::Shutdown) | None if !measuring => break,
+            Some(Command::Shutdown) => {
+                let _ = multiharp.stop_measurement();
+                send_batch(&records, &mut in_flight, &multiharp, Ok(Vec::new()));
+                break;
+            },
+            None => match multiharp.read_fifo(&mut buffer) {
+                Ok(n) if n > 0 => {
+                    idle = false;
+                    send_batch(&records, &mut in_flight, &multiharp, Ok(buffer[..n as usize].to_vec()));
+                },
+                // The FIFO is only certain to be drained once the measurement ends
+                Ok(_) => {
+                    idle = true;
+                    if !multiharp.ctc_status().unwrap_or(false) {
+                        let _ = multiharp.stop_measurement();
+                        send_batch(&records, &mut in_flight, &multiharp, Ok(Vec::new()));
+                        measuring = false;
+                    }
+                },
+                Err(e) => {
+                    let _ = multiharp.stop_measurement();
+                    send_batch(&records, &mut in_flight, &multiharp, Err(e));
+                    measuring = false;
+                },
+            },
+        }
+    }
+    multiharp
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::task::{Wake, Waker};
+    use std::thread::Thread;
+
+    use super::*;
+    use crate::{DebugMultiHarp150, MeasurementMode, ReferenceClock};
+
+    struct Unpark(Thread);
+
+    impl Wake for Unpark {
+        fn wake(self : Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    /// Polls `future` to completion on this thread
+    fn block_on<F : IntoFuture>(future : F) -> F::Output {
+        let waker = Waker::from(Arc::new(Unpark(std::thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        let mut future = Box::pin(future.into_future());
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            std::thread::park();
+        }
+    }
+
+    #[test]
+    fn test_device_actor() {
+        let mut mh = DebugMultiHarp150::default();
+        mh.init(MeasurementMode::T3, ReferenceClock::Internal).unwrap();
+        let actor = DeviceActor::spawn(mh);
+        let handle = actor.handle();
+
+        // Commands from another thread while the actor reads
+        handle.start(200).wait().unwrap().unwrap();
+        let other = handle.clone();
+        let rates = std::thread::spawn(move || other.query(|mh| mh.get_sync_rate()).wait())
+            .join().unwrap().unwrap();
+        assert!(rates.is_ok());
+        let bad = MultiHarpConfig { sync_div : Some(2), binning : Some(1000), ..Default::default() };
+        assert_eq!(handle.configure(bad).wait().unwrap().unwrap_err().setting, "binning");
+
+        let batches = actor.records().iter()
+            .take_while(|batch| !batch.as_ref().unwrap().is_empty())
+            .count();
+        assert!(batches > 0);
+        assert!(!handle.query(|mh| mh.ctc_status().unwrap()).wait().unwrap());
+
+        // Awaited, and a panicking query
+        let sync_div = block_on(handle.query(|mh| mh.current_settings().sync_div));
+        assert_eq!(sync_div, Ok(Some(1)));
+        assert_eq!(handle.query(|_| -> i32 { panic!("query") }).wait(), Err(NoReply));
+
+        // Stopped early, the measurement still ends with an empty batch
+        handle.start(10_000).wait().unwrap().unwrap();
+        assert_eq!(handle.stop().wait(), Ok(Ok(())));
+        assert!(actor.records().iter().any(|batch| batch.unwrap().is_empty()));
+
+        let mh = actor.shutdown().unwrap();
+        assert_eq!(mh.get_measurement_mode(), MeasurementMode::T3);
+        assert_eq!(handle.stop().wait(), Err(NoReply));
+        assert_eq!(block_on(handle.query(|_| ())), Err(NoReply));
+    }
+
+    #[test]
+    fn test_device_actor_budget() {
+        let mut mh = DebugMultiHarp150::default();
+        mh.init(MeasurementMode::T3, ReferenceClock::Internal).unwrap();
+        let budget = MemoryBudget::new(1);
+        let actor = DeviceActor::spawn_with_budget(mh, &budget);
+
+        // Every batch of records is over budget, but the end of the
+        // measurement still arrives
+        actor.handle().start(100).wait().unwrap().unwrap();
+        let batches = actor.records().iter().map(|batch| batch.unwrap()).take_while(|batch| !batch.is_empty()).count();
+        assert_eq!(batches, 0);
+        let usage = &budget.usage()[0];
+        assert_eq!(usage.name, "device_actor");
+        assert!(usage.dropped > 0);
+        assert_eq!(budget.used(), 0);
+
+        let mh = actor.shutdown().unwrap();
+        assert!(mh.recent_events().iter().any(|event| event.message.contains("memory budget is spent")));
+    }
+}
@@ -0,0 +1,299 @@
+//! Turns the bits returned by `get_warnings` into advice: what each
+//! warning means for the measurement and, where there is one, which
+//! setting to change.
+
+use crate::mhconsts::{self, TriggerEdge, Warning};
+use crate::metadata::Labels;
+use crate::rates::Rates;
+use crate::MultiHarpConfig;
+
+/// The warning bitmask returned by `get_warnings`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Warnings(pub i32);
+
+/// Every warning bit with a name, in bit order
+pub const ALL_WARNINGS : [(i32, &str); 11] = [
+    (mhconsts::WARNING_SYNC_RATE_ZERO, "WARNING_SYNC_RATE_ZERO"),
+    (mhconsts::WARNING_SYNC_RATE_VERY_LOW, "WARNING_SYNC_RATE_VERY_LOW"),
+    (mhconsts::WARNING_SYNC_RATE_TOO_HIGH, "WARNING_SYNC_RATE_TOO_HIGH"),
+    (mhconsts::WARNING_INPT_RATE_ZERO, "WARNING_INPT_RATE_ZERO"),
+    (mhconsts::WARNING_INPT_RATE_TOO_HIGH, "WARNING_INPT_RATE_TOO_HIGH"),
+    (mhconsts::WARNING_INPT_RATE_RATIO, "WARNING_INPT_RATE_RATIO"),
+    (mhconsts::WARNING_DIVIDER_GREATER_ONE, "WARNING_DIVIDER_GREATER_ONE"),
+    (mhconsts::WARNING_TIME_SPAN_TOO_SMALL, "WARNING_TIME_SPAN_TOO_SMALL"),
+    (mhconsts::WARNING_OFFSET_UNNECESSARY, "WARNING_OFFSET_UNNECESSARY"),
+    (mhconsts::WARNING_DIVIDER_TOO_SMALL, "WARNING_DIVIDER_TOO_SMALL"),
+    (mhconsts::WARNING_COUNTS_DROPPED, "WARNING_COUNTS_DROPPED"),
+];
+
+impl Warnings {
+    pub fn contains(&self, warning : i32) -> bool {
+        self.0 & warning != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// The set bits, as `(bit, name)`. Unknown bits are skipped.
+    pub fn iter(&self) -> impl Iterator<Item = (i32, &'static str)> + '_ {
+        ALL_WARNINGS.iter().copied().filter(|(bit, _)| self.contains(*bit))
+    }
+
+    /// The set warnings, in bit order. Unknown bits are skipped.
+    pub fn warnings(&self) -> Vec<Warning> {
+        Warning::from_bits(self.0)
+    }
+}
+
+impl From<i32> for Warnings {
+    fn from(bits : i32) -> Self {
+        Warnings(bits)
+    }
+}
+
+/// A change to a `MultiHarpConfig` that should clear a warning
+#[derive(Debug, Clone, PartialEq)]
+pub enum SettingChange {
+    SyncDiv(i32),
+    /// New sync trigger level in mV, keeping the edge
+    SyncTriggerLevel(i32, TriggerEdge),
+    Binning(i32),
+    Offset(i32),
+}
+
+impl SettingChange {
+    /// Applies the change to `config`
+    pub fn apply(&self, config : &mut MultiHarpConfig) {
+        match *self {
+            SettingChange::SyncDiv(div) => config.sync_div = Some(div),
+            SettingChange::SyncTriggerLevel(level, edge) => config.sync_trigger_edge = Some((level, edge)),
+            SettingChange::Binning(binning) => config.binning = Some(binning),
+            SettingChange::Offset(offset) => config.offset = Some(offset),
+        }
+    }
+}
+
+/// What a warning means, and what to do about it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Advisory {
+    /// The warning bit this advisory addresses
+    pub warning : i32,
+    pub message : String,
+    /// A setting change that should resolve the warning, if one exists.
+    /// `None` means the fix is outside the device (cabling, light level, ...)
+    pub change : Option<SettingChange>,
+}
+
+impl Advisory {
+    /// The warning this advisory addresses
+    pub fn kind(&self) -> Option<Warning> {
+        Warning::from_code(self.warning)
+    }
+}
+
+/// Next larger sync divider (they're powers of 2), if any
+fn larger_divider(div : i32) -> Option<i32> {
+    let next = (div.max(1) * 2).min(mhconsts::SYNCDIVMAX);
+    if next > div { Some(next) } else { None }
+}
+
+/// Advice for every warning set in `warnings`, given the `config` that
+/// produced them. Settings missing from `config` are assumed to be at
+/// their defaults. Call `get_all_count_rates` before `get_warnings`, or
+/// the rate warnings are meaningless.
+///
+/// ## Example
+///
+/// ```
+/// use multi_harp_patina::*;
+///
+/// let config = MultiHarpConfig { sync_div : Some(1), ..Default::default() };
+/// let advice = advise(&Warnings(WARNING_DIVIDER_TOO_SMALL), &config);
+/// assert_eq!(advice[0].change, Some(SettingChange::SyncDiv(2)));
+/// ```
+pub fn advise(warnings : &Warnings, config : &MultiHarpConfig) -> Vec<Advisory> {
+    let sync_div = config.sync_div.unwrap_or(1);
+    let binning = config.binning.unwrap_or(0);
+
+    warnings.iter().map(|(warning, _)| {
+        let (message, change) = match warning {
+            mhconsts::WARNING_SYNC_RATE_ZERO => (
+                "No sync signal detected. Check the sync cable and that the sync trigger level is \
+                within the signal's amplitude.".to_string(),
+                None
+            ),
+            mhconsts::WARNING_SYNC_RATE_VERY_LOW => {
+                let change = config.sync_trigger_edge
+                    .map(|(level, edge)| SettingChange::SyncTriggerLevel(level / 2, edge));
+                ("Sync rate is very low. The sync trigger level may be too far from zero, \
+                or the sync divider too large.".to_string(),
+                if sync_div > 1 { Some(SettingChange::SyncDiv(1)) } else { change })
+            },
+            mhconsts::WARNING_SYNC_RATE_TOO_HIGH => (
+                "Sync rate is above what the sync input supports at this divider. Increase the \
+                sync divider.".to_string(),
+                larger_divider(sync_div).map(SettingChange::SyncDiv)
+            ),
+            mhconsts::WARNING_INPT_RATE_ZERO => (
+                "No counts on an enabled input. Check the detector, its power and the input \
+                trigger level, or disable the channel.".to_string(),
+                None
+            ),
+            mhconsts::WARNING_INPT_RATE_TOO_HIGH => (
+                "An input count rate is too high for the input. Reduce the light level.".to_string(),
+                None
+            ),
+            mhconsts::WARNING_INPT_RATE_RATIO => (
+                "An input count rate is more than 5% of the sync rate, so TCSPC histograms will \
+                suffer from pile-up. Reduce the light level.".to_string(),
+                None
+            ),
+            mhconsts::WARNING_DIVIDER_GREATER_ONE => (
+                "The sync divider is greater than 1 in T2 mode, where it only affects the sync \
+                rate readout. Set it to 1 unless that's intended.".to_string(),
+                Some(SettingChange::SyncDiv(1))
+            ),
+            mhconsts::WARNING_TIME_SPAN_TOO_SMALL => (
+                "The histogram time span is shorter than the sync period, so late photons are \
+                lost. Increase the binning.".to_string(),
+                if binning < mhconsts::BINSTEPSMAX { Some(SettingChange::Binning(binning + 1)) } else { None }
+            ),
+            mhconsts::WARNING_OFFSET_UNNECESSARY => (
+                "The offset is unnecessary: the histogram already covers the sync period.".to_string(),
+                Some(SettingChange::Offset(0))
+            ),
+            mhconsts::WARNING_DIVIDER_TOO_SMALL => (
+                "The sync rate is too high for the dead time of the T3 mode sync input. Increase \
+                the sync divider.".to_string(),
+                larger_divider(sync_div).map(SettingChange::SyncDiv)
+            ),
+            mhconsts::WARNING_COUNTS_DROPPED => (
+                "Counts were dropped because the data rate exceeded the transfer rate. Read the \
+                FIFO more often or reduce the count rates.".to_string(),
+                None
+            ),
+            _ => unreachable!("`Warnings::iter` only yields known bits"),
+        };
+        Advisory { warning, message, change }
+    }).collect()
+}
+
+/// Photons per excitation pulse above which TCSPC lifetimes are
+/// noticeably distorted by pile-up. The classic rule of thumb is 1 to 5%.
+pub const PILE_UP_THRESHOLD : f64 = 0.05;
+
+/// Photons detected per sync pulse on each channel, computed from `Rates`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PileUpEstimate {
+    /// Input rate over sync rate, per channel
+    pub ratios : Vec<f64>,
+    pub threshold : f64,
+}
+
+impl PileUpEstimate {
+    /// Estimates pile-up from measured `rates`, warning above `threshold`
+    /// photons per pulse. Errors if there's no sync signal.
+    pub fn new(rates : &Rates, threshold : f64) -> Result<Self, String> {
+        if rates.sync <= 0.0 {
+            return Err("No sync signal to estimate pile-up against".to_string());
+        }
+        Ok(PileUpEstimate {
+            ratios : rates.inputs.iter().map(|r| r / rates.sync).collect(),
+            threshold,
+        })
+    }
+
+    /// The highest ratio of any channel
+    pub fn max_ratio(&self) -> f64 {
+        self.ratios.iter().copied().fold(0.0, f64::max)
+    }
+
+    /// Channels above the threshold
+    pub fn channels_over(&self) -> Vec<usize> {
+        self.ratios.iter().enumerate()
+            .filter(|(_, r)| **r > self.threshold)
+            .map(|(channel, _)| channel)
+            .collect()
+    }
+
+    /// An advisory (for `WARNING_INPT_RATE_RATIO`) if any channel is over the threshold
+    pub fn advisory(&self) -> Option<Advisory> {
+        let over = self.channels_over();
+        if over.is_empty() {
+            return None;
+        }
+        Some(Advisory {
+            warning : mhconsts::WARNING_INPT_RATE_RATIO,
+            message : format!(
+                "Channels {:?} detect up to {:.1}% photons per sync pulse, above the {:.1}% pile-up \
+                threshold. Lifetimes will be biased short. Reduce the light level.",
+                over, self.max_ratio() * 100.0, self.threshold * 100.0
+            ),
+            change : None,
+        })
+    }
+
+    /// Records the estimate as `pile_up_ratio` and `pile_up_ratio_<channel>`
+    /// labels, e.g. to include it in the manifest of the acquisition.
+    pub fn annotate(&self, labels : &mut Labels) {
+        labels.insert("pile_up_ratio", self.max_ratio());
+        for (channel, ratio) in self.ratios.iter().enumerate() {
+            labels.insert(format!("pile_up_ratio_{}", channel), ratio);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_advise() {
+        let config = MultiHarpConfig {
+            sync_div : Some(16),
+            binning : Some(3),
+            ..Default::default()
+        };
+        let warnings = Warnings(
+            mhconsts::WARNING_SYNC_RATE_TOO_HIGH
+            | mhconsts::WARNING_TIME_SPAN_TOO_SMALL
+            | mhconsts::WARNING_COUNTS_DROPPED
+            | 0x8000 // unknown
+        );
+        let advice = advise(&warnings, &config);
+        assert_eq!(advice.len(), 3);
+        // Already at the maximum divider
+        assert_eq!(advice[0].change, None);
+        assert_eq!(advice[1].change, Some(SettingChange::Binning(4)));
+        assert_eq!(advice[2].warning, mhconsts::WARNING_COUNTS_DROPPED);
+        assert_eq!(advice[2].kind(), Some(Warning::CountsDropped));
+        assert_eq!(warnings.warnings(), vec![Warning::SyncRateTooHigh, Warning::TimeSpanTooSmall, Warning::CountsDropped]);
+
+        let mut config = MultiHarpConfig::default();
+        advise(&Warnings(mhconsts::WARNING_OFFSET_UNNECESSARY), &config)[0]
+            .change.as_ref().unwrap().apply(&mut config);
+        assert_eq!(config.offset, Some(0));
+        assert!(advise(&Warnings::default(), &config).is_empty());
+    }
+
+    #[test]
+    fn test_pile_up_estimate() {
+        let rates = Rates {
+            sync : 1e6,
+            inputs : vec![1e4, 8e4],
+            readings : 1,
+            timestamp : std::time::SystemTime::now(),
+        };
+        let estimate = PileUpEstimate::new(&rates, PILE_UP_THRESHOLD).unwrap();
+        assert_eq!(estimate.ratios, vec![0.01, 0.08]);
+        assert_eq!(estimate.channels_over(), vec![1]);
+        assert_eq!(estimate.advisory().unwrap().warning, mhconsts::WARNING_INPT_RATE_RATIO);
+        assert!(PileUpEstimate::new(&rates, 0.1).unwrap().advisory().is_none());
+
+        let mut labels = Labels::new();
+        estimate.annotate(&mut labels);
+        assert_eq!(labels.get("pile_up_ratio"), Some("0.08"));
+        assert_eq!(labels.get("pile_up_ratio_0"), Some("0.01"));
+    }
+}
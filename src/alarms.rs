@@ -0,0 +1,229 @@
+//! Thresholds on the count rates, checked by a `RateRecorder` on every
+//! sample it reads, with what to do when one is crossed: log it, stop the
+//! measurement, or call a function. Meant to protect detectors from too
+//! much light during unattended runs, and to notice a lost laser.
+
+use std::fmt::Display;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use crate::channels;
+use crate::notify::Notifier;
+use crate::rates::Rates;
+
+/// Which rate an alarm watches
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateSource {
+    /// The sync rate, in Hz
+    Sync,
+    /// The count rate of an input channel (from 0), in counts per second
+    Input(usize),
+    /// The sum of the input rates
+    TotalInput,
+}
+
+impl RateSource {
+    /// The rate from `rates`, `None` for a channel the device doesn't have
+    pub fn read(&self, rates : &Rates) -> Option<f64> {
+        match self {
+            RateSource::Sync => Some(rates.sync),
+            RateSource::Input(channel) => rates.inputs.get(*channel).copied(),
+            RateSource::TotalInput => Some(rates.total_input()),
+        }
+    }
+}
+
+impl Display for RateSource {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RateSource::Sync => write!(f, "sync rate"),
+            RateSource::Input(channel) => write!(f, "channel {} rate", channel),
+            RateSource::TotalInput => write!(f, "total input rate"),
+        }
+    }
+}
+
+/// When an alarm goes off
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Threshold {
+    Above(f64),
+    Below(f64),
+}
+
+impl Threshold {
+    pub fn crossed(&self, rate : f64) -> bool {
+        match *self {
+            Threshold::Above(limit) => rate > limit,
+            Threshold::Below(limit) => rate < limit,
+        }
+    }
+}
+
+impl Display for Threshold {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Threshold::Above(limit) => write!(f, "> {}", limit),
+            Threshold::Below(limit) => write!(f, "< {}", limit),
+        }
+    }
+}
+
+/// Called with each alarm that goes off, on the `RateRecorder`'s thread
+pub type AlarmHook = Arc<dyn Fn(&AlarmTriggered) + Send + Sync>;
+
+/// What to do when an alarm goes off
+#[derive(Clone)]
+pub enum AlarmAction {
+    /// Records a warning in the device's event log
+    Log,
+    /// Stops the running measurement, if any
+    StopMeasurement,
+    /// Calls the hook
+    Call(AlarmHook),
+    /// Sends `Notification::Alarm` to the notifier's hooks
+    Notify(Notifier),
+}
+
+impl std::fmt::Debug for AlarmAction {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AlarmAction::Log => write!(f, "Log"),
+            AlarmAction::StopMeasurement => write!(f, "StopMeasurement"),
+            AlarmAction::Call(_) => write!(f, "Call(..)"),
+            AlarmAction::Notify(notifier) => write!(f, "Notify({:?})", notifier),
+        }
+    }
+}
+
+/// An alarm that went off
+#[derive(Debug, Clone, PartialEq)]
+pub struct AlarmTriggered {
+    pub name : String,
+    pub serial : String,
+    pub source : RateSource,
+    pub threshold : Threshold,
+    /// The rate that crossed the threshold
+    pub rate : f64,
+    pub timestamp : SystemTime,
+}
+
+impl Display for AlarmTriggered {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        let names = channels::channel_map(&self.serial);
+        match self.source {
+            RateSource::Input(channel) if names.name(channel as u8).is_some() => write!(f,
+                "Alarm {}: {} (channel {}) rate {} {}",
+                self.name, names.label(channel as u8), channel, self.rate, self.threshold,
+            ),
+            _ => write!(f, "Alarm {}: {} {} {}", self.name, self.source, self.rate, self.threshold),
+        }
+    }
+}
+
+/// A threshold on one rate and the actions taken when it is crossed.
+/// An alarm goes off once when the rate crosses the threshold, and again
+/// only after the rate has come back.
+///
+/// ## Example
+///
+/// ```
+/// use std::sync::{Arc, Mutex};
+/// use multi_harp_patina::*;
+///
+/// let mh = Arc::new(Mutex::new(DebugMultiHarp150::default()));
+/// let recorder = RateRecorder::start(Arc::clone(&mh), RATE_GATE, 10);
+/// recorder.add_alarm(
+///     RateAlarm::new("detector 2 overexposed", RateSource::Input(2), Threshold::Above(5e6))
+///         .with_action(AlarmAction::Log)
+///         .with_action(AlarmAction::StopMeasurement)
+/// );
+/// recorder.add_alarm(
+///     RateAlarm::new("laser off", RateSource::Sync, Threshold::Below(1e3))
+///         .with_action(AlarmAction::Call(Arc::new(|alarm| eprintln!("{}", alarm))))
+/// );
+/// ```
+#[derive(Debug, Clone)]
+pub struct RateAlarm {
+    name : String,
+    source : RateSource,
+    threshold : Threshold,
+    actions : Vec<AlarmAction>,
+    /// Whether the threshold was crossed at the last sample
+    active : bool,
+}
+
+impl RateAlarm {
+    /// An alarm without actions, see `with_action`
+    pub fn new(name : &str, source : RateSource, threshold : Threshold) -> Self {
+        RateAlarm { name : name.to_string(), source, threshold, actions : Vec::new(), active : false }
+    }
+
+    /// Adds `action`, taken after those added before it
+    pub fn with_action(mut self, action : AlarmAction) -> Self {
+        self.actions.push(action);
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn actions(&self) -> &[AlarmAction] {
+        &self.actions
+    }
+
+    /// Checks `rates` from the device `serial`, returning the alarm if it
+    /// just went off.
+    pub(crate) fn check(&mut self, serial : &str, rates : &Rates) -> Option<AlarmTriggered> {
+        let rate = self.source.read(rates)?;
+        let crossed = self.threshold.crossed(rate);
+        let went_off = crossed && !self.active;
+        self.active = crossed;
+        went_off.then(|| AlarmTriggered {
+            name : self.name.clone(),
+            serial : serial.to_string(),
+            source : self.source,
+            threshold : self.threshold,
+            rate,
+            timestamp : rates.timestamp,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rates(sync : f64, inputs : &[f64]) -> Rates {
+        Rates { sync, inputs : inputs.to_vec(), readings : 1, timestamp : SystemTime::now() }
+    }
+
+    #[test]
+    fn test_rate_alarm() {
+        let mut alarm = RateAlarm::new("bright", RateSource::Input(1), Threshold::Above(5e6));
+        assert!(alarm.check("1", &rates(1e6, &[0.0, 1e6])).is_none());
+        let triggered = alarm.check("1", &rates(1e6, &[0.0, 6e6])).unwrap();
+        assert_eq!(triggered.rate, 6e6);
+        assert_eq!(triggered.to_string(), "Alarm bright: channel 1 rate 6000000 > 5000000");
+
+        // Once per crossing
+        assert!(alarm.check("1", &rates(1e6, &[0.0, 7e6])).is_none());
+        assert!(alarm.check("1", &rates(1e6, &[0.0, 1e6])).is_none());
+        assert!(alarm.check("1", &rates(1e6, &[0.0, 7e6])).is_some());
+
+        // Named after the device's channel map
+        channels::set_channel_map("alarm_test", [(1, "GFP")].into_iter().collect());
+        let named = AlarmTriggered { serial : "alarm_test".to_string(), ..triggered };
+        assert_eq!(named.to_string(), "Alarm bright: GFP (channel 1) rate 6000000 > 5000000");
+        channels::clear_channel_map("alarm_test");
+
+        // A channel the device doesn't have never goes off
+        let mut missing = RateAlarm::new("missing", RateSource::Input(8), Threshold::Below(1.0));
+        assert!(missing.check("1", &rates(1e6, &[0.0; 4])).is_none());
+
+        let mut laser = RateAlarm::new("laser", RateSource::Sync, Threshold::Below(1e3));
+        assert!(laser.check("1", &rates(0.0, &[])).is_some());
+        let mut total = RateAlarm::new("total", RateSource::TotalInput, Threshold::Above(1.5));
+        assert!(total.check("1", &rates(0.0, &[1.0, 1.0])).is_some());
+    }
+}
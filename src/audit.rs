@@ -0,0 +1,200 @@
+//! An audit trail of device configuration: every setter of a
+//! `MultiHarpDevice` that succeeds reports the setting it changed, with
+//! the previous value when the device knows it, to a process-wide sink.
+//!
+//! Nothing is recorded until a sink is installed with `set_settings_log`
+//! (or `log_settings_to` for JSON lines), so devices that are not audited
+//! pay only for an atomic load per setter call.
+
+use std::fmt::Debug;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::events::{self, DeviceEventKind};
+use crate::metadata::json_string;
+
+type SettingsSink = Box<dyn FnMut(&SettingApplied) + Send>;
+
+/// Whether there is a sink or any recorder
+static ENABLED : AtomicBool = AtomicBool::new(false);
+static SINK : Mutex<Option<SettingsSink>> = Mutex::new(None);
+/// Sinks added by the crate itself, e.g. for a `SessionRecorder`,
+/// which get every setting alongside `SINK`
+static RECORDERS : Mutex<Vec<(u64, SettingsSink)>> = Mutex::new(Vec::new());
+static NEXT_RECORDER : AtomicU64 = AtomicU64::new(0);
+
+/// A setting applied to a device.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SettingApplied {
+    pub serial : String,
+    /// Name of the setter without its `set_` prefix, with the channel
+    /// for per-channel settings, e.g. `input_channel_offset[2]`
+    pub setting : String,
+    /// The value before the call, if the device keeps track of it
+    pub old : Option<String>,
+    pub new : String,
+    pub timestamp : SystemTime,
+}
+
+impl SettingApplied {
+    /// The record as one line of JSON, e.g.
+    /// `{"timestamp_us":1700000000000000,"serial":"1044272","setting":"sync_div","old":"1","new":"2"}`.
+    /// `old` is `null` when unknown.
+    pub fn to_json(&self) -> String {
+        let timestamp_us = self.timestamp.duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros())
+            .unwrap_or(0);
+        let old = self.old.as_deref().map(json_string).unwrap_or_else(|| "null".to_string());
+        format!(
+            "{{\"timestamp_us\":{},\"serial\":{},\"setting\":{},\"old\":{},\"new\":{}}}",
+            timestamp_us, json_string(&self.serial), json_string(&self.setting), old, json_string(&self.new)
+        )
+    }
+}
+
+/// Sends a `SettingApplied` to `sink` for every setting successfully applied
+/// to any device in this process, replacing the previous sink. `sink` is
+/// called on the thread that applied the setting, so it should be quick.
+///
+/// ## Example
+///
+/// ```
+/// use std::sync::{Arc, Mutex};
+/// use multi_harp_patina::*;
+///
+/// let applied = Arc::new(Mutex::new(Vec::new()));
+/// let log = Arc::clone(&applied);
+/// set_settings_log(move |setting| log.lock().unwrap().push(setting.clone()));
+///
+/// let mut mh = DebugMultiHarp150::default();
+/// mh.set_input_channel_offset(1, 2500).unwrap();
+/// clear_settings_log();
+///
+/// assert!(applied.lock().unwrap().iter()
+///     .any(|s| s.setting == "input_channel_offset[1]" && s.new == "2500"));
+/// ```
+pub fn set_settings_log<F>(sink : F) where F : FnMut(&SettingApplied) + Send + 'static {
+    *SINK.lock().unwrap_or_else(|e| e.into_inner()) = Some(Box::new(sink));
+    refresh_enabled();
+}
+
+/// Writes every applied setting to `writer` as a line of JSON (see
+/// `SettingApplied::to_json`), flushing after each line so the trail
+/// survives a crash. Write errors are reported on stderr and otherwise
+/// ignored, so a full disk never stops an acquisition.
+pub fn log_settings_to<W>(mut writer : W) where W : Write + Send + 'static {
+    set_settings_log(move |setting| {
+        if let Err(e) = writeln!(writer, "{}", setting.to_json()).and_then(|_| writer.flush()) {
+            eprintln!("Failed to log setting {} of {}: {}", setting.setting, setting.serial, e);
+        }
+    });
+}
+
+/// Stops recording applied settings, dropping the sink.
+pub fn clear_settings_log() {
+    SINK.lock().unwrap_or_else(|e| e.into_inner()).take();
+    refresh_enabled();
+}
+
+/// Sends every applied setting to `sink` as well as the sink of
+/// `set_settings_log`, until `remove_recorder` is called with the id
+/// returned.
+pub(crate) fn add_recorder(sink : SettingsSink) -> u64 {
+    let id = NEXT_RECORDER.fetch_add(1, Ordering::Relaxed);
+    RECORDERS.lock().unwrap_or_else(|e| e.into_inner()).push((id, sink));
+    refresh_enabled();
+    id
+}
+
+pub(crate) fn remove_recorder(id : u64) {
+    RECORDERS.lock().unwrap_or_else(|e| e.into_inner()).retain(|(r, _)| *r != id);
+    refresh_enabled();
+}
+
+fn refresh_enabled() {
+    let sink = SINK.lock().unwrap_or_else(|e| e.into_inner()).is_some();
+    let recorders = !RECORDERS.lock().unwrap_or_else(|e| e.into_inner()).is_empty();
+    ENABLED.store(sink || recorders, Ordering::SeqCst);
+}
+
+/// Reports that `setting` of the device `serial` changed from `old` to `new`.
+/// Values are recorded with their `Debug` formatting. The change also
+/// goes to the device's event log, whether or not a sink is installed.
+pub(crate) fn log_setting<N : Debug>(serial : &str, setting : &str, old : Option<&dyn Debug>, new : N) {
+    record_event(serial, setting, old, &new);
+    if ENABLED.load(Ordering::Relaxed) {
+        emit(serial, setting.to_string(), old, &new);
+    }
+}
+
+/// `log_setting` for a setting of one input channel
+pub(crate) fn log_channel_setting<N : Debug>(serial : &str, setting : &str, channel : i32, old : Option<&dyn Debug>, new : N) {
+    let setting = format!("{}[{}]", setting, channel);
+    record_event(serial, &setting, old, &new);
+    if ENABLED.load(Ordering::Relaxed) {
+        emit(serial, setting, old, &new);
+    }
+}
+
+fn record_event(serial : &str, setting : &str, old : Option<&dyn Debug>, new : &dyn Debug) {
+    let message = match old {
+        Some(old) => format!("{}: {:?} -> {:?}", setting, old, new),
+        None => format!("{}: {:?}", setting, new),
+    };
+    events::record(serial, DeviceEventKind::Setting, message);
+}
+
+fn emit(serial : &str, setting : String, old : Option<&dyn Debug>, new : &dyn Debug) {
+    let applied = SettingApplied {
+        serial : serial.to_string(),
+        setting,
+        old : old.map(|o| format!("{:?}", o)),
+        new : format!("{:?}", new),
+        timestamp : SystemTime::now(),
+    };
+    if let Some(sink) = SINK.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        sink(&applied);
+    }
+    for (_, recorder) in RECORDERS.lock().unwrap_or_else(|e| e.into_inner()).iter_mut() {
+        recorder(&applied);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use crate::{DebugMultiHarp150, MultiHarpDevice, TriggerEdge};
+
+    #[test]
+    fn test_settings_log() {
+        // Other tests configure debug devices in parallel, so look only
+        // for the values set here
+        let applied = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&applied);
+        set_settings_log(move |setting : &SettingApplied| log.lock().unwrap().push(setting.clone()));
+
+        let mut mh = DebugMultiHarp150::default();
+        mh.set_input_channel_offset(2, 1234).unwrap();
+        mh.set_input_channel_offset(2, 4321).unwrap();
+        mh.set_sync_edge_trigger(-321, TriggerEdge::Falling).unwrap();
+        assert!(mh.set_trigger_output(-1).is_err());
+        clear_settings_log();
+        mh.set_input_channel_offset(2, 5678).unwrap();
+
+        let applied = applied.lock().unwrap();
+        let find = |new : &str| applied.iter().find(|s| s.new == new).cloned();
+        let second = find("4321").unwrap();
+        assert_eq!(second.setting, "input_channel_offset[2]");
+        assert_eq!(second.old.as_deref(), Some("1234"));
+        assert_eq!(find("(-321, Falling)").unwrap().setting, "sync_edge_trigger");
+        assert!(find("-1").is_none());
+        assert!(find("5678").is_none());
+
+        let json = second.to_json();
+        assert!(json.starts_with("{\"timestamp_us\":"));
+        assert!(json.ends_with(",\"serial\":\"1044272\",\"setting\":\"input_channel_offset[2]\",\"old\":\"1234\",\"new\":\"4321\"}"));
+    }
+}
@@ -0,0 +1,273 @@
+//! Starting an acquisition in step with other instruments. A
+//! `StartBarrier` arms the acquisition (the processors initialized and,
+//! with a C1 trigger, the device waiting for its edge) and only begins
+//! collecting data once it is released, by a `release` call from any
+//! thread (e.g. on a signal from camera software) or by the C1 edge
+//! itself. The start is recorded both on the host clock and, from
+//! `MultiHarpDevice::get_start_time`, on the device clock, so the
+//! acquisition can be aligned with the others to well under a
+//! millisecond.
+
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::mhconsts::{MeasurementControlMode, TriggerEdge};
+use crate::pipeline::{Pipeline, PipelineError, Reads};
+use crate::MultiHarpDevice;
+
+/// What releases a `StartBarrier`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StartTrigger {
+    /// A call to `StartBarrier::release`. The measurement is started
+    /// once released, so the start lags the release by the time
+    /// `start_measurement` takes.
+    Release,
+    /// An edge on the C1 input, with the device armed in
+    /// `C1StartCtcStop` mode beforehand. The start is exact to the
+    /// device's clock.
+    C1(TriggerEdge),
+}
+
+/// When an acquisition behind a `StartBarrier` started.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StartRecord {
+    pub trigger : StartTrigger,
+    /// Host time of the `release` call, for `StartTrigger::Release`
+    pub released_at : Option<SystemTime>,
+    /// Host time at which `start_measurement` returned
+    pub armed_at : SystemTime,
+    /// Start of the measurement as reported by the device, if it
+    /// reports one (see `start_time_from_dwords`)
+    pub device_start : Option<SystemTime>,
+}
+
+impl StartRecord {
+    /// The best known start time: the device's, then the release's,
+    /// then the arming's
+    pub fn start(&self) -> SystemTime {
+        self.device_start.or(self.released_at).unwrap_or(self.armed_at)
+    }
+
+    /// Time between the `release` call and the start of the measurement
+    pub fn latency(&self) -> Option<Duration> {
+        let released = self.released_at?;
+        self.device_start.unwrap_or(self.armed_at).duration_since(released).ok()
+    }
+}
+
+/// What an acquisition behind a `StartBarrier` did.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BarrierRun {
+    /// Records read from the device
+    pub records : u64,
+    pub start : StartRecord,
+}
+
+/// Converts the three dwords of `MultiHarpDevice::get_start_time`, in ps
+/// since the Unix epoch, into a `SystemTime`. `None` if they are all zero,
+/// as from devices that don't report a start time.
+pub fn start_time_from_dwords(dwords : (u32, u32, u32)) -> Option<SystemTime> {
+    let (dword2, dword1, dword0) = dwords;
+    let ps = ((dword2 as u128) << 64) | ((dword1 as u128) << 32) | dword0 as u128;
+    if ps == 0 {
+        return None;
+    }
+    let nanos = u64::try_from(ps / 1000).ok()?;
+    UNIX_EPOCH.checked_add(Duration::from_nanos(nanos))
+}
+
+/// Holds the start of an acquisition until released. Clones share the
+/// barrier, so one can be kept to release it from another thread.
+///
+/// ## Example
+///
+/// ```
+/// use std::time::Duration;
+/// use multi_harp_patina::*;
+///
+/// let mut mh = DebugMultiHarp150::default();
+/// mh.init(MeasurementMode::T3, ReferenceClock::Internal).unwrap();
+/// let mut pipeline = Pipeline::new();
+/// pipeline.add(MarkerCounter::new(1).unwrap());
+///
+/// let barrier = StartBarrier::new().with_timeout(Duration::from_secs(5));
+/// let release = barrier.clone();
+/// std::thread::spawn(move || {
+///     // e.g. once the camera reports its first frame
+///     std::thread::sleep(Duration::from_millis(20));
+///     release.release();
+/// });
+/// let run = barrier.run(&mut pipeline, &mut mh, 100).unwrap();
+/// assert!(run.start.released_at.is_some());
+/// println!("Started {:?} after the release", run.start.latency().unwrap());
+/// ```
+#[derive(Debug, Clone)]
+pub struct StartBarrier {
+    trigger : StartTrigger,
+    timeout : Option<Duration>,
+    released : Arc<(Mutex<Option<SystemTime>>, Condvar)>,
+}
+
+impl Default for StartBarrier {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StartBarrier {
+    /// A barrier released by `release`, waiting indefinitely
+    pub fn new() -> Self {
+        StartBarrier {
+            trigger : StartTrigger::Release,
+            timeout : None,
+            released : Arc::new((Mutex::new(None), Condvar::new())),
+        }
+    }
+
+    /// A barrier released by an `edge` on the C1 input
+    pub fn on_c1(edge : TriggerEdge) -> Self {
+        StartBarrier { trigger : StartTrigger::C1(edge), ..Self::new() }
+    }
+
+    /// Gives up on an acquisition not released within `timeout`
+    pub fn with_timeout(mut self, timeout : Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn trigger(&self) -> StartTrigger {
+        self.trigger
+    }
+
+    /// Releases the barrier, recording the time. Returns `false` if it
+    /// was already released, keeping the first time.
+    pub fn release(&self) -> bool {
+        let (released, condvar) = &*self.released;
+        let mut released = released.lock().unwrap_or_else(|e| e.into_inner());
+        if released.is_some() {
+            return false;
+        }
+        *released = Some(SystemTime::now());
+        condvar.notify_all();
+        true
+    }
+
+    /// Host time of the `release` call, if released
+    pub fn released_at(&self) -> Option<SystemTime> {
+        *self.released.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn is_released(&self) -> bool {
+        self.released_at().is_some()
+    }
+
+    /// Arms the barrier again, for the next acquisition
+    pub fn reset(&self) {
+        *self.released.0.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    }
+
+    /// Blocks until the barrier is released or the timeout passes,
+    /// returning the time of the release.
+    pub fn wait(&self) -> Option<SystemTime> {
+        let (released, condvar) = &*self.released;
+        let guard = released.lock().unwrap_or_else(|e| e.into_inner());
+        let guard = match self.timeout {
+            Some(timeout) => condvar.wait_timeout_while(guard, timeout, |released| released.is_none())
+                .unwrap_or_else(|e| e.into_inner()).0,
+            None => condvar.wait_while(guard, |released| released.is_none())
+                .unwrap_or_else(|e| e.into_inner()),
+        };
+        *guard
+    }
+
+    /// Runs a measurement of `acquisition_time` ms on an initialized
+    /// device once the barrier is released, feeding every FIFO read
+    /// through `pipeline`. The processors are initialized before
+    /// waiting, so nothing is left to set up at the release. With a C1
+    /// trigger, the measurement control mode is restored afterwards.
+    ///
+    /// ## Errors
+    ///
+    /// As `Pipeline::run`, and `PipelineError::Barrier` if the barrier
+    /// isn't released within the timeout. The processors are still
+    /// finalized.
+    pub fn run<MH : MultiHarpDevice>(&self, pipeline : &mut Pipeline, multiharp : &mut MH, acquisition_time : i32) -> Result<BarrierRun, PipelineError> {
+        let info = pipeline.begin(multiharp, acquisition_time, "StartBarrier::run")?;
+        let mut length = Duration::from_millis(acquisition_time.max(0) as u64);
+        let previous = multiharp.current_settings().meas_control
+            .unwrap_or((MeasurementControlMode::SingleShotCtc, None, None));
+
+        let armed = match self.trigger {
+            StartTrigger::Release => match self.wait() {
+                Some(_) => multiharp.start_measurement(acquisition_time).map_err(PipelineError::from),
+                None => Err(PipelineError::Barrier(format!("Not released within {:?}", self.timeout.unwrap_or_default()))),
+            },
+            StartTrigger::C1(edge) => {
+                // The device only counts the acquisition time from the edge
+                length += self.timeout.unwrap_or(Duration::MAX - length);
+                multiharp.set_measurement_control_mode(MeasurementControlMode::C1StartCtcStop, Some(edge), None)
+                    .map_err(PipelineError::from)
+                    .and_then(|_| multiharp.start_measurement(acquisition_time).map_err(PipelineError::from))
+            },
+        };
+        if let Err(e) = armed {
+            let _ = pipeline.finalize();
+            if let StartTrigger::C1(_) = self.trigger {
+                let _ = multiharp.set_measurement_control_mode(previous.0, previous.1, previous.2);
+            }
+            return Err(e);
+        }
+        let armed_at = SystemTime::now();
+
+        let mut reads = Reads::default();
+        let acquired = pipeline.read_measurement(multiharp, &info, Instant::now(), length, false, &mut reads, |_| ());
+        let start = StartRecord {
+            trigger : self.trigger,
+            released_at : self.released_at(),
+            armed_at,
+            device_start : multiharp.get_start_time().ok().and_then(start_time_from_dwords),
+        };
+        let ended = pipeline.end(multiharp, &info, acquired);
+        if let StartTrigger::C1(_) = self.trigger {
+            let _ = multiharp.set_measurement_control_mode(previous.0, previous.1, previous.2);
+        }
+        ended.map(|_| BarrierRun { records : reads.total, start })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DebugMultiHarp150, MeasurementMode, ReferenceClock};
+
+    #[test]
+    fn test_start_barrier() {
+        let mut mh = DebugMultiHarp150::default();
+        mh.init(MeasurementMode::T3, ReferenceClock::Internal).unwrap();
+        let mut pipeline = Pipeline::new();
+
+        let barrier = StartBarrier::new().with_timeout(Duration::from_millis(20));
+        assert!(matches!(barrier.run(&mut pipeline, &mut mh, 50), Err(PipelineError::Barrier(_))));
+        assert!(!mh.ctc_status().unwrap());
+
+        assert!(barrier.release());
+        assert!(!barrier.release());
+        let run = barrier.run(&mut pipeline, &mut mh, 50).unwrap();
+        assert!(run.records > 0);
+        let device_start = run.start.device_start.unwrap();
+        assert!(device_start >= run.start.released_at.unwrap());
+        assert_eq!(run.start.start(), device_start);
+        barrier.reset();
+        assert!(!barrier.is_released());
+
+        // Armed on C1, the control mode is restored after
+        let barrier = StartBarrier::on_c1(TriggerEdge::Rising);
+        let run = barrier.run(&mut pipeline, &mut mh, 50).unwrap();
+        assert_eq!(run.start.released_at, None);
+        assert_eq!(run.start.latency(), None);
+        assert!(run.start.device_start.unwrap() >= device_start);
+
+        assert_eq!(start_time_from_dwords((0, 0, 0)), None);
+        assert_eq!(start_time_from_dwords((0, 0, 1_000_000)), Some(UNIX_EPOCH + Duration::from_micros(1)));
+    }
+}
@@ -7,14 +7,23 @@
 //! 
 //! We also include a demo where the MultiHarp is itself in a
 //! Mutex for comparison.
-
+//!
+//! Applications that just need the records can use
+//! `acquisition::Session`, which runs this loop for them.
+//!
+//! Runs on a `DebugMultiHarp150` when passed `--debug`, or when built
+//! without `MHLib`.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
 use std::sync::{
     Arc, Mutex, atomic::{AtomicBool,Ordering},
 };
 
 use flume;
 
-use multi_harp_patina::*;
+use multi_harp_patina::prelude::*;
+use multi_harp_patina::{ACQTMAX, SPECIAL};
 
 
 /// This is a simple example of how to use the `MultiHarp150` struct
@@ -22,15 +31,33 @@ use multi_harp_patina::*;
 /// that is updated by the `MultiHarp150` struct in one thread to a second for
 /// offloading
 fn main() {
-
+    let debug = std::env::args().skip(1).any(|arg| arg == "--debug");
     #[cfg(feature = "MHLib")]
-    let mh = open_first_device::<MultiHarp150>();
-    #[cfg(feature = "nolib")]
-    let mh = open_first_device::<DebugMultiHarp150>();
+    if !debug {
+        return run::<MultiHarp150>();
+    }
+    #[cfg(not(feature = "MHLib"))]
+    if !debug {
+        println!("Built without MHLib, running on a DebugMultiHarp150");
+    }
+    run::<DebugMultiHarp150>()
+}
+
+/// The example itself, on whichever kind of device `main` picked
+fn run<M : MultiHarpDevice + std::fmt::Display + Send + 'static>() {
+
+    // `MHP_DEVICE_SERIAL` and `MHP_CONFIG_PATH` pick the device and its settings,
+    // and the records are written to `MHP_OUTPUT_DIR` if it is set
+    let settings = match Settings::from_env() {
+        Ok(s) => s,
+        Err(e) => {println!("Error reading environment: {}", e); return;}
+    };
+
+    let mh = settings.open_device::<M>();
 
     match &mh {
         Ok(m) => {
-            println!("Opened device with serial number {}", m.get_serial());
+            println!("Opened {}", m);
             println!("Number of channels: {}", m.num_input_channels().unwrap());
             println!("Index : {}", m.get_index());
         }
@@ -50,7 +77,11 @@ fn main() {
     .map_err(|e| {println!("Error initializing device: {:?}", e); return ();})
     .unwrap();
 
-    load_default_config(&mut mh);
+    match settings.load_config() {
+        Ok(Some(config)) => mh.set_from_config(&config),
+        Ok(None) => load_default_config(&mut mh),
+        Err(e) => {println!("Error loading settings file: {}", e); return;}
+    }
 
     let count_rate = mh.get_all_count_rates()
     .map_err(|e| {println!("Count rate call failure: {:?}", e); return;}).unwrap();
@@ -62,6 +93,9 @@ fn main() {
         photons_per_sec, photons_per_sec * test_duration
     );
  
+    let serial = mh.get_serial();
+    let output = create_output(&settings, &format!("message_passing_{}.bin", serial));
+
     mh.start_measurement(ACQTMAX)
     .map_err(|e| {println!("Error starting measurement: {:?}", e); return ();}).unwrap();
     
@@ -81,7 +115,7 @@ fn main() {
     });
 
     let handle_stored_thread = std::thread::spawn(move ||
-        {offload_data(receiver);}
+        {offload_data(receiver, output);}
     );
 
     // how long to run it
@@ -102,8 +136,9 @@ fn main() {
         load_stored_histogram_with_mutex(mh, sender, acq_ptr);
     });
 
+    let output = create_output(&settings, &format!("message_passing_mutex_{}.bin", serial));
     let handle_stored_thread = std::thread::spawn(move ||
-        {offload_data(receiver);}
+        {offload_data(receiver, output);}
     );
 
     std::thread::sleep(std::time::Duration::from_secs(test_duration as u64));
@@ -138,10 +173,34 @@ fn load_default_config<M : MultiHarpDevice>(multiharp : &mut M) {
     multiharp.set_from_config(&config);
 }
 
+/// Creates `file_name` in `MHP_OUTPUT_DIR`, if it is set, exiting if
+/// that fails.
+fn create_output(settings : &Settings, file_name : &str) -> Option<BufWriter<File>> {
+    let path = settings.output_path(file_name)?;
+    match File::create(&path) {
+        Ok(f) => Some(BufWriter::new(f)),
+        Err(e) => {
+            eprintln!("Error creating output file {}: {}", path.display(), e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Appends `records` to `output`, if there is one, exiting if that fails.
+fn write_records(output : &mut Option<BufWriter<File>>, records : &[u32]) {
+    if let Some(out) = output.as_mut() {
+        let written = records.iter().try_for_each(|record| out.write_all(&record.to_le_bytes()));
+        if let Err(e) = written.and_then(|_| out.flush()) {
+            eprintln!("Error writing records: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
 /// Checks whether the histogram has been updated
 /// and then offloads the data, hopefully for other uses
 /// (saving? analysis? plotting? drawing an image?)
-fn offload_data(receiver : flume::Receiver<(Vec<u32>, usize)>) {
+fn offload_data(receiver : flume::Receiver<(Vec<u32>, usize)>, mut output : Option<BufWriter<File>>) {
     
     let mut total_processed : usize = 0;
     let mut overflow: usize = 0;
@@ -152,6 +211,7 @@ fn offload_data(receiver : flume::Receiver<(Vec<u32>, usize)>) {
         // println!("Histogram has {} entries", counts);
         
         // Do something with histo here!
+        write_records(&mut output, &histo[0..counts]);
         if counts > 0 {
             overflow += histo[0..counts].iter().fold(0, |acc, x| acc + ((x & SPECIAL) >> 31) as usize);
             // println!(
@@ -2,13 +2,19 @@
 //! 
 //! Here's some example code you can use to exchange
 //! MultiHarp measurement data across threads -- safely!
+//!
+//! Runs on a `DebugMultiHarp150` when passed `--debug`, or when built
+//! without `MHLib`.
 
+use std::fs::File;
+use std::io::{BufWriter, Write};
 use std::sync::{
      Arc, RwLock,
     atomic::{AtomicBool,Ordering},
 };
 
-use multi_harp_patina::*;
+use multi_harp_patina::prelude::*;
+use multi_harp_patina::{ACQTMAX, SPECIAL};
 
 
 /// This is a simple example of how to use the `MultiHarp150` struct
@@ -16,15 +22,33 @@ use multi_harp_patina::*;
 /// that is updated by the `MultiHarp150` struct in one thread, and
 /// offloaded by a second.
 fn main() {
-
+    let debug = std::env::args().skip(1).any(|arg| arg == "--debug");
     #[cfg(feature = "MHLib")]
-    let mh = open_first_device::<MultiHarp150>();
-    #[cfg(feature = "nolib")]
-    let mh = open_first_device::<DebugMultiHarp150>();
+    if !debug {
+        return run::<MultiHarp150>();
+    }
+    #[cfg(not(feature = "MHLib"))]
+    if !debug {
+        println!("Built without MHLib, running on a DebugMultiHarp150");
+    }
+    run::<DebugMultiHarp150>()
+}
+
+/// The example itself, on whichever kind of device `main` picked
+fn run<M : MultiHarpDevice + std::fmt::Display + Send + 'static>() {
+
+    // `MHP_DEVICE_SERIAL` and `MHP_CONFIG_PATH` pick the device and its settings,
+    // and the records are written to `MHP_OUTPUT_DIR` if it is set
+    let settings = match Settings::from_env() {
+        Ok(s) => s,
+        Err(e) => {println!("Error reading environment: {}", e); return;}
+    };
+
+    let mh = settings.open_device::<M>();
 
     match &mh {
         Ok(m) => {
-            println!("Opened device with serial number {}", m.get_serial());
+            println!("Opened {}", m);
             println!("Number of channels: {}", m.num_input_channels().unwrap());
             println!("Index : {}", m.get_index());
         }
@@ -44,7 +68,13 @@ fn main() {
     .map_err(|e| {println!("Error initializing device: {:?}", e); return ();})
     .unwrap();
 
-    load_default_config(&mut mh);
+    match settings.load_config() {
+        Ok(Some(config)) => mh.set_from_config(&config),
+        Ok(None) => load_default_config(&mut mh),
+        Err(e) => {println!("Error loading settings file: {}", e); return;}
+    }
+
+    let output = create_output(&settings, &format!("multithreaded_{}.bin", mh.get_serial()));
 
     let shared_info
         = (Vec::<u32>::with_capacity(TTREADMAX), 0 as usize);
@@ -76,7 +106,7 @@ fn main() {
     let histoptr = Arc::clone(&histo_ptr);
 
     let handle_stored_thread = std::thread::spawn(move ||
-        {offload_data(histoptr, acqpt)}
+        {offload_data(histoptr, acqpt, output)}
     );
 
     // how long to run it
@@ -113,12 +143,37 @@ fn load_default_config<M : MultiHarpDevice>(multiharp : &mut M) {
     multiharp.set_from_config(&config);
 }
 
+/// Creates `file_name` in `MHP_OUTPUT_DIR`, if it is set, exiting if
+/// that fails.
+fn create_output(settings : &Settings, file_name : &str) -> Option<BufWriter<File>> {
+    let path = settings.output_path(file_name)?;
+    match File::create(&path) {
+        Ok(f) => Some(BufWriter::new(f)),
+        Err(e) => {
+            eprintln!("Error creating output file {}: {}", path.display(), e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Appends `records` to `output`, if there is one, exiting if that fails.
+fn write_records(output : &mut Option<BufWriter<File>>, records : &[u32]) {
+    if let Some(out) = output.as_mut() {
+        let written = records.iter().try_for_each(|record| out.write_all(&record.to_le_bytes()));
+        if let Err(e) = written.and_then(|_| out.flush()) {
+            eprintln!("Error writing records: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
 /// Checks whether the histogram has been updated
 /// and then offloads the data, hopefully for other uses
 /// (saving? analysis? plotting? drawing an image?)
 fn offload_data(
     histo_ptr : Arc<RwLock<(Vec<u32>, usize)>>,
-    acquire : Arc<AtomicBool>
+    acquire : Arc<AtomicBool>,
+    mut output : Option<BufWriter<File>>,
     ) {
     let mut total_processed : usize = 0;
     while acquire.load(Ordering::Relaxed) {
@@ -129,6 +184,7 @@ fn offload_data(
                 histo.0[0..histo.1].iter().fold(0, |acc, x| acc + ((x & SPECIAL) >> 31))
             );
             // Do something with them here!
+            write_records(&mut output, &histo.0[0..histo.1]);
             total_processed += histo.1;
             histo.0.clear();
             histo.1 = 0;
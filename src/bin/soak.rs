@@ -0,0 +1,28 @@
+//! Runs a full acquisition against a `DebugMultiHarp150` for hours,
+//! reporting memory growth, dropped batches and latency at the end.
+//! Build it with `--release` to soak the code experiments will run.
+//!
+//! `MHP_SOAK_SECS` sets the length (an hour by default) and
+//! `MHP_SOAK_RATE` the count rate (5 Mcps by default).
+use std::time::Duration;
+
+use multi_harp_patina::{Pipeline, MarkerCounter, SoakTest};
+
+fn main() {
+    let secs = std::env::var("MHP_SOAK_SECS").ok().and_then(|s| s.parse().ok()).unwrap_or(3600);
+    let rate = std::env::var("MHP_SOAK_RATE").ok().and_then(|s| s.parse().ok()).unwrap_or(5e6);
+    println!("Soaking for {} s at {} counts per second", secs, rate);
+
+    let mut pipeline = Pipeline::new();
+    pipeline.add(MarkerCounter::new(1).unwrap());
+
+    match SoakTest::new(Duration::from_secs(secs)).with_count_rate(rate).run(&mut pipeline) {
+        Ok(report) => {
+            for sample in &report.samples {
+                println!("{:?}", sample);
+            }
+            println!("{}", report);
+        },
+        Err(e) => println!("Soak test failed: {}", e),
+    }
+}
@@ -1,28 +1,45 @@
 //! Implements an example with a simple `main` function, just as in the
 //! `MultiHarp` official documentation.
-use multi_harp_patina::*;
+//!
+//! Runs on a `DebugMultiHarp150` when passed `--debug`, or when built
+//! without `MHLib`.
+use multi_harp_patina::prelude::*;
 
-#[cfg(not (feature = "MHLib") )]
 fn main() {
-    println!("Example does not run in debug mode");
+    let debug = std::env::args().skip(1).any(|arg| arg == "--debug");
+    #[cfg(feature = "MHLib")]
+    if !debug {
+        return run::<MultiHarp150>();
+    }
+    #[cfg(not(feature = "MHLib"))]
+    if !debug {
+        println!("Built without MHLib, running on a DebugMultiHarp150");
+    }
+    run::<DebugMultiHarp150>()
 }
 
-#[cfg(feature = "MHLib")]
-fn main() {
+fn run<M : MultiHarpDevice + std::fmt::Display>() {
     let libv = get_library_version();
     match libv {
         Ok(v) => println!("Library version: {}", v),
         Err(e) => println!("Error getting library version: {:?}", e),
     }
 
+    // `MHP_DEVICE_SERIAL`, `MHP_CONFIG_PATH` and `MHP_LOG_LEVEL` override the defaults below,
+    // and the records are written to `MHP_OUTPUT_DIR` if it is set
+    let settings = match Settings::from_env() {
+        Ok(s) => s,
+        Err(e) => {println!("Error reading environment: {}", e); return;}
+    };
+
     println!("Searching for MultiHarp devices...");
     let devs = available_devices();
     println!("Available devices : {:?}", devs);
 
-    let mh = open_first_device::<MultiHarp150>();
+    let mh = settings.open_device::<M>();
     match &mh {
         Ok(m) => {
-            println!("Opened device with serial number {}", m.get_serial());
+            println!("Opened {}", m);
             println!("Number of channels: {}", m.num_input_channels().unwrap());
         }
         Err(e) => {
@@ -41,12 +58,14 @@ fn main() {
     .map_err(|e| {println!("Error initializing device: {:?}", e); return ();})
     .unwrap();
 
-    let (model, partno, ver) = mh.get_hardware_info()
-    .map_err(|e| {println!("Error getting hardware info: {:?}", e); return ();}).unwrap();
-    
-    println!("Model: {}, Part number: {}, Version: {}", model, partno, ver);
-
-    let config = MultiHarpConfig {
+    let config = match settings.load_config() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Error loading settings file: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let config = config.unwrap_or(MultiHarpConfig {
         binning : Some(0) ,
         sync_channel_offset : Some(10),
         sync_div : Some(2),
@@ -66,7 +85,7 @@ fn main() {
             ]
         ),
         ..Default::default()
-    };
+    });
 
     mh.set_from_config(&config);
 
@@ -86,6 +105,17 @@ fn main() {
         println!("Error starting measurement: {:?}", e); return ();
     }).unwrap();
 
+    let mut output = match settings.output_path(&format!("tttr_{}.bin", mh.get_serial())) {
+        Some(path) => match std::fs::File::create(&path) {
+            Ok(f) => Some(std::io::BufWriter::new(f)),
+            Err(e) => {
+                eprintln!("Error creating output file {}: {}", path.display(), e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
     let mut buf = vec![0u32; multi_harp_patina::TTREADMAX];
     while let Ok(x) = mh.ctc_status() {
         if !x {break;}
@@ -97,18 +127,33 @@ fn main() {
         }).unwrap();
 
 
-        println!("Read {} records in {} us", n_reads, time.elapsed().as_micros());
+        if settings.log_level() >= LogLevel::Debug {
+            println!("Read {} records in {} us", n_reads, time.elapsed().as_micros());
+        }
         // Do something with the data,
         // send it to another thread,
         // send it to a friend,
-        // write it to a file??
-        /* - snip - */
+        // or write it to a file
+        if let Some(out) = output.as_mut() {
+            use std::io::Write;
+            for record in &buf[..n_reads as usize] {
+                if let Err(e) = out.write_all(&record.to_le_bytes()) {
+                    eprintln!("Error writing records: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
     }
 
     mh.stop_measurement().map_err(|e| {
         println!("Error stopping measurement: {:?}", e); return ();
     }).unwrap();
 
+    if let Some(Err(e)) = output.as_mut().map(std::io::Write::flush) {
+        eprintln!("Error writing records: {}", e);
+        std::process::exit(1);
+    }
+
     // when mh goes out of scope, it will call `CloseDevice`
 
 }
\ No newline at end of file
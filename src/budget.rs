@@ -0,0 +1,263 @@
+//! One limit on the records held in memory by every buffer sharing a
+//! `MemoryBudget`, so that an acquisition service stays within a
+//! container's memory limit however its rates change. Each buffer
+//! (an `EventCache`, the records channel of a `DeviceActor`) holds an
+//! account of the budget, reserving room before it buffers records and
+//! returning it as they leave. When the budget is spent, a buffer spills
+//! to disk or drops the records, by the budget's `BudgetPolicy`, and
+//! counts them in its account.
+//!
+//! Sizes are counted in records, 4 bytes each. A decoded `TttrEvent`
+//! counts as `EVENT_RECORDS` records.
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Records a decoded `TttrEvent` takes the memory of
+pub const EVENT_RECORDS : usize = std::mem::size_of::<crate::tttr::TttrEvent>() / std::mem::size_of::<u32>();
+
+/// What a buffer does with records once the budget is spent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BudgetPolicy {
+    /// Discard them, counting them in `BudgetUsage::dropped`
+    #[default]
+    Drop,
+    /// Move what the buffer holds to disk, if it can (e.g. an
+    /// `EventCache` with a spill directory), and drop them otherwise
+    Spill,
+}
+
+/// What one account of a `MemoryBudget` holds and has let go of
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BudgetUsage {
+    pub name : String,
+    /// Records held now
+    pub held : usize,
+    /// The most records held at once
+    pub peak : usize,
+    /// Records discarded for lack of budget
+    pub dropped : u64,
+    /// Records moved to disk for lack of budget
+    pub spilled : u64,
+}
+
+impl std::fmt::Display for BudgetUsage {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} : {} records held (peak {}), {} dropped, {} spilled",
+            self.name, self.held, self.peak, self.dropped, self.spilled)
+    }
+}
+
+struct BudgetState {
+    limit : usize,
+    spill : AtomicBool,
+    used : AtomicUsize,
+    /// Indexed by `BudgetAccount::index`
+    accounts : Mutex<Vec<BudgetUsage>>,
+}
+
+/// A limit on the records buffered across the crate, shared by cloning.
+///
+/// ## Example
+///
+/// ```
+/// use multi_harp_patina::*;
+///
+/// let budget = MemoryBudget::from_bytes(64 << 20).with_policy(BudgetPolicy::Spill);
+///
+/// let mut mh = DebugMultiHarp150::new(1e5, 80e6, None);
+/// mh.init(MeasurementMode::T3, ReferenceClock::Internal).unwrap();
+/// let mut pipeline = Pipeline::new();
+/// pipeline.add(EventCache::new(usize::MAX)
+///     .with_spill_dir(std::env::temp_dir())
+///     .with_memory_budget(&budget));
+/// pipeline.run(&mut mh, 200).unwrap();
+///
+/// assert!(budget.used() <= budget.limit());
+/// for account in budget.usage() {
+///     println!("{}", account);
+/// }
+/// ```
+#[derive(Clone)]
+pub struct MemoryBudget {
+    state : Arc<BudgetState>,
+}
+
+impl MemoryBudget {
+    /// Allows `limit` records in memory at once.
+    pub fn new(limit : usize) -> Self {
+        MemoryBudget {
+            state : Arc::new(BudgetState {
+                limit,
+                spill : AtomicBool::new(false),
+                used : AtomicUsize::new(0),
+                accounts : Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    /// Allows `bytes` of records in memory at once.
+    pub fn from_bytes(bytes : usize) -> Self {
+        Self::new(bytes / std::mem::size_of::<u32>())
+    }
+
+    /// Sets what buffers do once the budget is spent, for every clone
+    /// of the budget.
+    pub fn with_policy(self, policy : BudgetPolicy) -> Self {
+        self.state.spill.store(policy == BudgetPolicy::Spill, Ordering::Release);
+        self
+    }
+
+    pub fn limit(&self) -> usize {
+        self.state.limit
+    }
+
+    pub fn policy(&self) -> BudgetPolicy {
+        match self.state.spill.load(Ordering::Acquire) {
+            true => BudgetPolicy::Spill,
+            false => BudgetPolicy::Drop,
+        }
+    }
+
+    /// Records held by all accounts
+    pub fn used(&self) -> usize {
+        self.state.used.load(Ordering::Acquire)
+    }
+
+    /// Records that can still be reserved
+    pub fn available(&self) -> usize {
+        self.limit().saturating_sub(self.used())
+    }
+
+    /// Every account opened on the budget, in the order opened,
+    /// including those since closed
+    pub fn usage(&self) -> Vec<BudgetUsage> {
+        self.accounts().clone()
+    }
+
+    /// Opens an account for a buffer, named `name` in `usage`. The
+    /// records it holds are returned to the budget when it is dropped.
+    pub fn account(&self, name : &str) -> BudgetAccount {
+        let mut accounts = self.accounts();
+        accounts.push(BudgetUsage { name : name.to_string(), held : 0, peak : 0, dropped : 0, spilled : 0 });
+        BudgetAccount { budget : self.clone(), index : accounts.len() - 1 }
+    }
+
+    fn accounts(&self) -> MutexGuard<'_, Vec<BudgetUsage>> {
+        self.state.accounts.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl std::fmt::Debug for MemoryBudget {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("MemoryBudget")
+            .field("limit", &self.limit())
+            .field("policy", &self.policy())
+            .field("used", &self.used())
+            .finish()
+    }
+}
+
+/// The share of a `MemoryBudget` held by one buffer.
+pub struct BudgetAccount {
+    budget : MemoryBudget,
+    index : usize,
+}
+
+impl BudgetAccount {
+    /// Reserves room for `records` more records, or nothing if that
+    /// would exceed the budget's limit.
+    pub fn try_reserve(&self, records : usize) -> bool {
+        let state = &self.budget.state;
+        let reserved = state.used.fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+            used.checked_add(records).filter(|total| *total <= state.limit)
+        });
+        if reserved.is_err() {
+            return false;
+        }
+        let mut accounts = self.budget.accounts();
+        let usage = &mut accounts[self.index];
+        usage.held += records;
+        usage.peak = usage.peak.max(usage.held);
+        true
+    }
+
+    /// Returns room for `records` records to the budget, at most as
+    /// many as the account holds.
+    pub fn release(&self, records : usize) {
+        let mut accounts = self.budget.accounts();
+        let usage = &mut accounts[self.index];
+        let records = records.min(usage.held);
+        usage.held -= records;
+        self.budget.state.used.fetch_sub(records, Ordering::AcqRel);
+    }
+
+    /// Returns everything the account holds to the budget.
+    pub fn release_all(&self) {
+        self.release(usize::MAX);
+    }
+
+    /// Records held by this account
+    pub fn held(&self) -> usize {
+        self.budget.accounts()[self.index].held
+    }
+
+    /// Counts `records` discarded for lack of budget.
+    pub fn record_dropped(&self, records : usize) {
+        self.budget.accounts()[self.index].dropped += records as u64;
+    }
+
+    /// Counts `records` moved to disk for lack of budget.
+    pub fn record_spilled(&self, records : usize) {
+        self.budget.accounts()[self.index].spilled += records as u64;
+    }
+
+    pub fn policy(&self) -> BudgetPolicy {
+        self.budget.policy()
+    }
+
+    pub fn usage(&self) -> BudgetUsage {
+        self.budget.accounts()[self.index].clone()
+    }
+}
+
+impl Drop for BudgetAccount {
+    fn drop(&mut self) {
+        self.release_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_budget() {
+        let budget = MemoryBudget::new(100);
+        let cache = budget.account("cache");
+        let channel = budget.account("channel");
+
+        assert!(cache.try_reserve(60));
+        assert!(!channel.try_reserve(50));
+        assert!(channel.try_reserve(40));
+        assert_eq!(budget.available(), 0);
+        channel.record_dropped(10);
+
+        cache.release(30);
+        assert_eq!(budget.used(), 70);
+        assert!(channel.try_reserve(30));
+        channel.release(1000);
+        assert_eq!(channel.held(), 0);
+        assert_eq!(budget.used(), 30);
+
+        let usage = budget.usage();
+        assert_eq!(usage[0], BudgetUsage { name : "cache".to_string(), held : 30, peak : 60, dropped : 0, spilled : 0 });
+        assert_eq!(usage[1].peak, 70);
+        assert_eq!(usage[1].dropped, 10);
+
+        drop(cache);
+        assert_eq!(budget.used(), 0);
+        assert_eq!(MemoryBudget::from_bytes(4096).limit(), 1024);
+        assert_eq!(EVENT_RECORDS, 4);
+    }
+}
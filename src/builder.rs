@@ -0,0 +1,415 @@
+//! Opens, initializes and configures a device in one call, so that an
+//! application never holds a device that is only partly set up.
+//! `MultiHarpDevice::open` initializes the device in T3 mode with the
+//! internal clock to query it; the builder initializes it again right
+//! away with the mode and clock asked for.
+//!
+//! `MultiHarpConfigBuilder` builds the `MultiHarpConfig` to apply one
+//! setting at a time, checking every value against the device's ranges
+//! before anything reaches a device.
+
+use std::fmt::{Debug, Display};
+use std::marker::PhantomData;
+
+use crate::error::{CheckedResult, MultiHarpError, PatinaError};
+use crate::limits::Limits;
+use crate::mhconsts::{self, MeasurementControlMode, MeasurementMode, ReferenceClock, TriggerEdge};
+use crate::model::DeviceModel;
+use crate::multiharp::MultiHarpDevice;
+use crate::transaction::TransactionError;
+use crate::MultiHarpConfig;
+
+/// The step of `DeviceBuilder::open` that failed. The device is closed
+/// again whenever it was opened.
+#[derive(Debug)]
+pub enum OpenError {
+    Open(PatinaError<i32>),
+    Init(MultiHarpError),
+    /// Applying the config failed, and the settings it changed were
+    /// rolled back before the device was closed
+    Configure(TransactionError),
+}
+
+impl Display for OpenError {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            OpenError::Open(e) => write!(f, "Opening the device failed: {}", e),
+            OpenError::Init(e) => write!(f, "Initializing the device failed: {}", e),
+            OpenError::Configure(e) => write!(f, "Configuring the device failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for OpenError {}
+
+/// Builds the device returned by `MultiHarpDevice::builder`.
+///
+/// ## Example
+///
+/// ```
+/// use multi_harp_patina::*;
+///
+/// let mh = DebugMultiHarp150::builder()
+///     .serial("1044272")
+///     .mode(MeasurementMode::T2)
+///     .config(MultiHarpConfig { sync_div : Some(4), ..Default::default() })
+///     .open()
+///     .unwrap();
+/// assert_eq!(mh.get_measurement_mode(), MeasurementMode::T2);
+/// assert_eq!(mh.current_settings().sync_div, Some(4));
+/// ```
+#[derive(Debug, Clone)]
+pub struct DeviceBuilder<MH> {
+    index : Option<i32>,
+    serial : Option<String>,
+    mode : MeasurementMode,
+    clock : ReferenceClock,
+    config : Option<MultiHarpConfig>,
+    device : PhantomData<fn() -> MH>,
+}
+
+impl<MH : MultiHarpDevice> Default for DeviceBuilder<MH> {
+    fn default() -> Self {
+        DeviceBuilder {
+            index : None,
+            serial : None,
+            mode : MeasurementMode::T3,
+            clock : ReferenceClock::Internal,
+            config : None,
+            device : PhantomData,
+        }
+    }
+}
+
+impl<MH : MultiHarpDevice> DeviceBuilder<MH> {
+    /// Opens the first available device in T3 mode with the internal
+    /// clock, unless told otherwise
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Opens the device with this serial number. Takes precedence over `index`.
+    pub fn serial(mut self, serial : &str) -> Self {
+        self.serial = Some(serial.to_string());
+        self
+    }
+
+    /// Opens the device at this index (0..7)
+    pub fn index(mut self, index : i32) -> Self {
+        self.index = Some(index);
+        self
+    }
+
+    pub fn mode(mut self, mode : MeasurementMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn clock(mut self, clock : ReferenceClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Settings applied after `init`, as one `with_settings_transaction`
+    pub fn config(mut self, config : MultiHarpConfig) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    /// Opens the device, initializes it and applies the config. Stops at
+    /// the first step that fails and closes the device again.
+    pub fn open(self) -> Result<MH, OpenError> {
+        let opened = match (&self.serial, self.index) {
+            (Some(serial), _) => MH::open_by_serial(serial),
+            (None, index) => MH::open(index),
+        };
+        // Dropping `multiharp` on an error below closes it
+        let mut multiharp = opened.map_err(OpenError::Open)?;
+        multiharp.init(self.mode, self.clock).map_err(OpenError::Init)?;
+        if let Some(config) = self.config {
+            multiharp.with_settings_transaction(|batch| *batch = config)
+                .map_err(OpenError::Configure)?;
+        }
+        Ok(multiharp)
+    }
+}
+
+/// Builds a `MultiHarpConfig` one setting at a time, returned by
+/// `MultiHarpConfig::builder`. Settings never called are left `None`,
+/// and the per channel settings add up, one channel per call.
+///
+/// ## Example
+///
+/// ```
+/// use multi_harp_patina::*;
+///
+/// let config = MultiHarpConfig::builder()
+///     .sync_div(2)
+///     .sync_trigger(-60, TriggerEdge::Falling)
+///     .input_edge(0, -100, TriggerEdge::Falling)
+///     .input_edge(1, -100, TriggerEdge::Falling)
+///     .input_enable(2, false)
+///     .binning(1)
+///     .build()
+///     .unwrap();
+/// assert_eq!(config.input_edges.unwrap().len(), 2);
+///
+/// assert!(MultiHarpConfig::builder().sync_div(32).build().is_err());
+/// ```
+#[derive(Debug, Clone)]
+pub struct MultiHarpConfigBuilder {
+    config : MultiHarpConfig,
+    limits : Limits,
+}
+
+impl Default for MultiHarpConfigBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Converts the `ArgumentError` of a `Limits::check` to one holding an `i64`
+fn widened<T : Into<i64> + Display + Debug>(checked : CheckedResult<(), T>) -> CheckedResult<(), i64> {
+    checked.map_err(|e| match e {
+        PatinaError::ArgumentError(name, value, message) => PatinaError::ArgumentError(name, value.into(), message),
+        _ => unreachable!("`Limits::check` only returns `ArgumentError`"),
+    })
+}
+
+fn push<T>(settings : &mut Option<Vec<T>>, setting : T) {
+    settings.get_or_insert_with(Vec::new).push(setting);
+}
+
+impl MultiHarpConfigBuilder {
+    /// Checks the settings against the ranges of any model
+    pub fn new() -> Self {
+        MultiHarpConfigBuilder {
+            config : MultiHarpConfig::default(),
+            limits : Limits::new(MeasurementMode::T3, None),
+        }
+    }
+
+    /// Checks the channel numbers against the channels of `model`
+    pub fn for_model(mut self, model : &DeviceModel) -> Self {
+        self.limits = Limits::new(MeasurementMode::T3, Some(model));
+        self
+    }
+
+    pub fn sync_div(mut self, div : i32) -> Self {
+        self.config.sync_div = Some(div);
+        self
+    }
+
+    /// Trigger level in mV and edge of the sync input
+    pub fn sync_trigger(mut self, level : i32, edge : TriggerEdge) -> Self {
+        self.config.sync_trigger_edge = Some((level, edge));
+        self
+    }
+
+    /// Sync channel offset in ps
+    pub fn sync_channel_offset(mut self, offset : i32) -> Self {
+        self.config.sync_channel_offset = Some(offset);
+        self
+    }
+
+    #[cfg(feature = "MHLv3_1_0")]
+    pub fn sync_channel_enable(mut self, enable : bool) -> Self {
+        self.config.sync_channel_enable = Some(enable);
+        self
+    }
+
+    /// Extended dead time of the sync input in ps, if `on`
+    pub fn sync_dead_time(mut self, on : bool, dead_time : i32) -> Self {
+        self.config.sync_dead_time = Some((on, dead_time));
+        self
+    }
+
+    /// Trigger level in mV and edge of input `channel`
+    pub fn input_edge(mut self, channel : i32, level : i32, edge : TriggerEdge) -> Self {
+        push(&mut self.config.input_edges, (channel, level, edge));
+        self
+    }
+
+    /// Offset of input `channel` in ps
+    pub fn input_offset(mut self, channel : i32, offset : i32) -> Self {
+        push(&mut self.config.input_offsets, (channel, offset));
+        self
+    }
+
+    pub fn input_enable(mut self, channel : i32, enable : bool) -> Self {
+        push(&mut self.config.input_enables, (channel, enable));
+        self
+    }
+
+    /// Extended dead time of input `channel` in ps, if `on`
+    pub fn input_dead_time(mut self, channel : i32, on : bool, dead_time : i32) -> Self {
+        push(&mut self.config.input_dead_times, (channel, on, dead_time));
+        self
+    }
+
+    #[cfg(feature = "MHLv3_0_0")]
+    pub fn input_hysteresis(mut self, hysteresis : bool) -> Self {
+        self.config.input_hysteresis = Some(hysteresis);
+        self
+    }
+
+    /// Stops the histogram measurement once a bin reaches `count`, if `stop`
+    pub fn stop_overflow(mut self, stop : bool, count : u32) -> Self {
+        self.config.stop_overflow = Some((stop, count));
+        self
+    }
+
+    pub fn binning(mut self, binning : i32) -> Self {
+        self.config.binning = Some(binning);
+        self
+    }
+
+    /// Histogram offset in ns
+    pub fn offset(mut self, offset : i32) -> Self {
+        self.config.offset = Some(offset);
+        self
+    }
+
+    /// Histogram length code
+    pub fn histo_len(mut self, length_code : i32) -> Self {
+        self.config.histo_len = Some(length_code);
+        self
+    }
+
+    pub fn meas_control(mut self, mode : MeasurementControlMode, start_edge : Option<TriggerEdge>, stop_edge : Option<TriggerEdge>) -> Self {
+        self.config.meas_control = Some((mode, start_edge, stop_edge));
+        self
+    }
+
+    /// Period of the trigger output in units of 100 ns, 0 is off
+    pub fn trigger_output(mut self, period : i32) -> Self {
+        self.config.trigger_output = Some(period);
+        self
+    }
+
+    /// Hold time of the overflow compression in ms
+    #[cfg(feature = "MHLv3_1_0")]
+    pub fn ofl_compression(mut self, hold_time : i32) -> Self {
+        self.config.ofl_compression = Some(hold_time);
+        self
+    }
+
+    pub fn marker_edges(mut self, edges : [TriggerEdge; 4]) -> Self {
+        self.config.marker_edges = Some(edges);
+        self
+    }
+
+    pub fn marker_enable(mut self, enables : [bool; 4]) -> Self {
+        self.config.marker_enable = Some(enables);
+        self
+    }
+
+    /// Marker holdoff time in ns
+    pub fn marker_holdoff(mut self, holdoff : i32) -> Self {
+        self.config.marker_holdoff = Some(holdoff);
+        self
+    }
+
+    /// Checks every setting against its range, returning the config or
+    /// the `ArgumentError` of the first setting out of range.
+    pub fn build(self) -> CheckedResult<MultiHarpConfig, i64> {
+        let limits = &self.limits;
+        let config = &self.config;
+        let channels = 0..=limits.max_channels - 1;
+        let check = |name : &str, value : Option<i32>, range : &std::ops::RangeInclusive<i32>| {
+            value.map_or(Ok(()), |value| widened(limits.check(name, value, range)))
+        };
+
+        check("sync_div", config.sync_div, &limits.sync_div)?;
+        check("sync_trigger_level", config.sync_trigger_edge.map(|(level, _)| level), &limits.trigger_level)?;
+        check("sync_channel_offset", config.sync_channel_offset, &limits.channel_offset)?;
+        check("sync_dead_time", config.sync_dead_time.filter(|(on, _)| *on).map(|(_, time)| time), &limits.dead_time)?;
+        for (channel, level, _) in config.input_edges.iter().flatten() {
+            check("channel", Some(*channel), &channels)?;
+            check("input_trigger_level", Some(*level), &limits.trigger_level)?;
+        }
+        for (channel, offset) in config.input_offsets.iter().flatten() {
+            check("channel", Some(*channel), &channels)?;
+            check("input_offset", Some(*offset), &limits.channel_offset)?;
+        }
+        for (channel, _) in config.input_enables.iter().flatten() {
+            check("channel", Some(*channel), &channels)?;
+        }
+        for (channel, on, dead_time) in config.input_dead_times.iter().flatten() {
+            check("channel", Some(*channel), &channels)?;
+            check("input_dead_time", Some(*dead_time).filter(|_| *on), &limits.dead_time)?;
+        }
+        if let Some((true, count)) = config.stop_overflow {
+            widened(limits.check("stop_count", count, &limits.stop_count))?;
+        }
+        check("binning", config.binning, &limits.binning)?;
+        check("offset", config.offset, &limits.offset)?;
+        check("histo_len", config.histo_len, &limits.length_code)?;
+        check("trigger_output", config.trigger_output, &limits.trigger_output)?;
+        #[cfg(feature = "MHLv3_1_0")]
+        check("ofl_compression", config.ofl_compression, &(mhconsts::HOLDTIMEMIN..=mhconsts::HOLDTIMEMAX))?;
+        let holdoff = limits.holdoff.clone().unwrap_or(mhconsts::HOLDOFFMIN..=mhconsts::HOLDOFFMAX);
+        check("marker_holdoff", config.marker_holdoff, &holdoff)?;
+        Ok(self.config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DebugMultiHarp150;
+
+    #[test]
+    fn test_builder() {
+        let mh = DebugMultiHarp150::builder().index(3).clock(ReferenceClock::External).open().unwrap();
+        assert_eq!(mh.get_index(), 3);
+        assert_eq!(mh.get_measurement_mode(), MeasurementMode::T3);
+        // The index is in use until `mh` is dropped
+        assert!(matches!(DebugMultiHarp150::builder().index(3).open(), Err(OpenError::Open(_))));
+        drop(mh);
+
+        let error = match DebugMultiHarp150::builder()
+            .index(3)
+            .config(MultiHarpConfig { sync_div : Some(2), binning : Some(1000), ..Default::default() })
+            .open() {
+            Err(OpenError::Configure(e)) => e,
+            _ => panic!("binning 1000 should fail"),
+        };
+        assert_eq!(error.setting, "binning");
+        let error = OpenError::Configure(error);
+        assert!(error.to_string().starts_with("Configuring the device failed"));
+        // Closed again after failing
+        assert!(DebugMultiHarp150::builder().index(3).open().is_ok());
+    }
+
+    #[test]
+    fn test_config_builder() {
+        let config = MultiHarpConfig::builder()
+            .sync_div(4)
+            .sync_dead_time(false, 0)
+            .input_edge(0, -100, TriggerEdge::Falling)
+            .input_offset(3, 2500)
+            .input_offset(1, -2500)
+            .marker_enable([true, false, false, false])
+            .build()
+            .unwrap();
+        assert_eq!(config, MultiHarpConfig {
+            sync_div : Some(4),
+            sync_dead_time : Some((false, 0)),
+            input_edges : Some(vec![(0, -100, TriggerEdge::Falling)]),
+            input_offsets : Some(vec![(3, 2500), (1, -2500)]),
+            marker_enable : Some([true, false, false, false]),
+            ..Default::default()
+        });
+
+        let error = MultiHarpConfig::builder().binning(2).input_edge(0, -1500, TriggerEdge::Rising).build().unwrap_err();
+        assert_eq!(error, PatinaError::ArgumentError(
+            "input_trigger_level".to_string(), -1500, "input_trigger_level must be between -1200 and 1200".to_string()
+        ));
+        assert!(MultiHarpConfig::builder().stop_overflow(true, 0).build().is_err());
+        assert!(MultiHarpConfig::builder().input_dead_time(0, true, 100).build().is_err());
+        // Channel 4 only exists on 8 channel models
+        assert!(MultiHarpConfig::builder().input_enable(4, true).build().is_ok());
+        assert!(MultiHarpConfig::builder().for_model(&DeviceModel::MultiHarp150_4P).input_enable(4, true).build().is_err());
+    }
+}
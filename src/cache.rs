@@ -0,0 +1,381 @@
+//! Keeps the decoded events of the current acquisition so they can be
+//! queried by time and channel as soon as it ends, without writing and
+//! re-reading a file. Memory use is bounded: past a set number of
+//! events, or once a shared `MemoryBudget` is spent, the cache spills to
+//! disk or stops recording.
+
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use crate::budget::{BudgetAccount, BudgetPolicy, MemoryBudget, EVENT_RECORDS};
+use crate::pipeline::{Batch, ProcessorResult, StreamInfo, StreamProcessor};
+use crate::tttr::{Decoder, EventKind, TttrEvent};
+
+/// Bytes per event in the spill file
+const SPILLED_EVENT_LEN : usize = 16;
+
+/// Events reserved from a `MemoryBudget` at a time
+const BUDGET_CHUNK : usize = 4096;
+
+/// A run of events written to the spill file
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct SpilledChunk {
+    offset : u64,
+    len : usize,
+    first_time : u64,
+    last_time : u64,
+}
+
+fn encode_event(event : &TttrEvent, out : &mut [u8]) {
+    out[..8].copy_from_slice(&event.time.to_le_bytes());
+    out[8..10].copy_from_slice(&event.dtime.to_le_bytes());
+    out[10] = event.channel;
+    out[11] = match event.kind {
+        EventKind::Photon => 0,
+        EventKind::Marker => 1,
+        EventKind::Sync => 2,
+    };
+    out[12..].fill(0);
+}
+
+fn decode_event(bytes : &[u8]) -> TttrEvent {
+    TttrEvent {
+        kind : match bytes[11] {
+            0 => EventKind::Photon,
+            1 => EventKind::Marker,
+            _ => EventKind::Sync,
+        },
+        channel : bytes[10],
+        dtime : u16::from_le_bytes([bytes[8], bytes[9]]),
+        time : u64::from_le_bytes(bytes[..8].try_into().unwrap()),
+    }
+}
+
+/// Decodes the stream of an acquisition and keeps every event for
+/// `query`. Events are held in memory up to `capacity`; beyond that they
+/// are moved to a spill file if one was set with `with_spill_dir`, and
+/// otherwise the rest of the acquisition is not recorded (see `dropped`).
+/// The cache is cleared at the start of each acquisition.
+///
+/// ## Example
+///
+/// ```
+/// use multi_harp_patina::*;
+///
+/// let mut mh = DebugMultiHarp150::new(1e5, 80e6, None);
+/// mh.init(MeasurementMode::T3, ReferenceClock::Internal).unwrap();
+///
+/// let mut pipeline = Pipeline::new();
+/// pipeline.add(EventCache::new(1_000_000).with_spill_dir(std::env::temp_dir()));
+/// pipeline.run(&mut mh, 200).unwrap();
+/// ```
+pub struct EventCache {
+    capacity : usize,
+    spill_dir : Option<PathBuf>,
+    spill_path : Option<PathBuf>,
+    spill : Option<BufWriter<File>>,
+    chunks : Vec<SpilledChunk>,
+    spilled_bytes : u64,
+    memory : Vec<TttrEvent>,
+    decoder : Option<Decoder>,
+    dropped : u64,
+    budget : Option<BudgetAccount>,
+    /// Events `budget` holds room for
+    reserved : usize,
+}
+
+impl EventCache {
+    /// Keeps up to `capacity` events in memory.
+    pub fn new(capacity : usize) -> Self {
+        EventCache {
+            capacity : capacity.max(1),
+            spill_dir : None,
+            spill_path : None,
+            spill : None,
+            chunks : Vec::new(),
+            spilled_bytes : 0,
+            memory : Vec::new(),
+            decoder : None,
+            dropped : 0,
+            budget : None,
+            reserved : 0,
+        }
+    }
+
+    /// Spills events beyond the capacity to a file in `directory`,
+    /// removed when the next acquisition starts or the cache is dropped.
+    pub fn with_spill_dir<P : AsRef<Path>>(mut self, directory : P) -> Self {
+        self.spill_dir = Some(directory.as_ref().to_path_buf());
+        self
+    }
+
+    /// Counts the events in memory against `budget`, as `EVENT_RECORDS`
+    /// records each. Once it is spent, events are spilled if the budget's
+    /// policy is `BudgetPolicy::Spill` and there is a spill directory, and
+    /// dropped otherwise.
+    pub fn with_memory_budget(mut self, budget : &MemoryBudget) -> Self {
+        self.budget = Some(budget.account("event_cache"));
+        self
+    }
+
+    /// Events recorded in the current (or last) acquisition
+    pub fn len(&self) -> usize {
+        self.memory.len() + self.spilled()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Events moved to the spill file
+    pub fn spilled(&self) -> usize {
+        self.chunks.iter().map(|c| c.len).sum()
+    }
+
+    /// Events that arrived after the cache or its memory budget was
+    /// full, with no spill file
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Photon records on channels that aren't enabled, left out of the cache
+    pub fn anomalies(&self) -> u64 {
+        self.decoder.as_ref().map(|d| d.anomalies()).unwrap_or(0)
+    }
+
+    /// Every event with `time` in `range` (syncs in T3, resolution units
+    /// in T2), in time order. With `channel`, only the photons on that
+    /// channel.
+    pub fn query(&self, range : Range<u64>, channel : Option<u8>) -> io::Result<Vec<TttrEvent>> {
+        let wanted = |event : &TttrEvent| match channel {
+            Some(channel) => event.kind == EventKind::Photon && event.channel == channel,
+            None => true,
+        };
+        let mut events = Vec::new();
+
+        let overlapping = self.chunks.iter()
+            .filter(|c| c.first_time < range.end && c.last_time >= range.start)
+            .collect::<Vec<_>>();
+        if !overlapping.is_empty() {
+            let path = self.spill_path.as_ref()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Spill file missing"))?;
+            let mut reader = BufReader::new(File::open(path)?);
+            let mut bytes = Vec::new();
+            for chunk in overlapping {
+                bytes.resize(chunk.len * SPILLED_EVENT_LEN, 0);
+                reader.seek(SeekFrom::Start(chunk.offset))?;
+                reader.read_exact(&mut bytes)?;
+                events.extend(bytes.chunks_exact(SPILLED_EVENT_LEN)
+                    .map(decode_event)
+                    .filter(|e| range.contains(&e.time) && wanted(e)));
+            }
+        }
+
+        let start = self.memory.partition_point(|e| e.time < range.start);
+        let end = self.memory.partition_point(|e| e.time < range.end);
+        events.extend(self.memory[start..end.max(start)].iter().filter(|e| wanted(e)));
+        Ok(events)
+    }
+
+    /// Moves the events in memory to the spill file, opening it if needed.
+    fn spill_memory(&mut self) -> io::Result<()> {
+        if self.spill.is_none() {
+            let directory = self.spill_dir.as_ref()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "No spill directory"))?;
+            let path = directory.join(format!("event_cache_{}_{:p}.bin", std::process::id(), self));
+            self.spill = Some(BufWriter::new(File::create(&path)?));
+            self.spill_path = Some(path);
+        }
+        let writer = self.spill.as_mut().unwrap();
+
+        let mut bytes = vec![0u8; self.memory.len() * SPILLED_EVENT_LEN];
+        for (event, out) in self.memory.iter().zip(bytes.chunks_exact_mut(SPILLED_EVENT_LEN)) {
+            encode_event(event, out);
+        }
+        writer.write_all(&bytes)?;
+        writer.flush()?;
+
+        self.chunks.push(SpilledChunk {
+            offset : self.spilled_bytes,
+            len : self.memory.len(),
+            first_time : self.memory.first().map(|e| e.time).unwrap_or(0),
+            last_time : self.memory.last().map(|e| e.time).unwrap_or(0),
+        });
+        self.spilled_bytes += bytes.len() as u64;
+        self.memory.clear();
+        self.release_budget();
+        Ok(())
+    }
+
+    /// Makes sure the budget holds room for one more event in memory.
+    fn reserve_event(&mut self) -> bool {
+        let budget = match self.budget.as_ref() {
+            Some(budget) if self.reserved <= self.memory.len() => budget,
+            _ => return true,
+        };
+        let chunk = BUDGET_CHUNK.min(self.capacity - self.memory.len()).max(1);
+        for events in [chunk, 1] {
+            if budget.try_reserve(events * EVENT_RECORDS) {
+                self.reserved += events;
+                return true;
+            }
+        }
+        false
+    }
+
+    fn release_budget(&mut self) {
+        if let Some(budget) = self.budget.as_ref() {
+            budget.release_all();
+        }
+        self.reserved = 0;
+    }
+
+    fn remove_spill_file(&mut self) {
+        self.spill.take();
+        if let Some(path) = self.spill_path.take() {
+            let _ = std::fs::remove_file(path);
+        }
+        self.chunks.clear();
+        self.spilled_bytes = 0;
+    }
+}
+
+impl StreamProcessor for EventCache {
+    fn name(&self) -> &str {
+        "event_cache"
+    }
+
+    fn init(&mut self, info : &StreamInfo) -> ProcessorResult {
+        self.remove_spill_file();
+        self.memory.clear();
+        self.release_budget();
+        self.dropped = 0;
+        self.decoder = Some(Decoder::for_stream(info)?);
+        Ok(())
+    }
+
+    fn process_batch(&mut self, batch : &Batch) -> ProcessorResult {
+        let mut decoder = self.decoder.take().ok_or("`process_batch` called before `init`")?;
+        let mut result = Ok(());
+        for record in batch.records.iter() {
+            let event = match decoder.decode(*record) {
+                Some(event) => event,
+                None => continue,
+            };
+            let over_budget = self.memory.len() < self.capacity && !self.reserve_event();
+            if self.memory.len() == self.capacity || over_budget {
+                let spill = self.spill_dir.is_some()
+                    && (!over_budget || self.budget.as_ref().map(|b| b.policy()) == Some(BudgetPolicy::Spill));
+                if spill {
+                    let spilled = self.memory.len();
+                    if let Err(e) = self.spill_memory() {
+                        result = Err(format!("Spilling the event cache: {}", e));
+                        break;
+                    }
+                    if let (true, Some(budget)) = (over_budget, self.budget.as_ref()) {
+                        budget.record_spilled(spilled * EVENT_RECORDS);
+                    }
+                }
+                // The rest of the budget may be held by other buffers
+                if !spill || !self.reserve_event() {
+                    self.dropped += 1;
+                    if let (true, Some(budget)) = (over_budget, self.budget.as_ref()) {
+                        budget.record_dropped(EVENT_RECORDS);
+                    }
+                    continue;
+                }
+            }
+            self.memory.push(event);
+        }
+        self.decoder = Some(decoder);
+        result
+    }
+}
+
+impl Drop for EventCache {
+    fn drop(&mut self) {
+        self.remove_spill_file();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+    use crate::mhconsts::MeasurementMode;
+    use crate::tttr::{pack_t3, OVERFLOW_CHANNEL};
+    use crate::testing::fixtures::stream_info;
+
+    fn fill(cache : &mut EventCache) {
+        cache.init(&stream_info(MeasurementMode::T3)).unwrap();
+        // Photons at syncs 0..10 on alternating channels, a marker at 5,
+        // then an overflow and photons at 1024..1030
+        let mut records = (0..10).map(|n| pack_t3(false, (n % 2) as u8, 100 + n, n)).collect::<Vec<_>>();
+        records.insert(5, pack_t3(true, 1, 0, 5));
+        records.push(pack_t3(true, OVERFLOW_CHANNEL, 0, 1));
+        records.extend((0..6).map(|n| pack_t3(false, 2, 0, n)));
+        cache.process_batch(&Batch { index : 0, read_at : Instant::now(), records : &records, mode : MeasurementMode::T3, sync_lost : false }).unwrap();
+    }
+
+    #[test]
+    fn test_event_cache_spill() {
+        let mut cache = EventCache::new(4).with_spill_dir(std::env::temp_dir());
+        fill(&mut cache);
+        assert_eq!(cache.len(), 17);
+        assert_eq!(cache.spilled(), 16);
+        assert_eq!(cache.dropped(), 0);
+
+        let all = cache.query(0..u64::MAX, None).unwrap();
+        assert_eq!(all.len(), 17);
+        assert!(all.windows(2).all(|w| w[0].time <= w[1].time));
+
+        let channel_1 = cache.query(2..8, Some(1)).unwrap();
+        assert_eq!(channel_1, vec![
+            TttrEvent::photon(1, 3, 103), TttrEvent::photon(1, 5, 105), TttrEvent::photon(1, 7, 107),
+        ]);
+        assert_eq!(cache.query(1024..1026, Some(2)).unwrap().len(), 2);
+        assert_eq!(cache.query(5..6, None).unwrap(), vec![TttrEvent::marker(1, 5), TttrEvent::photon(1, 5, 105)]);
+
+        let path = cache.spill_path.clone().unwrap();
+        assert!(path.exists());
+        drop(cache);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_event_cache_budget() {
+        let budget = MemoryBudget::new(6 * EVENT_RECORDS);
+        let other = budget.account("other");
+        assert!(other.try_reserve(2 * EVENT_RECORDS));
+
+        let mut cache = EventCache::new(100).with_memory_budget(&budget);
+        fill(&mut cache);
+        assert_eq!(cache.len(), 4);
+        assert_eq!(cache.dropped(), 13);
+        assert_eq!(budget.used(), budget.limit());
+        assert_eq!(budget.usage()[1].dropped, 13 * EVENT_RECORDS as u64);
+        drop(cache);
+        assert_eq!(budget.used(), 2 * EVENT_RECORDS);
+
+        let budget = budget.with_policy(BudgetPolicy::Spill);
+        let mut cache = EventCache::new(100).with_memory_budget(&budget).with_spill_dir(std::env::temp_dir());
+        fill(&mut cache);
+        assert_eq!(cache.len(), 17);
+        assert_eq!(cache.dropped(), 0);
+        assert!(budget.usage()[2].spilled > 0);
+        assert_eq!(cache.query(0..u64::MAX, None).unwrap().len(), 17);
+        drop(cache);
+        assert_eq!(budget.used(), 2 * EVENT_RECORDS);
+    }
+
+    #[test]
+    fn test_event_cache_without_spill() {
+        let mut cache = EventCache::new(4);
+        fill(&mut cache);
+        assert_eq!(cache.len(), 4);
+        assert_eq!(cache.dropped(), 13);
+        assert_eq!(cache.query(0..4, None).unwrap().len(), 4);
+    }
+}
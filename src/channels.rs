@@ -0,0 +1,175 @@
+//! Names for the input channels of each device ("GFP", "RFP",
+//! "APD-east"), so that datasets describe what was detected rather than
+//! the index of a cable that may since have moved.
+//!
+//! A `ChannelMap` is set per serial number, process-wide, with
+//! `set_channel_map`. `Pipeline` adds it to the labels of every
+//! acquisition, as `channel.<n>` labels, so each sink records it with
+//! its metadata, and histogram CSV columns, OME-TIFF channels and alarm
+//! messages use the names.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Display;
+use std::sync::Mutex;
+
+use crate::metadata::Labels;
+
+/// Prefix of the labels holding channel names, followed by the channel
+pub const CHANNEL_LABEL_PREFIX : &str = "channel.";
+
+static CHANNEL_MAPS : Mutex<Option<HashMap<String, ChannelMap>>> = Mutex::new(None);
+
+/// Logical names of hardware input channels (from 0).
+///
+/// ## Example
+///
+/// ```
+/// use multi_harp_patina::*;
+///
+/// let mut map = ChannelMap::new();
+/// map.insert(0, "GFP").insert(2, "RFP");
+/// assert_eq!(map.label(0), "GFP");
+/// assert_eq!(map.label(1), "ch1");
+/// assert_eq!(map.channel("RFP"), Some(2));
+///
+/// // Recorded with every acquisition of the device from now on
+/// set_channel_map("1044272", map);
+/// let mut mh = DebugMultiHarp150::default();
+/// mh.init(MeasurementMode::T3, ReferenceClock::Internal).unwrap();
+/// let mut pipeline = Pipeline::new();
+/// let manifest = std::env::temp_dir().join("channel_map_manifest.json");
+/// pipeline.add(ManifestWriter::new(&manifest));
+/// pipeline.run(&mut mh, 50).unwrap();
+/// assert!(std::fs::read_to_string(&manifest).unwrap().contains("\"channel.0\":\"GFP\""));
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChannelMap {
+    names : BTreeMap<u8, String>,
+}
+
+impl ChannelMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Names `channel`, replacing any previous name.
+    pub fn insert<S : Into<String>>(&mut self, channel : u8, name : S) -> &mut Self {
+        self.names.insert(channel, name.into());
+        self
+    }
+
+    pub fn remove(&mut self, channel : u8) -> Option<String> {
+        self.names.remove(&channel)
+    }
+
+    /// The name of `channel`, if it has one
+    pub fn name(&self, channel : u8) -> Option<&str> {
+        self.names.get(&channel).map(|name| name.as_str())
+    }
+
+    /// The name of `channel`, or `ch<n>` if it has none
+    pub fn label(&self, channel : u8) -> String {
+        self.name(channel).map_or_else(|| format!("ch{}", channel), |name| name.to_string())
+    }
+
+    /// The channel named `name`
+    pub fn channel(&self, name : &str) -> Option<u8> {
+        self.names.iter().find(|(_, n)| n.as_str() == name).map(|(channel, _)| *channel)
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    /// Named channels in order
+    pub fn iter(&self) -> impl Iterator<Item = (u8, &str)> {
+        self.names.iter().map(|(channel, name)| (*channel, name.as_str()))
+    }
+
+    /// Adds a `channel.<n>` label per named channel to `labels`, keeping
+    /// any already there.
+    pub fn add_to_labels(&self, labels : &mut Labels) {
+        for (channel, name) in self.iter() {
+            let key = format!("{}{}", CHANNEL_LABEL_PREFIX, channel);
+            if labels.get(&key).is_none() {
+                labels.insert(key, name);
+            }
+        }
+    }
+
+    /// The names in the `channel.<n>` labels of `labels`
+    pub fn from_labels(labels : &Labels) -> Self {
+        labels.iter()
+            .filter_map(|(key, name)| {
+                let channel = key.strip_prefix(CHANNEL_LABEL_PREFIX)?.parse::<u8>().ok()?;
+                Some((channel, name.to_string()))
+            })
+            .collect()
+    }
+}
+
+impl<S : Into<String>> FromIterator<(u8, S)> for ChannelMap {
+    fn from_iter<I : IntoIterator<Item = (u8, S)>>(iter : I) -> Self {
+        let mut map = ChannelMap::new();
+        for (channel, name) in iter {
+            map.insert(channel, name);
+        }
+        map
+    }
+}
+
+impl Display for ChannelMap {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        let names = self.iter().map(|(channel, name)| format!("{} = {}", channel, name)).collect::<Vec<_>>();
+        write!(f, "{}", names.join(", "))
+    }
+}
+
+/// Sets the channel names of the device `serial`, replacing any before.
+pub fn set_channel_map(serial : &str, map : ChannelMap) {
+    let mut maps = CHANNEL_MAPS.lock().unwrap_or_else(|e| e.into_inner());
+    maps.get_or_insert_with(HashMap::new).insert(serial.to_string(), map);
+}
+
+/// The channel names of the device `serial`, empty if none were set
+pub fn channel_map(serial : &str) -> ChannelMap {
+    let maps = CHANNEL_MAPS.lock().unwrap_or_else(|e| e.into_inner());
+    maps.as_ref().and_then(|maps| maps.get(serial)).cloned().unwrap_or_default()
+}
+
+pub fn clear_channel_map(serial : &str) {
+    let mut maps = CHANNEL_MAPS.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(maps) = maps.as_mut() {
+        maps.remove(serial);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_channel_map() {
+        let map : ChannelMap = [(3, "APD-east"), (0, "GFP")].into_iter().collect();
+        assert_eq!(map.to_string(), "0 = GFP, 3 = APD-east");
+        assert_eq!(map.channel("GFP"), Some(0));
+        assert_eq!(map.channel("RFP"), None);
+
+        // Labels set by hand win over the map
+        let mut labels = Labels::new();
+        labels.insert("channel.0", "EGFP").insert("sample", "fly_03");
+        map.add_to_labels(&mut labels);
+        assert_eq!(labels.get("channel.3"), Some("APD-east"));
+        let from_labels = ChannelMap::from_labels(&labels);
+        assert_eq!(from_labels.iter().collect::<Vec<_>>(), vec![(0, "EGFP"), (3, "APD-east")]);
+
+        set_channel_map("channel_map_test", map.clone());
+        assert_eq!(channel_map("channel_map_test"), map);
+        clear_channel_map("channel_map_test");
+        assert!(channel_map("channel_map_test").is_empty());
+    }
+}
@@ -0,0 +1,339 @@
+//! Interprets the device flags relating to the reference clock,
+//! so that acquisitions referenced to an external 10 MHz or GPS/PPS
+//! signal can be checked before (and during) a measurement.
+//!
+//! Also maps device time onto the host clock: a `ClockMapper` samples
+//! `get_elapsed_measurement_time` against the host's monotonic clock and
+//! fits a `ClockMapping`, so photon times can be converted to host wall
+//! clock time to line up with cameras and behavior rigs.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, TryLockError, mpsc};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::error::{MultiHarpError, MultiHarpResult};
+use crate::mhconsts::{self, ReferenceClock};
+use crate::MultiHarpDevice;
+
+/// State of the reference clock, read from `get_flags`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClockStatus {
+    /// The reference the device was initialized with
+    pub reference : ReferenceClock,
+    /// `FLAG_REF_LOST` -- the external reference (or its PPS) is absent
+    /// and the device is running from its internal oscillator
+    pub ref_lost : bool,
+    /// `FLAG_SYNC_LOST` -- no sync signal on the sync input
+    pub sync_lost : bool,
+}
+
+impl ClockStatus {
+    /// Builds the status from the bitmask returned by `get_flags`.
+    pub fn from_flags(reference : ReferenceClock, flags : i32) -> Self {
+        ClockStatus {
+            reference,
+            ref_lost : flags & mhconsts::FLAG_REF_LOST != 0,
+            sync_lost : flags & mhconsts::FLAG_SYNC_LOST != 0,
+        }
+    }
+
+    /// Whether the device is actually locked to the reference it was
+    /// initialized with. Always `true` for the internal clock, and for
+    /// White Rabbit references, whose status `get_flags` doesn't report.
+    pub fn is_locked(&self) -> bool {
+        !(self.reference.uses_external_reference() && self.ref_lost)
+    }
+}
+
+/// One reading of the device's elapsed measurement time with the host
+/// time it was taken at.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClockSample {
+    /// `get_elapsed_measurement_time`, in ms
+    pub device_ms : f64,
+    /// Midway between the host times before and after the call
+    pub host : Instant,
+}
+
+impl ClockSample {
+    /// Reads the elapsed measurement time of `multiharp` now.
+    pub fn read<MH : MultiHarpDevice>(multiharp : &MH) -> MultiHarpResult<Self> {
+        let before = Instant::now();
+        let device_ms = multiharp.get_elapsed_measurement_time()?;
+        let after = Instant::now();
+        Ok(ClockSample { device_ms, host : before + (after - before) / 2 })
+    }
+}
+
+/// A linear fit of host time against device time,
+/// `host = origin + offset + slope * device`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClockMapping {
+    /// Host monotonic time the fit is relative to
+    pub origin : Instant,
+    /// Wall clock time at `origin`
+    pub origin_wall : SystemTime,
+    /// Host seconds after `origin` at the start of the measurement
+    pub offset : f64,
+    /// Host seconds per device second
+    pub slope : f64,
+    /// Root mean square residual of the samples, in seconds
+    pub residual : f64,
+    pub samples : usize,
+}
+
+impl ClockMapping {
+    /// Least squares fit of `samples`, relative to the host time `origin`
+    /// read at the same moment as the wall clock time `origin_wall`.
+    /// `None` for fewer than two samples or if the device time doesn't
+    /// advance.
+    pub fn fit(samples : &[ClockSample], origin : Instant, origin_wall : SystemTime) -> Option<Self> {
+        let n = samples.len() as f64;
+        let points = samples.iter()
+            .map(|s| (s.device_ms * 1e-3, signed_seconds(s.host, origin)))
+            .collect::<Vec<_>>();
+        let mean_x = points.iter().map(|p| p.0).sum::<f64>() / n;
+        let mean_y = points.iter().map(|p| p.1).sum::<f64>() / n;
+        let sxx = points.iter().map(|p| (p.0 - mean_x).powi(2)).sum::<f64>();
+        let sxy = points.iter().map(|p| (p.0 - mean_x) * (p.1 - mean_y)).sum::<f64>();
+        if samples.len() < 2 || sxx <= 0.0 {
+            return None;
+        }
+        let slope = sxy / sxx;
+        let offset = mean_y - slope * mean_x;
+        let residual = (points.iter()
+            .map(|p| (p.1 - offset - slope * p.0).powi(2))
+            .sum::<f64>() / n).sqrt();
+        Some(ClockMapping { origin, origin_wall, offset, slope, residual, samples : samples.len() })
+    }
+
+    /// How fast the device clock runs relative to the host's, in ppm
+    pub fn drift_ppm(&self) -> f64 {
+        (1.0 / self.slope - 1.0) * 1e6
+    }
+
+    /// Host seconds after `origin` for `device` seconds into the measurement
+    fn host_seconds(&self, device : f64) -> f64 {
+        self.offset + self.slope * device
+    }
+
+    /// The host monotonic time of `device` seconds into the measurement
+    pub fn to_instant(&self, device : f64) -> Instant {
+        let seconds = self.host_seconds(device);
+        if seconds >= 0.0 {
+            self.origin + Duration::from_secs_f64(seconds)
+        } else {
+            self.origin - Duration::from_secs_f64(-seconds)
+        }
+    }
+
+    /// The host wall clock time of `device` seconds into the measurement
+    pub fn to_system_time(&self, device : f64) -> SystemTime {
+        let seconds = self.host_seconds(device);
+        if seconds >= 0.0 {
+            self.origin_wall + Duration::from_secs_f64(seconds)
+        } else {
+            self.origin_wall - Duration::from_secs_f64(-seconds)
+        }
+    }
+
+    /// The wall clock time of a photon `picoseconds` after the start of
+    /// the measurement (e.g. a T2 time times the resolution)
+    pub fn photon_time(&self, picoseconds : f64) -> SystemTime {
+        self.to_system_time(picoseconds * 1e-12)
+    }
+}
+
+/// `later - earlier` in seconds, negative if `later` is earlier
+fn signed_seconds(later : Instant, earlier : Instant) -> f64 {
+    match later.checked_duration_since(earlier) {
+        Some(d) => d.as_secs_f64(),
+        None => -earlier.duration_since(later).as_secs_f64(),
+    }
+}
+
+type ClockSamples = Arc<Mutex<VecDeque<ClockSample>>>;
+
+/// Samples the elapsed measurement time of a device on a background
+/// thread, keeping the last samples of the current measurement to fit a
+/// `ClockMapping`. Samples are only kept while the device time advances;
+/// when it goes back (a new measurement) the old ones are dropped.
+///
+/// As with `RateRecorder`, the device is shared through a `Mutex` and a
+/// sample is skipped rather than waited for while another thread holds it.
+///
+/// ## Example
+///
+/// ```
+/// use std::sync::{Arc, Mutex};
+/// use std::time::Duration;
+/// use multi_harp_patina::*;
+///
+/// let mh = Arc::new(Mutex::new(DebugMultiHarp150::default()));
+/// mh.lock().unwrap().init(MeasurementMode::T3, ReferenceClock::Internal).unwrap();
+/// mh.lock().unwrap().start_measurement(1000).unwrap();
+/// let mapper = ClockMapper::start(Arc::clone(&mh), Duration::from_millis(20), 100);
+/// std::thread::sleep(Duration::from_millis(200));
+/// let mapping = mapper.stop().unwrap().unwrap();
+/// mh.lock().unwrap().stop_measurement().unwrap();
+/// println!("Photon at 50 ms recorded at {:?}", mapping.photon_time(50e9));
+/// ```
+pub struct ClockMapper {
+    /// Host monotonic and wall clock times read together at `start`
+    origin : (Instant, SystemTime),
+    stop : Option<mpsc::Sender<()>>,
+    samples : ClockSamples,
+    thread : Option<JoinHandle<MultiHarpResult<()>>>,
+}
+
+impl ClockMapper {
+    /// Starts sampling `multiharp` every `interval`, fitting over the last
+    /// `capacity` samples (at least 2).
+    pub fn start<MH>(multiharp : Arc<Mutex<MH>>, interval : Duration, capacity : usize) -> Self
+    where MH : MultiHarpDevice + Send + 'static {
+        let capacity = capacity.max(2);
+        let origin = (Instant::now(), SystemTime::now());
+        let samples : ClockSamples = Arc::new(Mutex::new(VecDeque::with_capacity(capacity)));
+        let (stop, stopped) = mpsc::channel::<()>();
+
+        let buffer = Arc::clone(&samples);
+        let thread = std::thread::spawn(move || {
+            loop {
+                let sample = match multiharp.try_lock() {
+                    Ok(mh) => Some(ClockSample::read(&*mh)?),
+                    Err(TryLockError::WouldBlock) => None,
+                    Err(TryLockError::Poisoned(_)) => return Err(MultiHarpError::ThreadStateFail),
+                };
+                if let Some(sample) = sample {
+                    let mut samples = buffer.lock().map_err(|_| MultiHarpError::ThreadStateFail)?;
+                    // A new measurement
+                    if samples.back().map_or(false, |last| sample.device_ms < last.device_ms) {
+                        samples.clear();
+                    }
+                    // Skips readings before the start (0) or after the end
+                    if sample.device_ms > samples.back().map_or(0.0, |last| last.device_ms) {
+                        if samples.len() == capacity {
+                            samples.pop_front();
+                        }
+                        samples.push_back(sample);
+                    }
+                }
+
+                // Wakes early if `stop` is called or the mapper is dropped
+                match stopped.recv_timeout(interval) {
+                    Err(mpsc::RecvTimeoutError::Timeout) => {},
+                    _ => return Ok(()),
+                }
+            }
+        });
+
+        ClockMapper { origin, stop : Some(stop), samples, thread : Some(thread) }
+    }
+
+    /// Whether the mapper is still sampling
+    pub fn is_running(&self) -> bool {
+        self.thread.as_ref().map(|t| !t.is_finished()).unwrap_or(false)
+    }
+
+    /// The samples currently kept, oldest first
+    pub fn samples(&self) -> Vec<ClockSample> {
+        self.samples.lock().map(|s| s.iter().copied().collect()).unwrap_or_default()
+    }
+
+    /// The fit of the samples kept so far, if there are enough
+    pub fn mapping(&self) -> Option<ClockMapping> {
+        ClockMapping::fit(&self.samples(), self.origin.0, self.origin.1)
+    }
+
+    /// Stops sampling and returns the final mapping, or the error that
+    /// stopped the mapper early.
+    pub fn stop(mut self) -> MultiHarpResult<Option<ClockMapping>> {
+        self.teardown()?;
+        Ok(self.mapping())
+    }
+
+    fn teardown(&mut self) -> MultiHarpResult<()> {
+        // Dropping the sender wakes the thread
+        self.stop.take();
+        self.thread.take()
+            .map(|t| t.join().unwrap_or(Err(MultiHarpError::ThreadStateFail)))
+            .unwrap_or(Ok(()))
+    }
+}
+
+impl Drop for ClockMapper {
+    fn drop(&mut self) {
+        let _ = self.teardown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mhconsts::{FLAG_REF_LOST, FLAG_SYNC_LOST, MeasurementMode};
+    use crate::DebugMultiHarp150;
+
+    #[test]
+    fn test_clock_status_from_flags() {
+        let status = ClockStatus::from_flags(ReferenceClock::PpsGps, FLAG_REF_LOST | FLAG_SYNC_LOST);
+        assert!(status.ref_lost && status.sync_lost);
+        assert!(!status.is_locked());
+
+        assert!(ClockStatus::from_flags(ReferenceClock::PpsUart, 0).is_locked());
+        // The internal clock can't be lost
+        assert!(ClockStatus::from_flags(ReferenceClock::Internal, FLAG_REF_LOST).is_locked());
+    }
+
+    #[test]
+    fn test_clock_mapping_fit() {
+        let origin = Instant::now();
+        let wall = SystemTime::now();
+        // Device clock 50 ppm fast, measurement started 2 s after `origin`
+        let samples = (0..10)
+            .map(|i| ClockSample {
+                device_ms : i as f64 * 100.0 * (1.0 + 50e-6),
+                host : origin + Duration::from_secs(2) + Duration::from_millis(i * 100),
+            })
+            .collect::<Vec<_>>();
+        let mapping = ClockMapping::fit(&samples, origin, wall).unwrap();
+        assert!((mapping.drift_ppm() - 50.0).abs() < 1e-3, "{}", mapping.drift_ppm());
+        assert!((mapping.offset - 2.0).abs() < 1e-9);
+        assert!(mapping.residual < 1e-9);
+        assert_eq!(mapping.samples, 10);
+
+        let at = mapping.photon_time(1e12 * (1.0 + 50e-6));
+        let expected = wall + Duration::from_secs(3);
+        assert!(at.duration_since(expected).unwrap_or_else(|e| e.duration()) < Duration::from_micros(1));
+
+        assert!(ClockMapping::fit(&samples[..1], origin, wall).is_none());
+        assert!(ClockMapping::fit(&[samples[3]; 4], origin, wall).is_none());
+    }
+
+    #[test]
+    fn test_clock_mapper() {
+        let mh = Arc::new(Mutex::new(DebugMultiHarp150::default()));
+        {
+            let mut mh = mh.lock().unwrap();
+            mh.init(MeasurementMode::T3, ReferenceClock::Internal).unwrap();
+            mh.set_clock_drift_ppm(2000.0);
+        }
+        // Nothing to fit before the measurement starts
+        let mapper = ClockMapper::start(Arc::clone(&mh), Duration::from_millis(10), 100);
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(mapper.mapping().is_none());
+
+        mh.lock().unwrap().start_measurement(10_000).unwrap();
+        std::thread::sleep(Duration::from_millis(400));
+        assert!(mapper.is_running());
+        let mapping = mapper.mapping().unwrap();
+        mh.lock().unwrap().stop_measurement().unwrap();
+        assert!(mapping.samples > 10);
+        assert!((mapping.drift_ppm() - 2000.0).abs() < 500.0, "{}", mapping.drift_ppm());
+
+        // The start of the measurement maps to about when it was started
+        let started = mapping.to_instant(0.0);
+        assert!(started >= mapper.origin.0 && started.elapsed() >= Duration::from_millis(400));
+        mapper.stop().unwrap();
+    }
+}
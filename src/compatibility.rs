@@ -0,0 +1,217 @@
+//! Checks a whole `MultiHarpConfig` against the device it is meant for
+//! before any of it is applied: the ranges and channel count of the
+//! model, the features the device reports and the version of `MHLib` in
+//! use. `set_from_config` only finds these one setter at a time, leaving
+//! the device partly configured.
+//!
+//! The Main and Row event filters (`EvntFilt`) are not part of a
+//! `MultiHarpConfig`, so aren't checked here.
+
+use std::fmt::Display;
+use std::ops::RangeInclusive;
+
+use crate::error::MultiHarpResult;
+use crate::mhconsts::{self, FeatureMasks};
+use crate::multiharp::MultiHarpDevice;
+use crate::version::parse_version;
+use crate::MultiHarpConfig;
+
+/// A setting of a `MultiHarpConfig` the device can't apply
+#[derive(Debug, Clone, PartialEq)]
+pub enum Incompatibility {
+    /// The value is outside the range the device accepts
+    OutOfRange { setting : &'static str, value : i64, range : RangeInclusive<i64> },
+    /// The setting names an input channel the device doesn't have
+    NoSuchChannel { setting : &'static str, channel : i32, num_channels : i32 },
+    /// The setting needs a feature missing from `get_features`
+    MissingFeature { setting : &'static str, feature : FeatureMasks },
+    /// The setting needs a newer `MHLib` than the one loaded
+    LibraryTooOld { setting : &'static str, needs : &'static str, found : String },
+}
+
+impl Incompatibility {
+    /// The `MultiHarpConfig` field at fault
+    pub fn setting(&self) -> &'static str {
+        match self {
+            Incompatibility::OutOfRange { setting, .. }
+            | Incompatibility::NoSuchChannel { setting, .. }
+            | Incompatibility::MissingFeature { setting, .. }
+            | Incompatibility::LibraryTooOld { setting, .. } => setting,
+        }
+    }
+}
+
+impl Display for Incompatibility {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Incompatibility::OutOfRange { setting, value, range } => write!(f,
+                "`{}` is {}, but must be between {} and {}", setting, value, range.start(), range.end()
+            ),
+            Incompatibility::NoSuchChannel { setting, channel, num_channels } => write!(f,
+                "`{}` sets channel {}, but the device has {} input channels", setting, channel, num_channels
+            ),
+            Incompatibility::MissingFeature { setting, feature } => write!(f,
+                "`{}` needs {:?}, which the device doesn't have", setting, feature
+            ),
+            Incompatibility::LibraryTooOld { setting, needs, found } => write!(f,
+                "`{}` needs MHLib {} or later, but {} is loaded", setting, needs, found
+            ),
+        }
+    }
+}
+
+/// Collects the incompatibilities of one config
+struct Checker {
+    found : Vec<Incompatibility>,
+    num_channels : i32,
+}
+
+impl Checker {
+    fn range<T : Into<i64> + Copy>(&mut self, setting : &'static str, value : Option<T>, range : &RangeInclusive<T>) {
+        if let Some(value) = value {
+            let (value, range) = (value.into(), (*range.start()).into()..=(*range.end()).into());
+            if !range.contains(&value) {
+                self.found.push(Incompatibility::OutOfRange { setting, value, range });
+            }
+        }
+    }
+
+    fn channel(&mut self, setting : &'static str, channel : i32) {
+        if !(0..self.num_channels).contains(&channel) {
+            self.found.push(Incompatibility::NoSuchChannel { setting, channel, num_channels : self.num_channels });
+        }
+    }
+}
+
+impl MultiHarpConfig {
+    /// Every setting of this config that `multiharp` can't apply, empty
+    /// if all of it can. Nothing is sent to the device.
+    ///
+    /// The library version is only checked when `get_library_version`
+    /// reports one.
+    ///
+    /// ## Errors
+    ///
+    /// * `MultiHarpError` - If the device's model, channel count or
+    ///   features can't be read.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// use multi_harp_patina::*;
+    ///
+    /// let mut mh = DebugMultiHarp150::default();
+    /// mh.init(MeasurementMode::T3, ReferenceClock::Internal).unwrap();
+    ///
+    /// let config = MultiHarpConfig {
+    ///     sync_div : Some(32),
+    ///     input_enables : Some(vec![(0, true), (9, true)]),
+    ///     ..Default::default()
+    /// };
+    /// let problems = config.validate(&mh).unwrap();
+    /// assert_eq!(problems.iter().map(|p| p.setting()).collect::<Vec<_>>(), vec!["sync_div", "input_enables"]);
+    /// ```
+    pub fn validate<MH : MultiHarpDevice>(&self, multiharp : &MH) -> MultiHarpResult<Vec<Incompatibility>> {
+        let limits = multiharp.limits()?;
+        let mut checker = Checker { found : Vec::new(), num_channels : multiharp.num_input_channels()? };
+
+        checker.range("sync_div", self.sync_div, &limits.sync_div);
+        checker.range("sync_trigger_edge", self.sync_trigger_edge.map(|(level, _)| level), &limits.trigger_level);
+        checker.range("sync_channel_offset", self.sync_channel_offset, &limits.channel_offset);
+        checker.range("sync_dead_time", self.sync_dead_time.filter(|(on, _)| *on).map(|(_, time)| time), &limits.dead_time);
+        for (channel, level, _) in self.input_edges.iter().flatten() {
+            checker.channel("input_edges", *channel);
+            checker.range("input_edges", Some(*level), &limits.trigger_level);
+        }
+        for (channel, offset) in self.input_offsets.iter().flatten() {
+            checker.channel("input_offsets", *channel);
+            checker.range("input_offsets", Some(*offset), &limits.channel_offset);
+        }
+        for (channel, _) in self.input_enables.iter().flatten() {
+            checker.channel("input_enables", *channel);
+        }
+        for (channel, on, dead_time) in self.input_dead_times.iter().flatten() {
+            checker.channel("input_dead_times", *channel);
+            checker.range("input_dead_times", Some(*dead_time).filter(|_| *on), &limits.dead_time);
+        }
+        if let Some((true, count)) = self.stop_overflow {
+            checker.range("stop_overflow", Some(count), &limits.stop_count);
+        }
+        checker.range("binning", self.binning, &limits.binning);
+        checker.range("offset", self.offset, &limits.offset);
+        checker.range("histo_len", self.histo_len, &limits.length_code);
+        checker.range("trigger_output", self.trigger_output, &limits.trigger_output);
+        #[cfg(feature = "MHLv3_1_0")]
+        checker.range("ofl_compression", self.ofl_compression, &(mhconsts::HOLDTIMEMIN..=mhconsts::HOLDTIMEMAX));
+        let holdoff = limits.holdoff.clone().unwrap_or(mhconsts::HOLDOFFMIN..=mhconsts::HOLDOFFMAX);
+        checker.range("marker_holdoff", self.marker_holdoff, &holdoff);
+
+        let features = multiharp.get_features()?;
+        checker.found.extend(self.missing_features(features).into_iter()
+            .map(|(setting, feature)| Incompatibility::MissingFeature { setting, feature })
+        );
+
+        if let Ok(library) = crate::get_library_version() {
+            checker.found.extend(self.library_needs().into_iter()
+                .filter(|(_, needs)| {
+                    let found = parse_version(&library);
+                    !found.is_empty() && found < parse_version(needs)
+                })
+                .map(|(setting, needs)| Incompatibility::LibraryTooOld { setting, needs, found : library.trim().to_string() })
+            );
+        }
+
+        Ok(checker.found)
+    }
+
+    /// The settings of this config only available from some version of
+    /// `MHLib` on, with that version
+    fn library_needs(&self) -> Vec<(&'static str, &'static str)> {
+        let mut needs = Vec::new();
+        #[cfg(feature = "MHLv3_0_0")]
+        if self.input_hysteresis.is_some() {
+            needs.push(("input_hysteresis", "3.0"));
+        }
+        if self.marker_holdoff.is_some() {
+            needs.push(("marker_holdoff", "3.0"));
+        }
+        #[cfg(feature = "MHLv3_1_0")]
+        {
+            if self.sync_channel_enable.is_some() {
+                needs.push(("sync_channel_enable", "3.1"));
+            }
+            if self.ofl_compression.is_some() {
+                needs.push(("ofl_compression", "3.1"));
+            }
+        }
+        needs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mhconsts::{MeasurementMode, ReferenceClock};
+    use crate::DebugMultiHarp150;
+
+    #[test]
+    fn test_validate() {
+        let mut mh = DebugMultiHarp150::default();
+        mh.init(MeasurementMode::T3, ReferenceClock::Internal).unwrap();
+
+        assert!(MultiHarpConfig::default().validate(&mh).unwrap().is_empty());
+
+        let config = MultiHarpConfig {
+            sync_div : Some(4),
+            input_offsets : Some(vec![(1, 200), (2, i32::MAX)]),
+            input_dead_times : Some(vec![(-1, false, 0)]),
+            binning : Some(99),
+            ..Default::default()
+        };
+        let problems = config.validate(&mh).unwrap();
+        assert_eq!(problems.len(), 3);
+        assert!(matches!(problems[0], Incompatibility::OutOfRange { setting : "input_offsets", .. }));
+        assert_eq!(problems[1], Incompatibility::NoSuchChannel { setting : "input_dead_times", channel : -1, num_channels : 4 });
+        assert!(problems[2].to_string().starts_with("`binning` is 99, but must be between 0 and"));
+    }
+}
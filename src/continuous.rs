@@ -0,0 +1,364 @@
+//! Acquisitions longer than one measurement. A measurement lasts at most
+//! `ACQTMAX` (100 h), so `ContinuousAcquisition` runs measurements back
+//! to back through one `Pipeline`, restarting each as soon as the last
+//! ends. Every measurement starts its time count from zero, so at each
+//! restart the pipeline is passed overflow records moving its time base
+//! past the previous measurement and the time the restart took: the
+//! processors see a single acquisition on one time axis.
+//!
+//! With `MHLv3_1_0`, the device is instead put in `SwStartSwStop` mode
+//! if it accepts it, and a single measurement runs for the whole time.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::mhconsts::{self, MeasurementMode};
+use crate::pipeline::{Batch, Pipeline, PipelineError, Reads, StreamInfo};
+use crate::tttr::{pack_t2, pack_t3, OverflowCompression, OVERFLOW_CHANNEL, T2_WRAPAROUND, T3_WRAPAROUND};
+use crate::MultiHarpDevice;
+
+/// Runs measurements back to back as one acquisition.
+///
+/// ## Example
+///
+/// ```
+/// use std::time::Duration;
+/// use multi_harp_patina::*;
+///
+/// let mut mh = DebugMultiHarp150::default();
+/// mh.init(MeasurementMode::T3, ReferenceClock::Internal).unwrap();
+/// let mut pipeline = Pipeline::new();
+/// pipeline.add(MarkerCounter::new(1).unwrap());
+///
+/// // A week would be `Duration::from_secs(7 * 24 * 3600)`
+/// let run = ContinuousAcquisition::new(Duration::from_millis(250))
+///     .with_segment_time(100)
+///     .run(&mut pipeline, &mut mh)
+///     .unwrap();
+/// assert_eq!(run.segment_starts.len(), 3);
+/// ```
+#[derive(Debug, Clone)]
+pub struct ContinuousAcquisition {
+    duration : Duration,
+    segment_time : i32,
+    software_control : bool,
+    stop : Arc<AtomicBool>,
+}
+
+/// What a `ContinuousAcquisition` did.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContinuousRun {
+    /// Records read from the device, not counting the overflows added
+    /// at restarts
+    pub records : u64,
+    /// The time at which each measurement started on the stitched time
+    /// axis, in syncs (T3) or resolution units (T2). The first is 0.
+    pub segment_starts : Vec<u64>,
+    /// Whether a single `SwStartSwStop` measurement was used instead
+    /// of restarts
+    pub software_controlled : bool,
+}
+
+impl ContinuousAcquisition {
+    /// An acquisition lasting `duration`, in measurements of `ACQTMAX`.
+    pub fn new(duration : Duration) -> Self {
+        ContinuousAcquisition {
+            duration,
+            segment_time : mhconsts::ACQTMAX,
+            software_control : true,
+            stop : Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Restarts the measurement every `segment_time` ms, clamped to
+    /// `ACQTMIN..=ACQTMAX`, e.g. to keep the elapsed measurement time
+    /// of each one short.
+    pub fn with_segment_time(mut self, segment_time : i32) -> Self {
+        self.segment_time = segment_time.clamp(mhconsts::ACQTMIN, mhconsts::ACQTMAX);
+        self
+    }
+
+    /// Restarts measurements even if the device supports `SwStartSwStop`.
+    pub fn without_software_control(mut self) -> Self {
+        self.software_control = false;
+        self
+    }
+
+    /// A flag that ends the acquisition early when set, from any thread.
+    pub fn stop_flag(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.stop)
+    }
+
+    /// Runs the acquisition on an initialized device, feeding every FIFO
+    /// read through `pipeline`. Processors are initialized and finalized
+    /// once, however many measurements it takes.
+    ///
+    /// ## Errors
+    ///
+    /// As `Pipeline::run`. A failed restart ends the acquisition, with
+    /// the processors still finalized.
+    pub fn run<MH : MultiHarpDevice>(&self, pipeline : &mut Pipeline, multiharp : &mut MH) -> Result<ContinuousRun, PipelineError> {
+        self.stop.store(false, Ordering::Relaxed);
+        let info = pipeline.begin(multiharp, self.segment_time, "ContinuousAcquisition::run")?;
+
+        #[cfg(feature = "MHLv3_1_0")]
+        if self.software_control {
+            let previous = multiharp.current_settings().meas_control
+                .unwrap_or((mhconsts::MeasurementControlMode::SingleShotCtc, None, None));
+            if multiharp.set_measurement_control_mode(mhconsts::MeasurementControlMode::SwStartSwStop, None, None).is_ok() {
+                let run = self.run_software_controlled(pipeline, multiharp, &info);
+                let _ = multiharp.set_measurement_control_mode(previous.0, previous.1, previous.2);
+                return run;
+            }
+        }
+
+        let mut stitcher = Stitcher::new(&info);
+        // Time units per second: `nsync` counts divided syncs
+        let units_per_second = match info.mode {
+            MeasurementMode::T2 => 1e12 / info.resolution,
+            _ => multiharp.get_sync_rate().unwrap_or(0) as f64
+                / multiharp.current_settings().sync_div.unwrap_or(1).max(1) as f64,
+        };
+        let mut reads = Reads::default();
+        let mut segment_starts = Vec::new();
+        let run_started = Instant::now();
+
+        let acquired = (|| {
+            let mut previous_start = None;
+            loop {
+                let remaining = self.duration.saturating_sub(run_started.elapsed());
+                let segment_time = (remaining.as_millis().min(self.segment_time as u128) as i32).max(mhconsts::ACQTMIN);
+                if previous_start.is_some() {
+                    multiharp.stop_measurement()?;
+                }
+                multiharp.start_measurement(segment_time)?;
+                let started = Instant::now();
+
+                if let Some(previous) = previous_start {
+                    let elapsed = started.duration_since(previous).as_secs_f64() * units_per_second;
+                    let overflows = stitcher.restart(elapsed as u64);
+                    pipeline.process_batch(&Batch {
+                        index : reads.index,
+                        read_at : started,
+                        records : &overflows,
+                        mode : info.mode,
+                        sync_lost : false,
+                    })?;
+                    reads.index += 1;
+                }
+                segment_starts.push(stitcher.segment_start);
+                previous_start = Some(started);
+
+                let length = Duration::from_millis(segment_time as u64);
+                pipeline.read_measurement(multiharp, &info, started, length, true, &mut reads, |records| stitcher.observe(records))?;
+                // A measurement may end a little before its acquisition time:
+                // don't start another for less than the shortest one
+                let remaining = self.duration.saturating_sub(run_started.elapsed());
+                if remaining < Duration::from_millis(mhconsts::ACQTMIN as u64) || self.stop.load(Ordering::Relaxed) {
+                    return Ok(());
+                }
+            }
+        })();
+        pipeline.end(multiharp, &info, acquired)?;
+
+        Ok(ContinuousRun { records : reads.total, segment_starts, software_controlled : false })
+    }
+
+    /// A single measurement, stopped by software
+    #[cfg(feature = "MHLv3_1_0")]
+    fn run_software_controlled<MH : MultiHarpDevice>(&self, pipeline : &mut Pipeline, multiharp : &mut MH, info : &StreamInfo) -> Result<ContinuousRun, PipelineError> {
+        // The acquisition time is ignored in `SwStartSwStop` mode
+        multiharp.start_measurement(mhconsts::ACQTMAX)?;
+        let mut reads = Reads::default();
+        let started = Instant::now();
+        let acquired = (|| {
+            // In short steps, to check the stop flag
+            while started.elapsed() < self.duration && !self.stop.load(Ordering::Relaxed) {
+                let step = self.duration.saturating_sub(started.elapsed()).min(Duration::from_secs(1));
+                pipeline.read_measurement(multiharp, info, Instant::now(), step, false, &mut reads, |_| ())?;
+            }
+            Ok(())
+        })();
+        pipeline.end(multiharp, info, acquired)?;
+        Ok(ContinuousRun { records : reads.total, segment_starts : vec![0], software_controlled : true })
+    }
+}
+
+/// Follows the time base of a stream, to continue it from a restarted
+/// measurement.
+#[derive(Debug, Clone)]
+struct Stitcher {
+    mode : MeasurementMode,
+    wraparound : u64,
+    compression : OverflowCompression,
+    /// Time base after the overflows seen so far
+    base : u64,
+    /// Latest time of an event
+    last : Option<u64>,
+    /// Time base at the start of the current measurement
+    segment_start : u64,
+}
+
+impl Stitcher {
+    fn new(info : &StreamInfo) -> Self {
+        Stitcher {
+            mode : info.mode,
+            wraparound : match info.mode {
+                MeasurementMode::T2 => T2_WRAPAROUND,
+                _ => T3_WRAPAROUND,
+            },
+            compression : info.overflow_compression,
+            base : 0,
+            last : None,
+            segment_start : 0,
+        }
+    }
+
+    fn observe(&mut self, records : &[u32]) {
+        for &record in records {
+            let channel = ((record & mhconsts::CHANNEL) >> 25) as u8;
+            let low = match self.mode {
+                MeasurementMode::T2 => (record & mhconsts::HISTOTAG_T2) as u64,
+                _ => (record & mhconsts::SYNCTAG) as u64,
+            };
+            if record & mhconsts::SPECIAL != 0 && channel == OVERFLOW_CHANNEL {
+                self.base += self.compression.rollovers(low) * self.wraparound;
+            } else {
+                self.last = self.last.max(Some(self.base + low));
+            }
+        }
+    }
+
+    /// The overflow records starting a new measurement `elapsed` time
+    /// units after the current one started, and after its last event.
+    fn restart(&mut self, elapsed : u64) -> Vec<u32> {
+        let after_last = self.last.map_or(0, |last| last + 1);
+        let target = (self.segment_start + elapsed).max(after_last);
+        let new_base = ((target + self.wraparound - 1) / self.wraparound * self.wraparound).max(self.base);
+
+        let max_count = match self.compression {
+            OverflowCompression::Compressed => self.wraparound - 1,
+            OverflowCompression::Single => 1,
+        };
+        let mut rollovers = (new_base - self.base) / self.wraparound;
+        let mut records = Vec::new();
+        while rollovers > 0 {
+            let count = rollovers.min(max_count);
+            records.push(match self.mode {
+                MeasurementMode::T2 => pack_t2(true, OVERFLOW_CHANNEL, count as u32),
+                _ => pack_t3(true, OVERFLOW_CHANNEL, 0, count as u16),
+            });
+            rollovers -= count;
+        }
+        self.base = new_base;
+        self.segment_start = new_base;
+        records
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tttr::{Decoder, Encoder, TttrEvent};
+    use crate::{DebugMultiHarp150, ReferenceClock, StreamProcessor, ProcessorResult};
+    use crate::testing::fixtures::stream_info;
+
+    #[test]
+    fn test_stitcher() {
+        for compression in [OverflowCompression::Compressed, OverflowCompression::Single] {
+            let info = StreamInfo { overflow_compression : compression, ..stream_info(MeasurementMode::T3) };
+            let mut stitcher = Stitcher::new(&info);
+            let mut decoder = Decoder::for_stream(&info).unwrap();
+
+            // Two measurements, each starting from nsync 0
+            let first = [TttrEvent::photon(0, 10, 5), TttrEvent::photon(1, 2500, 5)];
+            let second = [TttrEvent::photon(2, 3, 5), TttrEvent::marker(1, 1100)];
+            let first = Encoder::new(MeasurementMode::T3).unwrap().with_overflow_compression(compression).encode_all(&first).unwrap();
+            let second = Encoder::new(MeasurementMode::T3).unwrap().with_overflow_compression(compression).encode_all(&second).unwrap();
+
+            stitcher.observe(&first);
+            assert_eq!(decoder.decode_all(&first).last().unwrap().time, 2500);
+            // The restart took 1000 syncs after the first measurement
+            // ran for 3000
+            let overflows = stitcher.restart(4000);
+            stitcher.observe(&second);
+            let mut stitched = overflows.clone();
+            stitched.extend(&second);
+            let times = decoder.decode_all(&stitched).iter().map(|e| e.time).collect::<Vec<_>>();
+            assert_eq!(times, vec![4096 + 3, 4096 + 1100]);
+            assert_eq!(stitcher.segment_start, 4096);
+
+            // A restart without new events still moves past the last one
+            assert!(!stitcher.restart(0).is_empty());
+            assert_eq!(stitcher.segment_start, 6144);
+        }
+
+        // In T2 the target is rounded up to whole rollovers
+        let mut stitcher = Stitcher::new(&stream_info(MeasurementMode::T2));
+        stitcher.observe(&[pack_t2(false, 0, 100)]);
+        assert_eq!(stitcher.restart(T2_WRAPAROUND * 3 + 1), vec![pack_t2(true, OVERFLOW_CHANNEL, 4)]);
+    }
+
+    /// Fails unless initialized once per acquisition and fed batches in order
+    #[derive(Default)]
+    struct Lifecycle {
+        initialized : bool,
+        next_index : u64,
+    }
+
+    impl StreamProcessor for Lifecycle {
+        fn name(&self) -> &str { "lifecycle" }
+
+        fn init(&mut self, _info : &StreamInfo) -> ProcessorResult {
+            if std::mem::replace(&mut self.initialized, true) {
+                return Err("initialized twice".to_string());
+            }
+            self.next_index = 0;
+            Ok(())
+        }
+
+        fn process_batch(&mut self, batch : &Batch) -> ProcessorResult {
+            if batch.index != self.next_index {
+                return Err(format!("batch {} after {}", batch.index, self.next_index));
+            }
+            self.next_index += 1;
+            Ok(())
+        }
+
+        fn finalize(&mut self) -> ProcessorResult {
+            self.initialized = false;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_continuous_acquisition() {
+        let mut mh = DebugMultiHarp150::default();
+        mh.init(MeasurementMode::T3, ReferenceClock::Internal).unwrap();
+        let mut pipeline = Pipeline::new();
+        pipeline.add(Lifecycle::default());
+
+        let acquisition = ContinuousAcquisition::new(Duration::from_millis(350))
+            .with_segment_time(100)
+            .without_software_control();
+        let run = acquisition.run(&mut pipeline, &mut mh).unwrap();
+        assert!(!run.software_controlled);
+        assert_eq!(run.segment_starts.len(), 4);
+        assert!(run.segment_starts.windows(2).all(|w| w[0] < w[1]), "{:?}", run.segment_starts);
+        assert!(run.records > 0);
+        assert!(!mh.ctc_status().unwrap());
+
+        // Stopped during the second measurement
+        let acquisition = ContinuousAcquisition::new(Duration::from_secs(10))
+            .with_segment_time(100)
+            .without_software_control();
+        let stop = acquisition.stop_flag();
+        let stopper = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(150));
+            stop.store(true, Ordering::Relaxed);
+        });
+        assert_eq!(acquisition.run(&mut pipeline, &mut mh).unwrap().segment_starts.len(), 2);
+        stopper.join().unwrap();
+    }
+}
@@ -0,0 +1,187 @@
+//! Coincidences and g(2) between photons recorded by two devices sharing
+//! a reference clock (10 MHz, PPS or White Rabbit), and the residual
+//! offset between their clocks measured with a shared pulsed source.
+//!
+//! The crate has no multi-device merger, so both streams are taken as
+//! photon times in picoseconds on the common clock, sorted, e.g. from
+//! `photon_times_ps` of each device's decoded T2 events.
+
+use crate::tttr::{EventKind, TttrEvent};
+
+/// The times in ps of the photons in `events` (decoded in T2 mode with bins
+/// of `resolution` ps), on `channel` or every channel, shifted by `offset`
+/// ps to bring them onto the common clock. Sorted by time.
+pub fn photon_times_ps(events : &[TttrEvent], resolution : f64, channel : Option<u8>, offset : f64) -> Vec<i64> {
+    let mut times = events.iter()
+        .filter(|e| e.kind == EventKind::Photon && channel.map_or(true, |c| e.channel == c))
+        .map(|e| (e.time as f64 * resolution + offset).round() as i64)
+        .collect::<Vec<_>>();
+    times.sort_unstable();
+    times
+}
+
+/// A histogram of the delays `b - a` between photons of two streams.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CrossCorrelation {
+    /// Width of each bin in ps
+    pub bin_width : f64,
+    /// Delay of the start of bin 0 in ps (`-window`)
+    pub start : f64,
+    pub counts : Vec<u64>,
+    /// Photons in each stream and the time they span, for normalization
+    pub photons_a : usize,
+    pub photons_b : usize,
+    pub duration : f64,
+}
+
+impl CrossCorrelation {
+    /// The delay at the centre of bin `i`, in ps
+    pub fn delay(&self, i : usize) -> f64 {
+        self.start + (i as f64 + 0.5) * self.bin_width
+    }
+
+    /// Total coincidences within the window
+    pub fn coincidences(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// The counts normalized by those expected from uncorrelated streams
+    /// at the same rates, so that 1 means no correlation. Zeros if either
+    /// stream is empty.
+    pub fn g2(&self) -> Vec<f64> {
+        let expected = self.photons_a as f64 * self.photons_b as f64 * self.bin_width / self.duration;
+        if expected <= 0.0 {
+            return vec![0.0; self.counts.len()];
+        }
+        self.counts.iter().map(|&c| c as f64 / expected).collect()
+    }
+
+    /// The delay of the fullest bin, refined by the centroid of it and its
+    /// neighbours. `None` without coincidences.
+    pub fn peak_delay(&self) -> Option<f64> {
+        let (peak, _) = self.counts.iter().enumerate()
+            .filter(|(_, &c)| c > 0)
+            .max_by_key(|(_, &c)| c)?;
+        let neighbours = peak.saturating_sub(1)..(peak + 2).min(self.counts.len());
+        let (weighted, total) = neighbours
+            .map(|i| (self.delay(i) * self.counts[i] as f64, self.counts[i] as f64))
+            .fold((0.0, 0.0), |(w, t), (dw, dt)| (w + dw, t + dt));
+        Some(weighted / total)
+    }
+}
+
+/// Histograms the delays `b - a` between every pair of photons less than
+/// `window` ps apart, in bins of `bin_width` ps. Both streams must be
+/// sorted (see `photon_times_ps`).
+///
+/// ## Errors
+///
+/// * If `bin_width` isn't positive, or `window` is negative
+///
+/// ## Example
+///
+/// ```
+/// use multi_harp_patina::*;
+///
+/// let a = vec![0, 1_000_000, 2_000_000];
+/// let b = a.iter().map(|t| t + 300).collect::<Vec<_>>();
+/// let correlation = cross_correlate(&a, &b, 100.0, 1000.0).unwrap();
+/// assert_eq!(correlation.coincidences(), 3);
+/// assert_eq!(correlation.peak_delay(), Some(350.0));
+/// ```
+pub fn cross_correlate(a : &[i64], b : &[i64], bin_width : f64, window : f64) -> Result<CrossCorrelation, String> {
+    if bin_width.is_nan() || bin_width <= 0.0 || window.is_nan() || window < 0.0 {
+        return Err(format!("Invalid correlation window of {} ps in bins of {} ps", window, bin_width));
+    }
+    let bins = ((2.0 * window / bin_width).ceil() as usize).max(1);
+    let mut counts = vec![0u64; bins];
+    let window_ps = window.ceil() as i64;
+
+    // Start of the photons of `b` that can still be within the window
+    let mut first = 0;
+    for &t in a {
+        while first < b.len() && b[first] < t - window_ps {
+            first += 1;
+        }
+        for &u in b[first..].iter().take_while(|&&u| u <= t + window_ps) {
+            let bin = ((u - t) as f64 + window) / bin_width;
+            if bin >= 0.0 && (bin as usize) < bins {
+                counts[bin as usize] += 1;
+            }
+        }
+    }
+
+    let start = a.first().into_iter().chain(b.first()).min().copied().unwrap_or(0);
+    let end = a.last().into_iter().chain(b.last()).max().copied().unwrap_or(0);
+    let duration = (end - start) as f64;
+    Ok(CrossCorrelation {
+        bin_width,
+        start : -window,
+        counts,
+        photons_a : a.len(),
+        photons_b : b.len(),
+        duration : duration.max(bin_width),
+    })
+}
+
+/// The residual offset in ps between two devices recording the same
+/// pulsed source, to add to the times of `b` (e.g. as the `offset` of
+/// `photon_times_ps`) so that coincident pulses line up. Correlates
+/// coarsely over `window` ps, then again in bins of `resolution` ps
+/// around the peak. `None` if no pulses coincide within `window`.
+pub fn calibrate_offset(a : &[i64], b : &[i64], window : f64, resolution : f64) -> Option<f64> {
+    let coarse_bin = (window / 50.0).max(resolution);
+    let coarse = cross_correlate(a, b, coarse_bin, window).ok()?
+        .peak_delay()?
+        .round() as i64;
+
+    let shifted = b.iter().map(|t| t - coarse).collect::<Vec<_>>();
+    let fine = cross_correlate(a, &shifted, resolution, coarse_bin * 2.0).ok()?.peak_delay()?;
+    Some(-(coarse as f64 + fine))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cross_correlate() {
+        // Pulses every 12.5 ns on both devices, the second 2345 ps late
+        let a = (0..1000).map(|i| i * 12_500).collect::<Vec<_>>();
+        let b = a.iter().map(|t| t + 2345).collect::<Vec<_>>();
+
+        let correlation = cross_correlate(&a, &b, 5.0, 5000.0).unwrap();
+        assert_eq!(correlation.counts.len(), 2000);
+        assert_eq!(correlation.coincidences(), 1000);
+        let peak = correlation.peak_delay().unwrap();
+        assert!((peak - 2347.5).abs() < 5.0, "{}", peak);
+        let g2 = correlation.g2();
+        assert!(g2.iter().cloned().fold(0.0, f64::max) > 100.0);
+
+        let offset = calibrate_offset(&a, &b, 10_000.0, 5.0).unwrap();
+        assert!((offset + 2345.0).abs() <= 5.0, "{}", offset);
+        let aligned = b.iter().map(|t| t + offset.round() as i64).collect::<Vec<_>>();
+        assert!(cross_correlate(&a, &aligned, 10.0, 100.0).unwrap().peak_delay().unwrap().abs() <= 10.0);
+
+        assert!(calibrate_offset(&a, &[], 10_000.0, 5.0).is_none());
+        assert_eq!(cross_correlate(&[], &b, 5.0, 100.0).unwrap().g2(), vec![0.0; 40]);
+
+        for bin_width in [0.0, -5.0, f64::NAN] {
+            assert!(cross_correlate(&a, &b, bin_width, 100.0).is_err());
+        }
+        assert!(cross_correlate(&a, &b, 5.0, -100.0).is_err());
+        assert!(calibrate_offset(&a, &b, 10_000.0, 0.0).is_none());
+    }
+
+    #[test]
+    fn test_photon_times() {
+        let events = [
+            TttrEvent::photon(1, 200, 0),
+            TttrEvent::sync(150),
+            TttrEvent::photon(0, 100, 0),
+            TttrEvent::marker(1, 120),
+        ];
+        assert_eq!(photon_times_ps(&events, 5.0, None, 0.0), vec![500, 1000]);
+        assert_eq!(photon_times_ps(&events, 5.0, Some(1), -10.0), vec![990]);
+    }
+}
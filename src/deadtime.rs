@@ -0,0 +1,178 @@
+//! Corrections for counts lost to detector and electronics dead time:
+//! the non-paralyzable model for count rates and intensity traces, and
+//! the Coates correction for TCSPC pile-up in histograms.
+
+use std::time::Duration;
+
+use crate::histogram::Histogram;
+use crate::rates::Rates;
+use crate::MultiHarpConfig;
+
+/// Dead time of a MultiHarp input with the extended dead time off, in ps
+pub const INTRINSIC_DEAD_TIME : f64 = 650.0;
+
+/// A corrected quantity and the size of the correction.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Corrected<T> {
+    pub value : T,
+    /// Corrected counts over measured counts (1 is no correction)
+    pub factor : f64,
+}
+
+/// Non-paralyzable dead time correction of a single `rate` (Hz) with
+/// dead time `dead_time` (ps): `rate / (1 - rate * dead_time)`. Errors if
+/// the rate saturates the input.
+pub fn correct_rate(rate : f64, dead_time : f64) -> Result<f64, String> {
+    let busy = rate * dead_time * 1e-12;
+    if busy >= 1.0 {
+        return Err(format!("{} Hz saturates an input with {} ps dead time", rate, dead_time));
+    }
+    Ok(rate / (1.0 - busy))
+}
+
+/// Per-channel dead times, as configured on the device.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeadTimes {
+    dead_times : Vec<f64>,
+}
+
+impl DeadTimes {
+    /// Dead times in ps, indexed by channel. Missing channels use
+    /// `INTRINSIC_DEAD_TIME`.
+    pub fn new(dead_times : Vec<f64>) -> Self {
+        DeadTimes { dead_times }
+    }
+
+    /// The dead times set by `config` (`input_dead_times`) on a device
+    /// with `num_channels` inputs.
+    pub fn from_config(config : &MultiHarpConfig, num_channels : i32) -> Self {
+        let mut dead_times = vec![INTRINSIC_DEAD_TIME; num_channels.max(0) as usize];
+        for (channel, on, dead_time) in config.input_dead_times.iter().flatten() {
+            if let Some(d) = dead_times.get_mut(*channel as usize) {
+                if *on {
+                    *d = *dead_time as f64;
+                }
+            }
+        }
+        DeadTimes { dead_times }
+    }
+
+    /// Dead time of `channel` in ps
+    pub fn get(&self, channel : usize) -> f64 {
+        self.dead_times.get(channel).copied().unwrap_or(INTRINSIC_DEAD_TIME)
+    }
+
+    /// Corrects the input rates of `rates`.
+    pub fn correct_rates(&self, rates : &Rates) -> Result<Corrected<Vec<f64>>, String> {
+        let corrected = rates.inputs.iter().enumerate()
+            .map(|(channel, rate)| correct_rate(*rate, self.get(channel)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let factor = ratio(corrected.iter().sum(), rates.total_input());
+        Ok(Corrected { value : corrected, factor })
+    }
+
+    /// Corrects an intensity trace of `channel`: photon counts in
+    /// consecutive bins of `bin_width`. Returns corrected counts per bin.
+    pub fn correct_trace(&self, channel : usize, counts : &[u32], bin_width : Duration) -> Result<Corrected<Vec<f64>>, String> {
+        let seconds = bin_width.as_secs_f64();
+        if seconds <= 0.0 {
+            return Err("Bin width must be positive".to_string());
+        }
+        let corrected = counts.iter()
+            .map(|c| correct_rate(*c as f64 / seconds, self.get(channel)).map(|r| r * seconds))
+            .collect::<Result<Vec<_>, _>>()?;
+        let measured = counts.iter().map(|c| *c as f64).sum();
+        let factor = ratio(corrected.iter().sum(), measured);
+        Ok(Corrected { value : corrected, factor })
+    }
+}
+
+fn ratio(corrected : f64, measured : f64) -> f64 {
+    if measured > 0.0 { corrected / measured } else { 1.0 }
+}
+
+/// Coates correction of a TCSPC histogram for pile-up, the loss of
+/// later photons in a sync period to the dead time following an
+/// earlier one. `excitations` is the number of sync periods the
+/// histogram was collected over (sync rate / divider times the acquisition time).
+///
+/// ## Example
+///
+/// ```
+/// use std::time::Duration;
+/// use multi_harp_patina::*;
+///
+/// let histogram = Histogram {
+///     bins : vec![10_000, 9_000, 8_000],
+///     bin_width_ps : 5.0,
+///     offset_ps : 0.0,
+///     channel : 0,
+///     acquired_for : Duration::from_millis(1),
+///     device_serial : "1044272".to_string(),
+/// };
+/// let corrected = coates_correction(&histogram, 80_000).unwrap();
+/// assert!(corrected.factor > 1.0);
+/// ```
+pub fn coates_correction(histogram : &Histogram, excitations : u64) -> Result<Corrected<Vec<f64>>, String> {
+    let excitations = excitations as f64;
+    let mut earlier = 0.0;
+    let corrected = histogram.bins.iter().map(|h| {
+        let h = *h as f64;
+        let remaining = excitations - earlier;
+        if h >= remaining {
+            return Err(format!(
+                "{} excitations are too few for {} counts: the histogram is saturated",
+                excitations, histogram.total_counts()
+            ));
+        }
+        earlier += h;
+        Ok(-excitations * (1.0 - h / remaining).ln())
+    }).collect::<Result<Vec<_>, _>>()?;
+    let factor = ratio(corrected.iter().sum(), histogram.total_counts() as f64);
+    Ok(Corrected { value : corrected, factor })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_and_trace_corrections() {
+        // 10% busy
+        assert!((correct_rate(1e8, 1000.0).unwrap() - 1e8 / 0.9).abs() < 1e-3);
+        assert!(correct_rate(1e9, 1000.0).is_err());
+
+        let config = MultiHarpConfig {
+            input_dead_times : Some(vec![(1, true, 10_000), (2, false, 50_000)]),
+            ..Default::default()
+        };
+        let dead_times = DeadTimes::from_config(&config, 4);
+        assert_eq!(dead_times.get(1), 10_000.0);
+        assert_eq!(dead_times.get(2), INTRINSIC_DEAD_TIME);
+
+        let trace = dead_times.correct_trace(1, &[0, 1000, 10_000], Duration::from_millis(1)).unwrap();
+        assert_eq!(trace.value[0], 0.0);
+        assert!(trace.value[2] > trace.value[1] * 10.0);
+        assert!(trace.factor > 1.0);
+    }
+
+    #[test]
+    fn test_coates_correction() {
+        let histogram = |bins| Histogram {
+            bins,
+            bin_width_ps : 5.0,
+            offset_ps : 0.0,
+            channel : 0,
+            acquired_for : Duration::ZERO,
+            device_serial : String::new(),
+        };
+        // Negligible pile-up at low rates
+        let low = coates_correction(&histogram(vec![10, 10]), 1_000_000_000).unwrap();
+        assert!((low.factor - 1.0).abs() < 1e-6);
+
+        let high = coates_correction(&histogram(vec![500, 250]), 1000).unwrap();
+        assert!((high.value[0] - -1000.0 * 0.5f64.ln()).abs() < 1e-9);
+        assert!((high.value[1] - high.value[0]).abs() < 1e-9);
+        assert!(coates_correction(&histogram(vec![600, 400]), 1000).is_err());
+    }
+}
@@ -0,0 +1,123 @@
+//! `MHLib` can only tell whether a device is available by opening it, and
+//! opening a device this process already holds succeeds -- so a scan that
+//! then closes it again closes the device under whoever opened it. Every
+//! probe and every open therefore goes through one process-wide lock, and
+//! the indices of the devices held by this process are never probed.
+
+use std::sync::{Mutex, MutexGuard};
+
+#[cfg(feature = "MHLib")]
+use std::ffi::*;
+
+use crate::error::MultiHarpError;
+#[cfg(feature = "MHLib")]
+use crate::mhlib::*;
+use crate::mhconsts;
+
+static ENUMERATION : Mutex<()> = Mutex::new(());
+static HELD : Mutex<Vec<i32>> = Mutex::new(Vec::new());
+
+/// Taken while a device is probed or opened
+pub(crate) fn enumeration_lock() -> MutexGuard<'static, ()> {
+    ENUMERATION.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn held() -> MutexGuard<'static, Vec<i32>> {
+    HELD.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Marks the device at `index` as open in this process
+#[cfg_attr(not(feature = "MHLib"), allow(dead_code))]
+pub(crate) fn hold(index : i32) {
+    let mut held = held();
+    if !held.contains(&index) {
+        held.push(index);
+    }
+}
+
+/// Marks the device at `index` as closed
+#[cfg_attr(not(feature = "MHLib"), allow(dead_code))]
+pub(crate) fn release(index : i32) {
+    held().retain(|&i| i != index);
+}
+
+/// Whether this process holds the device at `index` open
+pub fn is_held(index : i32) -> bool {
+    held().contains(&index)
+}
+
+/// What `probe_device` found at an index
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeviceProbe {
+    /// A device that can be opened, with its serial number
+    Available(String),
+    /// A device this process has open, left alone
+    HeldByProcess,
+    /// A device open in another process
+    Busy,
+    Locked,
+    NoDevice,
+    Error(MultiHarpError),
+}
+
+/// Reports on the device at `index` (0..7), opening and closing it to
+/// find out unless this process already holds it.
+///
+/// ## Example
+///
+/// ```
+/// use multi_harp_patina::*;
+///
+/// for index in 0..MAXDEVNUM {
+///     if let DeviceProbe::Available(serial) = probe_device(index) {
+///         println!("Device {} : {}", index, serial);
+///     }
+/// }
+/// ```
+pub fn probe_device(index : i32) -> DeviceProbe {
+    if !(0..mhconsts::MAXDEVNUM).contains(&index) {
+        return DeviceProbe::NoDevice;
+    }
+    let _enumeration = enumeration_lock();
+    if is_held(index) {
+        return DeviceProbe::HeldByProcess;
+    }
+
+    #[cfg(feature = "MHLib")]
+    {
+        let mut serial = [0 as c_char; 8];
+        let mh_result = unsafe { MH_OpenDevice(index, serial.as_mut_ptr()) };
+        match MultiHarpError::from(mh_result) {
+            MultiHarpError::None => {
+                let serial = unsafe { CStr::from_ptr(serial.as_ptr()) }.to_string_lossy().to_string();
+                unsafe { MH_CloseDevice(index) };
+                DeviceProbe::Available(serial)
+            },
+            MultiHarpError::DeviceOpenFail => DeviceProbe::NoDevice,
+            MultiHarpError::DeviceBusy => DeviceProbe::Busy,
+            MultiHarpError::DeviceLocked => DeviceProbe::Locked,
+            error => DeviceProbe::Error(error),
+        }
+    }
+    #[cfg(feature = "nolib")]
+    DeviceProbe::Available("Debug00".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_probe_held_device() {
+        let index = mhconsts::MAXDEVNUM - 1;
+        hold(index);
+        assert!(is_held(index));
+        assert_eq!(probe_device(index), DeviceProbe::HeldByProcess);
+        assert!(crate::available_devices().iter().all(|(i, _)| *i != index));
+
+        release(index);
+        assert!(!is_held(index));
+        assert_ne!(probe_device(index), DeviceProbe::HeldByProcess);
+        assert_eq!(probe_device(mhconsts::MAXDEVNUM), DeviceProbe::NoDevice);
+    }
+}
@@ -2,6 +2,8 @@
 use std::error::Error;
 use std::fmt::{Display, Debug};
 use crate::error_to_string;
+use crate::mhconsts::{MeasurementMode, ReferenceClock};
+use crate::version::VersionMismatch;
 
 /// Macro to convert a result from a MultiHarp function to a Result
 /// with the error code converted to a `MultiHarpError`
@@ -25,25 +27,27 @@ pub type MultiHarpResult<R> = Result<R, MultiHarpError>;
 #[cfg(feature = "async")]
 use std::future::{Future, IntoFuture, Ready};
 
-#[cfg(feature = "async")]
-pub type AsyncCheckedResult<R,T> = std::result::Result<R, AsyncPatinaError<T>>;
-
 #[derive(Debug, Clone, PartialEq)]
 pub enum PatinaError<T> where T : Display + Debug {
     MultiHarpError(MultiHarpError),
     ArgumentError(String, T, String),
     NoDeviceAvailable,
     FeatureNotAvailable(String),
-    NotImplemented,
-}
-
-#[cfg(feature = "async")]
-#[derive(Debug, Clone, PartialEq)]
-pub enum AsyncPatinaError<T> where T : Display + Debug + Future {
-    MultiHarpError(MultiHarpError),
-    ArgumentError(String, T, String),
-    NoDeviceAvailable,
-    FeatureNotAvailable(String),
+    /// The call (named in the `String`) is not meaningful in the
+    /// `MeasurementMode` the device was initialized in.
+    WrongMode(String, MeasurementMode),
+    /// The device was initialized with an external reference
+    /// clock that is not present.
+    ReferenceLost(ReferenceClock),
+    /// The installed `MHLib` or device firmware doesn't match what the
+    /// crate was built for (see `set_version_strictness`).
+    VersionMismatch(VersionMismatch),
+    /// Reading or writing a file failed (described in the `String`)
+    Io(String),
+    /// A hardware fault, either the error returned by `MHLib` or `None` if
+    /// the device raised `FLAG_SYSERROR`, with the device's `get_debug_info`
+    /// text to pass on to support.
+    HardwareError(Option<MultiHarpError>, String),
     NotImplemented,
 }
 
@@ -58,24 +62,16 @@ impl<T> IntoFuture for PatinaError<T> where T: Display + Debug + Future {
             PatinaError::ArgumentError(s, t, msg) => panic!("ArgumentError: {} {} {}", s, t, msg),
             PatinaError::NoDeviceAvailable => panic!("NoDeviceAvailable"),
             PatinaError::FeatureNotAvailable(s) => panic!("FeatureNotAvailable: {}", s),
+            PatinaError::WrongMode(s, mode) => panic!("WrongMode: {} {:?}", s, mode),
+            PatinaError::ReferenceLost(clock) => panic!("ReferenceLost: {:?}", clock),
+            PatinaError::VersionMismatch(m) => panic!("VersionMismatch: {}", m),
+            PatinaError::Io(e) => panic!("Io: {}", e),
+            PatinaError::HardwareError(e, info) => panic!("HardwareError: {:?} {}", e, info),
             PatinaError::NotImplemented => panic!("NotImplemented"),
         }
     }
 }
 
-#[cfg(feature = "async")]
-impl <T> From <PatinaError<T>> for AsyncPatinaError<T> where T: Display + Debug + Future {
-    fn from(e: PatinaError<T>) -> Self {
-        match e {
-            PatinaError::MultiHarpError(e) => AsyncPatinaError::MultiHarpError(e),
-            PatinaError::ArgumentError(s, t, msg) => AsyncPatinaError::ArgumentError(s, t, msg),
-            PatinaError::NoDeviceAvailable => AsyncPatinaError::NoDeviceAvailable,
-            PatinaError::FeatureNotAvailable(s) => AsyncPatinaError::FeatureNotAvailable(s),
-            PatinaError::NotImplemented => AsyncPatinaError::NotImplemented,
-        }
-    }
-}
-
 impl<T> Display for PatinaError<T> where T: Display + Debug {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
@@ -88,12 +84,50 @@ impl<T> Display for PatinaError<T> where T: Display + Debug {
             PatinaError::FeatureNotAvailable(feature) => {
                 write!(f, "Feature not available: {}", feature)
             },
+            PatinaError::WrongMode(call, mode) => {
+                write!(f, "`{}` is not available when the device is initialized in {:?} mode", call, mode)
+            },
+            PatinaError::ReferenceLost(clock) => {
+                write!(f, "Reference clock {:?} is not present, refusing to acquire on the internal clock", clock)
+            },
+            PatinaError::VersionMismatch(mismatch) => write!(f, "{}", mismatch),
+            PatinaError::Io(e) => write!(f, "I/O error: {}", e),
+            PatinaError::HardwareError(Some(e), info) => write!(f, "Hardware error: {}. Debug information: {}", e, info),
+            PatinaError::HardwareError(None, info) => write!(f, "The device reported a system error. Debug information: {}", info),
             PatinaError::NoDeviceAvailable => write!(f, "No MultiHarp devices available"),
             PatinaError::NotImplemented => write!(f, "Functionality not implemented in Rust yet"),
         }
     }
 }
 
+impl<T> PatinaError<T> where T : Display + Debug {
+    /// Converts the argument carried by an `ArgumentError`, so that errors
+    /// from setters with differently typed arguments can be combined.
+    pub fn map_argument<U, F>(self, f : F) -> PatinaError<U>
+    where U : Display + Debug, F : FnOnce(T) -> U {
+        match self {
+            PatinaError::MultiHarpError(e) => PatinaError::MultiHarpError(e),
+            PatinaError::ArgumentError(s, t, msg) => PatinaError::ArgumentError(s, f(t), msg),
+            PatinaError::NoDeviceAvailable => PatinaError::NoDeviceAvailable,
+            PatinaError::FeatureNotAvailable(s) => PatinaError::FeatureNotAvailable(s),
+            PatinaError::WrongMode(s, mode) => PatinaError::WrongMode(s, mode),
+            PatinaError::ReferenceLost(clock) => PatinaError::ReferenceLost(clock),
+            PatinaError::VersionMismatch(m) => PatinaError::VersionMismatch(m),
+            PatinaError::Io(e) => PatinaError::Io(e),
+            PatinaError::HardwareError(e, info) => PatinaError::HardwareError(e, info),
+            PatinaError::NotImplemented => PatinaError::NotImplemented,
+        }
+    }
+
+    /// The debug information attached to a `HardwareError`
+    pub fn debug_info(&self) -> Option<&str> {
+        match self {
+            PatinaError::HardwareError(_, info) => Some(info),
+            _ => None,
+        }
+    }
+}
+
 impl <T> From <MultiHarpError> for PatinaError<T> where T: Display + Debug {
     fn from(e: MultiHarpError) -> Self {
         PatinaError::MultiHarpError(e)
@@ -353,4 +387,25 @@ impl std::fmt::Display for MultiHarpError {
     }
 }
 
+impl MultiHarpError {
+    /// Whether the error reports a fault of the device, its USB link or its
+    /// firmware, rather than a misuse of the library, so that the device's
+    /// debug information is worth collecting.
+    pub fn is_hardware_error(&self) -> bool {
+        use MultiHarpError::*;
+        matches!(self,
+            DeviceHEventFail | DeviceCallBSetFail | DeviceBarMapFail | DeviceResetFail
+            | DMAFail | XTDeviceFail | FPGAConfFail | IFConfFail | FIFOResetFail
+            | USBGetIFInfoFail | USBHiSpeedFail | USBVCMDFail | USBBulkReadFail | USBResetFail
+            | LaneupTimeout | DoneAllTimeout | MBAckTimeoint | MActiveTimeout | MemClearFail
+            | MemTestFail | CalibFail | RefSelFail | StatusFail | ModNumberFail | DigMuxFail
+            | ModMuxFail | ModFirmwarePCBMismatch | ModFirmwareVersionMismatch | ModPropertyMismatch
+            | InvalidMagic | InvalidLength | RateFail | MBAckFail
+            | EEPROMF01 | EEPROMF02 | EEPROMF03 | EEPROMF04 | EEPROMF05 | EEPROMF06 | EEPROMF07
+            | EEPROMF08 | EEPROMF09 | EEPROMF10 | EEPROMF11 | EEPROMF12 | EEPROMF13 | EEPROMF14
+            | EEPROMF15
+        )
+    }
+}
+
 impl Error for MultiHarpError {}
\ No newline at end of file
@@ -0,0 +1,481 @@
+//! Suggests Main Filter settings that keep the event rate within what the
+//! USB link sustains, from the measured count rates. The estimate treats
+//! the inputs as uncorrelated, so it counts only the accidental
+//! coincidences the filter lets through; correlated events add to them,
+//! which is why `FilterSuggestion::apply` measures the filtered rates in
+//! filter test mode before leaving the filter on.
+
+use std::time::Duration;
+
+use crate::error::{CheckedResult, PatinaError};
+use crate::mhconsts::{self, MeasurementMode};
+use crate::multiharp::MultiHarpDevice;
+use crate::preflight::{DataRateEstimate, OutputFormat};
+use crate::rates::{measure_rates, RATE_GATE};
+
+/// Events per second a MultiHarp 150 sustains over USB 3.0. Slower
+/// ports and busy hosts sustain less.
+pub const DEFAULT_THROUGHPUT_LIMIT : f64 = 80e6;
+
+/// Settings of the Main Filter, which acts on the events of all rows.
+///
+/// ## Example
+///
+/// ```
+/// use multi_harp_patina::*;
+///
+/// // Keep only events with another event within 1 ns on channels 0 to 3
+/// let filter = MainEventFilter::coincidences(&[0, 1, 2, 3], 1000);
+/// let filtered = filter.estimate(&[1e6, 1e6, 1e6, 1e6]);
+/// assert!(filtered.iter().all(|rate| *rate < 1e4));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct MainEventFilter {
+    /// Time in ps around an event in which the other events are counted,
+    /// `TIMERANGEMIN..=TIMERANGEMAX`
+    pub time_range : i32,
+    /// Other events needed in `time_range` to keep an event,
+    /// `MATCHCNTMIN..=MATCHCNTMAX`
+    pub match_count : i32,
+    /// Keeps the events the filter would remove, and removes the rest
+    pub inverse : bool,
+    /// Input channels the filter acts on and counts events of
+    pub use_channels : Vec<i32>,
+    /// Input channels passed whatever the filter decides
+    pub pass_channels : Vec<i32>,
+    /// Whether the filter acts on and counts the sync events. Not part
+    /// of `estimate`.
+    pub use_sync : bool,
+    /// Whether the sync events are passed whatever the filter decides
+    pub pass_sync : bool,
+}
+
+impl MainEventFilter {
+    /// A singles filter: keeps the events on `channels` with at least one
+    /// other event on any of them within `time_range` ps.
+    pub fn coincidences(channels : &[i32], time_range : i32) -> Self {
+        MainEventFilter {
+            time_range,
+            match_count : 1,
+            inverse : false,
+            use_channels : channels.to_vec(),
+            pass_channels : Vec::new(),
+            use_sync : false,
+            pass_sync : false,
+        }
+    }
+
+    /// The rates left on each input after the filter, for uncorrelated
+    /// inputs at `inputs`. Channels the filter neither uses nor passes
+    /// are taken to keep their rate.
+    pub fn estimate(&self, inputs : &[f64]) -> Vec<f64> {
+        let used = |channel : usize| self.use_channels.contains(&(channel as i32))
+            && !self.pass_channels.contains(&(channel as i32));
+        let used_rate : f64 = inputs.iter().enumerate()
+            .filter(|(channel, _)| used(*channel))
+            .map(|(_, rate)| rate)
+            .sum();
+        // Other events within `time_range` either side are Poisson
+        let expected = used_rate * 2.0 * self.time_range as f64 * 1e-12;
+        let mut term = (-expected).exp();
+        let mut fewer = 0.0;
+        for k in 0..self.match_count.max(0) {
+            fewer += term;
+            term *= expected / (k + 1) as f64;
+        }
+        let kept = if self.inverse { fewer.min(1.0) } else { (1.0 - fewer).max(0.0) };
+
+        inputs.iter().enumerate()
+            .map(|(channel, rate)| if used(channel) { rate * kept } else { *rate })
+            .collect()
+    }
+}
+
+/// Main Filter settings that bring the input rates within a budget, with
+/// the rates before and after.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilterSuggestion {
+    /// `None` if the rates are within the budget without a filter
+    pub filter : Option<MainEventFilter>,
+    /// Input events per second that fit
+    pub budget : f64,
+    /// Sum of the input rates without a filter
+    pub unfiltered : f64,
+    /// Sum of the input rates expected with `filter`
+    pub estimated : f64,
+}
+
+/// What `FilterSuggestion::apply` measured in filter test mode
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilterVerification {
+    /// Sum of the input rates after the Main Filter
+    pub filtered : f64,
+    /// Whether the filter was left enabled. It is disabled again if
+    /// `filtered` exceeds the budget.
+    pub applied : bool,
+}
+
+impl FilterSuggestion {
+    /// The singles filter on every channel with counts with the longest
+    /// time range that keeps the sum of `inputs` within `budget`.
+    ///
+    /// ## Errors
+    ///
+    /// If even a 1 ps time range leaves too many events.
+    pub fn for_rates(inputs : &[f64], budget : f64) -> Result<Self, String> {
+        let unfiltered : f64 = inputs.iter().sum();
+        if unfiltered <= budget {
+            return Ok(FilterSuggestion { filter : None, budget, unfiltered, estimated : unfiltered });
+        }
+        let channels = inputs.iter().enumerate()
+            .filter(|(_, rate)| **rate > 0.0)
+            .map(|(channel, _)| channel as i32)
+            .collect::<Vec<_>>();
+        let filtered = |time_range : i32| -> f64 {
+            MainEventFilter::coincidences(&channels, time_range).estimate(inputs).iter().sum()
+        };
+
+        if filtered(1) > budget {
+            return Err(format!("No time range keeps {:.3e} events/s within {:.3e}", unfiltered, budget));
+        }
+        // The filtered rate grows with the time range
+        let (mut fits, mut too_long) = (1, mhconsts::TIMERANGEMAX + 1);
+        while too_long - fits > 1 {
+            let middle = fits + (too_long - fits) / 2;
+            if filtered(middle) <= budget { fits = middle } else { too_long = middle }
+        }
+        Ok(FilterSuggestion {
+            filter : Some(MainEventFilter::coincidences(&channels, fits)),
+            budget,
+            unfiltered,
+            estimated : filtered(fits),
+        })
+    }
+
+    /// Sets and enables the suggested filter, then runs the device in
+    /// filter test mode, which keeps the events out of the FIFO, to
+    /// measure the rates after it. The filter stays enabled only if they
+    /// are within the budget. Without a filter to suggest, disables the
+    /// Main Filter.
+    pub fn apply<MH : MultiHarpDevice>(&self, multiharp : &mut MH) -> CheckedResult<FilterVerification, i32> {
+        let filter = match &self.filter {
+            Some(filter) => filter,
+            None => {
+                multiharp.enable_main_event_filter(false)?;
+                return Ok(FilterVerification { filtered : self.unfiltered, applied : false });
+            },
+        };
+        multiharp.set_main_event_filter(filter)?;
+        multiharp.enable_main_event_filter(true)?;
+        multiharp.set_filter_test_mode(true)?;
+
+        let measured = multiharp.start_measurement(mhconsts::ACQTMAX).and_then(|_| {
+            // Two gates, so that one full reading is taken with the filter on
+            std::thread::sleep(2 * RATE_GATE);
+            multiharp.get_main_filtered_rates()
+        });
+        let stopped = multiharp.stop_measurement().map_err(PatinaError::MultiHarpError);
+        multiharp.set_filter_test_mode(false)?;
+        let (_, rates) = measured?;
+        stopped?;
+
+        let filtered = rates.iter().map(|rate| *rate as f64).sum::<f64>();
+        let applied = filtered <= self.budget;
+        if !applied {
+            multiharp.enable_main_event_filter(false)?;
+        }
+        Ok(FilterVerification { filtered, applied })
+    }
+}
+
+/// Measures the count rates and suggests the Main Filter settings that
+/// keep all the records the device sends within `limit` per second
+/// (e.g. `DEFAULT_THROUGHPUT_LIMIT`). The syncs of T2 mode and the
+/// overflow records aren't filtered, so they come out of the budget for
+/// the inputs.
+///
+/// ## Example
+///
+/// ```
+/// use multi_harp_patina::*;
+///
+/// let mut mh = DebugMultiHarp150::default();
+/// mh.init(MeasurementMode::T3, ReferenceClock::Internal).unwrap();
+/// // The debug device's inputs count 1e5 per second each
+/// let suggestion = suggest_event_filter(&mh, 2e5).unwrap();
+/// let filter = suggestion.filter.clone().unwrap();
+/// assert_eq!(filter.use_channels, vec![0, 1, 2, 3]);
+/// assert!(suggestion.estimated <= suggestion.budget);
+///
+/// let verification = suggestion.apply(&mut mh).unwrap();
+/// assert!(verification.applied);
+/// ```
+pub fn suggest_event_filter<MH : MultiHarpDevice>(multiharp : &MH, limit : f64) -> CheckedResult<FilterSuggestion, i32> {
+    let mode = multiharp.get_measurement_mode();
+    if mode == MeasurementMode::Histogramming {
+        return Err(PatinaError::WrongMode("suggest_event_filter".to_string(), mode));
+    }
+    let rates = measure_rates(multiharp, RATE_GATE).map_err(PatinaError::MultiHarpError)?;
+    let resolution = multiharp.get_resolution().map_err(PatinaError::MultiHarpError)?;
+    let config = multiharp.current_settings();
+    let records = DataRateEstimate::new(mode, &config, &rates, resolution, OutputFormat::RawRecords)
+        .records_per_second;
+    let enabled = multiharp.get_input_channel_enables().map_err(PatinaError::MultiHarpError)?;
+    let inputs = rates.inputs.iter().enumerate()
+        .map(|(channel, rate)| if enabled.get(channel).copied().unwrap_or(true) { *rate } else { 0.0 })
+        .collect::<Vec<_>>();
+    let unfiltered_records = records - inputs.iter().sum::<f64>();
+
+    FilterSuggestion::for_rates(&inputs, limit - unfiltered_records)
+        .map_err(|e| PatinaError::ArgumentError("limit".to_string(), limit as i32, e))
+}
+
+/// Which filter a `FilterTuner` sweeps
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventFilterStage {
+    /// The Row Filters of the rows with the tuned channels, read with
+    /// `get_row_filtered_rates`
+    Row,
+    /// The Main Filter, read with `get_main_filtered_rates`
+    Main,
+}
+
+/// One setting tried by a `FilterTuner`
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilterTrial {
+    pub filter : MainEventFilter,
+    /// Sum of the input rates measured after the filter
+    pub filtered : f64,
+}
+
+/// The settings a `FilterTuner` tried, in order, and the one it found best
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilterTuning {
+    pub trials : Vec<FilterTrial>,
+    /// Input events per second that fit
+    pub budget : f64,
+    /// Sum of the input rates before the filters
+    pub unfiltered : f64,
+}
+
+impl FilterTuning {
+    /// The trial keeping the most events within the budget, if any fit
+    pub fn best(&self) -> Option<&FilterTrial> {
+        self.trials.iter()
+            .filter(|trial| trial.filtered <= self.budget)
+            .fold(None, |best : Option<&FilterTrial>, trial| match best {
+                Some(best) if best.filtered >= trial.filtered => Some(best),
+                _ => Some(trial),
+            })
+    }
+}
+
+/// Finds filter settings empirically: sweeps the time range and match
+/// count of the row or main filters in filter test mode, which keeps the
+/// events out of the FIFO, measuring the rates after the filter at each.
+/// Unlike `suggest_event_filter`, the rates include the correlations
+/// between the inputs.
+///
+/// For each match count the time ranges are tried from the shortest,
+/// and the sweep moves on at the first that leaves too many events,
+/// since longer ones keep more.
+///
+/// ## Example
+///
+/// ```
+/// use std::time::Duration;
+/// use multi_harp_patina::*;
+///
+/// let mut mh = DebugMultiHarp150::default();
+/// mh.init(MeasurementMode::T2, ReferenceClock::Internal).unwrap();
+/// // The debug device's inputs count 1e5 per second each
+/// let tuning = FilterTuner::new(&[0, 1, 2, 3], 1e5)
+///     .with_match_counts(vec![1, 2])
+///     .with_settle_time(Duration::ZERO)
+///     .tune(&mut mh)
+///     .unwrap();
+/// let best = tuning.best().unwrap();
+/// assert!(best.filtered <= 1e5);
+/// mh.set_main_event_filter(&best.filter).unwrap();
+/// mh.enable_main_event_filter(true).unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct FilterTuner {
+    channels : Vec<i32>,
+    budget : f64,
+    stage : EventFilterStage,
+    time_ranges : Vec<i32>,
+    match_counts : Vec<i32>,
+    settle_time : Duration,
+}
+
+impl FilterTuner {
+    /// Tunes a singles filter using `channels` to keep the sum of the
+    /// input rates within `budget` per second, with the Main Filter, time
+    /// ranges of 100 ps to `TIMERANGEMAX` in 1-2-5 steps and match counts
+    /// of 1 to 3.
+    pub fn new(channels : &[i32], budget : f64) -> Self {
+        let time_ranges = (2..6)
+            .flat_map(|decade| [1, 2, 5].map(|step| step * 10i32.pow(decade)))
+            .filter(|time_range| *time_range < mhconsts::TIMERANGEMAX)
+            .chain(std::iter::once(mhconsts::TIMERANGEMAX))
+            .collect();
+        FilterTuner {
+            channels : channels.to_vec(),
+            budget,
+            stage : EventFilterStage::Main,
+            time_ranges,
+            match_counts : vec![1, 2, 3],
+            settle_time : 2 * RATE_GATE,
+        }
+    }
+
+    pub fn with_stage(mut self, stage : EventFilterStage) -> Self {
+        self.stage = stage;
+        self
+    }
+
+    /// Time ranges to try, in ps
+    pub fn with_time_ranges(mut self, mut time_ranges : Vec<i32>) -> Self {
+        time_ranges.sort_unstable();
+        self.time_ranges = time_ranges;
+        self
+    }
+
+    pub fn with_match_counts(mut self, match_counts : Vec<i32>) -> Self {
+        self.match_counts = match_counts;
+        self
+    }
+
+    /// How long to wait after setting a filter before reading the rates.
+    /// Two rate gates by default, so that one full reading is taken with
+    /// the filter on.
+    pub fn with_settle_time(mut self, settle_time : Duration) -> Self {
+        self.settle_time = settle_time;
+        self
+    }
+
+    /// Runs the sweep in one measurement in filter test mode. The filter
+    /// swept is disabled again afterwards, and test mode is left.
+    ///
+    /// ## Errors
+    ///
+    /// * `WrongMode` - In Histogramming mode.
+    /// * Any error setting a filter or reading the rates.
+    pub fn tune<MH : MultiHarpDevice>(&self, multiharp : &mut MH) -> CheckedResult<FilterTuning, i32> {
+        let mode = multiharp.get_measurement_mode();
+        if mode == MeasurementMode::Histogramming {
+            return Err(PatinaError::WrongMode("FilterTuner::tune".to_string(), mode));
+        }
+        multiharp.set_filter_test_mode(true)?;
+        let swept = multiharp.start_measurement(mhconsts::ACQTMAX)
+            .and_then(|_| self.sweep(multiharp));
+        let stopped = multiharp.stop_measurement().map_err(PatinaError::MultiHarpError);
+        let disabled = match self.stage {
+            EventFilterStage::Main => multiharp.enable_main_event_filter(false),
+            EventFilterStage::Row => multiharp.set_row_event_filters(&MainEventFilter::coincidences(&[], 0)),
+        };
+        multiharp.set_filter_test_mode(false)?;
+        let tuning = swept?;
+        stopped?;
+        disabled?;
+        Ok(tuning)
+    }
+
+    fn sweep<MH : MultiHarpDevice>(&self, multiharp : &mut MH) -> CheckedResult<FilterTuning, i32> {
+        let (_, unfiltered) = multiharp.get_all_count_rates().map_err(PatinaError::MultiHarpError)?;
+        let mut tuning = FilterTuning {
+            trials : Vec::new(),
+            budget : self.budget,
+            unfiltered : unfiltered.iter().map(|rate| *rate as f64).sum(),
+        };
+        for match_count in self.match_counts.iter() {
+            for time_range in self.time_ranges.iter() {
+                let filter = MainEventFilter {
+                    match_count : *match_count,
+                    ..MainEventFilter::coincidences(&self.channels, *time_range)
+                };
+                let (_, rates) = match self.stage {
+                    EventFilterStage::Main => {
+                        multiharp.set_main_event_filter(&filter)?;
+                        multiharp.enable_main_event_filter(true)?;
+                        std::thread::sleep(self.settle_time);
+                        multiharp.get_main_filtered_rates()?
+                    },
+                    EventFilterStage::Row => {
+                        multiharp.set_row_event_filters(&filter)?;
+                        std::thread::sleep(self.settle_time);
+                        multiharp.get_row_filtered_rates()?
+                    },
+                };
+                let filtered = rates.iter().map(|rate| *rate as f64).sum::<f64>();
+                tuning.trials.push(FilterTrial { filter, filtered });
+                if filtered > self.budget {
+                    break;
+                }
+            }
+        }
+        Ok(tuning)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_estimate() {
+        let filter = MainEventFilter { pass_channels : vec![3], ..MainEventFilter::coincidences(&[0, 1, 3], 500) };
+        let filtered = filter.estimate(&[1e7, 1e7, 1e5, 1e5]);
+        // 2e7 events/s over 1 ns: 2% chance of another
+        let kept = 1.0 - (-0.02f64).exp();
+        assert!((filtered[0] - 1e7 * kept).abs() < 1e-3);
+        assert_eq!(filtered[2], 1e5);
+        assert_eq!(filtered[3], 1e5);
+
+        let inverse = MainEventFilter { inverse : true, ..filter.clone() }.estimate(&[1e7, 1e7, 1e5, 1e5]);
+        assert!((inverse[0] + filtered[0] - 1e7).abs() < 1e-3);
+        let pairs = MainEventFilter { match_count : 2, ..filter }.estimate(&[1e7, 1e7, 1e5, 1e5]);
+        assert!(pairs[0] < filtered[0] / 50.0);
+    }
+
+    #[test]
+    fn test_filter_suggestion() {
+        let inputs = [5e7, 5e7, 0.0, 1e6];
+        assert_eq!(FilterSuggestion::for_rates(&inputs, 2e8).unwrap().filter, None);
+
+        let suggestion = FilterSuggestion::for_rates(&inputs, 1e7).unwrap();
+        let filter = suggestion.filter.as_ref().unwrap();
+        assert_eq!(filter.use_channels, vec![0, 1, 3]);
+        assert!(suggestion.estimated <= 1e7);
+        // One ps longer doesn't fit
+        let longer = MainEventFilter { time_range : filter.time_range + 1, ..filter.clone() };
+        assert!(longer.estimate(&inputs).iter().sum::<f64>() > 1e7);
+
+        assert!(FilterSuggestion::for_rates(&inputs, 1.0).is_err());
+    }
+
+    #[test]
+    fn test_filter_tuner() {
+        use crate::{DebugMultiHarp150, ReferenceClock};
+
+        let mut mh = DebugMultiHarp150::default();
+        mh.init(MeasurementMode::T2, ReferenceClock::Internal).unwrap();
+        let tuner = FilterTuner::new(&[0, 1], 1e3)
+            .with_time_ranges(vec![10000, 1000, 100000])
+            .with_match_counts(vec![1, 2])
+            .with_settle_time(Duration::ZERO);
+        let tuning = tuner.tune(&mut mh).unwrap();
+        assert_eq!(tuning.unfiltered, 4e5);
+        // The debug Main Filter keeps the rates of channels 2 and 3, which it doesn't use
+        assert!(tuning.best().is_none());
+        assert_eq!(tuning.trials[0].filter.time_range, 1000);
+
+        let tuning = tuner.with_stage(EventFilterStage::Row).tune(&mut mh).unwrap();
+        let best = tuning.best().unwrap();
+        assert!(best.filtered <= 1e3);
+        // Longer time ranges and fewer matches keep more
+        assert!(tuning.trials.iter().filter(|t| t.filtered <= 1e3).all(|t| t.filtered <= best.filtered));
+        assert_eq!(mh.get_row_filtered_rates().unwrap().1, vec![100000; 4]);
+    }
+}
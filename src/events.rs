@@ -0,0 +1,152 @@
+//! The last few events of each device -- errors, warnings, flag changes
+//! and applied settings -- kept in memory for post-mortem debugging.
+//! They are returned by `MultiHarpDevice::recent_events`, attached to
+//! `PatinaError::HardwareError` and written into session manifests.
+//!
+//! Events are kept per serial number, process-wide, so they outlive the
+//! device that raised them (e.g. one dropped after a fatal error).
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::metadata::json_string;
+
+/// Number of events kept per device unless `set_event_log_capacity` is called
+pub const DEFAULT_EVENT_CAPACITY : usize = 256;
+
+static CAPACITY : AtomicUsize = AtomicUsize::new(DEFAULT_EVENT_CAPACITY);
+static EVENTS : Mutex<Option<HashMap<String, VecDeque<DeviceEvent>>>> = Mutex::new(None);
+
+/// What a `DeviceEvent` reports
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceEventKind {
+    /// A call to the device failed
+    Error,
+    /// The device raised warnings, or an argument was clamped
+    Warning,
+    /// The device flags changed
+    Flags,
+    /// A setting was applied
+    Setting,
+}
+
+/// One entry of the event log of a device.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceEvent {
+    pub serial : String,
+    pub kind : DeviceEventKind,
+    pub message : String,
+    pub timestamp : SystemTime,
+}
+
+impl DeviceEvent {
+    /// The event as a JSON object, e.g.
+    /// `{"timestamp_us":1700000000000000,"kind":"Setting","message":"sync_div: 1 -> 2"}`
+    pub fn to_json(&self) -> String {
+        let timestamp_us = self.timestamp.duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros())
+            .unwrap_or(0);
+        format!(
+            "{{\"timestamp_us\":{},\"kind\":\"{:?}\",\"message\":{}}}",
+            timestamp_us, self.kind, json_string(&self.message)
+        )
+    }
+}
+
+impl std::fmt::Display for DeviceEvent {
+    fn fmt(&self, f : &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let since_epoch = self.timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+        write!(f, "[{}.{:06}] {:?}: {}", since_epoch.as_secs(), since_epoch.subsec_micros(), self.kind, self.message)
+    }
+}
+
+/// Keeps the last `capacity` events (at least 1) of each device from now
+/// on, dropping the oldest of any device that has more.
+pub fn set_event_log_capacity(capacity : usize) {
+    let capacity = capacity.max(1);
+    CAPACITY.store(capacity, Ordering::Relaxed);
+    if let Some(events) = EVENTS.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        for ring in events.values_mut() {
+            while ring.len() > capacity {
+                ring.pop_front();
+            }
+        }
+    }
+}
+
+/// The events kept for the device `serial`, oldest first.
+///
+/// ## Example
+///
+/// ```
+/// use multi_harp_patina::*;
+///
+/// let mut mh = DebugMultiHarp150::default();
+/// mh.set_sync_div(4).unwrap();
+/// assert!(recent_events(&mh.get_serial()).iter()
+///     .any(|e| e.kind == DeviceEventKind::Setting && e.message.starts_with("sync_div")));
+/// ```
+pub fn recent_events(serial : &str) -> Vec<DeviceEvent> {
+    EVENTS.lock().unwrap_or_else(|e| e.into_inner()).as_ref()
+        .and_then(|events| events.get(serial))
+        .map(|ring| ring.iter().cloned().collect())
+        .unwrap_or_default()
+}
+
+/// Forgets the events of the device `serial`.
+pub fn clear_events(serial : &str) {
+    if let Some(events) = EVENTS.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        events.remove(serial);
+    }
+}
+
+/// Adds an event to the log of the device `serial`.
+pub(crate) fn record(serial : &str, kind : DeviceEventKind, message : String) {
+    let capacity = CAPACITY.load(Ordering::Relaxed);
+    let event = DeviceEvent { serial : serial.to_string(), kind, message, timestamp : SystemTime::now() };
+    let mut events = EVENTS.lock().unwrap_or_else(|e| e.into_inner());
+    let ring = events.get_or_insert_with(HashMap::new)
+        .entry(event.serial.clone())
+        .or_default();
+    while ring.len() >= capacity {
+        ring.pop_front();
+    }
+    ring.push_back(event);
+}
+
+/// The events of `serial` as a JSON array, for manifests
+pub(crate) fn events_json(serial : &str) -> String {
+    let events = recent_events(serial).iter().map(DeviceEvent::to_json).collect::<Vec<_>>();
+    format!("[{}]", events.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_ring() {
+        // Other tests share the capacity, so use a serial of our own and
+        // stay below the default
+        let serial = "events-test";
+        for i in 0..DEFAULT_EVENT_CAPACITY + 10 {
+            record(serial, DeviceEventKind::Flags, format!("{}", i));
+        }
+        let events = recent_events(serial);
+        assert_eq!(events.len(), DEFAULT_EVENT_CAPACITY);
+        assert_eq!(events[0].message, "10");
+        assert_eq!(events.last().unwrap().message, format!("{}", DEFAULT_EVENT_CAPACITY + 9));
+        assert!(events.iter().all(|e| e.serial == serial));
+
+        let json = events[0].to_json();
+        assert!(json.starts_with("{\"timestamp_us\":"));
+        assert!(json.ends_with(",\"kind\":\"Flags\",\"message\":\"10\"}"));
+
+        clear_events(serial);
+        assert!(recent_events(serial).is_empty());
+        assert_eq!(events_json(serial), "[]");
+        assert!(recent_events("no-such-device").is_empty());
+    }
+}
@@ -0,0 +1,512 @@
+//! The external FPGA interface of a MultiHarp 160: an `ExtFpga` handle
+//! on its links, mode and user commands, the decoding of the T2Raw stream
+//! the device sends to the FPGA in `ExtFpgaMode::T2Raw`, and a source
+//! that feeds it through a `Pipeline` as ordinary T2 records.
+//!
+//! In T2Raw mode the device doesn't fold timetags into 25 bit records
+//! with overflows: every event is a 64 bit word with its full timetag.
+//! The layout decoded here is
+//!
+//! | Bits  | Field                                                        |
+//! |-------|--------------------------------------------------------------|
+//! | 0-47  | Timetag in units of the base resolution, from the start      |
+//! | 48-54 | Channel: 0 for the sync, 1 to 64 for inputs 0 to 63, or the marker bitfield |
+//! | 55-62 | Reserved, 0                                                  |
+//! | 63    | Set for markers                                              |
+//!
+//! with words stored little endian. Check it against the interface
+//! description of the FPGA firmware in use before relying on it: words
+//! with reserved bits set, or out of range channels, are skipped.
+
+use std::fmt::Display;
+use std::io::{self, Read};
+use std::time::Instant;
+
+use crate::error::{CheckedResult, PatinaError};
+use crate::mhconsts::{self, ExtFpgaLoopback, ExtFpgaMode, FeatureMasks, MeasurementMode};
+use crate::multiharp::MultiHarpDevice;
+use crate::pipeline::{Batch, Pipeline, PipelineError, StreamInfo};
+use crate::tttr::{Encoder, OverflowCompression, TttrEvent, MAX_MARKER_CHANNEL};
+
+/// Bytes per word of the T2Raw stream
+pub const T2RAW_WORD_LEN : usize = 8;
+/// Mask of the timetag of a T2Raw word
+pub const T2RAW_TIMETAG : u64 = (1 << 48) - 1;
+const T2RAW_CHANNEL_SHIFT : u32 = 48;
+const T2RAW_CHANNEL : u64 = 0x7F << T2RAW_CHANNEL_SHIFT;
+const T2RAW_RESERVED : u64 = 0xFF << 55;
+const T2RAW_MARKER : u64 = 1 << 63;
+
+/// Packs the fields of a T2Raw word. Out of range fields are truncated.
+pub fn pack_t2raw(marker : bool, channel : u8, timetag : u64) -> u64 {
+    ((marker as u64) << 63)
+    | (((channel as u64) << T2RAW_CHANNEL_SHIFT) & T2RAW_CHANNEL)
+    | (timetag & T2RAW_TIMETAG)
+}
+
+/// Unpacks T2Raw words into `TttrEvent`s with the same channel numbering
+/// and time units as a T2 `Decoder` on the device's FIFO.
+#[derive(Debug, Clone, Default)]
+pub struct T2RawDecoder {
+    skipped : u64,
+}
+
+impl T2RawDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the event in `word`, or `None` for a word that doesn't
+    /// decode to an event (counted in `skipped`).
+    pub fn decode(&mut self, word : u64) -> Option<TttrEvent> {
+        let channel = ((word & T2RAW_CHANNEL) >> T2RAW_CHANNEL_SHIFT) as u8;
+        let time = word & T2RAW_TIMETAG;
+        let event = match (word & T2RAW_MARKER != 0, channel) {
+            _ if word & T2RAW_RESERVED != 0 => None,
+            (true, 1..=MAX_MARKER_CHANNEL) => Some(TttrEvent::marker(channel, time)),
+            (true, _) => None,
+            (false, 0) => Some(TttrEvent::sync(time)),
+            (false, 1..=64) => Some(TttrEvent::photon(channel - 1, time, 0)),
+            (false, _) => None,
+        };
+        if event.is_none() {
+            self.skipped += 1;
+        }
+        event
+    }
+
+    /// Words that didn't decode to an event
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+}
+
+/// Reads a T2Raw stream, e.g. a file or pipe written by the FPGA's host
+/// interface, and feeds it through a `Pipeline` as T2 records, in batches
+/// of up to `TTREADMAX` words. Processors see the same stream as from
+/// `Pipeline::run` on a device in T2 mode.
+///
+/// ## Example
+///
+/// ```
+/// use multi_harp_patina::*;
+///
+/// let words = [pack_t2raw(false, 0, 1000), pack_t2raw(false, 3, 1250)];
+/// let bytes = words.iter().flat_map(|w| w.to_le_bytes()).collect::<Vec<_>>();
+///
+/// let mut source = T2RawSource::new(&bytes[..], "1044272", 4, 5.0);
+/// let mut pipeline = Pipeline::new();
+/// assert_eq!(source.run(&mut pipeline).unwrap(), 2);
+/// ```
+pub struct T2RawSource<R : Read> {
+    reader : R,
+    serial : String,
+    num_channels : i32,
+    resolution : f64,
+    decoder : T2RawDecoder,
+}
+
+impl<R : Read> T2RawSource<R> {
+    /// A source for the stream of the device `serial`, with `num_channels`
+    /// inputs and a base resolution of `resolution` ps.
+    pub fn new(reader : R, serial : &str, num_channels : i32, resolution : f64) -> Self {
+        T2RawSource {
+            reader,
+            serial : serial.to_string(),
+            num_channels,
+            resolution,
+            decoder : T2RawDecoder::new(),
+        }
+    }
+
+    /// Words of the stream that didn't decode to an event
+    pub fn skipped(&self) -> u64 {
+        self.decoder.skipped()
+    }
+
+    /// Fills `words` with as many whole words as fit in `bytes`. Fewer
+    /// means the stream ended.
+    fn read_words(&mut self, bytes : &mut [u8], words : &mut Vec<u64>) -> io::Result<()> {
+        let mut filled = 0;
+        while filled < bytes.len() {
+            match self.reader.read(&mut bytes[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        if filled % T2RAW_WORD_LEN != 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "T2Raw stream ends mid-word"));
+        }
+        words.clear();
+        words.extend(bytes[..filled].chunks_exact(T2RAW_WORD_LEN)
+            .map(|b| u64::from_le_bytes(b.try_into().unwrap())));
+        Ok(())
+    }
+
+    /// Feeds the whole stream through `pipeline`, from `init` to `finalize`.
+    ///
+    /// ## Returns
+    ///
+    /// * The number of events decoded
+    ///
+    /// ## Errors
+    ///
+    /// * `PipelineError::Device` - If the stream can't be read, ends
+    ///   mid-word, or goes back in time.
+    /// * `PipelineError::Processor` - If a processor fails. Processors
+    ///   are still finalized.
+    pub fn run(&mut self, pipeline : &mut Pipeline) -> Result<u64, PipelineError> {
+        let info = StreamInfo {
+            serial : self.serial.clone(),
+            mode : MeasurementMode::T2,
+            resolution : self.resolution,
+            num_channels : self.num_channels,
+            enabled_channels : vec![true; self.num_channels.max(0) as usize],
+            overflow_compression : OverflowCompression::Compressed,
+            labels : pipeline.labels().clone(),
+        };
+        pipeline.init(&info)?;
+
+        let mut encoder = Encoder::new(MeasurementMode::T2).map_err(PipelineError::Device)?;
+        let mut bytes = vec![0u8; mhconsts::TTREADMAX * T2RAW_WORD_LEN];
+        let mut words = Vec::with_capacity(mhconsts::TTREADMAX);
+        let mut records = Vec::with_capacity(mhconsts::TTREADMAX);
+        let mut index = 0u64;
+        let mut total = 0u64;
+
+        let streamed = (|| {
+            loop {
+                self.read_words(&mut bytes, &mut words)
+                    .map_err(|e| PipelineError::Device(format!("Reading the T2Raw stream: {}", e)))?;
+                if words.is_empty() {
+                    return Ok(());
+                }
+                let read_at = Instant::now();
+                records.clear();
+                for word in words.iter() {
+                    if let Some(event) = self.decoder.decode(*word) {
+                        encoder.encode(&event, &mut records).map_err(PipelineError::Device)?;
+                        total += 1;
+                    }
+                }
+                pipeline.process_batch(&Batch { index, read_at, records : &records, mode : MeasurementMode::T2, sync_lost : false })?;
+                index += 1;
+            }
+        })();
+
+        let finalized = pipeline.finalize();
+        streamed.and(finalized).map(|_| total)
+    }
+}
+
+/// The external FPGA interface of `multiharp`, borrowed from it by
+/// `MultiHarpDevice::ext_fpga` once the device has reported the
+/// `ExtFpga` feature.
+///
+/// ## Example
+///
+/// ```
+/// use multi_harp_patina::*;
+///
+/// let mut mh = DebugMultiHarp150::default();
+/// let mut fpga = mh.ext_fpga().unwrap();
+/// fpga.init_link(0, true).unwrap();
+/// assert_ne!(fpga.link_status(0).unwrap(), 0);
+/// fpga.set_mode(ExtFpgaMode::T2Raw, ExtFpgaLoopback::Off).unwrap();
+/// fpga.reset_stream_fifos().unwrap();
+///
+/// fpga.user_command(true, 0x10, 42).unwrap();
+/// assert_eq!(fpga.user_command(false, 0x10, 0).unwrap(), 42);
+/// ```
+pub struct ExtFpga<'a, MH : MultiHarpDevice> {
+    multiharp : &'a mut MH,
+    stride : u32,
+}
+
+impl<'a, MH : MultiHarpDevice> ExtFpga<'a, MH> {
+    /// See `MultiHarpDevice::ext_fpga`.
+    ///
+    /// ## Errors
+    ///
+    /// * `PatinaError::FeatureNotAvailable` - If `get_features` doesn't
+    ///   include `FeatureMasks::ExtFpga`.
+    pub fn new(multiharp : &'a mut MH) -> CheckedResult<Self, i32> {
+        if multiharp.get_features()? & FeatureMasks::ExtFpga as i32 == 0 {
+            return Err(PatinaError::FeatureNotAvailable(format!("External FPGA interface of {}", multiharp.get_serial())));
+        }
+        Ok(ExtFpga { multiharp, stride : 1 })
+    }
+
+    /// Switches the link `link` to the FPGA on or off.
+    pub fn init_link(&mut self, link : i32, on : bool) -> CheckedResult<(), i32> {
+        self.multiharp.ext_fpga_init_link(link, on)
+    }
+
+    /// The status word of the link `link`, as described in the interface
+    /// documentation of the FPGA.
+    pub fn link_status(&self, link : i32) -> CheckedResult<u32, i32> {
+        self.multiharp.ext_fpga_link_status(link)
+    }
+
+    /// What the device streams to the FPGA, and what it loops back.
+    pub fn set_mode(&mut self, mode : ExtFpgaMode, loopback : ExtFpgaLoopback) -> CheckedResult<(), i32> {
+        self.multiharp.ext_fpga_set_mode(mode, loopback)
+    }
+
+    /// Empties the FIFOs of the streams to and from the FPGA.
+    pub fn reset_stream_fifos(&mut self) -> CheckedResult<(), i32> {
+        self.multiharp.ext_fpga_reset_stream_fifos()
+    }
+
+    /// Sends a user command to the FPGA firmware: writes `data` to `addr`
+    /// if `write`, otherwise reads `addr`, returning the data the
+    /// firmware answers with.
+    pub fn user_command(&mut self, write : bool, addr : u32, data : u32) -> CheckedResult<u32, i32> {
+        self.multiharp.ext_fpga_user_command(write, addr, data)
+    }
+
+    /// How far apart consecutive registers are in the addresses of the
+    /// user commands, for `read_regs` and `write_regs` (default 1). With
+    /// 0 they read or write the same register every time, e.g. a FIFO.
+    pub fn with_register_stride(mut self, stride : u32) -> Self {
+        self.stride = stride;
+        self
+    }
+
+    /// Reads the register at `addr`.
+    pub fn read_reg(&mut self, addr : u32) -> Result<u32, RegisterError> {
+        self.user_command(false, addr, 0)
+            .map_err(|error| RegisterError::new(addr, false, 0, error))
+    }
+
+    /// Writes `value` to the register at `addr`.
+    pub fn write_reg(&mut self, addr : u32, value : u32) -> Result<(), RegisterError> {
+        self.user_command(true, addr, value)
+            .map(|_| ())
+            .map_err(|error| RegisterError::new(addr, true, 0, error))
+    }
+
+    /// Reads `count` consecutive registers from `addr` on, stopping at
+    /// the first that fails.
+    pub fn read_regs(&mut self, addr : u32, count : usize) -> Result<Vec<u32>, RegisterError> {
+        let addrs = self.addresses(addr, count, false)?;
+        let mut values = Vec::with_capacity(count);
+        for addr in addrs {
+            let value = self.user_command(false, addr, 0)
+                .map_err(|error| RegisterError::new(addr, false, values.len(), error))?;
+            values.push(value);
+        }
+        Ok(values)
+    }
+
+    /// Writes `values` to consecutive registers from `addr` on, stopping
+    /// at the first that fails.
+    pub fn write_regs(&mut self, addr : u32, values : &[u32]) -> Result<(), RegisterError> {
+        let addrs = self.addresses(addr, values.len(), true)?;
+        for (written, (addr, value)) in addrs.zip(values).enumerate() {
+            self.user_command(true, addr, *value)
+                .map_err(|error| RegisterError::new(addr, true, written, error))?;
+        }
+        Ok(())
+    }
+
+    /// Reads the register `R`.
+    pub fn read<R : FpgaRegister>(&mut self) -> Result<R, RegisterError> {
+        self.read_reg(R::ADDR).map(R::from_bits)
+    }
+
+    /// Writes `register` to its address.
+    pub fn write<R : FpgaRegister>(&mut self, register : &R) -> Result<(), RegisterError> {
+        self.write_reg(R::ADDR, register.bits())
+    }
+
+    /// The addresses of `count` registers from `addr` on, if all of them
+    /// are addressable.
+    fn addresses(&self, addr : u32, count : usize, write : bool) -> Result<impl Iterator<Item = u32>, RegisterError> {
+        let stride = self.stride;
+        let too_many = |message : String| RegisterError::new(addr, write, 0, PatinaError::ArgumentError(
+            "count".to_string(),
+            i32::try_from(count).unwrap_or(i32::MAX),
+            message,
+        ));
+        // A stride of 0 keeps any count addressable, but not countable in a u32
+        let count = u32::try_from(count)
+            .map_err(|_| too_many(format!("At most {} registers can be transferred at once", u32::MAX)))?;
+        let last = (count as u64).saturating_sub(1) * stride as u64 + addr as u64;
+        if last > u32::MAX as u64 {
+            return Err(too_many(format!("Registers from {:#x} on run past the last address", addr)));
+        }
+        Ok((0..count).map(move |i| addr + i * stride))
+    }
+}
+
+/// A register of the FPGA firmware, with its address and layout, for
+/// `ExtFpga::read` and `ExtFpga::write`.
+///
+/// ## Example
+///
+/// ```
+/// use multi_harp_patina::*;
+///
+/// /// Enables of the firmware's four counters
+/// struct CounterEnables([bool; 4]);
+///
+/// impl FpgaRegister for CounterEnables {
+///     const ADDR : u32 = 0x08;
+///
+///     fn from_bits(bits : u32) -> Self {
+///         CounterEnables(std::array::from_fn(|i| bits & (1 << i) != 0))
+///     }
+///
+///     fn bits(&self) -> u32 {
+///         self.0.iter().enumerate().map(|(i, on)| (*on as u32) << i).sum()
+///     }
+/// }
+///
+/// let mut mh = DebugMultiHarp150::default();
+/// let mut fpga = mh.ext_fpga().unwrap();
+/// fpga.write(&CounterEnables([true, false, true, false])).unwrap();
+/// assert_eq!(fpga.read_reg(0x08).unwrap(), 0b0101);
+/// assert_eq!(fpga.read::<CounterEnables>().unwrap().0, [true, false, true, false]);
+/// ```
+pub trait FpgaRegister : Sized {
+    /// The address of the user command reading and writing the register
+    const ADDR : u32;
+
+    /// Decodes the register from the value read at `ADDR`.
+    fn from_bits(bits : u32) -> Self;
+
+    /// The value to write at `ADDR` to set the register.
+    fn bits(&self) -> u32;
+}
+
+/// A register read or write of an `ExtFpga` that failed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegisterError {
+    /// The register that failed
+    pub addr : u32,
+    /// Whether the register was being written rather than read
+    pub write : bool,
+    /// Registers read or written by `read_regs` or `write_regs` before
+    /// the failure
+    pub transferred : usize,
+    /// Why it failed: an `ArgumentError` for registers past the last
+    /// address, otherwise the error of the user command
+    pub error : PatinaError<i32>,
+}
+
+impl RegisterError {
+    fn new(addr : u32, write : bool, transferred : usize, error : PatinaError<i32>) -> Self {
+        RegisterError { addr, write, transferred, error }
+    }
+}
+
+impl Display for RegisterError {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        let action = if self.write { "Writing" } else { "Reading" };
+        write!(f, "{} FPGA register {:#x} failed", action, self.addr)?;
+        if self.transferred > 0 {
+            write!(f, " after {} registers", self.transferred)?;
+        }
+        write!(f, ": {}", self.error)
+    }
+}
+
+impl std::error::Error for RegisterError {}
+
+impl From<RegisterError> for PatinaError<i32> {
+    fn from(error : RegisterError) -> Self {
+        error.error
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use crate::pipeline::{ProcessorResult, StreamProcessor};
+    use crate::tttr::{Decoder, T2_WRAPAROUND};
+
+    struct Collector {
+        events : Arc<Mutex<Vec<TttrEvent>>>,
+        decoder : Decoder,
+    }
+
+    impl StreamProcessor for Collector {
+        fn name(&self) -> &str { "collector" }
+
+        fn process_batch(&mut self, batch : &Batch) -> ProcessorResult {
+            let events = self.decoder.decode_all(batch.records);
+            self.events.lock().unwrap().extend(events);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_t2raw_source() {
+        let late = 3 * T2_WRAPAROUND + 17;
+        let words = [
+            pack_t2raw(false, 0, 10),
+            pack_t2raw(false, 1, 20),
+            pack_t2raw(false, 99, 25),
+            pack_t2raw(true, 0b0100, 30),
+            pack_t2raw(false, 0, 30) | (1 << 56),
+            pack_t2raw(false, 64, late),
+        ];
+        let bytes = words.iter().flat_map(|w| w.to_le_bytes()).collect::<Vec<_>>();
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut pipeline = Pipeline::new();
+        pipeline.add(Collector { events : Arc::clone(&events), decoder : Decoder::new(MeasurementMode::T2).unwrap() });
+        let mut source = T2RawSource::new(&bytes[..], "1044272", 64, 5.0);
+        assert_eq!(source.run(&mut pipeline).unwrap(), 4);
+        assert_eq!(source.skipped(), 2);
+        assert_eq!(*events.lock().unwrap(), vec![
+            TttrEvent::sync(10), TttrEvent::photon(0, 20, 0), TttrEvent::marker(0b0100, 30), TttrEvent::photon(63, late, 0),
+        ]);
+
+        // Half a word, and time running backwards
+        let mut source = T2RawSource::new(&bytes[..12], "1044272", 64, 5.0);
+        assert!(matches!(source.run(&mut Pipeline::new()), Err(PipelineError::Device(_))));
+        let backwards = [pack_t2raw(false, 1, 20), pack_t2raw(false, 1, 10)].iter()
+            .flat_map(|w| w.to_le_bytes()).collect::<Vec<_>>();
+        let mut source = T2RawSource::new(&backwards[..], "1044272", 64, 5.0);
+        assert!(source.run(&mut Pipeline::new()).is_err());
+    }
+
+    #[test]
+    fn test_ext_fpga() {
+        let mut mh = crate::DebugMultiHarp150::default();
+        mh.set_features(mhconsts::ALL_FEATURES & !(FeatureMasks::ExtFpga as i32));
+        assert!(matches!(mh.ext_fpga(), Err(PatinaError::FeatureNotAvailable(_))));
+
+        mh.set_features(mhconsts::ALL_FEATURES);
+        let mut fpga = mh.ext_fpga().unwrap();
+        assert_eq!(fpga.link_status(1).unwrap(), 0);
+        fpga.init_link(1, true).unwrap();
+        assert_eq!(fpga.link_status(1).unwrap(), 1);
+        assert_eq!(fpga.user_command(false, 0x20, 7).unwrap(), 0);
+        assert_eq!(fpga.user_command(true, 0x20, 7).unwrap(), 7);
+        fpga.init_link(1, false).unwrap();
+        assert_eq!(fpga.link_status(1).unwrap(), 0);
+
+        let mut fpga = fpga.with_register_stride(4);
+        fpga.write_regs(0x100, &[1, 2, 3]).unwrap();
+        assert_eq!(fpga.read_reg(0x108).unwrap(), 3);
+        assert_eq!(fpga.read_regs(0x100, 3).unwrap(), vec![1, 2, 3]);
+        let error = fpga.read_regs(u32::MAX - 4, 3).unwrap_err();
+        assert!(matches!(error.error, PatinaError::ArgumentError(..)));
+        assert!(error.to_string().starts_with("Reading FPGA register 0xfffffffb failed"));
+        assert!(fpga.read_regs(u32::MAX - 4, 2).is_ok());
+
+        let mut fpga = fpga.with_register_stride(0);
+        assert_eq!(fpga.read_regs(u32::MAX, 2).unwrap(), vec![0, 0]);
+        #[cfg(target_pointer_width = "64")]
+        {
+            let error = fpga.read_regs(0, u32::MAX as usize + 1).unwrap_err();
+            assert!(matches!(error.error, PatinaError::ArgumentError(_, i32::MAX, _)));
+        }
+    }
+}
@@ -0,0 +1,271 @@
+//! Online reductions of the T3 stream, applied before the records reach
+//! a processor: time-gating on the arrival time after the sync, and
+//! keeping only the first photon of each sync period.
+//!
+//! Both are `StreamProcessor`s wrapping the processor they feed, so they
+//! can be nested, e.g. a `TimeGate` in front of a `FirstPhoton` in front
+//! of a histogram. Overflows and markers always pass, so downstream
+//! processors keep the same time base.
+
+use std::ops::RangeInclusive;
+
+use crate::mhconsts::MeasurementMode;
+use crate::monitor::SyncEvent;
+use crate::pipeline::{Batch, ProcessorResult, StreamInfo, StreamProcessor};
+use crate::tttr::{Decoder, EventKind, TttrEvent};
+
+/// Passes each photon record of a batch for which `keep` returns `true`,
+/// and every special record, on to `next`.
+fn filter_batch<F>(
+    decoder : &mut Option<Decoder>,
+    kept : &mut Vec<u32>,
+    next : &mut Box<dyn StreamProcessor>,
+    batch : &Batch,
+    mut keep : F,
+) -> Result<u64, String>
+where F : FnMut(&TttrEvent) -> bool {
+    let decoder = decoder.as_mut().ok_or("`process_batch` called before `init`")?;
+    kept.clear();
+    let mut rejected = 0;
+    for record in batch.records.iter() {
+        match decoder.decode(*record) {
+            Some(event) if event.kind == EventKind::Photon && !keep(&event) => rejected += 1,
+            _ => kept.push(*record),
+        }
+    }
+    next.process_batch(&Batch { records : kept, ..*batch })?;
+    Ok(rejected)
+}
+
+fn t3_decoder(info : &StreamInfo, stage : &str) -> Result<Decoder, String> {
+    if info.mode != MeasurementMode::T3 {
+        return Err(format!("`{}` only applies to T3 mode, not {:?}", stage, info.mode));
+    }
+    Ok(Decoder::new(info.mode)?.with_overflow_compression(info.overflow_compression))
+}
+
+/// Keeps photons arriving between two times after the sync, e.g. to
+/// reject the excitation pulse or the detector afterpulsing tail.
+///
+/// ## Example
+///
+/// ```
+/// use multi_harp_patina::*;
+///
+/// # struct Histogrammer;
+/// # impl StreamProcessor for Histogrammer {
+/// #     fn name(&self) -> &str { "histogrammer" }
+/// #     fn process_batch(&mut self, batch : &Batch) -> ProcessorResult { Ok(()) }
+/// # }
+/// // Bins 200 to 2000 only, with the first of those photons in each sync period
+/// let stage = TimeGate::new(200..=2000, Box::new(FirstPhoton::new(Box::new(Histogrammer))));
+/// let mut pipeline = Pipeline::new();
+/// pipeline.add(stage);
+/// ```
+pub struct TimeGate {
+    window : RangeInclusive<u16>,
+    next : Box<dyn StreamProcessor>,
+    decoder : Option<Decoder>,
+    kept : Vec<u32>,
+    rejected : u64,
+}
+
+impl TimeGate {
+    /// Passes photons with `dtime` (in units of the resolution) in `window` to `next`.
+    pub fn new(window : RangeInclusive<u16>, next : Box<dyn StreamProcessor>) -> Self {
+        TimeGate { window, next, decoder : None, kept : Vec::new(), rejected : 0 }
+    }
+
+    /// A gate from `start` to `end` picoseconds after the sync, rounded
+    /// outwards to whole bins of `resolution` ps.
+    pub fn from_picoseconds(start : f64, end : f64, resolution : f64, next : Box<dyn StreamProcessor>) -> Result<Self, String> {
+        if resolution <= 0.0 || start < 0.0 || end < start {
+            return Err(format!("Invalid time gate {} to {} ps at {} ps resolution", start, end, resolution));
+        }
+        let to_bin = |ps : f64| ps.min(u16::MAX as f64 * resolution) / resolution;
+        Ok(Self::new(to_bin(start).floor() as u16..=to_bin(end).ceil() as u16, next))
+    }
+
+    pub fn window(&self) -> &RangeInclusive<u16> {
+        &self.window
+    }
+
+    /// Photons dropped in the current (or last) acquisition
+    pub fn rejected(&self) -> u64 {
+        self.rejected
+    }
+}
+
+impl StreamProcessor for TimeGate {
+    fn name(&self) -> &str {
+        "time_gate"
+    }
+
+    fn init(&mut self, info : &StreamInfo) -> ProcessorResult {
+        self.decoder = Some(t3_decoder(info, self.name())?);
+        self.rejected = 0;
+        self.next.init(info)
+    }
+
+    fn process_batch(&mut self, batch : &Batch) -> ProcessorResult {
+        let window = &self.window;
+        self.rejected += filter_batch(
+            &mut self.decoder, &mut self.kept, &mut self.next, batch,
+            |photon| window.contains(&photon.dtime),
+        )?;
+        Ok(())
+    }
+
+    fn sync_event(&mut self, event : &SyncEvent) -> ProcessorResult {
+        self.next.sync_event(event)
+    }
+
+    fn finalize(&mut self) -> ProcessorResult {
+        self.next.finalize()
+    }
+}
+
+/// Keeps only the first photon recorded in each sync period, on any
+/// channel or, with `per_channel`, on each channel. Records arrive in
+/// order of detection, so the first record of a period is its earliest
+/// photon.
+pub struct FirstPhoton {
+    per_channel : bool,
+    next : Box<dyn StreamProcessor>,
+    decoder : Option<Decoder>,
+    /// Sync period of the last photon kept, per channel if `per_channel`
+    last_kept : Vec<Option<u64>>,
+    kept : Vec<u32>,
+    rejected : u64,
+}
+
+impl FirstPhoton {
+    /// Passes the first photon of each sync period (on any channel) to `next`.
+    pub fn new(next : Box<dyn StreamProcessor>) -> Self {
+        FirstPhoton { per_channel : false, next, decoder : None, last_kept : Vec::new(), kept : Vec::new(), rejected : 0 }
+    }
+
+    /// Keeps the first photon of each period on every channel separately.
+    pub fn per_channel(mut self) -> Self {
+        self.per_channel = true;
+        self
+    }
+
+    /// Photons dropped in the current (or last) acquisition
+    pub fn rejected(&self) -> u64 {
+        self.rejected
+    }
+}
+
+impl StreamProcessor for FirstPhoton {
+    fn name(&self) -> &str {
+        "first_photon"
+    }
+
+    fn init(&mut self, info : &StreamInfo) -> ProcessorResult {
+        self.decoder = Some(t3_decoder(info, self.name())?);
+        self.last_kept = vec![None; if self.per_channel { info.num_channels.max(1) as usize } else { 1 }];
+        self.rejected = 0;
+        self.next.init(info)
+    }
+
+    fn process_batch(&mut self, batch : &Batch) -> ProcessorResult {
+        let per_channel = self.per_channel;
+        let last_kept = &mut self.last_kept;
+        self.rejected += filter_batch(
+            &mut self.decoder, &mut self.kept, &mut self.next, batch,
+            |photon| {
+                let index = if per_channel { photon.channel as usize } else { 0 };
+                if index >= last_kept.len() {
+                    last_kept.resize(index + 1, None);
+                }
+                if last_kept[index] == Some(photon.time) {
+                    return false;
+                }
+                last_kept[index] = Some(photon.time);
+                true
+            },
+        )?;
+        Ok(())
+    }
+
+    fn sync_event(&mut self, event : &SyncEvent) -> ProcessorResult {
+        self.next.sync_event(event)
+    }
+
+    fn finalize(&mut self) -> ProcessorResult {
+        self.next.finalize()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+    use std::sync::{Arc, Mutex};
+    use crate::tttr::{pack_t3, OVERFLOW_CHANNEL};
+    use crate::testing::fixtures::stream_info;
+
+    struct Collector {
+        out : Arc<Mutex<Vec<u32>>>,
+    }
+
+    impl StreamProcessor for Collector {
+        fn name(&self) -> &str { "collector" }
+
+        fn process_batch(&mut self, batch : &Batch) -> ProcessorResult {
+            self.out.lock().unwrap().extend_from_slice(batch.records);
+            Ok(())
+        }
+    }
+
+    fn run(stage : &mut dyn StreamProcessor, batches : &[&[u32]]) {
+        stage.init(&stream_info(MeasurementMode::T3)).unwrap();
+        for (index, records) in batches.iter().enumerate() {
+            stage.process_batch(&Batch { index : index as u64, read_at : Instant::now(), records, mode : MeasurementMode::T3, sync_lost : false }).unwrap();
+        }
+        stage.finalize().unwrap();
+    }
+
+    #[test]
+    fn test_time_gate() {
+        let out = Arc::new(Mutex::new(Vec::new()));
+        let mut gate = TimeGate::from_picoseconds(
+            1000.0, 2001.0, 5.0, Box::new(Collector { out : Arc::clone(&out) })
+        ).unwrap();
+        assert_eq!(gate.window(), &(200..=401));
+
+        let marker = pack_t3(true, 1, 0, 7);
+        let overflow = pack_t3(true, OVERFLOW_CHANNEL, 0, 1);
+        run(&mut gate, &[
+            &[pack_t3(false, 0, 199, 1), pack_t3(false, 0, 200, 1), marker],
+            &[overflow, pack_t3(false, 2, 401, 3), pack_t3(false, 2, 402, 3)],
+        ]);
+        assert_eq!(*out.lock().unwrap(), vec![
+            pack_t3(false, 0, 200, 1), marker, overflow, pack_t3(false, 2, 401, 3),
+        ]);
+        assert_eq!(gate.rejected(), 2);
+
+        assert!(TimeGate::new(0..=1, Box::new(Collector { out })).init(&stream_info(MeasurementMode::T2)).is_err());
+    }
+
+    #[test]
+    fn test_first_photon() {
+        let out = Arc::new(Mutex::new(Vec::new()));
+        let mut first = FirstPhoton::new(Box::new(Collector { out : Arc::clone(&out) }));
+        // The same `nsync` after an overflow is a different period,
+        // and periods can continue across batches
+        let overflow = pack_t3(true, OVERFLOW_CHANNEL, 0, 1);
+        run(&mut first, &[
+            &[pack_t3(false, 0, 10, 5), pack_t3(false, 1, 20, 5)],
+            &[pack_t3(false, 0, 30, 5), overflow, pack_t3(false, 1, 40, 5)],
+        ]);
+        assert_eq!(*out.lock().unwrap(), vec![pack_t3(false, 0, 10, 5), overflow, pack_t3(false, 1, 40, 5)]);
+        assert_eq!(first.rejected(), 2);
+
+        let out = Arc::new(Mutex::new(Vec::new()));
+        let mut first = FirstPhoton::new(Box::new(Collector { out : Arc::clone(&out) })).per_channel();
+        run(&mut first, &[&[pack_t3(false, 0, 10, 5), pack_t3(false, 1, 20, 5), pack_t3(false, 1, 30, 5)]]);
+        assert_eq!(*out.lock().unwrap(), vec![pack_t3(false, 0, 10, 5), pack_t3(false, 1, 20, 5)]);
+    }
+}
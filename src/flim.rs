@@ -0,0 +1,701 @@
+//! Assembles laser-scanning FLIM images from a T3 stream, using the
+//! scanner's line and frame markers to place each photon in a pixel,
+//! and writes them out frame by frame.
+//!
+//! Each frame holds an intensity image and a fast lifetime image (the
+//! mean arrival time after the sync, less an offset) for every input
+//! channel. `OmeTiffWriter` saves them as OME-TIFF, which Fiji opens
+//! with the channel names and timing intact.
+
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::irf::Irf;
+use crate::mhconsts::MeasurementMode;
+use crate::metadata::Labels;
+use crate::monitor::SyncEvent;
+use crate::pipeline::{Batch, ProcessorResult, StreamInfo, StreamProcessor};
+use crate::tttr::{Decoder, EventKind};
+
+/// How the scanner marks lines and frames, and the image size.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScanConfig {
+    pub pixels_per_line : usize,
+    pub lines_per_frame : usize,
+    /// Marker input (1 to 4) pulsed at the start of each line
+    pub line_start_marker : u8,
+    /// Marker input pulsed at the end of each line
+    pub line_stop_marker : u8,
+    /// Marker input pulsed at the start of each frame, if wired. A frame
+    /// marker arriving before a frame is complete discards the partial frame.
+    pub frame_marker : Option<u8>,
+    /// Subtracted from the mean arrival time to give the fast lifetime,
+    /// usually the position of the instrument response peak, in ps
+    pub lifetime_offset_ps : f64,
+    /// Used to convert frame times from syncs to seconds, if known
+    pub sync_period_ps : Option<f64>,
+}
+
+impl Default for ScanConfig {
+    /// 512 x 512 pixels, with the markers in the order PicoQuant's
+    /// scanner interfaces use: line start on 1, line stop on 2, frame on 3.
+    fn default() -> Self {
+        ScanConfig {
+            pixels_per_line : 512,
+            lines_per_frame : 512,
+            line_start_marker : 1,
+            line_stop_marker : 2,
+            frame_marker : Some(3),
+            lifetime_offset_ps : 0.0,
+            sync_period_ps : None,
+        }
+    }
+}
+
+impl ScanConfig {
+    /// Sets `lifetime_offset_ps` to the mean arrival time of `irf`, so the
+    /// fast lifetime is measured from the instrument response. Left as it
+    /// is if the IRF has no counts.
+    pub fn with_irf(mut self, irf : &Irf) -> Self {
+        if let Some(centroid) = irf.centroid_ps() {
+            self.lifetime_offset_ps = centroid;
+        }
+        self
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if self.pixels_per_line == 0 || self.lines_per_frame == 0 {
+            return Err("Frames must have at least one pixel".to_string());
+        }
+        let markers = [Some(self.line_start_marker), Some(self.line_stop_marker), self.frame_marker];
+        for marker in markers.iter().flatten() {
+            if !(1..=4).contains(marker) {
+                return Err(format!("Marker must be between 1 and 4, got {}", marker));
+            }
+        }
+        if self.line_start_marker == self.line_stop_marker {
+            return Err("Line start and stop must be on different markers".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// One assembled frame. Images are stored row by row, one after another
+/// for each enabled channel.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlimFrame {
+    /// Frames are numbered from 0 in each acquisition
+    pub number : u32,
+    pub width : usize,
+    pub height : usize,
+    pub channels : usize,
+    /// The input channel of each image, in order. Disabled channels
+    /// have no image.
+    pub channel_numbers : Vec<u8>,
+    /// Time of the first line start, in syncs since the start of the acquisition
+    pub start : u64,
+    /// Time of the last line stop, in syncs
+    pub end : u64,
+    /// Photons per pixel
+    pub intensity : Vec<u32>,
+    /// Mean arrival time less `ScanConfig::lifetime_offset_ps` in ns,
+    /// 0 where there were no photons
+    pub lifetime : Vec<f32>,
+}
+
+impl FlimFrame {
+    fn pixels(&self) -> usize {
+        self.width * self.height
+    }
+
+    /// The intensity image of the `channel`th image (see `channel_numbers`)
+    pub fn intensity_image(&self, channel : usize) -> &[u32] {
+        &self.intensity[channel * self.pixels()..(channel + 1) * self.pixels()]
+    }
+
+    /// The fast lifetime image of the `channel`th image, in ns
+    pub fn lifetime_image(&self, channel : usize) -> &[f32] {
+        &self.lifetime[channel * self.pixels()..(channel + 1) * self.pixels()]
+    }
+
+    /// Photons in the frame on every channel
+    pub fn total_counts(&self) -> u64 {
+        self.intensity.iter().map(|c| *c as u64).sum()
+    }
+}
+
+/// Receives each frame as it is completed by a `FlimAssembler`.
+#[allow(unused_variables)]
+pub trait FrameSink : Send {
+    /// Called before the first frame of an acquisition.
+    fn init(&mut self, info : &StreamInfo, config : &ScanConfig) -> Result<(), String> { Ok(()) }
+
+    fn write_frame(&mut self, frame : &FlimFrame) -> Result<(), String>;
+
+    /// Called after the last frame of an acquisition.
+    fn finish(&mut self) -> Result<(), String> { Ok(()) }
+}
+
+/// Builds `FlimFrame`s from the T3 stream and hands each to a `FrameSink`.
+///
+/// ## Example
+///
+/// ```no_run
+/// use multi_harp_patina::*;
+///
+/// let mut mh = DebugMultiHarp150::default();
+/// let config = ScanConfig { lifetime_offset_ps : 1200.0, ..Default::default() };
+/// let assembler = FlimAssembler::new(config, Box::new(OmeTiffWriter::new("flim", "fly_03"))).unwrap();
+/// let mut pipeline = Pipeline::new();
+/// pipeline.add(assembler);
+/// pipeline.run(&mut mh, 10_000).unwrap();
+/// ```
+pub struct FlimAssembler {
+    config : ScanConfig,
+    sink : Box<dyn FrameSink>,
+    decoder : Option<Decoder>,
+    resolution : f64,
+    channels : usize,
+    channel_numbers : Vec<u8>,
+    /// Image of each input channel, `None` if it's disabled
+    images : Vec<Option<usize>>,
+    /// `(image, nsync, dtime)` of the photons of the current line
+    line_photons : Vec<(usize, u64, u16)>,
+    line_start : Option<u64>,
+    line : usize,
+    frame_start : Option<u64>,
+    intensity : Vec<u32>,
+    dtime_sums : Vec<u64>,
+    frames : u32,
+    discarded : u32,
+}
+
+impl FlimAssembler {
+    pub fn new(config : ScanConfig, sink : Box<dyn FrameSink>) -> Result<Self, String> {
+        config.check()?;
+        Ok(FlimAssembler {
+            config,
+            sink,
+            decoder : None,
+            resolution : 0.0,
+            channels : 0,
+            channel_numbers : Vec::new(),
+            images : Vec::new(),
+            line_photons : Vec::new(),
+            line_start : None,
+            line : 0,
+            frame_start : None,
+            intensity : Vec::new(),
+            dtime_sums : Vec::new(),
+            frames : 0,
+            discarded : 0,
+        })
+    }
+
+    pub fn config(&self) -> &ScanConfig {
+        &self.config
+    }
+
+    /// Frames completed in the current (or last) acquisition
+    pub fn frames(&self) -> u32 {
+        self.frames
+    }
+
+    /// Partial frames dropped on a frame marker or at the end of the acquisition
+    pub fn discarded(&self) -> u32 {
+        self.discarded
+    }
+
+    /// Photon records on channels that aren't enabled, left out of the frames
+    pub fn anomalies(&self) -> u64 {
+        self.decoder.as_ref().map(|d| d.anomalies()).unwrap_or(0)
+    }
+
+    fn pixels(&self) -> usize {
+        self.config.pixels_per_line * self.config.lines_per_frame
+    }
+
+    fn clear_frame(&mut self) {
+        self.line = 0;
+        self.line_start = None;
+        self.frame_start = None;
+        self.intensity.iter_mut().for_each(|c| *c = 0);
+        self.dtime_sums.iter_mut().for_each(|s| *s = 0);
+    }
+
+    fn start_line(&mut self, time : u64) {
+        self.line_start = Some(time);
+        self.frame_start.get_or_insert(time);
+        self.line_photons.clear();
+    }
+
+    /// Bins the photons of the line that finished at `time`, emitting
+    /// the frame if it was the last line.
+    fn stop_line(&mut self, time : u64) -> Result<(), String> {
+        let start = match self.line_start.take() {
+            Some(start) => start,
+            None => return Ok(()),
+        };
+        let width = self.config.pixels_per_line;
+        let duration = time.saturating_sub(start).max(1) as f64;
+        let row = self.line * width;
+        for (image, nsync, dtime) in self.line_photons.drain(..) {
+            let x = ((nsync.saturating_sub(start) as f64 / duration * width as f64) as usize).min(width - 1);
+            let pixel = image * self.config.pixels_per_line * self.config.lines_per_frame + row + x;
+            self.intensity[pixel] += 1;
+            self.dtime_sums[pixel] += dtime as u64;
+        }
+
+        self.line += 1;
+        if self.line == self.config.lines_per_frame {
+            self.emit_frame(time)?;
+        }
+        Ok(())
+    }
+
+    fn emit_frame(&mut self, end : u64) -> Result<(), String> {
+        let offset_ns = self.config.lifetime_offset_ps / 1000.0;
+        let resolution_ns = self.resolution / 1000.0;
+        let lifetime = self.intensity.iter().zip(self.dtime_sums.iter())
+            .map(|(count, sum)| match count {
+                0 => 0.0,
+                n => (*sum as f64 / *n as f64 * resolution_ns - offset_ns) as f32,
+            })
+            .collect();
+        let frame = FlimFrame {
+            number : self.frames,
+            width : self.config.pixels_per_line,
+            height : self.config.lines_per_frame,
+            channels : self.channels,
+            channel_numbers : self.channel_numbers.clone(),
+            start : self.frame_start.unwrap_or(end),
+            end,
+            intensity : self.intensity.clone(),
+            lifetime,
+        };
+        self.frames += 1;
+        self.clear_frame();
+        self.sink.write_frame(&frame)
+    }
+}
+
+impl StreamProcessor for FlimAssembler {
+    fn name(&self) -> &str {
+        "flim_assembler"
+    }
+
+    fn init(&mut self, info : &StreamInfo) -> ProcessorResult {
+        if info.mode != MeasurementMode::T3 {
+            return Err(format!("FLIM needs T3 mode, not {:?}", info.mode));
+        }
+        self.decoder = Some(Decoder::for_stream(info)?);
+        self.resolution = info.resolution;
+        self.channel_numbers = info.enabled();
+        self.channels = self.channel_numbers.len();
+        self.images = vec![None; info.enabled_channels.len()];
+        for (image, channel) in self.channel_numbers.iter().enumerate() {
+            self.images[*channel as usize] = Some(image);
+        }
+        self.intensity = vec![0; self.channels * self.pixels()];
+        self.dtime_sums = vec![0; self.channels * self.pixels()];
+        self.line_photons.clear();
+        self.frames = 0;
+        self.discarded = 0;
+        self.clear_frame();
+        self.sink.init(info, &self.config)
+    }
+
+    fn process_batch(&mut self, batch : &Batch) -> ProcessorResult {
+        let mut decoder = self.decoder.take().ok_or("`process_batch` called before `init`")?;
+        let bit = |marker : u8| 1u8 << (marker - 1);
+        let result = batch.records.iter().try_for_each(|record| {
+            let event = match decoder.decode(*record) {
+                Some(event) => event,
+                None => return Ok(()),
+            };
+            match event.kind {
+                EventKind::Photon => {
+                    if let (Some(_), Some(Some(image))) = (self.line_start, self.images.get(event.channel as usize)) {
+                        self.line_photons.push((*image, event.time, event.dtime));
+                    }
+                },
+                EventKind::Marker => {
+                    if let Some(frame) = self.config.frame_marker {
+                        if event.channel & bit(frame) != 0 {
+                            if self.line > 0 {
+                                self.discarded += 1;
+                            }
+                            self.clear_frame();
+                        }
+                    }
+                    if event.channel & bit(self.config.line_stop_marker) != 0 {
+                        self.stop_line(event.time)?;
+                    }
+                    if event.channel & bit(self.config.line_start_marker) != 0 {
+                        self.start_line(event.time);
+                    }
+                },
+                EventKind::Sync => {},
+            }
+            Ok::<(), String>(())
+        });
+        self.decoder = Some(decoder);
+        result
+    }
+
+    fn sync_event(&mut self, event : &SyncEvent) -> ProcessorResult {
+        // Pixel positions are meaningless without the sync clock
+        if let SyncEvent::Lost(_) = event {
+            if self.line > 0 || self.line_start.is_some() {
+                self.discarded += 1;
+            }
+            self.clear_frame();
+        }
+        Ok(())
+    }
+
+    fn finalize(&mut self) -> ProcessorResult {
+        if self.line > 0 {
+            self.discarded += 1;
+        }
+        self.clear_frame();
+        self.sink.finish()
+    }
+}
+
+/// Writes each frame to its own OME-TIFF, `<prefix>_<frame>.ome.tif`.
+/// Every channel gets two 32-bit float planes, intensity and then fast
+/// lifetime (ns), named accordingly in the OME-XML. The frame's time,
+/// the device and the acquisition's labels are stored as annotations.
+pub struct OmeTiffWriter {
+    directory : PathBuf,
+    prefix : String,
+    info : Option<StreamInfo>,
+    config : Option<ScanConfig>,
+    written : Vec<PathBuf>,
+}
+
+impl OmeTiffWriter {
+    pub fn new<P : AsRef<Path>>(directory : P, prefix : &str) -> Self {
+        OmeTiffWriter {
+            directory : directory.as_ref().to_path_buf(),
+            prefix : prefix.to_string(),
+            info : None,
+            config : None,
+            written : Vec::new(),
+        }
+    }
+
+    /// Files written in the current (or last) acquisition
+    pub fn written(&self) -> &[PathBuf] {
+        &self.written
+    }
+
+    /// The OME-XML stored in the first page of `frame`'s file
+    pub fn ome_xml(&self, frame : &FlimFrame) -> String {
+        let mut xml = String::new();
+        let _ = write!(xml,
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+            <OME xmlns=\"http://www.openmicroscopy.org/Schemas/OME/2016-06\" \
+            xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\" \
+            xsi:schemaLocation=\"http://www.openmicroscopy.org/Schemas/OME/2016-06 \
+            http://www.openmicroscopy.org/Schemas/OME/2016-06/ome.xsd\">\
+            <Image ID=\"Image:0\" Name=\"{}\">\
+            <Pixels ID=\"Pixels:0\" DimensionOrder=\"XYCZT\" Type=\"float\" \
+            SizeX=\"{}\" SizeY=\"{}\" SizeC=\"{}\" SizeZ=\"1\" SizeT=\"1\" BigEndian=\"false\">",
+            xml_escape(&self.file_name(frame)), frame.width, frame.height, 2 * frame.channels,
+        );
+        for image in 0..frame.channels {
+            let channel = frame.channel_numbers.get(image).copied().unwrap_or(image as u8);
+            let name = match &self.info {
+                Some(info) => info.channel_name(channel),
+                None => format!("ch{}", channel),
+            };
+            let name = xml_escape(&name);
+            let _ = write!(xml,
+                "<Channel ID=\"Channel:0:{}\" Name=\"{} intensity\" SamplesPerPixel=\"1\"/>\
+                <Channel ID=\"Channel:0:{}\" Name=\"{} lifetime (ns)\" SamplesPerPixel=\"1\"/>",
+                2 * image, name, 2 * image + 1, name,
+            );
+        }
+        let _ = write!(xml, "<TiffData IFD=\"0\" PlaneCount=\"{}\"/>", 2 * frame.channels);
+        let delta_t = self.config.as_ref()
+            .and_then(|c| c.sync_period_ps)
+            .map(|period| format!(" DeltaT=\"{}\" DeltaTUnit=\"s\"", frame.start as f64 * period / 1e12))
+            .unwrap_or_default();
+        for plane in 0..2 * frame.channels {
+            let _ = write!(xml, "<Plane TheC=\"{}\" TheZ=\"0\" TheT=\"0\"{}/>", plane, delta_t);
+        }
+        xml.push_str("</Pixels><AnnotationRef ID=\"Annotation:0\"/></Image>");
+
+        let mut annotations = Labels::new();
+        if let Some(info) = &self.info {
+            annotations.insert("serial", &info.serial).insert("resolution_ps", info.resolution);
+            for (key, value) in info.labels.iter() {
+                annotations.insert(format!("label.{}", key), value);
+            }
+        }
+        if let Some(config) = &self.config {
+            annotations.insert("lifetime_offset_ps", config.lifetime_offset_ps);
+        }
+        annotations.insert("frame", frame.number)
+            .insert("start_nsync", frame.start)
+            .insert("end_nsync", frame.end);
+        xml.push_str("<StructuredAnnotations><MapAnnotation ID=\"Annotation:0\"><Value>");
+        for (key, value) in annotations.iter() {
+            let _ = write!(xml, "<M K=\"{}\">{}</M>", xml_escape(key), xml_escape(value));
+        }
+        xml.push_str("</Value></MapAnnotation></StructuredAnnotations></OME>");
+        xml
+    }
+
+    fn file_name(&self, frame : &FlimFrame) -> String {
+        format!("{}_{:05}.ome.tif", self.prefix, frame.number)
+    }
+
+    /// Writes `frame` as an OME-TIFF to `writer`.
+    pub fn write_to<W : Write>(&self, frame : &FlimFrame, writer : W) -> io::Result<()> {
+        let planes = (0..frame.channels).flat_map(|channel| [
+            frame.intensity_image(channel).iter().map(|c| *c as f32).collect::<Vec<_>>(),
+            frame.lifetime_image(channel).to_vec(),
+        ]).collect::<Vec<_>>();
+        write_float_tiff(writer, frame.width, frame.height, &planes, &self.ome_xml(frame))
+    }
+}
+
+impl FrameSink for OmeTiffWriter {
+    fn init(&mut self, info : &StreamInfo, config : &ScanConfig) -> Result<(), String> {
+        std::fs::create_dir_all(&self.directory)
+            .map_err(|e| format!("{}: {}", self.directory.display(), e))?;
+        self.info = Some(info.clone());
+        self.config = Some(config.clone());
+        self.written.clear();
+        Ok(())
+    }
+
+    fn write_frame(&mut self, frame : &FlimFrame) -> Result<(), String> {
+        let path = self.directory.join(self.file_name(frame));
+        let file = std::fs::File::create(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        self.write_to(frame, io::BufWriter::new(file))
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        self.written.push(path);
+        Ok(())
+    }
+}
+
+fn xml_escape(s : &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// Writes a little-endian baseline TIFF of 32-bit float grayscale
+/// `planes`, one page (and strip) each, with `description` as the
+/// `ImageDescription` of the first.
+fn write_float_tiff<W : Write>(
+    mut writer : W,
+    width : usize,
+    height : usize,
+    planes : &[Vec<f32>],
+    description : &str,
+) -> io::Result<()> {
+    const SHORT : u16 = 3;
+    const LONG : u16 = 4;
+    const ASCII : u16 = 2;
+
+    let mut out = Vec::with_capacity(8 + planes.len() * (width * height * 4 + 256) + description.len());
+    out.extend_from_slice(b"II*\0");
+    out.extend_from_slice(&0u32.to_le_bytes());
+    let mut previous_next_ifd = 4;
+
+    let mut description_offset = 0;
+    if !planes.is_empty() {
+        description_offset = out.len() as u32;
+        out.extend_from_slice(description.as_bytes());
+        out.push(0);
+    }
+
+    for (page, plane) in planes.iter().enumerate() {
+        if plane.len() != width * height {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Plane size doesn't match the image size"));
+        }
+        if out.len() % 2 == 1 {
+            out.push(0);
+        }
+        let data_offset = out.len() as u32;
+        plane.iter().for_each(|v| out.extend_from_slice(&v.to_le_bytes()));
+
+        let mut entries : Vec<(u16, u16, u32, u32)> = vec![
+            (256, LONG, 1, width as u32),
+            (257, LONG, 1, height as u32),
+            (258, SHORT, 1, 32),
+            (259, SHORT, 1, 1),
+            (262, SHORT, 1, 1),
+        ];
+        if page == 0 {
+            entries.push((270, ASCII, description.len() as u32 + 1, description_offset));
+        }
+        entries.extend_from_slice(&[
+            (273, LONG, 1, data_offset),
+            (277, SHORT, 1, 1),
+            (278, LONG, 1, height as u32),
+            (279, LONG, 1, (plane.len() * 4) as u32),
+            // SampleFormat: IEEE float
+            (339, SHORT, 1, 3),
+        ]);
+
+        if out.len() % 2 == 1 {
+            out.push(0);
+        }
+        let ifd_offset = out.len() as u32;
+        out[previous_next_ifd..previous_next_ifd + 4].copy_from_slice(&ifd_offset.to_le_bytes());
+        out.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        for (tag, kind, count, value) in entries {
+            out.extend_from_slice(&tag.to_le_bytes());
+            out.extend_from_slice(&kind.to_le_bytes());
+            out.extend_from_slice(&count.to_le_bytes());
+            // SHORT values are left-justified in the value field
+            match kind {
+                SHORT => {
+                    out.extend_from_slice(&(value as u16).to_le_bytes());
+                    out.extend_from_slice(&[0, 0]);
+                },
+                _ => out.extend_from_slice(&value.to_le_bytes()),
+            }
+        }
+        previous_next_ifd = out.len();
+        out.extend_from_slice(&0u32.to_le_bytes());
+    }
+    writer.write_all(&out)?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+    use std::sync::{Arc, Mutex};
+    use crate::tttr::pack_t3;
+    use crate::testing::fixtures::stream_info;
+
+    struct Frames(Arc<Mutex<Vec<FlimFrame>>>);
+
+    impl FrameSink for Frames {
+        fn write_frame(&mut self, frame : &FlimFrame) -> Result<(), String> {
+            self.0.lock().unwrap().push(frame.clone());
+            Ok(())
+        }
+    }
+
+    fn info() -> StreamInfo {
+        StreamInfo {
+            num_channels : 2,
+            enabled_channels : vec![true; 2],
+            labels : [("sample", "fly <03>")].into_iter().collect(),
+            ..stream_info(MeasurementMode::T3)
+        }
+    }
+
+    #[test]
+    fn test_flim_assembly() {
+        let frames = Arc::new(Mutex::new(Vec::new()));
+        let config = ScanConfig {
+            pixels_per_line : 2,
+            lines_per_frame : 2,
+            lifetime_offset_ps : 100.0,
+            ..Default::default()
+        };
+        let mut assembler = FlimAssembler::new(config, Box::new(Frames(Arc::clone(&frames)))).unwrap();
+        assembler.init(&info()).unwrap();
+
+        let marker = |m : u8, nsync| pack_t3(true, 1 << (m - 1), 0, nsync);
+        let records = [
+            marker(3, 0),
+            // Partial frame: discarded at the next frame marker
+            marker(1, 0), pack_t3(false, 0, 100, 1), marker(2, 10),
+            marker(3, 20),
+            // Line 0: two photons on the left pixel, one on the right on channel 1
+            marker(1, 20), pack_t3(false, 0, 100, 21), pack_t3(false, 0, 200, 22), pack_t3(false, 1, 50, 29), marker(2, 30),
+            // Photons between lines are dropped
+            pack_t3(false, 0, 100, 32),
+            marker(1, 40), pack_t3(false, 0, 100, 45), marker(2, 50),
+        ];
+        assembler.process_batch(&Batch { index : 0, read_at : Instant::now(), records : &records, mode : MeasurementMode::T3, sync_lost : false }).unwrap();
+        assembler.finalize().unwrap();
+
+        let frames = frames.lock().unwrap();
+        assert_eq!(frames.len(), 1);
+        assert_eq!(assembler.discarded(), 1);
+        let frame = &frames[0];
+        assert_eq!((frame.start, frame.end), (20, 50));
+        assert_eq!(frame.intensity_image(0), &[2, 0, 0, 1]);
+        assert_eq!(frame.intensity_image(1), &[0, 1, 0, 0]);
+        // Mean of bins 100 and 200 at 5 ps, less 0.1 ns
+        assert!((frame.lifetime_image(0)[0] - 0.65).abs() < 1e-6);
+        assert_eq!(frame.lifetime_image(0)[1], 0.0);
+        drop(frames);
+
+        // With channel 0 disabled, its photons are anomalies and it has no image
+        let frames = Arc::new(Mutex::new(Vec::new()));
+        let mut assembler = FlimAssembler::new(assembler.config().clone(), Box::new(Frames(Arc::clone(&frames)))).unwrap();
+        assembler.init(&StreamInfo { enabled_channels : vec![false, true], ..info() }).unwrap();
+        assembler.process_batch(&Batch { index : 0, read_at : Instant::now(), records : &records, mode : MeasurementMode::T3, sync_lost : false }).unwrap();
+        assert_eq!(assembler.anomalies(), 5);
+        assembler.finalize().unwrap();
+        let frame = &frames.lock().unwrap()[0];
+        assert_eq!((frame.channels, frame.channel_numbers.clone()), (1, vec![1]));
+        assert_eq!(frame.intensity_image(0), &[0, 1, 0, 0]);
+    }
+
+    #[test]
+    fn test_ome_tiff_layout() {
+        let frame = FlimFrame {
+            number : 7,
+            width : 3,
+            height : 2,
+            channels : 1,
+            channel_numbers : vec![2],
+            start : 100,
+            end : 200,
+            intensity : vec![1, 2, 3, 4, 5, 6],
+            lifetime : vec![0.5; 6],
+        };
+        let mut writer = OmeTiffWriter::new(std::env::temp_dir(), "test");
+        let config = ScanConfig { sync_period_ps : Some(12_500.0), ..Default::default() };
+        writer.init(&info(), &config).unwrap();
+
+        let xml = writer.ome_xml(&frame);
+        assert!(xml.contains("SizeC=\"2\""));
+        assert!(xml.contains("Name=\"ch2 lifetime (ns)\""));
+        assert!(xml.contains("<M K=\"label.sample\">fly &lt;03&gt;</M>"));
+        assert!(xml.contains("DeltaT=\"0.00000125\""));
+
+        let mut tiff = Vec::new();
+        writer.write_to(&frame, &mut tiff).unwrap();
+        assert_eq!(&tiff[..4], b"II*\0");
+        let u16_at = |at : usize| u16::from_le_bytes([tiff[at], tiff[at + 1]]);
+        let u32_at = |at : usize| u32::from_le_bytes(tiff[at..at + 4].try_into().unwrap());
+
+        // Walk both pages, reading back the pixel data
+        let mut ifd = u32_at(4) as usize;
+        let mut pages = Vec::new();
+        while ifd != 0 {
+            let entries = u16_at(ifd) as usize;
+            let tag = |t : u16| (0..entries)
+                .map(|i| ifd + 2 + 12 * i)
+                .find(|e| u16_at(*e) == t)
+                .map(|e| u32_at(e + 8));
+            let offset = tag(273).unwrap() as usize;
+            let plane = (0..6).map(|i| f32::from_le_bytes(tiff[offset + 4 * i..offset + 4 * i + 4].try_into().unwrap())).collect::<Vec<_>>();
+            pages.push((tag(270).is_some(), plane));
+            ifd = u32_at(ifd + 2 + 12 * entries) as usize;
+        }
+        assert_eq!(pages, vec![
+            (true, vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0]),
+            (false, vec![0.5; 6]),
+        ]);
+    }
+}
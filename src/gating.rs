@@ -0,0 +1,220 @@
+//! Splits a hardware-gated acquisition (`MeasurementControlMode::C1Gated`)
+//! into numbered segments, one per gate, so that externally triggered
+//! trials each get their own output.
+//!
+//! While the gate is closed the device records nothing, not even
+//! overflows, so a gate is taken to have closed once the FIFO has been
+//! empty for longer than an idle timeout.
+
+use std::time::{Duration, Instant};
+
+use crate::monitor::SyncEvent;
+use crate::pipeline::{Batch, ProcessorResult, StreamInfo, StreamProcessor};
+
+/// Creates the processor receiving the photons of segment `n`
+pub type SegmentFactory = Box<dyn FnMut(u32) -> Result<Box<dyn StreamProcessor>, String> + Send>;
+
+/// A completed (or, for the last one, current) gate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GateSegment {
+    /// Segments are numbered from 0 in each acquisition
+    pub number : u32,
+    /// Index of the first batch of the segment
+    pub start_batch : u64,
+    /// Index of the first batch after the gate closed. `None` while open.
+    pub end_batch : Option<u64>,
+    /// Records routed to the segment
+    pub records : u64,
+}
+
+/// Routes the records of each gate to a new processor from a factory.
+/// Each processor is initialized when its gate opens, with a `segment`
+/// label added to the acquisition's labels, and finalized when the gate
+/// closes.
+///
+/// ## Example
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use multi_harp_patina::*;
+///
+/// let mut mh = DebugMultiHarp150::default();
+/// mh.set_measurement_control_mode(MeasurementControlMode::C1Gated, Some(TriggerEdge::Rising), Some(TriggerEdge::Falling)).unwrap();
+///
+/// let segmenter = GateSegmenter::new(Duration::from_millis(20), Box::new(|n| {
+///     let spool = SpoolWriter::create(format!("trial_{:03}.spool", n)).map_err(|e| e.to_string())?;
+///     Ok(Box::new(spool) as Box<dyn StreamProcessor>)
+/// }));
+/// let mut pipeline = Pipeline::new();
+/// pipeline.add(segmenter);
+/// pipeline.run(&mut mh, 60_000).unwrap();
+/// ```
+pub struct GateSegmenter {
+    idle_timeout : Duration,
+    factory : SegmentFactory,
+    info : Option<StreamInfo>,
+    current : Option<Box<dyn StreamProcessor>>,
+    last_data : Option<Instant>,
+    next_batch : u64,
+    segments : Vec<GateSegment>,
+}
+
+impl GateSegmenter {
+    /// A gate is considered closed after `idle_timeout` without any
+    /// records. It should be longer than the longest gap in the data while
+    /// the gate is open (in T3 mode, overflows arrive at least every
+    /// 1024 syncs).
+    pub fn new(idle_timeout : Duration, factory : SegmentFactory) -> Self {
+        GateSegmenter {
+            idle_timeout,
+            factory,
+            info : None,
+            current : None,
+            last_data : None,
+            next_batch : 0,
+            segments : Vec::new(),
+        }
+    }
+
+    /// Every segment of the current (or last) acquisition
+    pub fn segments(&self) -> &[GateSegment] {
+        &self.segments
+    }
+
+    /// Whether a gate is currently open
+    pub fn is_open(&self) -> bool {
+        self.current.is_some()
+    }
+
+    fn open_segment(&mut self, batch_index : u64) -> ProcessorResult {
+        let number = self.segments.len() as u32;
+        let mut info = self.info.clone().ok_or("`process_batch` called before `init`")?;
+        info.labels.insert("segment", number);
+
+        let mut processor = (self.factory)(number)?;
+        processor.init(&info)
+            .map_err(|e| format!("segment {} `{}`: {}", number, processor.name(), e))?;
+        self.current = Some(processor);
+        self.segments.push(GateSegment { number, start_batch : batch_index, end_batch : None, records : 0 });
+        Ok(())
+    }
+
+    fn close_segment(&mut self, batch_index : u64) -> ProcessorResult {
+        if let Some(mut processor) = self.current.take() {
+            let segment = self.segments.last_mut().unwrap();
+            segment.end_batch = Some(batch_index);
+            processor.finalize()
+                .map_err(|e| format!("segment {} `{}`: {}", segment.number, processor.name(), e))?;
+        }
+        Ok(())
+    }
+}
+
+impl StreamProcessor for GateSegmenter {
+    fn name(&self) -> &str {
+        "gate_segmenter"
+    }
+
+    fn init(&mut self, info : &StreamInfo) -> ProcessorResult {
+        self.info = Some(info.clone());
+        self.current = None;
+        self.last_data = None;
+        self.next_batch = 0;
+        self.segments.clear();
+        Ok(())
+    }
+
+    fn process_batch(&mut self, batch : &Batch) -> ProcessorResult {
+        let now = Instant::now();
+        self.next_batch = batch.index + 1;
+        if batch.records.is_empty() {
+            let idle = self.last_data.map(|t| now.duration_since(t) >= self.idle_timeout).unwrap_or(true);
+            if idle {
+                self.close_segment(batch.index)?;
+            }
+            return Ok(());
+        }
+
+        self.last_data = Some(now);
+        if self.current.is_none() {
+            self.open_segment(batch.index)?;
+        }
+        self.segments.last_mut().unwrap().records += batch.records.len() as u64;
+        let processor = self.current.as_mut().unwrap();
+        processor.process_batch(batch)
+            .map_err(|e| format!("segment {} `{}`: {}", self.segments.len() - 1, processor.name(), e))
+    }
+
+    fn sync_event(&mut self, event : &SyncEvent) -> ProcessorResult {
+        match self.current.as_mut() {
+            Some(processor) => processor.sync_event(event),
+            None => Ok(()),
+        }
+    }
+
+    fn finalize(&mut self) -> ProcessorResult {
+        self.close_segment(self.next_batch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use crate::mhconsts::MeasurementMode;
+    use crate::testing::fixtures::stream_info;
+
+    /// `(segment label, records)` of each finalized segment
+    type Collected = Arc<Mutex<Vec<(String, Vec<u32>)>>>;
+
+    struct Collector {
+        out : Collected,
+        records : Vec<u32>,
+        segment : String,
+    }
+
+    impl StreamProcessor for Collector {
+        fn name(&self) -> &str { "collector" }
+
+        fn init(&mut self, info : &StreamInfo) -> ProcessorResult {
+            self.segment = info.labels.get("segment").unwrap().to_string();
+            Ok(())
+        }
+
+        fn process_batch(&mut self, batch : &Batch) -> ProcessorResult {
+            self.records.extend_from_slice(batch.records);
+            Ok(())
+        }
+
+        fn finalize(&mut self) -> ProcessorResult {
+            self.out.lock().unwrap().push((self.segment.clone(), self.records.clone()));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_gate_segmenter() {
+        let out = Arc::new(Mutex::new(Vec::new()));
+        let factory_out = Arc::clone(&out);
+        let mut segmenter = GateSegmenter::new(Duration::ZERO, Box::new(move |_| {
+            Ok(Box::new(Collector { out : Arc::clone(&factory_out), records : Vec::new(), segment : String::new() }) as Box<dyn StreamProcessor>)
+        }));
+        segmenter.init(&stream_info(MeasurementMode::T3)).unwrap();
+
+        let reads : [&[u32]; 6] = [&[], &[1, 2], &[3], &[], &[], &[4]];
+        for (index, records) in reads.iter().enumerate() {
+            segmenter.process_batch(&Batch { index : index as u64, read_at : Instant::now(), records, mode : MeasurementMode::T3, sync_lost : false }).unwrap();
+        }
+        assert!(segmenter.is_open());
+        segmenter.finalize().unwrap();
+
+        assert_eq!(segmenter.segments(), &[
+            GateSegment { number : 0, start_batch : 1, end_batch : Some(3), records : 3 },
+            GateSegment { number : 1, start_batch : 5, end_batch : Some(6), records : 1 },
+        ]);
+        assert_eq!(*out.lock().unwrap(), vec![
+            ("0".to_string(), vec![1, 2, 3]),
+            ("1".to_string(), vec![4]),
+        ]);
+    }
+}
@@ -0,0 +1,427 @@
+//! Streams decoded TTTR events into an HDF5 file, for acquisitions too
+//! long to hold in memory and too valuable to keep only as raw records.
+//!
+//! ## Layout
+//!
+//! * Attributes of the root: `serial`, `mode` (`"T2"` or `"T3"`),
+//!   `resolution_ps`, `sync_period_ps` (T3), `num_channels`,
+//!   `enabled_channels`, `created_unix_s`, `creator`, and once finished,
+//!   `photons`, `markers` and `syncs`
+//! * `labels`: a group with one string attribute per label
+//! * `photons`: the datasets `channel`, `nsync` and `dtime` (T3) or
+//!   `timetag` (T2), and `time_ps`, the time since the start of the
+//!   acquisition in ps
+//! * `markers`: `channel` (the marker bitfield) and `time_ps`, and
+//!   given a `ClockMapping`, `host_time_s`, the host wall clock time in
+//!   seconds since the Unix epoch
+//! * `syncs` (T2): `time_ps`
+//!
+//! Every dataset is one dimensional, chunked and, unless turned off,
+//! compressed with deflate.
+
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hdf5::types::VarLenUnicode;
+use hdf5::{Dataset, File, Group, H5Type, Location};
+
+use crate::clock::ClockMapping;
+use crate::error::{CheckedResult, PatinaError};
+use crate::markers::MarkerTable;
+use crate::mhconsts::MeasurementMode;
+use crate::multiharp::MultiHarpDevice;
+use crate::partition::{partition_path, PartitionSink, Partitioned, Partitioning};
+use crate::pipeline::{Batch, ProcessorResult, StreamInfo, StreamProcessor};
+use crate::tttr::{Decoder, EventColumns, EventKind};
+
+/// Events per chunk of each dataset, and per write
+pub const DEFAULT_HDF5_CHUNK : usize = 1 << 16;
+/// Deflate level of the datasets
+pub const DEFAULT_HDF5_COMPRESSION : u8 = 4;
+
+/// The datasets events of one kind are appended to
+struct EventDatasets {
+    channel : Option<Dataset>,
+    /// `nsync` in T3, `timetag` in T2
+    time : Option<Dataset>,
+    dtime : Option<Dataset>,
+    time_ps : Dataset,
+    written : usize,
+}
+
+impl EventDatasets {
+    fn append(&mut self, channels : &[u8], times : &[u64], dtimes : &[u16], times_ps : &[u64]) -> hdf5::Result<()> {
+        let start = self.written;
+        if let Some(dataset) = self.channel.as_ref() {
+            append(dataset, start, channels)?;
+        }
+        if let Some(dataset) = self.time.as_ref() {
+            append(dataset, start, times)?;
+        }
+        if let Some(dataset) = self.dtime.as_ref() {
+            append(dataset, start, dtimes)?;
+        }
+        append(&self.time_ps, start, times_ps)?;
+        self.written += times_ps.len();
+        Ok(())
+    }
+}
+
+fn append<T : H5Type>(dataset : &Dataset, start : usize, values : &[T]) -> hdf5::Result<()> {
+    if values.is_empty() {
+        return Ok(());
+    }
+    dataset.resize(start + values.len())?;
+    dataset.write_slice(values, start..start + values.len())
+}
+
+fn write_string_attr(location : &Location, name : &str, value : &str) -> hdf5::Result<()> {
+    let value = value.parse::<VarLenUnicode>().map_err(|e| hdf5::Error::from(e.to_string()))?;
+    location.new_attr::<VarLenUnicode>().create(name)?.write_scalar(&value)
+}
+
+fn write_attr<T : H5Type>(location : &Location, name : &str, value : T) -> hdf5::Result<()> {
+    location.new_attr::<T>().create(name)?.write_scalar(&value)
+}
+
+/// Writes the photons, markers and syncs of one acquisition to an HDF5
+/// file as they are read, with the measurement's metadata as attributes.
+/// The datasets are created by `init`, as a `StreamProcessor`, or by
+/// `begin`, and the file is complete after `finish`.
+///
+/// ## Example
+///
+/// ```no_run
+/// use multi_harp_patina::*;
+///
+/// let mh = open_t3(None).unwrap();
+/// let writer = Hdf5Writer::for_device("flim.h5", &*mh).unwrap();
+/// let mut pipeline = Pipeline::new();
+/// pipeline.add(writer);
+/// ```
+pub struct Hdf5Writer {
+    file : File,
+    sync_period : Option<f64>,
+    chunk : usize,
+    compression : Option<u8>,
+    mode : MeasurementMode,
+    resolution : f64,
+    decoder : Option<Decoder>,
+    pending : EventColumns,
+    photons : Option<EventDatasets>,
+    markers : Option<EventDatasets>,
+    syncs : Option<EventDatasets>,
+    marker_table : MarkerTable,
+}
+
+impl Hdf5Writer {
+    /// Creates the file at `path`, replacing any existing file.
+    pub fn create<P : AsRef<Path>>(path : P) -> hdf5::Result<Self> {
+        Ok(Hdf5Writer {
+            file : File::create(path)?,
+            sync_period : None,
+            chunk : DEFAULT_HDF5_CHUNK,
+            compression : Some(DEFAULT_HDF5_COMPRESSION),
+            mode : MeasurementMode::T3,
+            resolution : 0.0,
+            decoder : None,
+            pending : EventColumns::new(),
+            photons : None,
+            markers : None,
+            syncs : None,
+            marker_table : MarkerTable::new(),
+        })
+    }
+
+    /// Writes a file per partition of the acquisition, at
+    /// `partition_path(path, n)`. T3 streams need the `sync_period` in ps.
+    pub fn partitioned<P : AsRef<Path>>(path : P, partitioning : Partitioning, sync_period : Option<f64>) -> Partitioned<Self> {
+        let path = path.as_ref().to_path_buf();
+        let partitioned = Partitioned::new(partitioning, Box::new(move |n| {
+            let writer = Self::create(partition_path(&path, n)).map_err(|e| e.to_string())?;
+            Ok(match sync_period {
+                Some(period) => writer.with_sync_period(period),
+                None => writer,
+            })
+        }));
+        match sync_period {
+            Some(period) => partitioned.with_sync_period(period),
+            None => partitioned,
+        }
+    }
+
+    /// Creates the file at `path`, taking the sync period from the device
+    /// and recording its sync divider and binning.
+    pub fn for_device<P : AsRef<Path>, MH : MultiHarpDevice>(path : P, multiharp : &MH) -> CheckedResult<Self, i32> {
+        let sync_period = multiharp.get_sync_period().map_err(PatinaError::MultiHarpError)?;
+        let writer = Self::create(path).map_err(|e| PatinaError::Io(e.to_string()))?
+            .with_sync_period(sync_period * 1e12);
+        let settings = multiharp.current_settings();
+        let recorded = settings.sync_div.map_or(Ok(()), |div| write_attr(&writer.file, "sync_divider", div))
+            .and_then(|_| settings.binning.map_or(Ok(()), |binning| write_attr(&writer.file, "binning", binning)));
+        recorded.map_err(|e| PatinaError::Io(e.to_string()))?;
+        Ok(writer)
+    }
+
+    /// The sync period in ps, needed to give T3 events a `time_ps`
+    pub fn with_sync_period(mut self, period : f64) -> Self {
+        self.sync_period = Some(period);
+        self
+    }
+
+    /// Events per chunk, and per write. Larger chunks compress better
+    /// but hold more events in memory.
+    pub fn with_chunk_size(mut self, chunk : usize) -> Self {
+        self.chunk = chunk.max(1);
+        self
+    }
+
+    /// Deflate level (0 to 9), or `None` to store the datasets uncompressed
+    pub fn with_compression(mut self, level : Option<u8>) -> Self {
+        self.compression = level.map(|level| level.min(9));
+        self
+    }
+
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// Photons written so far, not counting those still buffered
+    pub fn photons_written(&self) -> usize {
+        self.photons.as_ref().map_or(0, |photons| photons.written)
+    }
+
+    /// The marker events written so far
+    pub fn markers(&self) -> &MarkerTable {
+        &self.marker_table
+    }
+
+    /// Adds the host times of the markers, written by `finish`, see
+    /// `MarkerTable::set_clock_mapping`
+    pub fn set_clock_mapping(&mut self, mapping : ClockMapping) {
+        self.marker_table.set_clock_mapping(mapping);
+    }
+
+    fn dataset<T : H5Type>(&self, group : &Group, name : &str) -> hdf5::Result<Dataset> {
+        let mut builder = group.new_dataset::<T>().chunk(self.chunk);
+        if let Some(level) = self.compression {
+            builder = builder.deflate(level);
+        }
+        builder.shape(0usize..).create(name)
+    }
+
+    fn event_datasets(&self, group : &str, with_channel : bool, with_times : bool) -> hdf5::Result<EventDatasets> {
+        let group = self.file.create_group(group)?;
+        let t3 = self.mode == MeasurementMode::T3;
+        Ok(EventDatasets {
+            channel : if with_channel { Some(self.dataset::<u8>(&group, "channel")?) } else { None },
+            time : match (with_times, t3) {
+                (true, true) => Some(self.dataset::<u64>(&group, "nsync")?),
+                (true, false) => Some(self.dataset::<u64>(&group, "timetag")?),
+                _ => None,
+            },
+            dtime : if with_times && t3 { Some(self.dataset::<u16>(&group, "dtime")?) } else { None },
+            time_ps : self.dataset::<u64>(&group, "time_ps")?,
+            written : 0,
+        })
+    }
+
+    /// Writes the metadata of the acquisition described by `info` and
+    /// creates the datasets.
+    ///
+    /// ## Errors
+    ///
+    /// If the file already holds an acquisition, or a T3 stream has no
+    /// sync period.
+    pub fn begin(&mut self, info : &StreamInfo) -> hdf5::Result<()> {
+        if self.photons.is_some() {
+            return Err("An HDF5 file holds a single acquisition".into());
+        }
+        if info.mode == MeasurementMode::T3 && self.sync_period.is_none() {
+            return Err("T3 streams need the sync period, see `with_sync_period`".into());
+        }
+        self.mode = info.mode;
+        self.resolution = info.resolution;
+        self.decoder = Some(Decoder::for_stream(info)?);
+        let mut marker_table = MarkerTable::new();
+        if let Some(period) = self.sync_period {
+            marker_table = marker_table.with_sync_period(period);
+        }
+        marker_table.begin(info)?;
+        self.marker_table = marker_table;
+
+        write_string_attr(&self.file, "serial", &info.serial)?;
+        write_string_attr(&self.file, "mode", &format!("{:?}", info.mode))?;
+        write_attr(&self.file, "resolution_ps", info.resolution)?;
+        if let (Some(period), MeasurementMode::T3) = (self.sync_period, info.mode) {
+            write_attr(&self.file, "sync_period_ps", period)?;
+        }
+        write_attr(&self.file, "num_channels", info.num_channels)?;
+        let enabled = info.enabled_channels.iter().map(|e| *e as u8).collect::<Vec<_>>();
+        self.file.new_attr::<u8>().shape(enabled.len()).create("enabled_channels")?.write(&enabled[..])?;
+        let created = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+        write_attr(&self.file, "created_unix_s", created)?;
+        write_string_attr(&self.file, "creator", concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION")))?;
+        let labels = self.file.create_group("labels")?;
+        for (key, value) in info.labels.iter() {
+            write_string_attr(&labels, key, value)?;
+        }
+
+        self.photons = Some(self.event_datasets("photons", true, true)?);
+        self.markers = Some(self.event_datasets("markers", true, false)?);
+        if info.mode == MeasurementMode::T2 {
+            self.syncs = Some(self.event_datasets("syncs", false, false)?);
+        }
+        Ok(())
+    }
+
+    /// Decodes `records` and appends their events, writing once a chunk
+    /// of them is buffered.
+    pub fn write_records(&mut self, records : &[u32]) -> hdf5::Result<()> {
+        let decoder = self.decoder.as_mut().ok_or("The acquisition hasn't begun, see `begin`")?;
+        decoder.decode_into(records, &mut self.pending);
+        if self.pending.len() >= self.chunk {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Appends already decoded events, e.g. those of one partition,
+    /// writing once a chunk of them is buffered.
+    pub fn write_columns(&mut self, columns : &EventColumns) -> hdf5::Result<()> {
+        if self.decoder.is_none() {
+            return Err("The acquisition hasn't begun, see `begin`".into());
+        }
+        self.pending.extend_from_range(columns, 0..columns.len());
+        if self.pending.len() >= self.chunk {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the buffered events
+    pub fn flush(&mut self) -> hdf5::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let times_ps = self.pending.times_ps(self.mode, self.resolution, self.sync_period.unwrap_or(0.0));
+        for (kind, datasets) in [
+            (EventKind::Photon, self.photons.as_mut()),
+            (EventKind::Marker, self.markers.as_mut()),
+            (EventKind::Sync, self.syncs.as_mut()),
+        ] {
+            let datasets = match datasets {
+                Some(datasets) => datasets,
+                None => continue,
+            };
+            let indices = (0..self.pending.len()).filter(|i| self.pending.kinds[*i] == kind).collect::<Vec<_>>();
+            let channels = indices.iter().map(|i| self.pending.channels[*i]).collect::<Vec<_>>();
+            let times = indices.iter().map(|i| self.pending.time[*i]).collect::<Vec<_>>();
+            let dtimes = indices.iter().map(|i| self.pending.dtime[*i]).collect::<Vec<_>>();
+            let kind_times_ps = indices.iter().map(|i| times_ps[*i]).collect::<Vec<_>>();
+            datasets.append(&channels, &times, &dtimes, &kind_times_ps)?;
+        }
+        self.marker_table.extend_from_columns(&self.pending);
+        self.pending.clear();
+        self.file.flush()
+    }
+
+    /// Writes the buffered events and the number of events of each kind
+    pub fn finish(&mut self) -> hdf5::Result<()> {
+        self.flush()?;
+        for (name, datasets) in [("photons", &self.photons), ("markers", &self.markers), ("syncs", &self.syncs)] {
+            if let Some(datasets) = datasets {
+                write_attr(&self.file, name, datasets.written as u64)?;
+            }
+        }
+        if self.marker_table.clock_mapping().is_some() {
+            let host_times = self.marker_table.rows().iter()
+                .map(|row| row.host_time
+                    .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                    .map_or(f64::NAN, |since| since.as_secs_f64()))
+                .collect::<Vec<_>>();
+            let markers = self.file.group("markers")?;
+            let dataset = self.dataset::<f64>(&markers, "host_time_s")?;
+            append(&dataset, 0, &host_times)?;
+        }
+        self.file.flush()
+    }
+}
+
+impl PartitionSink for Hdf5Writer {
+    fn begin(&mut self, info : &StreamInfo) -> Result<(), String> {
+        Hdf5Writer::begin(self, info).map_err(|e| e.to_string())
+    }
+
+    fn write_columns(&mut self, columns : &EventColumns) -> Result<(), String> {
+        Hdf5Writer::write_columns(self, columns).map_err(|e| e.to_string())
+    }
+
+    fn finish(&mut self) -> Result<(), String> {
+        Hdf5Writer::finish(self).map_err(|e| e.to_string())
+    }
+}
+
+impl StreamProcessor for Hdf5Writer {
+    fn name(&self) -> &str { "hdf5_writer" }
+
+    fn init(&mut self, info : &StreamInfo) -> ProcessorResult {
+        self.begin(info).map_err(|e| e.to_string())
+    }
+
+    fn process_batch(&mut self, batch : &Batch) -> ProcessorResult {
+        self.write_records(batch.records).map_err(|e| e.to_string())
+    }
+
+    fn finalize(&mut self) -> ProcessorResult {
+        self.finish().map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::Labels;
+    use crate::tttr::{pack_t3, OverflowCompression, OVERFLOW_CHANNEL, T3_WRAPAROUND};
+    use crate::testing::fixtures::stream_info;
+
+    #[test]
+    fn test_hdf5_writer() {
+        let path = std::env::temp_dir().join("multi_harp_patina_test.h5");
+        let mut labels = Labels::new();
+        labels.insert("sample", "fly_03");
+        let info = StreamInfo { labels, ..stream_info(MeasurementMode::T3) };
+
+        let mut writer = Hdf5Writer::create(&path).unwrap().with_chunk_size(2);
+        assert!(writer.begin(&info).is_err());
+        let mut writer = writer.with_sync_period(12_500.0);
+        writer.begin(&info).unwrap();
+        writer.write_records(&[
+            pack_t3(false, 0, 100, 1),
+            pack_t3(true, 2, 0, 3),
+            pack_t3(true, OVERFLOW_CHANNEL, 0, 0),
+            pack_t3(false, 3, 7, 2),
+        ]).unwrap();
+        writer.write_records(&[pack_t3(false, 1, 0, 5)]).unwrap();
+        assert_eq!(writer.photons_written(), 2);
+        writer.finish().unwrap();
+        assert!(writer.begin(&info).is_err());
+
+        let file = writer.file();
+        assert_eq!(file.dataset("photons/channel").unwrap().read_raw::<u8>().unwrap(), vec![0, 3, 1]);
+        let nsync = T3_WRAPAROUND + 2;
+        assert_eq!(file.dataset("photons/nsync").unwrap().read_raw::<u64>().unwrap(), vec![1, nsync, T3_WRAPAROUND + 5]);
+        assert_eq!(file.dataset("photons/dtime").unwrap().read_raw::<u16>().unwrap(), vec![100, 7, 0]);
+        let time_ps = file.dataset("photons/time_ps").unwrap().read_raw::<u64>().unwrap();
+        assert_eq!(time_ps[..2], [12_500 + 500, nsync * 12_500 + 35]);
+        assert_eq!(file.dataset("markers/time_ps").unwrap().read_raw::<u64>().unwrap(), vec![3 * 12_500]);
+        assert!(file.group("syncs").is_err());
+        assert!(file.dataset("markers/host_time_s").is_err());
+        assert_eq!(writer.markers().len(), 1);
+
+        assert_eq!(file.attr("photons").unwrap().read_scalar::<u64>().unwrap(), 3);
+        assert_eq!(file.attr("resolution_ps").unwrap().read_scalar::<f64>().unwrap(), 5.0);
+        let sample = file.group("labels").unwrap().attr("sample").unwrap().read_scalar::<VarLenUnicode>().unwrap();
+        assert_eq!(sample.as_str(), "fly_03");
+    }
+}
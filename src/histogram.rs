@@ -0,0 +1,771 @@
+//! Histograms that keep their time axis and where they came from, so
+//! that nothing downstream has to remember the settings they were
+//! acquired with.
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::channels;
+use crate::error::{CheckedResult, PatinaError};
+use crate::limits::Limits;
+use crate::mhconsts::{self, MeasurementMode};
+use crate::pipeline::{Batch, ProcessorResult, StreamInfo, StreamProcessor};
+use crate::tttr::{Decoder, EventKind};
+use crate::MultiHarpDevice;
+
+/// An arrival time histogram from one input channel.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    /// Counts in each bin
+    pub bins : Vec<u32>,
+    /// Width of each bin in picoseconds
+    pub bin_width_ps : f64,
+    /// Time of the start of the first bin, relative to the sync, in picoseconds
+    pub offset_ps : f64,
+    /// Input channel the histogram was collected on
+    pub channel : i32,
+    /// How long the measurement ran
+    pub acquired_for : Duration,
+    /// Serial number of the device it was collected on
+    pub device_serial : String,
+}
+
+impl Histogram {
+    /// Number of bins
+    pub fn len(&self) -> usize {
+        self.bins.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bins.is_empty()
+    }
+
+    /// Sum over all bins
+    pub fn total_counts(&self) -> u64 {
+        self.bins.iter().map(|b| *b as u64).sum()
+    }
+
+    /// Start time of bin `bin` in picoseconds after the sync
+    pub fn bin_time(&self, bin : usize) -> f64 {
+        self.offset_ps + bin as f64 * self.bin_width_ps
+    }
+
+    /// Start time of every bin in picoseconds after the sync
+    pub fn time_axis(&self) -> Vec<f64> {
+        (0..self.bins.len()).map(|i| self.bin_time(i)).collect()
+    }
+
+    /// The fullest bin and its count. The first one, if several are tied.
+    pub fn max_bin(&self) -> Option<(usize, u32)> {
+        self.bins.iter().copied().enumerate()
+            .fold(None, |max, (i, b)| match max {
+                Some((_, m)) if m >= b => max,
+                _ => Some((i, b)),
+            })
+    }
+
+    /// The histogram with only its nonzero bins stored
+    pub fn to_sparse(&self) -> SparseHistogram {
+        SparseHistogram {
+            counts : self.bins.iter().copied().enumerate().filter(|(_, b)| *b != 0).collect(),
+            len : self.bins.len(),
+            bin_width_ps : self.bin_width_ps,
+            offset_ps : self.offset_ps,
+            channel : self.channel,
+            acquired_for : self.acquired_for,
+            device_serial : self.device_serial.clone(),
+        }
+    }
+}
+
+/// A `Histogram` storing only the bins with counts, for long ranges
+/// (e.g. 65536 bins of a coarse binning) that are mostly empty.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SparseHistogram {
+    /// Counts of the nonzero bins, by bin index
+    pub counts : BTreeMap<usize, u32>,
+    /// Number of bins, including the empty ones
+    pub len : usize,
+    /// Width of each bin in picoseconds
+    pub bin_width_ps : f64,
+    /// Time of the start of the first bin, relative to the sync, in picoseconds
+    pub offset_ps : f64,
+    /// Input channel the histogram was collected on
+    pub channel : i32,
+    /// How long the measurement ran
+    pub acquired_for : Duration,
+    /// Serial number of the device it was collected on
+    pub device_serial : String,
+}
+
+impl SparseHistogram {
+    /// An empty histogram of `len` bins
+    pub fn new(len : usize, bin_width_ps : f64, offset_ps : f64, channel : i32, device_serial : &str) -> Self {
+        SparseHistogram {
+            counts : BTreeMap::new(),
+            len,
+            bin_width_ps,
+            offset_ps,
+            channel,
+            acquired_for : Duration::ZERO,
+            device_serial : device_serial.to_string(),
+        }
+    }
+
+    /// Number of bins, including the empty ones
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Number of bins with counts
+    pub fn occupied(&self) -> usize {
+        self.counts.len()
+    }
+
+    /// Counts in bin `bin`, 0 if it is empty or out of range
+    pub fn get(&self, bin : usize) -> u32 {
+        self.counts.get(&bin).copied().unwrap_or(0)
+    }
+
+    /// Adds `count` to bin `bin`, saturating. Bins past `len` are ignored.
+    pub fn add(&mut self, bin : usize, count : u32) {
+        if bin < self.len && count != 0 {
+            let entry = self.counts.entry(bin).or_insert(0);
+            *entry = entry.saturating_add(count);
+        }
+    }
+
+    /// Sum over all bins
+    pub fn total_counts(&self) -> u64 {
+        self.counts.values().map(|b| *b as u64).sum()
+    }
+
+    /// Start time of bin `bin` in picoseconds after the sync
+    pub fn bin_time(&self, bin : usize) -> f64 {
+        self.offset_ps + bin as f64 * self.bin_width_ps
+    }
+
+    /// The fullest bin and its count. The first one, if several are tied.
+    /// `None` if every bin is empty.
+    pub fn max_bin(&self) -> Option<(usize, u32)> {
+        self.counts.iter()
+            .fold(None, |max, (i, b)| match max {
+                Some((_, m)) if m >= *b => max,
+                _ => Some((*i, *b)),
+            })
+    }
+
+    /// The histogram with every bin stored
+    pub fn to_dense(&self) -> Histogram {
+        let mut bins = vec![0; self.len];
+        for (bin, count) in self.counts.iter() {
+            bins[*bin] = *count;
+        }
+        Histogram {
+            bins,
+            bin_width_ps : self.bin_width_ps,
+            offset_ps : self.offset_ps,
+            channel : self.channel,
+            acquired_for : self.acquired_for,
+            device_serial : self.device_serial.clone(),
+        }
+    }
+}
+
+/// Histograms the arrival times of T3 photons in software, one
+/// `SparseHistogram` per enabled channel, with a bin per `dtime` value.
+/// Use it directly with `init`, `process_batch` and `finalize`, or as a
+/// `Pipeline` stage with `with_output` to save the histograms when the
+/// acquisition ends (see `write_sparse_histograms_csv`).
+///
+/// ## Example
+///
+/// ```
+/// use multi_harp_patina::*;
+///
+/// let info = StreamInfo {
+///     serial : "1044272".to_string(),
+///     mode : MeasurementMode::T3,
+///     resolution : 80.0,
+///     num_channels : 2,
+///     enabled_channels : vec![true, true],
+///     overflow_compression : OverflowCompression::Compressed,
+///     labels : Labels::default(),
+/// };
+/// let mut histogrammer = SparseHistogrammer::new();
+/// histogrammer.init(&info).unwrap();
+/// let records = [pack_t3(false, 1, 300, 0), pack_t3(false, 1, 300, 1), pack_t3(false, 0, 12, 2)];
+/// histogrammer.process_batch(&Batch { index : 0, read_at : std::time::Instant::now(), records : &records, mode : MeasurementMode::T3, sync_lost : false }).unwrap();
+///
+/// let histograms = histogrammer.histograms();
+/// assert_eq!(histograms[1].get(300), 2);
+/// assert_eq!(histograms[1].occupied(), 1);
+/// assert_eq!(histograms[0].bin_time(12), 960.0);
+/// ```
+#[derive(Default)]
+pub struct SparseHistogrammer {
+    output : Option<PathBuf>,
+    histograms : Vec<SparseHistogram>,
+    decoder : Option<Decoder>,
+    started : Option<Instant>,
+}
+
+impl SparseHistogrammer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Writes the histograms to `path` as sparse CSV at the end of each
+    /// acquisition, replacing the file.
+    pub fn with_output<P : AsRef<Path>>(mut self, path : P) -> Self {
+        self.output = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// The histograms of the current (or last) acquisition, by channel
+    pub fn histograms(&self) -> &[SparseHistogram] {
+        &self.histograms
+    }
+
+    /// Photons on disabled channels, see `Decoder::anomalies`
+    pub fn anomalies(&self) -> u64 {
+        self.decoder.as_ref().map(|d| d.anomalies()).unwrap_or(0)
+    }
+
+    /// Empties the histograms without losing the place in the stream,
+    /// to histogram the acquisition in pieces
+    pub fn reset(&mut self) {
+        self.histograms.iter_mut().for_each(|h| h.counts.clear());
+        self.started = Some(Instant::now());
+    }
+}
+
+impl StreamProcessor for SparseHistogrammer {
+    fn name(&self) -> &str { "sparse_histogrammer" }
+
+    fn init(&mut self, info : &StreamInfo) -> ProcessorResult {
+        if info.mode != MeasurementMode::T3 {
+            return Err(format!("Software histograms need T3 records, not {:?}", info.mode));
+        }
+        let len = Limits::new(MeasurementMode::T3, None).max_histogram_len;
+        self.histograms = info.enabled().into_iter()
+            .map(|channel| SparseHistogram::new(len, info.resolution, 0.0, channel as i32, &info.serial))
+            .collect();
+        self.decoder = Some(Decoder::for_stream(info)?);
+        self.started = Some(Instant::now());
+        Ok(())
+    }
+
+    fn process_batch(&mut self, batch : &Batch) -> ProcessorResult {
+        let decoder = self.decoder.as_mut().ok_or("process_batch called before init")?;
+        for event in decoder.decode_all(batch.records) {
+            if event.kind != EventKind::Photon {
+                continue;
+            }
+            if let Some(histogram) = self.histograms.iter_mut().find(|h| h.channel == event.channel as i32) {
+                histogram.add(event.dtime as usize, 1);
+            }
+        }
+        Ok(())
+    }
+
+    fn finalize(&mut self) -> ProcessorResult {
+        let acquired_for = self.started.map(|s| s.elapsed()).unwrap_or_default();
+        self.histograms.iter_mut().for_each(|h| h.acquired_for = acquired_for);
+        if let Some(path) = self.output.as_ref() {
+            std::fs::File::create(path)
+                .and_then(|file| write_sparse_histograms_csv(&self.histograms, io::BufWriter::new(file)))
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+        }
+        Ok(())
+    }
+}
+
+/// Which bin ended a measurement stopped by `set_stop_overflow`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StopOverflowReport {
+    pub channel : i32,
+    pub bin : usize,
+    /// Counts in the bin, at least the stop count
+    pub count : u32,
+    /// Start time of the bin in picoseconds after the sync
+    pub bin_time_ps : f64,
+    /// Measurement time elapsed when it stopped, in milliseconds
+    pub elapsed_ms : f64,
+}
+
+impl std::fmt::Display for StopOverflowReport {
+    fn fmt(&self, f : &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Stopped after {} ms: channel {} bin {} ({} ps) reached {} counts",
+            self.elapsed_ms, self.channel, self.bin, self.bin_time_ps, self.count
+        )
+    }
+}
+
+/// Finds the bin in `histograms` that reached `stopcount`, if any.
+/// If more than one did, the fullest is reported.
+pub fn find_stop_overflow(histograms : &[Histogram], stopcount : u32, elapsed_ms : f64) -> Option<StopOverflowReport> {
+    histograms.iter()
+        .filter_map(|h| h.max_bin().map(|(bin, count)| (h, bin, count)))
+        .filter(|(_, _, count)| *count >= stopcount)
+        .fold(None, |max : Option<(&Histogram, usize, u32)>, candidate| match max {
+            Some(m) if m.2 >= candidate.2 => Some(m),
+            _ => Some(candidate),
+        })
+        .map(|(h, bin, count)| StopOverflowReport {
+            channel : h.channel,
+            bin,
+            count,
+            bin_time_ps : h.bin_time(bin),
+            elapsed_ms,
+        })
+}
+
+/// After a measurement has ended, reports which channel and bin hit
+/// `stopcount` (the value passed to `set_stop_overflow`) and when. Returns
+/// `None` if no bin reached it, i.e. the measurement stopped for another
+/// reason.
+///
+/// ## Errors
+///
+/// - `PatinaError::WrongMode` if the device is not in Histogramming mode.
+pub fn stop_overflow_report<MH : MultiHarpDevice>(
+    multiharp : &mut MH,
+    stopcount : u32,
+) -> CheckedResult<Option<StopOverflowReport>, i32> {
+    let mode = multiharp.get_measurement_mode();
+    if mode != MeasurementMode::Histogramming {
+        return Err(PatinaError::WrongMode("stop_overflow_report".to_string(), mode));
+    }
+    let elapsed_ms = multiharp.get_elapsed_measurement_time()?;
+    let histograms = all_histograms(multiharp, Duration::from_secs_f64(elapsed_ms / 1000.0))?;
+    Ok(find_stop_overflow(&histograms, stopcount, elapsed_ms))
+}
+
+/// How often `acquire_histograms` reports progress
+const PROGRESS_INTERVAL : Duration = Duration::from_millis(100);
+
+/// Clears the histograms and runs a measurement for `acquisition_time` ms,
+/// returning how long it actually ran. Stops early if the device ends the
+/// measurement itself (e.g. on a stop overflow). `progress` is called with
+/// the time elapsed every `PROGRESS_INTERVAL` and when the measurement ends.
+/// If `progress` panics the measurement is stopped before the panic
+/// continues.
+fn run_histogram_measurement<MH, F>(
+    multiharp : &mut MH,
+    acquisition_time : i32,
+    mut progress : F,
+) -> CheckedResult<Duration, i32>
+where MH : MultiHarpDevice, F : FnMut(Duration) {
+    let mode = multiharp.get_measurement_mode();
+    if mode != MeasurementMode::Histogramming {
+        return Err(PatinaError::WrongMode("acquire_histogram".to_string(), mode));
+    }
+    multiharp.clear_histogram()?;
+    multiharp.start_measurement(acquisition_time)?;
+    let started = Instant::now();
+    let mut reported = started;
+    let waited = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        while multiharp.ctc_status()?
+        && started.elapsed().as_millis() < acquisition_time as u128 {
+            std::thread::sleep(Duration::from_millis(1));
+            if reported.elapsed() >= PROGRESS_INTERVAL {
+                reported = Instant::now();
+                progress(started.elapsed());
+            }
+        }
+        Ok(())
+    }));
+    let waited = match waited {
+        Ok(waited) => waited,
+        Err(panic) => {
+            let _ = multiharp.stop_measurement();
+            std::panic::resume_unwind(panic);
+        },
+    };
+    let acquired_for = started.elapsed();
+    multiharp.stop_measurement()?;
+    progress(acquired_for);
+    waited.map(|_| acquired_for).map_err(|e : crate::MultiHarpError| PatinaError::from(e))
+}
+
+/// Builds a `Histogram` for `channel` from `bins` and the device's current settings.
+fn histogram_from_device<MH : MultiHarpDevice>(
+    multiharp : &MH,
+    bins : Vec<u32>,
+    channel : i32,
+    acquired_for : Duration,
+) -> CheckedResult<Histogram, i32> {
+    Ok(Histogram {
+        bins,
+        bin_width_ps : multiharp.get_resolution()?,
+        offset_ps : multiharp.get_offset() as f64 * 1000.0,
+        channel,
+        acquired_for,
+        device_serial : multiharp.get_serial(),
+    })
+}
+
+/// Clears the histogram memory, measures for `acquisition_time` ms and
+/// returns the histogram of `channel`. The device must be initialized in
+/// Histogramming mode.
+///
+/// ## Arguments
+///
+/// * `multiharp` - The device to acquire from.
+///
+/// * `channel` - The input channel to return the histogram of.
+///
+/// * `acquisition_time` - Measurement time in milliseconds.
+///
+/// ## Errors
+///
+/// - `PatinaError::WrongMode` if the device is not in Histogramming mode.
+///
+/// ## Example
+///
+/// ```
+/// use multi_harp_patina::*;
+///
+/// let mut mh = DebugMultiHarp150::default();
+/// mh.init(MeasurementMode::Histogramming, ReferenceClock::Internal).unwrap();
+/// let histogram = acquire_histogram(&mut mh, 0, 10).unwrap();
+/// assert_eq!(histogram.device_serial, "1044272");
+/// println!("First bin starts at {} ps", histogram.bin_time(0));
+/// ```
+pub fn acquire_histogram<MH : MultiHarpDevice>(
+    multiharp : &mut MH,
+    channel : i32,
+    acquisition_time : i32,
+) -> CheckedResult<Histogram, i32> {
+    let acquired_for = run_histogram_measurement(multiharp, acquisition_time, |_| {})?;
+    let bins = multiharp.get_histogram_by_copy(channel)?;
+    histogram_from_device(multiharp, bins, channel, acquired_for)
+}
+
+/// Progress of `acquire_histograms`, passed to its callback
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HistogramProgress {
+    pub elapsed : Duration,
+    /// The requested measurement time
+    pub duration : Duration,
+}
+
+impl HistogramProgress {
+    /// Elapsed fraction of the measurement, from 0 to 1
+    pub fn fraction(&self) -> f64 {
+        if self.duration.is_zero() {
+            return 1.0;
+        }
+        (self.elapsed.as_secs_f64() / self.duration.as_secs_f64()).min(1.0)
+    }
+}
+
+/// Everything collected by `acquire_histograms`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistogramAcquisition {
+    /// One histogram per enabled input channel, in channel order
+    pub histograms : Vec<Histogram>,
+    /// The bin that stopped the measurement, if a stop count was set and reached
+    pub stop_overflow : Option<StopOverflowReport>,
+    /// Where the histograms were saved, if anywhere
+    pub output : Option<PathBuf>,
+}
+
+/// Runs a complete Histogramming mode experiment: clears the histogram
+/// memory, measures for `duration` (or until a bin reaches
+/// `stop_overflow`), fetches the histograms of every enabled channel, stops the
+/// device and, if `output` is given, saves them there as CSV (see
+/// `write_histograms_csv`).
+///
+/// ## Arguments
+///
+/// * `multiharp` - A device initialized in Histogramming mode.
+///
+/// * `duration` - Measurement time, rounded down to whole milliseconds.
+///
+/// * `stop_overflow` - If set, stop when any bin reaches this count.
+///   `None` leaves the device's current stop overflow setting.
+///
+/// * `output` - File to save the histograms to.
+///
+/// * `progress` - Called about every 100 ms, and once the measurement ends.
+///
+/// ## Errors
+///
+/// - `PatinaError::WrongMode` if the device is not in Histogramming mode.
+/// - `PatinaError::ArgumentError` if `duration` or `stop_overflow` is out of range.
+/// - `PatinaError::Io` if the histograms can't be saved.
+///
+/// ## Example
+///
+/// ```
+/// use std::time::Duration;
+/// use multi_harp_patina::*;
+///
+/// let mut mh = DebugMultiHarp150::default();
+/// mh.init(MeasurementMode::Histogramming, ReferenceClock::Internal).unwrap();
+/// let run = acquire_histograms(&mut mh, Duration::from_millis(10), Some(10_000), None, |p| {
+///     println!("{:.0}%", 100.0 * p.fraction());
+/// }).unwrap();
+/// assert_eq!(run.histograms.len(), 4);
+/// assert_eq!(run.stop_overflow, None);
+/// ```
+pub fn acquire_histograms<MH, F>(
+    multiharp : &mut MH,
+    duration : Duration,
+    stop_overflow : Option<u32>,
+    output : Option<&Path>,
+    mut progress : F,
+) -> CheckedResult<HistogramAcquisition, i32>
+where MH : MultiHarpDevice, F : FnMut(&HistogramProgress) {
+    let acquisition_time = duration.as_millis().min(i32::MAX as u128) as i32;
+    if !(mhconsts::ACQTMIN..=mhconsts::ACQTMAX).contains(&acquisition_time) {
+        return Err(PatinaError::ArgumentError(
+            "duration".to_string(),
+            acquisition_time,
+            format!("Duration must be between {} and {} ms", mhconsts::ACQTMIN, mhconsts::ACQTMAX))
+        );
+    }
+    if let Some(stopcount) = stop_overflow {
+        multiharp.set_stop_overflow(true, stopcount).map_err(|e| e.map_argument(|c| c as i32))?;
+    }
+
+    let acquired_for = run_histogram_measurement(multiharp, acquisition_time, |elapsed| {
+        progress(&HistogramProgress { elapsed, duration })
+    })?;
+    let histograms = all_histograms(multiharp, acquired_for)?;
+
+    let stop_overflow = match stop_overflow {
+        Some(stopcount) => {
+            let elapsed_ms = multiharp.get_elapsed_measurement_time()?;
+            find_stop_overflow(&histograms, stopcount, elapsed_ms)
+        },
+        None => None,
+    };
+
+    if let Some(path) = output {
+        std::fs::File::create(path)
+            .and_then(|file| write_histograms_csv(&histograms, io::BufWriter::new(file)))
+            .map_err(|e| PatinaError::Io(format!("{}: {}", path.display(), e)))?;
+    }
+
+    Ok(HistogramAcquisition { histograms, stop_overflow, output : output.map(Path::to_path_buf) })
+}
+
+/// Writes `histograms` as CSV: a `time_ps` column with the time axis of
+/// the first, then one column of counts per histogram, named from the
+/// device's `ChannelMap` or `ch<n>`. The device serial, bin width and
+/// acquisition time come first, as `# key = value` comment lines.
+pub fn write_histograms_csv<W : Write>(histograms : &[Histogram], mut writer : W) -> io::Result<()> {
+    let first = match histograms.first() {
+        Some(first) => first,
+        None => return writer.flush(),
+    };
+    writeln!(writer, "# serial = {}", first.device_serial)?;
+    writeln!(writer, "# bin_width_ps = {}", first.bin_width_ps)?;
+    writeln!(writer, "# acquired_for_ms = {}", first.acquired_for.as_secs_f64() * 1000.0)?;
+    let names = channels::channel_map(&first.device_serial);
+    let columns = histograms.iter().map(|h| names.label(h.channel as u8)).collect::<Vec<_>>();
+    writeln!(writer, "time_ps,{}", columns.join(","))?;
+
+    let len = histograms.iter().map(|h| h.len()).max().unwrap_or(0);
+    for bin in 0..len {
+        write!(writer, "{}", first.bin_time(bin))?;
+        for histogram in histograms {
+            write!(writer, ",{}", histogram.bins.get(bin).copied().unwrap_or(0))?;
+        }
+        writeln!(writer)?;
+    }
+    writer.flush()
+}
+
+/// Writes `histograms` as CSV like `write_histograms_csv`, but with
+/// only the rows of bins that have counts in some histogram, and a
+/// `bin` column before `time_ps`. The number of bins is given in a
+/// `# bins = n` comment line.
+pub fn write_sparse_histograms_csv<W : Write>(histograms : &[SparseHistogram], mut writer : W) -> io::Result<()> {
+    let first = match histograms.first() {
+        Some(first) => first,
+        None => return writer.flush(),
+    };
+    writeln!(writer, "# serial = {}", first.device_serial)?;
+    writeln!(writer, "# bin_width_ps = {}", first.bin_width_ps)?;
+    writeln!(writer, "# acquired_for_ms = {}", first.acquired_for.as_secs_f64() * 1000.0)?;
+    writeln!(writer, "# bins = {}", histograms.iter().map(|h| h.len()).max().unwrap_or(0))?;
+    let names = channels::channel_map(&first.device_serial);
+    let columns = histograms.iter().map(|h| names.label(h.channel as u8)).collect::<Vec<_>>();
+    writeln!(writer, "bin,time_ps,{}", columns.join(","))?;
+
+    let mut occupied = histograms.iter().flat_map(|h| h.counts.keys().copied()).collect::<Vec<_>>();
+    occupied.sort_unstable();
+    occupied.dedup();
+    for bin in occupied {
+        write!(writer, "{},{}", bin, first.bin_time(bin))?;
+        for histogram in histograms {
+            write!(writer, ",{}", histogram.get(bin))?;
+        }
+        writeln!(writer)?;
+    }
+    writer.flush()
+}
+
+/// Reads the histograms of all enabled channels from the device
+fn all_histograms<MH : MultiHarpDevice>(
+    multiharp : &mut MH,
+    acquired_for : Duration,
+) -> CheckedResult<Vec<Histogram>, i32> {
+    let num_channels = multiharp.num_input_channels()?.max(1) as usize;
+    let enabled = multiharp.get_input_channel_enables()?;
+    let all = multiharp.get_all_histograms_by_copy()?;
+    let bins_per_channel = all.len() / num_channels;
+    (0..num_channels).filter(|channel| enabled.get(*channel).copied().unwrap_or(true)).map(|channel| {
+        let bins = all[channel * bins_per_channel..(channel + 1) * bins_per_channel].to_vec();
+        histogram_from_device(multiharp, bins, channel as i32, acquired_for)
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DebugMultiHarp150, ReferenceClock};
+    use crate::testing::fixtures::stream_info;
+
+    #[test]
+    fn test_acquire_histograms() {
+        let mut mh = DebugMultiHarp150::default();
+        assert!(matches!(acquire_histogram(&mut mh, 0, 1), Err(PatinaError::WrongMode(..))));
+
+        mh.init(MeasurementMode::Histogramming, ReferenceClock::Internal).unwrap();
+        mh.set_offset(20).unwrap();
+        let path = std::env::temp_dir().join("test_acquire_histograms.csv");
+        let mut reports = Vec::new();
+        let run = acquire_histograms(&mut mh, Duration::from_millis(5), Some(100), Some(&path), |p| reports.push(*p)).unwrap();
+        let histograms = run.histograms;
+        assert_eq!(run.output.as_deref(), Some(path.as_path()));
+        assert_eq!(reports.last().unwrap().fraction(), 1.0);
+        assert_eq!(histograms.len(), 4);
+        assert_eq!(histograms[2].channel, 2);
+        assert_eq!(histograms[2].len(), crate::mhconsts::MAXHISTLEN);
+        assert_eq!(histograms[2].offset_ps, 20_000.0);
+        assert_eq!(histograms[2].bin_time(2), 20_000.0 + 2.0 * histograms[2].bin_width_ps);
+        assert!(histograms[2].acquired_for >= Duration::from_millis(5));
+        assert!(!mh.ctc_status().unwrap());
+
+        let csv = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(csv.starts_with("# serial = 1044272\n"));
+        assert!(csv.contains("\ntime_ps,ch0,ch1,ch2,ch3\n20000,0,0,0,0\n"));
+        assert_eq!(csv.lines().count(), 4 + crate::mhconsts::MAXHISTLEN);
+
+        mh.set_input_channel_enable(1, false).unwrap();
+        let run = acquire_histograms(&mut mh, Duration::from_millis(5), None, None, |_| {}).unwrap();
+        assert_eq!(run.histograms.iter().map(|h| h.channel).collect::<Vec<_>>(), vec![0, 2, 3]);
+
+        assert!(matches!(
+            acquire_histograms(&mut mh, Duration::ZERO, None, None, |_| {}),
+            Err(PatinaError::ArgumentError(..))
+        ));
+
+        // A panicking progress callback still stops the measurement
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            acquire_histograms(&mut mh, Duration::from_millis(1000), None, None, |_| panic!("progress"))
+        }));
+        assert!(panicked.is_err());
+        assert!(!mh.ctc_status().unwrap());
+    }
+
+    #[test]
+    fn test_sparse_histograms() {
+        let dense = Histogram {
+            bins : vec![0, 0, 7, 0, 0, 0, 3, 0],
+            bin_width_ps : 25.0,
+            offset_ps : 100.0,
+            channel : 1,
+            acquired_for : Duration::from_millis(3),
+            device_serial : "1044272".to_string(),
+        };
+        let mut sparse = dense.to_sparse();
+        assert_eq!((sparse.len(), sparse.occupied()), (8, 2));
+        assert_eq!(sparse.max_bin(), Some((2, 7)));
+        assert_eq!(sparse.total_counts(), dense.total_counts());
+        assert_eq!(sparse.to_dense(), dense);
+
+        sparse.add(6, 4);
+        sparse.add(8, 1);
+        sparse.add(0, 0);
+        assert_eq!((sparse.get(6), sparse.occupied()), (7, 2));
+        let mut other = SparseHistogram::new(8, 25.0, 100.0, 3, "1044272");
+        other.add(4, u32::MAX);
+        other.add(4, 1);
+        assert_eq!(other.get(4), u32::MAX);
+
+        let mut csv = Vec::new();
+        write_sparse_histograms_csv(&[sparse, other], &mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert!(csv.contains("# bins = 8\nbin,time_ps,ch1,ch3\n2,150,7,0\n4,200,0,4294967295\n6,250,7,0\n"));
+        assert_eq!(csv.lines().count(), 5 + 3);
+    }
+
+    #[test]
+    fn test_sparse_histogrammer() {
+        use crate::tttr::pack_t3;
+
+        let path = std::env::temp_dir().join(format!("test_sparse_histogrammer_{}.csv", std::process::id()));
+        let mut histogrammer = SparseHistogrammer::new().with_output(&path);
+        let mut info = StreamInfo { enabled_channels : vec![true, false, true, true], ..stream_info(MeasurementMode::T2) };
+        assert!(histogrammer.init(&info).is_err());
+        info.mode = MeasurementMode::T3;
+        histogrammer.init(&info).unwrap();
+
+        let records = [
+            pack_t3(false, 0, 40, 1), pack_t3(false, 2, (1 << 15) - 1, 2),
+            pack_t3(false, 1, 40, 3), pack_t3(true, 1, 0, 4), pack_t3(false, 0, 40, 5),
+        ];
+        histogrammer.process_batch(&Batch { index : 0, read_at : Instant::now(), records : &records, mode : MeasurementMode::T3, sync_lost : false }).unwrap();
+        histogrammer.finalize().unwrap();
+
+        let histograms = histogrammer.histograms();
+        assert_eq!(histograms.iter().map(|h| h.channel).collect::<Vec<_>>(), vec![0, 2, 3]);
+        assert_eq!(histograms[0].get(40), 2);
+        assert_eq!(histograms[1].len(), 1 << 15);
+        assert_eq!(histograms[1].get((1 << 15) - 1), 1);
+        assert_eq!(histograms[2].total_counts(), 0);
+        assert_eq!(histogrammer.anomalies(), 1);
+
+        let csv = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(csv.contains("bin,time_ps,ch0,ch2,ch3\n40,200,2,0,0\n32767,163835,0,1,0\n"));
+    }
+
+    #[test]
+    fn test_find_stop_overflow() {
+        let histogram = |channel, bins| Histogram {
+            bins,
+            bin_width_ps : 5.0,
+            offset_ps : 0.0,
+            channel,
+            acquired_for : Duration::ZERO,
+            device_serial : String::new(),
+        };
+        let histograms = [
+            histogram(0, vec![0, 3, 1]),
+            histogram(1, vec![2, 0, 10, 10]),
+            histogram(2, vec![]),
+        ];
+        assert_eq!(histograms[1].max_bin(), Some((2, 10)));
+        assert_eq!(find_stop_overflow(&histograms, 11, 1.0), None);
+        assert_eq!(
+            find_stop_overflow(&histograms, 3, 12.5),
+            Some(StopOverflowReport { channel : 1, bin : 2, count : 10, bin_time_ps : 10.0, elapsed_ms : 12.5 })
+        );
+    }
+}
@@ -0,0 +1,409 @@
+//! Instrument response functions: a histogram of scattered excitation
+//! light, or of a sample with a lifetime far below the resolution, taken
+//! under the same timing settings as the measurements it will be used with.
+//! `capture_irf` acquires one, and an `IrfLibrary` keeps it on disk keyed
+//! by device, channel and the settings that move or shape the response, so
+//! analysis code finds the right IRF from a device's current settings
+//! without being told which file to read.
+
+use std::fmt::Display;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::error::PatinaError;
+use crate::histogram::{acquire_histogram, Histogram};
+use crate::metadata::Labels;
+use crate::multiharp::MultiHarpDevice;
+use crate::transaction::TransactionError;
+use crate::MultiHarpConfig;
+
+/// Peak height `capture_irf` requires unless told otherwise
+pub const DEFAULT_IRF_PEAK_COUNTS : u32 = 10_000;
+
+/// The settings that change where the response of `channel` falls in the
+/// histogram, or its shape: the sync trigger and offset, the binning and
+/// histogram offset, and the trigger and offset of `channel`. Two
+/// configs with the same timing settings share an IRF.
+pub fn timing_settings(config : &MultiHarpConfig, channel : i32) -> MultiHarpConfig {
+    MultiHarpConfig {
+        sync_trigger_edge : config.sync_trigger_edge,
+        sync_channel_offset : config.sync_channel_offset,
+        binning : config.binning,
+        offset : config.offset,
+        input_edges : config.input_edges.as_ref()
+            .map(|edges| edges.iter().copied().filter(|(ch, _, _)| *ch == channel).collect()),
+        input_offsets : config.input_offsets.as_ref()
+            .map(|offsets| offsets.iter().copied().filter(|(ch, _)| *ch == channel).collect()),
+        ..Default::default()
+    }
+}
+
+/// What `capture_irf` measures and how.
+///
+/// ## Example
+///
+/// ```
+/// use std::time::Duration;
+/// use multi_harp_patina::*;
+///
+/// let capture = IrfCapture::new(1)
+///     .with_config(MultiHarpConfig { binning : Some(0), offset : Some(0), ..Default::default() })
+///     .with_duration(Duration::from_secs(5))
+///     .with_label("excitation", "485 nm");
+/// assert_eq!(capture.channel(), 1);
+/// ```
+#[derive(Debug, Clone)]
+pub struct IrfCapture {
+    channel : i32,
+    config : Option<MultiHarpConfig>,
+    duration : Duration,
+    min_peak_counts : u32,
+    labels : Labels,
+}
+
+impl IrfCapture {
+    /// Captures the IRF of `channel` over one second with the device's
+    /// current settings, requiring a peak of `DEFAULT_IRF_PEAK_COUNTS`
+    pub fn new(channel : i32) -> Self {
+        IrfCapture {
+            channel,
+            config : None,
+            duration : Duration::from_secs(1),
+            min_peak_counts : DEFAULT_IRF_PEAK_COUNTS,
+            labels : Labels::new(),
+        }
+    }
+
+    pub fn channel(&self) -> i32 {
+        self.channel
+    }
+
+    /// Settings applied, as one `with_settings_transaction`, before measuring
+    pub fn with_config(mut self, config : MultiHarpConfig) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    /// Measurement time, rounded down to whole milliseconds
+    pub fn with_duration(mut self, duration : Duration) -> Self {
+        self.duration = duration;
+        self
+    }
+
+    /// Fewest counts in the fullest bin for the IRF to be kept
+    pub fn with_min_peak_counts(mut self, counts : u32) -> Self {
+        self.min_peak_counts = counts;
+        self
+    }
+
+    /// Stored with the IRF, e.g. the excitation wavelength or the
+    /// scatterer used
+    pub fn with_label<V : ToString>(mut self, key : &str, value : V) -> Self {
+        self.labels.insert(key, value);
+        self
+    }
+}
+
+/// The step of `capture_irf` that failed
+#[derive(Debug)]
+pub enum IrfError {
+    /// Applying the config failed; the settings it changed were rolled back
+    Configure(TransactionError),
+    Acquire(PatinaError<i32>),
+    /// The fullest bin had fewer counts than required
+    TooFewCounts { peak : u32, required : u32 },
+}
+
+impl Display for IrfError {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            IrfError::Configure(e) => write!(f, "Configuring the device failed: {}", e),
+            IrfError::Acquire(e) => write!(f, "Acquiring the IRF failed: {}", e),
+            IrfError::TooFewCounts { peak, required } =>
+                write!(f, "IRF peak has {} counts, {} required", peak, required),
+        }
+    }
+}
+
+impl std::error::Error for IrfError {}
+
+/// An instrument response function and the conditions it was taken under.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Irf {
+    pub histogram : Histogram,
+    /// The timing settings of the channel (see `timing_settings`)
+    pub settings : MultiHarpConfig,
+    pub captured_at : SystemTime,
+    pub labels : Labels,
+}
+
+impl Irf {
+    pub fn channel(&self) -> i32 {
+        self.histogram.channel
+    }
+
+    /// Time of the start of the fullest bin, in ps after the sync
+    pub fn peak_ps(&self) -> Option<f64> {
+        self.histogram.max_bin().map(|(bin, _)| self.histogram.bin_time(bin))
+    }
+
+    /// Mean arrival time, in ps after the sync, taking each count at the
+    /// start of its bin as `FlimAssembler` does. The offset to subtract
+    /// from mean arrival times to get a fast lifetime, see
+    /// `ScanConfig::with_irf`.
+    pub fn centroid_ps(&self) -> Option<f64> {
+        let total = self.histogram.total_counts();
+        if total == 0 {
+            return None;
+        }
+        let weighted : f64 = self.histogram.bins.iter().enumerate()
+            .map(|(bin, count)| *count as f64 * self.histogram.bin_time(bin))
+            .sum();
+        Some(weighted / total as f64)
+    }
+
+    /// Whether this IRF was taken with the same timing settings for its
+    /// channel as `config`
+    pub fn matches(&self, config : &MultiHarpConfig) -> bool {
+        timing_settings(config, self.channel()) == self.settings
+    }
+}
+
+/// Applies the settings of `capture`, measures the histogram of its
+/// channel and checks the peak is high enough to use. The device must be
+/// initialized in Histogramming mode, with the excitation light reaching
+/// the detector of that channel.
+///
+/// ## Example
+///
+/// ```
+/// use multi_harp_patina::*;
+///
+/// let mut mh = DebugMultiHarp150::default();
+/// mh.init(MeasurementMode::Histogramming, ReferenceClock::Internal).unwrap();
+/// let capture = IrfCapture::new(0).with_duration(std::time::Duration::from_millis(10));
+/// // Nothing reaches the debug device's detectors
+/// assert!(matches!(capture_irf(&mut mh, &capture), Err(IrfError::TooFewCounts { .. })));
+/// ```
+pub fn capture_irf<MH : MultiHarpDevice>(multiharp : &mut MH, capture : &IrfCapture) -> Result<Irf, IrfError> {
+    if let Some(config) = &capture.config {
+        multiharp.with_settings_transaction(|batch| *batch = config.clone())
+            .map_err(IrfError::Configure)?;
+    }
+    let acquisition_time = capture.duration.as_millis().min(i32::MAX as u128) as i32;
+    let histogram = acquire_histogram(multiharp, capture.channel, acquisition_time)
+        .map_err(IrfError::Acquire)?;
+
+    let peak = histogram.max_bin().map(|(_, count)| count).unwrap_or(0);
+    if peak < capture.min_peak_counts {
+        return Err(IrfError::TooFewCounts { peak, required : capture.min_peak_counts });
+    }
+    Ok(Irf {
+        histogram,
+        settings : timing_settings(&multiharp.current_settings(), capture.channel),
+        captured_at : SystemTime::now(),
+        labels : capture.labels.clone(),
+    })
+}
+
+/// IRFs stored in a directory, one file per device, channel and timing
+/// settings. Storing an IRF replaces any taken under the same conditions.
+///
+/// ## Example
+///
+/// ```
+/// use std::time::Duration;
+/// use multi_harp_patina::*;
+///
+/// let library = IrfLibrary::new(std::env::temp_dir().join("irf_doctest"));
+/// let mut mh = DebugMultiHarp150::default();
+/// mh.init(MeasurementMode::Histogramming, ReferenceClock::Internal).unwrap();
+/// let capture = IrfCapture::new(0).with_duration(Duration::from_millis(10)).with_min_peak_counts(0);
+/// let irf = capture_irf(&mut mh, &capture).unwrap();
+/// library.store(&irf).unwrap();
+///
+/// // Later, for the analysis of data taken with the same settings
+/// let irf = library.for_device(&mh, 0).unwrap().unwrap();
+/// let scan = ScanConfig::default().with_irf(&irf);
+/// ```
+pub struct IrfLibrary {
+    dir : PathBuf,
+}
+
+impl IrfLibrary {
+    /// A library stored in `dir`. The directory is created the first
+    /// time an IRF is stored.
+    pub fn new<P : AsRef<Path>>(dir : P) -> Self {
+        IrfLibrary { dir : dir.as_ref().to_path_buf() }
+    }
+
+    /// The `irf` directory next to the settings of `SettingsCache::default_location`
+    pub fn default_location() -> Option<Self> {
+        crate::settings::SettingsCache::default_location().map(|cache| IrfLibrary::new(cache.dir().join("irf")))
+    }
+
+    /// The file holding the IRF of `channel` on `serial` taken with `config`.
+    /// Leading zeros of the serial are trimmed, as in `SettingsCache`.
+    pub fn path_for(&self, serial : &str, channel : i32, config : &MultiHarpConfig) -> PathBuf {
+        let hash = fnv1a(timing_settings(config, channel).to_settings_string().as_bytes());
+        self.dir.join(format!("{}_ch{}_{:016x}.irf", serial.trim_start_matches('0'), channel, hash))
+    }
+
+    pub fn store(&self, irf : &Irf) -> io::Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        let path = self.path_for(&irf.histogram.device_serial, irf.channel(), &irf.settings);
+        let tmp = path.with_extension("irf.tmp");
+        std::fs::write(&tmp, to_irf_string(irf))?;
+        std::fs::rename(&tmp, &path)
+    }
+
+    /// The IRF of `channel` on `serial` taken with the timing settings of
+    /// `config`, or `None` if there is none.
+    pub fn lookup(&self, serial : &str, channel : i32, config : &MultiHarpConfig) -> io::Result<Option<Irf>> {
+        let path = self.path_for(serial, channel, config);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let irf = std::fs::read_to_string(&path)
+            .and_then(|contents| parse_irf(&contents)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e))))?;
+        // Guards against a hash collision
+        Ok(irf.matches(config).then_some(irf))
+    }
+
+    /// The IRF of `channel` matching the current settings of `multiharp`
+    pub fn for_device<MH : MultiHarpDevice>(&self, multiharp : &MH, channel : i32) -> io::Result<Option<Irf>> {
+        self.lookup(&multiharp.get_serial(), channel, &multiharp.current_settings())
+    }
+}
+
+/// 64-bit FNV-1a, which unlike `DefaultHasher` gives the same file names
+/// whatever the Rust version
+fn fnv1a(bytes : &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3))
+}
+
+/// The IRF as `# key = value` metadata lines, its timing settings in the
+/// format of `MultiHarpConfig::to_settings_string`, and its counts on
+/// one `bins = ` line
+fn to_irf_string(irf : &Irf) -> String {
+    let histogram = &irf.histogram;
+    let mut lines = vec![
+        format!("# serial = {}", histogram.device_serial),
+        format!("# channel = {}", histogram.channel),
+        format!("# bin_width_ps = {}", histogram.bin_width_ps),
+        format!("# offset_ps = {}", histogram.offset_ps),
+        format!("# acquired_for_ms = {}", histogram.acquired_for.as_millis()),
+        format!("# captured_at = {}", irf.captured_at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()),
+    ];
+    lines.extend(irf.labels.iter().map(|(key, value)| format!("# label.{} = {}", key, value)));
+    let settings = irf.settings.to_settings_string();
+    if !settings.is_empty() {
+        lines.push(settings);
+    }
+    lines.push(format!("bins = {}", histogram.bins.iter().map(|b| b.to_string()).collect::<Vec<_>>().join(",")));
+    lines.join("\n") + "\n"
+}
+
+fn parse_irf(s : &str) -> Result<Irf, String> {
+    let mut histogram = Histogram {
+        bins : Vec::new(),
+        bin_width_ps : 0.0,
+        offset_ps : 0.0,
+        channel : 0,
+        acquired_for : Duration::ZERO,
+        device_serial : String::new(),
+    };
+    let mut captured_at = UNIX_EPOCH;
+    let mut labels = Labels::new();
+    let mut settings = Vec::new();
+    let number = |value : &str| value.parse::<f64>().map_err(|_| format!("Invalid number `{}`", value));
+
+    for line in s.lines() {
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => continue,
+        };
+        match key.strip_prefix('#').map(str::trim) {
+            Some("serial") => histogram.device_serial = value.to_string(),
+            Some("channel") => histogram.channel = number(value)? as i32,
+            Some("bin_width_ps") => histogram.bin_width_ps = number(value)?,
+            Some("offset_ps") => histogram.offset_ps = number(value)?,
+            Some("acquired_for_ms") => histogram.acquired_for = Duration::from_millis(number(value)? as u64),
+            Some("captured_at") => captured_at = UNIX_EPOCH + Duration::from_secs(number(value)? as u64),
+            Some(key) => if let Some(label) = key.strip_prefix("label.") {
+                labels.insert(label, value);
+            },
+            None if key == "bins" => {
+                histogram.bins = value.split(',').filter(|b| !b.is_empty())
+                    .map(|b| b.trim().parse().map_err(|_| format!("Invalid count `{}`", b)))
+                    .collect::<Result<_, _>>()?;
+            },
+            None => settings.push(line),
+        }
+    }
+    let settings = MultiHarpConfig::from_settings_string(&settings.join("\n"))?;
+    Ok(Irf { histogram, settings, captured_at, labels })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DebugMultiHarp150, MeasurementMode, ReferenceClock, TriggerEdge};
+
+    #[test]
+    fn test_irf_library() {
+        let mut mh = DebugMultiHarp150::default();
+        mh.init(MeasurementMode::Histogramming, ReferenceClock::Internal).unwrap();
+        let capture = IrfCapture::new(2)
+            .with_config(MultiHarpConfig { binning : Some(1), offset : Some(100), ..Default::default() })
+            .with_duration(Duration::from_millis(10))
+            .with_min_peak_counts(0)
+            .with_label("scatterer", "ludox");
+        let mut irf = capture_irf(&mut mh, &capture).unwrap();
+        assert_eq!(irf.settings.binning, Some(1));
+        assert_eq!(irf.settings.input_edges.as_ref().unwrap().len(), 1);
+        assert_eq!(irf.centroid_ps(), None);
+        irf.histogram.bins[10] = 5;
+        irf.histogram.bins[11] = 15;
+        assert_eq!(irf.peak_ps(), irf.histogram.bin_time(11).into());
+        let expected = irf.histogram.bin_time(11) - irf.histogram.bin_width_ps / 4.0;
+        assert!((irf.centroid_ps().unwrap() - expected).abs() < 1e-9);
+
+        let dir = std::env::temp_dir().join("test_irf_library");
+        let _ = std::fs::remove_dir_all(&dir);
+        let library = IrfLibrary::new(&dir);
+        assert!(library.for_device(&mh, 2).unwrap().is_none());
+        library.store(&irf).unwrap();
+        let loaded = library.for_device(&mh, 2).unwrap().unwrap();
+        assert_eq!(loaded.histogram.bins, irf.histogram.bins);
+        assert_eq!(loaded.settings, irf.settings);
+        assert_eq!(loaded.labels.get("scatterer"), Some("ludox"));
+        assert_eq!(loaded.histogram.device_serial, mh.get_serial());
+
+        // Other channels' settings don't matter, but the channel's own do
+        mh.set_input_edge_trigger(0, -200, TriggerEdge::Rising).unwrap();
+        assert!(library.for_device(&mh, 2).unwrap().is_some());
+        mh.set_input_channel_offset(2, 50).unwrap();
+        assert!(library.for_device(&mh, 2).unwrap().is_none());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_capture_irf_errors() {
+        let mut mh = DebugMultiHarp150::default();
+        let capture = IrfCapture::new(0).with_duration(Duration::from_millis(10));
+        assert!(matches!(capture_irf(&mut mh, &capture), Err(IrfError::Acquire(PatinaError::WrongMode(..)))));
+
+        mh.init(MeasurementMode::Histogramming, ReferenceClock::Internal).unwrap();
+        let capture = capture.with_config(MultiHarpConfig { binning : Some(1000), ..Default::default() });
+        assert!(matches!(capture_irf(&mut mh, &capture), Err(IrfError::Configure(_))));
+        let capture = capture.with_config(MultiHarpConfig::default());
+        match capture_irf(&mut mh, &capture) {
+            Err(e @ IrfError::TooFewCounts { .. }) =>
+                assert_eq!(e.to_string(), format!("IRF peak has 0 counts, {} required", DEFAULT_IRF_PEAK_COUNTS)),
+            _ => panic!("the debug device has no counts"),
+        }
+    }
+}
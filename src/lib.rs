@@ -7,6 +7,9 @@
 //! the MultiHarp 150 device, as well as a `DebugMultiHarp150`
 //! for offline testing of functionality.
 //! 
+//! `use multi_harp_patina::prelude::*` imports the device traits, the
+//! common enums and error types and the acquisition helpers.
+//! 
 //! # Crate features
 
 //! ### MultiHarp library features
@@ -25,23 +28,196 @@
 //! `MHLib` library. This includes the `MH_SetSyncChannelEnable` function
 //! and the various Gating methods
 
+//! ### Other features
+
+//! * - proptest
+//!     Exports the `records` module of `proptest` strategies generating
+//!     valid and adversarial T2/T3 record streams, for testing code that
+//!     consumes the output of `read_fifo`.
+
+//! * - plugins
+//!     Allows a `Pipeline` to load `StreamProcessor`s from dylibs built
+//!     with `export_processor!`.
+
+//! * - hdf5
+//!     Exports `Hdf5Writer`, which streams decoded events into an HDF5
+//!     file. Needs the HDF5 C library.
+
+//! * - parquet
+//!     Exports `ParquetWriter` and `events_to_record_batch`, which write
+//!     decoded events as Arrow record batches to Parquet files.
+
+//! * - serde
+//!     Derives `Serialize` and `Deserialize` for `MultiHarpConfig`, so
+//!     `MultiHarpConfig::from_file` and `to_file` read and write TOML
+//!     and JSON as well as settings files.
+
 #[cfg(all(feature = "nolib", feature = "MHLib"))]
 compile_error!("features `nolib` and `MHLib` are mutually \
 exclusive. If you want to use the `nolib` feature, you must disable \
 default features `--no-default-features`.");
 
+pub mod acquisition;
+mod actor;
+mod advisor;
+mod alarms;
+mod audit;
+mod barrier;
+mod budget;
+mod builder;
+mod cache;
+mod channels;
+mod clock;
+mod compatibility;
+mod continuous;
+mod correlation;
+mod deadtime;
+mod enumeration;
 mod error;
+mod events;
+mod eventfilter;
+mod extfpga;
+mod filters;
+mod flim;
+mod gating;
+#[cfg(feature = "hdf5")]
+mod hdf5_export;
+mod histogram;
+mod irf;
+mod lifetime;
+mod limits;
+mod markers;
+mod metadata;
 mod mhlib;
 mod mhconsts;
+mod model;
+mod monitor;
 mod multiharp;
+mod notify;
+#[cfg(feature = "parquet")]
+mod parquet_sink;
+mod partition;
+mod pipeline;
+mod preflight;
+pub mod prelude;
+pub mod ptu;
+mod rates;
+mod record_filter;
+mod selftest;
+mod session;
+mod settings;
+mod spool;
 mod testing;
+mod transaction;
+mod trigger;
+mod tttr;
+mod typestate;
+mod validation;
+mod version;
+mod whiterabbit;
 
 pub use crate::mhconsts::*;
-pub use crate::multiharp::MultiHarpDevice;
+pub use crate::actor::{DeviceActor, DeviceHandle, Reply, ReplyFuture, NoReply};
+pub use crate::advisor::{
+    advise, Advisory, SettingChange, Warnings, ALL_WARNINGS, PileUpEstimate, PILE_UP_THRESHOLD,
+};
+pub use crate::alarms::{RateAlarm, RateSource, Threshold, AlarmAction, AlarmHook, AlarmTriggered};
+pub use crate::audit::{SettingApplied, set_settings_log, log_settings_to, clear_settings_log};
+pub use crate::barrier::{StartBarrier, StartTrigger, StartRecord, BarrierRun, start_time_from_dwords};
+pub use crate::budget::{MemoryBudget, BudgetAccount, BudgetPolicy, BudgetUsage, EVENT_RECORDS};
+pub use crate::builder::{DeviceBuilder, MultiHarpConfigBuilder, OpenError};
+pub use crate::cache::EventCache;
+pub use crate::channels::{ChannelMap, set_channel_map, channel_map, clear_channel_map, CHANNEL_LABEL_PREFIX};
+pub use crate::clock::{ClockStatus, ClockSample, ClockMapping, ClockMapper};
+pub use crate::compatibility::Incompatibility;
+pub use crate::continuous::{ContinuousAcquisition, ContinuousRun};
+pub use crate::correlation::{CrossCorrelation, cross_correlate, calibrate_offset, photon_times_ps};
+pub use crate::lifetime::{LifetimeMonitor, LifetimePoint};
+pub use crate::limits::Limits;
+pub use crate::model::{DeviceModel, ModelCapabilities, RowFilterChannels, ChannelMask, CHANNELS_PER_ROW};
+pub use crate::monitor::{SyncMonitor, SyncEvent, SyncLossInterval, FifoFillEstimator};
+pub use crate::multiharp::{MultiHarpDevice, MissingFeaturePolicy};
+#[cfg(feature = "async")]
+pub use crate::multiharp::AsyncMultiHarpDevice;
 #[cfg(feature = "MHLib")]
 pub use crate::multiharp::MultiHarp150;
-pub use crate::testing::debug_multiharp::DebugMultiHarp150;
+pub use crate::testing::debug_multiharp::{
+    DebugMultiHarp150, DEBUG_WR_LOCK_TIME, DEBUG_WR_MAC, DEBUG_FIFO_LATENCY, DEBUG_TRANSFER_BLOCK, DEBUG_HOLD_TIME
+};
+pub use crate::testing::conformance as multiharp_conformance;
+pub use crate::testing::soak::{SoakTest, SoakReport, SoakSample, LatencyHistogram, resident_memory};
+pub use crate::deadtime::{
+    Corrected, DeadTimes, coates_correction, correct_rate, INTRINSIC_DEAD_TIME,
+};
+pub use crate::enumeration::{DeviceProbe, probe_device, is_held};
 pub use crate::error::{PatinaError, MultiHarpError};
+pub use crate::events::{
+    DeviceEvent, DeviceEventKind, recent_events, clear_events, set_event_log_capacity,
+    DEFAULT_EVENT_CAPACITY,
+};
+pub use crate::eventfilter::{
+    MainEventFilter, FilterSuggestion, FilterVerification, suggest_event_filter, DEFAULT_THROUGHPUT_LIMIT,
+    FilterTuner, FilterTuning, FilterTrial, EventFilterStage,
+};
+pub use crate::extfpga::{ExtFpga, FpgaRegister, RegisterError, T2RawDecoder, T2RawSource, pack_t2raw, T2RAW_WORD_LEN, T2RAW_TIMETAG};
+pub use crate::filters::{TimeGate, FirstPhoton};
+pub use crate::flim::{FlimAssembler, FlimFrame, FrameSink, OmeTiffWriter, ScanConfig};
+pub use crate::gating::{GateSegmenter, GateSegment, SegmentFactory};
+#[cfg(feature = "hdf5")]
+pub use crate::hdf5_export::{Hdf5Writer, DEFAULT_HDF5_CHUNK, DEFAULT_HDF5_COMPRESSION};
+pub use crate::histogram::{
+    Histogram, HistogramAcquisition, HistogramProgress, StopOverflowReport, acquire_histogram,
+    acquire_histograms, find_stop_overflow, stop_overflow_report, write_histograms_csv,
+    SparseHistogram, SparseHistogrammer, write_sparse_histograms_csv,
+};
+pub use crate::irf::{
+    Irf, IrfCapture, IrfError, IrfLibrary, capture_irf, timing_settings, DEFAULT_IRF_PEAK_COUNTS,
+};
+pub use crate::record_filter::{RecordFilter, FilterStage, MAX_FILTER_CHANNEL};
+pub use crate::rates::{Rates, RateRecorder, RateSample, FlagChange, measure_rates, RATE_GATE};
+pub use crate::selftest::{SelfTest, SelfTestReport, SelfTestResult, SelfTestOutcome, DEFAULT_DARK_RATE_LIMIT};
+pub use crate::session::{SessionRecorder, ReplayError, replay, to_session_string, parse_session};
+pub use crate::settings::{SettingsCache, Settings, LogLevel};
+pub use crate::spool::{
+    SpoolReader, SpoolWriter, SpoolBlock, SegmentHeader,
+    SPOOL_MAGIC, SPOOL_VERSION, SEGMENT_TAG, RECORDS_TAG, TIMED_RECORDS_TAG, MAX_BLOCK_RECORDS
+};
+pub use crate::tttr::{
+    TttrEvent, EventKind, Encoder, Decoder, ChannelOffsets, OverflowCompression, T3Record, T2Record, pack_t2, pack_t3,
+    SyncDivider, DividedPulse, EventColumns,
+    OVERFLOW_CHANNEL, MAX_MARKER_CHANNEL, T2_WRAPAROUND, T3_WRAPAROUND,
+};
+pub use crate::transaction::TransactionError;
+pub use crate::typestate::{
+    MultiHarp, Mode, TttrMode, Histogramming, T2, T3, DefaultDevice, Opened, Measuring,
+    open_histogramming, open_t2, open_t3,
+};
+pub use crate::trigger::{TriggerSequence, TriggerSequencer, TriggerStep, Repeat};
+pub use crate::validation::{
+    ValidationPolicy, ArgumentClamped, set_validation_policy, validation_policy,
+    set_clamp_warnings, clear_clamp_warnings,
+};
+pub use crate::version::{
+    VersionMismatch, VersionComponent, VersionStrictness, check_versions,
+    set_version_strictness, version_strictness,
+    BUILT_FOR_LIBRARY_VERSION, MIN_HARDWARE_VERSION
+};
+pub use crate::markers::{MarkerCounter, MarkerConfig, MarkerTable, MarkerRow, marker_table_path};
+pub use crate::metadata::{Labels, ManifestWriter};
+pub use crate::whiterabbit::{WhiteRabbit, WrStatus, WrPtpState, WrServoState, WrTerminal, WR_POLL_INTERVAL, WR_TERM_POLL_INTERVAL};
+pub use crate::notify::{Notifier, Notification, NotificationHook, CommandHook, StatusFileHook, WebhookHook, WEBHOOK_TIMEOUT};
+#[cfg(feature = "parquet")]
+pub use crate::parquet_sink::{ParquetWriter, event_schema, events_to_record_batch, marker_schema, markers_to_record_batch, DEFAULT_PARQUET_BATCH};
+pub use crate::partition::{Partitioned, Partitioning, PartitionSink, PartitionFactory, Partition, partition_path};
+pub use crate::pipeline::{
+    StreamProcessor, Pipeline, PipelineError, Batch, StreamInfo,
+    ProcessorResult, PROCESSOR_API_VERSION
+};
+pub use crate::preflight::{
+    Preflight, DataRateEstimate, OutputFormat, free_space, DEFAULT_SPACE_MARGIN
+};
+#[cfg(any(test, feature = "proptest"))]
+pub use crate::testing::records;
 use crate::mhlib::*;
 use crate::error::mh_to_result;
 use std::ffi::*;
@@ -103,9 +279,16 @@ impl Iterator for MHDeviceIterator {
     type Item = (i32, String);
 
     /// Scans until it finds an available device or
-    /// exhausts the possible indices.
+    /// exhausts the possible indices. Devices this process
+    /// holds open are skipped rather than opened and closed.
     fn next(&mut self) -> Option<Self::Item> {
         if self.devidx < 8 {
+            let _enumeration = enumeration::enumeration_lock();
+            if enumeration::is_held(self.devidx) {
+                self.devidx += 1;
+                drop(_enumeration);
+                return self.next();
+            }
             let mut serial = [0 as c_char; 8];
             #[cfg(feature = "MHLib")]
             let mh_result = unsafe{ MH_OpenDevice(self.devidx, serial.as_mut_ptr()) };
@@ -115,6 +298,7 @@ impl Iterator for MHDeviceIterator {
                 // Keep going until you either run out
                 // of devices or find one that opens.
                 self.devidx += 1;
+                drop(_enumeration);
 
                 return self.next();
             }
@@ -141,6 +325,12 @@ impl Iterator for MHDeviceIterator {
 /// to set many parameters in one function call
 /// 
 /// Any parameters set to `None` will not be set
+///
+/// With the `serde` feature it is `Serialize` and `Deserialize`, with
+/// every field optional, and can be kept in TOML or JSON files (see
+/// `from_file`).
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize), serde(default))]
 pub struct MultiHarpConfig {
     pub sync_div : Option<i32>,
     pub sync_trigger_edge : Option<(i32, TriggerEdge)>,
@@ -165,6 +355,9 @@ pub struct MultiHarpConfig {
     pub offset : Option<i32>,
     pub histo_len : Option<i32>,
 
+    /// (mode, start edge, stop edge), a table of `mode`, `start_edge` and
+    /// `stop_edge` when serialized
+    #[cfg_attr(feature = "serde", serde(with = "crate::settings::meas_control_serde"))]
     pub meas_control : Option<(MeasurementControlMode, Option<TriggerEdge>, Option<TriggerEdge>)>,
     pub trigger_output : Option<i32>,
 
@@ -212,6 +405,53 @@ impl Default for MultiHarpConfig {
     }
 }
 
+impl MultiHarpConfig {
+    /// Builds a config one setting at a time, checking the values at
+    /// `build`
+    pub fn builder() -> MultiHarpConfigBuilder {
+        MultiHarpConfigBuilder::new()
+    }
+
+    /// The settings of this configuration that need a feature missing
+    /// from the bitmask `features`, as returned by `get_features`, each
+    /// with the feature it needs.
+    pub fn missing_features(&self, features : i32) -> Vec<(&'static str, FeatureMasks)> {
+        let needs = [
+            ("sync_dead_time", self.sync_dead_time.is_some(), FeatureMasks::ProgTd),
+            ("input_dead_times", self.input_dead_times.is_some(), FeatureMasks::ProgTd),
+            #[cfg(feature = "MHLv3_0_0")]
+            ("input_hysteresis", self.input_hysteresis.is_some(), FeatureMasks::ProgHyst),
+            ("trigger_output", self.trigger_output.is_some(), FeatureMasks::TrigOut),
+            ("marker_edges", self.marker_edges.is_some(), FeatureMasks::Markers),
+            ("marker_enable", self.marker_enable.is_some(), FeatureMasks::Markers),
+            ("marker_holdoff", self.marker_holdoff.is_some(), FeatureMasks::Markers),
+        ];
+        needs.into_iter()
+            .filter(|(_, set, feature)| *set && (features & *feature as i32) == 0)
+            .map(|(setting, _, feature)| (setting, feature))
+            .collect()
+    }
+
+    /// This configuration without the settings `missing_features` lists
+    pub fn supported_by(&self, features : i32) -> MultiHarpConfig {
+        let mut config = self.clone();
+        for (setting, _) in self.missing_features(features) {
+            match setting {
+                "sync_dead_time" => config.sync_dead_time = None,
+                "input_dead_times" => config.input_dead_times = None,
+                #[cfg(feature = "MHLv3_0_0")]
+                "input_hysteresis" => config.input_hysteresis = None,
+                "trigger_output" => config.trigger_output = None,
+                "marker_edges" => config.marker_edges = None,
+                "marker_enable" => config.marker_enable = None,
+                "marker_holdoff" => config.marker_holdoff = None,
+                _ => unreachable!("`missing_features` lists only the settings above"),
+            }
+        }
+        config
+    }
+}
+
 /// Scans all possible device numbers and returns a list of
 /// available MultiHarp devices by index and serial number.
 /// 
@@ -0,0 +1,212 @@
+//! Fast lifetimes over the course of an acquisition, one per channel per
+//! interval, to watch for drift (a detector warming up, a sample
+//! bleaching) during long experiments without storing the photons.
+
+use std::time::{Duration, Instant};
+
+use crate::histogram::SparseHistogrammer;
+use crate::irf::Irf;
+use crate::pipeline::{Batch, ProcessorResult, StreamInfo, StreamProcessor};
+
+/// The fast lifetime of one channel over one interval
+#[derive(Debug, Clone, PartialEq)]
+pub struct LifetimePoint {
+    pub channel : i32,
+    /// End of the interval, from the first batch of the acquisition
+    pub elapsed : Duration,
+    /// Photons in the interval
+    pub photons : u64,
+    /// Mean arrival time less the offset, in ns. `None` without photons.
+    pub lifetime_ns : Option<f64>,
+}
+
+type PointCallback = Box<dyn FnMut(&LifetimePoint) + Send>;
+
+/// Histograms the arrival times of each channel with a
+/// `SparseHistogrammer` and, every interval, turns the histograms into
+/// fast lifetimes and starts them over. T3 only.
+///
+/// ## Example
+///
+/// ```
+/// use std::time::{Duration, Instant};
+/// use multi_harp_patina::*;
+///
+/// let info = StreamInfo {
+///     serial : "1044272".to_string(),
+///     mode : MeasurementMode::T3,
+///     resolution : 100.0,
+///     num_channels : 1,
+///     enabled_channels : vec![true],
+///     overflow_compression : OverflowCompression::Compressed,
+///     labels : Labels::default(),
+/// };
+/// let mut monitor = LifetimeMonitor::new(Duration::from_secs(1)).with_offset_ps(200.0);
+/// monitor.init(&info).unwrap();
+/// let start = Instant::now();
+/// let records = [pack_t3(false, 0, 20, 0), pack_t3(false, 0, 40, 1)];
+/// for (index, read_at) in [start, start + Duration::from_millis(1100)].into_iter().enumerate() {
+///     let batch = Batch { index : index as u64, read_at, records : &records, mode : MeasurementMode::T3, sync_lost : false };
+///     monitor.process_batch(&batch).unwrap();
+/// }
+/// let points = monitor.take_points();
+/// assert_eq!(points.len(), 1);
+/// assert_eq!(points[0].photons, 4);
+/// assert_eq!(points[0].lifetime_ns, Some(2.8));
+/// ```
+pub struct LifetimeMonitor {
+    interval : Duration,
+    offset_ps : f64,
+    histogrammer : SparseHistogrammer,
+    first_batch : Option<Instant>,
+    interval_start : Option<Instant>,
+    last_batch : Option<Instant>,
+    points : Vec<LifetimePoint>,
+    callback : Option<PointCallback>,
+}
+
+impl LifetimeMonitor {
+    /// A monitor giving one lifetime per channel every `interval`
+    pub fn new(interval : Duration) -> Self {
+        LifetimeMonitor {
+            interval,
+            offset_ps : 0.0,
+            histogrammer : SparseHistogrammer::new(),
+            first_batch : None,
+            interval_start : None,
+            last_batch : None,
+            points : Vec::new(),
+            callback : None,
+        }
+    }
+
+    /// Subtracted from the mean arrival time, usually the instrument
+    /// response, as `ScanConfig::lifetime_offset_ps`
+    pub fn with_offset_ps(mut self, offset_ps : f64) -> Self {
+        self.offset_ps = offset_ps;
+        self
+    }
+
+    /// Measures lifetimes from the centroid of `irf`, see `Irf::centroid_ps`
+    pub fn with_irf(self, irf : &Irf) -> Self {
+        match irf.centroid_ps() {
+            Some(centroid) => self.with_offset_ps(centroid),
+            None => self,
+        }
+    }
+
+    /// Called with each point as its interval ends, on the pipeline's thread
+    pub fn with_callback<F : FnMut(&LifetimePoint) + Send + 'static>(mut self, callback : F) -> Self {
+        self.callback = Some(Box::new(callback));
+        self
+    }
+
+    /// The points since the last call, in time order and by channel within
+    /// an interval. Taking them keeps a long acquisition from growing the
+    /// series without bound.
+    pub fn take_points(&mut self) -> Vec<LifetimePoint> {
+        std::mem::take(&mut self.points)
+    }
+
+    /// Ends the current interval at `end`
+    fn emit(&mut self, end : Instant) {
+        let elapsed = self.first_batch.map(|first| end.duration_since(first)).unwrap_or_default();
+        for histogram in self.histogrammer.histograms() {
+            let photons : u64 = histogram.counts.values().map(|c| *c as u64).sum();
+            let lifetime_ns = (photons > 0).then(|| {
+                let weighted : f64 = histogram.counts.iter().map(|(bin, c)| histogram.bin_time(*bin) * *c as f64).sum();
+                (weighted / photons as f64 - self.offset_ps) / 1000.0
+            });
+            let point = LifetimePoint { channel : histogram.channel, elapsed, photons, lifetime_ns };
+            if let Some(callback) = self.callback.as_mut() {
+                callback(&point);
+            }
+            self.points.push(point);
+        }
+        self.histogrammer.reset();
+        self.interval_start = Some(end);
+    }
+}
+
+impl StreamProcessor for LifetimeMonitor {
+    fn name(&self) -> &str { "lifetime_monitor" }
+
+    fn init(&mut self, info : &StreamInfo) -> ProcessorResult {
+        self.histogrammer.init(info)?;
+        self.first_batch = None;
+        self.interval_start = None;
+        self.last_batch = None;
+        Ok(())
+    }
+
+    fn process_batch(&mut self, batch : &Batch) -> ProcessorResult {
+        self.first_batch.get_or_insert(batch.read_at);
+        let interval_start = *self.interval_start.get_or_insert(batch.read_at);
+        self.histogrammer.process_batch(batch)?;
+        self.last_batch = Some(batch.read_at);
+        if batch.read_at.duration_since(interval_start) >= self.interval {
+            self.emit(batch.read_at);
+        }
+        Ok(())
+    }
+
+    /// Ends the last, partial interval
+    fn finalize(&mut self) -> ProcessorResult {
+        if let (Some(start), Some(last)) = (self.interval_start, self.last_batch) {
+            if last > start {
+                self.emit(last);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mhconsts::MeasurementMode;
+    use crate::tttr::pack_t3;
+    use std::sync::{Arc, Mutex};
+    use crate::testing::fixtures::stream_info;
+
+    #[test]
+    fn test_lifetime_monitor() {
+        let info = StreamInfo {
+            resolution : 10.0,
+            num_channels : 3,
+            enabled_channels : vec![true, false, true],
+            ..stream_info(MeasurementMode::T3)
+        };
+        let seen = Arc::new(Mutex::new(0));
+        let counter = Arc::clone(&seen);
+        let mut monitor = LifetimeMonitor::new(Duration::from_millis(500))
+            .with_callback(move |_| *counter.lock().unwrap() += 1);
+        monitor.init(&info).unwrap();
+
+        let start = Instant::now();
+        let batches : [(u64, &[u32]); 4] = [
+            (0, &[pack_t3(false, 0, 100, 0)]),
+            (300, &[pack_t3(false, 0, 300, 1)]),
+            (600, &[pack_t3(false, 2, 50, 2)]),
+            (700, &[pack_t3(false, 0, 500, 3)]),
+        ];
+        for (index, (ms, records)) in batches.iter().enumerate() {
+            let read_at = start + Duration::from_millis(*ms);
+            monitor.process_batch(&Batch { index : index as u64, read_at, records, mode : MeasurementMode::T3, sync_lost : false }).unwrap();
+        }
+        monitor.finalize().unwrap();
+
+        let points = monitor.take_points();
+        assert_eq!(points.len(), 4);
+        assert_eq!(*seen.lock().unwrap(), 4);
+        // First interval ends at the batch read 600 ms in
+        assert_eq!(points[0], LifetimePoint { channel : 0, elapsed : Duration::from_millis(600), photons : 2, lifetime_ns : Some(2.0) });
+        assert_eq!(points[1].channel, 2);
+        assert_eq!(points[1].lifetime_ns, Some(0.5));
+        // The last, partial interval is ended by `finalize`
+        assert_eq!(points[2].elapsed, Duration::from_millis(700));
+        assert_eq!(points[2].lifetime_ns, Some(5.0));
+        assert_eq!(points[3], LifetimePoint { channel : 2, elapsed : Duration::from_millis(700), photons : 0, lifetime_ns : None });
+        assert!(monitor.take_points().is_empty());
+    }
+}
@@ -0,0 +1,171 @@
+//! The ranges accepted by the device's settings and the layout of its
+//! records, collected in one place for the mode, model and library
+//! version in use.
+
+use std::fmt::{Debug, Display};
+use std::ops::RangeInclusive;
+
+use crate::error::{CheckedResult, PatinaError};
+use crate::mhconsts::{self, MeasurementMode};
+use crate::model::DeviceModel;
+use crate::tttr::{T2_WRAPAROUND, T3_WRAPAROUND};
+use crate::validation;
+
+/// Setting ranges and record layout for one measurement mode, as
+/// returned by `MultiHarpDevice::limits`. Settings that the library
+/// version this crate was built for doesn't support are `None`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Limits {
+    pub mode : MeasurementMode,
+    /// Number of input channels of the model (`MAXINPCHAN` if unknown)
+    pub max_channels : i32,
+    /// Finest bin width of the model in picoseconds, if known
+    pub base_resolution : Option<f64>,
+    /// Bins in a histogram: `MAXHISTLEN` in Histogramming mode, the
+    /// number of `dtime` values in T3, 0 in T2
+    pub max_histogram_len : usize,
+    /// Bits of the arrival time in a record: `dtime` in T3, the timetag in T2
+    pub time_bits : u32,
+    /// Bits of the sync counter in a T3 record, 0 in T2
+    pub nsync_bits : u32,
+    /// Rollover of `nsync` (T3) or the timetag (T2). 0 in Histogramming mode.
+    pub wraparound : u64,
+    /// Records returned by one `read_fifo`
+    pub fifo_read_len : usize,
+
+    pub sync_div : RangeInclusive<i32>,
+    /// Binning codes, each doubling the bin width
+    pub binning : RangeInclusive<i32>,
+    /// Histogram length codes (Histogramming mode)
+    pub length_code : RangeInclusive<i32>,
+    /// mV
+    pub trigger_level : RangeInclusive<i32>,
+    /// ps
+    pub channel_offset : RangeInclusive<i32>,
+    /// ps
+    pub dead_time : RangeInclusive<i32>,
+    /// ns
+    pub offset : RangeInclusive<i32>,
+    /// ms
+    pub acquisition_time : RangeInclusive<i32>,
+    pub stop_count : RangeInclusive<u32>,
+    /// Units of 100 ns, 0 is off
+    pub trigger_output : RangeInclusive<i32>,
+    /// ns, `MHLv3_0_0` and later
+    pub holdoff : Option<RangeInclusive<i32>>,
+    /// Hysteresis codes, `MHLv3_0_0` and later
+    pub hysteresis : Option<RangeInclusive<i32>>,
+    /// Event filter rows
+    pub filter_row : RangeInclusive<i32>,
+}
+
+impl Limits {
+    /// The limits for `mode` on `model`. Model dependent limits fall back
+    /// to the most permissive value when `model` is `None` or `Unknown`.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// use multi_harp_patina::*;
+    ///
+    /// let limits = Limits::new(MeasurementMode::T3, Some(&DeviceModel::MultiHarp150_8N));
+    /// assert_eq!(limits.max_channels, 8);
+    /// assert_eq!(limits.max_histogram_len, 1 << 15);
+    /// assert!(limits.check("sync_div", 32, &limits.sync_div).is_err());
+    /// ```
+    pub fn new(mode : MeasurementMode, model : Option<&DeviceModel>) -> Self {
+        let capabilities = model.and_then(|m| m.capabilities());
+        let (max_histogram_len, time_bits, nsync_bits, wraparound) = match mode {
+            MeasurementMode::Histogramming => (mhconsts::MAXHISTLEN, 0, 0, 0),
+            MeasurementMode::T2 => (0, mhconsts::HISTOTAG_T2.count_ones(), 0, T2_WRAPAROUND),
+            MeasurementMode::T3 => (
+                1 << mhconsts::HISTOTAG_T3.count_ones(),
+                mhconsts::HISTOTAG_T3.count_ones(),
+                mhconsts::SYNCTAG.count_ones(),
+                T3_WRAPAROUND,
+            ),
+        };
+
+        Limits {
+            mode,
+            max_channels : capabilities.map(|c| c.max_channels).unwrap_or(mhconsts::MAXINPCHAN),
+            base_resolution : capabilities.map(|c| c.base_resolution),
+            max_histogram_len,
+            time_bits,
+            nsync_bits,
+            wraparound,
+            fifo_read_len : mhconsts::TTREADMAX,
+
+            sync_div : mhconsts::SYNCDIVMIN..=mhconsts::SYNCDIVMAX,
+            binning : 0..=mhconsts::BINSTEPSMAX,
+            length_code : mhconsts::MINLENCODE..=mhconsts::MAXLENCODE,
+            trigger_level : mhconsts::TRGLVLMIN..=mhconsts::TRGLVLMAX,
+            channel_offset : mhconsts::CHANNEL_OFFS_MIN..=mhconsts::CHANNEL_OFFS_MAX,
+            dead_time : mhconsts::EXTDEADMIN..=mhconsts::EXTDEADMAX,
+            offset : mhconsts::OFFSETMIN..=mhconsts::OFFSETMAX,
+            acquisition_time : mhconsts::ACQTMIN..=mhconsts::ACQTMAX,
+            stop_count : mhconsts::STOPCNTMIN..=mhconsts::STOPCNTMAX,
+            trigger_output : mhconsts::TRIGOUTMIN..=mhconsts::TRIGOUTMAX,
+            holdoff : if cfg!(feature = "MHLv3_0_0") { Some(mhconsts::HOLDOFFMIN..=mhconsts::HOLDOFFMAX) } else { None },
+            hysteresis : if cfg!(feature = "MHLv3_0_0") { Some(mhconsts::HYSTCODEMIN..=mhconsts::HYSTCODEMAX) } else { None },
+            filter_row : mhconsts::ROWIDXMIN..=mhconsts::ROWIDXMAX,
+        }
+    }
+
+    /// Checks `value` against one of the ranges in `self`, with the
+    /// `ArgumentError` the device methods return.
+    pub fn check<T : PartialOrd + Display + Debug + Copy>(
+        &self,
+        name : &str,
+        value : T,
+        range : &RangeInclusive<T>,
+    ) -> CheckedResult<(), T> {
+        if !range.contains(&value) {
+            return Err(PatinaError::ArgumentError(
+                name.to_string(),
+                value,
+                format!("{} must be between {} and {}", name, range.start(), range.end()))
+            );
+        }
+        Ok(())
+    }
+
+    /// `check` under the current `ValidationPolicy`, for a setting of the
+    /// device `serial`: returns the value to apply.
+    pub(crate) fn validate<T>(
+        &self,
+        serial : &str,
+        name : &str,
+        value : T,
+        range : &RangeInclusive<T>,
+    ) -> CheckedResult<T, T> where T : PartialOrd + Display + Debug + Copy + Into<i64> {
+        validation::validate(serial, name, value, range.clone(), name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limits_by_mode() {
+        let t2 = Limits::new(MeasurementMode::T2, None);
+        assert_eq!(t2.time_bits, 25);
+        assert_eq!(t2.wraparound, 1 << 25);
+        assert_eq!(t2.max_channels, mhconsts::MAXINPCHAN);
+        assert_eq!(t2.base_resolution, None);
+
+        let t3 = Limits::new(MeasurementMode::T3, Some(&DeviceModel::MultiHarp150_4P));
+        assert_eq!((t3.time_bits, t3.nsync_bits), (15, 10));
+        assert_eq!(t3.wraparound, 1 << t3.nsync_bits);
+        assert_eq!(t3.base_resolution, Some(5.0));
+
+        let histo = Limits::new(MeasurementMode::Histogramming, None);
+        assert_eq!(histo.max_histogram_len, 1024 << mhconsts::MAXLENCODE);
+        assert!(histo.check("offset", 0, &histo.offset).is_ok());
+        assert_eq!(
+            histo.check("offset", -1, &histo.offset),
+            Err(PatinaError::ArgumentError("offset".to_string(), -1, "offset must be between 0 and 100000000".to_string()))
+        );
+    }
+}
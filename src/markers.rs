@@ -0,0 +1,533 @@
+//! Treats a marker input as a general purpose event counter (stimulus
+//! pulses, camera exposures, ...), timestamping each event on the same
+//! clock as the photons so behavior and stimuli can be aligned without
+//! a second DAQ. `MarkerConfig` sets up the marker inputs themselves.
+//!
+//! `MarkerTable` keeps every marker event of an acquisition with its
+//! device time and, given a `ClockMapping`, its host wall clock time.
+//! The PTU, HDF5 and Parquet sinks each keep one and write it next to
+//! the photons, since the markers are often all a collaborator needs.
+
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::mhconsts::{MeasurementMode, TriggerEdge};
+use crate::MultiHarpConfig;
+use crate::metadata::Labels;
+use crate::multiharp::{photon_special, photon_to_channel, photon_to_arrival_t2, photon_to_sync_counter};
+use crate::pipeline::{Batch, ProcessorResult, StreamInfo, StreamProcessor};
+use crate::tttr::{Decoder, EventColumns, EventKind, OverflowCompression, OVERFLOW_CHANNEL, T2_WRAPAROUND, T3_WRAPAROUND};
+use crate::clock::ClockMapping;
+
+/// Every setting of the four marker inputs, applied together by
+/// `MultiHarpDevice::set_marker_config` so that the inputs are never left
+/// with, e.g., new enables but old edges. Index 0 is marker 1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MarkerConfig {
+    pub enables : [bool; 4],
+    pub edges : [TriggerEdge; 4],
+    /// Minimum time between markers in ns, see `set_marker_holdoff_time`
+    pub holdoff_ns : i32,
+}
+
+impl Default for MarkerConfig {
+    /// Every marker disabled, on rising edges, without holdoff
+    fn default() -> Self {
+        MarkerConfig { enables : [false; 4], edges : [TriggerEdge::Rising; 4], holdoff_ns : 0 }
+    }
+}
+
+impl MarkerConfig {
+    /// Only `marker` (1 to 4) enabled, on `edge`
+    pub fn single(marker : u8, edge : TriggerEdge) -> Result<Self, String> {
+        if !(1..=4).contains(&marker) {
+            return Err(format!("Marker must be between 1 and 4, got {}", marker));
+        }
+        let mut config = MarkerConfig::default();
+        config.enables[marker as usize - 1] = true;
+        config.edges[marker as usize - 1] = edge;
+        Ok(config)
+    }
+
+    /// The marker settings of `config`, if it has all three.
+    pub fn from_config(config : &MultiHarpConfig) -> Option<Self> {
+        Some(MarkerConfig {
+            enables : config.marker_enable?,
+            edges : config.marker_edges?,
+            holdoff_ns : config.marker_holdoff?,
+        })
+    }
+
+    /// Sets the marker settings of `config`.
+    pub fn apply_to(&self, config : &mut MultiHarpConfig) {
+        config.marker_edges = Some(self.edges);
+        config.marker_enable = Some(self.enables);
+        config.marker_holdoff = Some(self.holdoff_ns);
+    }
+}
+
+/// Counts events on one marker input (1 to 4) and records when each
+/// occurred. Timestamps are in syncs since the start of the acquisition
+/// in T3 mode, or in units of the resolution in T2 mode.
+///
+/// ## Example
+///
+/// ```no_run
+/// use multi_harp_patina::*;
+///
+/// let mut mh = DebugMultiHarp150::default();
+/// let mut pipeline = Pipeline::new();
+/// pipeline.add(MarkerCounter::new(1).unwrap());
+/// pipeline.run(&mut mh, 1000).unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct MarkerCounter {
+    marker : u8,
+    mode : MeasurementMode,
+    compression : OverflowCompression,
+    overflow_base : u64,
+    timestamps : Vec<u64>,
+    labels : Labels,
+    output : Option<std::path::PathBuf>,
+}
+
+impl MarkerCounter {
+    /// Counts events on `marker` (1 to 4).
+    pub fn new(marker : u8) -> Result<Self, String> {
+        if !(1..=4).contains(&marker) {
+            return Err(format!("Marker must be between 1 and 4, got {}", marker));
+        }
+        Ok(MarkerCounter {
+            marker,
+            mode : MeasurementMode::T3,
+            compression : OverflowCompression::Compressed,
+            overflow_base : 0,
+            timestamps : Vec::new(),
+            labels : Labels::new(),
+            output : None,
+        })
+    }
+
+    /// Writes the timestamps as CSV to `path` when the acquisition finishes,
+    /// so they're stored next to the photon data.
+    pub fn with_output<P : AsRef<std::path::Path>>(mut self, path : P) -> Self {
+        self.output = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// The marker input being counted
+    pub fn marker(&self) -> u8 {
+        self.marker
+    }
+
+    /// Number of events seen so far
+    pub fn count(&self) -> usize {
+        self.timestamps.len()
+    }
+
+    /// Time of each event, in syncs (T3) or resolution units (T2)
+    /// since the start of the acquisition.
+    pub fn timestamps(&self) -> &[u64] {
+        &self.timestamps
+    }
+
+    /// Writes one `event,timestamp` line per event, with a header. The
+    /// acquisition's labels come first, as `# key = value` comment lines.
+    pub fn write_csv<W : Write>(&self, mut writer : W) -> io::Result<()> {
+        let unit = match self.mode {
+            MeasurementMode::T2 => "timetag",
+            _ => "nsync",
+        };
+        for (key, value) in self.labels.iter() {
+            writeln!(writer, "# {} = {}", key, value)?;
+        }
+        writeln!(writer, "event,{}", unit)?;
+        for (i, t) in self.timestamps.iter().enumerate() {
+            writeln!(writer, "{},{}", i, t)?;
+        }
+        Ok(())
+    }
+
+    /// Overflows are counted even when `skip_markers` is set, so that
+    /// later timestamps stay correct.
+    fn handle_record(&mut self, record : u32, skip_markers : bool) {
+        if !photon_special(record) {
+            return;
+        }
+        let channel = photon_to_channel(record);
+        let (wraparound, low) = match self.mode {
+            MeasurementMode::T2 => (T2_WRAPAROUND, photon_to_arrival_t2(record) as u64),
+            _ => (T3_WRAPAROUND, photon_to_sync_counter(record) as u64),
+        };
+
+        if channel == OVERFLOW_CHANNEL {
+            self.overflow_base += self.compression.rollovers(low) * wraparound;
+        } else if !skip_markers && channel <= 15 && channel & (1 << (self.marker - 1)) != 0 {
+            self.timestamps.push(self.overflow_base + low);
+        }
+    }
+}
+
+impl StreamProcessor for MarkerCounter {
+    fn name(&self) -> &str {
+        "marker_counter"
+    }
+
+    fn init(&mut self, info : &StreamInfo) -> ProcessorResult {
+        self.mode = info.mode;
+        self.compression = info.overflow_compression;
+        self.labels = info.labels.clone();
+        self.overflow_base = 0;
+        self.timestamps.clear();
+        Ok(())
+    }
+
+    fn process_batch(&mut self, batch : &Batch) -> ProcessorResult {
+        // T3 timestamps count syncs, so they're meaningless while it's lost
+        let skip_markers = batch.sync_lost && batch.mode == MeasurementMode::T3;
+        batch.records.iter().for_each(|r| self.handle_record(*r, skip_markers));
+        Ok(())
+    }
+
+    fn finalize(&mut self) -> ProcessorResult {
+        if let Some(path) = &self.output {
+            let file = std::fs::File::create(path)
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+            self.write_csv(io::BufWriter::new(file))
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+        }
+        Ok(())
+    }
+}
+
+/// One marker event of a `MarkerTable`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MarkerRow {
+    /// The markers that fired, bit 0 for marker 1
+    pub markers : u8,
+    /// Syncs (T3) or resolution units (T2) since the start of the acquisition
+    pub time : u64,
+    /// Picoseconds since the start of the acquisition
+    pub time_ps : u64,
+    /// Host wall clock time, once a `ClockMapping` is set
+    pub host_time : Option<SystemTime>,
+}
+
+/// The path of the marker table written next to the file at `path`:
+/// `run.ptu` becomes `run.markers.<extension>`.
+pub fn marker_table_path<P : AsRef<Path>>(path : P, extension : &str) -> PathBuf {
+    path.as_ref().with_extension(format!("markers.{}", extension))
+}
+
+/// Every marker event of an acquisition, with its device time and, once
+/// `set_clock_mapping` is called (e.g. with the mapping returned by
+/// `ClockMapper::stop`), its host wall clock time. Markers can be added
+/// from decoded events, or from raw records after `begin`.
+///
+/// As a `StreamProcessor`, with `with_output`, it writes the table as
+/// CSV when the acquisition finishes.
+///
+/// ## Example
+///
+/// ```
+/// use std::time::Instant;
+/// use multi_harp_patina::*;
+///
+/// let info = StreamInfo {
+///     serial : "1044272".to_string(),
+///     mode : MeasurementMode::T3,
+///     resolution : 5.0,
+///     num_channels : 4,
+///     enabled_channels : vec![true; 4],
+///     overflow_compression : OverflowCompression::Compressed,
+///     labels : Labels::default(),
+/// };
+/// let mut table = MarkerTable::new().with_sync_period(12_500.0);
+/// table.init(&info).unwrap();
+/// let records = [pack_t3(false, 0, 100, 1), pack_t3(true, 2, 0, 4)];
+/// table.process_batch(&Batch { index : 0, read_at : Instant::now(), records : &records, mode : MeasurementMode::T3, sync_lost : false }).unwrap();
+/// assert_eq!(table.rows()[0].time_ps, 50_000);
+/// ```
+#[derive(Debug, Clone)]
+pub struct MarkerTable {
+    mode : MeasurementMode,
+    resolution : f64,
+    sync_period : Option<f64>,
+    decoder : Option<Decoder>,
+    markers : Vec<u8>,
+    times : Vec<u64>,
+    mapping : Option<ClockMapping>,
+    labels : Labels,
+    output : Option<PathBuf>,
+}
+
+impl Default for MarkerTable {
+    fn default() -> Self {
+        MarkerTable::new()
+    }
+}
+
+impl MarkerTable {
+    pub fn new() -> Self {
+        MarkerTable {
+            mode : MeasurementMode::T3,
+            resolution : 0.0,
+            sync_period : None,
+            decoder : None,
+            markers : Vec::new(),
+            times : Vec::new(),
+            mapping : None,
+            labels : Labels::new(),
+            output : None,
+        }
+    }
+
+    /// The sync period in ps, needed to give T3 markers a `time_ps`
+    pub fn with_sync_period(mut self, period : f64) -> Self {
+        self.sync_period = Some(period);
+        self
+    }
+
+    /// Writes the table as CSV to `path` when the acquisition finishes
+    pub fn with_output<P : AsRef<Path>>(mut self, path : P) -> Self {
+        self.output = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Empties the table for the acquisition described by `info`.
+    ///
+    /// ## Errors
+    ///
+    /// If a T3 stream has no sync period.
+    pub fn begin(&mut self, info : &StreamInfo) -> Result<(), String> {
+        if info.mode == MeasurementMode::T3 && self.sync_period.is_none() {
+            return Err("T3 streams need the sync period, see `with_sync_period`".to_string());
+        }
+        self.mode = info.mode;
+        self.resolution = info.resolution;
+        self.decoder = Some(Decoder::for_stream(info)?);
+        self.labels = info.labels.clone();
+        self.markers.clear();
+        self.times.clear();
+        self.mapping = None;
+        Ok(())
+    }
+
+    /// Maps the markers' device times to host times in `rows` and the
+    /// written tables.
+    pub fn set_clock_mapping(&mut self, mapping : ClockMapping) {
+        self.mapping = Some(mapping);
+    }
+
+    pub fn clock_mapping(&self) -> Option<&ClockMapping> {
+        self.mapping.as_ref()
+    }
+
+    pub fn mode(&self) -> MeasurementMode {
+        self.mode
+    }
+
+    /// Whether `begin` has been called
+    pub fn has_begun(&self) -> bool {
+        self.decoder.is_some()
+    }
+
+    /// Adds a marker event at `time` syncs (T3) or resolution units (T2).
+    pub fn push(&mut self, markers : u8, time : u64) {
+        self.markers.push(markers);
+        self.times.push(time);
+    }
+
+    /// Adds the markers of already decoded events.
+    pub fn extend_from_columns(&mut self, columns : &EventColumns) {
+        for event in columns.iter().filter(|event| event.kind == EventKind::Marker) {
+            self.push(event.channel, event.time);
+        }
+    }
+
+    /// Decodes `records`, keeping their markers.
+    ///
+    /// ## Errors
+    ///
+    /// If the acquisition hasn't begun, see `begin`.
+    pub fn decode(&mut self, records : &[u32]) -> Result<(), String> {
+        let decoder = self.decoder.as_mut().ok_or("The acquisition hasn't begun, see `begin`")?;
+        for event in records.iter().filter_map(|record| decoder.decode(*record)) {
+            if event.kind == EventKind::Marker {
+                self.markers.push(event.channel);
+                self.times.push(event.time);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.times.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.times.is_empty()
+    }
+
+    /// Every marker event, in the order recorded
+    pub fn rows(&self) -> Vec<MarkerRow> {
+        let unit = match self.mode {
+            MeasurementMode::T3 => self.sync_period.unwrap_or(0.0),
+            _ => self.resolution,
+        };
+        self.markers.iter().zip(self.times.iter())
+            .map(|(markers, time)| {
+                let time_ps = (*time as f64 * unit).round() as u64;
+                MarkerRow {
+                    markers : *markers,
+                    time : *time,
+                    time_ps,
+                    host_time : self.mapping.map(|mapping| mapping.photon_time(time_ps as f64)),
+                }
+            })
+            .collect()
+    }
+
+    /// Writes one `markers,<nsync or timetag>,time_ps,host_time_s` line
+    /// per marker event, with a header, after the acquisition's labels as
+    /// `# key = value` comment lines. `host_time_s` is in seconds since
+    /// the Unix epoch, and empty without a clock mapping.
+    pub fn write_csv<W : Write>(&self, mut writer : W) -> io::Result<()> {
+        let unit = match self.mode {
+            MeasurementMode::T2 => "timetag",
+            _ => "nsync",
+        };
+        for (key, value) in self.labels.iter() {
+            writeln!(writer, "# {} = {}", key, value)?;
+        }
+        writeln!(writer, "markers,{},time_ps,host_time_s", unit)?;
+        for row in self.rows() {
+            let host = row.host_time
+                .map(|t| format!("{:.6}", t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64()))
+                .unwrap_or_default();
+            writeln!(writer, "{},{},{},{}", row.markers, row.time, row.time_ps, host)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the table as CSV to the file at `path`.
+    pub fn save_csv<P : AsRef<Path>>(&self, path : P) -> io::Result<()> {
+        let file = std::fs::File::create(path)?;
+        let mut writer = io::BufWriter::new(file);
+        self.write_csv(&mut writer)?;
+        writer.flush()
+    }
+}
+
+impl StreamProcessor for MarkerTable {
+    fn name(&self) -> &str {
+        "marker_table"
+    }
+
+    fn init(&mut self, info : &StreamInfo) -> ProcessorResult {
+        self.begin(info)
+    }
+
+    fn process_batch(&mut self, batch : &Batch) -> ProcessorResult {
+        self.decode(batch.records)
+    }
+
+    fn finalize(&mut self) -> ProcessorResult {
+        if let Some(path) = &self.output {
+            self.save_csv(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+    use super::{MarkerConfig, MarkerCounter};
+    use crate::mhconsts::{MeasurementMode, TriggerEdge};
+    use crate::{DebugMultiHarp150, MultiHarpDevice};
+    use crate::pipeline::{Batch, StreamInfo, StreamProcessor};
+    use crate::testing::fixtures::stream_info;
+
+    fn t3(special : bool, channel : u32, nsync : u32) -> u32 {
+        ((special as u32) << 31) | (channel << 25) | nsync
+    }
+
+    #[test]
+    fn test_marker_counter_t3() {
+        let mut counter = MarkerCounter::new(2).unwrap();
+        counter.init(&stream_info(MeasurementMode::T3)).unwrap();
+
+        let records = [
+            t3(true, 0b0010, 10),   // marker 2
+            t3(false, 1, 11),       // photon
+            t3(true, 0b0001, 12),   // marker 1, ignored
+            t3(true, 0x3F, 0),      // one overflow
+            t3(true, 0b0011, 5),    // markers 1 and 2
+            t3(true, 0x3F, 3),      // three overflows
+            t3(true, 0b0010, 7),
+        ];
+        counter.process_batch(&Batch { index : 0, read_at : Instant::now(), records : &records, mode : MeasurementMode::T3, sync_lost : false }).unwrap();
+        assert_eq!(counter.count(), 3);
+        assert_eq!(counter.timestamps(), &[10, 1024 + 5, 4 * 1024 + 7]);
+
+        let mut csv = Vec::new();
+        counter.write_csv(&mut csv).unwrap();
+        assert!(String::from_utf8(csv).unwrap().starts_with("event,nsync\n0,10\n"));
+
+        assert!(MarkerCounter::new(5).is_err());
+    }
+
+    #[test]
+    fn test_marker_table() {
+        use std::time::{Duration, SystemTime};
+        use crate::clock::ClockMapping;
+        use crate::tttr::pack_t2;
+        use super::{MarkerTable, marker_table_path};
+
+        let mut table = MarkerTable::new();
+        let info = stream_info(MeasurementMode::T2);
+        assert!(table.decode(&[pack_t2(true, 1, 10)]).is_err());
+        table.begin(&info).unwrap();
+        table.decode(&[pack_t2(true, 1, 10), pack_t2(false, 0, 11), pack_t2(true, 0b0101, 200_000)]).unwrap();
+        assert_eq!(table.len(), 2);
+        let rows = table.rows();
+        assert_eq!((rows[1].markers, rows[1].time, rows[1].time_ps), (0b0101, 200_000, 1_000_000));
+        assert_eq!(rows[0].host_time, None);
+
+        // Host clock one second ahead at the start of the measurement
+        let origin = Instant::now();
+        let origin_wall = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        table.set_clock_mapping(ClockMapping { origin, origin_wall, offset : 1.0, slope : 1.0, residual : 0.0, samples : 2 });
+        assert_eq!(table.rows()[1].host_time, Some(origin_wall + Duration::from_secs(1) + Duration::from_micros(1)));
+
+        let mut csv = Vec::new();
+        table.write_csv(&mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert!(csv.starts_with("markers,timetag,time_ps,host_time_s\n1,10,50,1001.000000\n"), "{}", csv);
+
+        let t3 = StreamInfo { mode : MeasurementMode::T3, ..info };
+        assert!(MarkerTable::new().begin(&t3).is_err());
+        assert_eq!(marker_table_path("/data/run.ptu", "csv"), std::path::PathBuf::from("/data/run.markers.csv"));
+    }
+
+    #[test]
+    fn test_marker_config() {
+        let mut mh = DebugMultiHarp150::default();
+        assert_eq!(mh.marker_config(), Some(MarkerConfig::default()));
+
+        let mut markers = MarkerConfig::single(2, TriggerEdge::Falling).unwrap();
+        markers.holdoff_ns = 1000;
+        mh.set_marker_config(&markers).unwrap();
+        assert_eq!(mh.marker_config(), Some(markers));
+        assert!(MarkerConfig::single(5, TriggerEdge::Rising).is_err());
+
+        // A bad holdoff undoes the edges and enables set before it
+        let bad = MarkerConfig { enables : [true; 4], edges : [TriggerEdge::Falling; 4], holdoff_ns : -1 };
+        let error = mh.set_marker_config(&bad).unwrap_err();
+        assert_eq!(error.setting, "marker_holdoff_time");
+        assert!(error.rolled_back(), "{}", error);
+        assert_eq!(mh.marker_config(), Some(markers));
+    }
+}
@@ -0,0 +1,191 @@
+//! User-supplied labels describing a measurement (sample ID, laser
+//! power, objective, ...), carried to every output through
+//! `StreamInfo` so each sink records the same metadata.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io;
+
+use crate::events::events_json;
+use crate::mhconsts::MeasurementMode;
+use crate::pipeline::{Batch, ProcessorResult, StreamInfo, StreamProcessor};
+
+/// Ordered key/value labels attached to an acquisition.
+///
+/// ## Example
+///
+/// ```
+/// use multi_harp_patina::*;
+///
+/// let mut pipeline = Pipeline::new();
+/// pipeline.labels_mut()
+///     .insert("sample", "fly_03")
+///     .insert("power_mw", 12.5)
+///     .insert("objective", "25x/1.1");
+/// assert_eq!(pipeline.labels().get("sample"), Some("fly_03"));
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Labels {
+    labels : BTreeMap<String, String>,
+}
+
+impl Labels {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets `key` to `value`, replacing any previous value.
+    pub fn insert<K : Into<String>, V : ToString>(&mut self, key : K, value : V) -> &mut Self {
+        self.labels.insert(key.into(), value.to_string());
+        self
+    }
+
+    pub fn get(&self, key : &str) -> Option<&str> {
+        self.labels.get(key).map(|v| v.as_str())
+    }
+
+    pub fn remove(&mut self, key : &str) -> Option<String> {
+        self.labels.remove(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.labels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    /// Labels in key order
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.labels.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    /// The labels as a JSON object, e.g. `{"power_mw":"12.5","sample":"fly_03"}`.
+    /// Values are always strings.
+    pub fn to_json(&self) -> String {
+        let entries = self.iter()
+            .map(|(k, v)| format!("{}:{}", json_string(k), json_string(v)))
+            .collect::<Vec<_>>();
+        format!("{{{}}}", entries.join(","))
+    }
+}
+
+impl<K : Into<String>, V : ToString> FromIterator<(K, V)> for Labels {
+    fn from_iter<I : IntoIterator<Item = (K, V)>>(iter : I) -> Self {
+        let mut labels = Labels::new();
+        for (k, v) in iter {
+            labels.insert(k, v);
+        }
+        labels
+    }
+}
+
+/// Quotes and escapes `s` as a JSON string
+pub(crate) fn json_string(s : &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => { let _ = write!(out, "\\u{:04x}", c as u32); },
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Writes a JSON manifest describing the acquisition -- device, mode,
+/// enabled channels, labels, number of batches and records, and the
+/// device's recent events (see `recent_events`) -- when it finishes.
+pub struct ManifestWriter {
+    path : std::path::PathBuf,
+    info : Option<StreamInfo>,
+    batches : u64,
+    records : u64,
+}
+
+impl ManifestWriter {
+    pub fn new<P : AsRef<std::path::Path>>(path : P) -> Self {
+        ManifestWriter { path : path.as_ref().to_path_buf(), info : None, batches : 0, records : 0 }
+    }
+
+    /// The manifest as it would be written now
+    pub fn to_json(&self) -> String {
+        let (serial, mode, resolution, channels, labels) = match &self.info {
+            Some(info) => (info.serial.as_str(), info.mode, info.resolution, info.enabled(), info.labels.to_json()),
+            None => ("", MeasurementMode::T3, 0.0, Vec::new(), Labels::new().to_json()),
+        };
+        let channels = channels.iter().map(|c| c.to_string()).collect::<Vec<_>>().join(",");
+        format!(
+            "{{\"serial\":{},\"mode\":\"{:?}\",\"resolution_ps\":{},\"enabled_channels\":[{}],\"batches\":{},\"records\":{},\"labels\":{},\"recent_events\":{}}}\n",
+            json_string(serial), mode, resolution, channels, self.batches, self.records, labels, events_json(serial)
+        )
+    }
+}
+
+impl StreamProcessor for ManifestWriter {
+    fn name(&self) -> &str {
+        "manifest_writer"
+    }
+
+    fn init(&mut self, info : &StreamInfo) -> ProcessorResult {
+        self.info = Some(info.clone());
+        self.batches = 0;
+        self.records = 0;
+        Ok(())
+    }
+
+    fn process_batch(&mut self, batch : &Batch) -> ProcessorResult {
+        self.batches += 1;
+        self.records += batch.records.len() as u64;
+        Ok(())
+    }
+
+    fn finalize(&mut self) -> ProcessorResult {
+        std::fs::write(&self.path, self.to_json())
+            .map_err(|e : io::Error| format!("{}: {}", self.path.display(), e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+    use crate::testing::fixtures::stream_info;
+
+    #[test]
+    fn test_labels_json() {
+        let labels = [("sample", "fly \"03\""), ("power_mw", "12.5")]
+            .into_iter()
+            .collect::<Labels>();
+        assert_eq!(labels.to_json(), r#"{"power_mw":"12.5","sample":"fly \"03\""}"#);
+        assert_eq!(Labels::new().to_json(), "{}");
+    }
+
+    #[test]
+    fn test_manifest_writer() {
+        let mut labels = Labels::new();
+        labels.insert("objective", "25x");
+        let mut manifest = ManifestWriter::new(std::env::temp_dir().join("unused.json"));
+        // A serial of its own, so the events of other tests stay out
+        crate::events::record("manifest-test", crate::DeviceEventKind::Flags, "0x0000 -> 0x0002".to_string());
+        manifest.init(&StreamInfo {
+            serial : "manifest-test".to_string(),
+            enabled_channels : vec![true, true, false, true],
+            labels,
+            ..stream_info(MeasurementMode::T2)
+        }).unwrap();
+        manifest.process_batch(&Batch { index : 0, read_at : Instant::now(), records : &[0, 1, 2], mode : MeasurementMode::T2, sync_lost : false }).unwrap();
+        let json = manifest.to_json();
+        assert!(json.starts_with(
+            "{\"serial\":\"manifest-test\",\"mode\":\"T2\",\"resolution_ps\":5,\"enabled_channels\":[0,1,3],\"batches\":1,\"records\":3,\"labels\":{\"objective\":\"25x\"},\"recent_events\":[{\"timestamp_us\":"
+        ));
+        assert!(json.ends_with(",\"kind\":\"Flags\",\"message\":\"0x0000 -> 0x0002\"}]}\n"));
+    }
+}
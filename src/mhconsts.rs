@@ -102,8 +102,19 @@ pub const WARNING_OFFSET_UNNECESSARY : i32 = 0x0800;
 pub const WARNING_DIVIDER_TOO_SMALL : i32 = 0x1000;
 pub const WARNING_COUNTS_DROPPED : i32 = 0x2000;
 
+//The following are bitmasks for results from GetFlags()
+pub const FLAG_OVERFLOW : i32 = 0x0001;
+pub const FLAG_FIFOFULL : i32 = 0x0002;
+pub const FLAG_SYNC_LOST : i32 = 0x0004;
+/// The external reference clock (or its PPS signal) is absent
+pub const FLAG_REF_LOST : i32 = 0x0008;
+pub const FLAG_SYSERROR : i32 = 0x0010;
+pub const FLAG_ACTIVE : i32 = 0x0020;
+pub const FLAG_CNTS_DROPPED : i32 = 0x0040;
+pub const FLAG_SOFTERROR : i32 = 0x0080;
+
 /// MultiHarp modes
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum MeasurementMode {
     Histogramming = 0,
     T2 = 2,
@@ -112,7 +123,7 @@ pub enum MeasurementMode {
 
 /// Which clock the MultiHarp should be
 /// referenced to
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum ReferenceClock {
     /// Multiharp internal oscillator
     Internal = 0,
@@ -136,9 +147,24 @@ pub enum ReferenceClock {
     WrGrandmasterMH = 9,
 }
 
+impl ReferenceClock {
+    /// Whether the clock includes a pulse-per-second signal from a GPS receiver
+    pub fn uses_pps(&self) -> bool {
+        matches!(self, ReferenceClock::PpsGps | ReferenceClock::PpsUart)
+    }
+
+    /// Whether the clock is a 10 MHz external reference, with or
+    /// without PPS. White Rabbit references are reported separately
+    /// by `MH_GetWRStatus` and are not included.
+    pub fn uses_external_reference(&self) -> bool {
+        matches!(self, ReferenceClock::External | ReferenceClock::PpsGps | ReferenceClock::PpsUart)
+    }
+}
+
 /// Hardware triggered measurements through TTL vs. 
 /// software gating of the initiation of measurement.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum MeasurementControlMode {
     /// Runs until the `tacq` time passed to `MH_StartMeas` elapses
     SingleShotCtc = 0,
@@ -159,7 +185,8 @@ pub enum MeasurementControlMode {
 }
 
 /// Set edge used to identify triggers
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum TriggerEdge {
     Rising = 1,
     Falling = 0,
@@ -167,7 +194,7 @@ pub enum TriggerEdge {
 
 /// Allows checking of features available
 /// in this device
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum FeatureMasks {
     /// Dll license available
     Dll = 0x0001,
@@ -189,8 +216,11 @@ pub enum FeatureMasks {
     EvntFilt = 0x0100,
 }
 
+/// Every bit of `FeatureMasks`
+pub const ALL_FEATURES : i32 = 0x01FF;
+
 /// Masks used to read MH_GetFlags
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Flags {
     /// Histogram mode only
     Overflow = 0x0001,
@@ -204,6 +234,121 @@ pub enum Flags {
     Active = 0x0020,
     /// Counts were dropped
     CountsDropped = 0x0040,
+    /// A recoverable error, see `get_debug_info`
+    SoftError = 0x0080,
+}
+
+/// Masks used to read MH_GetWarnings, with the bits of the `WARNING_`
+/// constants
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Warning {
+    SyncRateZero = 0x0001,
+    SyncRateVeryLow = 0x0002,
+    SyncRateTooHigh = 0x0004,
+    InputRateZero = 0x0010,
+    InputRateTooHigh = 0x0040,
+    InputRateRatio = 0x0100,
+    DividerGreaterOne = 0x0200,
+    TimeSpanTooSmall = 0x0400,
+    OffsetUnnecessary = 0x0800,
+    DividerTooSmall = 0x1000,
+    CountsDropped = 0x2000,
+}
+
+/// Gives an enum of bits its numeric code, the name of its `mhconsts`
+/// constant, and the conversions from the bitmask `MHLib` returns.
+/// The codes and names are `MHLib`'s, so they are stable across
+/// versions of this crate.
+macro_rules! bit_codes {
+    ($name:ident { $($variant:ident => $constant:literal),* $(,)? }) => {
+        impl $name {
+            /// Every variant, in bit order
+            pub const ALL : [$name; [$($name::$variant),*].len()] = [$($name::$variant),*];
+
+            /// The bit of the variant
+            pub fn code(self) -> i32 {
+                self as i32
+            }
+
+            /// The variant with the bit `code`, if any
+            pub fn from_code(code : i32) -> Option<Self> {
+                Self::ALL.iter().copied().find(|variant| variant.code() == code)
+            }
+
+            /// The variants set in `bits`, in bit order. Unknown bits are skipped.
+            pub fn from_bits(bits : i32) -> Vec<Self> {
+                Self::ALL.iter().copied().filter(|variant| bits & variant.code() != 0).collect()
+            }
+
+            /// The name of the `mhconsts` constant of the bit
+            pub fn constant_name(self) -> &'static str {
+                match self {
+                    $($name::$variant => $constant,)*
+                }
+            }
+        }
+    };
+}
+
+bit_codes!(Flags {
+    Overflow => "FLAG_OVERFLOW",
+    FifoFull => "FLAG_FIFOFULL",
+    SyncLost => "FLAG_SYNC_LOST",
+    RefLost => "FLAG_REF_LOST",
+    SysError => "FLAG_SYSERROR",
+    Active => "FLAG_ACTIVE",
+    CountsDropped => "FLAG_CNTS_DROPPED",
+    SoftError => "FLAG_SOFTERROR",
+});
+
+bit_codes!(Warning {
+    SyncRateZero => "WARNING_SYNC_RATE_ZERO",
+    SyncRateVeryLow => "WARNING_SYNC_RATE_VERY_LOW",
+    SyncRateTooHigh => "WARNING_SYNC_RATE_TOO_HIGH",
+    InputRateZero => "WARNING_INPT_RATE_ZERO",
+    InputRateTooHigh => "WARNING_INPT_RATE_TOO_HIGH",
+    InputRateRatio => "WARNING_INPT_RATE_RATIO",
+    DividerGreaterOne => "WARNING_DIVIDER_GREATER_ONE",
+    TimeSpanTooSmall => "WARNING_TIME_SPAN_TOO_SMALL",
+    OffsetUnnecessary => "WARNING_OFFSET_UNNECESSARY",
+    DividerTooSmall => "WARNING_DIVIDER_TOO_SMALL",
+    CountsDropped => "WARNING_COUNTS_DROPPED",
+});
+
+impl Flags {
+    /// A short English description, for applications without their own
+    pub fn description(self) -> &'static str {
+        match self {
+            Flags::Overflow => "Histogram overflow",
+            Flags::FifoFull => "FIFO full, records lost",
+            Flags::SyncLost => "Sync signal lost",
+            Flags::RefLost => "External reference clock lost",
+            Flags::SysError => "Hardware error, contact support",
+            Flags::Active => "Measurement running",
+            Flags::CountsDropped => "Counts dropped",
+            Flags::SoftError => "Recoverable error",
+        }
+    }
+}
+
+impl Warning {
+    /// A short English description, for applications without their own
+    /// (see `advise` for a longer one and a fix)
+    pub fn description(self) -> &'static str {
+        match self {
+            Warning::SyncRateZero => "No sync signal",
+            Warning::SyncRateVeryLow => "Sync rate very low",
+            Warning::SyncRateTooHigh => "Sync rate too high",
+            Warning::InputRateZero => "No counts on an enabled input",
+            Warning::InputRateTooHigh => "Input rate too high",
+            Warning::InputRateRatio => "Input rate above 5% of the sync rate",
+            Warning::DividerGreaterOne => "Sync divider greater than 1 in T2 mode",
+            Warning::TimeSpanTooSmall => "Histogram shorter than the sync period",
+            Warning::OffsetUnnecessary => "Offset unnecessary",
+            Warning::DividerTooSmall => "Sync divider too small",
+            Warning::CountsDropped => "Counts dropped",
+        }
+    }
 }
 
 pub const ROWIDXMIN : i32 = 0;
@@ -262,6 +407,7 @@ pub const WR_MAC_LEN : usize = 6;
 pub const WR_SCRIPT_LEN : usize = 256;
 pub const WR_TERM_LEN : usize = 513;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum WRMode {
     Off = 0,
     Slave = 1,
@@ -277,6 +423,7 @@ pub const WR_STATUS_IS_NEW : u32 = 0x80000000;
 
 /// Only usable with an external FPGA
 /// connected to a MultiHarp 160
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum ExtFpgaMode {
     Off = 0,
     T2Raw = 1,
@@ -284,9 +431,99 @@ pub enum ExtFpgaMode {
     T3 = 3,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum ExtFpgaLoopback {
     Off = 0,
     Custom = 1,
     T2 = 2,
     T3 = 3,
-}
\ No newline at end of file
+}
+
+/// Implements `Display` as the name of each variant and `FromStr` parsing
+/// it back, ignoring case, e.g. for command line arguments.
+macro_rules! named_variants {
+    ($name:ident { $($(#[$attr:meta])* $variant:ident),* $(,)? }) => {
+        impl std::fmt::Display for $name {
+            fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+                let name = match self {
+                    $($(#[$attr])* $name::$variant => stringify!($variant),)*
+                };
+                f.write_str(name)
+            }
+        }
+
+        impl std::str::FromStr for $name {
+            type Err = String;
+
+            fn from_str(s : &str) -> Result<Self, String> {
+                $(
+                    $(#[$attr])*
+                    if s.eq_ignore_ascii_case(stringify!($variant)) {
+                        return Ok($name::$variant);
+                    }
+                )*
+                Err(format!("Invalid {} `{}`", stringify!($name), s))
+            }
+        }
+    };
+}
+
+named_variants!(MeasurementMode { Histogramming, T2, T3 });
+named_variants!(ReferenceClock {
+    Internal, External, WRMaster, WRSlave, WRGrandmaster, PpsGps, PpsUart, WrMasterMH, WrSlaveMH, WrGrandmasterMH,
+});
+named_variants!(MeasurementControlMode {
+    SingleShotCtc, C1Gated, C1StartCtcStop, C1StartC2Stop, WrM2S, WrS2M,
+    #[cfg(feature = "MHLv3_1_0")]
+    SwStartSwStop,
+});
+named_variants!(TriggerEdge { Rising, Falling });
+named_variants!(FeatureMasks { Dll, Tttr, Markers, LowRes, TrigOut, ProgTd, ExtFpga, ProgHyst, EvntFilt });
+named_variants!(Flags { Overflow, FifoFull, SyncLost, RefLost, SysError, Active, CountsDropped, SoftError });
+named_variants!(Warning {
+    SyncRateZero, SyncRateVeryLow, SyncRateTooHigh, InputRateZero, InputRateTooHigh, InputRateRatio,
+    DividerGreaterOne, TimeSpanTooSmall, OffsetUnnecessary, DividerTooSmall, CountsDropped,
+});
+named_variants!(WRMode { Off, Slave, Master, Grandmaster });
+named_variants!(ExtFpgaMode { Off, T2Raw, T2, T3 });
+named_variants!(ExtFpgaLoopback { Off, Custom, T2, T3 });
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_named_variants() {
+        for mode in [MeasurementMode::Histogramming, MeasurementMode::T2, MeasurementMode::T3] {
+            assert_eq!(mode.to_string().parse::<MeasurementMode>(), Ok(mode));
+        }
+        assert_eq!("rising".parse::<TriggerEdge>(), Ok(TriggerEdge::Rising));
+        assert_eq!(ReferenceClock::WrSlaveMH.to_string(), "WrSlaveMH");
+        assert_eq!("ppsgps".parse::<ReferenceClock>(), Ok(ReferenceClock::PpsGps));
+        assert_eq!("T4".parse::<MeasurementMode>(), Err("Invalid MeasurementMode `T4`".to_string()));
+
+        assert_eq!("syncratezero".parse::<Warning>(), Ok(Warning::SyncRateZero));
+
+        let clocks = [ReferenceClock::Internal, ReferenceClock::External, ReferenceClock::Internal];
+        assert_eq!(clocks.iter().collect::<HashSet<_>>().len(), 2);
+    }
+
+    #[test]
+    fn test_bit_codes() {
+        assert_eq!(Flags::ALL.len(), 8);
+        assert_eq!(Flags::SoftError.code(), FLAG_SOFTERROR);
+        assert_eq!(Flags::from_bits(FLAG_SYNC_LOST | FLAG_FIFOFULL | 0x4000), vec![Flags::FifoFull, Flags::SyncLost]);
+        assert_eq!(Flags::from_code(FLAG_REF_LOST), Some(Flags::RefLost));
+        assert_eq!(Flags::from_code(0x0003), None);
+
+        let constants = [
+            WARNING_SYNC_RATE_ZERO, WARNING_SYNC_RATE_VERY_LOW, WARNING_SYNC_RATE_TOO_HIGH, WARNING_INPT_RATE_ZERO,
+            WARNING_INPT_RATE_TOO_HIGH, WARNING_INPT_RATE_RATIO, WARNING_DIVIDER_GREATER_ONE, WARNING_TIME_SPAN_TOO_SMALL,
+            WARNING_OFFSET_UNNECESSARY, WARNING_DIVIDER_TOO_SMALL, WARNING_COUNTS_DROPPED,
+        ];
+        assert_eq!(Warning::ALL.map(Warning::code), constants);
+        assert_eq!(Warning::from_bits(WARNING_COUNTS_DROPPED | WARNING_SYNC_RATE_ZERO), vec![Warning::SyncRateZero, Warning::CountsDropped]);
+        assert_eq!(Warning::InputRateRatio.constant_name(), "WARNING_INPT_RATE_RATIO");
+    }
+}
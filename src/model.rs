@@ -0,0 +1,330 @@
+//! Identifies which MultiHarp variant is connected, parsed from
+//! the model string returned by `MH_GetHardwareInfo`, along with
+//! a static table of what each model can do, including how input
+//! channels map onto the rows of the event filters.
+
+use std::fmt::Display;
+
+/// Known MultiHarp hardware models. The `P` variants of the
+/// MultiHarp 150 have a 5 ps base resolution, the `N` variants
+/// 80 ps.
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeviceModel {
+    MultiHarp150_4P,
+    MultiHarp150_8P,
+    MultiHarp150_16P,
+    MultiHarp150_4N,
+    MultiHarp150_8N,
+    MultiHarp150_16N,
+    MultiHarp160,
+    /// Model string not recognized by this version of the crate
+    Unknown(String),
+}
+
+/// Static properties of a `DeviceModel`. These are upper
+/// bounds for the model -- query the device itself (e.g.
+/// `num_input_channels`) for the actual configuration.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ModelCapabilities {
+    /// Maximum number of physical input channels
+    pub max_channels : i32,
+    /// Finest bin width in picoseconds
+    pub base_resolution : f64,
+    /// Whether an external FPGA can be attached
+    pub ext_fpga : bool,
+}
+
+static MH150P_4 : ModelCapabilities = ModelCapabilities { max_channels : 4, base_resolution : 5.0, ext_fpga : false };
+static MH150P_8 : ModelCapabilities = ModelCapabilities { max_channels : 8, base_resolution : 5.0, ext_fpga : false };
+static MH150P_16 : ModelCapabilities = ModelCapabilities { max_channels : 16, base_resolution : 5.0, ext_fpga : false };
+static MH150N_4 : ModelCapabilities = ModelCapabilities { max_channels : 4, base_resolution : 80.0, ext_fpga : false };
+static MH150N_8 : ModelCapabilities = ModelCapabilities { max_channels : 8, base_resolution : 80.0, ext_fpga : false };
+static MH150N_16 : ModelCapabilities = ModelCapabilities { max_channels : 16, base_resolution : 80.0, ext_fpga : false };
+static MH160 : ModelCapabilities = ModelCapabilities { max_channels : crate::mhconsts::MAXINPCHAN, base_resolution : 5.0, ext_fpga : true };
+
+impl DeviceModel {
+    /// Parses the model string from `get_hardware_info`, e.g.
+    /// `"MultiHarp 150 4P"`. Whitespace and case are ignored.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// use multi_harp_patina::DeviceModel;
+    ///
+    /// assert_eq!(DeviceModel::from_model_string("MultiHarp 150 8N"), DeviceModel::MultiHarp150_8N);
+    /// ```
+    pub fn from_model_string(model : &str) -> Self {
+        let normalized = model.split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+            .to_uppercase();
+
+        match normalized.as_str() {
+            "MULTIHARP 150 4P" => DeviceModel::MultiHarp150_4P,
+            "MULTIHARP 150 8P" => DeviceModel::MultiHarp150_8P,
+            "MULTIHARP 150 16P" => DeviceModel::MultiHarp150_16P,
+            "MULTIHARP 150 4N" => DeviceModel::MultiHarp150_4N,
+            "MULTIHARP 150 8N" => DeviceModel::MultiHarp150_8N,
+            "MULTIHARP 150 16N" => DeviceModel::MultiHarp150_16N,
+            s if s.starts_with("MULTIHARP 160") => DeviceModel::MultiHarp160,
+            _ => DeviceModel::Unknown(model.to_string()),
+        }
+    }
+
+    /// Returns the static capability table entry for this model,
+    /// or `None` if the model is `Unknown`.
+    pub fn capabilities(&self) -> Option<&'static ModelCapabilities> {
+        match self {
+            DeviceModel::MultiHarp150_4P => Some(&MH150P_4),
+            DeviceModel::MultiHarp150_8P => Some(&MH150P_8),
+            DeviceModel::MultiHarp150_16P => Some(&MH150P_16),
+            DeviceModel::MultiHarp150_4N => Some(&MH150N_4),
+            DeviceModel::MultiHarp150_8N => Some(&MH150N_8),
+            DeviceModel::MultiHarp150_16N => Some(&MH150N_16),
+            DeviceModel::MultiHarp160 => Some(&MH160),
+            DeviceModel::Unknown(_) => None,
+        }
+    }
+}
+
+/// Input channels handled by each row FPGA, and so by each Row Filter
+pub const CHANNELS_PER_ROW : i32 = 8;
+
+/// The channels of one row marked in a `use_channels` or
+/// `pass_channels` bitfield of the event filters, numbered from 0 at the
+/// leftmost channel of the row. The manual numbers the bits from the
+/// right; this type does the reversal. Only the Main Filter can act on
+/// the sync channel, marked in row 0.
+///
+/// ## Example
+///
+/// ```
+/// use multi_harp_patina::*;
+///
+/// let mask = ChannelMask::from_channels(&[0, 3]).unwrap().with_sync();
+/// assert!(mask.contains(3) && mask.has_sync());
+/// assert_eq!(mask.to_string(), "{0, 3, sync}");
+/// assert_eq!(mask.bits(), 0b1_0000_1001);
+/// assert!(ChannelMask::from_channels(&[8]).is_err());
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct ChannelMask(u16);
+
+impl ChannelMask {
+    /// No channel
+    pub const EMPTY : ChannelMask = ChannelMask(0);
+    /// Every input channel of a row
+    pub const ALL : ChannelMask = ChannelMask((1 << CHANNELS_PER_ROW) - 1);
+    /// The sync channel, for the Main Filter
+    pub const SYNC : ChannelMask = ChannelMask(1 << CHANNELS_PER_ROW);
+
+    /// The mask of `channels`, each an index within the row
+    pub fn from_channels(channels : &[i32]) -> Result<Self, String> {
+        channels.iter().try_fold(Self::EMPTY, |mask, channel| {
+            if !(0..CHANNELS_PER_ROW).contains(channel) {
+                return Err(format!("Channel {} is not in a row of {}", channel, CHANNELS_PER_ROW));
+            }
+            Ok(ChannelMask(mask.0 | 1 << channel))
+        })
+    }
+
+    /// The mask of a bitfield as passed to `MHLib`, if it has no bits
+    /// beyond the sync channel
+    pub fn from_bits(bits : i32) -> Option<Self> {
+        (bits & !(Self::ALL.0 | Self::SYNC.0) as i32 == 0).then_some(ChannelMask(bits as u16))
+    }
+
+    /// The bitfield passed to `MHLib`
+    pub fn bits(&self) -> i32 {
+        self.0 as i32
+    }
+
+    /// This mask with the sync channel
+    pub fn with_sync(self) -> Self {
+        self | Self::SYNC
+    }
+
+    /// Whether `channel`, an index within the row, is marked
+    pub fn contains(&self, channel : i32) -> bool {
+        (0..CHANNELS_PER_ROW).contains(&channel) && self.0 & (1 << channel) != 0
+    }
+
+    pub fn has_sync(&self) -> bool {
+        self.0 & Self::SYNC.0 != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// The marked input channels of the row, in order
+    pub fn channels(&self) -> impl Iterator<Item = i32> + '_ {
+        (0..CHANNELS_PER_ROW).filter(|channel| self.contains(*channel))
+    }
+}
+
+impl std::ops::BitOr for ChannelMask {
+    type Output = ChannelMask;
+
+    fn bitor(self, other : ChannelMask) -> ChannelMask {
+        ChannelMask(self.0 | other.0)
+    }
+}
+
+impl std::ops::BitOrAssign for ChannelMask {
+    fn bitor_assign(&mut self, other : ChannelMask) {
+        self.0 |= other.0;
+    }
+}
+
+impl Display for ChannelMask {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        let mut marked = self.channels().map(|channel| channel.to_string()).collect::<Vec<_>>();
+        if self.has_sync() {
+            marked.push("sync".to_string());
+        }
+        write!(f, "{{{}}}", marked.join(", "))
+    }
+}
+
+/// The channels of one row used and passed by the row and main event
+/// filters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RowFilterChannels {
+    pub row : i32,
+    pub use_channels : ChannelMask,
+    pub pass_channels : ChannelMask,
+}
+
+impl RowFilterChannels {
+    /// Whether no channel of the row is used or passed
+    pub fn is_empty(&self) -> bool {
+        self.use_channels.is_empty() && self.pass_channels.is_empty()
+    }
+}
+
+impl ModelCapabilities {
+    /// Number of rows of input channels
+    pub fn rows(&self) -> i32 {
+        (self.max_channels + CHANNELS_PER_ROW - 1) / CHANNELS_PER_ROW
+    }
+
+    /// The row of input `channel` (numbered from 0, as in `set_input_*`)
+    /// and its bit within the row, or `None` if the model has no such channel.
+    pub fn row_position(&self, channel : i32) -> Option<(i32, i32)> {
+        if channel < 0 || channel >= self.max_channels {
+            return None;
+        }
+        Some((channel / CHANNELS_PER_ROW, channel % CHANNELS_PER_ROW))
+    }
+
+    /// Translates lists of input channels to use and to pass unconditionally
+    /// into the bitfields of every row of this model, in row order.
+    ///
+    /// ## Errors
+    ///
+    /// If a channel does not exist on this model
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// use multi_harp_patina::*;
+    ///
+    /// let caps = DeviceModel::MultiHarp150_16P.capabilities().unwrap();
+    /// let rows = caps.row_filter_channels(&[0, 1, 9], &[15]).unwrap();
+    /// assert_eq!(rows[0].use_channels, ChannelMask::from_channels(&[0, 1]).unwrap());
+    /// assert_eq!(rows[1].use_channels.to_string(), "{1}");
+    /// assert_eq!(rows[1].pass_channels.bits(), 0b1000_0000);
+    /// ```
+    pub fn row_filter_channels(&self, use_channels : &[i32], pass_channels : &[i32]) -> Result<Vec<RowFilterChannels>, String> {
+        let mut rows = (0..self.rows())
+            .map(|row| RowFilterChannels { row, ..Default::default() })
+            .collect::<Vec<_>>();
+        for (channels, passed) in [(use_channels, false), (pass_channels, true)] {
+            for channel in channels {
+                let (row, bit) = self.row_position(*channel).ok_or_else(|| format!(
+                    "Channel {} does not exist on a model with {} channels", channel, self.max_channels
+                ))?;
+                let row = &mut rows[row as usize];
+                let mask = ChannelMask(1 << bit);
+                if passed {
+                    row.pass_channels |= mask;
+                } else {
+                    row.use_channels |= mask;
+                }
+            }
+        }
+        Ok(rows)
+    }
+}
+
+impl Display for DeviceModel {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DeviceModel::MultiHarp150_4P => write!(f, "MultiHarp 150 4P"),
+            DeviceModel::MultiHarp150_8P => write!(f, "MultiHarp 150 8P"),
+            DeviceModel::MultiHarp150_16P => write!(f, "MultiHarp 150 16P"),
+            DeviceModel::MultiHarp150_4N => write!(f, "MultiHarp 150 4N"),
+            DeviceModel::MultiHarp150_8N => write!(f, "MultiHarp 150 8N"),
+            DeviceModel::MultiHarp150_16N => write!(f, "MultiHarp 150 16N"),
+            DeviceModel::MultiHarp160 => write!(f, "MultiHarp 160"),
+            DeviceModel::Unknown(s) => write!(f, "Unknown model ({})", s),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_model_strings() {
+        assert_eq!(DeviceModel::from_model_string("MultiHarp 150 4P"), DeviceModel::MultiHarp150_4P);
+        assert_eq!(DeviceModel::from_model_string(" multiharp  150 16N "), DeviceModel::MultiHarp150_16N);
+        assert_eq!(DeviceModel::from_model_string("MultiHarp 160"), DeviceModel::MultiHarp160);
+        assert_eq!(
+            DeviceModel::from_model_string("HydraHarp 400"),
+            DeviceModel::Unknown("HydraHarp 400".to_string())
+        );
+
+        // Display round-trips through the parser
+        let model = DeviceModel::MultiHarp150_8P;
+        assert_eq!(DeviceModel::from_model_string(&model.to_string()), model);
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let caps = DeviceModel::MultiHarp150_8N.capabilities().unwrap();
+        assert_eq!(caps.max_channels, 8);
+        assert_eq!(caps.base_resolution, 80.0);
+        assert!(!caps.ext_fpga);
+
+        assert!(DeviceModel::MultiHarp160.capabilities().unwrap().ext_fpga);
+        assert!(DeviceModel::Unknown("".to_string()).capabilities().is_none());
+    }
+
+    #[test]
+    fn test_row_filter_channels() {
+        let caps = DeviceModel::MultiHarp150_4N.capabilities().unwrap();
+        assert_eq!(caps.rows(), 1);
+        assert_eq!(caps.row_position(3), Some((0, 3)));
+        assert_eq!(caps.row_position(4), None);
+        assert!(caps.row_filter_channels(&[4], &[]).is_err());
+
+        let caps = DeviceModel::MultiHarp160.capabilities().unwrap();
+        assert_eq!(caps.rows(), 8);
+        let rows = caps.row_filter_channels(&[7, 8, 63], &[7]).unwrap();
+        assert_eq!(rows.len(), 8);
+        let last = ChannelMask::from_channels(&[7]).unwrap();
+        assert_eq!(rows[0], RowFilterChannels { row : 0, use_channels : last, pass_channels : last });
+        assert_eq!(rows[1].use_channels.bits(), 1);
+        assert_eq!(rows[7].use_channels, last);
+
+        assert_eq!(ChannelMask::from_bits(0b1_0000_0001), Some(ChannelMask::SYNC | ChannelMask::from_channels(&[0]).unwrap()));
+        assert_eq!(ChannelMask::from_bits(1 << 9), None);
+        assert_eq!(ChannelMask::ALL.channels().count(), 8);
+        assert_eq!(ChannelMask::EMPTY.to_string(), "{}");
+        assert!(rows[2..7].iter().all(|r| r.is_empty()));
+    }
+}
@@ -0,0 +1,329 @@
+//! Watches the device during an acquisition for conditions that
+//! make the data meaningless without anything in the record stream
+//! itself saying so, or that are about to.
+
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::error::MultiHarpResult;
+use crate::mhconsts::{self, MeasurementMode};
+use crate::rates::Rates;
+use crate::MultiHarpDevice;
+
+/// A period during which the sync signal was absent, measured from the
+/// start of monitoring and in FIFO reads (`Batch::index`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SyncLossInterval {
+    pub start : Duration,
+    /// `None` while the sync is still lost
+    pub end : Option<Duration>,
+    /// First batch read while the sync was lost
+    pub start_batch : u64,
+    /// First batch read after the sync came back
+    pub end_batch : Option<u64>,
+}
+
+/// Change in sync state reported by `SyncMonitor::poll`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyncEvent {
+    Lost(SyncLossInterval),
+    Restored(SyncLossInterval),
+}
+
+/// Tracks sync loss through the `FLAG_SYNC_LOST` flag and `get_sync_rate`,
+/// keeping a record of every loss interval. Used by `Pipeline` to mark
+/// batches read during a loss (`Batch::sync_lost`) and to report the
+/// intervals to processors through `StreamProcessor::sync_event`, but
+/// can also be polled directly.
+///
+/// ## Example
+///
+/// ```
+/// use std::time::Duration;
+/// use multi_harp_patina::*;
+///
+/// let mut mh = DebugMultiHarp150::default();
+/// mh.init(MeasurementMode::T3, ReferenceClock::Internal).unwrap();
+///
+/// let monitor = SyncMonitor::new(1e6, Duration::from_millis(100));
+/// let mut pipeline = Pipeline::new();
+/// pipeline.set_sync_monitor(monitor);
+/// pipeline.run(&mut mh, 500).unwrap();
+/// println!("{:?}", pipeline.sync_monitor().unwrap().intervals());
+/// ```
+#[derive(Debug, Clone)]
+pub struct SyncMonitor {
+    min_rate : f64,
+    check_interval : Duration,
+    started : Option<Instant>,
+    last_check : Option<Instant>,
+    intervals : Vec<SyncLossInterval>,
+}
+
+impl SyncMonitor {
+    /// Treats the sync as lost whenever the device flags it, or when the
+    /// sync rate falls below `min_rate` Hz. The device is checked at most
+    /// once per `check_interval` (`get_sync_rate` only updates every 100 ms).
+    pub fn new(min_rate : f64, check_interval : Duration) -> Self {
+        SyncMonitor {
+            min_rate,
+            check_interval,
+            started : None,
+            last_check : None,
+            intervals : Vec::new(),
+        }
+    }
+
+    /// Forgets previous intervals and restarts the clock.
+    pub fn reset(&mut self) {
+        self.started = None;
+        self.last_check = None;
+        self.intervals.clear();
+    }
+
+    /// Whether the sync was lost at the last check
+    pub fn is_lost(&self) -> bool {
+        self.intervals.last().map(|i| i.end.is_none()).unwrap_or(false)
+    }
+
+    /// Every loss interval since the last `reset`
+    pub fn intervals(&self) -> &[SyncLossInterval] {
+        &self.intervals
+    }
+
+    /// Checks the device if `check_interval` has passed, returning the
+    /// change in state, if any. `batch` is the index of the next batch to
+    /// be read, recorded in the interval.
+    pub fn poll<MH : MultiHarpDevice>(&mut self, multiharp : &MH, batch : u64) -> MultiHarpResult<Option<SyncEvent>> {
+        let now = Instant::now();
+        let started = *self.started.get_or_insert(now);
+        if let Some(last) = self.last_check {
+            if now.duration_since(last) < self.check_interval {
+                return Ok(None);
+            }
+        }
+        self.last_check = Some(now);
+
+        let flagged = multiharp.get_flags()? & mhconsts::FLAG_SYNC_LOST != 0;
+        let lost = flagged || (multiharp.get_sync_rate()? as f64) < self.min_rate;
+        let elapsed = now.duration_since(started);
+
+        match (lost, self.is_lost()) {
+            (true, false) => {
+                let interval = SyncLossInterval {
+                    start : elapsed, end : None, start_batch : batch, end_batch : None,
+                };
+                self.intervals.push(interval);
+                Ok(Some(SyncEvent::Lost(interval)))
+            },
+            (false, true) => {
+                let interval = self.intervals.last_mut().unwrap();
+                interval.end = Some(elapsed);
+                interval.end_batch = Some(batch);
+                Ok(Some(SyncEvent::Restored(*interval)))
+            },
+            _ => Ok(None),
+        }
+    }
+}
+
+/// Estimates how full the device's FIFO is from the rate records arrive
+/// at and the reads taking them out, so a poller or watchdog can act
+/// before the device raises `FLAG_FIFOFULL` and data is lost. The device
+/// doesn't report its FIFO occupancy, so this is only as good as the rate
+/// it is given.
+///
+/// A read returning fewer records than asked for emptied the FIFO; a full
+/// read leaves whatever arrived faster than it drained. Clones share the
+/// same estimate, so one can be given to a `Pipeline` to update on every
+/// read while another is checked from a different thread.
+///
+/// ## Example
+///
+/// ```
+/// use std::time::{Duration, Instant};
+/// use multi_harp_patina::*;
+///
+/// // A FIFO of 1 M records, read 100 k records at a time
+/// let estimator = FifoFillEstimator::new(1 << 20).with_read_len(100_000);
+/// estimator.set_rate(2e6);
+///
+/// let start = Instant::now();
+/// estimator.reset(start);
+/// // 200 k records arrived in the first 100 ms, but half are still waiting
+/// estimator.record_read(100_000, start + Duration::from_millis(100));
+/// let fill = estimator.estimated_fifo_fill_at(start + Duration::from_millis(100));
+/// assert!((fill - 100_000.0 / (1 << 20) as f64).abs() < 1e-9);
+/// assert_eq!(estimator.read_cadence(), Some(Duration::from_millis(100)));
+/// ```
+#[derive(Debug, Clone)]
+pub struct FifoFillEstimator {
+    depth : u64,
+    read_len : u64,
+    state : Arc<Mutex<FifoFillState>>,
+}
+
+#[derive(Debug, Default)]
+struct FifoFillState {
+    /// Records per second
+    rate : f64,
+    /// Records left in the FIFO after the last read
+    backlog : f64,
+    last_read : Option<Instant>,
+    cadence : Option<Duration>,
+}
+
+impl FifoFillEstimator {
+    /// An estimator for a FIFO holding `depth` records (see the device's
+    /// manual), read `TTREADMAX` records at a time.
+    pub fn new(depth : u64) -> Self {
+        FifoFillEstimator {
+            depth : depth.max(1),
+            read_len : mhconsts::TTREADMAX as u64,
+            state : Arc::new(Mutex::new(FifoFillState::default())),
+        }
+    }
+
+    /// Records asked for by each read, if not `TTREADMAX`
+    pub fn with_read_len(mut self, read_len : usize) -> Self {
+        self.read_len = read_len as u64;
+        self
+    }
+
+    fn state(&self) -> MutexGuard<'_, FifoFillState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Starts a new measurement at `at`, with the FIFO empty.
+    pub fn reset(&self, at : Instant) {
+        let mut state = self.state();
+        state.backlog = 0.0;
+        state.last_read = Some(at);
+        state.cadence = None;
+    }
+
+    /// Sets the rate records arrive at, in records per second.
+    pub fn set_rate(&self, records_per_second : f64) {
+        self.state().rate = records_per_second.max(0.0);
+    }
+
+    /// Sets the rate from measured count rates. In T2 mode every sync is
+    /// a record too. Markers and overflow records are left out.
+    pub fn update_rates(&self, rates : &Rates, mode : MeasurementMode) {
+        let rate = match mode {
+            MeasurementMode::T2 => rates.sync + rates.total_input(),
+            _ => rates.total_input(),
+        };
+        self.set_rate(rate);
+    }
+
+    /// Accounts for a read that returned `records` at `read_at`.
+    pub fn record_read(&self, records : usize, read_at : Instant) {
+        let depth = self.depth as f64;
+        let mut state = self.state();
+        let elapsed = state.last_read.map(|last| read_at.saturating_duration_since(last));
+        if let Some(elapsed) = elapsed {
+            state.cadence = Some(match state.cadence {
+                Some(cadence) => (cadence * 3 + elapsed) / 4,
+                None => elapsed,
+            });
+        }
+        state.backlog = match records as u64 >= self.read_len {
+            true => {
+                let arrived = state.rate * elapsed.unwrap_or_default().as_secs_f64();
+                (state.backlog + arrived - records as f64).clamp(0.0, depth)
+            },
+            false => 0.0,
+        };
+        state.last_read = Some(read_at);
+    }
+
+    /// Estimated records in the FIFO at `at`
+    pub fn estimated_records_at(&self, at : Instant) -> u64 {
+        let state = self.state();
+        let since = state.last_read.map(|last| at.saturating_duration_since(last)).unwrap_or_default();
+        (state.backlog + state.rate * since.as_secs_f64()).min(self.depth as f64) as u64
+    }
+
+    /// Estimated fraction of the FIFO filled at `at`, from 0 to 1
+    pub fn estimated_fifo_fill_at(&self, at : Instant) -> f64 {
+        self.estimated_records_at(at) as f64 / self.depth as f64
+    }
+
+    /// Estimated fraction of the FIFO filled now, from 0 to 1
+    pub fn estimated_fifo_fill(&self) -> f64 {
+        self.estimated_fifo_fill_at(Instant::now())
+    }
+
+    /// How long until the FIFO is estimated to fill if it isn't read,
+    /// or `None` at a rate of 0.
+    pub fn time_to_full(&self) -> Option<Duration> {
+        let free = self.depth - self.estimated_records_at(Instant::now());
+        let rate = self.state().rate;
+        match rate > 0.0 {
+            true => Some(Duration::from_secs_f64(free as f64 / rate)),
+            false => None,
+        }
+    }
+
+    /// Smoothed interval between reads, once there have been two
+    pub fn read_cadence(&self) -> Option<Duration> {
+        self.state().cadence
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DebugMultiHarp150;
+
+    #[test]
+    fn test_sync_monitor_intervals() {
+        let mut mh = DebugMultiHarp150::default();
+        let mut monitor = SyncMonitor::new(1e6, Duration::ZERO);
+
+        assert_eq!(monitor.poll(&mh, 0).unwrap(), None);
+
+        mh.set_flags(mhconsts::FLAG_SYNC_LOST);
+        assert!(matches!(monitor.poll(&mh, 3).unwrap(), Some(SyncEvent::Lost(_))));
+        assert!(monitor.is_lost());
+        assert_eq!(monitor.poll(&mh, 4).unwrap(), None);
+
+        mh.set_flags(0);
+        let restored = monitor.poll(&mh, 5).unwrap();
+        assert!(matches!(restored, Some(SyncEvent::Restored(SyncLossInterval { start_batch : 3, end_batch : Some(5), .. }))));
+
+        // A low rate counts as a loss too
+        mh.set_sync_rate(10.0);
+        assert!(matches!(monitor.poll(&mh, 6).unwrap(), Some(SyncEvent::Lost(_))));
+        assert_eq!(monitor.intervals().len(), 2);
+    }
+
+    #[test]
+    fn test_fifo_fill_estimator() {
+        let estimator = FifoFillEstimator::new(1000).with_read_len(100);
+        let start = Instant::now();
+        let ms = |n| start + Duration::from_millis(n);
+        estimator.reset(start);
+        let rates = Rates { sync : 1000.0, inputs : vec![500.0, 500.0], readings : 1, timestamp : std::time::SystemTime::now() };
+        estimator.update_rates(&rates, MeasurementMode::T2);
+        assert_eq!(estimator.estimated_records_at(ms(100)), 200);
+
+        // Full reads fall behind, a short one empties the FIFO
+        estimator.record_read(100, ms(100));
+        estimator.record_read(100, ms(200));
+        assert_eq!(estimator.estimated_records_at(ms(200)), 200);
+        assert_eq!(estimator.estimated_records_at(ms(300)), 400);
+        assert_eq!(estimator.estimated_fifo_fill_at(ms(10_000)), 1.0);
+        estimator.record_read(50, ms(250));
+        assert_eq!(estimator.estimated_records_at(ms(250)), 0);
+        assert_eq!(estimator.read_cadence(), Some(Duration::from_micros(87_500)));
+
+        // The clone shares the estimate
+        let shared = estimator.clone();
+        estimator.update_rates(&rates, MeasurementMode::T3);
+        assert_eq!(shared.estimated_records_at(ms(350)), 100);
+        shared.set_rate(0.0);
+        assert_eq!(estimator.time_to_full(), None);
+    }
+}
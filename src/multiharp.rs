@@ -3,13 +3,31 @@
 use std::ffi::*;
 #[cfg(feature = "async")]
 use async_trait::async_trait;
-#[cfg(feature = "async")]
-use crate::error::AsyncCheckedResult;
 
 use crate::error::{MultiHarpError, PatinaError, mh_to_result, CheckedResult, MultiHarpResult};
-use crate::{mhconsts, TriggerEdge, WRMode, ROWIDXMAX, ROWIDXMIN};
+use crate::{mhconsts, TriggerEdge, WRMode, ExtFpgaMode, ExtFpgaLoopback};
 use crate::mhlib::*;
-use crate::MultiHarpConfig;
+use crate::{MultiHarpConfig, MarkerConfig};
+use crate::{ChannelMask, DeviceModel, RowFilterChannels};
+use crate::events::{self, DeviceEvent, DeviceEventKind};
+#[cfg(feature = "MHLib")]
+use crate::audit;
+use crate::validation;
+use crate::Limits;
+use crate::ClockStatus;
+use crate::version::{VersionMismatch, BUILT_FOR_LIBRARY_VERSION};
+use crate::tttr::OverflowCompression;
+use crate::selftest::{SelfTest, SelfTestReport};
+use crate::builder::DeviceBuilder;
+use crate::eventfilter::MainEventFilter;
+use crate::whiterabbit::{WhiteRabbit, WrStatus, WrTerminal};
+use crate::extfpga::ExtFpga;
+#[cfg(feature = "MHLib")]
+use crate::enumeration;
+#[cfg(feature = "MHLib")]
+use crate::version::{VersionStrictness, check_versions, version_strictness};
+use crate::SettingsCache;
+use crate::transaction::{self, TransactionError};
 use crate::{available_devices, MHDeviceIterator};
 
 
@@ -24,7 +42,7 @@ pub fn photon_special(photon : u32) -> bool {
 #[inline]
 /// six highest bits other than the overflow bit
 pub fn photon_to_channel(photon : u32) -> u8 {
-    (photon & mhconsts::CHANNEL) as u8
+    ((photon & mhconsts::CHANNEL) >> 25) as u8
 }
 
 #[allow(dead_code)]
@@ -38,7 +56,7 @@ pub fn photon_to_arrival_t2(photon : u32) -> u32 {
 #[inline]
 /// 7th to 7+15 = 22nd bit from high bits, 15 bit output
 pub fn photon_to_arrival_t3(photon : u32) -> u16 {
-    (photon & mhconsts::HISTOTAG_T3) as u16
+    ((photon & mhconsts::HISTOTAG_T3) >> 10) as u16
 }
 
 
@@ -49,13 +67,45 @@ pub fn photon_to_sync_counter(photon : u32) -> u16 {
     (photon & mhconsts::SYNCTAG) as u16
 }
 
+/// What `set_from_config_with_policy` does with the settings of a
+/// configuration that need a feature the device lacks, so that one
+/// configuration can be shared by devices with different features.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MissingFeaturePolicy {
+    /// Refuse the configuration without applying any of it
+    Error,
+    /// Leave those settings out with a warning and apply the rest
+    #[default]
+    SkipWithWarning,
+}
+
+/// The Row and Main Filter bitfields selecting `use_channels` and
+/// `pass_channels`, numbered as in `set_input_*`, on the model of `multiharp`.
+pub(crate) fn row_filter_channels<MH : MultiHarpDevice>(multiharp : &MH, use_channels : &[i32], pass_channels : &[i32])
+-> CheckedResult<Vec<RowFilterChannels>, i32> {
+    let num_channels = multiharp.num_input_channels().map_err(PatinaError::MultiHarpError)?;
+    if let Some(channel) = use_channels.iter().chain(pass_channels).find(|c| **c < 0 || **c >= num_channels) {
+        return Err(PatinaError::ArgumentError(
+            "channel".to_string(),
+            *channel,
+            format!("Channel must be between 0 and {}", num_channels - 1))
+        );
+    }
+    let model = multiharp.model().map_err(PatinaError::MultiHarpError)?;
+    let capabilities = model.capabilities()
+        .ok_or_else(|| PatinaError::FeatureNotAvailable(format!("Row layout of {}", model)))?;
+    capabilities.row_filter_channels(use_channels, pass_channels)
+        .map_err(PatinaError::FeatureNotAvailable)
+}
+
 /// A trait for MultiHarp devices -- must implement
 /// all of the below methods.
 #[allow(unused_variables)]
 pub trait MultiHarpDevice : Sized {
 
     /// Calls many `set_` functions to set the device with
-    /// the configuration provided. TODO make this report failures!
+    /// the configuration provided. Failures are printed and skipped: use
+    /// `with_settings_transaction` to stop at the first one and undo the rest.
     fn set_from_config(&mut self, config : &MultiHarpConfig) -> () {
 
         if let Some(sync_div) = config.sync_div {
@@ -169,6 +219,163 @@ pub trait MultiHarpDevice : Sized {
         }
     }
 
+    /// `set_from_config`, first checking the settings against the
+    /// device's features (see `MultiHarpConfig::missing_features`) and
+    /// handling those it lacks by `policy`. Other failures are printed
+    /// and skipped as by `set_from_config`.
+    ///
+    /// ## Returns
+    ///
+    /// * `Vec<&'static str>` - The settings left out, empty with
+    ///   `MissingFeaturePolicy::Error`.
+    ///
+    /// ## Errors
+    ///
+    /// * `PatinaError::FeatureNotAvailable` - With `MissingFeaturePolicy::Error`,
+    ///   naming every setting the device can't apply.
+    /// * `MultiHarpError` - If the features can't be read.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// use multi_harp_patina::*;
+    ///
+    /// let mut mh = DebugMultiHarp150::default();
+    /// mh.set_features(FeatureMasks::Tttr as i32 | FeatureMasks::Markers as i32);
+    /// let config = MultiHarpConfig { sync_div : Some(2), trigger_output : Some(100), ..Default::default() };
+    ///
+    /// assert!(mh.set_from_config_with_policy(&config, MissingFeaturePolicy::Error).is_err());
+    /// let skipped = mh.set_from_config_with_policy(&config, MissingFeaturePolicy::SkipWithWarning).unwrap();
+    /// assert_eq!(skipped, vec!["trigger_output"]);
+    /// assert_eq!(mh.current_settings().sync_div, Some(2));
+    /// ```
+    fn set_from_config_with_policy(
+        &mut self,
+        config : &MultiHarpConfig,
+        policy : MissingFeaturePolicy,
+    ) -> CheckedResult<Vec<&'static str>, i32> {
+        let features = self.get_features().map_err(PatinaError::MultiHarpError)?;
+        let missing = config.missing_features(features);
+        if !missing.is_empty() && policy == MissingFeaturePolicy::Error {
+            return Err(PatinaError::FeatureNotAvailable(missing.iter()
+                .map(|(setting, feature)| format!("{:?} (for `{}`)", feature, setting))
+                .collect::<Vec<_>>()
+                .join(", ")
+            ));
+        }
+        let serial = self.get_serial();
+        for (setting, feature) in missing.iter() {
+            let message = format!("Skipping `{}`: {:?} is not available on this device", setting, feature);
+            events::record(&serial, DeviceEventKind::Warning, message.clone());
+            eprintln!("Warning: {}", message);
+        }
+        self.set_from_config(&config.supported_by(features));
+        Ok(missing.into_iter().map(|(setting, _)| setting).collect())
+    }
+
+    /// Loads the configuration last stored in `cache` under this device's
+    /// serial number and applies it with `set_from_config`. Intended to be
+    /// called right after `open` and `init`.
+    /// 
+    /// ## Returns
+    /// 
+    /// * `bool` - `true` if settings were found and applied, `false` if
+    ///   nothing is cached for this device.
+    fn apply_cached_settings(&mut self, cache : &SettingsCache) -> std::io::Result<bool> {
+        match cache.load(&self.get_serial())? {
+            Some(config) => {
+                self.set_from_config(&config);
+                Ok(true)
+            },
+            None => Ok(false),
+        }
+    }
+
+    /// Applies `config` with `set_from_config` and remembers it in `cache`
+    /// as the latest settings for this device.
+    fn set_from_config_cached(&mut self, config : &MultiHarpConfig, cache : &SettingsCache) -> std::io::Result<()> {
+        self.set_from_config(config);
+        cache.store(&self.get_serial(), config)
+    }
+
+    /// The settings known to be applied to the device, which
+    /// `with_settings_transaction` rolls back to. Settings left `None`
+    /// are unknown.
+    fn current_settings(&self) -> MultiHarpConfig { MultiHarpConfig::default() }
+
+    /// The settings in effect on the device: those in `current_settings`,
+    /// with the binning and input enables queried from the device when
+    /// they weren't tracked. Used to check what `set_from_config` actually
+    /// applied when some of its setters failed.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// use multi_harp_patina::*;
+    ///
+    /// let mut mh = DebugMultiHarp150::default();
+    /// mh.init(MeasurementMode::T3, ReferenceClock::Internal).unwrap();
+    ///
+    /// // The binning is out of range, so only the sync divider is applied
+    /// let config = MultiHarpConfig { sync_div : Some(2), binning : Some(99), ..Default::default() };
+    /// mh.set_from_config(&config);
+    /// let applied = mh.snapshot_config().unwrap();
+    /// assert_eq!(applied.sync_div, Some(2));
+    /// assert_eq!(applied.binning, Some(0));
+    /// ```
+    fn snapshot_config(&self) -> MultiHarpResult<MultiHarpConfig> {
+        let mut config = self.current_settings();
+        if config.binning.is_none() && self.get_measurement_mode() != mhconsts::MeasurementMode::T2 {
+            let (base_resolution, _) = self.get_base_resolution()?;
+            let resolution = self.get_resolution()?;
+            if base_resolution > 0.0 && resolution > 0.0 {
+                config.binning = Some((resolution / base_resolution).log2().round() as i32);
+            }
+        }
+        if config.input_enables.is_none() {
+            config.input_enables = Some(self.get_input_channel_enables()?.into_iter()
+                .enumerate()
+                .map(|(channel, enable)| (channel as i32, enable))
+                .collect()
+            );
+        }
+        Ok(config)
+    }
+
+    /// Applies the settings `build` puts in an empty `MultiHarpConfig`, in
+    /// the order of `set_from_config`, as one transaction: if any setter
+    /// fails, the settings already applied are restored to their values
+    /// from `current_settings` and the rest aren't attempted.
+    ///
+    /// ## Errors
+    ///
+    /// * `TransactionError` - Naming the setting that failed and why, and
+    ///   any setting that couldn't be restored.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// use multi_harp_patina::*;
+    ///
+    /// let mut mh = DebugMultiHarp150::default();
+    /// let error = mh.with_settings_transaction(|config| {
+    ///     config.sync_div = Some(4);
+    ///     config.binning = Some(1000);
+    /// }).unwrap_err();
+    /// assert_eq!(error.setting, "binning");
+    /// assert_eq!(mh.current_settings().sync_div, Some(1));
+    /// ```
+    fn with_settings_transaction<F>(&mut self, build : F) -> Result<(), TransactionError>
+    where F : FnOnce(&mut MultiHarpConfig) {
+        transaction::run(self, build)
+    }
+
+    /// Opens, initializes and configures a device in one call, closing it
+    /// again if any step fails. See `DeviceBuilder`.
+    fn builder() -> DeviceBuilder<Self> {
+        DeviceBuilder::new()
+    }
+
     // Open a MultiHarp device by index.
     /// 
     /// ## Arguments
@@ -243,6 +450,19 @@ pub trait MultiHarpDevice : Sized {
         Ok(("".to_string(), "".to_string(), "".to_string()))
     }
 
+    /// Parses the model string from `get_hardware_info` into a `DeviceModel`,
+    /// whose `capabilities()` describe the channel count, base resolution,
+    /// and external FPGA support of the hardware found.
+    fn model(&self) -> MultiHarpResult<DeviceModel> {
+        self.get_hardware_info().map(|(model, _, _)| DeviceModel::from_model_string(&model))
+    }
+
+    /// Returns the setting ranges and record layout for the current
+    /// measurement mode and this device's model.
+    fn limits(&self) -> MultiHarpResult<Limits> {
+        Ok(Limits::new(self.get_measurement_mode(), Some(&self.model()?)))
+    }
+
     /// Returns the base resolution in picoseconds -- the finest possible bins --
     /// as well as the total number of allowed bins.
     /// 
@@ -273,13 +493,7 @@ pub trait MultiHarpDevice : Sized {
     /// 
     /// * `sync_div` - The sync divider to set. Must be between 1 and 16. 
     fn set_sync_div(&mut self, sync_div : i32) -> CheckedResult<(), i32>{
-        if sync_div < mhconsts::SYNCDIVMIN || sync_div > mhconsts::SYNCDIVMAX {
-            return Err(PatinaError::ArgumentError(
-                "sync_div".to_string(),
-                sync_div,
-                format!("Sync divider must be between {} and {}", mhconsts::SYNCDIVMIN, mhconsts::SYNCDIVMAX))
-            );
-        }
+        let sync_div = validation::validate(&self.get_serial(), "sync_div", sync_div, mhconsts::SYNCDIVMIN..=mhconsts::SYNCDIVMAX, "Sync divider")?;
         Ok(())
     }
 
@@ -292,13 +506,7 @@ pub trait MultiHarpDevice : Sized {
     /// 
     /// * `edge` - The edge of the sync signal to trigger on.
     fn set_sync_edge_trigger(&mut self, level : i32, edge : mhconsts::TriggerEdge) -> CheckedResult<(), i32>{
-        if level < mhconsts::TRGLVLMIN || level > mhconsts::TRGLVLMAX {
-            return Err(PatinaError::ArgumentError(
-                "level".to_string(),
-                level,
-                format!("Level must be between {} and {}", mhconsts::TRGLVLMIN, mhconsts::TRGLVLMAX))
-            );
-        }
+        let level = validation::validate(&self.get_serial(), "level", level, mhconsts::TRGLVLMIN..=mhconsts::TRGLVLMAX, "Level")?;
         Ok(())
     }
 
@@ -308,13 +516,7 @@ pub trait MultiHarpDevice : Sized {
     /// 
     /// * `offset` - The offset to set in picoseconds. Must be between -99999 and 99999 ps.
     fn set_sync_channel_offset(&mut self, offset : i32) -> CheckedResult<(), i32>{
-        if offset < mhconsts::CHANNEL_OFFS_MIN || offset > mhconsts::CHANNEL_OFFS_MAX {
-            return Err(PatinaError::ArgumentError(
-                "offset".to_string(),
-                offset,
-                format!("Channel offset must be between {} and {}", mhconsts::CHANNEL_OFFS_MIN, mhconsts::CHANNEL_OFFS_MAX))
-            );
-        }
+        let offset = validation::validate(&self.get_serial(), "offset", offset, mhconsts::CHANNEL_OFFS_MIN..=mhconsts::CHANNEL_OFFS_MAX, "Channel offset")?;
         Ok(())
     }
 
@@ -333,13 +535,7 @@ pub trait MultiHarpDevice : Sized {
     /// 
     /// * `deadtime` - The dead time to set in picoseconds.
     fn set_sync_dead_time(&mut self, on : bool, deadtime : i32) -> CheckedResult<(), i32>{
-        if deadtime < mhconsts::EXTDEADMIN || deadtime > mhconsts::EXTDEADMAX {
-            return Err(PatinaError::ArgumentError(
-                "deadtime".to_string(),
-                deadtime,
-                format!("Dead time must be between {} and {}", mhconsts::EXTDEADMIN, mhconsts::EXTDEADMAX))
-            );
-        }
+        let deadtime = validation::validate(&self.get_serial(), "deadtime", deadtime, mhconsts::EXTDEADMIN..=mhconsts::EXTDEADMAX, "Dead time")?;
         Ok(())    
     }
 
@@ -355,13 +551,7 @@ pub trait MultiHarpDevice : Sized {
     /// * `edge` - The edge of the input signal to trigger on.
     /// 
     fn set_input_edge_trigger(&mut self, channel : i32, level : i32, edge : mhconsts::TriggerEdge) -> CheckedResult<(), i32>{
-        if level < mhconsts::TRGLVLMIN || level > mhconsts::TRGLVLMAX {
-            return Err(PatinaError::ArgumentError(
-                "level".to_string(),
-                level,
-                format!("Level must be between {} and {}", mhconsts::TRGLVLMIN, mhconsts::TRGLVLMAX))
-            );
-        }
+        let level = validation::validate(&self.get_serial(), "level", level, mhconsts::TRGLVLMIN..=mhconsts::TRGLVLMAX, "Level")?;
         Ok(())
     }
 
@@ -375,13 +565,7 @@ pub trait MultiHarpDevice : Sized {
     /// 
     /// * `offset` - The offset to set in picoseconds. Must be between -99999 and 99999 ps.
     fn set_input_channel_offset(&mut self, channel : i32, offset : i32) -> CheckedResult<(), i32>{
-        if offset < mhconsts::CHANNEL_OFFS_MIN || offset > mhconsts::CHANNEL_OFFS_MAX {
-            return Err(PatinaError::ArgumentError(
-                "offset".to_string(),
-                offset,
-                format!("Channel offset must be between {} and {}", mhconsts::CHANNEL_OFFS_MIN, mhconsts::CHANNEL_OFFS_MAX))
-            );
-        }
+        let offset = validation::validate(&self.get_serial(), "offset", offset, mhconsts::CHANNEL_OFFS_MIN..=mhconsts::CHANNEL_OFFS_MAX, "Channel offset")?;
         Ok(())
     }
 
@@ -407,13 +591,7 @@ pub trait MultiHarpDevice : Sized {
     /// 
     /// * `deadtime` - The dead time to set in picoseconds.
     fn set_input_dead_time(&mut self, channel : i32, on : bool, deadtime : i32) -> CheckedResult<(), i32> {
-        if deadtime < mhconsts::EXTDEADMIN || deadtime > mhconsts::EXTDEADMAX {
-            return Err(PatinaError::ArgumentError(
-                "deadtime".to_string(),
-                deadtime,
-                format!("Dead time must be between {} and {}", mhconsts::EXTDEADMIN, mhconsts::EXTDEADMAX))
-            );
-        }
+        let deadtime = validation::validate(&self.get_serial(), "deadtime", deadtime, mhconsts::EXTDEADMIN..=mhconsts::EXTDEADMAX, "Dead time")?;
         Ok(())
     }
 
@@ -436,13 +614,7 @@ pub trait MultiHarpDevice : Sized {
     /// 
     /// * `stopcount` - The number of counts to stop on. Must be between 1 and 4294967295.
     fn set_stop_overflow(&mut self, stop_overflow : bool, stopcount : u32) -> CheckedResult<(), u32> {
-        if stopcount < mhconsts::STOPCNTMIN {
-            return Err(PatinaError::ArgumentError(
-                "stopcount".to_string(),
-                stopcount,
-                format!("Stop count must be between {} and {}", mhconsts::STOPCNTMIN, mhconsts::STOPCNTMAX))
-            );
-        }
+        validation::validate(&self.get_serial(), "stopcount", stopcount, mhconsts::STOPCNTMIN..=mhconsts::STOPCNTMAX, "Stop count")?;
 
         Ok(())
     }
@@ -455,13 +627,7 @@ pub trait MultiHarpDevice : Sized {
     /// * `binning` - The binning to set. Must be between 0 and 24 (corresponding to
     /// pooling 2^0 to 2^24 bins).
     fn set_binning(&mut self, binning : i32) -> CheckedResult<(), i32> {
-        if binning < 0 || binning > mhconsts::BINSTEPSMAX {
-            return Err(PatinaError::ArgumentError(
-                "binning".to_string(),
-                binning,
-                format!("Binning must be between 0 and {}", mhconsts::BINSTEPSMAX))
-            );
-        }
+        let binning = validation::validate(&self.get_serial(), "binning", binning, 0..=mhconsts::BINSTEPSMAX, "Binning")?;
         Ok(())
     }
 
@@ -475,13 +641,7 @@ pub trait MultiHarpDevice : Sized {
     /// - `set_input_channel_offset`
     /// - `set_sync_channel_offset`
     fn set_offset(&mut self, offset : i32) -> CheckedResult<(), i32> {
-        if offset < mhconsts::OFFSETMIN || offset > mhconsts::OFFSETMAX {
-            return Err(PatinaError::ArgumentError(
-                "offset".to_string(),
-                offset,
-                format!("Offset must be between {} and {}", mhconsts::OFFSETMIN, mhconsts::OFFSETMAX))
-            );
-        }
+        let offset = validation::validate(&self.get_serial(), "offset", offset, mhconsts::OFFSETMIN..=mhconsts::OFFSETMAX, "Offset")?;
         Ok(())
     }
 
@@ -498,13 +658,7 @@ pub trait MultiHarpDevice : Sized {
     /// 
     /// * `CheckedResult<i32, i32>` - The actual length of the histogram.
     fn set_histogram_len(&mut self, lencode : i32) -> CheckedResult<i32, i32> {
-        if lencode < mhconsts::MINLENCODE || lencode > mhconsts::MAXLENCODE {
-            return Err(PatinaError::ArgumentError(
-                "lencode".to_string(),
-                lencode,
-                format!("Length code must be between {} and {}", mhconsts::MINLENCODE, mhconsts::MAXLENCODE))
-            );
-        }
+        let lencode = validation::validate(&self.get_serial(), "lencode", lencode, mhconsts::MINLENCODE..=mhconsts::MAXLENCODE, "Length code")?;
         Ok(65536)
     }
 
@@ -541,13 +695,7 @@ pub trait MultiHarpDevice : Sized {
     /// 
     /// * `period` - The period to set in units of 100 ns.
     fn set_trigger_output(&mut self, period : i32) -> CheckedResult<(), i32>{
-        if period < mhconsts::TRIGOUTMIN || period > mhconsts::TRIGOUTMAX {
-            return Err(PatinaError::ArgumentError(
-                "period".to_string(),
-                period,
-                format!("Period must be between {} and {}", mhconsts::TRIGOUTMIN, mhconsts::TRIGOUTMAX))
-            );
-        }
+        let period = validation::validate(&self.get_serial(), "period", period, mhconsts::TRIGOUTMIN..=mhconsts::TRIGOUTMAX, "Period")?;
         Ok(())
     }
 
@@ -562,6 +710,11 @@ pub trait MultiHarpDevice : Sized {
     /// - `set_measurement_control_mode` - If the software library version is >3.1, this
     /// can be used to bypass the `acquistion_time` parameter entirely, permitting very
     /// very long acquisitions.
+    ///
+    /// ## Errors
+    ///
+    /// - `PatinaError::ReferenceLost` if the device was initialized with an external
+    ///   reference clock that is not present (see `check_reference_clock`).
     fn start_measurement(&mut self, acquisition_time : i32) -> CheckedResult<(), i32>;
 
     /// Stops the current measurement. Must be called after `start_measurement`, even
@@ -577,7 +730,8 @@ pub trait MultiHarpDevice : Sized {
     fn ctc_status(&self) -> MultiHarpResult<bool>;
 
     /// Fills an existing buffer with the arrival time histogram from the device.
-    /// TODO check if the buffer is the right size.
+    /// Returns `PatinaError::WrongMode` unless the device was initialized in
+    /// Histogramming mode. TODO check if the buffer is the right size.
     /// 
     /// ## Arguments
     /// 
@@ -634,9 +788,129 @@ pub trait MultiHarpDevice : Sized {
     fn get_count_rate(&self, channel : i32) -> CheckedResult<i32, i32> {Ok(1e5 as i32)}
 
     /// Returns the count rates of all channels in photons per second and the sync rate
-    /// in Hz.
+    /// in Hz. The rates are only updated every 100 ms, so they read as zero right after
+    /// `init` -- use `measure_rates` to wait for valid ones.
     fn get_all_count_rates(&self) -> MultiHarpResult<(i32, Vec<i32>)> {Ok((78e6 as i32, vec![1e5 as i32; 4]))}
 
+    /// The count rates as `MHLib` writes them: one per channel the model
+    /// can have, which on some models is more than `num_input_channels`
+    /// (the extra entries are 0). `get_all_count_rates` returns the first
+    /// `num_input_channels` of these.
+    fn get_all_count_rates_full(&self) -> MultiHarpResult<(i32, Vec<i32>)> {
+        self.get_all_count_rates()
+    }
+
+    /// The sync rate and the count rates of the enabled input channels
+    /// only, as `(channel, rate)`.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// use multi_harp_patina::*;
+    ///
+    /// let mut mh = DebugMultiHarp150::default();
+    /// mh.set_input_channel_enable(1, false).unwrap();
+    /// let (_, rates) = mh.get_enabled_count_rates().unwrap();
+    /// assert_eq!(rates.iter().map(|(channel, _)| *channel).collect::<Vec<_>>(), vec![0, 2, 3]);
+    /// ```
+    fn get_enabled_count_rates(&self) -> MultiHarpResult<(i32, Vec<(i32, i32)>)> {
+        let (sync, rates) = self.get_all_count_rates()?;
+        let enabled = self.get_input_channel_enables()?;
+        let rates = rates.into_iter().enumerate()
+            .filter(|(channel, _)| enabled.get(*channel).copied().unwrap_or(false))
+            .map(|(channel, rate)| (channel as i32, rate))
+            .collect();
+        Ok((sync, rates))
+    }
+
+    /// Sets the Main Filter, which acts on the aggregated events of all
+    /// rows, without enabling it. Time-tagging modes only, from `MHLib` 3.1.
+    ///
+    /// ### See also
+    ///
+    /// - `suggest_event_filter` - Settings keeping the rates within the USB bandwidth.
+    fn set_main_event_filter(&mut self, filter : &MainEventFilter) -> CheckedResult<(), i32> {
+        Err(PatinaError::NotImplemented)
+    }
+
+    /// When the Main Filter is disabled, all events are passed.
+    fn enable_main_event_filter(&mut self, enable : bool) -> CheckedResult<(), i32> {
+        Err(PatinaError::NotImplemented)
+    }
+
+    /// In filter test mode no events are transferred into the FIFO, so the
+    /// rates after the filters can be read with `get_main_filtered_rates`
+    /// during a measurement whose unfiltered rate would overrun it.
+    fn set_filter_test_mode(&mut self, test_mode : bool) -> CheckedResult<(), i32> {
+        Err(PatinaError::NotImplemented)
+    }
+
+    /// The sync rate and the count rates of all channels after the Main
+    /// Filter. A measurement must be running, and the rates are updated
+    /// every 100 ms as with `get_all_count_rates`.
+    fn get_main_filtered_rates(&self) -> CheckedResult<(i32, Vec<i32>), i32> {
+        Err(PatinaError::NotImplemented)
+    }
+
+    /// Sets the Row Filter of `row`, which acts on the input channels of
+    /// that row before the Main Filter, without enabling it.
+    /// `use_channels` and `pass_channels` mark channels of the row, see
+    /// `ModelCapabilities::row_filter_channels`, and not the sync channel.
+    /// Time-tagging modes only, from `MHLib` 3.1.
+    ///
+    /// ### See also
+    ///
+    /// - `set_row_event_filters` - The Row Filters for lists of channels.
+    fn set_row_event_filter(
+        &mut self, row : i32, time_range : i32, match_cnt : i32, inverse : bool,
+        use_channels : ChannelMask, pass_channels : ChannelMask,
+    ) -> CheckedResult<(), i32> {
+        Err(PatinaError::NotImplemented)
+    }
+
+    /// When the Row Filter of `row` is disabled, all its events are passed.
+    fn enable_row_event_filter(&mut self, row : i32, enable : bool) -> CheckedResult<(), i32> {
+        Err(PatinaError::NotImplemented)
+    }
+
+    /// Sets and enables, with the settings of `filter`, the Row Filter of
+    /// every row containing one of its channels, and disables those of
+    /// the other rows.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// use multi_harp_patina::*;
+    ///
+    /// let mut mh = DebugMultiHarp150::default();
+    /// mh.init(MeasurementMode::T2, ReferenceClock::Internal).unwrap();
+    /// mh.set_row_event_filters(&MainEventFilter::coincidences(&[0, 1], 1000)).unwrap();
+    ///
+    /// // Channels 2 and 3 are in the filtered row, but neither used nor passed
+    /// let (_, rates) = mh.get_row_filtered_rates().unwrap();
+    /// assert_eq!(&rates[2..], &[0, 0]);
+    /// ```
+    fn set_row_event_filters(&mut self, filter : &MainEventFilter) -> CheckedResult<(), i32> {
+        let rows = row_filter_channels(self, &filter.use_channels, &filter.pass_channels)?;
+        for row in rows {
+            if !row.is_empty() {
+                self.set_row_event_filter(
+                    row.row, filter.time_range, filter.match_count, filter.inverse,
+                    row.use_channels, row.pass_channels,
+                )?;
+            }
+            self.enable_row_event_filter(row.row, !row.is_empty())?;
+        }
+        Ok(())
+    }
+
+    /// The sync rate and the count rates of all channels after the Row
+    /// Filters, before the Main Filter. A measurement must be running,
+    /// and the rates are updated every 100 ms as with `get_all_count_rates`.
+    fn get_row_filtered_rates(&self) -> CheckedResult<(i32, Vec<i32>), i32> {
+        Err(PatinaError::NotImplemented)
+    }
+
     /// Returns the set flags of the device, interpretable using
     /// the bitmasks in `mhconsts`.
     /// 
@@ -645,6 +919,17 @@ pub trait MultiHarpDevice : Sized {
     /// - `get_warnings` - To get the warning flags.
     fn get_flags(&self) -> MultiHarpResult<i32> {Ok(0)}
 
+    /// The flags set on the device, decoded from `get_flags`
+    fn active_flags(&self) -> MultiHarpResult<Vec<mhconsts::Flags>> {
+        self.get_flags().map(mhconsts::Flags::from_bits)
+    }
+
+    /// Returns the features of the device as a bitmask of
+    /// `FeatureMasks`.
+    fn get_features(&self) -> MultiHarpResult<i32> {
+        Ok(mhconsts::ALL_FEATURES)
+    }
+
     /// Returns the set warnings of the device, interpretable using
     /// the bitmasks in `mhconsts`. Prior to this call, you must call
     /// `get_all_count_rates` or `get_sync_rate` and `get_count_rate` for
@@ -656,14 +941,42 @@ pub trait MultiHarpDevice : Sized {
     /// - `get_warnings_text`
     fn get_warnings(&self) -> MultiHarpResult<i32> {Ok(0)}
 
+    /// The warnings set on the device, decoded from `get_warnings`. As
+    /// with `get_warnings`, read the count rates first.
+    fn active_warnings(&self) -> MultiHarpResult<Vec<mhconsts::Warning>> {
+        self.get_warnings().map(mhconsts::Warning::from_bits)
+    }
+
 
     /// Returns a human-readable string to interpret the device warnings
     /// 
     /// ### See also
     /// - `get_warnings`
     /// - `get_flags`
+    /// - `get_warnings_text_into` - Without allocating, for frequent polling
     fn get_warnings_text(&self) -> MultiHarpResult<String> {Ok("No warnings".to_string())}
 
+    /// Writes the text of `get_warnings_text` into `buffer` and returns it,
+    /// without allocating, so the warnings can be polled many times a
+    /// second with one buffer.
+    /// 
+    /// ## Example
+    /// 
+    /// ```
+    /// use multi_harp_patina::*;
+    /// 
+    /// let mh = DebugMultiHarp150::default();
+    /// let mut buffer = Box::new([0u8; WARNLEN]);
+    /// for _ in 0..3 {
+    ///     assert_eq!(mh.get_warnings_text_into(&mut buffer).unwrap(), "No warnings");
+    /// }
+    /// ```
+    fn get_warnings_text_into<'a>(&self, buffer : &'a mut [u8; mhconsts::WARNLEN]) -> MultiHarpResult<&'a str> {
+        let text = "No warnings";
+        buffer[..text.len()].copy_from_slice(text.as_bytes());
+        Ok(std::str::from_utf8(&buffer[..text.len()]).unwrap_or_default())
+    }
+
     /// Returns the elapsed measurement time in milliseconds. When
     /// using the `SwStartSwStop` mode, these results will be less accurate.
     fn get_elapsed_measurement_time(&self) -> MultiHarpResult<f64> {Ok(0.0)}
@@ -689,7 +1002,8 @@ pub trait MultiHarpDevice : Sized {
     fn get_start_time(&self) -> MultiHarpResult<(u32, u32, u32)> {Ok((0, 0, 0))}
 
     /// Loads a buffer with the arrival time data from the device. Returns the actual
-    /// number of counts read. Only meaningful in TTTR mode.
+    /// number of counts read. Only meaningful in TTTR mode -- returns
+    /// `PatinaError::WrongMode` if the device was initialized in Histogramming mode.
     /// 
     /// ## Arguments
     /// 
@@ -720,16 +1034,42 @@ pub trait MultiHarpDevice : Sized {
     /// * `holdoff_time` - The holdoff time to set in nanoseconds. Must be between 0 and 25500 ns
     /// (25.5 microseconds)
     fn set_marker_holdoff_time(&mut self, holdofftime : i32) -> CheckedResult<(), i32> {
-        if holdofftime < mhconsts::HOLDOFFMIN || holdofftime > mhconsts::HOLDOFFMAX {
-            return Err(PatinaError::ArgumentError(
-                "holdofftime".to_string(),
-                holdofftime,
-                format!("Holdoff time must be between {} and {}", mhconsts::HOLDOFFMIN, mhconsts::HOLDOFFMAX))
-            );
-        }
+        let holdofftime = validation::validate(&self.get_serial(), "holdofftime", holdofftime, mhconsts::HOLDOFFMIN..=mhconsts::HOLDOFFMAX, "Holdoff time")?;
         Ok(())
     }
 
+    /// Sets the edges, enables and holdoff time of the markers as one
+    /// transaction (see `with_settings_transaction`), so a failure leaves
+    /// the marker inputs as they were rather than partly reconfigured.
+    /// Prefer this to the individual marker setters.
+    ///
+    /// ## Errors
+    ///
+    /// * `TransactionError` - Naming the marker setting that failed.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// use multi_harp_patina::*;
+    ///
+    /// let mut mh = DebugMultiHarp150::default();
+    /// let markers = MarkerConfig {
+    ///     enables : [true, true, false, false],
+    ///     edges : [TriggerEdge::Rising, TriggerEdge::Falling, TriggerEdge::Rising, TriggerEdge::Rising],
+    ///     holdoff_ns : 100,
+    /// };
+    /// mh.set_marker_config(&markers).unwrap();
+    /// assert_eq!(mh.marker_config(), Some(markers));
+    /// ```
+    fn set_marker_config(&mut self, markers : &MarkerConfig) -> Result<(), TransactionError> {
+        self.with_settings_transaction(|config| markers.apply_to(config))
+    }
+
+    /// The marker settings from `current_settings`, if all are known.
+    fn marker_config(&self) -> Option<MarkerConfig> {
+        MarkerConfig::from_config(&self.current_settings())
+    }
+
     /// The setting is useful when data rates are very low, so that the sync signals
     /// are far more common than photons (i.e. << 1 photon per 1000 pulses) and overflows
     /// happen regularly long before a useful amount of data arrives. The hardware will
@@ -744,25 +1084,304 @@ pub trait MultiHarpDevice : Sized {
     /// 
     /// * `hold_time` - The hold time to set in milliseconds. Must be between 0 and 255 ms.
     fn set_overflow_compression(&mut self, holdtime : i32) -> CheckedResult<(), i32> {
-        if holdtime < mhconsts::HOLDTIMEMIN || holdtime > mhconsts::HOLDTIMEMAX {
-            return Err(PatinaError::ArgumentError(
-                "holdtime".to_string(),
-                holdtime,
-                format!("Hold time must be between {} and {}", mhconsts::HOLDTIMEMIN, mhconsts::HOLDTIMEMAX))
-            );
-        }
+        let holdtime = validation::validate(&self.get_serial(), "holdtime", holdtime, mhconsts::HOLDTIMEMIN..=mhconsts::HOLDTIMEMAX, "Hold time")?;
         Ok(())
     }
 
     fn get_index(&self) -> i32;
     fn get_serial(&self) -> String;
+
+    /// Returns the mode the device was last initialized in.
+    fn get_measurement_mode(&self) -> mhconsts::MeasurementMode;
+
+    /// Returns the reference clock the device was last initialized with.
+    fn get_reference_clock(&self) -> mhconsts::ReferenceClock;
+
+    /// Returns the histogram offset last set with `set_offset`, in nanoseconds.
+    fn get_offset(&self) -> i32 { 0 }
+
+    /// Returns whether each input channel is enabled, as last set with
+    /// `set_input_channel_enable`. Every channel is enabled when the
+    /// device is opened.
+    fn get_input_channel_enables(&self) -> MultiHarpResult<Vec<bool>> {
+        Ok(vec![true; self.num_input_channels()?.max(0) as usize])
+    }
+
+    /// Library and hardware version mismatches found when the device was
+    /// opened with `VersionStrictness::Warn`.
+    fn version_mismatches(&self) -> Vec<VersionMismatch> { Vec::new() }
+
+    /// How the overflow records read from this device count rollovers,
+    /// from the version of `MHLib` in use or, if that can't be read, the
+    /// version the crate was built for.
+    fn overflow_compression(&self) -> OverflowCompression {
+        crate::get_library_version().ok()
+            .and_then(|version| OverflowCompression::for_library_version(&version))
+            .or_else(|| OverflowCompression::for_library_version(BUILT_FOR_LIBRARY_VERSION))
+            .unwrap_or_default()
+    }
+
+    /// Runs the default `SelfTest`: initializes the device in T3 mode and
+    /// checks that its inputs read dark, its flags are clear and a short
+    /// T3 measurement decodes, with one result per check. Leaves the
+    /// device initialized. Use `SelfTest` directly to check a trigger
+    /// output loopback or inputs left connected.
+    fn self_test(&mut self) -> SelfTestReport {
+        SelfTest::new().run(self)
+    }
+
+    /// The last events (errors, warnings, flag changes and applied
+    /// settings) of this device, oldest first. See `set_event_log_capacity`.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// use multi_harp_patina::*;
+    ///
+    /// let mut mh = DebugMultiHarp150::default();
+    /// assert!(mh.set_binning(100).is_err());
+    /// let last = mh.recent_events().pop().unwrap();
+    /// assert_eq!(last.kind, DeviceEventKind::Error);
+    /// ```
+    fn recent_events(&self) -> Vec<DeviceEvent> {
+        events::recent_events(&self.get_serial())
+    }
+
+    /// Reads the reference clock flags from `get_flags`.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// use multi_harp_patina::*;
+    ///
+    /// let mut mh = DebugMultiHarp150::default();
+    /// mh.init(MeasurementMode::T3, ReferenceClock::PpsGps).unwrap();
+    /// let status = mh.clock_status().unwrap();
+    /// assert!(status.is_locked());
+    /// ```
+    fn clock_status(&self) -> MultiHarpResult<ClockStatus> {
+        Ok(ClockStatus::from_flags(self.get_reference_clock(), self.get_flags()?))
+    }
+
+    /// Returns `PatinaError::HardwareError`, with the `get_debug_info`
+    /// text, if the device raised `FLAG_SYSERROR`. Called by
+    /// `start_measurement`, so that a faulty device is reported before
+    /// it acquires rather than by missing data.
+    fn check_system_error(&self) -> CheckedResult<(), i32> {
+        if self.get_flags()? & mhconsts::FLAG_SYSERROR != 0 {
+            return Err(hardware_error(self, None));
+        }
+        Ok(())
+    }
+
+    /// Returns the `ClockStatus`, or `PatinaError::ReferenceLost` if the
+    /// device was initialized with an external (10 MHz or GPS/PPS)
+    /// reference that is absent. Called by `start_measurement` so that
+    /// a measurement never silently runs on the drifting internal clock.
+    fn check_reference_clock(&self) -> CheckedResult<ClockStatus, i32> {
+        let status = self.clock_status()?;
+        if !status.is_locked() {
+            return Err(PatinaError::ReferenceLost(status.reference));
+        }
+        Ok(status)
+    }
+
+    /// The White Rabbit core of the device, with its MAC address, init
+    /// script, mode and lock behind one handle that applies them in
+    /// the right order. Prefer it to the `wrabbit_` methods.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// use multi_harp_patina::*;
+    /// use std::time::Duration;
+    ///
+    /// let mut mh = DebugMultiHarp150::default();
+    /// mh.set_wrabbit_lock_time(Duration::from_millis(20));
+    ///
+    /// let mut wr = mh.white_rabbit();
+    /// wr.set_mac("PATINA").unwrap();
+    /// wr.start(WRMode::Slave).unwrap();
+    /// assert!(wr.wait_for_lock(Duration::from_secs(1)).unwrap());
+    /// assert!(wr.status().unwrap().mac_set);
+    /// ```
+    fn white_rabbit(&mut self) -> WhiteRabbit<'_, Self> {
+        WhiteRabbit::new(self)
+    }
+
+    /// Returns the MAC address of the White Rabbit core as a string of
+    /// length `WR_MAC_LEN`.
+    fn wrabbit_get_mac(&self) -> CheckedResult<String, i32> {
+        Err(PatinaError::NotImplemented)
+    }
+
+    /// Set the MAC address of the White Rabbit core. Must be a string of
+    /// length `WR_MAC_LEN`.
+    ///
+    /// Note: The MAC address must be unique within the network you are using
+    fn wrabbit_set_mac(&mut self, mac : &str) -> CheckedResult<(), usize> {
+        Err(PatinaError::NotImplemented)
+    }
+
+    /// Retrieves the White Rabbit initialization script from the MultiHarp's EEPROM.
+    fn wrabbit_get_init_script(&self) -> CheckedResult<String, i32> {
+        Err(PatinaError::NotImplemented)
+    }
+
+    /// Sets the White Rabbit initialization script in the MultiHarp's EEPROM.
+    /// Lines are separated by a newline character.
+    fn wrabbit_set_init_script(&mut self, script : &str) -> CheckedResult<(), i32> {
+        Err(PatinaError::NotImplemented)
+    }
+
+    /// Set WhiteRabbit link on or off.
+    fn set_wrabbit_link(&mut self, on : bool) -> CheckedResult<(), i32> {
+        Err(PatinaError::NotImplemented)
+    }
+
+    /// Set how the White Rabbit core boots.
+    ///
+    /// ## Arguments
+    ///
+    /// * `boot_from_script` - Whether to boot from the script.
+    ///   If true, boots from script in EEPROM (set with `wrabbit_set_init_script`).
+    ///
+    /// * `reinit_with_mode` - Whether to reinitialize with a new mode
+    ///   (provided in the third argument)
+    ///
+    /// * `mode` - The mode to set the WRabbit to.
+    fn set_wrabbit_mode(&mut self, boot_from_script : bool, reinit_with_mode : bool, mode : WRMode) -> CheckedResult<(), i32> {
+        Err(PatinaError::NotImplemented)
+    }
+
+    /// Used to set the current UTC time of a White Rabbit code for
+    /// a device configured as a WR master. If a slave is connected,
+    /// it will be set to the same time.
+    fn set_wrabbit_time(&mut self, time_high_dw : u32, time_low_dw : u32) -> CheckedResult<(), i32> {
+        Err(PatinaError::NotImplemented)
+    }
+
+    /// Retrieve the UTC time of a MultiHarp's WR core.
+    ///
+    /// ## Returns
+    ///
+    /// * (time_high_dw, time_low_dw, subsec_16_ns) - The time in 3 parts:
+    ///    - `time_high_dw` - The most significant 32 bits of the time in seconds since epoch
+    ///    - `time_low_dw` - The lowest 32 bits of the time in seconds since epoch
+    ///    - `subsec_16_ns` - The subsecond part of the time in 16 ns units.
+    fn get_wrabbit_time(&self) -> CheckedResult<(u32, u32, u32), i32> {
+        Err(PatinaError::NotImplemented)
+    }
+
+    /// The status of the WRabbit core, decoded from the bitfield
+    /// (`WR_STATUS_` masks in `mhconsts`), which is kept in `WrStatus::bits`.
+    fn get_wrabbit_status(&self) -> CheckedResult<WrStatus, i32> {
+        Err(PatinaError::NotImplemented)
+    }
+
+    /// The terminal output of the WR core, once it has received the
+    /// command `gui` (see `MultiHarp150::get_wrabbit_term_output`).
+    fn get_wrabbit_term_output(&self) -> CheckedResult<String, i32> {
+        Err(PatinaError::NotImplemented)
+    }
+
+    /// Polls `get_wrabbit_term_output` for the lines of the WR core's
+    /// terminal, without its escape sequences. See `WrTerminal`.
+    fn wrabbit_terminal(&self) -> WrTerminal<'_, Self> {
+        WrTerminal::new(self)
+    }
+
+    /// The external FPGA interface of a MultiHarp 160, with its links,
+    /// mode and user commands. See `ExtFpga`.
+    ///
+    /// ## Errors
+    ///
+    /// * `PatinaError::FeatureNotAvailable` - If the device doesn't
+    ///   report `FeatureMasks::ExtFpga`.
+    fn ext_fpga(&mut self) -> CheckedResult<ExtFpga<'_, Self>, i32> {
+        ExtFpga::new(self)
+    }
+
+    /// Switches the link `link` to the external FPGA on or off.
+    fn ext_fpga_init_link(&mut self, link : i32, on : bool) -> CheckedResult<(), i32> {
+        Err(PatinaError::NotImplemented)
+    }
+
+    /// The status of the link `link` to the external FPGA.
+    fn ext_fpga_link_status(&self, link : i32) -> CheckedResult<u32, i32> {
+        Err(PatinaError::NotImplemented)
+    }
+
+    /// Sets what is streamed to the external FPGA, and what it loops back.
+    fn ext_fpga_set_mode(&mut self, mode : ExtFpgaMode, loopback : ExtFpgaLoopback) -> CheckedResult<(), i32> {
+        Err(PatinaError::NotImplemented)
+    }
+
+    /// Empties the FIFOs of the streams to and from the external FPGA.
+    fn ext_fpga_reset_stream_fifos(&mut self) -> CheckedResult<(), i32> {
+        Err(PatinaError::NotImplemented)
+    }
+
+    /// Sends a user command to the external FPGA, writing `data` to
+    /// `addr` if `write` or reading `addr` if not. Returns the data of
+    /// the reply.
+    fn ext_fpga_user_command(&mut self, write : bool, addr : u32, data : u32) -> CheckedResult<u32, i32> {
+        Err(PatinaError::NotImplemented)
+    }
+}
+
+/// A `PatinaError::HardwareError` for `error`, with the debug information
+/// of `multiharp` (or why it couldn't be read) followed by its recent events.
+pub(crate) fn hardware_error<M, T>(multiharp : &M, error : Option<MultiHarpError>) -> PatinaError<T>
+where M : MultiHarpDevice, T : std::fmt::Display + std::fmt::Debug {
+    let mut info = multiharp.get_debug_info()
+        .unwrap_or_else(|e| format!("Debug information unavailable: {}", e));
+    let serial = multiharp.get_serial();
+    let recent = events::recent_events(&serial);
+    if !recent.is_empty() {
+        info.push_str("\nRecent events:");
+        for event in recent {
+            info.push_str(&format!("\n{}", event));
+        }
+    }
+    let message = match &error {
+        Some(e) => format!("Hardware error: {}", e),
+        None => "System error flag raised".to_string(),
+    };
+    events::record(&serial, DeviceEventKind::Error, message);
+    PatinaError::HardwareError(error, info)
 }
 
+/// Calls of `MultiHarpDevice` that can be awaited from a tokio runtime
+/// without stalling its executor.
 #[cfg(feature = "async")]
 #[async_trait]
-pub trait AsyncMultiHarpDevice {
-    // Loads from the FIFO buffer asynchronously.
-    // async fn read_fifo_async<'a, 'b>(&'a self, buffer : &'b mut Vec<u32>) -> AsyncCheckedResult<i32, u32>;
+pub trait AsyncMultiHarpDevice : MultiHarpDevice + Send {
+    /// `read_fifo`, with the blocking call to `MHLib` run on tokio's
+    /// blocking thread pool. Resolves with the number of records read
+    /// into `buffer`.
+    ///
+    /// The device is borrowed mutably until the read completes, and
+    /// `buffer` is only written once it has. If the future is dropped
+    /// before then (e.g. by a timeout in `select!`), `buffer` is left as
+    /// it was, but the read still finishes on the blocking pool and its
+    /// records are lost. The device must not be used again until that
+    /// read has completed, as `MHLib` doesn't allow concurrent calls on
+    /// one device.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// use multi_harp_patina::*;
+    ///
+    /// let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    /// let mut mh = DebugMultiHarp150::default();
+    /// mh.start_measurement(100).unwrap();
+    /// let mut buffer = vec![0u32; TTREADMAX];
+    /// let read = runtime.block_on(mh.read_fifo_async(&mut buffer)).unwrap();
+    /// println!("Read {} records", read);
+    /// ```
+    async fn read_fifo_async(&mut self, buffer : &mut Vec<u32>) -> CheckedResult<i32, u32>;
 }
 
 /// A more object-oriented way to
@@ -782,11 +1401,22 @@ pub trait AsyncMultiHarpDevice {
 /// to guard the MultiHarp with a Mutex or other synchronization
 /// primitive.
 #[cfg(feature = "MHLib")]
+#[derive(Debug)]
 pub struct MultiHarp150 {
     index : i32,
     serial : String,
+    /// `(model, part number, version)`, queried once at `open`
+    hardware_info : (String, String, String),
     initialized : bool,
+    mode : mhconsts::MeasurementMode,
+    reference_clock : mhconsts::ReferenceClock,
+    version_mismatches : Vec<VersionMismatch>,
+    offset : i32,
     num_channels : i32,
+    /// Last value set with `set_input_channel_enable`, per channel
+    input_enables : Vec<bool>,
+    /// Every setting applied since the device was opened
+    applied : MultiHarpConfig,
     features : i32, // marks which features are available on this device.
 }
 
@@ -817,6 +1447,10 @@ impl MultiHarpDevice for MultiHarp150 {
     /// - `PatinaError::NoDeviceAvailable` if there are either
     /// no connected `MultiHarp` devices or no available multiple
     /// harp devices when `None` is passed as an argument.
+    /// 
+    /// - `PatinaError::VersionMismatch` if the `MHLib` or hardware
+    ///   version differs from what the crate was built for and
+    ///   `set_version_strictness(VersionStrictness::Error)` was called.
     fn open(index : Option<i32>) -> CheckedResult<Self, i32> {
         if index.is_none() {
             let dev_vec = available_devices();
@@ -837,6 +1471,12 @@ impl MultiHarpDevice for MultiHarp150 {
         }
 
         
+        // Kept until the device is registered as held, so no scan can
+        // open and close it in between
+        let _enumeration = enumeration::enumeration_lock();
+        if enumeration::is_held(index) {
+            return Err(PatinaError::from(MultiHarpError::DeviceBusy));
+        }
         let mut serial = [0 as c_char; 8];
         let mh_result = unsafe { MH_OpenDevice(index, serial.as_mut_ptr()) };
         if mh_result != 0 {
@@ -863,15 +1503,37 @@ impl MultiHarpDevice for MultiHarp150 {
             return Err(PatinaError::from(MultiHarpError::from(features_result)));
         }
 
-        Ok(
-            MultiHarp150 {
-                index,
-                serial: unsafe { CStr::from_ptr(serial.as_mut_ptr()) }.to_str().unwrap().to_string(),
-                initialized: false,
-                num_channels,
-                features,
+        let hardware_info = query_hardware_info(index)?;
+
+        enumeration::hold(index);
+        let mut multiharp = MultiHarp150 {
+            index,
+            serial: unsafe { CStr::from_ptr(serial.as_mut_ptr()) }.to_str().unwrap().to_string(),
+            hardware_info,
+            initialized: false,
+            mode: mhconsts::MeasurementMode::T3,
+            reference_clock: mhconsts::ReferenceClock::Internal,
+            version_mismatches: Vec::new(),
+            offset: 0,
+            num_channels,
+            input_enables: vec![true; num_channels.max(0) as usize],
+            applied: MultiHarpConfig::default(),
+            features,
+        };
+
+        // Dropping `multiharp` on failure closes the device again
+        let strictness = version_strictness();
+        if strictness != VersionStrictness::Ignore {
+            let library = crate::get_library_version()?;
+            let (_, _, hardware) = multiharp.get_hardware_info()?;
+            let mismatches = check_versions(&library, &hardware);
+            if strictness == VersionStrictness::Error && !mismatches.is_empty() {
+                return Err(PatinaError::VersionMismatch(mismatches[0].clone()));
             }
-        )
+            multiharp.version_mismatches = mismatches;
+        }
+
+        Ok(multiharp)
     }
 
     /// Iterate over MultiHarp device indices until the provided serial number
@@ -936,29 +1598,22 @@ impl MultiHarpDevice for MultiHarp150 {
             mh_result,
             {
                 self.initialized = true;
+                self.mode = mode;
+                self.reference_clock = reference_clock;
                 ()
             }
         )
     }
 
     /// Returns the model code of the MultiHarp device, its part number, and its version.
+    /// These don't change while the device is open, so they're read once
+    /// in `open` and cached.
     /// 
     /// ## Returns
     /// 
     /// * `(Model, PartNumber, Version)`
     fn get_hardware_info(&self) -> MultiHarpResult<(String, String, String)> {
-        let mut model_code = [0 as c_char; 24];
-        let mut part_number = [0 as c_char; 8];
-        let mut version = [0 as c_char; 8];
-
-        mh_to_result!(
-            unsafe { MH_GetHardwareInfo(self.index, model_code.as_mut_ptr(), part_number.as_mut_ptr(), version.as_mut_ptr()) },
-            (
-                unsafe { CStr::from_ptr(model_code.as_mut_ptr()) }.to_str().unwrap().to_string(),
-                unsafe { CStr::from_ptr(part_number.as_mut_ptr()) }.to_str().unwrap().to_string(),
-                unsafe { CStr::from_ptr(version.as_mut_ptr()) }.to_str().unwrap().to_string()
-            )
-        )
+        Ok(self.hardware_info.clone())
     }
 
     /// Returns the base resolution in picoseconds -- the finest possible bins --
@@ -992,7 +1647,7 @@ impl MultiHarpDevice for MultiHarp150 {
     /// Should be called on a `MultiHarpError` to get more information.
     fn get_debug_info(&self) -> MultiHarpResult<String> {
         let debug_string = [0 as c_char; mhconsts::DEBUGSTRLEN];
-        let mh_result = unsafe { MH_GetErrorString(debug_string.as_ptr() as *mut c_char, self.index) };
+        let mh_result = unsafe { MH_GetDebugInfo(self.index, debug_string.as_ptr() as *mut c_char) };
         mh_to_result!(
             mh_result,
             unsafe { CStr::from_ptr(debug_string.as_ptr() as *mut c_char) }.to_str().unwrap().to_string()
@@ -1012,15 +1667,12 @@ impl MultiHarpDevice for MultiHarp150 {
     /// 
     /// * `sync_div` - The sync divider to set. Must be between 1 and 16.
     fn set_sync_div(&mut self, sync_div : i32) -> CheckedResult<(), i32> {
-        if sync_div < mhconsts::SYNCDIVMIN || sync_div > mhconsts::SYNCDIVMAX {
-            return Err(PatinaError::ArgumentError(
-                "sync_div".to_string(),
-                sync_div,
-                format!("Sync divider must be between {} and {}", mhconsts::SYNCDIVMIN, mhconsts::SYNCDIVMAX))
-            );
-        } 
+        let sync_div = validation::validate(&self.serial, "sync_div", sync_div, mhconsts::SYNCDIVMIN..=mhconsts::SYNCDIVMAX, "Sync divider")?;
         let mh_result = unsafe { MH_SetSyncDiv(self.index, sync_div) };
-        mh_to_result!(mh_result, ()).map_err(|e| PatinaError::from(e))
+        mh_to_result!(mh_result, ()).map_err(|e| self.device_error(e))?;
+        self.applied.sync_div = Some(sync_div);
+        audit::log_setting(&self.serial, "sync_div", None, sync_div);
+        Ok(())
     }
 
     /// Sets the level and edge of the sync signal to trigger on.
@@ -1032,15 +1684,12 @@ impl MultiHarpDevice for MultiHarp150 {
     /// 
     /// * `edge` - The edge of the sync signal to trigger on.
     fn set_sync_edge_trigger(&mut self, level : i32, edge : mhconsts::TriggerEdge) -> CheckedResult<(), i32> {
-        if level < mhconsts::TRGLVLMIN || level > mhconsts::TRGLVLMAX {
-            return Err(PatinaError::ArgumentError(
-                "level".to_string(),
-                level,
-                format!("Level must be between {} and {}", mhconsts::TRGLVLMIN, mhconsts::TRGLVLMAX))
-            );
-        }
+        let level = validation::validate(&self.serial, "level", level, mhconsts::TRGLVLMIN..=mhconsts::TRGLVLMAX, "Level")?;
         let mh_result = unsafe { MH_SetSyncEdgeTrg(self.index, level as c_int, edge as c_int) };
-        mh_to_result!(mh_result, ()).map_err(|e| PatinaError::from(e))
+        mh_to_result!(mh_result, ()).map_err(|e| self.device_error(e))?;
+        self.applied.sync_trigger_edge = Some((level, edge));
+        audit::log_setting(&self.serial, "sync_edge_trigger", None, (level, edge));
+        Ok(())
     }
 
     /// Sets the timing offset of the sync channel in picoseconds.
@@ -1049,22 +1698,22 @@ impl MultiHarpDevice for MultiHarp150 {
     /// 
     /// * `offset` - The offset to set in picoseconds. Must be between -99999 and 99999 ps.
     fn set_sync_channel_offset(&mut self, offset : i32) -> CheckedResult<(), i32> {
-        if offset < mhconsts::CHANNEL_OFFS_MIN || offset > mhconsts::CHANNEL_OFFS_MAX {
-            return Err(PatinaError::ArgumentError(
-                "offset".to_string(),
-                offset,
-                format!("Offset must be between {} and {}", mhconsts::CHANNEL_OFFS_MIN, mhconsts::CHANNEL_OFFS_MAX))
-            );
-        }
+        let offset = validation::validate(&self.serial, "offset", offset, mhconsts::CHANNEL_OFFS_MIN..=mhconsts::CHANNEL_OFFS_MAX, "Offset")?;
         let mh_result = unsafe { MH_SetSyncChannelOffset(self.index, offset) };
-        mh_to_result!(mh_result, ()).map_err(|e| PatinaError::from(e))
+        mh_to_result!(mh_result, ()).map_err(|e| self.device_error(e))?;
+        self.applied.sync_channel_offset = Some(offset);
+        audit::log_setting(&self.serial, "sync_channel_offset", None, offset);
+        Ok(())
     }
 
     /// Enables or disables the sync channel. Only useful in T2 mode
     #[cfg(feature = "MHLv3_1_0")]
     fn set_sync_channel_enable(&mut self, enable : bool) -> CheckedResult<(), i32> {
         let mh_result = unsafe { MH_SetSyncChannelEnable(self.index, enable as i32) };
-        mh_to_result!(mh_result, ()).map_err(|e| PatinaError::from(e))
+        mh_to_result!(mh_result, ()).map_err(|e| self.device_error(e))?;
+        self.applied.sync_channel_enable = Some(enable);
+        audit::log_setting(&self.serial, "sync_channel_enable", None, enable);
+        Ok(())
     }
 
     /// Sets the dead time of the sync signal. This function is used to suppress
@@ -1079,16 +1728,13 @@ impl MultiHarpDevice for MultiHarp150 {
         if (self.features & (mhconsts::FeatureMasks::ProgTd as i32)) == 0 {
             return Err(PatinaError::FeatureNotAvailable("Programmable dead time".to_string()));
         }
-        if deadtime < mhconsts::EXTDEADMIN || deadtime > mhconsts::EXTDEADMAX {
-            return Err(PatinaError::ArgumentError(
-                "deadtime".to_string(),
-                deadtime,
-                format!("Dead time must be between {} and {}", mhconsts::EXTDEADMIN, mhconsts::EXTDEADMAX))
-            );
-        }
+        let deadtime = validation::validate(&self.serial, "deadtime", deadtime, mhconsts::EXTDEADMIN..=mhconsts::EXTDEADMAX, "Dead time")?;
 
         let mh_result = unsafe { MH_SetSyncDeadTime(self.index, on as i32, deadtime) };
-        mh_to_result!(mh_result, ()).map_err(|e| PatinaError::from(e))
+        mh_to_result!(mh_result, ()).map_err(|e| self.device_error(e))?;
+        self.applied.sync_dead_time = Some((on, deadtime));
+        audit::log_setting(&self.serial, "sync_dead_time", None, (on, deadtime));
+        Ok(())
     }
 
     /// Sets the level and edge for photon detection of the channel specified.
@@ -1111,15 +1757,12 @@ impl MultiHarpDevice for MultiHarp150 {
             );
         }
         
-        if level < mhconsts::TRGLVLMIN || level > mhconsts::TRGLVLMAX {
-            return Err(PatinaError::ArgumentError(
-                "level".to_string(),
-                level,
-                format!("Level must be between {} and {}", mhconsts::TRGLVLMIN, mhconsts::TRGLVLMAX))
-            );
-        }
+        let level = validation::validate(&self.serial, "level", level, mhconsts::TRGLVLMIN..=mhconsts::TRGLVLMAX, "Level")?;
         let mh_result = unsafe { MH_SetInputEdgeTrg(self.index, channel, level, edge as c_int) };
-        mh_to_result!(mh_result, ()).map_err(|e| PatinaError::from(e))
+        mh_to_result!(mh_result, ()).map_err(|e| self.device_error(e))?;
+        transaction::upsert(&mut self.applied.input_edges, (channel, level, edge), |e| e.0 == channel);
+        audit::log_channel_setting(&self.serial, "input_edge_trigger", channel, None, (level, edge));
+        Ok(())
     }
 
     /// Sets the offset of the input channel in picoseconds. This is equivalent to
@@ -1140,15 +1783,12 @@ impl MultiHarpDevice for MultiHarp150 {
             );
         }
 
-        if offset < mhconsts::CHANNEL_OFFS_MIN || offset > mhconsts::CHANNEL_OFFS_MAX {
-            return Err(PatinaError::ArgumentError(
-                "offset".to_string(),
-                offset,
-                format!("Offset must be between {} and {}", mhconsts::CHANNEL_OFFS_MIN, mhconsts::CHANNEL_OFFS_MAX))
-            );
-        }
+        let offset = validation::validate(&self.serial, "offset", offset, mhconsts::CHANNEL_OFFS_MIN..=mhconsts::CHANNEL_OFFS_MAX, "Offset")?;
         let mh_result = unsafe { MH_SetInputChannelOffset(self.index, channel, offset) };
-        mh_to_result!(mh_result, ()).map_err(|e| PatinaError::from(e))
+        mh_to_result!(mh_result, ()).map_err(|e| self.device_error(e))?;
+        transaction::upsert(&mut self.applied.input_offsets, (channel, offset), |e| e.0 == channel);
+        audit::log_channel_setting(&self.serial, "input_channel_offset", channel, None, offset);
+        Ok(())
     }
 
     /// Enables or disables the input channel.
@@ -1167,7 +1807,11 @@ impl MultiHarpDevice for MultiHarp150 {
             );
         }
         let mh_result = unsafe { MH_SetInputChannelEnable(self.index, channel, enable as i32) };
-        mh_to_result!(mh_result, ()).map_err(|e| PatinaError::from(e))
+        mh_to_result!(mh_result, ()).map_err(|e| self.device_error(e))?;
+        let old = std::mem::replace(&mut self.input_enables[channel as usize], enable);
+        transaction::upsert(&mut self.applied.input_enables, (channel, enable), |e| e.0 == channel);
+        audit::log_channel_setting(&self.serial, "input_channel_enable", channel, Some(&old), enable);
+        Ok(())
     }
 
     /// Set the dead time of the input channel. Used to suppress afterpulsing artifacts
@@ -1189,15 +1833,12 @@ impl MultiHarpDevice for MultiHarp150 {
             );
         }
         
-        if deadtime < mhconsts::EXTDEADMIN || deadtime > mhconsts::EXTDEADMAX {
-            return Err(PatinaError::ArgumentError(
-                "deadtime".to_string(),
-                deadtime,
-                format!("Dead time must be between {} and {}", mhconsts::EXTDEADMIN, mhconsts::EXTDEADMAX))
-            );
-        }
+        let deadtime = validation::validate(&self.serial, "deadtime", deadtime, mhconsts::EXTDEADMIN..=mhconsts::EXTDEADMAX, "Dead time")?;
         let mh_result = unsafe { MH_SetInputDeadTime(self.index, channel, on as i32,  deadtime) };
-        mh_to_result!(mh_result, ()).map_err(|e| PatinaError::from(e))
+        mh_to_result!(mh_result, ()).map_err(|e| self.device_error(e))?;
+        transaction::upsert(&mut self.applied.input_dead_times, (channel, on, deadtime), |e| e.0 == channel);
+        audit::log_channel_setting(&self.serial, "input_dead_time", channel, None, (on, deadtime));
+        Ok(())
     }
 
     /// Used to accommodate hysteresis on the input and sync channels for detectors
@@ -1212,7 +1853,10 @@ impl MultiHarpDevice for MultiHarp150 {
             return Err(PatinaError::FeatureNotAvailable("Hysteresis".to_string()));
         }
         let mh_result = unsafe { MH_SetInputHysteresis(self.index, hystcode as i32) };
-        mh_to_result!(mh_result, ()).map_err(|e| PatinaError::from(e))
+        mh_to_result!(mh_result, ()).map_err(|e| self.device_error(e))?;
+        self.applied.input_hysteresis = Some(hystcode);
+        audit::log_setting(&self.serial, "input_hysteresis", None, hystcode);
+        Ok(())
     }
 
     /// Determines if a measurement will stop when the histogram overflows.
@@ -1224,16 +1868,13 @@ impl MultiHarpDevice for MultiHarp150 {
     /// * `stopcount` - The number of counts to stop on. Must be between 1 and 4294967295.
     fn set_stop_overflow(&mut self, stop_overflow : bool, stopcount : u32) -> CheckedResult<(), u32> {
 
-        if stopcount < mhconsts::STOPCNTMIN || stopcount > mhconsts::STOPCNTMAX {
-            return Err(PatinaError::ArgumentError(
-                "stopcount".to_string(),
-                stopcount,
-                format!("Stop count must be between {} and {}", mhconsts::STOPCNTMIN, mhconsts::STOPCNTMAX))
-            );
-        }
+        let stopcount = validation::validate(&self.serial, "stopcount", stopcount, mhconsts::STOPCNTMIN..=mhconsts::STOPCNTMAX, "Stop count")?;
 
         let mh_result = unsafe { MH_SetStopOverflow(self.index, stop_overflow as i32, stopcount) };
-        mh_to_result!(mh_result, ()).map_err(|e| PatinaError::from(e))
+        mh_to_result!(mh_result, ()).map_err(|e| self.device_error(e))?;
+        self.applied.stop_overflow = Some((stop_overflow, stopcount));
+        audit::log_setting(&self.serial, "stop_overflow", None, (stop_overflow, stopcount));
+        Ok(())
     }
 
     /// Only applies in Histogramming or T3 mode. The binning corresponds to repeated
@@ -1244,15 +1885,12 @@ impl MultiHarpDevice for MultiHarp150 {
     /// * `binning` - The binning to set. Must be between 0 and 24 (corresponding to
     /// pooling 2^0 to 2^24 bins).
     fn set_binning(&mut self, binning : i32) -> CheckedResult<(), i32> {
-        if binning < 0 || binning > mhconsts::BINSTEPSMAX {
-            return Err(PatinaError::ArgumentError(
-                "binning".to_string(),
-                binning,
-                format!("Binning must be between 0 and {}", mhconsts::BINSTEPSMAX))
-            );
-        }
+        let binning = validation::validate(&self.serial, "binning", binning, 0..=mhconsts::BINSTEPSMAX, "Binning")?;
         let mh_result = unsafe { MH_SetBinning(self.index, binning) };
-        mh_to_result!(mh_result, ()).map_err(|e| PatinaError::from(e))
+        mh_to_result!(mh_result, ()).map_err(|e| self.device_error(e))?;
+        self.applied.binning = Some(binning);
+        audit::log_setting(&self.serial, "binning", None, binning);
+        Ok(())
     }
 
     /// Sets the overall offset subtracted from the difference between stop and start,
@@ -1265,15 +1903,14 @@ impl MultiHarpDevice for MultiHarp150 {
     /// - `set_input_channel_offset`
     /// - `set_sync_channel_offset`
     fn set_offset(&mut self, offset : i32) -> CheckedResult<(), i32> {
-        if offset < mhconsts::OFFSETMIN || offset > mhconsts::OFFSETMAX {
-            return Err(PatinaError::ArgumentError(
-                "offset".to_string(),
-                offset,
-                format!("Offset must be between {} and {}", mhconsts::OFFSETMIN, mhconsts::OFFSETMAX))
-            );
-        }
+        let offset = validation::validate(&self.serial, "offset", offset, mhconsts::OFFSETMIN..=mhconsts::OFFSETMAX, "Offset")?;
         let mh_result = unsafe { MH_SetOffset(self.index, offset) };
-        mh_to_result!(mh_result, ()).map_err(|e| PatinaError::from(e))
+        mh_to_result!(mh_result, ()).map_err(|e| self.device_error(e))?;
+        let old = self.offset;
+        self.offset = offset;
+        self.applied.offset = Some(offset);
+        audit::log_setting(&self.serial, "offset", Some(&old), offset);
+        Ok(())
     }
 
     /// Sets the number of bins of the histograms collected. The histogram length
@@ -1289,16 +1926,13 @@ impl MultiHarpDevice for MultiHarp150 {
     /// 
     /// * `CheckedResult<i32, i32>` - The actual length of the histogram.
     fn set_histogram_len(&mut self, lencode : i32) -> CheckedResult<i32, i32> {
-        if lencode < mhconsts::MINLENCODE || lencode > mhconsts::MAXLENCODE {
-            return Err(PatinaError::ArgumentError(
-                "lencode".to_string(),
-                lencode,
-                format!("Length code must be between {} and {}", mhconsts::MINLENCODE, mhconsts::MAXLENCODE))
-            );
-        }
+        let lencode = validation::validate(&self.serial, "lencode", lencode, mhconsts::MINLENCODE..=mhconsts::MAXLENCODE, "Length code")?;
         let mut actual_lencode = 0;
         let mh_result = unsafe { MH_SetHistoLen(self.index, lencode, &mut actual_lencode) };
-        mh_to_result!(mh_result, actual_lencode).map_err(|e| PatinaError::from(e))
+        mh_to_result!(mh_result, ()).map_err(|e| self.device_error(e))?;
+        self.applied.histo_len = Some(lencode);
+        audit::log_setting(&self.serial, "histogram_len", None, actual_lencode);
+        Ok(actual_lencode)
     }
 
     /// Clears the histogram of the device. Does nothing if in T2 or T3 mode
@@ -1340,7 +1974,7 @@ impl MultiHarpDevice for MultiHarp150 {
                 let start_edge = start_edge.unwrap();
                 let stop_edge = stop_edge.unwrap();
                 let mh_result = unsafe { MH_SetMeasControl(self.index, mode as c_int, start_edge as i32, stop_edge as i32) };
-                return mh_to_result!(mh_result, ()).map_err(|e| PatinaError::from(e))
+                mh_to_result!(mh_result, ()).map_err(|e| self.device_error(e))?;
             }
 
             mhconsts::MeasurementControlMode::C1StartCtcStop => {
@@ -1354,7 +1988,7 @@ impl MultiHarpDevice for MultiHarp150 {
                 let start_edge = start_edge.unwrap();
                 let stop_edge = 0;
                 let mh_result = unsafe { MH_SetMeasControl(self.index, mode as c_int, start_edge as i32, stop_edge) };
-                return mh_to_result!(mh_result, ()).map_err(|e| PatinaError::from(e))
+                mh_to_result!(mh_result, ()).map_err(|e| self.device_error(e))?;
             }
             mhconsts::MeasurementControlMode::C1StartC2Stop => {
                 if start_edge.is_none() || stop_edge.is_none() {
@@ -1367,18 +2001,21 @@ impl MultiHarpDevice for MultiHarp150 {
                 let start_edge = start_edge.unwrap();
                 let stop_edge = stop_edge.unwrap();
                 let mh_result = unsafe { MH_SetMeasControl(self.index, mode as c_int, start_edge as i32, stop_edge as i32) };
-                return mh_to_result!(mh_result, ()).map_err(|e| PatinaError::from(e))
+                mh_to_result!(mh_result, ()).map_err(|e| self.device_error(e))?;
             }
             // #[cfg(feature = "MHLv_3_1_0")]
             // mhconsts::MeasurementControlMode::SwStartSwStop => {
             //     let mh_result = unsafe { MH_SetMeasControl(self.index, mode as c_int, 0, 0) };
-            //     return mh_to_result!(mh_result, ()).map_err(|e| PatinaError::from(e))
+            //     return mh_to_result!(mh_result, ()).map_err(|e| self.device_error(e))
             // }
             _ => {
                 let mh_result = unsafe { MH_SetMeasControl(self.index, mode as c_int, 0, 0) };
-                return mh_to_result!(mh_result, ()).map_err(|e| PatinaError::from(e))
+                mh_to_result!(mh_result, ()).map_err(|e| self.device_error(e))?;
             }
         }
+        self.applied.meas_control = Some((mode, start_edge, stop_edge));
+        audit::log_setting(&self.serial, "measurement_control_mode", None, (mode, start_edge, stop_edge));
+        Ok(())
     }
 
     /// Sets the period of the programmable trigger output. Setting the
@@ -1391,15 +2028,12 @@ impl MultiHarpDevice for MultiHarp150 {
         if (self.features & (mhconsts::FeatureMasks::TrigOut as i32)) == 0 {
             return Err(PatinaError::FeatureNotAvailable("Trigger Output".to_string()));
         }
-        if period < mhconsts::TRIGOUTMIN || period > mhconsts::TRIGOUTMAX {
-            return Err(PatinaError::ArgumentError(
-                "period".to_string(),
-                period,
-                format!("Period must be between {} and {}", mhconsts::TRIGOUTMIN, mhconsts::TRIGOUTMAX))
-            );
-        }
+        let period = validation::validate(&self.serial, "period", period, mhconsts::TRIGOUTMIN..=mhconsts::TRIGOUTMAX, "Period")?;
         let mh_result = unsafe { MH_SetTriggerOutput(self.index, period) };
-        mh_to_result!(mh_result, ()).map_err(|e| PatinaError::from(e))
+        mh_to_result!(mh_result, ()).map_err(|e| self.device_error(e))?;
+        self.applied.trigger_output = Some(period);
+        audit::log_setting(&self.serial, "trigger_output", None, period);
+        Ok(())
     }
 
     /// Starts a measurement with the given acquisition time in milliseconds
@@ -1413,16 +2047,17 @@ impl MultiHarpDevice for MultiHarp150 {
     /// - `set_measurement_control_mode` - If the software library version is >3.1, this
     /// can be used to bypass the `acquistion_time` parameter entirely, permitting very
     /// very long acquisitions.
+    ///
+    /// ## Errors
+    ///
+    /// - `PatinaError::ReferenceLost` if the device was initialized with an external
+    ///   reference clock that is not present (see `check_reference_clock`).
     fn start_measurement(&mut self, acquisition_time : i32) -> CheckedResult<(), i32> {
-        if acquisition_time < mhconsts::ACQTMIN || acquisition_time > mhconsts::ACQTMAX {
-            return Err(PatinaError::ArgumentError(
-                "acquisition_time".to_string(),
-                acquisition_time,
-                format!("Acquisition time must be between {} and {}", mhconsts::ACQTMIN, mhconsts::ACQTMAX))
-            );
-        }
+        let acquisition_time = validation::validate(&self.serial, "acquisition_time", acquisition_time, mhconsts::ACQTMIN..=mhconsts::ACQTMAX, "Acquisition time")?;
+        self.check_system_error()?;
+        self.check_reference_clock()?;
         let mh_result = unsafe { MH_StartMeas(self.index, acquisition_time) };
-        mh_to_result!(mh_result, ()).map_err(|e| PatinaError::from(e))
+        mh_to_result!(mh_result, ()).map_err(|e| self.device_error(e))
     }
 
     /// Stops the current measurement. Must be called after `start_measurement`, even
@@ -1456,6 +2091,9 @@ impl MultiHarpDevice for MultiHarp150 {
     /// * `Vec<u32>` - The histogram of arrival times, of length determined by the
     /// current histogram length TODO: make it actually determined, currently just MAXHISTLEN
     fn get_histogram_by_copy(&mut self, channel : i32) -> Result<Vec<u32>, PatinaError<i32>> {
+        if self.mode != mhconsts::MeasurementMode::Histogramming {
+            return Err(PatinaError::WrongMode("get_histogram_by_copy".to_string(), self.mode));
+        }
         let mut histogram = vec![0u32; mhconsts::MAXHISTLEN];
         if channel < 0 || channel >= self.num_channels {
             return Err(PatinaError::ArgumentError(
@@ -1466,19 +2104,21 @@ impl MultiHarpDevice for MultiHarp150 {
         }
 
         let mh_result = unsafe { MH_GetHistogram(self.index, histogram.as_mut_ptr(), channel) };
-        mh_to_result!(mh_result, histogram).map_err(|e| PatinaError::from(e))
+        mh_to_result!(mh_result, histogram).map_err(|e| self.device_error(e))
     }
 
     /// Returns all histograms from the device. This makes a copy, rather
     /// than filling an existing buffer.
     fn get_all_histograms_by_copy(&mut self) -> MultiHarpResult<Vec<u32>> {
-        let mut histograms = vec![0u32; mhconsts::MAXHISTLEN * self.num_channels as usize];
+        let mut histograms = vec![0u32; mhconsts::MAXHISTLEN * self.buffer_channels()];
         let mh_result = unsafe { MH_GetAllHistograms(self.index, histograms.as_mut_ptr()) };
+        histograms.truncate(mhconsts::MAXHISTLEN * self.num_channels.max(0) as usize);
         mh_to_result!(mh_result, histograms)
     }
 
     /// Fills an existing buffer with the arrival time histogram from the device.
-    /// TODO check if the buffer is the right size.
+    /// Returns `PatinaError::WrongMode` unless the device was initialized in
+    /// Histogramming mode. TODO check if the buffer is the right size.
     /// 
     /// ## Arguments
     /// 
@@ -1487,6 +2127,9 @@ impl MultiHarpDevice for MultiHarp150 {
     /// 
     /// * `channel` - The channel to get the histogram for. Must be an available channel for the device.
     fn fill_histogram<'a, 'b>(&'a mut self, histogram : &'b mut Vec<u32>, channel : i32) -> CheckedResult<(), i32> {
+        if self.mode != mhconsts::MeasurementMode::Histogramming {
+            return Err(PatinaError::WrongMode("fill_histogram".to_string(), self.mode));
+        }
         if channel < 0 || channel >= self.num_channels {
             return Err(PatinaError::ArgumentError(
                 "channel".to_string(),
@@ -1499,15 +2142,18 @@ impl MultiHarpDevice for MultiHarp150 {
         mh_to_result!(mh_result, ()).map_err(|e| PatinaError::from(e))
     }
 
-    /// Populates an existing buffer with all histograms from the device. Expects
-    /// a buffer for all channels, so the buffer must be at least `num_channels * histogram_length`
-    /// long. TODO: actually provide checking!
+    /// Populates an existing buffer with all histograms from the device. `MHLib`
+    /// may write `MAXHISTLEN` bins for every channel the model can have, so
+    /// a shorter buffer is first grown to that length.
     /// 
     /// ## Arguments
     /// 
-    /// * `histograms` - The buffer to fill with all histograms. Must be at least as long
-    /// as the setting's histogram length times the number of channels. TODO check this arg!
+    /// * `histograms` - The buffer to fill with all histograms.
     fn fill_all_histograms<'a, 'b>(&'a mut self, histograms : &'b mut Vec<u32>) -> MultiHarpResult<()> {
+        let needed = mhconsts::MAXHISTLEN * self.buffer_channels();
+        if histograms.len() < needed {
+            histograms.resize(needed, 0);
+        }
         let mh_result = unsafe { MH_GetAllHistograms(self.index, histograms.as_mut_ptr()) };
         mh_to_result!(mh_result, ())
     }
@@ -1542,18 +2188,186 @@ impl MultiHarpDevice for MultiHarp150 {
         }
         let mut count_rate = 0;
         let mh_result = unsafe { MH_GetCountRate(self.index, channel, &mut count_rate) };
-        mh_to_result!(mh_result, count_rate).map_err(|e| PatinaError::from(e))
+        mh_to_result!(mh_result, count_rate).map_err(|e| self.device_error(e))
     }
 
     /// Returns the count rates of all channels in photons per second and the sync rate
     /// in Hz.
     fn get_all_count_rates(&self) -> MultiHarpResult<(i32, Vec<i32>)> {
+        let (sync_rate, mut count_rates) = self.get_all_count_rates_full()?;
+        count_rates.truncate(self.num_channels.max(0) as usize);
+        Ok((sync_rate, count_rates))
+    }
+
+    /// The count rates of every channel the model can have, as written by
+    /// `MHLib`.
+    fn get_all_count_rates_full(&self) -> MultiHarpResult<(i32, Vec<i32>)> {
         let mut sync_rate : i32 = 0;
-        let mut count_rates = vec![0i32; self.num_channels as usize];
+        let mut count_rates = vec![0i32; self.buffer_channels()];
         let mh_result = unsafe { MH_GetAllCountRates(self.index, &mut sync_rate, count_rates.as_mut_ptr()) };
         mh_to_result!(mh_result, (sync_rate, count_rates))
     }
 
+    #[cfg_attr(not(feature = "MHLv3_1_0"), allow(unused_variables))]
+    fn set_main_event_filter(&mut self, filter : &MainEventFilter) -> CheckedResult<(), i32> {
+        #[cfg(feature = "MHLv3_1_0")]
+        {
+            self.set_main_event_filter_params(filter.time_range, filter.match_count, filter.inverse)?;
+            self.set_main_event_filter_for_channels(filter)?;
+            audit::log_setting(&self.serial, "main_event_filter", None, filter);
+            Ok(())
+        }
+        #[cfg(not(feature = "MHLv3_1_0"))]
+        Err(PatinaError::FeatureNotAvailable("Event filters (MHLib 3.1)".to_string()))
+    }
+
+    #[cfg_attr(not(feature = "MHLv3_1_0"), allow(unused_variables))]
+    fn enable_main_event_filter(&mut self, enable : bool) -> CheckedResult<(), i32> {
+        #[cfg(feature = "MHLv3_1_0")]
+        {
+            let mh_result = unsafe { MH_EnableMainEventFilter(self.index, enable as i32) };
+            mh_to_result!(mh_result, ()).map_err(|e| self.device_error(e))
+        }
+        #[cfg(not(feature = "MHLv3_1_0"))]
+        Err(PatinaError::FeatureNotAvailable("Event filters (MHLib 3.1)".to_string()))
+    }
+
+    /// One important purpose of the event filters is to reduce USB load.
+    /// When the input data rates are higher than the USB bandwith,
+    /// there will at some point be a FiFo overrun. Setting filter test
+    /// mode disables all data transfers into the FiFo so that a test
+    /// measurement can be run without interruption by a FiFo overrun,
+    /// while `get_main_filtered_rates` monitors the count rates after the
+    /// Main Filter. When the filtering effect is satisfactory the test
+    /// mode can be switched off again to perform the regular measurement.
+    #[cfg_attr(not(feature = "MHLv3_1_0"), allow(unused_variables))]
+    fn set_filter_test_mode(&mut self, test_mode : bool) -> CheckedResult<(), i32> {
+        #[cfg(feature = "MHLv3_1_0")]
+        {
+            let mh_result = unsafe { MH_SetFilterTestMode(self.index, test_mode as i32) };
+            mh_to_result!(mh_result, ()).map_err(|e| self.device_error(e))
+        }
+        #[cfg(not(feature = "MHLv3_1_0"))]
+        Err(PatinaError::FeatureNotAvailable("Event filters (MHLib 3.1)".to_string()))
+    }
+
+    fn get_main_filtered_rates(&self) -> CheckedResult<(i32, Vec<i32>), i32> {
+        #[cfg(feature = "MHLv3_1_0")]
+        {
+            let mut sync_rate : i32 = 0;
+            let mut count_rates = vec![0i32; self.buffer_channels()];
+            let mh_result = unsafe { MH_GetMainFilteredRates(self.index, &mut sync_rate, count_rates.as_mut_ptr()) };
+            count_rates.truncate(self.num_channels.max(0) as usize);
+            mh_to_result!(mh_result, (sync_rate, count_rates)).map_err(|e| self.device_error(e))
+        }
+        #[cfg(not(feature = "MHLv3_1_0"))]
+        Err(PatinaError::FeatureNotAvailable("Event filters (MHLib 3.1)".to_string()))
+    }
+
+    /// This sets the parameters for one Row Filter implemented
+    /// in the local FPGA processing that row of input channels.
+    /// Each Row Filter can act only on the input channels within
+    /// its own row and never on the sync channel. The value
+    /// timerange de- termines the time window the filter is
+    /// acting on. The parameter matchcnt specifies how many
+    /// other events must fall into the chosen time window for
+    /// the filter condition to act on the event at hand. The
+    /// parameter inverse inverts the filter action, i.e. when
+    /// the filter would regularly have eliminated an event it
+    /// will then keep it and vice versa. For the typical case,
+    /// let it be not inverted. Then, if matchcnt is 1 we will
+    /// obtain a simple ‘singles filter’. This is the most
+    /// straightforward and most useful filter in typical quantum
+    /// optics experiments. It will suppress all events that do
+    /// not have at least one coincident event within the chosen
+    /// time range, be this in the same or any other channel
+    /// marked as ‘use’ in this row. The bitfield passchannels
+    /// is used to indicate if a channel is to be passed through
+    /// the filter unconditionally, whether it is marked as ‘use’
+    /// or not. The events on a channel that is marked neither as
+    /// ‘use’ nor as ‘pass’ will not pass the filter, provided
+    /// the filter is enabled. The parameter settings are
+    /// irrelevant as long as the filter is not enabled.
+    /// The output from the Row Filters is fed to the Main Filter.
+    /// The overall filtering result depends on their combined
+    /// action. Only the Main Filter can act on all channels of
+    /// the MutiHarp device includ - ing the sync channel. It is
+    /// usually sufficient and easier to use the Main Filter alone.
+    /// 
+    /// The only reasons for using the Row Filter(s) are early data
+    /// reduction, so as to not overload the Main Filter, and the
+    /// possible need for more complex filters, e.g. with different
+    /// time ranges.
+    /// 
+    /// ## Arguments
+    /// 
+    /// * `row` - The row to set the filter for. Must be between `ROWIDXMIN` and `ROWIDXMAX`.
+    /// 
+    /// * `time_range` - Time distance in picoseconds to other events
+    ///   to meet filter condition
+    /// 
+    /// * `match_cnt` - Number of other events to meet filter condition
+    /// 
+    /// * `inverse` - Whether to invert the filter action. 0 is normal,
+    ///   1 is inverse filter
+    /// 
+    /// * `use_channels` - The channels of the row to use in the filter.
+    /// 
+    /// * `pass_channels` - The channels of the row to pass through the
+    ///   filter unconditionally.
+    #[cfg_attr(not(feature = "MHLv3_1_0"), allow(unused_variables))]
+    fn set_row_event_filter(
+        &mut self, row : i32, time_range : i32, match_cnt : i32, inverse : bool,
+        use_channels : ChannelMask, pass_channels : ChannelMask,
+    ) -> CheckedResult<(), i32> {
+        #[cfg(feature = "MHLv3_1_0")]
+        {
+            let row = validation::validate(&self.serial, "row", row, mhconsts::ROWIDXMIN..=mhconsts::ROWIDXMAX, "Row")?;
+            let time_range = validation::validate(&self.serial, "time_range", time_range, mhconsts::TIMERANGEMIN..=mhconsts::TIMERANGEMAX, "Time range")?;
+            let match_cnt = validation::validate(&self.serial, "match_cnt", match_cnt, mhconsts::MATCHCNTMIN..=mhconsts::MATCHCNTMAX, "Match count")?;
+            if use_channels.has_sync() || pass_channels.has_sync() {
+                return Err(PatinaError::ArgumentError(
+                    "use_channels".to_string(),
+                    use_channels.bits() | pass_channels.bits(),
+                    "The Row Filters don't act on the sync channel".to_string())
+                );
+            }
+            let mh_result = unsafe { MH_SetRowEventFilter(
+                self.index, row, time_range, match_cnt, inverse as i32, use_channels.bits(), pass_channels.bits()
+            ) };
+            mh_to_result!(mh_result, ()).map_err(|e| self.device_error(e))?;
+            audit::log_setting(&self.serial, &format!("row_event_filter.{}", row), None, (time_range, match_cnt, inverse, use_channels.to_string(), pass_channels.to_string()));
+            Ok(())
+        }
+        #[cfg(not(feature = "MHLv3_1_0"))]
+        Err(PatinaError::FeatureNotAvailable("Event filters (MHLib 3.1)".to_string()))
+    }
+
+    #[cfg_attr(not(feature = "MHLv3_1_0"), allow(unused_variables))]
+    fn enable_row_event_filter(&mut self, row : i32, enable : bool) -> CheckedResult<(), i32> {
+        #[cfg(feature = "MHLv3_1_0")]
+        {
+            let row = validation::validate(&self.serial, "row", row, mhconsts::ROWIDXMIN..=mhconsts::ROWIDXMAX, "Row")?;
+            let mh_result = unsafe { MH_EnableRowEventFilter(self.index, row, enable as i32) };
+            mh_to_result!(mh_result, ()).map_err(|e| self.device_error(e))
+        }
+        #[cfg(not(feature = "MHLv3_1_0"))]
+        Err(PatinaError::FeatureNotAvailable("Event filters (MHLib 3.1)".to_string()))
+    }
+
+    fn get_row_filtered_rates(&self) -> CheckedResult<(i32, Vec<i32>), i32> {
+        #[cfg(feature = "MHLv3_1_0")]
+        {
+            let mut sync_rate : i32 = 0;
+            let mut count_rates = vec![0i32; self.buffer_channels()];
+            let mh_result = unsafe { MH_GetRowFilteredRates(self.index, &mut sync_rate, count_rates.as_mut_ptr()) };
+            count_rates.truncate(self.num_channels.max(0) as usize);
+            mh_to_result!(mh_result, (sync_rate, count_rates)).map_err(|e| self.device_error(e))
+        }
+        #[cfg(not(feature = "MHLv3_1_0"))]
+        Err(PatinaError::FeatureNotAvailable("Event filters (MHLib 3.1)".to_string()))
+    }
+
     /// Returns the set flags of the device, interpretable using
     /// the bitmasks in `mhconsts`.
     /// 
@@ -1566,6 +2380,12 @@ impl MultiHarpDevice for MultiHarp150 {
         mh_to_result!(mh_result, flags)
     }
 
+    /// Returns the features of the device as a bitmask of
+    /// `FeatureMasks`, as read when it was opened.
+    fn get_features(&self) -> MultiHarpResult<i32> {
+        Ok(self.features)
+    }
+
     /// Returns the set warnings of the device, interpretable using
     /// the bitmasks in `mhconsts`. Prior to this call, you must call
     /// `get_all_count_rates` or `get_sync_rate` and `get_count_rate` for
@@ -1587,10 +2407,18 @@ impl MultiHarpDevice for MultiHarp150 {
     /// - `get_warnings`
     /// - `get_flags`
     fn get_warnings_text(&self) -> MultiHarpResult<String> {
+        let mut warnings_text = [0u8; mhconsts::WARNLEN];
+        self.get_warnings_text_into(&mut warnings_text).map(str::to_string)
+    }
+
+    /// Writes the text of `get_warnings_text` into `buffer` and returns it,
+    /// without allocating.
+    fn get_warnings_text_into<'a>(&self, buffer : &'a mut [u8; mhconsts::WARNLEN]) -> MultiHarpResult<&'a str> {
         let warnings = self.get_warnings()?;
-        let mut warnings_text = [0 as c_char; mhconsts::WARNLEN];
-        let mh_result = unsafe { MH_GetWarningsText(self.index, warnings_text.as_mut_ptr(), warnings) };
-        mh_to_result!(mh_result, unsafe { CStr::from_ptr(warnings_text.as_mut_ptr()) }.to_str().unwrap().to_string())
+        let mh_result = unsafe { MH_GetWarningsText(self.index, buffer.as_mut_ptr() as *mut c_char, warnings) };
+        // NUL-terminated by the library
+        let len = buffer.iter().position(|&b| b == 0).unwrap_or(buffer.len());
+        mh_to_result!(mh_result, std::str::from_utf8(&buffer[..len]).unwrap_or_default())
     }
 
     /// Returns the sync period in seconds. Resolution is the
@@ -1636,7 +2464,8 @@ impl MultiHarpDevice for MultiHarp150 {
     }
 
     /// Loads a buffer with the arrival time data from the device. Returns the actual
-    /// number of counts read. Only meaningful in TTTR mode.
+    /// number of counts read. Only meaningful in TTTR mode -- returns
+    /// `PatinaError::WrongMode` if the device was initialized in Histogramming mode.
     /// 
     /// ## Arguments
     /// 
@@ -1648,6 +2477,9 @@ impl MultiHarpDevice for MultiHarp150 {
     /// * `CheckedResult<i32, u32>` - The actual number of counts read. Data
     /// after this value is undefined.
     fn read_fifo<'a, 'b>(&'a self, buffer : &'b mut Vec<u32>) -> CheckedResult<i32, u32> {
+        if self.mode == mhconsts::MeasurementMode::Histogramming {
+            return Err(PatinaError::WrongMode("read_fifo".to_string(), self.mode));
+        }
         if buffer.len() < mhconsts::TTREADMAX {
             return Err(PatinaError::ArgumentError(
                 "buffer".to_string(),
@@ -1664,13 +2496,19 @@ impl MultiHarpDevice for MultiHarp150 {
     /// meaningful in TTTR mode.
     fn set_marker_edges(&mut self, marker1 : TriggerEdge, marker2 : TriggerEdge, marker3 : TriggerEdge, marker4 : TriggerEdge) -> MultiHarpResult<()> {
         let mh_result = unsafe { MH_SetMarkerEdges(self.index, marker1 as c_int, marker2 as c_int, marker3 as c_int, marker4 as c_int) };
-        mh_to_result!(mh_result, ())
+        mh_to_result!(mh_result, ())?;
+        self.applied.marker_edges = Some([marker1, marker2, marker3, marker4]);
+        audit::log_setting(&self.serial, "marker_edges", None, [marker1, marker2, marker3, marker4]);
+        Ok(())
     }
 
     /// Used to enable or disable individual TTL marker inputs. Only meaningful in TTTR mode.
     fn set_marker_enable(&mut self, enable1 : bool, enable2 : bool, enable3: bool, enable4 : bool) -> MultiHarpResult<()> {
         let mh_result = unsafe { MH_SetMarkerEnable(self.index, enable1 as i32, enable2 as i32, enable3 as i32, enable4 as i32) };
-        mh_to_result!(mh_result, ())
+        mh_to_result!(mh_result, ())?;
+        self.applied.marker_enable = Some([enable1, enable2, enable3, enable4]);
+        audit::log_setting(&self.serial, "marker_enable", None, [enable1, enable2, enable3, enable4]);
+        Ok(())
     }
 
     /// Sets the holdoff time for the markers in nanoseconds. This is not normally required,
@@ -1682,15 +2520,12 @@ impl MultiHarpDevice for MultiHarp150 {
     /// * `holdoff_time` - The holdoff time to set in nanoseconds. Must be between 0 and 25500 ns
     /// (25.5 microseconds)
     fn set_marker_holdoff_time(&mut self, holdoff_time : i32) -> CheckedResult<(), i32> {
-        if holdoff_time < 0 || holdoff_time > mhconsts::HOLDOFFMAX {
-            return Err(PatinaError::ArgumentError(
-                "holdoff_time".to_string(),
-                holdoff_time,
-                format!("Holdoff time must be between {} and {}", 0, mhconsts::HOLDOFFMAX))
-            );
-        }
+        let holdoff_time = validation::validate(&self.serial, "holdoff_time", holdoff_time, 0..=mhconsts::HOLDOFFMAX, "Holdoff time")?;
         let mh_result = unsafe { MH_SetMarkerHoldoffTime(self.index, holdoff_time) };
-        mh_to_result!(mh_result, ()).map_err(|e| PatinaError::from(e))
+        mh_to_result!(mh_result, ()).map_err(|e| self.device_error(e))?;
+        self.applied.marker_holdoff = Some(holdoff_time);
+        audit::log_setting(&self.serial, "marker_holdoff_time", None, holdoff_time);
+        Ok(())
     }
 
     /// The setting is useful when data rates are very low, so that the sync signals
@@ -1706,17 +2541,14 @@ impl MultiHarpDevice for MultiHarp150 {
     /// ## Arguments
     /// 
     /// * `hold_time` - The hold time to set in milliseconds. Must be between 0 and 255 ms.
-    #[cfg(feature = "v3_1")]
+    #[cfg(feature = "MHLv3_1_0")]
     fn set_overflow_compression(&mut self, hold_time : i32) -> CheckedResult<(), i32> {
-        if hold_time < mhconsts::HOLDTIMEMIN || hold_time > mhconsts::HOLDTIMEMAX {
-            return Err(PatinaError::ArgumentError(
-                "hold_time".to_string(),
-                hold_time,
-                format!("Hold time must be between {} and {}",mhconsts::HOLDTIMEMIN, mhconsts::HOLDTIMEMAX))
-            );
-        }
+        let hold_time = validation::validate(&self.serial, "hold_time", hold_time, mhconsts::HOLDTIMEMIN..=mhconsts::HOLDTIMEMAX, "Hold time")?;
         let mh_result = unsafe { MH_SetOflCompression(self.index, hold_time) };
-        mh_to_result!(mh_result, ()).map_err(|e| PatinaError::from(e))
+        mh_to_result!(mh_result, ()).map_err(|e| self.device_error(e))?;
+        self.applied.ofl_compression = Some(hold_time);
+        audit::log_setting(&self.serial, "overflow_compression", None, hold_time);
+        Ok(())
     }
 
     /// Return a copy of the MultiHarp device index.
@@ -1728,115 +2560,197 @@ impl MultiHarpDevice for MultiHarp150 {
     fn get_serial(&self) -> String {
         self.serial.clone()
     }
-}
 
-/// Event filtering functionality
-#[cfg(feature = "MHLib_v3_1_0")]
-#[allow(dead_code)]
-impl MultiHarp150 {
-    /// This sets the parameters for one Row Filter implemented
-    /// in the local FPGA processing that row of input channels.
-    /// Each Row Filter can act only on the input channels within
-    /// its own row and never on the sync channel. The value
-    /// timerange de- termines the time window the filter is
-    /// acting on. The parameter matchcnt specifies how many
-    /// other events must fall into the chosen time window for
-    /// the filter condition to act on the event at hand. The
-    /// parameter inverse inverts the filter action, i.e. when
-    /// the filter would regularly have eliminated an event it
-    /// will then keep it and vice versa. For the typical case,
-    /// let it be not inverted. Then, if matchcnt is 1 we will
-    /// obtain a simple ‘singles filter’. This is the most
-    /// straightforward and most useful filter in typical quantum
-    /// optics experiments. It will suppress all events that do
-    /// not have at least one coincident event within the chosen
-    /// time range, be this in the same or any other channel
-    /// marked as ‘use’ in this row. The bitfield passchannels
-    /// is used to indicate if a channel is to be passed through
-    /// the filter unconditionally, whether it is marked as ‘use’
-    /// or not. The events on a channel that is marked neither as
-    /// ‘use’ nor as ‘pass’ will not pass the filter, provided
-    /// the filter is enabled. The parameter settings are
-    /// irrelevant as long as the filter is not enabled.
-    /// The output from the Row Filters is fed to the Main Filter.
-    /// The overall filtering result depends on their combined
-    /// action. Only the Main Filter can act on all channels of
-    /// the MutiHarp device includ - ing the sync channel. It is
-    /// usually sufficient and easier to use the Main Filter alone.
-    /// 
-    /// The only reasons for using the Row Filter(s) are early data
-    /// reduction, so as to not overload the Main Filter, and the
-    /// possible need for more complex filters, e.g. with different
-    /// time ranges.
-    /// 
-    /// ## Arguments
-    /// 
-    /// * `row` - The row to set the filter for. Must be between 0 and 8.
-    /// 
-    /// * `time_range` - Time distance in picoseconds to other events
-    /// to meet filter condition
-    /// 
-    /// * `match_cnt` - Number of other events to meet filter condition
-    /// 
-    /// * `inverse` - Whether to invert the filter action. 0 is normal,
-    /// 1 is inverse filter
-    /// 
-    /// * `use_channels` - Bitfield of channels to use in the filter, with
-    /// bit 7 as the rightmost input channel and bit 0 as the leftmost channel.
-    /// Setting a bit to high means to use the channel in the filter.
-    /// 
-    /// * `pass_channels` - Bitfield of channels to pass through the
-    /// filter unconditionally. If a bit is high, it is passed unconditionally.
-    fn set_row_event_filter(
-        &self, row : i32, time_range : i32,
-        match_cnt : i32, inverse : bool, use_channels : i32,
-        pass_channels : i32,
-    ) -> CheckedResult<(), i32>{
-        if (row < ROWIDXMIN || row > ROWIDXMAX) {
-            return Err(PatinaError::ArgumentError(
-                "row".to_string(),
-                row,
-                format!("Row must be between {} and {}", ROWIDXMIN, ROWIDXMAX))
-            );
-        }
+    /// Returns the mode passed to the last successful `init`
+    /// (`open` initializes in T3 mode).
+    fn get_measurement_mode(&self) -> mhconsts::MeasurementMode {
+        self.mode
+    }
 
-        if (time_range < TIME_RANGEMIN || time_range > TIME_RANGEMAX) {
-            return Err(PatinaError::ArgumentError(
-                "time_range".to_string(),
-                time_range,
-                format!("Time range must be between {} and {}", TIME_RANGEMIN, TIME_RANGEMAX))
+    fn get_reference_clock(&self) -> mhconsts::ReferenceClock {
+        self.reference_clock
+    }
+
+    fn get_offset(&self) -> i32 {
+        self.offset
+    }
+
+    fn get_input_channel_enables(&self) -> MultiHarpResult<Vec<bool>> {
+        Ok(self.input_enables.clone())
+    }
+
+    /// The settings applied since the device was opened. The library
+    /// can't read settings back, so anything not set through this
+    /// struct is unknown.
+    fn current_settings(&self) -> MultiHarpConfig {
+        self.applied.clone()
+    }
+
+    fn version_mismatches(&self) -> Vec<VersionMismatch> {
+        self.version_mismatches.clone()
+    }
+    fn wrabbit_get_mac(&self) -> CheckedResult<String, i32> {
+        let mut mac = [0 as c_char; mhconsts::WR_MAC_LEN + 1];
+        let mh_result = unsafe { MH_WRabbitGetMAC(self.index, mac.as_mut_ptr()) };
+        mh_to_result!(mh_result, unsafe { CStr::from_ptr(mac.as_ptr()) }.to_string_lossy().to_string())
+            .map_err(|e| self.device_error(e))
+    }
+
+    fn wrabbit_set_mac(&mut self, mac : &str) -> CheckedResult<(), usize> {
+        if mac.len() != mhconsts::WR_MAC_LEN {
+            return Err(
+                PatinaError::ArgumentError(
+                "mac".to_string(),
+                mac.len(),
+                format!("MAC address must be {} characters long", mhconsts::WR_MAC_LEN))
             );
         }
+        let mac = CString::new(mac).map_err(|_| PatinaError::ArgumentError(
+            "mac".to_string(), mac.len(), "MAC address must not contain NUL".to_string()
+        ))?;
+        let mh_result = unsafe { MH_WRabbitSetMAC(self.index, mac.as_ptr()) };
+        mh_to_result!(mh_result, ()).map_err(|e| self.device_error(e))
+    }
 
-        if (match_cnt < MATCHCNTMIN || match_cnt > MATCHCNTMAX) {
-            return Err(PatinaError::ArgumentError(
-                "match_cnt".to_string(),
-                match_cnt,
-                format!("Match count must be between {} and {}", MATCHCNTMIN, MATCHCNTMAX))
-            );
+    fn wrabbit_get_init_script(&self) -> CheckedResult<String, i32> {
+        let mut script = [0 as c_char; mhconsts::WR_SCRIPT_LEN];
+        let mh_result = unsafe { MH_WRabbitGetInitScript(self.index, script.as_mut_ptr()) };
+        mh_to_result!(mh_result, unsafe { CStr::from_ptr(script.as_ptr()) }.to_string_lossy().to_string())
+            .map_err(|e| self.device_error(e))
+    }
+
+    fn wrabbit_set_init_script(&mut self, script : &str) -> CheckedResult<(), i32> {
+        let script = CString::new(script).map_err(|e| PatinaError::ArgumentError(
+            "script".to_string(), e.nul_position() as i32, "Script must not contain NUL".to_string()
+        ))?;
+        let mh_result = unsafe { MH_WRabbitSetInitScript(self.index, script.as_ptr()) };
+        mh_to_result!(mh_result, ()).map_err(|e| self.device_error(e))
+    }
+
+    fn set_wrabbit_link(&mut self, on : bool) -> CheckedResult<(), i32> {
+        let mh_result = unsafe { MH_WRabbitInitLink(self.index, on as i32) };
+        mh_to_result!(mh_result, ()).map_err(|e| self.device_error(e))
+    }
+
+    fn set_wrabbit_mode(&mut self, boot_from_script : bool, reinit_with_mode : bool, mode : WRMode) -> CheckedResult<(), i32> {
+        let mh_result = unsafe {
+            MH_WRabbitSetMode(
+                self.index,
+                !boot_from_script as i32,
+                reinit_with_mode as i32,
+                mode as i32)
+            };
+        mh_to_result!(mh_result, ()).map_err(|e| self.device_error(e))
+    }
+
+    fn set_wrabbit_time(&mut self, time_high_dw : u32, time_low_dw : u32) -> CheckedResult<(), i32> {
+        let mh_result = unsafe { MH_WRabbitSetTime(self.index, time_high_dw, time_low_dw) };
+        mh_to_result!(mh_result, ()).map_err(|e| self.device_error(e))
+    }
+
+    fn get_wrabbit_time(&self) -> CheckedResult<(u32, u32, u32), i32> {
+        let mut time_high_dw = 0u32;
+        let mut time_low_dw = 0u32;
+        let mut subsec_16_ns = 0u32;
+        let mh_result = unsafe { MH_WRabbitGetTime(self.index, &mut time_high_dw, &mut time_low_dw, &mut subsec_16_ns) };
+        mh_to_result!(mh_result, (time_high_dw, time_low_dw, subsec_16_ns)).map_err(|e| self.device_error(e))
+    }
+
+    fn get_wrabbit_status(&self) -> CheckedResult<WrStatus, i32> {
+        let mut status = 0;
+        let mh_result = unsafe { MH_WRabbitGetStatus(self.index, &mut status) };
+        mh_to_result!(mh_result, WrStatus::from_bits(status)).map_err(|e| self.device_error(e))
+    }
+
+    /// When the MultiHarp’s WR core has received the command gui
+    /// (should be the last line of the init script) it sends terminal
+    /// output describing its state. 
+    /// This needs to be done repeatedly.
+    /// The output will contain escape sequences for control of
+    /// text color, screen refresh, etc. In order to present it
+    /// correctly these escape sequences must be interpreted and
+    /// translated to the corresponding control mechanisms of
+    /// the chosen display scheme. To take care of this the data can
+    /// be sent to a terminal emulator. Note that this is read-only.
+    /// There is currently no way of injecting commands to the WR
+    /// core’s console prompt.
+    fn get_wrabbit_term_output(&self) -> CheckedResult<String, i32> {
+        let mut buffer = [0 as c_char; mhconsts::WR_TERM_LEN];
+        let mut term_output_chars = 0;
+        let mh_result = unsafe { MH_WRabbitGetTermOutput(self.index, buffer.as_mut_ptr(), &mut term_output_chars) };
+
+        let term_output = buffer.iter()
+            .take(term_output_chars.max(0) as usize)
+            .map(|c| *c as u8 as char)
+            .collect::<String>();
+
+        mh_to_result!(mh_result, term_output).map_err(|e| self.device_error(e))
+    }
+
+    #[cfg_attr(not(feature = "MHLv3_0_0"), allow(unused_variables))]
+    fn ext_fpga_init_link(&mut self, link : i32, on : bool) -> CheckedResult<(), i32> {
+        #[cfg(feature = "MHLv3_0_0")]
+        {
+            let mh_result = unsafe { MH_ExtFPGAInitLink(self.index, link, on as i32) };
+            mh_to_result!(mh_result, ()).map_err(|e| self.device_error(e))?;
+            audit::log_setting(&self.serial, &format!("ext_fpga_link_{}", link), None, on);
+            Ok(())
         }
+        #[cfg(not(feature = "MHLv3_0_0"))]
+        Err(PatinaError::FeatureNotAvailable("External FPGA (MHLib 3.0)".to_string()))
+    }
 
-        let mh_result = unsafe { MH_SetRowFilter(
-            self.index, row, time_range, match_cnt, inverse as i32, use_channels, pass_channels
-        ) };
+    #[cfg_attr(not(feature = "MHLv3_0_0"), allow(unused_variables))]
+    fn ext_fpga_link_status(&self, link : i32) -> CheckedResult<u32, i32> {
+        #[cfg(feature = "MHLv3_0_0")]
+        {
+            let mut status = 0u32;
+            let mh_result = unsafe { MH_ExtFPGAGetLinkStatus(self.index, link, &mut status) };
+            mh_to_result!(mh_result, status).map_err(|e| self.device_error(e))
+        }
+        #[cfg(not(feature = "MHLv3_0_0"))]
+        Err(PatinaError::FeatureNotAvailable("External FPGA (MHLib 3.0)".to_string()))
+    }
 
-        mh_to_result!(mh_result, ()).map_err(|e| PatinaError::from(e))
+    #[cfg_attr(not(feature = "MHLv3_0_0"), allow(unused_variables))]
+    fn ext_fpga_set_mode(&mut self, mode : ExtFpgaMode, loopback : ExtFpgaLoopback) -> CheckedResult<(), i32> {
+        #[cfg(feature = "MHLv3_0_0")]
+        {
+            let mh_result = unsafe { MH_ExtFPGASetMode(self.index, mode as i32, loopback as i32) };
+            mh_to_result!(mh_result, ()).map_err(|e| self.device_error(e))?;
+            audit::log_setting(&self.serial, "ext_fpga_mode", None, (mode, loopback));
+            Ok(())
+        }
+        #[cfg(not(feature = "MHLv3_0_0"))]
+        Err(PatinaError::FeatureNotAvailable("External FPGA (MHLib 3.0)".to_string()))
     }
 
-    /// When the filter is disabled, all events are passed.
-    fn enable_row_event_filter(&self, row : i32, enable : bool) -> CheckedResult<(), i32> {
-        if (row < ROWIDXMIN || row > ROWIDXMAX) {
-            return Err(PatinaError::ArgumentError(
-                "row".to_string(),
-                row,
-                format!("Row must be between {} and {}", ROWIDXMIN, ROWIDXMAX))
-            );
+    fn ext_fpga_reset_stream_fifos(&mut self) -> CheckedResult<(), i32> {
+        #[cfg(feature = "MHLv3_0_0")]
+        {
+            let mh_result = unsafe { MH_ExtFPGAResetStreamFifos(self.index) };
+            mh_to_result!(mh_result, ()).map_err(|e| self.device_error(e))
         }
+        #[cfg(not(feature = "MHLv3_0_0"))]
+        Err(PatinaError::FeatureNotAvailable("External FPGA (MHLib 3.0)".to_string()))
+    }
 
-        let mh_result = unsafe { MH_EnableRowFilter(self.index, row, enable as i32) };
-        mh_to_result!(mh_result, ()).map_err(|e| PatinaError::from(e))
+    #[cfg_attr(not(feature = "MHLv3_0_0"), allow(unused_variables))]
+    fn ext_fpga_user_command(&mut self, write : bool, addr : u32, data : u32) -> CheckedResult<u32, i32> {
+        #[cfg(feature = "MHLv3_0_0")]
+        {
+            let mut data = data;
+            let mh_result = unsafe { MH_ExtFPGAUserCommand(self.index, write as i32, addr, &mut data) };
+            mh_to_result!(mh_result, data).map_err(|e| self.device_error(e))
+        }
+        #[cfg(not(feature = "MHLv3_0_0"))]
+        Err(PatinaError::FeatureNotAvailable("External FPGA (MHLib 3.0)".to_string()))
     }
+}
 
+/// Event filtering functionality
+#[cfg(all(feature = "MHLib", feature = "MHLv3_1_0"))]
+impl MultiHarp150 {
     /// This sets the parameters for the Main Filter implemented in the
     /// main FPGA processing the aggregated events arriving from the row FPGAs.
     /// The Main Filter can therefore act on all channels of the MutiHarp device
@@ -1861,145 +2775,54 @@ impl MultiHarp150 {
     /// Filters are early data reduction, so as to not overload the Main
     /// Filter, and the pos- sible need for more complex filters, e.g. with
     /// different time ranges.
-    fn set_main_event_filter_params(&self, time_range : i32, match_cnt : i32, inverse : bool)
+    pub fn set_main_event_filter_params(&mut self, time_range : i32, match_cnt : i32, inverse : bool)
     -> CheckedResult<(), i32> {
-        if (time_range < TIME_RANGEMIN || time_range > TIME_RANGEMAX) {
-            return Err(PatinaError::ArgumentError(
-                "time_range".to_string(),
-                time_range,
-                format!("Time range must be between {} and {}", TIME_RANGEMIN, TIME_RANGEMAX))
-            );
-        }
-
-        if (match_cnt < MATCHCNTMIN || match_cnt > MATCHCNTMAX) {
-            return Err(PatinaError::ArgumentError(
-                "match_cnt".to_string(),
-                match_cnt,
-                format!("Match count must be between {} and {}", MATCHCNTMIN, MATCHCNTMAX))
-            );
-        }
-
-        let mh_result = unsafe { MH_SetMainFilterParams(self.index, time_range, match_cnt, inverse as i32) };
-        mh_to_result!(mh_result, ()).map_err(|e| PatinaError::from(e))
+        let time_range = validation::validate(&self.serial, "time_range", time_range, mhconsts::TIMERANGEMIN..=mhconsts::TIMERANGEMAX, "Time range")?;
+        let match_cnt = validation::validate(&self.serial, "match_cnt", match_cnt, mhconsts::MATCHCNTMIN..=mhconsts::MATCHCNTMAX, "Match count")?;
+        let mh_result = unsafe { MH_SetMainEventFilterParams(self.index, time_range, match_cnt, inverse as i32) };
+        mh_to_result!(mh_result, ()).map_err(|e| self.device_error(e))
     }
 
-    fn set_main_event_filter_channels(&self, row : i32, use_channels : i32, pass_channels : i32)
+    /// Marks the channels of `row` the Main Filter uses and passes. The
+    /// sync channel can only be marked in row 0.
+    pub fn set_main_event_filter_channels(&mut self, row : i32, use_channels : ChannelMask, pass_channels : ChannelMask)
     -> CheckedResult<(), i32> {
-        if (row < ROWIDXMIN || row > ROWIDXMAX) {
+        let row = validation::validate(&self.serial, "row", row, mhconsts::ROWIDXMIN..=mhconsts::ROWIDXMAX, "Row")?;
+        if row != 0 && (use_channels.has_sync() || pass_channels.has_sync()) {
             return Err(PatinaError::ArgumentError(
                 "row".to_string(),
                 row,
-                format!("Row must be between {} and {}", ROWIDXMIN, ROWIDXMAX))
+                "The sync channel can only be marked in row 0".to_string())
             );
         }
 
-        let mh_result = unsafe { MH_SetMainFilterChannels(self.index, row, use_channels, pass_channels) };
-        mh_to_result!(mh_result, ()).map_err(|e| PatinaError::from(e))
-    }
-
-    fn enable_main_event_filter(&self, enable : bool) -> MultiHarpResult<()> {
-        let mh_result = unsafe { MH_EnableMainFilter(self.index, enable as i32) };
-        mh_to_result!(mh_result, ())
-    }
-
-    /// One important purpose of the event filters is to reduce USB load.
-    /// When the input data rates are higher than the USB bandwith,
-    /// there will at some point be a FiFo overrun. It may under such
-    /// conditions be difficult to empirically optimize the filter settings.
-    /// Setting filter test mode disables all data transfers into the FiFo
-    /// so that a test measurement can be run without interruption by a
-    /// FiFo overrun. The library routines MH_GetRowFilteredRates and
-    /// MH_GetMainFilteredRates can then be used to monitor the count rates
-    /// after the Row Filter and after the Main Filter. When the filtering
-    /// effect is satisfactory the test mode can be switched off again to
-    /// perform the regular measurement.
-    /// 
-    /// ## Arguments
-    /// 
-    /// * `test_mode` - Whether to enable or disable the filter test mode.
-    /// If true, the filter test mode is enabled. If false, the filter test
-    /// mode is disabled.
-    fn set_filter_test_mode(&self, test_mode : bool) -> MultiHarpResult<()> {
-        let mh_result = unsafe { MH_SetFilterTestMode(self.index, enable as i32) };
-        mh_to_result!(mh_result, ())
-    }
-
-    ///This call retrieves the count rates after the Row Filters before
-    /// entering the Main Filter. A measurement must be running to obtain
-    /// valid results. Allow at least 100 ms to get a new reading. This is
-    /// the gate time of the rate counters.
-    /// 
-    /// ## Returns
-    /// 
-    /// * `i32` - The sync rate after the filter
-    /// * `Vec<i32>` - The count rates of all channels after the filter
-    fn get_row_filtered_rates(&self) -> MultiHarpResult<(i32, Vec<i32>)> {
-        let mut sync_rate : i32 = 0;
-        let mut count_rates = vec![0i32; self.num_channels as usize];
-        let mh_result = unsafe { MH_GetRowFilteredRates(self.index, &mut sync_rate, count_rates.as_mut_ptr()) };
-        mh_to_result!(mh_result, (sync_rate, count_rates))
+        let mh_result = unsafe { MH_SetMainEventFilterChannels(self.index, row, use_channels.bits(), pass_channels.bits()) };
+        mh_to_result!(mh_result, ()).map_err(|e| self.device_error(e))
     }
 
-    /// This call retrieves the count rates after the Main Filter. A measurement
-    /// must be running to obtain valid results. Allow at least 100 ms to get a
-    /// new reading. This is the gate time of the rate counters.
-    /// 
-    /// ## Returns
-    /// 
-    /// * `i32` - The sync rate after the filter
-    /// * `Vec<i32>` - The count rates of all channels after the filter
-    fn get_main_filtered_rates(&self) -> MultiHarpResult<(i32, Vec<i32>)> {
-        let mut sync_rate : i32 = 0;
-        let mut count_rates = vec![0i32; self.num_channels as usize];
-        let mh_result = unsafe { MH_GetMainFilteredRates(self.index, &mut sync_rate, count_rates.as_mut_ptr()) };
-        mh_to_result!(mh_result, (sync_rate, count_rates))
+    /// Marks the channels and sync use of `filter` for the Main Filter,
+    /// in every row of this model.
+    pub fn set_main_event_filter_for_channels(&mut self, filter : &MainEventFilter) -> CheckedResult<(), i32> {
+        for mut row in row_filter_channels(self, &filter.use_channels, &filter.pass_channels)? {
+            if row.row == 0 {
+                if filter.use_sync {
+                    row.use_channels |= ChannelMask::SYNC;
+                }
+                if filter.pass_sync {
+                    row.pass_channels |= ChannelMask::SYNC;
+                }
+            }
+            self.set_main_event_filter_channels(row.row, row.use_channels, row.pass_channels)?;
+        }
+        Ok(())
     }
 }
 
-/// WhiteRabbit functionality -- not
-/// implemented for debug tools.
+/// The White Rabbit SFP calibration data, not yet part of
+/// `WhiteRabbit`.
 #[cfg(feature = "MHLib")]
 #[allow(dead_code)]
 impl MultiHarp150 {
-    /// Returns the MAC address of the device as a string of length 6.
-    fn wrabbit_get_mac(&self) -> MultiHarpResult<String> {
-        let mut mac = [0 as c_char; mhconsts::WR_MAC_LEN];
-        let mh_result = unsafe { MH_WRabbitGetMAC(self.index, mac.as_mut_ptr()) };
-        mh_to_result!(mh_result, unsafe { CStr::from_ptr(mac.as_mut_ptr()) }.to_str().unwrap().to_string())
-    }
-
-    /// Set the MAC address of the device. Must be a string of length 6.
-    /// 
-    /// Note: The MAC address must be unique within the network you are using
-    fn wrabbit_set_mac(&self, mac : &str) -> CheckedResult<(), usize> {
-        if mac.len() != mhconsts::WR_MAC_LEN {
-            return Err(
-                PatinaError::ArgumentError(
-                "mac".to_string(),
-                mac.len() as usize,
-                format!("MAC address must be {} characters long", mhconsts::WR_MAC_LEN))
-            );
-        }
-        let mac = CString::new(mac).unwrap();
-        let mh_result = unsafe { MH_WRabbitSetMAC(self.index, mac.as_ptr()) };
-        mh_to_result!(mh_result, ()).map_err(|e| PatinaError::from(e))
-    }
-
-    /// Retrieves the White Rabbit initialization script from the MultiHarp's EEPROM.
-    fn wrabbit_get_init_script(&self) -> MultiHarpResult<String> {
-        let mut script = [0 as c_char; mhconsts::WR_SCRIPT_LEN];
-        let mh_result = unsafe { MH_WRabbitGetInitScript(self.index, script.as_mut_ptr()) };
-        mh_to_result!(mh_result, unsafe { CStr::from_ptr(script.as_mut_ptr()) }.to_str().unwrap().to_string())
-    }
-
-    /// Sets the White Rabbit initialization script in the MultiHarp's EEPROM.
-    /// Lines are separated by a newline character.
-    fn wrabbit_set_init_script(&self, script : &str) -> MultiHarpResult<()> {
-        let script = CString::new(script).unwrap();
-        let mh_result = unsafe { MH_WRabbitSetInitScript(self.index, script.as_ptr()) };
-        mh_to_result!(mh_result, ())
-    }
-
     /// Used to retrieve SFP module calibration data (if any) from EEPROM.
     /// 
     /// ## Returns
@@ -2066,115 +2889,168 @@ impl MultiHarp150 {
         ) };
         mh_to_result!(mh_result, ())
     }
+}
 
-    /// Set WhiteRabbit link on or off.
-    fn set_wrabbit_link(&self, on : bool) -> MultiHarpResult<()> {
-        let mh_result = unsafe { MH_WRabbitInitLink(self.index, on as i32) };
-        mh_to_result!(mh_result, ())
-    }
-
-    /// Set how the White Rabbit core boots.
-    /// 
-    /// ## Arguments
-    /// 
-    /// * `boot_from_script` - Whether to boot from the script.
-    /// If true, boots from script in EEPROM (set with `wrabbit_set_init_script`).
-    /// 
-    /// * `reinit_with_mode` - Whether to reinitialize with a new mode
-    /// (provided in the third argument)
-    /// 
-    /// * `mode` - The mode to set the WRabbit to. Must be between 0 and 3.
-    /// 0 : Off, 1 : Slave, 2 : Master, 3 : GrandMaster
-    fn set_wrabbit_mode(&self, boot_from_script : bool, reinit_with_mode : bool, mode : WRMode) -> MultiHarpResult<()> {
-        let mh_result = unsafe { 
-            MH_WRabbitSetMode(
-        self.index,
-!boot_from_script as i32,
-                reinit_with_mode as i32,
-                mode as i32)
-            };
-        mh_to_result!(mh_result, ())
-    }
-
-    /// Used to set the current UTC time of a White Rabbit code for
-    /// a device configured as a WR master. If a slave is connected,
-    /// it will be set to the same time.
-    fn set_wrabbit_time(&self, time_high_dw : u32, time_low_dw : u32) -> MultiHarpResult<()> {
-        let mh_result = unsafe { MH_WRabbitSetTime(self.index, time_high_dw, time_low_dw) };
-        mh_to_result!(mh_result, ())
-    }
-
-    /// Retrieve the UTC time of a MultiHarp's WR core.
-    /// 
-    /// ## Returns
-    /// 
-    /// * (time_high_dw, time_low_dw, subsec_16_ns) - The time in 3 parts:
-    ///    - `time_high_dw` - The most significant 32 bits of the time in secsonds since epoch
-    ///    - `time_low_dw` - The lowest 32 bits of the time in seconds since epoch
-    ///    - `subsec_16_ns` - The subsecond part of the time in 16 ns units.
-    fn get_wrabbit_time(&self) -> MultiHarpResult<(u32, u32, u32)> {
-        let mut time_high_dw = 0u32;
-        let mut time_low_dw = 0u32;
-        let mut subsec_16_ns = 0u32;
-        let mh_result = unsafe { MH_WRabbitGetTime(self.index, &mut time_high_dw, &mut time_low_dw, &mut subsec_16_ns) };
-        mh_to_result!(mh_result, (time_high_dw, time_low_dw, subsec_16_ns))
-    }
-
-    /// Get the status of the WRabbit core. Interpreted as a
-    /// bitfield, using the masks in `mhconsts`.
-    fn get_wrabbit_status(&self) -> MultiHarpResult<i32> {
-        let mut status = 0;
-        let mh_result = unsafe { MH_WRabbitGetStatus(self.index, &mut status) };
-        mh_to_result!(mh_result, status)
+#[cfg(all(feature = "MHLib", feature = "async"))]
+#[async_trait]
+impl AsyncMultiHarpDevice for MultiHarp150 {
+    async fn read_fifo_async(&mut self, buffer : &mut Vec<u32>) -> CheckedResult<i32, u32> {
+        if self.mode == mhconsts::MeasurementMode::Histogramming {
+            return Err(PatinaError::WrongMode("read_fifo_async".to_string(), self.mode));
+        }
+        if buffer.len() < mhconsts::TTREADMAX {
+            return Err(PatinaError::ArgumentError(
+                "buffer".to_string(),
+                buffer.len() as u32,
+                format!("Buffer must be at least {} long", mhconsts::TTREADMAX))
+            );
+        }
+        // The call may outlive this future, so it reads into a buffer of
+        // its own, and `buffer` is only written once it has returned.
+        let index = self.index;
+        let (records, mh_result, count) = tokio::task::spawn_blocking(move || {
+            let mut records = vec![0u32; mhconsts::TTREADMAX];
+            let mut count = 0;
+            let mh_result = unsafe { MH_ReadFiFo(index, records.as_mut_ptr(), &mut count) };
+            (records, mh_result, count)
+        }).await.unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic()));
+        let count = mh_to_result!(mh_result, count).map_err(|e| self.device_error(e))?;
+        buffer[..count as usize].copy_from_slice(&records[..count as usize]);
+        Ok(count)
     }
+}
 
-    /// When the MultiHarp’s WR core has received the command gui
-    /// (should be the last line of the init script) it sends terminal
-    /// output describing its state. 
-    /// This needs to be done repeatedly.
-    /// The output will contain escape sequences for control of
-    /// text color, screen refresh, etc. In order to present it
-    /// correctly these escape sequences must be interpreted and
-    /// translated to the corresponding control mechanisms of
-    /// the chosen display scheme. To take care of this the data can
-    /// be sent to a terminal emulator. Note that this is read-only.
-    /// There is currently no way of injecting commands to the WR
-    /// core’s console prompt.
-    fn get_wrabbit_term_output(&self) -> MultiHarpResult<String> {
-        let mut buffer = [0 as c_char; mhconsts::WR_TERM_LEN];
-        let mut term_output_chars = 0;
-        let mh_result = unsafe { MH_WRabbitGetTermOutput(self.index, buffer.as_mut_ptr(), &mut term_output_chars) };
+/// Reads the model code, part number and version of an open device.
+#[cfg(feature = "MHLib")]
+fn query_hardware_info(index : i32) -> MultiHarpResult<(String, String, String)> {
+    let mut model_code = [0 as c_char; 24];
+    let mut part_number = [0 as c_char; 8];
+    let mut version = [0 as c_char; 8];
+
+    mh_to_result!(
+        unsafe { MH_GetHardwareInfo(index, model_code.as_mut_ptr(), part_number.as_mut_ptr(), version.as_mut_ptr()) },
+        (
+            unsafe { CStr::from_ptr(model_code.as_mut_ptr()) }.to_str().unwrap().to_string(),
+            unsafe { CStr::from_ptr(part_number.as_mut_ptr()) }.to_str().unwrap().to_string(),
+            unsafe { CStr::from_ptr(version.as_mut_ptr()) }.to_str().unwrap().to_string()
+        )
+    )
+}
 
-        // Take only the `term_output_chars` from `buffer` and
-        // copy them to a string to return
+/// One line identity for logs, e.g.
+/// `MultiHarp 150 4P #1044272 (4 channels, firmware 1.0)`
+pub(crate) fn fmt_identity(
+    f : &mut std::fmt::Formatter<'_>,
+    model : &str,
+    serial : &str,
+    num_channels : i32,
+    firmware : &str,
+) -> std::fmt::Result {
+    write!(f, "{} #{} ({} channels, firmware {})", model, serial, num_channels, firmware)
+}
 
-        // Maybe a bad implementation...
-        let mut term_output = String::new();
-        for i in 0..term_output_chars {
-            term_output.push(buffer[i as usize] as u8 as char);
+#[cfg(feature = "MHLib")]
+impl MultiHarp150 {
+    /// Channels `MHLib` may write into the buffers of the calls returning
+    /// every channel at once: as many as the model can have, which can be
+    /// more than the device reports, or `MAXINPCHAN` for a model this
+    /// crate doesn't know.
+    fn buffer_channels(&self) -> usize {
+        DeviceModel::from_model_string(&self.hardware_info.0).capabilities()
+            .map_or(mhconsts::MAXINPCHAN, |c| c.max_channels)
+            .max(self.num_channels) as usize
+    }
+
+    /// Converts an error returned by `MHLib`, attaching the debug
+    /// information of the device to hardware faults, and adds it to the
+    /// event log.
+    fn device_error<T>(&self, error : MultiHarpError) -> PatinaError<T>
+    where T : std::fmt::Display + std::fmt::Debug {
+        if error.is_hardware_error() {
+            hardware_error(self, Some(error))
+        } else {
+            events::record(&self.serial, DeviceEventKind::Error, format!("{}", error));
+            PatinaError::from(error)
         }
-
-        mh_to_result!(mh_result, term_output)
     }
 }
 
-// #[cfg(feature = "async")]
-// impl AsyncMultiHarpDevice for MultiHarp150 {
-//     /// Implements an asynchronous version of `read_fifo` for the MultiHarp150 for
-//     /// single-threaded asynchronous programming models.
-//     async fn read_fifo_async<'a, 'b>(&'a self, buffer : &'b mut Vec<u32>) -> AsyncCheckedResult<i32, u32> { 
-//         async move {
-//             self.read_fifo(buffer).map_err(|e| e.into_future())
-//         }.await
-//     }
-// }
+#[cfg(feature = "MHLib")]
+impl std::fmt::Display for MultiHarp150 {
+    fn fmt(&self, f : &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (model, _, firmware) = &self.hardware_info;
+        fmt_identity(f, model, &self.serial, self.num_channels, firmware)
+    }
+}
 
 #[cfg(feature = "MHLib")]
 impl Drop for MultiHarp150 {
     fn drop(&mut self) {
         let mh_return = unsafe { MH_CloseDevice(self.index) };
+        enumeration::release(self.index);
         if mh_return != 0 {
             eprintln!("Error closing device {}: {}", self.index, error_to_string(mh_return as i32).unwrap());
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_photon_fields() {
+        // T3: photon on channel 3, dtime 1234, nsync 567
+        let t3 = (3 << 25) | (1234 << 10) | 567;
+        assert!(!photon_special(t3));
+        assert_eq!(photon_to_channel(t3), 3);
+        assert_eq!(photon_to_arrival_t3(t3), 1234);
+        assert_eq!(photon_to_sync_counter(t3), 567);
+
+        // T2: markers 0b0101 at timetag 0x1ABCDEF
+        let t2 = mhconsts::SPECIAL | (0b0101 << 25) | 0x1ABCDEF;
+        assert!(photon_special(t2));
+        assert_eq!(photon_to_channel(t2), 0b0101);
+        assert_eq!(photon_to_arrival_t2(t2), 0x1ABCDEF);
+        // An overflow sets all six channel bits
+        assert_eq!(photon_to_channel(mhconsts::SPECIAL | mhconsts::CHANNEL), 63);
+    }
+
+    #[test]
+    fn test_row_filter_channels_out_of_range() {
+        use crate::{DebugMultiHarp150, MeasurementMode, ReferenceClock};
+
+        let mut mh = DebugMultiHarp150::default();
+        mh.init(MeasurementMode::T2, ReferenceClock::Internal).unwrap();
+        let channels = mh.num_input_channels().unwrap();
+
+        for channel in [channels, -1] {
+            let filter = MainEventFilter::coincidences(&[0, channel], 1000);
+            match row_filter_channels(&mh, &filter.use_channels, &filter.pass_channels) {
+                Err(PatinaError::ArgumentError(name, value, _)) => {
+                    assert_eq!(name, "channel");
+                    assert_eq!(value, channel);
+                },
+                other => panic!("Expected an ArgumentError, got {:?}", other),
+            }
+            assert!(matches!(mh.set_row_event_filters(&filter), Err(PatinaError::ArgumentError(..))));
+        }
+        assert!(row_filter_channels(&mh, &[0, channels - 1], &[]).is_ok());
+    }
+
+    /// Needs a connected MultiHarp: `cargo test -- --ignored`
+    #[cfg(all(feature = "MHLib", feature = "MHLv3_1_0"))]
+    #[test]
+    #[ignore]
+    fn test_multiharp150_overflow_compression() {
+        use crate::{MeasurementMode, ReferenceClock};
+        let _strictness = crate::version::StrictnessGuard::lock();
+
+        let mut mh = MultiHarp150::open(None).unwrap();
+        mh.init(MeasurementMode::T2, ReferenceClock::Internal).unwrap();
+        mh.set_overflow_compression(mhconsts::HOLDTIMEMAX).unwrap();
+        assert_eq!(mh.applied.ofl_compression, Some(mhconsts::HOLDTIMEMAX));
+        assert!(mh.set_overflow_compression(mhconsts::HOLDTIMEMAX + 1).is_err());
+        assert_eq!(mh.snapshot_config().unwrap().ofl_compression, Some(mhconsts::HOLDTIMEMAX));
+    }
+}
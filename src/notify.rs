@@ -0,0 +1,357 @@
+//! Tells someone when an unattended acquisition ends, fails, or sets off
+//! an alarm. A `Notifier` holds `NotificationHook`s and is given to a
+//! `Pipeline` with `set_notifier`, which notifies at the end of each
+//! acquisition, or to an alarm with `AlarmAction::Notify`.
+//!
+//! Three hooks come with the crate: `CommandHook` runs a program,
+//! `StatusFileHook` rewrites a JSON status file and `WebhookHook` POSTs
+//! the same JSON to a URL. A hook that fails is reported in the device's
+//! event log and doesn't stop the others.
+
+use std::fmt::Display;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::alarms::AlarmTriggered;
+use crate::events::{self, DeviceEventKind};
+use crate::metadata::json_string;
+
+/// How long a webhook may take to connect and answer
+pub const WEBHOOK_TIMEOUT : Duration = Duration::from_secs(10);
+
+/// What happened
+#[derive(Debug, Clone, PartialEq)]
+pub enum Notification {
+    /// An acquisition ended without errors
+    Completed { serial : String, timestamp : SystemTime },
+    /// An acquisition ended with `error`
+    Failed { serial : String, error : String, timestamp : SystemTime },
+    /// An alarm went off
+    Alarm(AlarmTriggered),
+}
+
+impl Notification {
+    pub fn completed(serial : &str) -> Self {
+        Notification::Completed { serial : serial.to_string(), timestamp : SystemTime::now() }
+    }
+
+    pub fn failed<E : Display>(serial : &str, error : E) -> Self {
+        Notification::Failed { serial : serial.to_string(), error : error.to_string(), timestamp : SystemTime::now() }
+    }
+
+    /// `"completed"`, `"failed"` or `"alarm"`
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Notification::Completed { .. } => "completed",
+            Notification::Failed { .. } => "failed",
+            Notification::Alarm(_) => "alarm",
+        }
+    }
+
+    pub fn serial(&self) -> &str {
+        match self {
+            Notification::Completed { serial, .. } | Notification::Failed { serial, .. } => serial,
+            Notification::Alarm(alarm) => &alarm.serial,
+        }
+    }
+
+    pub fn timestamp(&self) -> SystemTime {
+        match self {
+            Notification::Completed { timestamp, .. } | Notification::Failed { timestamp, .. } => *timestamp,
+            Notification::Alarm(alarm) => alarm.timestamp,
+        }
+    }
+
+    /// The notification as a JSON object, e.g.
+    /// `{"event":"failed","serial":"1044272","timestamp_us":1700000000000000,"message":"Acquisition on 1044272 failed: ..."}`
+    pub fn to_json(&self) -> String {
+        let timestamp_us = self.timestamp().duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros())
+            .unwrap_or(0);
+        format!(
+            "{{\"event\":{},\"serial\":{},\"timestamp_us\":{},\"message\":{}}}",
+            json_string(self.kind()), json_string(self.serial()), timestamp_us, json_string(&self.to_string()),
+        )
+    }
+}
+
+impl Display for Notification {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Notification::Completed { serial, .. } => write!(f, "Acquisition on {} completed", serial),
+            Notification::Failed { serial, error, .. } => write!(f, "Acquisition on {} failed: {}", serial, error),
+            Notification::Alarm(alarm) => write!(f, "{} on {}", alarm, alarm.serial),
+        }
+    }
+}
+
+/// Something to do with each `Notification`, on the thread that raised
+/// it. The `String` describes what went wrong.
+pub trait NotificationHook : Send + Sync {
+    fn notify(&self, notification : &Notification) -> Result<(), String>;
+}
+
+impl<F> NotificationHook for F where F : Fn(&Notification) -> Result<(), String> + Send + Sync {
+    fn notify(&self, notification : &Notification) -> Result<(), String> {
+        self(notification)
+    }
+}
+
+/// The hooks to call with each notification, in the order they were added.
+///
+/// ## Example
+///
+/// ```
+/// use multi_harp_patina::*;
+///
+/// let status = std::env::temp_dir().join("notify_example_status.json");
+/// let notifier = Notifier::new()
+///     .with_hook(StatusFileHook::new(&status))
+///     .with_hook(CommandHook::new("echo").arg("acquisition finished"));
+///
+/// let mut mh = DebugMultiHarp150::default();
+/// mh.init(MeasurementMode::T3, ReferenceClock::Internal).unwrap();
+/// let mut pipeline = Pipeline::new();
+/// pipeline.set_notifier(notifier);
+/// pipeline.run(&mut mh, 50).unwrap();
+/// assert!(std::fs::read_to_string(&status).unwrap().contains("\"event\":\"completed\""));
+/// ```
+#[derive(Clone, Default)]
+pub struct Notifier {
+    hooks : Vec<Arc<dyn NotificationHook>>,
+}
+
+impl std::fmt::Debug for Notifier {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Notifier({} hooks)", self.hooks.len())
+    }
+}
+
+impl Notifier {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `hook`, called after those added before it
+    pub fn with_hook<H : NotificationHook + 'static>(mut self, hook : H) -> Self {
+        self.hooks.push(Arc::new(hook));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+
+    /// Calls every hook with `notification`, recording the failures of
+    /// any as warnings of the device in its event log. Returns how many
+    /// hooks failed.
+    pub fn notify(&self, notification : &Notification) -> usize {
+        let mut failed = 0;
+        for hook in self.hooks.iter() {
+            if let Err(e) = hook.notify(notification) {
+                let message = format!("Notification hook failed: {}", e);
+                events::record(notification.serial(), DeviceEventKind::Warning, message.clone());
+                eprintln!("Warning: {}", message);
+                failed += 1;
+            }
+        }
+        failed
+    }
+}
+
+/// Runs a program and waits for it, with the notification in the
+/// environment variables `MHP_EVENT` (see `Notification::kind`),
+/// `MHP_SERIAL` and `MHP_MESSAGE`. Fails if the program exits with an
+/// error.
+#[derive(Debug, Clone)]
+pub struct CommandHook {
+    program : String,
+    args : Vec<String>,
+}
+
+impl CommandHook {
+    pub fn new(program : &str) -> Self {
+        CommandHook { program : program.to_string(), args : Vec::new() }
+    }
+
+    /// Appends an argument
+    pub fn arg(mut self, arg : &str) -> Self {
+        self.args.push(arg.to_string());
+        self
+    }
+}
+
+impl NotificationHook for CommandHook {
+    fn notify(&self, notification : &Notification) -> Result<(), String> {
+        let status = Command::new(&self.program)
+            .args(&self.args)
+            .env("MHP_EVENT", notification.kind())
+            .env("MHP_SERIAL", notification.serial())
+            .env("MHP_MESSAGE", notification.to_string())
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .status()
+            .map_err(|e| format!("Couldn't run `{}`: {}", self.program, e))?;
+        if !status.success() {
+            return Err(format!("`{}` exited with {}", self.program, status));
+        }
+        Ok(())
+    }
+}
+
+/// Replaces the contents of a file with `Notification::to_json` of the
+/// latest notification, for a monitor that polls it. The file is
+/// written beside and renamed over, so it is never seen half written.
+#[derive(Debug, Clone)]
+pub struct StatusFileHook {
+    path : PathBuf,
+}
+
+impl StatusFileHook {
+    pub fn new<P : Into<PathBuf>>(path : P) -> Self {
+        StatusFileHook { path : path.into() }
+    }
+}
+
+impl NotificationHook for StatusFileHook {
+    fn notify(&self, notification : &Notification) -> Result<(), String> {
+        let mut partial = self.path.clone().into_os_string();
+        partial.push(".tmp");
+        let partial = PathBuf::from(partial);
+        std::fs::write(&partial, notification.to_json() + "\n")
+            .and_then(|_| std::fs::rename(&partial, &self.path))
+            .map_err(|e| format!("Couldn't write {}: {}", self.path.display(), e))
+    }
+}
+
+/// POSTs `Notification::to_json` to a URL, failing unless the answer is
+/// a 2xx status. `http://` URLs are sent directly; `https://` URLs are
+/// sent with the system's `curl`, which ships with Linux, macOS and
+/// Windows 10 and later.
+#[derive(Debug, Clone)]
+pub struct WebhookHook {
+    url : String,
+    timeout : Duration,
+}
+
+impl WebhookHook {
+    pub fn new(url : &str) -> Self {
+        WebhookHook { url : url.to_string(), timeout : WEBHOOK_TIMEOUT }
+    }
+
+    pub fn with_timeout(mut self, timeout : Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    fn post_http(&self, address : &str, body : &str) -> Result<(), String> {
+        let (host, path) = match address.find('/') {
+            Some(slash) => (&address[..slash], &address[slash..]),
+            None => (address, "/"),
+        };
+        let authority = if host.contains(':') { host.to_string() } else { format!("{}:80", host) };
+        let address = std::net::ToSocketAddrs::to_socket_addrs(&authority)
+            .map_err(|e| format!("Couldn't resolve {}: {}", host, e))?
+            .next()
+            .ok_or_else(|| format!("Couldn't resolve {}", host))?;
+        let mut stream = TcpStream::connect_timeout(&address, self.timeout)
+            .map_err(|e| format!("Couldn't connect to {}: {}", host, e))?;
+        let _ = stream.set_read_timeout(Some(self.timeout));
+        let _ = stream.set_write_timeout(Some(self.timeout));
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            path, host, body.len(), body,
+        );
+        stream.write_all(request.as_bytes()).map_err(|e| format!("Couldn't send to {}: {}", self.url, e))?;
+
+        let mut response = Vec::new();
+        let _ = stream.read_to_end(&mut response);
+        let status_line = String::from_utf8_lossy(&response).lines().next().unwrap_or_default().to_string();
+        match status_line.split_whitespace().nth(1) {
+            Some(code) if code.starts_with('2') => Ok(()),
+            Some(_) => Err(format!("{} answered `{}`", self.url, status_line)),
+            None => Err(format!("No answer from {}", self.url)),
+        }
+    }
+
+    fn post_curl(&self, body : &str) -> Result<(), String> {
+        let output = Command::new("curl")
+            .args(["--silent", "--show-error", "--fail", "--max-time"])
+            .arg(self.timeout.as_secs().max(1).to_string())
+            .args(["--header", "Content-Type: application/json", "--data-binary", body, &self.url])
+            .stdin(Stdio::null())
+            .output()
+            .map_err(|e| format!("Couldn't run curl: {}", e))?;
+        if !output.status.success() {
+            return Err(format!("curl failed: {}", String::from_utf8_lossy(&output.stderr).trim()));
+        }
+        Ok(())
+    }
+}
+
+impl NotificationHook for WebhookHook {
+    fn notify(&self, notification : &Notification) -> Result<(), String> {
+        let body = notification.to_json();
+        match self.url.strip_prefix("http://") {
+            Some(address) => self.post_http(address, &body),
+            None if self.url.starts_with("https://") => self.post_curl(&body),
+            None => Err(format!("Unsupported webhook URL {}", self.url)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_notifier() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0u8; 1024];
+            // Until the whole body, which ends the JSON, has arrived
+            while !request.ends_with(b"}") {
+                let read = stream.read(&mut buffer).unwrap();
+                if read == 0 { break; }
+                request.extend_from_slice(&buffer[..read]);
+            }
+            stream.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let status = std::env::temp_dir().join("notify_test_status.json");
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let seen_by_hook = Arc::clone(&seen);
+        let notifier = Notifier::new()
+            .with_hook(WebhookHook::new(&format!("http://127.0.0.1:{}/hooks/mhp", port)))
+            .with_hook(StatusFileHook::new(&status))
+            .with_hook(move |n : &Notification| {
+                seen_by_hook.lock().unwrap().push(n.kind());
+                Err("pager offline".to_string())
+            });
+
+        let failed = Notification::failed("notify_test", "Sync lost");
+        assert_eq!(notifier.notify(&failed), 1);
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /hooks/mhp HTTP/1.1\r\n"));
+        assert!(request.ends_with(&failed.to_json()));
+        let written = std::fs::read_to_string(&status).unwrap();
+        assert!(written.contains("\"message\":\"Acquisition on notify_test failed: Sync lost\""));
+        assert_eq!(*seen.lock().unwrap(), vec!["failed"]);
+        assert!(events::recent_events("notify_test").last().unwrap().message.ends_with("pager offline"));
+
+        // Nothing listening any more
+        let gone = WebhookHook::new(&format!("http://127.0.0.1:{}/", port)).with_timeout(Duration::from_millis(200));
+        assert!(gone.notify(&Notification::completed("notify_test")).is_err());
+        assert!(WebhookHook::new("ftp://example.com").notify(&failed).is_err());
+    }
+}
@@ -0,0 +1,506 @@
+//! Decoded events as Arrow record batches, and a sink writing them to
+//! Parquet, which pandas, polars and most other analysis tools read
+//! directly.
+//!
+//! ## Columns
+//!
+//! * `kind`: `"photon"`, `"marker"` or `"sync"` (dictionary encoded)
+//! * `channel`: the input channel of photons, the marker bitfield of markers
+//! * `nsync` and `dtime` (T3) or `timetag` (T2), as in the records but
+//!   with the overflows accumulated
+//! * `time_ps`: time since the start of the acquisition in ps
+//!
+//! The measurement's metadata (`serial`, `mode`, `resolution_ps`,
+//! `sync_period_ps`, `num_channels`, `creator` and each label as
+//! `label.<key>`) is stored as key-value metadata of the schema.
+//!
+//! The marker events are also written to a file of their own, with the
+//! columns of `marker_schema`, so they can be read without the photons.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+
+use arrow_array::types::Int8Type;
+use arrow_array::{ArrayRef, DictionaryArray, RecordBatch, TimestampNanosecondArray, UInt16Array, UInt64Array, UInt8Array};
+use arrow_schema::{ArrowError, DataType, Field, Schema, SchemaRef, TimeUnit};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::errors::ParquetError;
+use parquet::file::properties::WriterProperties;
+
+use crate::clock::ClockMapping;
+use crate::error::{CheckedResult, PatinaError};
+use crate::markers::{marker_table_path, MarkerTable};
+use crate::mhconsts::MeasurementMode;
+use crate::multiharp::MultiHarpDevice;
+use crate::partition::{partition_path, PartitionSink, Partitioned, Partitioning};
+use crate::pipeline::{Batch, ProcessorResult, StreamInfo, StreamProcessor};
+use crate::tttr::{Decoder, EventColumns, EventKind};
+
+/// Events per record batch, and so at least per row group
+pub const DEFAULT_PARQUET_BATCH : usize = 1 << 20;
+
+/// The columns of the events of a `mode` stream, without metadata
+pub fn event_schema(mode : MeasurementMode) -> Schema {
+    let kind = DataType::Dictionary(Box::new(DataType::Int8), Box::new(DataType::Utf8));
+    let mut fields = vec![
+        Field::new("kind", kind, false),
+        Field::new("channel", DataType::UInt8, false),
+    ];
+    match mode {
+        MeasurementMode::T3 => {
+            fields.push(Field::new("nsync", DataType::UInt64, false));
+            fields.push(Field::new("dtime", DataType::UInt16, false));
+        },
+        _ => fields.push(Field::new("timetag", DataType::UInt64, false)),
+    }
+    fields.push(Field::new("time_ps", DataType::UInt64, false));
+    Schema::new(fields)
+}
+
+/// The columns of the marker table of a `mode` stream: `markers` (the
+/// bitfield), `nsync` (T3) or `timetag` (T2), `time_ps` and `host_time`,
+/// null without a `ClockMapping`.
+pub fn marker_schema(mode : MeasurementMode) -> Schema {
+    let time = match mode {
+        MeasurementMode::T3 => "nsync",
+        _ => "timetag",
+    };
+    Schema::new(vec![
+        Field::new("markers", DataType::UInt8, false),
+        Field::new(time, DataType::UInt64, false),
+        Field::new("time_ps", DataType::UInt64, false),
+        Field::new("host_time", DataType::Timestamp(TimeUnit::Nanosecond, Some("UTC".into())), true),
+    ])
+}
+
+/// Converts the rows of `table` into a record batch of `marker_schema`.
+pub fn markers_to_record_batch(table : &MarkerTable) -> Result<RecordBatch, ArrowError> {
+    let rows = table.rows();
+    let host_times = rows.iter()
+        .map(|row| row.host_time
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|since| since.as_nanos() as i64))
+        .collect::<TimestampNanosecondArray>()
+        .with_timezone("UTC");
+    let arrays : Vec<ArrayRef> = vec![
+        Arc::new(rows.iter().map(|row| row.markers).collect::<UInt8Array>()),
+        Arc::new(rows.iter().map(|row| row.time).collect::<UInt64Array>()),
+        Arc::new(rows.iter().map(|row| row.time_ps).collect::<UInt64Array>()),
+        Arc::new(host_times),
+    ];
+    RecordBatch::try_new(Arc::new(marker_schema(table.mode())), arrays)
+}
+
+/// Converts `columns` into a record batch of `event_schema(mode)`. The
+/// `time_ps` column is computed as `EventColumns::times_ps`.
+///
+/// ## Example
+///
+/// ```
+/// use multi_harp_patina::*;
+///
+/// let columns = EventColumns::from_events(&[TttrEvent::photon(1, 2, 100), TttrEvent::marker(4, 3)]);
+/// let batch = events_to_record_batch(&columns, MeasurementMode::T3, 5.0, 12_500.0).unwrap();
+/// assert_eq!(batch.num_rows(), 2);
+/// assert_eq!(batch.schema().field(2).name(), "nsync");
+/// ```
+pub fn events_to_record_batch(
+    columns : &EventColumns,
+    mode : MeasurementMode,
+    resolution : f64,
+    sync_period : f64,
+) -> Result<RecordBatch, ArrowError> {
+    let kinds = columns.kinds.iter()
+        .map(|kind| match kind {
+            EventKind::Photon => "photon",
+            EventKind::Marker => "marker",
+            EventKind::Sync => "sync",
+        })
+        .collect::<DictionaryArray<Int8Type>>();
+    let mut arrays : Vec<ArrayRef> = vec![
+        Arc::new(kinds),
+        Arc::new(UInt8Array::from(columns.channels.clone())),
+        Arc::new(UInt64Array::from(columns.time.clone())),
+    ];
+    if mode == MeasurementMode::T3 {
+        arrays.push(Arc::new(UInt16Array::from(columns.dtime.clone())));
+    }
+    arrays.push(Arc::new(UInt64Array::from(columns.times_ps(mode, resolution, sync_period))));
+    RecordBatch::try_new(Arc::new(event_schema(mode)), arrays)
+}
+
+/// Writes the events of one acquisition to a Parquet file as they are
+/// read. The schema, with the measurement's metadata, is written by
+/// `init`, as a `StreamProcessor`, or by `begin`, and the file is
+/// complete after `finish`.
+///
+/// ## Example
+///
+/// ```
+/// use std::time::Instant;
+/// use multi_harp_patina::*;
+///
+/// let info = StreamInfo {
+///     serial : "1044272".to_string(),
+///     mode : MeasurementMode::T3,
+///     resolution : 5.0,
+///     num_channels : 4,
+///     enabled_channels : vec![true; 4],
+///     overflow_compression : OverflowCompression::Compressed,
+///     labels : Labels::default(),
+/// };
+/// let path = std::env::temp_dir().join("parquet_doctest.parquet");
+/// let mut writer = ParquetWriter::create(&path).unwrap().with_sync_period(12_500.0);
+/// writer.init(&info).unwrap();
+/// let records = [pack_t3(false, 0, 100, 1), pack_t3(false, 2, 200, 3)];
+/// writer.process_batch(&Batch { index : 0, read_at : Instant::now(), records : &records, mode : MeasurementMode::T3, sync_lost : false }).unwrap();
+/// writer.finalize().unwrap();
+/// assert_eq!(writer.rows_written(), 2);
+/// ```
+pub struct ParquetWriter<W : Write + Send> {
+    inner : Option<W>,
+    writer : Option<ArrowWriter<W>>,
+    schema : Option<SchemaRef>,
+    sync_period : Option<f64>,
+    batch_size : usize,
+    compression : Compression,
+    mode : MeasurementMode,
+    resolution : f64,
+    decoder : Option<Decoder>,
+    pending : EventColumns,
+    rows : usize,
+    markers : MarkerTable,
+    marker_output : Option<PathBuf>,
+}
+
+impl ParquetWriter<File> {
+    /// Creates the file at `path`, replacing any existing file, and
+    /// writes the marker table to `marker_table_path(path, "parquet")`.
+    pub fn create<P : AsRef<Path>>(path : P) -> std::io::Result<Self> {
+        Ok(Self::new(File::create(&path)?).with_marker_output(marker_table_path(path, "parquet")))
+    }
+
+    /// Writes a file per partition of the acquisition, at
+    /// `partition_path(path, n)`, each with its marker table. T3 streams
+    /// need the `sync_period` in ps.
+    ///
+    /// ## Example
+    ///
+    /// ```no_run
+    /// use multi_harp_patina::*;
+    ///
+    /// let mut mh = DebugMultiHarp150::default();
+    /// mh.init(MeasurementMode::T3, ReferenceClock::Internal).unwrap();
+    /// // A file per frame of a scan, `scan.part0000.parquet` on
+    /// let frames = ParquetWriter::partitioned("scan.parquet", Partitioning::Marker(1), Some(12_500.0));
+    /// let mut pipeline = Pipeline::new();
+    /// pipeline.add(frames);
+    /// pipeline.run(&mut mh, 1000).unwrap();
+    /// ```
+    pub fn partitioned<P : AsRef<Path>>(path : P, partitioning : Partitioning, sync_period : Option<f64>) -> Partitioned<Self> {
+        let path = path.as_ref().to_path_buf();
+        let partitioned = Partitioned::new(partitioning, Box::new(move |n| {
+            let writer = Self::create(partition_path(&path, n)).map_err(|e| e.to_string())?;
+            Ok(match sync_period {
+                Some(period) => writer.with_sync_period(period),
+                None => writer,
+            })
+        }));
+        match sync_period {
+            Some(period) => partitioned.with_sync_period(period),
+            None => partitioned,
+        }
+    }
+
+    /// Creates the file at `path`, taking the sync period from the device.
+    pub fn for_device<P : AsRef<Path>, MH : MultiHarpDevice>(path : P, multiharp : &MH) -> CheckedResult<Self, i32> {
+        let sync_period = multiharp.get_sync_period().map_err(PatinaError::MultiHarpError)?;
+        Ok(Self::create(path).map_err(|e| PatinaError::Io(e.to_string()))?.with_sync_period(sync_period * 1e12))
+    }
+}
+
+impl<W : Write + Send> ParquetWriter<W> {
+    /// Writes to `inner`, with Snappy compression
+    pub fn new(inner : W) -> Self {
+        ParquetWriter {
+            inner : Some(inner),
+            writer : None,
+            schema : None,
+            sync_period : None,
+            batch_size : DEFAULT_PARQUET_BATCH,
+            compression : Compression::SNAPPY,
+            mode : MeasurementMode::T3,
+            resolution : 0.0,
+            decoder : None,
+            pending : EventColumns::new(),
+            rows : 0,
+            markers : MarkerTable::new(),
+            marker_output : None,
+        }
+    }
+
+    /// Writes the marker events to a Parquet file at `path` on `finish`
+    pub fn with_marker_output<P : AsRef<Path>>(mut self, path : P) -> Self {
+        self.marker_output = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// The sync period in ps, needed to give T3 events a `time_ps`
+    pub fn with_sync_period(mut self, period : f64) -> Self {
+        self.sync_period = Some(period);
+        self
+    }
+
+    /// Events per record batch. Larger batches compress better but hold
+    /// more events in memory.
+    pub fn with_batch_size(mut self, batch_size : usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    pub fn with_compression(mut self, compression : Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Rows written so far, not counting those still buffered
+    pub fn rows_written(&self) -> usize {
+        self.rows
+    }
+
+    /// The schema of the file, once the acquisition has begun
+    pub fn schema(&self) -> Option<&SchemaRef> {
+        self.schema.as_ref()
+    }
+
+    /// The marker events written so far
+    pub fn markers(&self) -> &MarkerTable {
+        &self.markers
+    }
+
+    /// Adds host times to the marker table, see `MarkerTable::set_clock_mapping`
+    pub fn set_clock_mapping(&mut self, mapping : ClockMapping) {
+        self.markers.set_clock_mapping(mapping);
+    }
+
+    /// Starts the file with the schema and metadata of the acquisition
+    /// described by `info`.
+    ///
+    /// ## Errors
+    ///
+    /// If the file already holds an acquisition, or a T3 stream has no
+    /// sync period.
+    pub fn begin(&mut self, info : &StreamInfo) -> Result<(), ParquetError> {
+        let inner = self.inner.take().ok_or_else(|| ParquetError::General("A Parquet file holds a single acquisition".to_string()))?;
+        if info.mode == MeasurementMode::T3 && self.sync_period.is_none() {
+            self.inner = Some(inner);
+            return Err(ParquetError::General("T3 streams need the sync period, see `with_sync_period`".to_string()));
+        }
+        self.mode = info.mode;
+        self.resolution = info.resolution;
+        self.decoder = Some(Decoder::for_stream(info).map_err(ParquetError::General)?);
+        let mut markers = MarkerTable::new();
+        if let Some(period) = self.sync_period {
+            markers = markers.with_sync_period(period);
+        }
+        markers.begin(info).map_err(ParquetError::General)?;
+        self.markers = markers;
+
+        let mut metadata = HashMap::new();
+        metadata.insert("serial".to_string(), info.serial.clone());
+        metadata.insert("mode".to_string(), format!("{:?}", info.mode));
+        metadata.insert("resolution_ps".to_string(), info.resolution.to_string());
+        if let (Some(period), MeasurementMode::T3) = (self.sync_period, info.mode) {
+            metadata.insert("sync_period_ps".to_string(), period.to_string());
+        }
+        metadata.insert("num_channels".to_string(), info.num_channels.to_string());
+        metadata.insert("creator".to_string(), concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION")).to_string());
+        for (key, value) in info.labels.iter() {
+            metadata.insert(format!("label.{}", key), value.to_string());
+        }
+        let schema = Arc::new(event_schema(info.mode).with_metadata(metadata));
+
+        let properties = WriterProperties::builder()
+            .set_compression(self.compression)
+            .set_max_row_group_row_count(Some(self.batch_size))
+            .build();
+        self.writer = Some(ArrowWriter::try_new(inner, Arc::clone(&schema), Some(properties))?);
+        self.schema = Some(schema);
+        Ok(())
+    }
+
+    /// Decodes `records` and appends their events, writing a record
+    /// batch once enough are buffered.
+    pub fn write_records(&mut self, records : &[u32]) -> Result<(), ParquetError> {
+        let decoder = self.decoder.as_mut()
+            .ok_or_else(|| ParquetError::General("The acquisition hasn't begun, see `begin`".to_string()))?;
+        decoder.decode_into(records, &mut self.pending);
+        if self.pending.len() >= self.batch_size {
+            self.write_pending()?;
+        }
+        Ok(())
+    }
+
+    /// Appends already decoded events, e.g. those of one partition,
+    /// writing a record batch once enough are buffered.
+    pub fn write_columns(&mut self, columns : &EventColumns) -> Result<(), ParquetError> {
+        if self.decoder.is_none() {
+            return Err(ParquetError::General("The acquisition hasn't begun, see `begin`".to_string()));
+        }
+        self.pending.extend_from_range(columns, 0..columns.len());
+        if self.pending.len() >= self.batch_size {
+            self.write_pending()?;
+        }
+        Ok(())
+    }
+
+    fn write_pending(&mut self) -> Result<(), ParquetError> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let (writer, schema) = match (self.writer.as_mut(), self.schema.as_ref()) {
+            (Some(writer), Some(schema)) => (writer, schema),
+            _ => return Err(ParquetError::General("The acquisition hasn't begun, see `begin`".to_string())),
+        };
+        let batch = events_to_record_batch(&self.pending, self.mode, self.resolution, self.sync_period.unwrap_or(0.0))?
+            .with_schema(Arc::clone(schema))?;
+        writer.write(&batch)?;
+        self.markers.extend_from_columns(&self.pending);
+        self.rows += self.pending.len();
+        self.pending.clear();
+        Ok(())
+    }
+
+    /// Writes the buffered events and the file's footer. Nothing can be
+    /// written after.
+    pub fn finish(&mut self) -> Result<(), ParquetError> {
+        self.write_pending()?;
+        if let Some(writer) = self.writer.as_mut() {
+            writer.finish()?;
+        }
+        if let (Some(path), true) = (&self.marker_output, self.markers.has_begun()) {
+            let batch = markers_to_record_batch(&self.markers)?;
+            let mut writer = ArrowWriter::try_new(File::create(path)?, batch.schema(), None)?;
+            writer.write(&batch)?;
+            writer.close()?;
+        }
+        Ok(())
+    }
+}
+
+impl<W : Write + Send> StreamProcessor for ParquetWriter<W> {
+    fn name(&self) -> &str { "parquet_writer" }
+
+    fn init(&mut self, info : &StreamInfo) -> ProcessorResult {
+        self.begin(info).map_err(|e| e.to_string())
+    }
+
+    fn process_batch(&mut self, batch : &Batch) -> ProcessorResult {
+        self.write_records(batch.records).map_err(|e| e.to_string())
+    }
+
+    fn finalize(&mut self) -> ProcessorResult {
+        self.finish().map_err(|e| e.to_string())
+    }
+}
+
+impl<W : Write + Send> PartitionSink for ParquetWriter<W> {
+    fn begin(&mut self, info : &StreamInfo) -> Result<(), String> {
+        ParquetWriter::begin(self, info).map_err(|e| e.to_string())
+    }
+
+    fn write_columns(&mut self, columns : &EventColumns) -> Result<(), String> {
+        ParquetWriter::write_columns(self, columns).map_err(|e| e.to_string())
+    }
+
+    fn finish(&mut self) -> Result<(), String> {
+        ParquetWriter::finish(self).map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::cast::AsArray;
+    use arrow_array::types::{UInt16Type, UInt64Type, UInt8Type};
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use crate::metadata::Labels;
+    use crate::tttr::{pack_t2, OverflowCompression, OVERFLOW_CHANNEL, T2_WRAPAROUND};
+    use crate::testing::fixtures::stream_info;
+
+    #[test]
+    fn test_parquet_writer() {
+        let path = std::env::temp_dir().join("multi_harp_patina_test.parquet");
+        let mut labels = Labels::new();
+        labels.insert("sample", "fly_03");
+        let info = StreamInfo { labels, ..stream_info(MeasurementMode::T2) };
+
+        let mut writer = ParquetWriter::create(&path).unwrap().with_batch_size(2);
+        writer.begin(&info).unwrap();
+        writer.write_records(&[pack_t2(false, 1, 10), pack_t2(true, OVERFLOW_CHANNEL, 0), pack_t2(true, 0, 3)]).unwrap();
+        writer.write_records(&[pack_t2(true, 2, 4)]).unwrap();
+        assert_eq!(writer.rows_written(), 2);
+        writer.finish().unwrap();
+        assert!(writer.begin(&info).is_err());
+
+        let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(&path).unwrap()).unwrap();
+        assert_eq!(reader.metadata().num_row_groups(), 2);
+        let metadata = reader.schema().metadata().clone();
+        assert_eq!(metadata["mode"], "T2");
+        assert_eq!(metadata["label.sample"], "fly_03");
+        let batches = reader.build().unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(batches[0].num_columns(), 4);
+        let column = |index : usize| batches.iter()
+            .flat_map(|batch| batch.column(index).as_primitive::<UInt64Type>().values().to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(column(2), vec![10, T2_WRAPAROUND + 3, T2_WRAPAROUND + 4]);
+        assert_eq!(column(3)[0], 50);
+        let channels = batches.iter()
+            .flat_map(|batch| batch.column(1).as_primitive::<UInt8Type>().values().to_vec())
+            .collect::<Vec<_>>();
+        assert_eq!(channels, vec![1, 0, 2]);
+        let kinds = batches.iter()
+            .flat_map(|batch| {
+                let kinds = batch.column(0).as_dictionary::<Int8Type>();
+                let kinds = kinds.downcast_dict::<arrow_array::StringArray>().unwrap();
+                kinds.into_iter().map(|kind| kind.unwrap().to_string()).collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(kinds, vec!["photon", "sync", "marker"]);
+
+        // The marker is also in the marker table, without a host time
+        let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(marker_table_path(&path, "parquet")).unwrap()).unwrap();
+        let markers = reader.build().unwrap().next().unwrap().unwrap();
+        assert_eq!(markers.schema().field(1).name(), "timetag");
+        assert_eq!(markers.column(0).as_primitive::<UInt8Type>().values(), &[2]);
+        assert_eq!(markers.column(1).as_primitive::<UInt64Type>().values(), &[T2_WRAPAROUND + 4]);
+        assert_eq!(markers.column(3).null_count(), 1);
+
+        // A file per window of 100 ps, each with the absolute times
+        let mut windows = ParquetWriter::partitioned(&path, Partitioning::TimeWindow(100), None);
+        windows.init(&info).unwrap();
+        let records = [pack_t2(false, 0, 2), pack_t2(false, 1, 4), pack_t2(false, 1, 30)];
+        windows.process_batch(&Batch { index : 0, read_at : std::time::Instant::now(), records : &records, mode : MeasurementMode::T2, sync_lost : false }).unwrap();
+        windows.finalize().unwrap();
+        let times = (0..2).map(|n| {
+            let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(partition_path(&path, n)).unwrap()).unwrap();
+            assert_eq!(reader.schema().metadata()["label.partition"], n.to_string());
+            reader.build().unwrap()
+                .flat_map(|batch| batch.unwrap().column(3).as_primitive::<UInt64Type>().values().to_vec())
+                .collect::<Vec<_>>()
+        }).collect::<Vec<_>>();
+        assert_eq!(times, vec![vec![10, 20], vec![150]]);
+
+        // T3 streams get a `dtime` column
+        let columns = EventColumns::from_events(&[crate::tttr::TttrEvent::photon(0, 2, 7)]);
+        let batch = events_to_record_batch(&columns, MeasurementMode::T3, 5.0, 100.0).unwrap();
+        assert_eq!(batch.column(3).as_primitive::<UInt16Type>().values(), &[7]);
+        assert_eq!(batch.column(4).as_primitive::<UInt64Type>().values(), &[235]);
+    }
+
+}
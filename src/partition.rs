@@ -0,0 +1,328 @@
+//! Output split into partitions, one file each, so that
+//! downstream analysis can work on the partitions in parallel without
+//! re-splitting one large file. A `Partitioned` processor decodes the
+//! stream once and hands the events of each partition to a sink of its
+//! own, made by a factory. Times stay relative to the start of the
+//! acquisition in every partition, so partitions line up with each other.
+
+use std::ops::Range;
+
+use crate::mhconsts::MeasurementMode;
+use crate::pipeline::{Batch, ProcessorResult, StreamInfo, StreamProcessor};
+use crate::tttr::{Decoder, EventColumns, EventKind};
+
+/// Where one partition ends and the next begins
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Partitioning {
+    /// One partition per acquisition (each `init`), so one sink can be
+    /// reused across measurements, e.g. the segments of a trial sequence
+    Acquisition,
+    /// A new partition at each event of a marker (1 to 4), e.g. the frame
+    /// clock of a scanner. The marker event starts its partition.
+    Marker(u8),
+    /// Partitions of a fixed length of acquisition time, in ps. Windows
+    /// without events get no partition.
+    TimeWindow(u64),
+}
+
+/// A sink written one partition at a time, from decoded events.
+pub trait PartitionSink : Send {
+    /// Starts the partition, with a `partition` label added to `info`
+    fn begin(&mut self, info : &StreamInfo) -> Result<(), String>;
+    /// Appends the events of the partition, in order
+    fn write_columns(&mut self, columns : &EventColumns) -> Result<(), String>;
+    /// Completes the partition. Nothing is written after.
+    fn finish(&mut self) -> Result<(), String>;
+}
+
+/// Creates the sink of partition `n`
+pub type PartitionFactory<S> = Box<dyn FnMut(u32) -> Result<S, String> + Send>;
+
+/// A partition written by a `Partitioned` processor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Partition {
+    /// Partitions are numbered from 0, across acquisitions
+    pub number : u32,
+    /// The acquisition it belongs to, counting from 0
+    pub acquisition : u32,
+    /// Time of its first event, in syncs (T3) or resolution units (T2)
+    pub start : Option<u64>,
+    /// Events written to it
+    pub events : u64,
+}
+
+/// Routes the events of each partition to a new sink from a factory.
+///
+/// ## Example
+///
+/// ```
+/// use std::time::Instant;
+/// use multi_harp_patina::*;
+///
+/// #[derive(Default)]
+/// struct Counter { events : usize }
+///
+/// impl PartitionSink for Counter {
+///     fn begin(&mut self, _info : &StreamInfo) -> Result<(), String> { Ok(()) }
+///     fn write_columns(&mut self, columns : &EventColumns) -> Result<(), String> {
+///         self.events += columns.len();
+///         Ok(())
+///     }
+///     fn finish(&mut self) -> Result<(), String> { Ok(()) }
+/// }
+///
+/// let info = StreamInfo {
+///     serial : "1044272".to_string(),
+///     mode : MeasurementMode::T3,
+///     resolution : 5.0,
+///     num_channels : 4,
+///     enabled_channels : vec![true; 4],
+///     overflow_compression : OverflowCompression::Compressed,
+///     labels : Labels::default(),
+/// };
+/// // e.g. `ParquetWriter::create(partition_path("scan.parquet", n))` for a file per frame
+/// let mut frames = Partitioned::new(Partitioning::Marker(1), Box::new(|_| Ok(Counter::default())));
+/// frames.init(&info).unwrap();
+/// let records = [pack_t3(false, 0, 10, 1), pack_t3(true, 1, 20, 0), pack_t3(false, 0, 30, 1)];
+/// frames.process_batch(&Batch { index : 0, read_at : Instant::now(), records : &records, mode : MeasurementMode::T3, sync_lost : false }).unwrap();
+/// frames.finalize().unwrap();
+/// assert_eq!(frames.partitions().len(), 2);
+/// assert_eq!(frames.partitions()[1].events, 2);
+/// ```
+pub struct Partitioned<S> {
+    partitioning : Partitioning,
+    factory : PartitionFactory<S>,
+    sync_period : Option<f64>,
+    info : Option<StreamInfo>,
+    decoder : Option<Decoder>,
+    decoded : EventColumns,
+    part : EventColumns,
+    /// The sink of the open partition, and its time window
+    current : Option<(S, u64)>,
+    acquisitions : u32,
+    partitions : Vec<Partition>,
+}
+
+/// The path of partition `n` of the output at `path`: `run.parquet`
+/// becomes `run.part0003.parquet`.
+pub fn partition_path<P : AsRef<std::path::Path>>(path : P, n : u32) -> std::path::PathBuf {
+    let path = path.as_ref();
+    match path.extension() {
+        Some(extension) => path.with_extension(format!("part{:04}.{}", n, extension.to_string_lossy())),
+        None => path.with_extension(format!("part{:04}", n)),
+    }
+}
+
+impl<S : PartitionSink> Partitioned<S> {
+    pub fn new(partitioning : Partitioning, factory : PartitionFactory<S>) -> Self {
+        Partitioned {
+            partitioning,
+            factory,
+            sync_period : None,
+            info : None,
+            decoder : None,
+            decoded : EventColumns::new(),
+            part : EventColumns::new(),
+            current : None,
+            acquisitions : 0,
+            partitions : Vec::new(),
+        }
+    }
+
+    /// The sync period in ps, needed for time windows in T3
+    pub fn with_sync_period(mut self, period : f64) -> Self {
+        self.sync_period = Some(period);
+        self
+    }
+
+    pub fn partitioning(&self) -> Partitioning {
+        self.partitioning
+    }
+
+    /// Every partition written so far, the last possibly still open
+    pub fn partitions(&self) -> &[Partition] {
+        &self.partitions
+    }
+
+    fn open(&mut self, window : u64) -> ProcessorResult {
+        let number = self.partitions.len() as u32;
+        let mut info = self.info.clone().ok_or("`process_batch` called before `init`")?;
+        info.labels.insert("partition", number);
+        let mut sink = (self.factory)(number)?;
+        sink.begin(&info).map_err(|e| format!("partition {} : {}", number, e))?;
+        self.current = Some((sink, window));
+        self.partitions.push(Partition { number, acquisition : self.acquisitions - 1, start : None, events : 0 });
+        Ok(())
+    }
+
+    fn close(&mut self) -> ProcessorResult {
+        if let Some((mut sink, _)) = self.current.take() {
+            let number = self.partitions.last().map_or(0, |p| p.number);
+            sink.finish().map_err(|e| format!("partition {} : {}", number, e))?;
+        }
+        Ok(())
+    }
+
+    /// Writes the decoded events in `range` to the open partition
+    fn write(&mut self, range : Range<usize>) -> ProcessorResult {
+        if range.is_empty() {
+            return Ok(());
+        }
+        let (sink, _) = self.current.as_mut().ok_or("`process_batch` called before `init`")?;
+        self.part.clear();
+        self.part.extend_from_range(&self.decoded, range.clone());
+        let partition = self.partitions.last_mut().unwrap();
+        partition.start = partition.start.or(Some(self.decoded.time[range.start]));
+        partition.events += range.len() as u64;
+        sink.write_columns(&self.part).map_err(|e| format!("partition {} : {}", partition.number, e))
+    }
+}
+
+impl<S : PartitionSink> StreamProcessor for Partitioned<S> {
+    fn name(&self) -> &str {
+        "partitioned"
+    }
+
+    fn init(&mut self, info : &StreamInfo) -> ProcessorResult {
+        match self.partitioning {
+            Partitioning::Marker(marker) if !(1..=4).contains(&marker) =>
+                return Err(format!("Marker must be between 1 and 4, got {}", marker)),
+            Partitioning::TimeWindow(0) => return Err("Time windows must be longer than 0 ps".to_string()),
+            Partitioning::TimeWindow(_) if info.mode == MeasurementMode::T3 && self.sync_period.is_none() =>
+                return Err("T3 time windows need the sync period, see `with_sync_period`".to_string()),
+            _ => {},
+        }
+        self.close()?;
+        self.decoder = Some(Decoder::for_stream(info)?);
+        self.info = Some(info.clone());
+        self.acquisitions += 1;
+        self.open(0)
+    }
+
+    fn process_batch(&mut self, batch : &Batch) -> ProcessorResult {
+        let info = self.info.as_ref().ok_or("`process_batch` called before `init`")?;
+        let (mode, resolution) = (info.mode, info.resolution);
+        let decoder = self.decoder.as_mut().ok_or("`process_batch` called before `init`")?;
+        self.decoded.clear();
+        decoder.decode_into(batch.records, &mut self.decoded);
+
+        let mut start = 0;
+        match self.partitioning {
+            Partitioning::Acquisition => {},
+            Partitioning::Marker(marker) => {
+                let bit = 1u8 << (marker - 1);
+                for index in 0..self.decoded.len() {
+                    if self.decoded.kinds[index] == EventKind::Marker && self.decoded.channels[index] & bit != 0 {
+                        self.write(start..index)?;
+                        self.close()?;
+                        self.open(0)?;
+                        start = index;
+                    }
+                }
+            },
+            Partitioning::TimeWindow(window) => {
+                let times_ps = self.decoded.times_ps(mode, resolution, self.sync_period.unwrap_or(0.0));
+                for (index, time_ps) in times_ps.into_iter().enumerate() {
+                    let window = time_ps / window;
+                    if self.current.as_ref().map_or(false, |(_, current)| window > *current) {
+                        self.write(start..index)?;
+                        self.close()?;
+                        self.open(window)?;
+                        start = index;
+                    }
+                }
+            },
+        }
+        self.write(start..self.decoded.len())
+    }
+
+    fn finalize(&mut self) -> ProcessorResult {
+        self.close()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Instant;
+    use crate::testing::fixtures::stream_info;
+
+    use super::*;
+    use crate::tttr::{pack_t2, TttrEvent};
+
+    /// `(partition label, events)` of each finished partition
+    type Collected = Arc<Mutex<Vec<(String, Vec<TttrEvent>)>>>;
+
+    struct Collector {
+        out : Collected,
+        label : String,
+        events : Vec<TttrEvent>,
+    }
+
+    impl PartitionSink for Collector {
+        fn begin(&mut self, info : &StreamInfo) -> Result<(), String> {
+            self.label = info.labels.get("partition").unwrap().to_string();
+            Ok(())
+        }
+
+        fn write_columns(&mut self, columns : &EventColumns) -> Result<(), String> {
+            self.events.extend(columns.iter());
+            Ok(())
+        }
+
+        fn finish(&mut self) -> Result<(), String> {
+            self.out.lock().unwrap().push((self.label.clone(), self.events.clone()));
+            Ok(())
+        }
+    }
+
+    fn partitioned(partitioning : Partitioning) -> (Partitioned<Collector>, Collected) {
+        let out = Arc::new(Mutex::new(Vec::new()));
+        let factory_out = Arc::clone(&out);
+        let factory = Box::new(move |_| Ok(Collector { out : Arc::clone(&factory_out), label : String::new(), events : Vec::new() }));
+        (Partitioned::new(partitioning, factory), out)
+    }
+
+    #[test]
+    fn test_partitioned() {
+        let info = stream_info(MeasurementMode::T2);
+        let records = [pack_t2(false, 0, 10), pack_t2(true, 1, 20), pack_t2(false, 1, 30), pack_t2(true, 2, 40), pack_t2(false, 0, 250)];
+        let batch = Batch { index : 0, read_at : Instant::now(), records : &records, mode : MeasurementMode::T2, sync_lost : false };
+
+        // Each marker 1 starts a frame, marker 2 doesn't
+        let (mut frames, out) = partitioned(Partitioning::Marker(1));
+        frames.init(&info).unwrap();
+        frames.process_batch(&batch).unwrap();
+        frames.finalize().unwrap();
+        let out = out.lock().unwrap();
+        assert_eq!(out.iter().map(|(label, events)| (label.as_str(), events.len())).collect::<Vec<_>>(), vec![("0", 1), ("1", 4)]);
+        assert_eq!(out[1].1[0], TttrEvent::marker(1, 20));
+        assert_eq!(frames.partitions()[1].start, Some(20));
+
+        // Windows of 500 ps (100 bins), numbered across acquisitions
+        let (mut windows, out) = partitioned(Partitioning::TimeWindow(500));
+        for _ in 0..2 {
+            windows.init(&info).unwrap();
+            windows.process_batch(&batch).unwrap();
+            windows.finalize().unwrap();
+        }
+        let partitions = windows.partitions();
+        assert_eq!(partitions.len(), 4);
+        assert_eq!((partitions[2].number, partitions[2].acquisition, partitions[2].events), (2, 1, 4));
+        assert_eq!(out.lock().unwrap()[3].1, vec![TttrEvent::photon(0, 250, 0)]);
+
+        // One per acquisition
+        let (mut acquisitions, _) = partitioned(Partitioning::Acquisition);
+        acquisitions.init(&info).unwrap();
+        acquisitions.process_batch(&batch).unwrap();
+        acquisitions.init(&info).unwrap();
+        acquisitions.finalize().unwrap();
+        assert_eq!(acquisitions.partitions().iter().map(|p| p.events).collect::<Vec<_>>(), vec![5, 0]);
+
+        let (mut invalid, _) = partitioned(Partitioning::Marker(5));
+        assert!(invalid.init(&info).is_err());
+        let (mut invalid, _) = partitioned(Partitioning::TimeWindow(500));
+        assert!(invalid.init(&StreamInfo { mode : MeasurementMode::T3, ..info }).is_err());
+        assert_eq!(partition_path("run.parquet", 3), std::path::PathBuf::from("run.part0003.parquet"));
+    }
+}
@@ -0,0 +1,672 @@
+//! Hosts user-supplied analyses of the TTTR stream. A `Pipeline`
+//! reads the FIFO of a `MultiHarpDevice` and hands each batch of
+//! records to every registered `StreamProcessor` in turn.
+//!
+//! Processors can be compiled into the acquisition program, or, with the
+//! `plugins` feature, loaded at runtime from a separate dylib exporting
+//! them with `export_processor!`.
+//!
+//! A processor that panics is reported as `PipelineError::Panicked`
+//! rather than unwinding through the reader, so the measurement is still
+//! stopped and every processor finalized.
+
+use std::fmt::Display;
+use std::panic::{self, AssertUnwindSafe};
+use std::time::{Duration, Instant};
+
+use crate::channels::{self, ChannelMap, CHANNEL_LABEL_PREFIX};
+use crate::error::{MultiHarpError, PatinaError};
+use crate::events::{self, DeviceEventKind};
+use crate::mhconsts::{self, MeasurementMode};
+use crate::MultiHarpDevice;
+use crate::monitor::{SyncMonitor, SyncEvent, FifoFillEstimator};
+use crate::metadata::Labels;
+use crate::notify::{Notification, Notifier};
+use crate::preflight::Preflight;
+use crate::tttr::OverflowCompression;
+
+/// Version of the `StreamProcessor` interface. Bumped whenever the trait
+/// or `Batch`/`StreamInfo` change, and checked when loading plugins.
+pub const PROCESSOR_API_VERSION : u32 = 5;
+
+/// Result type returned by `StreamProcessor` methods. The `String`
+/// describes what went wrong and is reported in a `PipelineError`.
+pub type ProcessorResult = Result<(), String>;
+
+/// Properties of the acquisition provided to `StreamProcessor::init`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamInfo {
+    /// Serial number of the device producing the stream
+    pub serial : String,
+    /// `T2` or `T3`
+    pub mode : MeasurementMode,
+    /// Bin width in picoseconds
+    pub resolution : f64,
+    /// Number of input channels on the device
+    pub num_channels : i32,
+    /// Whether each input channel is enabled. Disabled channels should
+    /// be left out of per-channel outputs, and a record claiming one is
+    /// anomalous.
+    pub enabled_channels : Vec<bool>,
+    /// How overflow records count rollovers, see
+    /// `MultiHarpDevice::overflow_compression`
+    pub overflow_compression : OverflowCompression,
+    /// User labels describing the measurement, which every sink
+    /// should record with its output
+    pub labels : Labels,
+}
+
+impl StreamInfo {
+    /// Whether photons on `channel` are expected. `false` for channels
+    /// the device doesn't have.
+    pub fn is_enabled(&self, channel : u8) -> bool {
+        self.enabled_channels.get(channel as usize).copied().unwrap_or(false)
+    }
+
+    /// The enabled input channels, in order
+    pub fn enabled(&self) -> Vec<u8> {
+        (0..self.enabled_channels.len() as u8).filter(|c| self.is_enabled(*c)).collect()
+    }
+
+    /// The channel names in `labels`, see `ChannelMap`
+    pub fn channel_map(&self) -> ChannelMap {
+        ChannelMap::from_labels(&self.labels)
+    }
+
+    /// The name of `channel`, or `ch<n>` if it has none
+    pub fn channel_name(&self, channel : u8) -> String {
+        self.labels.get(&format!("{}{}", CHANNEL_LABEL_PREFIX, channel))
+            .map_or_else(|| format!("ch{}", channel), |name| name.to_string())
+    }
+}
+
+/// One read of the FIFO.
+#[derive(Debug, Clone, Copy)]
+pub struct Batch<'a> {
+    /// Number of batches preceding this one in the acquisition, a
+    /// sequence number that no empty read skips
+    pub index : u64,
+    /// When the read returned, on the host's monotonic clock, for
+    /// correlating the batch with host-side events such as stage moves
+    pub read_at : Instant,
+    /// Raw records, as returned by `read_fifo`
+    pub records : &'a [u32],
+    /// `T2` or `T3`, determining how `records` are decoded
+    pub mode : MeasurementMode,
+    /// The `SyncMonitor` found the sync lost when this batch was read,
+    /// so T3 timing in `records` isn't meaningful. Always `false`
+    /// without a monitor.
+    pub sync_lost : bool,
+}
+
+/// A real-time analysis of the TTTR stream.
+///
+/// `init` is called once before the measurement starts, `process_batch`
+/// for every read of the FIFO (including empty reads), and `finalize` once
+/// after the measurement stops, even if an earlier call failed.
+///
+/// ## Example
+///
+/// ```
+/// use multi_harp_patina::*;
+///
+/// #[derive(Default)]
+/// struct PhotonCounter { photons : u64 }
+///
+/// impl StreamProcessor for PhotonCounter {
+///     fn name(&self) -> &str { "photon_counter" }
+///
+///     fn process_batch(&mut self, batch : &Batch) -> ProcessorResult {
+///         self.photons += batch.records.iter().filter(|r| *r & SPECIAL == 0).count() as u64;
+///         Ok(())
+///     }
+/// }
+///
+/// let mut pipeline = Pipeline::new();
+/// pipeline.add(PhotonCounter::default());
+/// ```
+#[allow(unused_variables)]
+pub trait StreamProcessor : Send {
+    /// Identifies the processor in errors and logs
+    fn name(&self) -> &str;
+
+    /// Prepares for a new acquisition.
+    fn init(&mut self, info : &StreamInfo) -> ProcessorResult { Ok(()) }
+
+    /// Handles one read of the FIFO.
+    fn process_batch(&mut self, batch : &Batch) -> ProcessorResult;
+
+    /// Called when the pipeline's `SyncMonitor` detects the sync is lost
+    /// or restored, e.g. to annotate the output with the interval.
+    fn sync_event(&mut self, event : &SyncEvent) -> ProcessorResult { Ok(()) }
+
+    /// Called after the last batch of an acquisition, e.g. to flush output.
+    fn finalize(&mut self) -> ProcessorResult { Ok(()) }
+}
+
+/// Errors encountered while running a `Pipeline`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PipelineError {
+    /// The device reported an error (described in the `String`)
+    Device(String),
+    /// A processor (named in the first `String`) returned an error
+    Processor(String, String),
+    /// A plugin library could not be loaded
+    Plugin(String),
+    /// The `Preflight` check refused to start the measurement
+    Preflight(String),
+    /// A processor (named in the first `String`) panicked, with the
+    /// panic message
+    Panicked(String, String),
+    /// A `StartBarrier` wasn't released in time
+    Barrier(String),
+}
+
+impl Display for PipelineError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PipelineError::Device(e) => write!(f, "Device error: {}", e),
+            PipelineError::Processor(name, e) => write!(f, "Processor `{}` failed: {}", name, e),
+            PipelineError::Plugin(e) => write!(f, "Plugin error: {}", e),
+            PipelineError::Preflight(e) => write!(f, "Preflight failed: {}", e),
+            PipelineError::Panicked(name, e) => write!(f, "Processor `{}` panicked: {}", name, e),
+            PipelineError::Barrier(e) => write!(f, "Start barrier: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for PipelineError {}
+
+impl<T> From<PatinaError<T>> for PipelineError where T : Display + std::fmt::Debug {
+    fn from(e : PatinaError<T>) -> Self {
+        PipelineError::Device(e.to_string())
+    }
+}
+
+impl From<MultiHarpError> for PipelineError {
+    fn from(e : MultiHarpError) -> Self {
+        PipelineError::Device(e.to_string())
+    }
+}
+
+/// An ordered collection of `StreamProcessor`s fed from one device.
+#[derive(Default)]
+pub struct Pipeline {
+    processors : Vec<Box<dyn StreamProcessor>>,
+    sync_monitor : Option<SyncMonitor>,
+    fifo_estimator : Option<FifoFillEstimator>,
+    preflight : Option<Preflight>,
+    notifier : Option<Notifier>,
+    labels : Labels,
+    // Declared after `processors` so that the libraries backing
+    // plugin processors are unloaded only after they're dropped
+    #[cfg(feature = "plugins")]
+    libraries : Vec<libloading::Library>,
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends a processor. Processors see each batch in the order they were added.
+    pub fn add<P : StreamProcessor + 'static>(&mut self, processor : P) -> &mut Self {
+        self.processors.push(Box::new(processor));
+        self
+    }
+
+    /// Appends an already boxed processor.
+    pub fn add_boxed(&mut self, processor : Box<dyn StreamProcessor>) -> &mut Self {
+        self.processors.push(processor);
+        self
+    }
+
+    /// Watches for sync loss during `run`, flagging affected batches and
+    /// reporting each loss to the processors.
+    pub fn set_sync_monitor(&mut self, monitor : SyncMonitor) -> &mut Self {
+        self.sync_monitor = Some(monitor);
+        self
+    }
+
+    /// The monitor set by `set_sync_monitor`, holding the sync loss
+    /// intervals of the last `run`.
+    pub fn sync_monitor(&self) -> Option<&SyncMonitor> {
+        self.sync_monitor.as_ref()
+    }
+
+    /// Updates `estimator` on every FIFO read during `run`. Keep a clone
+    /// to check the estimate from another thread.
+    pub fn set_fifo_estimator(&mut self, estimator : FifoFillEstimator) -> &mut Self {
+        self.fifo_estimator = Some(estimator);
+        self
+    }
+
+    /// Checks that the output of each `run` will fit on disk before
+    /// starting it, at the rates measured on the device.
+    pub fn set_preflight(&mut self, preflight : Preflight) -> &mut Self {
+        self.preflight = Some(preflight);
+        self
+    }
+
+    /// Notifies the hooks of `notifier` when each acquisition has ended,
+    /// with `Notification::Completed` or `Notification::Failed`.
+    pub fn set_notifier(&mut self, notifier : Notifier) -> &mut Self {
+        self.notifier = Some(notifier);
+        self
+    }
+
+    /// Labels passed to the processors in `StreamInfo` at the start of each `run`.
+    pub fn labels(&self) -> &Labels {
+        &self.labels
+    }
+
+    pub fn labels_mut(&mut self) -> &mut Labels {
+        &mut self.labels
+    }
+
+    /// Names of the registered processors, in order.
+    pub fn processor_names(&self) -> Vec<String> {
+        self.processors.iter().map(|p| p.name().to_string()).collect()
+    }
+
+    /// Loads a processor from a dylib built with `export_processor!`.
+    ///
+    /// ## Safety
+    ///
+    /// Trait objects have no stable ABI, so the library must be built with
+    /// the same compiler and the same version of this crate. Only
+    /// `PROCESSOR_API_VERSION` is checked. Loading a library also runs any
+    /// initialization code it contains.
+    #[cfg(feature = "plugins")]
+    pub unsafe fn load_plugin<P : AsRef<std::ffi::OsStr>>(&mut self, path : P) -> Result<&mut Self, PipelineError> {
+        let path_str = path.as_ref().to_string_lossy().to_string();
+        let library = libloading::Library::new(path.as_ref())
+            .map_err(|e| PipelineError::Plugin(format!("{}: {}", path_str, e)))?;
+
+        let version = library.get::<unsafe extern "C" fn() -> u32>(b"mhp_processor_api_version\0")
+            .map_err(|e| PipelineError::Plugin(format!("{}: {}", path_str, e)))?();
+        if version != PROCESSOR_API_VERSION {
+            return Err(PipelineError::Plugin(format!(
+                "{}: built for processor API version {}, expected {}", path_str, version, PROCESSOR_API_VERSION
+            )));
+        }
+
+        let create = library.get::<unsafe extern "C" fn() -> *mut Box<dyn StreamProcessor>>(b"mhp_processor_create\0")
+            .map_err(|e| PipelineError::Plugin(format!("{}: {}", path_str, e)))?;
+        let processor = create();
+        if processor.is_null() {
+            return Err(PipelineError::Plugin(format!("{}: constructor returned null", path_str)));
+        }
+        self.processors.push(*Box::from_raw(processor));
+        self.libraries.push(library);
+        Ok(self)
+    }
+
+    /// Calls `init` on every processor.
+    pub fn init(&mut self, info : &StreamInfo) -> Result<(), PipelineError> {
+        self.processors.iter_mut().try_for_each(|p| guarded(p.as_mut(), |p| p.init(info)))
+    }
+
+    /// Passes `batch` to every processor, stopping at the first error.
+    pub fn process_batch(&mut self, batch : &Batch) -> Result<(), PipelineError> {
+        self.processors.iter_mut().try_for_each(|p| guarded(p.as_mut(), |p| p.process_batch(batch)))
+    }
+
+    /// Passes a sync loss `event` to every processor, stopping at the first error.
+    pub fn sync_event(&mut self, event : &SyncEvent) -> Result<(), PipelineError> {
+        self.processors.iter_mut().try_for_each(|p| guarded(p.as_mut(), |p| p.sync_event(event)))
+    }
+
+    /// Calls `finalize` on every processor, even if some fail or panic
+    /// (including one that panicked earlier, so it can flush what it
+    /// has). Returns the first error.
+    pub fn finalize(&mut self) -> Result<(), PipelineError> {
+        let mut result = Ok(());
+        for p in self.processors.iter_mut() {
+            if let Err(e) = guarded(p.as_mut(), |p| p.finalize()) {
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+        result
+    }
+
+    /// Runs a measurement of `acquisition_time` milliseconds on an
+    /// initialized device, feeding every FIFO read through the pipeline,
+    /// down to the records still in the FIFO when the measurement ends.
+    ///
+    /// ## Returns
+    ///
+    /// * The total number of records processed
+    ///
+    /// ## Errors
+    ///
+    /// * `PipelineError::Device` - If the device is in `Histogramming` mode
+    ///   or fails to start, read or stop.
+    /// * `PipelineError::Preflight` - If a `Preflight` is set and the output
+    ///   won't fit on disk. Nothing is started.
+    /// * `PipelineError::Processor` - If a processor fails. The measurement
+    ///   is stopped and processors are still finalized.
+    /// * `PipelineError::Panicked` - If a processor panics, with the same
+    ///   cleanup as a failure.
+    pub fn run<MH : MultiHarpDevice>(&mut self, multiharp : &mut MH, acquisition_time : i32) -> Result<u64, PipelineError> {
+        let info = self.begin(multiharp, acquisition_time, "Pipeline::run")?;
+        multiharp.start_measurement(acquisition_time)?;
+        let mut reads = Reads::default();
+        let length = Duration::from_millis(acquisition_time.max(0) as u64);
+        let acquired = self.read_measurement(multiharp, &info, Instant::now(), length, true, &mut reads, |_| ());
+        self.end(multiharp, &info, acquired).map(|_| reads.total)
+    }
+
+    /// Checks the device and the disk and initializes the processors for
+    /// measurements of `acquisition_time` ms, returning the stream they
+    /// were initialized for. `caller` names the entry point in errors.
+    pub(crate) fn begin<MH : MultiHarpDevice>(&mut self, multiharp : &mut MH, acquisition_time : i32, caller : &str) -> Result<StreamInfo, PipelineError> {
+        let mode = multiharp.get_measurement_mode();
+        if mode == MeasurementMode::Histogramming {
+            return Err(PatinaError::<i32>::WrongMode(caller.to_string(), mode).into());
+        }
+        if let Some(preflight) = self.preflight.as_ref() {
+            preflight.check_device(multiharp, acquisition_time)?;
+        }
+
+        let serial = multiharp.get_serial();
+        let mut labels = self.labels.clone();
+        channels::channel_map(&serial).add_to_labels(&mut labels);
+        let info = StreamInfo {
+            serial,
+            mode,
+            resolution : multiharp.get_resolution()?,
+            num_channels : multiharp.num_input_channels()?,
+            enabled_channels : multiharp.get_input_channel_enables()?,
+            overflow_compression : multiharp.overflow_compression(),
+            labels,
+        };
+        self.init(&info)?;
+        if let Some(monitor) = self.sync_monitor.as_mut() {
+            monitor.reset();
+        }
+        Ok(info)
+    }
+
+    /// Feeds the FIFO reads of a measurement started at `started` through
+    /// the pipeline, until it ends or `length` has passed, or, if `drain`
+    /// is set, until it has ended and the FIFO is empty. `observe` sees
+    /// the records of each read first.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn read_measurement<MH, F>(
+        &mut self,
+        multiharp : &mut MH,
+        info : &StreamInfo,
+        started : Instant,
+        length : Duration,
+        drain : bool,
+        reads : &mut Reads,
+        mut observe : F,
+    ) -> Result<(), PipelineError>
+    where MH : MultiHarpDevice, F : FnMut(&[u32]) {
+        if let Some(estimator) = self.fifo_estimator.as_ref() {
+            estimator.reset(started);
+        }
+        let mut buffer = vec![0u32; mhconsts::TTREADMAX];
+        loop {
+            let measuring = multiharp.ctc_status()?;
+            let running = measuring && started.elapsed() < length;
+            if !running && !drain {
+                break;
+            }
+            let mut sync_lost = false;
+            if let Some(monitor) = self.sync_monitor.as_mut() {
+                let event = monitor.poll(multiharp, reads.index)?;
+                sync_lost = monitor.is_lost();
+                if let Some(event) = event {
+                    self.sync_event(&event)?;
+                }
+            }
+            let n = multiharp.read_fifo(&mut buffer)? as usize;
+            let read_at = Instant::now();
+            if let Some(estimator) = self.fifo_estimator.as_ref() {
+                estimator.record_read(n, read_at);
+            }
+            observe(&buffer[..n]);
+            self.process_batch(&Batch { index : reads.index, read_at, records : &buffer[..n], mode : info.mode, sync_lost })?;
+            reads.index += 1;
+            reads.total += n as u64;
+            // Drained, once the measurement has ended
+            if !measuring && n == 0 {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Stops the measurement and finalizes the processors, returning the
+    /// first error of `acquired`, stopping and finalizing.
+    pub(crate) fn end<MH : MultiHarpDevice>(&mut self, multiharp : &mut MH, info : &StreamInfo, acquired : Result<(), PipelineError>) -> Result<(), PipelineError> {
+        if let Err(e) = &acquired {
+            events::record(&info.serial, DeviceEventKind::Error, e.to_string());
+        }
+        let stopped = multiharp.stop_measurement().map_err(PipelineError::from);
+        let finalized = self.finalize();
+        let ended = acquired.and(stopped).and(finalized);
+        if let Some(notifier) = self.notifier.as_ref() {
+            notifier.notify(&match &ended {
+                Ok(()) => Notification::completed(&info.serial),
+                Err(e) => Notification::failed(&info.serial, e),
+            });
+        }
+        ended
+    }
+}
+
+/// Position in the stream of an acquisition, across measurements
+#[derive(Debug, Default)]
+pub(crate) struct Reads {
+    /// Index of the next batch
+    pub index : u64,
+    /// Records read so far
+    pub total : u64,
+}
+
+/// Calls `f` on `processor`, naming the processor in its error, and
+/// catches a panic as `PipelineError::Panicked`.
+fn guarded<F>(processor : &mut dyn StreamProcessor, f : F) -> Result<(), PipelineError>
+where F : FnOnce(&mut dyn StreamProcessor) -> ProcessorResult {
+    match panic::catch_unwind(AssertUnwindSafe(|| f(&mut *processor))) {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(e)) => Err(PipelineError::Processor(processor.name().to_string(), e)),
+        Err(payload) => {
+            let message = payload.downcast_ref::<&str>().map(|s| s.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".to_string());
+            Err(PipelineError::Panicked(processor.name().to_string(), message))
+        },
+    }
+}
+
+/// Exports a `StreamProcessor` from a dylib so that it can be loaded
+/// with `Pipeline::load_plugin`. Takes an expression constructing
+/// the processor, called each time the plugin is loaded.
+///
+/// The plugin crate should have `crate-type = ["cdylib"]` and
+/// `export_processor!` should be invoked once, at the crate root.
+///
+/// ## Example
+///
+/// ```ignore
+/// use multi_harp_patina::*;
+///
+/// struct Flim { /* ... */ }
+/// impl StreamProcessor for Flim { /* ... */ }
+///
+/// export_processor!(Flim::new());
+/// ```
+#[macro_export]
+macro_rules! export_processor {
+    ($constructor:expr) => {
+        #[no_mangle]
+        pub extern "C" fn mhp_processor_api_version() -> u32 {
+            $crate::PROCESSOR_API_VERSION
+        }
+
+        #[no_mangle]
+        pub extern "C" fn mhp_processor_create() -> *mut Box<dyn $crate::StreamProcessor> {
+            let processor : Box<dyn $crate::StreamProcessor> = Box::new($constructor);
+            Box::into_raw(Box::new(processor))
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use crate::{DebugMultiHarp150, ReferenceClock};
+
+    /// Records every call it receives into a shared log
+    struct Recorder {
+        log : Arc<Mutex<Vec<String>>>,
+        fail_on_batch : Option<u64>,
+    }
+
+    impl StreamProcessor for Recorder {
+        fn name(&self) -> &str { "recorder" }
+
+        fn init(&mut self, info : &StreamInfo) -> ProcessorResult {
+            self.log.lock().unwrap().push(format!("init {:?}", info.mode));
+            Ok(())
+        }
+
+        fn process_batch(&mut self, batch : &Batch) -> ProcessorResult {
+            if Some(batch.index) == self.fail_on_batch {
+                return Err("bad batch".to_string());
+            }
+            self.log.lock().unwrap().push("batch".to_string());
+            Ok(())
+        }
+
+        fn finalize(&mut self) -> ProcessorResult {
+            self.log.lock().unwrap().push("finalize".to_string());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_pipeline_lifecycle() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut pipeline = Pipeline::new();
+        pipeline.add(Recorder { log : Arc::clone(&log), fail_on_batch : None });
+
+        let mut mh = DebugMultiHarp150::new(1e4, 80e6, None);
+        mh.init(MeasurementMode::T3, ReferenceClock::Internal).unwrap();
+        pipeline.run(&mut mh, 200).unwrap();
+
+        let log = log.lock().unwrap();
+        assert_eq!(log.first().unwrap(), "init T3");
+        assert_eq!(log.last().unwrap(), "finalize");
+        assert!(log.iter().any(|l| l == "batch"));
+    }
+
+    #[test]
+    fn test_pipeline_processor_error() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut pipeline = Pipeline::new();
+        pipeline.add(Recorder { log : Arc::clone(&log), fail_on_batch : Some(0) });
+        assert_eq!(pipeline.processor_names(), vec!["recorder".to_string()]);
+
+        let mut mh = DebugMultiHarp150::new(1e4, 80e6, None);
+        mh.init(MeasurementMode::T3, ReferenceClock::Internal).unwrap();
+        assert_eq!(
+            pipeline.run(&mut mh, 200),
+            Err(PipelineError::Processor("recorder".to_string(), "bad batch".to_string()))
+        );
+        // Still finalized after the failure
+        assert_eq!(log.lock().unwrap().last().unwrap(), "finalize");
+
+        mh.init(MeasurementMode::Histogramming, ReferenceClock::Internal).unwrap();
+        assert!(matches!(pipeline.run(&mut mh, 200), Err(PipelineError::Device(_))));
+    }
+
+    /// Panics on its second batch
+    struct Panicker {
+        batches : u64,
+        finalized : Arc<Mutex<bool>>,
+    }
+
+    impl StreamProcessor for Panicker {
+        fn name(&self) -> &str { "panicker" }
+
+        fn process_batch(&mut self, _batch : &Batch) -> ProcessorResult {
+            self.batches += 1;
+            if self.batches == 2 {
+                panic!("batch {} out of bounds", self.batches);
+            }
+            Ok(())
+        }
+
+        fn finalize(&mut self) -> ProcessorResult {
+            *self.finalized.lock().unwrap() = true;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_pipeline_processor_panic() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let finalized = Arc::new(Mutex::new(false));
+        let mut pipeline = Pipeline::new();
+        pipeline.add(Panicker { batches : 0, finalized : Arc::clone(&finalized) });
+        pipeline.add(Recorder { log : Arc::clone(&log), fail_on_batch : None });
+
+        let mut mh = DebugMultiHarp150::new(1e4, 80e6, None);
+        mh.init(MeasurementMode::T3, ReferenceClock::Internal).unwrap();
+        assert_eq!(
+            pipeline.run(&mut mh, 500),
+            Err(PipelineError::Panicked("panicker".to_string(), "batch 2 out of bounds".to_string()))
+        );
+        // Stopped and finalized, the panicking processor included
+        assert!(!mh.ctc_status().unwrap());
+        assert!(*finalized.lock().unwrap());
+        assert_eq!(log.lock().unwrap().last().unwrap(), "finalize");
+        assert!(mh.recent_events().iter().any(|e| e.message.contains("panicked")));
+
+        // The reader survives to run again
+        pipeline.run(&mut mh, 50).unwrap();
+    }
+
+    /// Keeps the size of every batch
+    struct Sizes(Arc<Mutex<Vec<usize>>>);
+
+    impl StreamProcessor for Sizes {
+        fn name(&self) -> &str { "sizes" }
+
+        fn process_batch(&mut self, batch : &Batch) -> ProcessorResult {
+            self.0.lock().unwrap().push(batch.records.len());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_pipeline_drains_fifo() {
+        let sizes = Arc::new(Mutex::new(Vec::new()));
+        let mut pipeline = Pipeline::new();
+        pipeline.add(Sizes(Arc::clone(&sizes)));
+
+        // Held on the device past the end of the measurement, so only
+        // reads after the CTC has ended return the records
+        let mut mh = DebugMultiHarp150::new(1e4, 80e6, None);
+        mh.init(MeasurementMode::T2, ReferenceClock::Internal).unwrap();
+        mh.set_overflow_compression(mhconsts::HOLDTIMEMAX).unwrap();
+        let total = pipeline.run(&mut mh, 100).unwrap();
+
+        let sizes = sizes.lock().unwrap();
+        assert!(total > 0);
+        assert_eq!(sizes.iter().sum::<usize>() as u64, total);
+        // The last batch with records is followed by an empty read
+        assert_eq!(sizes.last(), Some(&0));
+        assert!(sizes[sizes.len() - 2] > 0);
+        let mut buffer = vec![0u32; mhconsts::TTREADMAX];
+        assert_eq!(mh.read_fifo(&mut buffer).unwrap(), 0);
+    }
+}
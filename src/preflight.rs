@@ -0,0 +1,294 @@
+//! Estimates how much data an acquisition will produce, and checks
+//! before it starts that the output volume can hold it, so a long
+//! acquisition doesn't fail hours in when the disk fills.
+//!
+//! The estimate is an upper bound for the rates it's given: it counts
+//! one overflow record per rollover, as if the device never compressed
+//! them, and assumes every enabled channel keeps its measured rate.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::mhconsts::{self, MeasurementMode};
+use crate::multiharp::MultiHarpDevice;
+use crate::pipeline::PipelineError;
+use crate::rates::{measure_rates, Rates, RATE_GATE};
+use crate::tttr::{T2_WRAPAROUND, T3_WRAPAROUND};
+use crate::MultiHarpConfig;
+
+/// Factor by which the free space must exceed the estimate when no
+/// other margin is set
+pub const DEFAULT_SPACE_MARGIN : f64 = 1.2;
+
+/// Bytes of a spool's file header
+const SPOOL_FILE_HEADER_LEN : u64 = 12;
+/// Bytes of a spool segment header, with room for a serial number and labels
+const SPOOL_SEGMENT_HEADER_LEN : u64 = 4096;
+/// Bytes of the tag and count starting each block of records in a spool
+const SPOOL_BLOCK_HEADER_LEN : f64 = 8.0;
+
+/// How an acquisition is written to disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// A `SpoolWriter` spool
+    Spool,
+    /// The raw `u32` records, with no framing
+    RawRecords,
+    /// One histogram of `u32` counts per input channel, written at the
+    /// end of a `Histogramming` measurement
+    Histograms,
+}
+
+/// The data an acquisition is expected to produce.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DataRateEstimate {
+    /// FIFO records per second, including syncs (T2) and overflows
+    pub records_per_second : f64,
+    /// Bytes written per second of acquisition
+    pub bytes_per_second : f64,
+    /// Bytes written regardless of the acquisition time (file headers,
+    /// histograms)
+    pub fixed_bytes : u64,
+}
+
+impl DataRateEstimate {
+    /// Estimates the output of a measurement in `mode` with `config` applied,
+    /// at the count `rates` measured with it, at `resolution` ps, written as `format`.
+    /// Channels disabled in `config` are taken to produce no records.
+    pub fn new(mode : MeasurementMode, config : &MultiHarpConfig, rates : &Rates, resolution : f64, format : OutputFormat) -> Self {
+        let disabled = |channel : usize| config.input_enables.as_ref()
+            .map(|enables| enables.iter().any(|&(c, enable)| c as usize == channel && !enable))
+            .unwrap_or(false);
+        let photons : f64 = rates.inputs.iter().enumerate()
+            .filter(|(channel, _)| !disabled(*channel))
+            .map(|(_, rate)| rate)
+            .sum();
+        let syncs = rates.sync / config.sync_div.unwrap_or(1).max(1) as f64;
+
+        let records_per_second = match mode {
+            MeasurementMode::T2 => photons + syncs + 1e12 / (T2_WRAPAROUND as f64 * resolution.max(f64::MIN_POSITIVE)),
+            MeasurementMode::T3 => photons + syncs / T3_WRAPAROUND as f64,
+            MeasurementMode::Histogramming => 0.0,
+        };
+
+        let (bytes_per_second, fixed_bytes) = match format {
+            OutputFormat::Spool => (
+                records_per_second * (4.0 + SPOOL_BLOCK_HEADER_LEN / mhconsts::TTREADMAX as f64),
+                SPOOL_FILE_HEADER_LEN + SPOOL_SEGMENT_HEADER_LEN,
+            ),
+            OutputFormat::RawRecords => (records_per_second * 4.0, 0),
+            OutputFormat::Histograms => {
+                let len_code = config.histo_len.unwrap_or(mhconsts::MAXLENCODE);
+                let bins = 1024u64 << len_code.clamp(mhconsts::MINLENCODE, mhconsts::MAXLENCODE);
+                (0.0, rates.inputs.len() as u64 * bins * 4)
+            },
+        };
+
+        DataRateEstimate { records_per_second, bytes_per_second, fixed_bytes }
+    }
+
+    /// Expected size of the output of an acquisition lasting `acquisition_time`
+    pub fn total_bytes(&self, acquisition_time : Duration) -> u64 {
+        self.fixed_bytes + (self.bytes_per_second * acquisition_time.as_secs_f64()).ceil() as u64
+    }
+}
+
+/// Bytes available to this process on the volume holding `path`. `path`
+/// needn't exist yet: the nearest existing ancestor is checked.
+pub fn free_space<P : AsRef<Path>>(path : P) -> io::Result<u64> {
+    let path = path.as_ref();
+    let existing = path.ancestors()
+        .find(|p| !p.as_os_str().is_empty() && p.exists())
+        .unwrap_or_else(|| Path::new("."));
+    free_space_at(existing)
+}
+
+#[cfg(windows)]
+fn free_space_at(path : &Path) -> io::Result<u64> {
+    use std::os::windows::ffi::OsStrExt;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetDiskFreeSpaceExW(directory : *const u16, available : *mut u64, total : *mut u64, free : *mut u64) -> i32;
+    }
+
+    let wide = path.as_os_str().encode_wide().chain(Some(0)).collect::<Vec<u16>>();
+    let mut available = 0u64;
+    let ok = unsafe {
+        GetDiskFreeSpaceExW(wide.as_ptr(), &mut available, std::ptr::null_mut(), std::ptr::null_mut())
+    };
+    if ok == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(available)
+}
+
+/// Asks POSIX `df` rather than calling `statvfs`, whose struct layout
+/// differs between platforms.
+#[cfg(unix)]
+fn free_space_at(path : &Path) -> io::Result<u64> {
+    let output = std::process::Command::new("df").arg("-Pk").arg(path).output()?;
+    if !output.status.success() {
+        return Err(io::Error::new(io::ErrorKind::Other,
+            format!("`df` failed: {}", String::from_utf8_lossy(&output.stderr).trim())));
+    }
+    // Filesystem 1024-blocks Used Available Capacity Mounted-on
+    String::from_utf8_lossy(&output.stdout).lines()
+        .nth(1)
+        .and_then(|line| line.split_whitespace().nth(3))
+        .and_then(|available| available.parse::<u64>().ok())
+        .map(|kib| kib * 1024)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Unexpected output from `df`"))
+}
+
+#[cfg(not(any(unix, windows)))]
+fn free_space_at(_path : &Path) -> io::Result<u64> {
+    Err(io::Error::new(io::ErrorKind::Other, "Free space can't be read on this platform"))
+}
+
+/// Refuses to start an acquisition whose output wouldn't fit on disk.
+/// Set one on a `Pipeline` with `set_preflight` to check before every `run`.
+///
+/// ## Example
+///
+/// ```
+/// use multi_harp_patina::*;
+///
+/// let mut mh = DebugMultiHarp150::default();
+/// mh.init(MeasurementMode::T3, ReferenceClock::Internal).unwrap();
+///
+/// let preflight = Preflight::new(std::env::temp_dir(), OutputFormat::Spool);
+/// let estimate = preflight.check_device(&mh, 1_000).unwrap();
+/// println!("{:.1} MB/s", estimate.bytes_per_second / 1e6);
+/// ```
+pub struct Preflight {
+    directory : PathBuf,
+    format : OutputFormat,
+    config : MultiHarpConfig,
+    margin : f64,
+}
+
+impl Preflight {
+    /// Checks for room in `directory` for output written as `format`.
+    pub fn new<P : AsRef<Path>>(directory : P, format : OutputFormat) -> Self {
+        Preflight {
+            directory : directory.as_ref().to_path_buf(),
+            format,
+            config : MultiHarpConfig::default(),
+            margin : DEFAULT_SPACE_MARGIN,
+        }
+    }
+
+    /// The configuration applied to the device, for the sync divider,
+    /// disabled channels and histogram length. Without one, no channels
+    /// are disabled and the divider is 1.
+    pub fn with_config(mut self, config : MultiHarpConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Requires `margin` times the estimated size to be free (at least 1).
+    pub fn with_margin(mut self, margin : f64) -> Self {
+        self.margin = margin.max(1.0);
+        self
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    pub fn format(&self) -> OutputFormat {
+        self.format
+    }
+
+    /// Checks that an acquisition of `acquisition_time` ms producing
+    /// `estimate` fits in the directory.
+    ///
+    /// ## Returns
+    ///
+    /// * The bytes free in the directory
+    ///
+    /// ## Errors
+    ///
+    /// * If the free space is less than the margin times the estimate, or
+    ///   can't be read.
+    pub fn check(&self, estimate : &DataRateEstimate, acquisition_time : i32) -> Result<u64, String> {
+        let needed = estimate.total_bytes(Duration::from_millis(acquisition_time.max(0) as u64));
+        let free = free_space(&self.directory)
+            .map_err(|e| format!("Reading the free space in {}: {}", self.directory.display(), e))?;
+        if (free as f64) < needed as f64 * self.margin {
+            return Err(format!(
+                "An acquisition of {} ms needs about {} MB in {}, only {} MB are free",
+                acquisition_time, needed / 1_000_000, self.directory.display(), free / 1_000_000
+            ));
+        }
+        Ok(free)
+    }
+
+    /// Measures the rates of an initialized device and checks that an
+    /// acquisition of `acquisition_time` ms at those rates fits in the
+    /// directory. Takes `RATE_GATE` to measure the rates.
+    ///
+    /// ## Returns
+    ///
+    /// * The estimate the check was based on
+    ///
+    /// ## Errors
+    ///
+    /// * `PipelineError::Device` - If the rates or resolution can't be read.
+    /// * `PipelineError::Preflight` - If the output won't fit.
+    pub fn check_device<MH : MultiHarpDevice>(&self, multiharp : &MH, acquisition_time : i32) -> Result<DataRateEstimate, PipelineError> {
+        let rates = measure_rates(multiharp, RATE_GATE)?;
+        let estimate = DataRateEstimate::new(
+            multiharp.get_measurement_mode(), &self.config, &rates, multiharp.get_resolution()?, self.format,
+        );
+        self.check(&estimate, acquisition_time).map_err(PipelineError::Preflight)?;
+        Ok(estimate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::SystemTime;
+
+    fn rates() -> Rates {
+        Rates { sync : 80e6, inputs : vec![1e6, 2e6, 0.0, 5e5], readings : 1, timestamp : SystemTime::now() }
+    }
+
+    #[test]
+    fn test_data_rate_estimate() {
+        let config = MultiHarpConfig {
+            sync_div : Some(8),
+            input_enables : Some(vec![(3, false)]),
+            ..Default::default()
+        };
+        let t3 = DataRateEstimate::new(MeasurementMode::T3, &config, &rates(), 5.0, OutputFormat::RawRecords);
+        assert_eq!(t3.records_per_second, 3e6 + 1e7 / 1024.0);
+        assert_eq!(t3.bytes_per_second, t3.records_per_second * 4.0);
+        assert_eq!(t3.total_bytes(Duration::from_secs(10)), (t3.bytes_per_second * 10.0).ceil() as u64);
+
+        let t2 = DataRateEstimate::new(MeasurementMode::T2, &config, &rates(), 5.0, OutputFormat::Spool);
+        assert!(t2.records_per_second > 3e6 + 1e7);
+        assert!(t2.bytes_per_second > t2.records_per_second * 4.0);
+        assert!(t2.fixed_bytes > 0);
+
+        let histograms = DataRateEstimate::new(MeasurementMode::Histogramming, &config, &rates(), 5.0, OutputFormat::Histograms);
+        assert_eq!(histograms.bytes_per_second, 0.0);
+        assert_eq!(histograms.total_bytes(Duration::from_secs(3600)), 4 * 65536 * 4);
+    }
+
+    #[test]
+    fn test_preflight() {
+        let directory = std::env::temp_dir().join("not_created_yet");
+        assert!(free_space(&directory).unwrap() > 0);
+
+        let preflight = Preflight::new(&directory, OutputFormat::RawRecords);
+        let small = DataRateEstimate { records_per_second : 1.0, bytes_per_second : 4.0, fixed_bytes : 0 };
+        assert!(preflight.check(&small, 1000).is_ok());
+        let huge = DataRateEstimate { records_per_second : 1e18, bytes_per_second : 4e18, fixed_bytes : 0 };
+        let message = preflight.check(&huge, i32::MAX).unwrap_err();
+        assert!(message.contains("only"), "{}", message);
+    }
+}
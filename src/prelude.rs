@@ -0,0 +1,40 @@
+//! The names most applications need, for a single glob import:
+//!
+//! ```
+//! use multi_harp_patina::prelude::*;
+//!
+//! let mut mh = DebugMultiHarp150::default();
+//! mh.init(MeasurementMode::T3, ReferenceClock::Internal).unwrap();
+//! mh.set_sync_edge_trigger(-100, TriggerEdge::Falling).unwrap();
+//! ```
+//!
+//! Unlike `use multi_harp_patina::*`, this leaves out the library's
+//! constants and the more specialized processors and file formats,
+//! which can be imported by name from the crate root.
+
+pub use crate::multiharp::{MultiHarpDevice, MissingFeaturePolicy};
+#[cfg(feature = "async")]
+pub use crate::multiharp::AsyncMultiHarpDevice;
+#[cfg(feature = "MHLib")]
+pub use crate::multiharp::MultiHarp150;
+pub use crate::testing::debug_multiharp::DebugMultiHarp150;
+pub use crate::{available_devices, open_first_device, get_library_version};
+pub use crate::typestate::{MultiHarp, Histogramming, T2, T3, open_histogramming, open_t2, open_t3};
+
+pub use crate::MultiHarpConfig;
+pub use crate::settings::{Settings, LogLevel};
+
+pub use crate::mhconsts::{
+    MeasurementMode, ReferenceClock, TriggerEdge, MeasurementControlMode, WRMode,
+};
+/// The size of buffer `read_fifo` needs
+pub use crate::mhconsts::TTREADMAX;
+
+pub use crate::error::{PatinaError, MultiHarpError, CheckedResult, MultiHarpResult};
+pub use crate::pipeline::{PipelineError, ProcessorResult};
+pub use crate::transaction::TransactionError;
+
+pub use crate::pipeline::{Pipeline, StreamProcessor, Batch, StreamInfo};
+pub use crate::tttr::{TttrEvent, EventKind, Decoder};
+pub use crate::histogram::{Histogram, acquire_histogram, acquire_histograms};
+pub use crate::rates::{Rates, measure_rates};
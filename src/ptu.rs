@@ -0,0 +1,511 @@
+//! PicoQuant Unified TTTR (`.ptu`) files, which SymPhoTime and
+//! PicoQuant's own analysis code read, written from the raw records of
+//! `read_fifo`.
+//!
+//! ## Format
+//!
+//! All integers are little endian.
+//!
+//! * The magic bytes `PQTTTR`, then the format version, each padded to 8 bytes
+//! * A sequence of tags, each a 32 byte NUL-padded name, an index (`i32`,
+//!   -1 for tags that aren't indexed), a type (`u32`, the `TY_*` constants)
+//!   and an 8 byte value. Strings give their length in bytes as the value and
+//!   follow the tag.
+//! * The tag `Header_End`, then the raw records (`u32`) to the end of the file
+//!
+//! The marker events are also written apart, as the CSV of a `MarkerTable`
+//! next to the file (`run.ptu` and `run.markers.csv`).
+
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::clock::ClockMapping;
+use crate::error::{CheckedResult, PatinaError};
+use crate::markers::{marker_table_path, MarkerTable};
+use crate::mhconsts::MeasurementMode;
+use crate::metadata::Labels;
+use crate::multiharp::MultiHarpDevice;
+use crate::pipeline::{Batch, ProcessorResult, StreamInfo, StreamProcessor};
+
+pub const PTU_MAGIC : &[u8; 8] = b"PQTTTR\0\0";
+pub const PTU_VERSION : &[u8; 8] = b"1.0.00\0\0";
+
+pub const TY_EMPTY8 : u32 = 0xFFFF_0008;
+pub const TY_BOOL8 : u32 = 0x0000_0008;
+pub const TY_INT8 : u32 = 0x1000_0008;
+pub const TY_FLOAT8 : u32 = 0x2000_0008;
+pub const TY_TDATETIME : u32 = 0x2100_0008;
+pub const TY_ANSI_STRING : u32 = 0x4001_FFFF;
+
+/// `TTResultFormat_TTTRRecType` of MultiHarp T2 records
+pub const REC_TYPE_MULTIHARP_T2 : i64 = 0x0001_0207;
+/// `TTResultFormat_TTTRRecType` of MultiHarp T3 records
+pub const REC_TYPE_MULTIHARP_T3 : i64 = 0x0001_0307;
+
+/// Longest tag name, leaving room for its NUL
+pub const MAX_IDENT_LEN : usize = 31;
+/// Largest string accepted when reading a header
+const MAX_STRING_LEN : u64 = 1 << 20;
+/// Days from the `TDateTime` epoch (1899-12-30) to the Unix epoch
+const TDATETIME_UNIX_EPOCH : f64 = 25569.0;
+const SECONDS_PER_DAY : f64 = 86400.0;
+
+/// The value of a header tag
+#[derive(Debug, Clone, PartialEq)]
+pub enum TagValue {
+    Empty,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    /// Stored as days since 1899-12-30, to the millisecond or so
+    DateTime(SystemTime),
+    AnsiString(String),
+}
+
+/// One entry of a PTU header
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tag {
+    pub ident : String,
+    /// Position in an array of tags sharing `ident` (e.g. one per input
+    /// channel), or -1
+    pub index : i32,
+    pub value : TagValue,
+}
+
+/// The tags of a PTU file, without `Header_End`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Header {
+    tags : Vec<Tag>,
+}
+
+impl Header {
+    /// The tags a reader needs to interpret the records: the mode, the
+    /// record type, the resolution (`resolution` ps) and the global
+    /// resolution -- `sync_period` s in T3, the resolution in T2.
+    pub fn new(mode : MeasurementMode, resolution : f64, sync_period : f64) -> Result<Self, String> {
+        let (mode_number, record_type, global_resolution) = match mode {
+            MeasurementMode::T2 => (2, REC_TYPE_MULTIHARP_T2, resolution * 1e-12),
+            MeasurementMode::T3 => (3, REC_TYPE_MULTIHARP_T3, sync_period),
+            MeasurementMode::Histogramming => return Err("Histogramming mode has no TTTR records".to_string()),
+        };
+        let mut header = Header::default();
+        header
+            .set("CreatorSW_Name", TagValue::AnsiString(env!("CARGO_PKG_NAME").to_string()))
+            .set("CreatorSW_Version", TagValue::AnsiString(env!("CARGO_PKG_VERSION").to_string()))
+            .set("File_CreatingTime", TagValue::DateTime(SystemTime::now()))
+            .set("Measurement_Mode", TagValue::Int(mode_number))
+            .set("Measurement_SubMode", TagValue::Int(0))
+            .set("MeasDesc_Resolution", TagValue::Float(resolution * 1e-12))
+            .set("MeasDesc_GlobalResolution", TagValue::Float(global_resolution))
+            .set("TTResultFormat_TTTRRecType", TagValue::Int(record_type))
+            .set("TTResultFormat_BitsPerRecord", TagValue::Int(32))
+            .set("TTResult_NumberOfRecords", TagValue::Int(0));
+        Ok(header)
+    }
+
+    /// The tags of `new` for the device's mode, resolution and sync
+    /// period, and its identity, sync settings and count rates.
+    pub fn from_device<MH : MultiHarpDevice>(multiharp : &MH) -> CheckedResult<Self, i32> {
+        let mode = multiharp.get_measurement_mode();
+        let resolution = match mode {
+            MeasurementMode::T2 => multiharp.get_base_resolution().map_err(PatinaError::MultiHarpError)?.0,
+            _ => multiharp.get_resolution().map_err(PatinaError::MultiHarpError)?,
+        };
+        let sync_period = multiharp.get_sync_period().map_err(PatinaError::MultiHarpError)?;
+        let mut header = Header::new(mode, resolution, sync_period)
+            .map_err(|e| PatinaError::WrongMode(e, mode))?;
+
+        let (model, part_number, version) = multiharp.get_hardware_info().map_err(PatinaError::MultiHarpError)?;
+        let (sync_rate, input_rates) = multiharp.get_all_count_rates().map_err(PatinaError::MultiHarpError)?;
+        let settings = multiharp.current_settings();
+        header
+            .set("HW_Type", TagValue::AnsiString(model))
+            .set("HW_PartNo", TagValue::AnsiString(part_number))
+            .set("HW_Version", TagValue::AnsiString(version))
+            .set("HW_SerialNo", TagValue::AnsiString(multiharp.get_serial()))
+            // Counting the sync
+            .set("HW_InpChannels", TagValue::Int(input_rates.len() as i64 + 1))
+            .set("MeasDesc_BinningFactor", TagValue::Int(1 << settings.binning.unwrap_or(0)))
+            .set("MeasDesc_Offset", TagValue::Int(multiharp.get_offset() as i64))
+            .set("TTResult_SyncRate", TagValue::Int(sync_rate as i64));
+        if let Some(sync_div) = settings.sync_div {
+            header.set("HWSync_Divider", TagValue::Int(sync_div as i64));
+        }
+        if let Some(offset) = settings.sync_channel_offset {
+            header.set("HWSync_Offset", TagValue::Int(offset as i64));
+        }
+        for (channel, rate) in input_rates.iter().enumerate() {
+            header.set_indexed("TTResult_InputRate", channel as i32, TagValue::Int(*rate as i64));
+        }
+        Ok(header)
+    }
+
+    /// Sets the tag `ident`, replacing any value it had
+    pub fn set(&mut self, ident : &str, value : TagValue) -> &mut Self {
+        self.set_indexed(ident, -1, value)
+    }
+
+    /// Sets entry `index` of the tag array `ident`
+    pub fn set_indexed(&mut self, ident : &str, index : i32, value : TagValue) -> &mut Self {
+        match self.tags.iter_mut().find(|tag| tag.ident == ident && tag.index == index) {
+            Some(tag) => tag.value = value,
+            None => self.tags.push(Tag { ident : ident.to_string(), index, value }),
+        }
+        self
+    }
+
+    pub fn get(&self, ident : &str) -> Option<&TagValue> {
+        self.get_indexed(ident, -1)
+    }
+
+    pub fn get_indexed(&self, ident : &str, index : i32) -> Option<&TagValue> {
+        self.tags.iter().find(|tag| tag.ident == ident && tag.index == index).map(|tag| &tag.value)
+    }
+
+    pub fn tags(&self) -> &[Tag] {
+        &self.tags
+    }
+
+    /// Stores `labels` as the tag arrays `UsrLabel_Key` and `UsrLabel_Value`
+    pub fn set_labels(&mut self, labels : &Labels) -> &mut Self {
+        self.tags.retain(|tag| tag.ident != "UsrLabel_Key" && tag.ident != "UsrLabel_Value");
+        for (index, (key, value)) in labels.iter().enumerate() {
+            self.set_indexed("UsrLabel_Key", index as i32, TagValue::AnsiString(key.to_string()));
+            self.set_indexed("UsrLabel_Value", index as i32, TagValue::AnsiString(value.to_string()));
+        }
+        self
+    }
+
+    /// The measurement mode given by `Measurement_Mode`
+    pub fn mode(&self) -> Option<MeasurementMode> {
+        match self.get("Measurement_Mode") {
+            Some(TagValue::Int(2)) => Some(MeasurementMode::T2),
+            Some(TagValue::Int(3)) => Some(MeasurementMode::T3),
+            _ => None,
+        }
+    }
+
+    /// Reads the magic, the version and the tags up to `Header_End`,
+    /// leaving `reader` at the first record.
+    pub fn read<R : Read>(reader : &mut R) -> io::Result<Self> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != PTU_MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a PTU file"));
+        }
+        reader.read_exact(&mut [0u8; 8])?;
+
+        let mut header = Header::default();
+        loop {
+            let mut ident = [0u8; 32];
+            let mut index = [0u8; 4];
+            let mut kind = [0u8; 4];
+            let mut value = [0u8; 8];
+            reader.read_exact(&mut ident)?;
+            reader.read_exact(&mut index)?;
+            reader.read_exact(&mut kind)?;
+            reader.read_exact(&mut value)?;
+            let len = ident.iter().position(|b| *b == 0).unwrap_or(ident.len());
+            let ident = String::from_utf8_lossy(&ident[..len]).to_string();
+            let index = i32::from_le_bytes(index);
+            let value = match u32::from_le_bytes(kind) {
+                TY_EMPTY8 => TagValue::Empty,
+                TY_BOOL8 => TagValue::Bool(u64::from_le_bytes(value) != 0),
+                TY_INT8 => TagValue::Int(i64::from_le_bytes(value)),
+                TY_FLOAT8 => TagValue::Float(f64::from_le_bytes(value)),
+                TY_TDATETIME => {
+                    let seconds = (f64::from_le_bytes(value) - TDATETIME_UNIX_EPOCH) * SECONDS_PER_DAY;
+                    let time = Duration::try_from_secs_f64(seconds.max(0.0)).ok()
+                        .and_then(|since_epoch| UNIX_EPOCH.checked_add(since_epoch))
+                        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("Tag {} is out of range", ident)))?;
+                    TagValue::DateTime(time)
+                },
+                TY_ANSI_STRING => {
+                    let len = u64::from_le_bytes(value);
+                    if len > MAX_STRING_LEN {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Tag {} is {} bytes long", ident, len)));
+                    }
+                    let mut bytes = vec![0u8; len as usize];
+                    reader.read_exact(&mut bytes)?;
+                    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+                    TagValue::AnsiString(String::from_utf8_lossy(&bytes[..end]).to_string())
+                },
+                kind => return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Tag {} has unsupported type {:#010x}", ident, kind),
+                )),
+            };
+            if ident == "Header_End" {
+                return Ok(header);
+            }
+            header.tags.push(Tag { ident, index, value });
+        }
+    }
+}
+
+/// Writes one tag. Strings are NUL-terminated and padded to 8 bytes.
+fn write_tag<W : Write>(writer : &mut W, ident : &str, index : i32, value : &TagValue) -> io::Result<()> {
+    if ident.len() > MAX_IDENT_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Tag name {} is too long", ident)));
+    }
+    let mut name = [0u8; 32];
+    name[..ident.len()].copy_from_slice(ident.as_bytes());
+    writer.write_all(&name)?;
+    writer.write_all(&index.to_le_bytes())?;
+
+    let (kind, bytes, string) = match value {
+        TagValue::Empty => (TY_EMPTY8, [0u8; 8], None),
+        TagValue::Bool(b) => (TY_BOOL8, (*b as u64).to_le_bytes(), None),
+        TagValue::Int(i) => (TY_INT8, i.to_le_bytes(), None),
+        TagValue::Float(f) => (TY_FLOAT8, f.to_le_bytes(), None),
+        TagValue::DateTime(time) => {
+            let seconds = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+            (TY_TDATETIME, (TDATETIME_UNIX_EPOCH + seconds / SECONDS_PER_DAY).to_le_bytes(), None)
+        },
+        TagValue::AnsiString(s) => {
+            let mut padded = s.as_bytes().to_vec();
+            padded.resize((s.len() / 8 + 1) * 8, 0);
+            (TY_ANSI_STRING, (padded.len() as u64).to_le_bytes(), Some(padded))
+        },
+    };
+    writer.write_all(&kind.to_le_bytes())?;
+    writer.write_all(&bytes)?;
+    if let Some(string) = string {
+        writer.write_all(&string)?;
+    }
+    Ok(())
+}
+
+/// Writes a PTU file: the header when the first records arrive (or at
+/// `init`, as a `StreamProcessor`), the records as they come, and the
+/// final record count into the header when finished. A file holds one
+/// acquisition.
+///
+/// ## Example
+///
+/// ```
+/// use multi_harp_patina::*;
+///
+/// let mut mh = DebugMultiHarp150::default();
+/// mh.init(MeasurementMode::T3, ReferenceClock::Internal).unwrap();
+/// let path = std::env::temp_dir().join("ptu_doctest.ptu");
+///
+/// let mut writer = ptu::Writer::create(&path, ptu::Header::from_device(&mh).unwrap()).unwrap();
+/// writer.write_records(&[pack_t3(false, 0, 100, 1), pack_t3(false, 1, 200, 2)]).unwrap();
+/// writer.finish().unwrap();
+///
+/// let mut file = std::fs::File::open(&path).unwrap();
+/// let header = ptu::Header::read(&mut file).unwrap();
+/// assert_eq!(header.get("TTResult_NumberOfRecords"), Some(&ptu::TagValue::Int(2)));
+/// assert_eq!(header.get("HW_SerialNo"), Some(&ptu::TagValue::AnsiString(mh.get_serial())));
+/// ```
+pub struct Writer<W : Write + Seek> {
+    writer : W,
+    header : Header,
+    /// Where the value of `TTResult_NumberOfRecords` is, once the header is written
+    count_position : Option<u64>,
+    records : u64,
+    markers : MarkerTable,
+    marker_output : Option<PathBuf>,
+}
+
+impl Writer<BufWriter<File>> {
+    /// Creates the file at `path`, replacing any existing file, and
+    /// writes the marker table to `marker_table_path(path, "csv")`.
+    pub fn create<P : AsRef<Path>>(path : P, header : Header) -> io::Result<Self> {
+        Ok(Writer::new(BufWriter::new(File::create(&path)?), header)
+            .with_marker_output(marker_table_path(path, "csv")))
+    }
+}
+
+impl<W : Write + Seek> Writer<W> {
+    pub fn new(writer : W, header : Header) -> Self {
+        Writer { writer, header, count_position : None, records : 0, markers : MarkerTable::new(), marker_output : None }
+    }
+
+    /// Writes the marker events of the acquisition as CSV to `path` on
+    /// `finish`. The markers are only kept for streams started by `init`.
+    pub fn with_marker_output<P : AsRef<Path>>(mut self, path : P) -> Self {
+        self.marker_output = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// The marker events written so far
+    pub fn markers(&self) -> &MarkerTable {
+        &self.markers
+    }
+
+    /// Adds host times to the marker table, see `MarkerTable::set_clock_mapping`
+    pub fn set_clock_mapping(&mut self, mapping : ClockMapping) {
+        self.markers.set_clock_mapping(mapping);
+    }
+
+    /// Writes to `writer` with the header of `Header::from_device`
+    pub fn for_device<MH : MultiHarpDevice>(writer : W, multiharp : &MH) -> CheckedResult<Self, i32> {
+        Ok(Writer::new(writer, Header::from_device(multiharp)?))
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// Records written so far
+    pub fn records(&self) -> u64 {
+        self.records
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        if self.count_position.is_some() {
+            return Ok(());
+        }
+        self.header.set("TTResult_NumberOfRecords", TagValue::Int(0));
+        let w = &mut self.writer;
+        w.write_all(PTU_MAGIC)?;
+        w.write_all(PTU_VERSION)?;
+        for tag in &self.header.tags {
+            if tag.ident == "TTResult_NumberOfRecords" {
+                // The value follows the name, index and type
+                self.count_position = Some(w.stream_position()? + 40);
+            }
+            write_tag(w, &tag.ident, tag.index, &tag.value)?;
+        }
+        write_tag(w, "Header_End", -1, &TagValue::Empty)
+    }
+
+    /// Appends raw records, writing the header first if needed.
+    pub fn write_records(&mut self, records : &[u32]) -> io::Result<()> {
+        self.write_header()?;
+        for record in records {
+            self.writer.write_all(&record.to_le_bytes())?;
+        }
+        self.records += records.len() as u64;
+        // Without `init` there is no stream to decode the markers of
+        let _ = self.markers.decode(records);
+        Ok(())
+    }
+
+    /// Writes the number of records into the header and flushes. The
+    /// file stays valid to read after each call.
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.write_header()?;
+        if let Some(position) = self.count_position {
+            let end = self.writer.stream_position()?;
+            self.writer.seek(SeekFrom::Start(position))?;
+            self.writer.write_all(&(self.records as i64).to_le_bytes())?;
+            self.writer.seek(SeekFrom::Start(end))?;
+        }
+        self.header.set("TTResult_NumberOfRecords", TagValue::Int(self.records as i64));
+        if let (Some(path), true) = (&self.marker_output, self.markers.has_begun()) {
+            self.markers.save_csv(path)?;
+        }
+        self.writer.flush()
+    }
+
+    /// Finishes the file and returns the underlying writer
+    pub fn into_inner(mut self) -> io::Result<W> {
+        self.finish()?;
+        Ok(self.writer)
+    }
+}
+
+impl<W : Write + Seek + Send> StreamProcessor for Writer<W> {
+    fn name(&self) -> &str { "ptu_writer" }
+
+    /// Adds the acquisition's labels and the serial number to the header
+    fn init(&mut self, info : &StreamInfo) -> ProcessorResult {
+        if self.count_position.is_some() {
+            return Err("A PTU file holds a single acquisition".to_string());
+        }
+        if self.header.mode() != Some(info.mode) {
+            return Err(format!("The header is for {:?}, not {:?}", self.header.mode(), info.mode));
+        }
+        self.header
+            .set("HW_SerialNo", TagValue::AnsiString(info.serial.clone()))
+            .set_labels(&info.labels);
+        let mut markers = MarkerTable::new();
+        if let Some(TagValue::Float(period)) = self.header.get("MeasDesc_GlobalResolution") {
+            markers = markers.with_sync_period(period * 1e12);
+        }
+        markers.begin(info)?;
+        self.markers = markers;
+        self.write_header().map_err(|e| e.to_string())
+    }
+
+    fn process_batch(&mut self, batch : &Batch) -> ProcessorResult {
+        self.write_records(batch.records).map_err(|e| e.to_string())
+    }
+
+    fn finalize(&mut self) -> ProcessorResult {
+        self.finish().map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::tttr::pack_t2;
+    use crate::testing::fixtures::stream_info;
+
+    #[test]
+    fn test_ptu_round_trip() {
+        let mut header = Header::new(MeasurementMode::T2, 5.0, 0.0).unwrap();
+        header.set("File_Comment", TagValue::AnsiString("exactly 8".to_string()))
+            .set("MeasDesc_StopOnOvfl", TagValue::Bool(true));
+        let mut labels = Labels::new();
+        labels.insert("sample", "fly_03");
+        let info = StreamInfo { labels, ..stream_info(MeasurementMode::T2) };
+
+        let mut writer = Writer::new(Cursor::new(Vec::new()), header);
+        writer.init(&info).unwrap();
+        let records = [pack_t2(false, 0, 10), pack_t2(true, 0, 20), pack_t2(true, 1, 25), pack_t2(false, 3, 30)];
+        writer.write_records(&records).unwrap();
+        writer.finalize().unwrap();
+        assert!(writer.init(&info).is_err());
+        assert_eq!(writer.markers().rows().iter().map(|row| (row.markers, row.time)).collect::<Vec<_>>(), vec![(1, 25)]);
+        let bytes = writer.into_inner().unwrap().into_inner();
+
+        let mut reader = Cursor::new(&bytes);
+        let read = Header::read(&mut reader).unwrap();
+        assert_eq!(read.mode(), Some(MeasurementMode::T2));
+        assert_eq!(read.get("TTResult_NumberOfRecords"), Some(&TagValue::Int(4)));
+        assert_eq!(read.get("TTResultFormat_TTTRRecType"), Some(&TagValue::Int(REC_TYPE_MULTIHARP_T2)));
+        assert_eq!(read.get("MeasDesc_GlobalResolution"), Some(&TagValue::Float(5e-12)));
+        assert_eq!(read.get("File_Comment"), Some(&TagValue::AnsiString("exactly 8".to_string())));
+        assert_eq!(read.get("MeasDesc_StopOnOvfl"), Some(&TagValue::Bool(true)));
+        assert_eq!(read.get_indexed("UsrLabel_Value", 0), Some(&TagValue::AnsiString("fly_03".to_string())));
+        match read.get("File_CreatingTime") {
+            Some(TagValue::DateTime(time)) => assert!(SystemTime::now().duration_since(*time).unwrap() < Duration::from_secs(60)),
+            other => panic!("File_CreatingTime is {:?}", other),
+        }
+
+        // Every string is padded to 8 bytes, so the records start aligned
+        let start = reader.position() as usize;
+        assert_eq!(start % 8, 0);
+        let body = bytes[start..].chunks(4).map(|c| u32::from_le_bytes(c.try_into().unwrap())).collect::<Vec<_>>();
+        assert_eq!(body, records);
+
+        assert!(Header::new(MeasurementMode::Histogramming, 5.0, 0.0).is_err());
+        let mut long = Header::default();
+        long.set("A_Tag_Name_Longer_Than_Thirty_One", TagValue::Empty);
+        assert!(Writer::new(Cursor::new(Vec::new()), long).write_records(&[]).is_err());
+    }
+
+    #[test]
+    fn test_ptu_bad_date() {
+        // Too far for a `Duration`, then for a `SystemTime`
+        for days in [f64::INFINITY, f64::MAX, 2e14] {
+            let mut bytes = PTU_MAGIC.to_vec();
+            bytes.extend_from_slice(PTU_VERSION);
+            bytes.extend_from_slice(&[0u8; 32]);
+            bytes[16..16 + 17].copy_from_slice(b"File_CreatingTime");
+            bytes.extend_from_slice(&(-1i32).to_le_bytes());
+            bytes.extend_from_slice(&TY_TDATETIME.to_le_bytes());
+            bytes.extend_from_slice(&days.to_le_bytes());
+            let error = Header::read(&mut Cursor::new(&bytes)).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
@@ -0,0 +1,427 @@
+//! Count rates that are safe to read right after `init`: the device only
+//! updates its rate counters every 100 ms, so reading them too early
+//! returns zeros. `RateRecorder` keeps reading them in the background,
+//! whether or not a measurement is running, along with the device flags
+//! so it can report each flag as it is raised or cleared. Flag changes
+//! and new warnings also go to the device's event log. Each sample is
+//! checked against the `RateAlarm`s added to the recorder.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, TryLockError, mpsc};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
+
+use crate::advisor::Warnings;
+use crate::alarms::{AlarmAction, RateAlarm};
+use crate::notify::Notification;
+use crate::error::{MultiHarpError, MultiHarpResult};
+use crate::events::{self, DeviceEventKind};
+use crate::mhconsts::Flags;
+use crate::MultiHarpDevice;
+
+/// How often the device updates its rate counters
+pub const RATE_GATE : Duration = Duration::from_millis(100);
+
+/// Sync and input count rates, averaged over a gate.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rates {
+    /// Sync rate in Hz
+    pub sync : f64,
+    /// Count rate of each input channel in counts per second
+    pub inputs : Vec<f64>,
+    /// Number of `RATE_GATE` readings averaged
+    pub readings : u32,
+    /// When the last reading was taken
+    pub timestamp : SystemTime,
+}
+
+impl Rates {
+    /// Sum of the input rates
+    pub fn total_input(&self) -> f64 {
+        self.inputs.iter().sum()
+    }
+}
+
+/// Waits out `gate` (at least `RATE_GATE`) and returns the rates averaged
+/// over one reading per `RATE_GATE` in it.
+///
+/// ## Example
+///
+/// ```
+/// use std::time::Duration;
+/// use multi_harp_patina::*;
+///
+/// let mut mh = DebugMultiHarp150::default();
+/// mh.init(MeasurementMode::T3, ReferenceClock::Internal).unwrap();
+/// let rates = measure_rates(&mh, Duration::from_millis(300)).unwrap();
+/// assert_eq!(rates.readings, 3);
+/// println!("Sync {} Hz, inputs {:?}", rates.sync, rates.inputs);
+/// ```
+pub fn measure_rates<MH : MultiHarpDevice>(multiharp : &MH, gate : Duration) -> MultiHarpResult<Rates> {
+    let readings = ((gate.as_millis() / RATE_GATE.as_millis()) as u32).max(1);
+
+    let mut sync_sum = 0.0;
+    let mut input_sums : Vec<f64> = Vec::new();
+    for _ in 0..readings {
+        std::thread::sleep(RATE_GATE);
+        let (sync, inputs) = multiharp.get_all_count_rates()?;
+        sync_sum += sync as f64;
+        input_sums.resize(inputs.len(), 0.0);
+        input_sums.iter_mut().zip(inputs).for_each(|(sum, rate)| *sum += rate as f64);
+    }
+
+    Ok(Rates {
+        sync : sync_sum / readings as f64,
+        inputs : input_sums.into_iter().map(|sum| sum / readings as f64).collect(),
+        readings,
+        timestamp : SystemTime::now(),
+    })
+}
+
+/// One reading taken by a `RateRecorder`
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateSample {
+    pub rates : Rates,
+    /// `get_warnings`, read right after the rates it refers to
+    pub warnings : Warnings,
+    /// `get_flags`, read with the warnings
+    pub flags : i32,
+}
+
+/// A change in the flags of a device seen by a `RateRecorder`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FlagChange {
+    pub old : i32,
+    pub new : i32,
+    /// When the new flags were read
+    pub timestamp : SystemTime,
+}
+
+impl FlagChange {
+    /// Whether `flag` went from clear to set
+    pub fn raised(&self, flag : Flags) -> bool {
+        self.new & !self.old & flag as i32 != 0
+    }
+
+    /// Whether `flag` went from set to clear
+    pub fn cleared(&self, flag : Flags) -> bool {
+        self.old & !self.new & flag as i32 != 0
+    }
+}
+
+type SampleBuffer = Arc<Mutex<VecDeque<RateSample>>>;
+type FlagSubscribers = Arc<Mutex<Vec<mpsc::Sender<FlagChange>>>>;
+type Alarms = Arc<Mutex<Vec<RateAlarm>>>;
+
+/// Polls the count rates and warnings of a device on a background thread,
+/// keeping the most recent readings, so an instrument can be watched
+/// between experiments as well as during them. Polling stops when `stop`
+/// is called, when the `RateRecorder` is dropped, or at the first error.
+///
+/// The device is shared through a `Mutex`. A reading is skipped rather
+/// than waited for while another thread holds it (e.g. a `Pipeline`
+/// running an acquisition), so the recorder never holds up the FIFO.
+///
+/// ## Example
+///
+/// ```
+/// use std::sync::{Arc, Mutex};
+/// use std::time::Duration;
+/// use multi_harp_patina::*;
+///
+/// let mh = Arc::new(Mutex::new(DebugMultiHarp150::default()));
+/// let recorder = RateRecorder::start(Arc::clone(&mh), RATE_GATE, 600);
+/// std::thread::sleep(Duration::from_millis(250));
+/// let samples = recorder.stop().unwrap();
+/// assert!(!samples.is_empty());
+/// ```
+pub struct RateRecorder {
+    stop : Option<mpsc::Sender<()>>,
+    samples : SampleBuffer,
+    subscribers : FlagSubscribers,
+    alarms : Alarms,
+    thread : Option<JoinHandle<MultiHarpResult<()>>>,
+}
+
+impl RateRecorder {
+    /// Starts reading `multiharp` every `interval` (at least `RATE_GATE`),
+    /// keeping the last `capacity` samples.
+    pub fn start<MH>(multiharp : Arc<Mutex<MH>>, interval : Duration, capacity : usize) -> Self
+    where MH : MultiHarpDevice + Send + 'static {
+        let interval = interval.max(RATE_GATE);
+        let capacity = capacity.max(1);
+        let samples : SampleBuffer = Arc::new(Mutex::new(VecDeque::with_capacity(capacity)));
+        let subscribers : FlagSubscribers = Arc::new(Mutex::new(Vec::new()));
+        let alarms : Alarms = Arc::new(Mutex::new(Vec::new()));
+        let (stop, stopped) = mpsc::channel::<()>();
+
+        let buffer = Arc::clone(&samples);
+        let subscribed = Arc::clone(&subscribers);
+        let watched = Arc::clone(&alarms);
+        let thread = std::thread::spawn(move || {
+            // Flags are taken to be clear before the first reading
+            let mut last_flags = 0;
+            let mut last_warnings = 0;
+            loop {
+                // Wakes early if `stop` is called or the recorder is dropped
+                match stopped.recv_timeout(interval) {
+                    Err(mpsc::RecvTimeoutError::Timeout) => {},
+                    _ => return Ok(()),
+                }
+
+                let mut mh = match multiharp.try_lock() {
+                    Ok(mh) => mh,
+                    Err(TryLockError::WouldBlock) => continue,
+                    Err(TryLockError::Poisoned(_)) => return Err(MultiHarpError::ThreadStateFail),
+                };
+                let (sync, inputs) = mh.get_all_count_rates()?;
+                let (warnings, flags, serial) = (mh.get_warnings()?, mh.get_flags()?, mh.get_serial());
+                let timestamp = SystemTime::now();
+                if flags != last_flags {
+                    let change = FlagChange { old : last_flags, new : flags, timestamp };
+                    events::record(&serial, DeviceEventKind::Flags, format!("0x{:04x} -> 0x{:04x}", last_flags, flags));
+                    subscribed.lock().map_err(|_| MultiHarpError::ThreadStateFail)?
+                        .retain(|subscriber| subscriber.send(change).is_ok());
+                    last_flags = flags;
+                }
+                if warnings != last_warnings {
+                    if warnings != 0 {
+                        let names = Warnings(warnings).iter().map(|(_, name)| name).collect::<Vec<_>>();
+                        events::record(&serial, DeviceEventKind::Warning, names.join(" | "));
+                    }
+                    last_warnings = warnings;
+                }
+                let sample = RateSample {
+                    rates : Rates {
+                        sync : sync as f64,
+                        inputs : inputs.into_iter().map(|rate| rate as f64).collect(),
+                        readings : 1,
+                        timestamp,
+                    },
+                    warnings : Warnings(warnings),
+                    flags,
+                };
+
+                let mut alarms = watched.lock().map_err(|_| MultiHarpError::ThreadStateFail)?;
+                for alarm in alarms.iter_mut() {
+                    let triggered = match alarm.check(&serial, &sample.rates) {
+                        Some(triggered) => triggered,
+                        None => continue,
+                    };
+                    for action in alarm.actions() {
+                        match action {
+                            AlarmAction::Log => events::record(&serial, DeviceEventKind::Warning, triggered.to_string()),
+                            AlarmAction::StopMeasurement => if mh.ctc_status().unwrap_or(false) {
+                                let _ = mh.stop_measurement();
+                            },
+                            AlarmAction::Call(hook) => hook(&triggered),
+                            AlarmAction::Notify(notifier) => {
+                                notifier.notify(&Notification::Alarm(triggered.clone()));
+                            },
+                        }
+                    }
+                }
+                drop(alarms);
+                drop(mh);
+
+                let mut buffer = buffer.lock().map_err(|_| MultiHarpError::ThreadStateFail)?;
+                if buffer.len() == capacity {
+                    buffer.pop_front();
+                }
+                buffer.push_back(sample);
+            }
+        });
+
+        RateRecorder { stop : Some(stop), samples, subscribers, alarms, thread : Some(thread) }
+    }
+
+    /// Checks every sample from now on against `alarm`, taking its actions
+    /// on the recorder's thread while it holds the device. Like the rates,
+    /// alarms are only checked when the device isn't held elsewhere.
+    pub fn add_alarm(&self, alarm : RateAlarm) {
+        if let Ok(mut alarms) = self.alarms.lock() {
+            alarms.push(alarm);
+        }
+    }
+
+    /// Removes every alarm, returning them
+    pub fn clear_alarms(&self) -> Vec<RateAlarm> {
+        self.alarms.lock().map(|mut a| a.drain(..).collect()).unwrap_or_default()
+    }
+
+    /// Delivers every change in the device flags from now on, once per
+    /// transition, rather than the flags of each sample. Flags already set
+    /// at the first reading arrive as a change from 0. The channel closes
+    /// when the recorder stops.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// use std::sync::{Arc, Mutex};
+    /// use multi_harp_patina::*;
+    ///
+    /// let mh = Arc::new(Mutex::new(DebugMultiHarp150::default()));
+    /// let recorder = RateRecorder::start(Arc::clone(&mh), RATE_GATE, 10);
+    /// let changes = recorder.flags_changed();
+    ///
+    /// mh.lock().unwrap().set_flags(FLAG_FIFOFULL);
+    /// let change = changes.recv().unwrap();
+    /// assert!(change.raised(Flags::FifoFull));
+    /// ```
+    pub fn flags_changed(&self) -> mpsc::Receiver<FlagChange> {
+        let (sender, receiver) = mpsc::channel();
+        if let Ok(mut subscribers) = self.subscribers.lock() {
+            subscribers.push(sender);
+        }
+        receiver
+    }
+
+    /// Whether the recorder is still polling
+    pub fn is_running(&self) -> bool {
+        self.thread.as_ref().map(|t| !t.is_finished()).unwrap_or(false)
+    }
+
+    /// The most recent sample, if any
+    pub fn latest(&self) -> Option<RateSample> {
+        self.samples.lock().ok().and_then(|s| s.back().cloned())
+    }
+
+    /// Removes and returns the samples recorded so far, oldest first.
+    pub fn take_samples(&self) -> Vec<RateSample> {
+        self.samples.lock().map(|mut s| s.drain(..).collect()).unwrap_or_default()
+    }
+
+    /// Stops polling and returns the remaining samples, or the error that
+    /// stopped the recorder early.
+    pub fn stop(mut self) -> MultiHarpResult<Vec<RateSample>> {
+        self.teardown()?;
+        Ok(self.take_samples())
+    }
+
+    fn teardown(&mut self) -> MultiHarpResult<()> {
+        // Dropping the sender wakes the thread
+        self.stop.take();
+        let result = self.thread.take()
+            .map(|t| t.join().unwrap_or(Err(MultiHarpError::ThreadStateFail)))
+            .unwrap_or(Ok(()));
+        // Closes the `flags_changed` channels
+        if let Ok(mut subscribers) = self.subscribers.lock() {
+            subscribers.clear();
+        }
+        result
+    }
+}
+
+impl Drop for RateRecorder {
+    fn drop(&mut self) {
+        let _ = self.teardown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DebugMultiHarp150;
+
+    #[test]
+    fn test_measure_rates() {
+        let mh = DebugMultiHarp150::default();
+        let started = std::time::Instant::now();
+        // Too short a gate is extended to one reading
+        let rates = measure_rates(&mh, Duration::from_millis(10)).unwrap();
+        assert!(started.elapsed() >= RATE_GATE);
+        assert_eq!(rates.readings, 1);
+        assert_eq!(rates.inputs.len(), 4);
+        assert_eq!(rates.total_input(), 4e5);
+        assert_eq!(rates.sync, 78e6);
+    }
+
+    #[test]
+    fn test_rate_recorder_without_measurement() {
+        let mh = Arc::new(Mutex::new(DebugMultiHarp150::default()));
+        let recorder = RateRecorder::start(Arc::clone(&mh), Duration::ZERO, 2);
+        std::thread::sleep(RATE_GATE * 4);
+        assert!(recorder.is_running());
+        assert_eq!(recorder.latest().unwrap().rates.sync, 78e6);
+
+        // Skips readings while the device is busy
+        let guard = mh.lock().unwrap();
+        recorder.take_samples();
+        std::thread::sleep(RATE_GATE * 2);
+        assert!(recorder.latest().is_none());
+        drop(guard);
+
+        std::thread::sleep(RATE_GATE * 4);
+        let samples = recorder.stop().unwrap();
+        // Only the last `capacity` are kept
+        assert_eq!(samples.len(), 2);
+        assert!(samples.iter().all(|s| s.warnings.is_empty()));
+        assert!(samples[0].rates.timestamp <= samples[1].rates.timestamp);
+    }
+
+    #[test]
+    fn test_flags_changed() {
+        let mh = Arc::new(Mutex::new(DebugMultiHarp150::default()));
+        mh.lock().unwrap().set_flags(crate::FLAG_SYNC_LOST);
+        let recorder = RateRecorder::start(Arc::clone(&mh), Duration::ZERO, 10);
+        let changes = recorder.flags_changed();
+        let timeout = RATE_GATE * 10;
+
+        let initial = changes.recv_timeout(timeout).unwrap();
+        assert_eq!((initial.old, initial.new), (0, crate::FLAG_SYNC_LOST));
+        assert!(initial.raised(Flags::SyncLost));
+
+        mh.lock().unwrap().set_flags(crate::FLAG_SYNC_LOST | crate::FLAG_FIFOFULL);
+        let full = changes.recv_timeout(timeout).unwrap();
+        assert!(full.raised(Flags::FifoFull));
+        assert!(!full.raised(Flags::SyncLost));
+
+        mh.lock().unwrap().set_flags(0);
+        let cleared = changes.recv_timeout(timeout).unwrap();
+        assert!(cleared.cleared(Flags::FifoFull) && cleared.cleared(Flags::SyncLost));
+
+        // Unchanged flags aren't reported again
+        assert!(changes.recv_timeout(RATE_GATE * 3).is_err());
+        assert!(recorder.latest().unwrap().flags == 0);
+        recorder.stop().unwrap();
+        assert_eq!(changes.recv(), Err(mpsc::RecvError));
+
+        let serial = mh.lock().unwrap().get_serial();
+        assert!(crate::recent_events(&serial).iter()
+            .any(|e| e.kind == crate::DeviceEventKind::Flags && e.message == "0x0006 -> 0x0000"));
+    }
+
+    #[test]
+    fn test_rate_alarms() {
+        use crate::alarms::{RateSource, Threshold};
+
+        let mh = Arc::new(Mutex::new(DebugMultiHarp150::default()));
+        mh.lock().unwrap().init(crate::MeasurementMode::T3, crate::ReferenceClock::Internal).unwrap();
+        mh.lock().unwrap().start_measurement(crate::ACQTMAX).unwrap();
+        let recorder = RateRecorder::start(Arc::clone(&mh), Duration::ZERO, 10);
+
+        let (sender, triggered) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        recorder.add_alarm(
+            RateAlarm::new("bright", RateSource::Input(0), Threshold::Above(5e4))
+                .with_action(AlarmAction::Log)
+                .with_action(AlarmAction::StopMeasurement)
+                .with_action(AlarmAction::Call(Arc::new(move |alarm| {
+                    let _ = sender.lock().unwrap().send(alarm.clone());
+                })))
+        );
+        recorder.add_alarm(RateAlarm::new("dark", RateSource::Sync, Threshold::Below(1e3)));
+
+        let alarm = triggered.recv_timeout(RATE_GATE * 10).unwrap();
+        assert_eq!((alarm.name.as_str(), alarm.rate), ("bright", 1e5));
+        assert!(!mh.lock().unwrap().ctc_status().unwrap());
+        // Went off once, though the rate stays above the threshold
+        assert!(triggered.recv_timeout(RATE_GATE * 3).is_err());
+
+        assert_eq!(recorder.clear_alarms().len(), 2);
+        recorder.stop().unwrap();
+        let serial = mh.lock().unwrap().get_serial();
+        assert!(crate::recent_events(&serial).iter()
+            .any(|e| e.kind == crate::DeviceEventKind::Warning && e.message.starts_with("Alarm bright")));
+    }
+}
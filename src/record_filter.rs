@@ -0,0 +1,437 @@
+//! Photon filters written as text, so that which photons an acquisition
+//! keeps can be set in a config file or on the command line. An
+//! expression is a list of clauses joined by `and` (or `&&`, or `;`),
+//! all of which a photon must pass:
+//!
+//! * `channel in {0, 2..4}`, `channel not in {1}`, `channel = 3`,
+//!   `channel != 3` -- channel sets, with ranges `a..b` or `a..=b`
+//! * `dtime in 200..2000`, `dtime >= 200`, `dtime < 2000` -- arrival
+//!   time after the sync, in bins of the resolution (T3 only)
+//! * `marker gate 1 to 2` -- photons after marker 1 and before marker 2,
+//!   e.g. the forward sweep of each line of a scan
+//! * `rate <= 5e5` -- at most this many photons per second, by the time
+//!   each batch was read, the rest dropped
+//!
+//! Parsing combines the clauses into one channel mask, one `dtime` range,
+//! one gate and one rate, so a photon costs a few comparisons whatever
+//! the expression. Markers and overflows always pass.
+
+use std::fmt::{self, Display};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use crate::mhconsts::MeasurementMode;
+use crate::monitor::SyncEvent;
+use crate::pipeline::{Batch, ProcessorResult, StreamInfo, StreamProcessor};
+use crate::tttr::{Decoder, EventKind, TttrEvent};
+
+/// Highest channel a `channel` clause can name
+pub const MAX_FILTER_CHANNEL : u8 = 63;
+/// Highest marker a `marker gate` clause can name
+const MAX_GATE_MARKER : u8 = 4;
+
+/// A parsed filter expression.
+///
+/// ## Example
+///
+/// ```
+/// use multi_harp_patina::*;
+///
+/// let filter : RecordFilter = "channel in {0, 2} and dtime in 200..2000 and dtime >= 500".parse().unwrap();
+/// assert!(filter.keeps(&TttrEvent::photon(2, 10, 500)));
+/// assert!(!filter.keeps(&TttrEvent::photon(1, 10, 500)));
+/// assert!(!filter.keeps(&TttrEvent::photon(0, 10, 2000)));
+/// // Printed in the combined form
+/// assert_eq!(filter.to_string(), "channel in {0, 2} and dtime in 500..=1999");
+/// ```
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct RecordFilter {
+    /// Bit `c` set if channel `c` passes
+    channels : Option<u64>,
+    dtime : Option<RangeInclusive<u16>>,
+    /// Markers (1 to 4) opening and closing the gate
+    marker_gate : Option<(u8, u8)>,
+    /// Photons per second
+    rate_limit : Option<f64>,
+}
+
+fn parse_number<T : FromStr>(s : &str, clause : &str) -> Result<T, String> {
+    s.trim().parse::<T>().map_err(|_| format!("Expected a number, not `{}`, in `{}`", s.trim(), clause))
+}
+
+/// Parses `a`, `a..b` or `a..=b` as an inclusive range of `u16`s
+fn parse_range(s : &str, clause : &str) -> Result<RangeInclusive<u16>, String> {
+    let s = s.trim();
+    if let Some((start, end)) = s.split_once("..=") {
+        return Ok(parse_number(start, clause)?..=parse_number(end, clause)?);
+    }
+    if let Some((start, end)) = s.split_once("..") {
+        let end : u16 = parse_number(end, clause)?;
+        if end == 0 {
+            return Err(format!("Empty range in `{}`", clause));
+        }
+        return Ok(parse_number(start, clause)?..=end - 1);
+    }
+    let value = parse_number(s, clause)?;
+    Ok(value..=value)
+}
+
+/// Parses `{0, 2..4}` (the braces optional) as a channel mask
+fn parse_channel_set(s : &str, clause : &str) -> Result<u64, String> {
+    let s = s.trim();
+    let s = s.strip_prefix('{').and_then(|s| s.strip_suffix('}')).unwrap_or(s);
+    let mut mask = 0u64;
+    for item in s.split(',').filter(|item| !item.trim().is_empty()) {
+        let range = parse_range(item, clause)?;
+        if *range.end() > MAX_FILTER_CHANNEL as u16 {
+            return Err(format!("Channel {} is out of range in `{}`", range.end(), clause));
+        }
+        for channel in range {
+            mask |= 1 << channel;
+        }
+    }
+    Ok(mask)
+}
+
+impl RecordFilter {
+    /// Passes every photon
+    pub fn all() -> Self {
+        Self::default()
+    }
+
+    /// Whether `photon` passes the channel and `dtime` clauses. The gate
+    /// and the rate limit depend on the stream, see `FilterStage`.
+    pub fn keeps(&self, photon : &TttrEvent) -> bool {
+        self.channels.map_or(true, |mask| photon.channel <= MAX_FILTER_CHANNEL && mask & (1 << photon.channel) != 0)
+            && self.dtime.as_ref().map_or(true, |dtime| dtime.contains(&photon.dtime))
+    }
+
+    pub fn marker_gate(&self) -> Option<(u8, u8)> {
+        self.marker_gate
+    }
+
+    pub fn rate_limit(&self) -> Option<f64> {
+        self.rate_limit
+    }
+
+    fn restrict_channels(&mut self, mask : u64) {
+        self.channels = Some(self.channels.unwrap_or(u64::MAX) & mask);
+    }
+
+    fn restrict_dtime(&mut self, range : RangeInclusive<u16>) {
+        self.dtime = Some(match self.dtime.take() {
+            Some(dtime) => (*dtime.start()).max(*range.start())..=(*dtime.end()).min(*range.end()),
+            None => range,
+        });
+    }
+
+    fn add_clause(&mut self, clause : &str) -> Result<(), String> {
+        let field_end = clause.find(|c : char| !c.is_ascii_alphabetic()).unwrap_or(clause.len());
+        let (field, rest) = clause.split_at(field_end);
+        let rest = rest.trim();
+        match field {
+            "channel" => {
+                if let Some(set) = rest.strip_prefix("not in") {
+                    self.restrict_channels(!parse_channel_set(set, clause)?);
+                } else if let Some(set) = rest.strip_prefix("in") {
+                    self.restrict_channels(parse_channel_set(set, clause)?);
+                } else if let Some(channel) = rest.strip_prefix("!=") {
+                    self.restrict_channels(!parse_channel_set(channel, clause)?);
+                } else if let Some(channel) = rest.strip_prefix("==").or_else(|| rest.strip_prefix('=')) {
+                    self.restrict_channels(parse_channel_set(channel, clause)?);
+                } else {
+                    return Err(format!("Expected `in`, `not in`, `=` or `!=` in `{}`", clause));
+                }
+            },
+            "dtime" => {
+                let range = if let Some(range) = rest.strip_prefix("in") {
+                    parse_range(range, clause)?
+                } else if let Some(value) = rest.strip_prefix(">=") {
+                    parse_number(value, clause)?..=u16::MAX
+                } else if let Some(value) = rest.strip_prefix("<=") {
+                    0..=parse_number(value, clause)?
+                } else if let Some(value) = rest.strip_prefix('>') {
+                    parse_number::<u16>(value, clause)?.saturating_add(1)..=u16::MAX
+                } else if let Some(value) = rest.strip_prefix('<') {
+                    match parse_number::<u16>(value, clause)?.checked_sub(1) {
+                        Some(end) => 0..=end,
+                        None => return Err(format!("No `dtime` passes `{}`", clause)),
+                    }
+                } else {
+                    return Err(format!("Expected `in`, `<`, `<=`, `>` or `>=` in `{}`", clause));
+                };
+                self.restrict_dtime(range);
+            },
+            "marker" => {
+                let (open, close) = rest.strip_prefix("gate")
+                    .and_then(|markers| markers.split_once("to"))
+                    .ok_or_else(|| format!("Expected `marker gate <open> to <close>`, not `{}`", clause))?;
+                let open : u8 = parse_number(open, clause)?;
+                let close : u8 = parse_number(close, clause)?;
+                if !(1..=MAX_GATE_MARKER).contains(&open) || !(1..=MAX_GATE_MARKER).contains(&close) {
+                    return Err(format!("Markers are numbered 1 to {}, in `{}`", MAX_GATE_MARKER, clause));
+                }
+                if self.marker_gate.is_some() {
+                    return Err(format!("Only one marker gate is allowed, at `{}`", clause));
+                }
+                self.marker_gate = Some((open, close));
+            },
+            "rate" => {
+                let limit : f64 = rest.strip_prefix("<=").or_else(|| rest.strip_prefix('<'))
+                    .ok_or_else(|| format!("Expected `rate <= <photons per second>`, not `{}`", clause))
+                    .and_then(|limit| parse_number(limit, clause))?;
+                if limit.is_nan() || limit < 0.0 {
+                    return Err(format!("Invalid rate limit in `{}`", clause));
+                }
+                self.rate_limit = Some(self.rate_limit.map_or(limit, |rate| rate.min(limit)));
+            },
+            _ => return Err(format!("Unknown field `{}` in `{}`", field, clause)),
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for RecordFilter {
+    type Err = String;
+
+    /// Parses an expression, see the module documentation. An empty
+    /// expression passes every photon.
+    fn from_str(s : &str) -> Result<Self, String> {
+        let mut filter = RecordFilter::all();
+        let mut clause = Vec::new();
+        for token in s.split_whitespace().flat_map(|token| token.split_inclusive(';')) {
+            let (token, ends_clause) = match token.strip_suffix(';') {
+                Some(token) => (token, true),
+                None => (token, token == "and" || token == "&&"),
+            };
+            if token != "and" && token != "&&" && !token.is_empty() {
+                clause.push(token);
+            }
+            if ends_clause && !clause.is_empty() {
+                filter.add_clause(&clause.join(" "))?;
+                clause.clear();
+            }
+        }
+        if !clause.is_empty() {
+            filter.add_clause(&clause.join(" "))?;
+        }
+        Ok(filter)
+    }
+}
+
+impl Display for RecordFilter {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        let mut clauses = Vec::new();
+        if let Some(mask) = self.channels {
+            let channels = (0..=MAX_FILTER_CHANNEL)
+                .filter(|channel| mask & (1 << channel) != 0)
+                .map(|channel| channel.to_string())
+                .collect::<Vec<_>>();
+            clauses.push(format!("channel in {{{}}}", channels.join(", ")));
+        }
+        if let Some(dtime) = &self.dtime {
+            clauses.push(format!("dtime in {}..={}", dtime.start(), dtime.end()));
+        }
+        if let Some((open, close)) = self.marker_gate {
+            clauses.push(format!("marker gate {} to {}", open, close));
+        }
+        if let Some(rate) = self.rate_limit {
+            clauses.push(format!("rate <= {}", rate));
+        }
+        write!(f, "{}", clauses.join(" and "))
+    }
+}
+
+/// Passes the photons a `RecordFilter` keeps, and every special record,
+/// on to `next`.
+///
+/// ## Example
+///
+/// ```
+/// use multi_harp_patina::*;
+///
+/// # struct Histogrammer;
+/// # impl StreamProcessor for Histogrammer {
+/// #     fn name(&self) -> &str { "histogrammer" }
+/// #     fn process_batch(&mut self, batch : &Batch) -> ProcessorResult { Ok(()) }
+/// # }
+/// // e.g. read from a config file or a command line argument
+/// let expression = "channel != 3 and marker gate 1 to 2";
+/// let stage = FilterStage::new(expression.parse().unwrap(), Box::new(Histogrammer));
+/// let mut pipeline = Pipeline::new();
+/// pipeline.add(stage);
+/// ```
+pub struct FilterStage {
+    filter : RecordFilter,
+    next : Box<dyn StreamProcessor>,
+    decoder : Option<Decoder>,
+    gate_open : bool,
+    /// Start of the current second of the rate limit, and photons kept in it
+    rate_window : Option<(Instant, f64)>,
+    kept : Vec<u32>,
+    rejected : u64,
+}
+
+impl FilterStage {
+    pub fn new(filter : RecordFilter, next : Box<dyn StreamProcessor>) -> Self {
+        FilterStage { filter, next, decoder : None, gate_open : false, rate_window : None, kept : Vec::new(), rejected : 0 }
+    }
+
+    pub fn filter(&self) -> &RecordFilter {
+        &self.filter
+    }
+
+    /// Photons dropped in the current (or last) acquisition
+    pub fn rejected(&self) -> u64 {
+        self.rejected
+    }
+
+    /// Opens or closes the gate on the markers in `markers`, closing
+    /// first so that a marker that does both reopens it
+    fn update_gate(&mut self, markers : u8) {
+        if let Some((open, close)) = self.filter.marker_gate {
+            if markers & (1 << (close - 1)) != 0 {
+                self.gate_open = false;
+            }
+            if markers & (1 << (open - 1)) != 0 {
+                self.gate_open = true;
+            }
+        }
+    }
+}
+
+impl StreamProcessor for FilterStage {
+    fn name(&self) -> &str {
+        "filter_stage"
+    }
+
+    fn init(&mut self, info : &StreamInfo) -> ProcessorResult {
+        if self.filter.dtime.is_some() && info.mode != MeasurementMode::T3 {
+            return Err(format!("`dtime` clauses only apply to T3 mode, not {:?}", info.mode));
+        }
+        self.decoder = Some(Decoder::new(info.mode)?.with_overflow_compression(info.overflow_compression));
+        self.gate_open = false;
+        self.rate_window = None;
+        self.rejected = 0;
+        self.next.init(info)
+    }
+
+    fn process_batch(&mut self, batch : &Batch) -> ProcessorResult {
+        let window = self.rate_window.get_or_insert((batch.read_at, 0.0));
+        if batch.read_at.duration_since(window.0) >= Duration::from_secs(1) {
+            *window = (batch.read_at, 0.0);
+        }
+        let mut kept_this_second = window.1;
+
+        self.kept.clear();
+        for record in batch.records.iter() {
+            let event = self.decoder.as_mut().ok_or("`process_batch` called before `init`")?.decode(*record);
+            match event {
+                Some(event) if event.kind == EventKind::Photon => {
+                    let keep = self.filter.keeps(&event)
+                        && (self.filter.marker_gate.is_none() || self.gate_open)
+                        && self.filter.rate_limit.map_or(true, |limit| kept_this_second < limit);
+                    if keep {
+                        kept_this_second += 1.0;
+                        self.kept.push(*record);
+                    } else {
+                        self.rejected += 1;
+                    }
+                },
+                Some(event) => {
+                    if event.kind == EventKind::Marker {
+                        self.update_gate(event.channel);
+                    }
+                    self.kept.push(*record);
+                },
+                None => self.kept.push(*record),
+            }
+        }
+        if let Some((_, kept)) = self.rate_window.as_mut() {
+            *kept = kept_this_second;
+        }
+        self.next.process_batch(&Batch { records : &self.kept, ..*batch })
+    }
+
+    fn sync_event(&mut self, event : &SyncEvent) -> ProcessorResult {
+        self.next.sync_event(event)
+    }
+
+    fn finalize(&mut self) -> ProcessorResult {
+        self.next.finalize()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use crate::tttr::{pack_t3, OVERFLOW_CHANNEL};
+    use crate::testing::fixtures::stream_info;
+
+    struct Collector {
+        out : Arc<Mutex<Vec<u32>>>,
+    }
+
+    impl StreamProcessor for Collector {
+        fn name(&self) -> &str { "collector" }
+
+        fn process_batch(&mut self, batch : &Batch) -> ProcessorResult {
+            self.out.lock().unwrap().extend_from_slice(batch.records);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_parse_record_filter() {
+        let filter : RecordFilter = "channel in 0..=3; channel not in {1} && dtime > 10 and dtime<=20 and rate <= 1e3 and rate < 2e3"
+            .parse().unwrap();
+        assert_eq!(filter.to_string(), "channel in {0, 2, 3} and dtime in 11..=20 and rate <= 1000");
+        assert_eq!(filter.to_string().parse::<RecordFilter>().unwrap(), filter);
+        assert_eq!("".parse::<RecordFilter>().unwrap(), RecordFilter::all());
+        assert_eq!("marker gate 1 to 2".parse::<RecordFilter>().unwrap().marker_gate(), Some((1, 2)));
+
+        for invalid in [
+            "channel 3", "channel in {64}", "dtime in 5..0", "dtime < 0", "marker gate 0 to 2",
+            "marker gate 1 to 2 and marker gate 3 to 4", "rate >= 5", "nsync < 5", "channel = x",
+        ] {
+            assert!(invalid.parse::<RecordFilter>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_filter_stage() {
+        let out = Arc::new(Mutex::new(Vec::new()));
+        let filter = "channel != 3 and marker gate 1 to 2 and rate <= 2".parse().unwrap();
+        let mut stage = FilterStage::new(filter, Box::new(Collector { out : Arc::clone(&out) }));
+        stage.init(&stream_info(MeasurementMode::T3)).unwrap();
+
+        let start = Instant::now();
+        let records = [
+            pack_t3(false, 0, 10, 1),
+            pack_t3(true, 1, 0, 2),
+            pack_t3(false, 3, 10, 3),
+            pack_t3(false, 0, 10, 3),
+            pack_t3(true, OVERFLOW_CHANNEL, 0, 1),
+            pack_t3(false, 1, 10, 4),
+            pack_t3(false, 2, 10, 5),
+        ];
+        let batch = |index : u64, ms : u64, records| Batch {
+            index, read_at : start + Duration::from_millis(ms), records, mode : MeasurementMode::T3, sync_lost : false,
+        };
+        stage.process_batch(&batch(0, 0, &records)).unwrap();
+        // A new second, but the gate is closed
+        stage.process_batch(&batch(1, 1000, &[pack_t3(true, 2, 0, 6), pack_t3(false, 0, 10, 7)])).unwrap();
+        stage.finalize().unwrap();
+
+        assert_eq!(*out.lock().unwrap(), vec![
+            records[1], records[3], records[4], records[5], pack_t3(true, 2, 0, 6),
+        ]);
+        // Before the gate, channel 3, over the rate, after the gate
+        assert_eq!(stage.rejected(), 4);
+
+        let mut stage = FilterStage::new("dtime < 5".parse().unwrap(), Box::new(Collector { out }));
+        assert!(stage.init(&stream_info(MeasurementMode::T2)).is_err());
+    }
+}
@@ -0,0 +1,312 @@
+//! A quick check of a device before an experimental session. The
+//! self-test initializes the device in T3 mode, checks that its rate
+//! counters read close to zero with nothing connected, optionally counts
+//! the trigger output looped back into an input, checks the flags and
+//! warnings, and makes a short T3 measurement. The result of each is
+//! reported separately, so a failure points at the part to look at.
+//!
+//! Unlike the conformance suite, which checks that an implementation
+//! of `MultiHarpDevice` behaves as documented, the self-test checks the
+//! hardware behind it.
+
+use std::fmt::Display;
+use std::time::{Duration, Instant};
+
+use crate::events::{self, DeviceEventKind};
+use crate::advisor::Warnings;
+use crate::mhconsts::{self, Flags, MeasurementMode, ReferenceClock};
+use crate::multiharp::MultiHarpDevice;
+use crate::rates::{measure_rates, RATE_GATE};
+use crate::tttr::Decoder;
+
+/// Highest input rate, in counts per second, taken for an input with
+/// nothing connected, unless set with `SelfTest::with_dark_rate_limit`
+pub const DEFAULT_DARK_RATE_LIMIT : f64 = 1000.0;
+
+/// Period of the trigger output during the loopback check, in units of
+/// 100 ns (10 kHz)
+const LOOPBACK_PERIOD : i32 = 1000;
+
+/// How far the looped back rate may be from the trigger output rate
+const LOOPBACK_TOLERANCE : f64 = 0.05;
+
+/// Acquisition time of the T3 measurement, in ms
+const T3_READ_TIME : i32 = 100;
+
+/// Longest the T3 measurement may take to end
+const T3_READ_TIMEOUT : Duration = Duration::from_secs(5);
+
+/// Flags that fail the self-test when set after `init`
+const FAILING_FLAGS : [(Flags, &str); 4] = [
+    (Flags::SysError, "SysError"),
+    (Flags::FifoFull, "FifoFull"),
+    (Flags::CountsDropped, "CountsDropped"),
+    (Flags::RefLost, "RefLost"),
+];
+
+/// How one part of the self-test went
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelfTestOutcome {
+    Passed,
+    Failed,
+    /// Not run, because it wasn't asked for or an earlier part failed
+    Skipped,
+}
+
+/// The result of one part of the self-test
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelfTestResult {
+    /// `init`, `rates`, `loopback`, `flags` or `t3_read`
+    pub subsystem : &'static str,
+    pub outcome : SelfTestOutcome,
+    /// What was measured, or what went wrong
+    pub detail : String,
+}
+
+/// The results of `MultiHarpDevice::self_test`, in the order run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelfTestReport {
+    pub serial : String,
+    pub results : Vec<SelfTestResult>,
+}
+
+impl SelfTestReport {
+    /// Whether nothing failed
+    pub fn passed(&self) -> bool {
+        self.failures().next().is_none()
+    }
+
+    /// The parts that failed
+    pub fn failures(&self) -> impl Iterator<Item = &SelfTestResult> {
+        self.results.iter().filter(|r| r.outcome == SelfTestOutcome::Failed)
+    }
+
+    /// The result for `subsystem`, if it is part of the report
+    pub fn get(&self, subsystem : &str) -> Option<&SelfTestResult> {
+        self.results.iter().find(|r| r.subsystem == subsystem)
+    }
+}
+
+impl Display for SelfTestReport {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "Self-test of {}: {}", self.serial, if self.passed() { "passed" } else { "FAILED" })?;
+        for result in self.results.iter() {
+            let outcome = match result.outcome {
+                SelfTestOutcome::Passed => "pass",
+                SelfTestOutcome::Failed => "FAIL",
+                SelfTestOutcome::Skipped => "skip",
+            };
+            writeln!(f, "  {:<4} {:<8} {}", outcome, result.subsystem, result.detail)?;
+        }
+        Ok(())
+    }
+}
+
+/// The sequence run by `MultiHarpDevice::self_test`, for a setup other
+/// than the default (no inputs connected, no loopback).
+///
+/// ## Example
+///
+/// ```
+/// use multi_harp_patina::*;
+///
+/// let mut mh = DebugMultiHarp150::default();
+/// // The debug device always sees photons, so don't expect dark inputs
+/// let report = SelfTest::new().with_dark_rate_limit(None).run(&mut mh);
+/// println!("{}", report);
+/// assert!(report.passed());
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelfTest {
+    dark_rate_limit : Option<f64>,
+    loopback_channel : Option<i32>,
+}
+
+impl Default for SelfTest {
+    fn default() -> Self {
+        SelfTest { dark_rate_limit : Some(DEFAULT_DARK_RATE_LIMIT), loopback_channel : None }
+    }
+}
+
+impl SelfTest {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fails the rates check if an input counts more than `limit` per
+    /// second. `None` only checks that the rates can be read, for inputs
+    /// left connected.
+    pub fn with_dark_rate_limit(mut self, limit : Option<f64>) -> Self {
+        self.dark_rate_limit = limit;
+        self
+    }
+
+    /// Checks the trigger output wired into input `channel`: it should
+    /// count the trigger rate while the output runs.
+    pub fn with_trigger_loopback(mut self, channel : i32) -> Self {
+        self.loopback_channel = Some(channel);
+        self
+    }
+
+    /// Runs every part on `multiharp`, leaving it initialized in T3 mode
+    /// with the internal clock. A failed `init` skips the rest.
+    pub fn run<MH : MultiHarpDevice>(&self, multiharp : &mut MH) -> SelfTestReport {
+        let serial = multiharp.get_serial();
+        let mut results = Vec::new();
+        let mut record = |subsystem, result : Result<Option<String>, String>| {
+            let (outcome, detail) = match result {
+                Ok(Some(detail)) => (SelfTestOutcome::Passed, detail),
+                Ok(None) => (SelfTestOutcome::Skipped, "not configured".to_string()),
+                Err(detail) => {
+                    events::record(&serial, DeviceEventKind::Error, format!("Self-test of {} failed: {}", subsystem, detail));
+                    (SelfTestOutcome::Failed, detail)
+                },
+            };
+            results.push(SelfTestResult { subsystem, outcome, detail });
+        };
+
+        let initialized = multiharp.init(MeasurementMode::T3, ReferenceClock::Internal)
+            .map(|_| Some("T3 mode, internal clock".to_string()))
+            .map_err(|e| format!("`init` failed: {}", e));
+        let init_failed = initialized.is_err();
+        record("init", initialized);
+        if init_failed {
+            for subsystem in ["rates", "loopback", "flags", "t3_read"] {
+                results.push(SelfTestResult {
+                    subsystem,
+                    outcome : SelfTestOutcome::Skipped,
+                    detail : "`init` failed".to_string(),
+                });
+            }
+            return SelfTestReport { serial, results };
+        }
+
+        record("rates", self.check_rates(multiharp).map(Some));
+        record("loopback", self.check_loopback(multiharp));
+        record("flags", check_flags(multiharp).map(Some));
+        record("t3_read", check_t3_read(multiharp).map(Some));
+        SelfTestReport { serial, results }
+    }
+
+    fn check_rates<MH : MultiHarpDevice>(&self, multiharp : &MH) -> Result<String, String> {
+        let rates = measure_rates(multiharp, 2 * RATE_GATE).map_err(|e| format!("Reading the rates failed: {}", e))?;
+        let detail = format!("sync {:.0} Hz, inputs {:?} cps", rates.sync, rates.inputs);
+        let limit = match self.dark_rate_limit {
+            Some(limit) => limit,
+            None => return Ok(detail),
+        };
+        let over = rates.inputs.iter().enumerate()
+            .filter(|(_, &rate)| rate > limit)
+            .map(|(channel, _)| channel.to_string())
+            .collect::<Vec<_>>();
+        if over.is_empty() {
+            Ok(detail)
+        } else {
+            Err(format!("{}; channels {} count more than {} cps with no input", detail, over.join(", "), limit))
+        }
+    }
+
+    fn check_loopback<MH : MultiHarpDevice>(&self, multiharp : &mut MH) -> Result<Option<String>, String> {
+        let channel = match self.loopback_channel {
+            Some(channel) => channel,
+            None => return Ok(None),
+        };
+        let previous = multiharp.current_settings().trigger_output.unwrap_or(0);
+        multiharp.set_trigger_output(LOOPBACK_PERIOD)
+            .map_err(|e| format!("`set_trigger_output` failed: {}", e))?;
+        // Give the counters a full gate with the output running
+        std::thread::sleep(RATE_GATE);
+        let rates = measure_rates(multiharp, 2 * RATE_GATE);
+        let _ = multiharp.set_trigger_output(previous);
+
+        let rates = rates.map_err(|e| format!("Reading the rates failed: {}", e))?;
+        let counted = *rates.inputs.get(channel as usize)
+            .ok_or_else(|| format!("No input channel {}", channel))?;
+        let expected = 1e7 / LOOPBACK_PERIOD as f64;
+        let detail = format!("channel {} counted {:.0} cps of the {:.0} Hz trigger output", channel, counted, expected);
+        if (counted - expected).abs() <= LOOPBACK_TOLERANCE * expected {
+            Ok(Some(detail))
+        } else {
+            Err(detail)
+        }
+    }
+}
+
+fn check_flags<MH : MultiHarpDevice>(multiharp : &MH) -> Result<String, String> {
+    let flags = multiharp.get_flags().map_err(|e| format!("`get_flags` failed: {}", e))?;
+    let warnings = Warnings::from(multiharp.get_warnings().map_err(|e| format!("`get_warnings` failed: {}", e))?);
+    let warning_names = warnings.iter().map(|(_, name)| name).collect::<Vec<_>>();
+    let detail = format!("flags 0x{:04x}, warnings [{}]", flags, warning_names.join(", "));
+
+    let raised = FAILING_FLAGS.iter()
+        .filter(|(flag, _)| flags & *flag as i32 != 0)
+        .map(|(_, name)| *name)
+        .collect::<Vec<_>>();
+    if raised.is_empty() {
+        Ok(detail)
+    } else {
+        Err(format!("{}; {} raised", detail, raised.join(", ")))
+    }
+}
+
+fn check_t3_read<MH : MultiHarpDevice>(multiharp : &mut MH) -> Result<String, String> {
+    let enabled = multiharp.get_input_channel_enables().map_err(|e| e.to_string())?;
+    let mut decoder = Decoder::new(MeasurementMode::T3)?
+        .with_overflow_compression(multiharp.overflow_compression())
+        .with_enabled_channels(&enabled);
+    multiharp.start_measurement(T3_READ_TIME).map_err(|e| format!("`start_measurement` failed: {}", e))?;
+
+    let mut buffer = vec![0u32; mhconsts::TTREADMAX];
+    let mut records = 0;
+    let mut photons = 0;
+    let started = Instant::now();
+    let read = (|| {
+        loop {
+            let running = multiharp.ctc_status().map_err(|e| format!("`ctc_status` failed: {}", e))?;
+            let n = multiharp.read_fifo(&mut buffer).map_err(|e| format!("`read_fifo` failed: {}", e))? as usize;
+            records += n;
+            photons += decoder.decode_all(&buffer[..n]).len();
+            if !running && n == 0 {
+                return Ok(());
+            }
+            if started.elapsed() > T3_READ_TIMEOUT {
+                return Err(format!("The measurement didn't end within {:?}", T3_READ_TIMEOUT));
+            }
+        }
+    })();
+    let stopped = multiharp.stop_measurement().map_err(|e| format!("`stop_measurement` failed: {}", e));
+    read.and(stopped)?;
+
+    let detail = format!("{} records, {} events in {} ms", records, photons, T3_READ_TIME);
+    if decoder.anomalies() > 0 {
+        return Err(format!("{}; {} records on disabled channels", detail, decoder.anomalies()));
+    }
+    Ok(detail)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DebugMultiHarp150;
+
+    #[test]
+    fn test_self_test() {
+        let mut mh = DebugMultiHarp150::default();
+        let report = mh.self_test();
+        let subsystems = report.results.iter().map(|r| r.subsystem).collect::<Vec<_>>();
+        assert_eq!(subsystems, ["init", "rates", "loopback", "flags", "t3_read"]);
+
+        // The simulated photons aren't dark counts, and the simulated
+        // inputs don't see the trigger output
+        assert!(!report.passed());
+        assert_eq!(report.failures().map(|r| r.subsystem).collect::<Vec<_>>(), ["rates"]);
+        assert_eq!(report.get("loopback").unwrap().outcome, SelfTestOutcome::Skipped);
+        assert!(report.get("t3_read").unwrap().detail.contains("records"));
+        assert!(report.to_string().contains("FAIL rates"));
+        assert!(mh.recent_events().iter().any(|e| e.message.starts_with("Self-test of rates failed")));
+
+        let report = SelfTest::new().with_dark_rate_limit(None).with_trigger_loopback(1).run(&mut mh);
+        assert_eq!(report.failures().map(|r| r.subsystem).collect::<Vec<_>>(), ["loopback"]);
+        assert_eq!(mh.get_trigger_output(), 0);
+    }
+}
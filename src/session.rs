@@ -0,0 +1,220 @@
+//! Records the setter calls made on a device during a session, in order,
+//! so the same instrument state can be reproduced later with `replay`,
+//! on the same device or another of the same model.
+//!
+//! Each command is a `MultiHarpConfig` holding the one setting changed,
+//! and a session saves as those commands' lines of the settings file
+//! format (see `MultiHarpConfig::to_settings_string`), one per command in
+//! the order they were made. Unlike a settings file, the same setting
+//! can appear more than once.
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use crate::audit::{self, SettingApplied};
+use crate::error::PatinaError;
+use crate::multiharp::MultiHarpDevice;
+use crate::transaction;
+use crate::MultiHarpConfig;
+
+/// Records the settings applied to one device (by serial number) from
+/// `start` until it is dropped, including settings from other threads.
+///
+/// ## Example
+///
+/// ```
+/// use multi_harp_patina::*;
+///
+/// let mut mh = DebugMultiHarp150::default();
+/// let session = SessionRecorder::start(&mh.get_serial());
+/// mh.set_sync_div(2).unwrap();
+/// mh.set_input_channel_offset(1, 2500).unwrap();
+/// mh.set_sync_div(4).unwrap();
+///
+/// let saved = to_session_string(&session.commands().unwrap());
+/// assert_eq!(saved, "sync_div = 2\ninput_offsets = 1 2500\nsync_div = 4\n");
+///
+/// let mut other = DebugMultiHarp150::default();
+/// replay(&parse_session(&saved).unwrap(), &mut other).unwrap();
+/// assert_eq!(other.current_settings().sync_div, Some(4));
+/// ```
+pub struct SessionRecorder {
+    id : u64,
+    applied : Arc<Mutex<Vec<SettingApplied>>>,
+}
+
+impl SessionRecorder {
+    /// Starts recording the settings applied to the device `serial`.
+    pub fn start(serial : &str) -> Self {
+        let applied = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&applied);
+        let serial = serial.to_string();
+        let id = audit::add_recorder(Box::new(move |setting : &SettingApplied| {
+            if setting.serial == serial {
+                log.lock().unwrap_or_else(|e| e.into_inner()).push(setting.clone());
+            }
+        }));
+        SessionRecorder { id, applied }
+    }
+
+    /// The settings applied so far, as the settings log reports them
+    pub fn settings(&self) -> Vec<SettingApplied> {
+        self.applied.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// The settings applied so far as commands for `replay`.
+    ///
+    /// ## Errors
+    ///
+    /// * If a setting has no equivalent in `MultiHarpConfig` for the
+    ///   features this crate was built with, e.g. `overflow_compression`
+    ///   without `MHLv3_1_0`.
+    pub fn commands(&self) -> Result<Vec<MultiHarpConfig>, String> {
+        self.applied.lock().unwrap_or_else(|e| e.into_inner())
+            .iter().map(command).collect()
+    }
+}
+
+impl Drop for SessionRecorder {
+    fn drop(&mut self) {
+        audit::remove_recorder(self.id);
+    }
+}
+
+/// Converts a setting from the settings log to a line of the settings
+/// file format. Values are logged with their `Debug` formatting, so
+/// e.g. `(-100, Some(Falling))` becomes the fields `-100 Falling`.
+fn command(setting : &SettingApplied) -> Result<MultiHarpConfig, String> {
+    let (name, channel) = match setting.setting.split_once('[') {
+        Some((name, channel)) => (name, Some(channel.trim_end_matches(']'))),
+        None => (setting.setting.as_str(), None),
+    };
+    let key = match name {
+        "sync_edge_trigger" => "sync_trigger_edge",
+        "input_edge_trigger" => "input_edges",
+        "input_channel_offset" => "input_offsets",
+        "input_channel_enable" => "input_enables",
+        "input_dead_time" => "input_dead_times",
+        "histogram_len" => "histo_len",
+        "measurement_control_mode" => "meas_control",
+        "marker_holdoff_time" => "marker_holdoff",
+        "overflow_compression" => "ofl_compression",
+        other => other,
+    };
+    let fields = setting.new
+        .split(|c : char| "()[],".contains(c) || c.is_whitespace())
+        .filter(|f| !f.is_empty() && *f != "Some")
+        .map(|f| if f == "None" { "-" } else { f });
+    let value = channel.into_iter().chain(fields).collect::<Vec<_>>().join(" ");
+
+    parse_command(&format!("{} = {}", key, value))
+        .map_err(|e| format!("{}: {}", setting.setting, e))
+}
+
+/// Parses one line of the settings file format, which must set exactly one setting.
+fn parse_command(line : &str) -> Result<MultiHarpConfig, String> {
+    let config = MultiHarpConfig::from_settings_string(line)?;
+    if config == MultiHarpConfig::default() {
+        return Err(format!("`{}` is not a setting of this build", line.trim()));
+    }
+    Ok(config)
+}
+
+/// Writes `commands` in the settings file format, one line each.
+pub fn to_session_string(commands : &[MultiHarpConfig]) -> String {
+    commands.iter().map(|c| c.to_settings_string()).collect()
+}
+
+/// Parses the format written by `to_session_string`. Blank lines and
+/// lines starting with `#` are ignored, but unlike a settings file
+/// unknown keys are an error, so a replay never silently skips a step.
+pub fn parse_session(s : &str) -> Result<Vec<MultiHarpConfig>, String> {
+    s.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.trim().starts_with('#'))
+        .map(|(line_number, line)| parse_command(line)
+            .map_err(|e| format!("line {}: {}", line_number + 1, e)))
+        .collect()
+}
+
+/// Why `replay` stopped.
+#[derive(Debug)]
+pub struct ReplayError {
+    /// Position of the failing command in the list replayed
+    pub index : usize,
+    /// The setting that failed, named as in the settings log
+    pub setting : String,
+    pub error : PatinaError<String>,
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Command {} ({}) failed: {}", self.index, self.setting, self.error)
+    }
+}
+
+impl std::error::Error for ReplayError {}
+
+/// Applies `commands` to `multiharp` in order, as recorded by a
+/// `SessionRecorder`, stopping at the first that fails. Settings applied
+/// before the failure stay applied.
+pub fn replay<M : MultiHarpDevice>(commands : &[MultiHarpConfig], multiharp : &mut M) -> Result<(), ReplayError> {
+    for (index, command) in commands.iter().enumerate() {
+        let mut failure = None;
+        transaction::apply_config(multiharp, command, |setting, error| {
+            failure = Some(ReplayError { index, setting, error });
+            false
+        });
+        if let Some(failure) = failure {
+            return Err(failure);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DebugMultiHarp150, MeasurementControlMode, TriggerEdge};
+
+    #[test]
+    fn test_record_and_replay() {
+        // A serial of its own, as other tests set up debug devices in parallel
+        let mut mh = DebugMultiHarp150::open_by_serial("5550001").unwrap();
+        let session = SessionRecorder::start("5550001");
+        mh.set_sync_edge_trigger(-120, TriggerEdge::Falling).unwrap();
+        mh.set_input_edge_trigger(2, -80, TriggerEdge::Rising).unwrap();
+        mh.set_input_channel_enable(3, false).unwrap();
+        mh.set_input_dead_time(1, true, 800).unwrap();
+        mh.set_measurement_control_mode(MeasurementControlMode::C1Gated, Some(TriggerEdge::Rising), None).unwrap();
+        mh.set_histogram_len(3).unwrap();
+        assert!(mh.set_sync_div(0).is_err());
+        mh.set_sync_div(8).unwrap();
+        DebugMultiHarp150::default().set_sync_div(2).unwrap();
+
+        let commands = session.commands().unwrap();
+        assert_eq!(commands.len(), 7);
+        let saved = to_session_string(&commands);
+        assert_eq!(saved.lines().collect::<Vec<_>>(), vec![
+            "sync_trigger_edge = -120 Falling",
+            "input_edges = 2 -80 Rising",
+            "input_enables = 3 false",
+            "input_dead_times = 1 true 800",
+            "meas_control = C1Gated Rising -",
+            "histo_len = 3",
+            "sync_div = 8",
+        ]);
+
+        drop(session);
+        let mut other = DebugMultiHarp150::default();
+        replay(&parse_session(&saved).unwrap(), &mut other).unwrap();
+        let (replayed, original) = (other.current_settings(), mh.current_settings());
+        assert_eq!(replayed, original);
+
+        assert!(parse_session("sync_div = 2\nsync_dev = 3\n").unwrap_err().starts_with("line 2:"));
+        let failing = parse_session("binning = 1\nsync_div = 99\nbinning = 2\n").unwrap();
+        let error = replay(&failing, &mut other).unwrap_err();
+        assert_eq!((error.index, error.setting.as_str()), (1, "sync_div"));
+        assert_eq!(other.current_settings().binning, Some(1));
+    }
+}
@@ -0,0 +1,692 @@
+//! Remembers the last configuration applied to each MultiHarp,
+//! keyed by serial number, so that detectors don't need to be
+//! re-tuned every time the acquisition software restarts.
+//!
+//! Settings are stored as one plain text file per device, with
+//! one `key = value` line per field of `MultiHarpConfig` that is
+//! not `None`. Keys this build does not know about (e.g. fields
+//! only available with a newer `MHLib` feature) are ignored.
+//!
+//! With the `serde` feature, configurations can also be kept in TOML
+//! or JSON files, e.g. instrument presets under version control (see
+//! `MultiHarpConfig::from_file`).
+
+use std::ffi::OsStr;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::MultiHarpConfig;
+use crate::mhconsts::TriggerEdge;
+use crate::MultiHarpDevice;
+use crate::error::CheckedResult;
+
+/// A directory of cached `MultiHarpConfig`s, one per serial number.
+///
+/// ## Example
+///
+/// ```no_run
+/// use multi_harp_patina::*;
+///
+/// let cache = SettingsCache::new("/tmp/multiharp_settings");
+/// let mut mh = DebugMultiHarp150::default();
+///
+/// // Restore the detector settings from the last session, if any
+/// let restored = mh.apply_cached_settings(&cache).unwrap();
+/// println!("Restored settings: {}", restored);
+///
+/// // Tweak a trigger level and remember it for next time
+/// cache.update(&mh.get_serial(), |config| {
+///     config.sync_trigger_edge = Some((-70, TriggerEdge::Falling));
+/// }).unwrap();
+/// ```
+pub struct SettingsCache {
+    dir : PathBuf,
+}
+
+impl SettingsCache {
+    /// Creates a cache stored in `dir`. The directory is created
+    /// the first time settings are stored.
+    pub fn new<P : AsRef<Path>>(dir : P) -> Self {
+        SettingsCache { dir : dir.as_ref().to_path_buf() }
+    }
+
+    /// A per-user cache in `%APPDATA%\multi_harp_patina` on Windows or
+    /// `$HOME/.multi_harp_patina` elsewhere. Returns `None` if neither
+    /// environment variable is set.
+    pub fn default_location() -> Option<Self> {
+        #[cfg(windows)]
+        let base = std::env::var_os("APPDATA").map(|p| PathBuf::from(p).join("multi_harp_patina"));
+        #[cfg(not(windows))]
+        let base = std::env::var_os("HOME").map(|p| PathBuf::from(p).join(".multi_harp_patina"));
+        base.map(SettingsCache::new)
+    }
+
+    /// The directory the settings are stored in
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The file used to store settings for `serial`. Leading zeros are
+    /// trimmed, as in `open_by_serial`, so '01044272' and '1044272'
+    /// share an entry.
+    pub fn path_for(&self, serial : &str) -> PathBuf {
+        self.dir.join(format!("{}.cfg", serial.trim_start_matches('0')))
+    }
+
+    /// Stores `config` as the most recent settings for `serial`, replacing
+    /// any previous entry.
+    pub fn store(&self, serial : &str, config : &MultiHarpConfig) -> io::Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        let path = self.path_for(serial);
+        // Write then rename so a crash mid-write never leaves a truncated file
+        let tmp = path.with_extension("cfg.tmp");
+        std::fs::write(&tmp, config.to_settings_string())?;
+        std::fs::rename(&tmp, &path)
+    }
+
+    /// Loads the settings stored for `serial`, or `None` if there are none.
+    pub fn load(&self, serial : &str) -> io::Result<Option<MultiHarpConfig>> {
+        let path = self.path_for(serial);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let contents = std::fs::read_to_string(&path)?;
+        MultiHarpConfig::from_settings_string(&contents)
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))
+    }
+
+    /// Loads the settings for `serial` (or an empty config), lets `f` modify them,
+    /// and stores the result. Used to remember individual changes, e.g. a trigger
+    /// level tuned by hand, without rebuilding the whole config.
+    pub fn update<F : FnOnce(&mut MultiHarpConfig)>(&self, serial : &str, f : F) -> io::Result<()> {
+        let mut config = self.load(serial)?.unwrap_or_default();
+        f(&mut config);
+        self.store(serial, &config)
+    }
+
+    /// Forgets the settings stored for `serial`.
+    pub fn remove(&self, serial : &str) -> io::Result<()> {
+        let path = self.path_for(serial);
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Environment variable holding the serial number of the device to open
+pub const ENV_DEVICE_SERIAL : &str = "MHP_DEVICE_SERIAL";
+/// Environment variable holding the path of a settings file to apply
+pub const ENV_CONFIG_PATH : &str = "MHP_CONFIG_PATH";
+/// Environment variable holding the directory to write acquired data to
+pub const ENV_OUTPUT_DIR : &str = "MHP_OUTPUT_DIR";
+/// Environment variable holding the log level (`error`, `warn`, `info`, `debug`, `trace`)
+pub const ENV_LOG_LEVEL : &str = "MHP_LOG_LEVEL";
+
+/// Verbosity of the binaries and of `Settings` consumers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl std::str::FromStr for LogLevel {
+    type Err = String;
+    fn from_str(s : &str) -> Result<Self, String> {
+        match s.trim().to_lowercase().as_str() {
+            "error" => Ok(LogLevel::Error),
+            "warn" | "warning" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            "trace" => Ok(LogLevel::Trace),
+            _ => Err(format!("Invalid log level `{}`", s)),
+        }
+    }
+}
+
+/// Acquisition defaults for a deployment, read from `MHP_*` environment
+/// variables so that shared machines can be configured without editing
+/// files. Unset or empty variables are left as `None`.
+///
+/// * `MHP_DEVICE_SERIAL` - serial number of the device to open
+/// * `MHP_CONFIG_PATH` - a settings file, in the format of `SettingsCache`
+/// * `MHP_OUTPUT_DIR` - directory for acquired data
+/// * `MHP_LOG_LEVEL` - one of `error`, `warn`, `info`, `debug`, `trace`
+///
+/// ## Example
+///
+/// ```no_run
+/// use multi_harp_patina::*;
+///
+/// let settings = Settings::from_env().unwrap();
+/// let mut mh = settings.open_device::<DebugMultiHarp150>().unwrap();
+/// if let Some(config) = settings.load_config().unwrap() {
+///     mh.set_from_config(&config);
+/// }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Settings {
+    pub device_serial : Option<String>,
+    pub config_path : Option<PathBuf>,
+    pub output_dir : Option<PathBuf>,
+    pub log_level : Option<LogLevel>,
+}
+
+impl Settings {
+    /// Reads the `MHP_*` variables from the process environment.
+    ///
+    /// ## Errors
+    ///
+    /// * If `MHP_LOG_LEVEL` is set to an unrecognized level
+    /// * If one of the `MHP_*` variables is not valid Unicode
+    pub fn from_env() -> Result<Self, String> {
+        Self::from_vars_os(std::env::vars_os())
+    }
+
+    /// As `from_env`, but reading from the provided `(name, value)` pairs,
+    /// which like the process environment need not be Unicode. Only the
+    /// values of the `MHP_*` variables must be.
+    pub fn from_vars_os<I, K, V>(vars : I) -> Result<Self, String>
+    where I : IntoIterator<Item = (K, V)>, K : AsRef<OsStr>, V : AsRef<OsStr> {
+        let keys = [ENV_DEVICE_SERIAL, ENV_CONFIG_PATH, ENV_OUTPUT_DIR, ENV_LOG_LEVEL];
+        let mut found = Vec::new();
+        for (key, value) in vars {
+            let Some(key) = keys.iter().find(|k| key.as_ref() == OsStr::new(k)) else { continue };
+            let value = value.as_ref().to_str()
+                .ok_or_else(|| format!("{}: {:?} is not valid Unicode", key, value.as_ref()))?;
+            found.push((*key, value.to_string()));
+        }
+        Self::from_vars(found)
+    }
+
+    /// As `from_env`, but reading from the provided `(name, value)` pairs.
+    pub fn from_vars<I, K, V>(vars : I) -> Result<Self, String>
+    where I : IntoIterator<Item = (K, V)>, K : AsRef<str>, V : AsRef<str> {
+        let mut settings = Settings::default();
+        for (key, value) in vars {
+            let value = value.as_ref().trim();
+            if value.is_empty() { continue; }
+            match key.as_ref() {
+                ENV_DEVICE_SERIAL => settings.device_serial = Some(value.to_string()),
+                ENV_CONFIG_PATH => settings.config_path = Some(PathBuf::from(value)),
+                ENV_OUTPUT_DIR => settings.output_dir = Some(PathBuf::from(value)),
+                ENV_LOG_LEVEL => settings.log_level = Some(
+                    value.parse().map_err(|e| format!("{}: {}", ENV_LOG_LEVEL, e))?
+                ),
+                _ => {}
+            }
+        }
+        Ok(settings)
+    }
+
+    /// Opens the device with serial `device_serial`, or the first `MH`
+    /// that can be opened if none is set (for a `DebugMultiHarp150`, one
+    /// is always available).
+    pub fn open_device<MH : MultiHarpDevice>(&self) -> CheckedResult<MH, i32> {
+        match &self.device_serial {
+            Some(serial) => MH::open_by_serial(serial),
+            None => MH::open(None),
+        }
+    }
+
+    /// Reads the settings file at `config_path` (see
+    /// `MultiHarpConfig::from_file`), or `None` if it is unset.
+    pub fn load_config(&self) -> io::Result<Option<MultiHarpConfig>> {
+        match &self.config_path {
+            Some(path) => MultiHarpConfig::from_file(path).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Where to write the output file `file_name`: in `output_dir`, or
+    /// `None` if it is unset.
+    pub fn output_path(&self, file_name : &str) -> Option<PathBuf> {
+        self.output_dir.as_ref().map(|dir| dir.join(file_name))
+    }
+
+    /// The log level, defaulting to `LogLevel::Info`.
+    pub fn log_level(&self) -> LogLevel {
+        self.log_level.unwrap_or(LogLevel::Info)
+    }
+}
+
+/// The lowercase extension of a configuration file
+fn config_extension(path : &Path) -> Option<String> {
+    path.extension().and_then(|e| e.to_str()).map(|e| e.to_ascii_lowercase())
+}
+
+fn optional_edge_to_str(edge : Option<TriggerEdge>) -> String {
+    edge.map(|e| e.to_string()).unwrap_or_else(|| "-".to_string())
+}
+
+fn parse_optional_edge(s : &str) -> Result<Option<TriggerEdge>, String> {
+    if s == "-" { Ok(None) } else { s.parse().map(Some) }
+}
+
+fn parse_num<T : std::str::FromStr>(s : &str) -> Result<T, String> {
+    s.parse::<T>().map_err(|_| format!("Invalid number `{}`", s))
+}
+
+fn parse_bool(s : &str) -> Result<bool, String> {
+    s.parse::<bool>().map_err(|_| format!("Invalid boolean `{}`", s))
+}
+
+/// Splits a value of whitespace-separated fields, checking the count
+fn fields(value : &str, n : usize) -> Result<Vec<&str>, String> {
+    let f = value.split_whitespace().collect::<Vec<_>>();
+    if f.len() != n {
+        return Err(format!("Expected {} fields, found `{}`", n, value));
+    }
+    Ok(f)
+}
+
+/// Splits a value of comma-separated entries, each with `n` fields
+fn entries(value : &str, n : usize) -> Result<Vec<Vec<&str>>, String> {
+    value.split(',')
+        .map(|e| e.trim())
+        .filter(|e| !e.is_empty())
+        .map(|e| fields(e, n))
+        .collect()
+}
+
+/// `MultiHarpConfig::meas_control` as a table, since TOML has no null to
+/// leave an edge out of an array
+#[cfg(feature = "serde")]
+pub(crate) mod meas_control_serde {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use crate::mhconsts::{MeasurementControlMode, TriggerEdge};
+
+    type Value = Option<(MeasurementControlMode, Option<TriggerEdge>, Option<TriggerEdge>)>;
+
+    #[derive(Serialize, Deserialize)]
+    struct MeasControl {
+        mode : MeasurementControlMode,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        start_edge : Option<TriggerEdge>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        stop_edge : Option<TriggerEdge>,
+    }
+
+    pub fn serialize<S : Serializer>(value : &Value, serializer : S) -> Result<S::Ok, S::Error> {
+        value.map(|(mode, start_edge, stop_edge)| MeasControl { mode, start_edge, stop_edge }).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D : Deserializer<'de>>(deserializer : D) -> Result<Value, D::Error> {
+        Ok(Option::<MeasControl>::deserialize(deserializer)?.map(|m| (m.mode, m.start_edge, m.stop_edge)))
+    }
+}
+
+impl MultiHarpConfig {
+    /// Reads a configuration file, by its extension: TOML (`.toml`) or
+    /// JSON (`.json`) with the `serde` feature, and otherwise the format
+    /// of `to_settings_string`. Fields this build doesn't know about are
+    /// ignored.
+    ///
+    /// ## Example
+    ///
+    /// ```no_run
+    /// use multi_harp_patina::*;
+    ///
+    /// // e.g. `sync_div = 2` and `input_enables = [[0, true], [1, false]]`
+    /// let preset = MultiHarpConfig::from_file("presets/flim_80mhz.toml").unwrap();
+    /// let mut mh = DebugMultiHarp150::default();
+    /// mh.set_from_config(&preset);
+    /// ```
+    pub fn from_file<P : AsRef<Path>>(path : P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)?;
+        let invalid = |e : String| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e));
+        match config_extension(path).as_deref() {
+            #[cfg(feature = "serde")]
+            Some("toml") => toml::from_str(&contents).map_err(|e| invalid(e.to_string())),
+            #[cfg(feature = "serde")]
+            Some("json") => serde_json::from_str(&contents).map_err(|e| invalid(e.to_string())),
+            #[cfg(not(feature = "serde"))]
+            Some("toml") | Some("json") => Err(invalid("TOML and JSON files need the `serde` feature".to_string())),
+            _ => Self::from_settings_string(&contents).map_err(invalid),
+        }
+    }
+
+    /// Writes the configuration to a file in the format `from_file`
+    /// reads for its extension, replacing any existing file.
+    pub fn to_file<P : AsRef<Path>>(&self, path : P) -> io::Result<()> {
+        let path = path.as_ref();
+        let invalid = |e : String| io::Error::new(io::ErrorKind::InvalidInput, format!("{}: {}", path.display(), e));
+        let contents = match config_extension(path).as_deref() {
+            #[cfg(feature = "serde")]
+            Some("toml") => toml::to_string_pretty(self).map_err(|e| invalid(e.to_string()))?,
+            #[cfg(feature = "serde")]
+            Some("json") => serde_json::to_string_pretty(self).map_err(|e| invalid(e.to_string()))?,
+            #[cfg(not(feature = "serde"))]
+            Some("toml") | Some("json") => return Err(invalid("TOML and JSON files need the `serde` feature".to_string())),
+            _ => self.to_settings_string(),
+        };
+        std::fs::write(path, contents)
+    }
+
+    /// Serializes every field that is set as a `key = value` line, the
+    /// format used by `SettingsCache`.
+    pub fn to_settings_string(&self) -> String {
+        let mut lines = Vec::<String>::new();
+
+        if let Some(sync_div) = self.sync_div {
+            lines.push(format!("sync_div = {}", sync_div));
+        }
+        if let Some((level, edge)) = self.sync_trigger_edge {
+            lines.push(format!("sync_trigger_edge = {} {}", level, edge));
+        }
+        if let Some(offset) = self.sync_channel_offset {
+            lines.push(format!("sync_channel_offset = {}", offset));
+        }
+        #[cfg(feature = "MHLv3_1_0")]
+        if let Some(enable) = self.sync_channel_enable {
+            lines.push(format!("sync_channel_enable = {}", enable));
+        }
+        if let Some((on, deadtime)) = self.sync_dead_time {
+            lines.push(format!("sync_dead_time = {} {}", on, deadtime));
+        }
+
+        if let Some(input_edges) = &self.input_edges {
+            lines.push(format!("input_edges = {}", input_edges.iter()
+                .map(|(ch, level, edge)| format!("{} {} {}", ch, level, edge))
+                .collect::<Vec<_>>().join(", ")));
+        }
+        if let Some(input_offsets) = &self.input_offsets {
+            lines.push(format!("input_offsets = {}", input_offsets.iter()
+                .map(|(ch, offset)| format!("{} {}", ch, offset))
+                .collect::<Vec<_>>().join(", ")));
+        }
+        if let Some(input_enables) = &self.input_enables {
+            lines.push(format!("input_enables = {}", input_enables.iter()
+                .map(|(ch, enable)| format!("{} {}", ch, enable))
+                .collect::<Vec<_>>().join(", ")));
+        }
+        if let Some(input_dead_times) = &self.input_dead_times {
+            lines.push(format!("input_dead_times = {}", input_dead_times.iter()
+                .map(|(ch, on, deadtime)| format!("{} {} {}", ch, on, deadtime))
+                .collect::<Vec<_>>().join(", ")));
+        }
+        #[cfg(feature = "MHLv3_0_0")]
+        if let Some(hysteresis) = self.input_hysteresis {
+            lines.push(format!("input_hysteresis = {}", hysteresis));
+        }
+
+        if let Some((stop, count)) = self.stop_overflow {
+            lines.push(format!("stop_overflow = {} {}", stop, count));
+        }
+        if let Some(binning) = self.binning {
+            lines.push(format!("binning = {}", binning));
+        }
+        if let Some(offset) = self.offset {
+            lines.push(format!("offset = {}", offset));
+        }
+        if let Some(histo_len) = self.histo_len {
+            lines.push(format!("histo_len = {}", histo_len));
+        }
+
+        if let Some((mode, start, stop)) = self.meas_control {
+            lines.push(format!("meas_control = {} {} {}",
+                mode, optional_edge_to_str(start), optional_edge_to_str(stop)
+            ));
+        }
+        if let Some(period) = self.trigger_output {
+            lines.push(format!("trigger_output = {}", period));
+        }
+
+        #[cfg(feature = "MHLv3_1_0")]
+        if let Some(holdtime) = self.ofl_compression {
+            lines.push(format!("ofl_compression = {}", holdtime));
+        }
+
+        if let Some(edges) = self.marker_edges {
+            lines.push(format!("marker_edges = {}", edges.iter()
+                .map(|e| e.to_string()).collect::<Vec<_>>().join(" ")));
+        }
+        if let Some(enables) = self.marker_enable {
+            lines.push(format!("marker_enable = {}", enables.iter()
+                .map(|e| e.to_string()).collect::<Vec<_>>().join(" ")));
+        }
+        if let Some(holdoff) = self.marker_holdoff {
+            lines.push(format!("marker_holdoff = {}", holdoff));
+        }
+
+        let mut out = lines.join("\n");
+        out.push('\n');
+        out
+    }
+
+    /// Parses the format written by `to_settings_string`. Blank lines and
+    /// lines starting with `#` are ignored, as are unknown keys.
+    pub fn from_settings_string(s : &str) -> Result<Self, String> {
+        let mut config = MultiHarpConfig::default();
+
+        for (line_number, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = line.split_once('=')
+                .ok_or(format!("line {}: expected `key = value`", line_number + 1))?;
+            let (key, value) = (key.trim(), value.trim());
+
+            let parsed : Result<(), String> = (|| {
+                match key {
+                    "sync_div" => { config.sync_div = Some(parse_num(value)?); },
+                    "sync_trigger_edge" => {
+                        let f = fields(value, 2)?;
+                        config.sync_trigger_edge = Some((parse_num(f[0])?, f[1].parse()?));
+                    },
+                    "sync_channel_offset" => { config.sync_channel_offset = Some(parse_num(value)?); },
+                    #[cfg(feature = "MHLv3_1_0")]
+                    "sync_channel_enable" => { config.sync_channel_enable = Some(parse_bool(value)?); },
+                    "sync_dead_time" => {
+                        let f = fields(value, 2)?;
+                        config.sync_dead_time = Some((parse_bool(f[0])?, parse_num(f[1])?));
+                    },
+                    "input_edges" => {
+                        config.input_edges = Some(entries(value, 3)?.iter()
+                            .map(|f| Ok((parse_num(f[0])?, parse_num(f[1])?, f[2].parse()?)))
+                            .collect::<Result<Vec<_>, String>>()?);
+                    },
+                    "input_offsets" => {
+                        config.input_offsets = Some(entries(value, 2)?.iter()
+                            .map(|f| Ok((parse_num(f[0])?, parse_num(f[1])?)))
+                            .collect::<Result<Vec<_>, String>>()?);
+                    },
+                    "input_enables" => {
+                        config.input_enables = Some(entries(value, 2)?.iter()
+                            .map(|f| Ok((parse_num(f[0])?, parse_bool(f[1])?)))
+                            .collect::<Result<Vec<_>, String>>()?);
+                    },
+                    "input_dead_times" => {
+                        config.input_dead_times = Some(entries(value, 3)?.iter()
+                            .map(|f| Ok((parse_num(f[0])?, parse_bool(f[1])?, parse_num(f[2])?)))
+                            .collect::<Result<Vec<_>, String>>()?);
+                    },
+                    #[cfg(feature = "MHLv3_0_0")]
+                    "input_hysteresis" => { config.input_hysteresis = Some(parse_bool(value)?); },
+                    "stop_overflow" => {
+                        let f = fields(value, 2)?;
+                        config.stop_overflow = Some((parse_bool(f[0])?, parse_num(f[1])?));
+                    },
+                    "binning" => { config.binning = Some(parse_num(value)?); },
+                    "offset" => { config.offset = Some(parse_num(value)?); },
+                    "histo_len" => { config.histo_len = Some(parse_num(value)?); },
+                    "meas_control" => {
+                        let f = fields(value, 3)?;
+                        config.meas_control = Some((
+                            f[0].parse()?, parse_optional_edge(f[1])?, parse_optional_edge(f[2])?
+                        ));
+                    },
+                    "trigger_output" => { config.trigger_output = Some(parse_num(value)?); },
+                    #[cfg(feature = "MHLv3_1_0")]
+                    "ofl_compression" => { config.ofl_compression = Some(parse_num(value)?); },
+                    "marker_edges" => {
+                        let f = fields(value, 4)?;
+                        config.marker_edges = Some([
+                            f[0].parse()?, f[1].parse()?, f[2].parse()?, f[3].parse()?
+                        ]);
+                    },
+                    "marker_enable" => {
+                        let f = fields(value, 4)?;
+                        config.marker_enable = Some([
+                            parse_bool(f[0])?, parse_bool(f[1])?, parse_bool(f[2])?, parse_bool(f[3])?
+                        ]);
+                    },
+                    "marker_holdoff" => { config.marker_holdoff = Some(parse_num(value)?); },
+                    _ => {},
+                }
+                Ok(())
+            })();
+
+            parsed.map_err(|e| format!("line {}: {}", line_number + 1, e))?;
+        }
+
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SettingsCache, Settings, LogLevel};
+    use crate::{MultiHarpConfig, MultiHarpDevice, DebugMultiHarp150, TriggerEdge, MeasurementControlMode};
+
+    fn example_config() -> MultiHarpConfig {
+        MultiHarpConfig {
+            sync_div : Some(2),
+            sync_trigger_edge : Some((-80, TriggerEdge::Falling)),
+            input_edges : Some(vec![
+                (0, -100, TriggerEdge::Falling),
+                (1, -120, TriggerEdge::Rising),
+            ]),
+            input_enables : Some(vec![(0, true), (1, false)]),
+            stop_overflow : Some((true, 1000)),
+            meas_control : Some((MeasurementControlMode::C1Gated, Some(TriggerEdge::Rising), None)),
+            marker_edges : Some([TriggerEdge::Rising, TriggerEdge::Falling, TriggerEdge::Rising, TriggerEdge::Rising]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_settings_string_round_trip() {
+        let text = example_config().to_settings_string();
+        let parsed = MultiHarpConfig::from_settings_string(&text).unwrap();
+        assert_eq!(parsed.to_settings_string(), text);
+        assert_eq!(parsed.binning, None);
+        assert!(matches!(parsed.sync_trigger_edge, Some((-80, TriggerEdge::Falling))));
+
+        assert!(MultiHarpConfig::from_settings_string("sync_div = two").is_err());
+        assert!(MultiHarpConfig::from_settings_string("# comment\nunknown_key = 3\n").is_ok());
+    }
+
+    #[test]
+    fn test_config_files() {
+        let dir = std::env::temp_dir().join(format!("mhp_config_files_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = example_config();
+
+        config.to_file(dir.join("preset.txt")).unwrap();
+        assert_eq!(MultiHarpConfig::from_file(dir.join("preset.txt")).unwrap(), config);
+
+        #[cfg(feature = "serde")]
+        {
+            for name in ["preset.toml", "preset.json"] {
+                config.to_file(dir.join(name)).unwrap();
+                assert_eq!(MultiHarpConfig::from_file(dir.join(name)).unwrap(), config);
+            }
+            let toml = std::fs::read_to_string(dir.join("preset.toml")).unwrap();
+            assert!(toml.contains("mode = \"C1Gated\""));
+            assert!(!toml.contains("binning"));
+
+            // Unknown keys are ignored, as in settings files
+            std::fs::write(dir.join("written.toml"), "sync_div = 4\nunknown_key = 3\n\n[meas_control]\nmode = \"SingleShotCtc\"\n").unwrap();
+            let written = MultiHarpConfig::from_file(dir.join("written.toml")).unwrap();
+            assert_eq!(written.sync_div, Some(4));
+            assert_eq!(written.meas_control, Some((MeasurementControlMode::SingleShotCtc, None, None)));
+            std::fs::write(dir.join("invalid.json"), "{\"sync_div\" : \"two\"}").unwrap();
+            assert!(MultiHarpConfig::from_file(dir.join("invalid.json")).is_err());
+        }
+        #[cfg(not(feature = "serde"))]
+        assert!(config.to_file(dir.join("preset.toml")).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_cache_store_and_apply() {
+        let dir = std::env::temp_dir().join(format!("mhp_settings_test_{}", std::process::id()));
+        let cache = SettingsCache::new(&dir);
+        let mut mh = DebugMultiHarp150::default();
+
+        assert!(!mh.apply_cached_settings(&cache).unwrap());
+
+        cache.store(&format!("0{}", mh.get_serial()), &example_config()).unwrap();
+        cache.update(&mh.get_serial(), |config| config.sync_div = Some(4)).unwrap();
+
+        let loaded = cache.load(&mh.get_serial()).unwrap().unwrap();
+        assert_eq!(loaded.sync_div, Some(4));
+        assert!(matches!(loaded.sync_trigger_edge, Some((-80, TriggerEdge::Falling))));
+        assert!(mh.apply_cached_settings(&cache).unwrap());
+
+        cache.remove(&mh.get_serial()).unwrap();
+        assert!(cache.load(&mh.get_serial()).unwrap().is_none());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_settings_from_vars() {
+        let settings = Settings::from_vars(vec![
+            ("MHP_DEVICE_SERIAL", "01044272"),
+            ("MHP_OUTPUT_DIR", "/data/flim"),
+            ("MHP_LOG_LEVEL", "Debug"),
+            ("MHP_CONFIG_PATH", ""),
+            ("PATH", "/usr/bin"),
+        ]).unwrap();
+        assert_eq!(settings.device_serial.as_deref(), Some("01044272"));
+        assert_eq!(settings.output_dir, Some("/data/flim".into()));
+        assert_eq!(settings.config_path, None);
+        assert_eq!(settings.log_level(), LogLevel::Debug);
+        assert!(LogLevel::Debug > LogLevel::Info);
+        assert_eq!(settings.output_path("tttr.bin"), Some("/data/flim/tttr.bin".into()));
+        assert_eq!(Settings::default().output_path("tttr.bin"), None);
+
+        assert!(Settings::from_vars(vec![("MHP_LOG_LEVEL", "loud")]).is_err());
+        assert_eq!(Settings::from_vars(Vec::<(String, String)>::new()).unwrap().log_level(), LogLevel::Info);
+    }
+
+    #[test]
+    fn test_settings_load_config() {
+        let dir = std::env::temp_dir().join(format!("mhp_env_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("rig.cfg");
+        std::fs::write(&path, example_config().to_settings_string()).unwrap();
+
+        let settings = Settings { config_path : Some(path), ..Default::default() };
+        let config = settings.load_config().unwrap().unwrap();
+        assert_eq!(config.sync_div, Some(2));
+        assert!(Settings::default().load_config().unwrap().is_none());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_settings_from_vars_os() {
+        use std::ffi::OsString;
+        use std::os::unix::ffi::OsStringExt;
+
+        let not_unicode = OsString::from_vec(b"/data/\xff".to_vec());
+        let settings = Settings::from_vars_os(vec![
+            (OsString::from("MHP_DEVICE_SERIAL"), OsString::from("01044272")),
+            (OsString::from("OTHER"), not_unicode.clone()),
+        ]).unwrap();
+        assert_eq!(settings.device_serial.as_deref(), Some("01044272"));
+
+        let error = Settings::from_vars_os(vec![(OsString::from("MHP_OUTPUT_DIR"), not_unicode)]).unwrap_err();
+        assert!(error.starts_with("MHP_OUTPUT_DIR"));
+    }
+}
@@ -0,0 +1,461 @@
+//! A raw spool of FIFO records on disk, written as the acquisition
+//! runs so that nothing is lost if the program dies, and read back
+//! later for analysis or conversion.
+//!
+//! A spool holds one or more segments, each an acquisition with its own
+//! header. An interrupted run can be resumed with `SpoolWriter::append`,
+//! which continues the segment numbering in the same file.
+//!
+//! ## Format
+//!
+//! All integers are little endian.
+//!
+//! * File header: the magic bytes `MHPSPOOL`, then the format version (`u32`)
+//! * A sequence of blocks, each starting with a `u32` tag:
+//!   * `SEGMENT_TAG` -- segment number (`u32`), mode (`u8`), resolution in ps
+//!     (`f64`), start time in ms since the Unix epoch (`u64`), serial number
+//!     and labels JSON (each a `u32` length followed by UTF-8 bytes)
+//!   * `RECORDS_TAG` -- record count (`u32`) followed by the raw records
+//!   * `TIMED_RECORDS_TAG` -- as `RECORDS_TAG`, for a writer made
+//!     `with_timestamps`, but preceded by the batch's sequence number
+//!     (`u64`) and the host's monotonic time of the read in ns since the
+//!     segment began (`u64`)
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::mhconsts::MeasurementMode;
+use crate::pipeline::{Batch, ProcessorResult, StreamInfo, StreamProcessor};
+
+pub const SPOOL_MAGIC : &[u8; 8] = b"MHPSPOOL";
+pub const SPOOL_VERSION : u32 = 1;
+pub const SEGMENT_TAG : u32 = 0x5345_4731; // "SEG1"
+pub const RECORDS_TAG : u32 = 0x5245_4331; // "REC1"
+pub const TIMED_RECORDS_TAG : u32 = 0x5245_4354; // "RECT"
+
+/// Largest record block accepted when reading, so a corrupted length
+/// can't trigger an enormous allocation.
+pub const MAX_BLOCK_RECORDS : u32 = 1 << 24;
+/// Largest serial number or labels string accepted when reading
+const MAX_STRING_LEN : u32 = 1 << 20;
+
+/// The header starting each segment of a spool
+#[derive(Debug, Clone, PartialEq)]
+pub struct SegmentHeader {
+    /// Numbered consecutively from 0 within a spool
+    pub number : u32,
+    pub mode : MeasurementMode,
+    pub resolution : f64,
+    /// Milliseconds since the Unix epoch
+    pub start_time_ms : u64,
+    pub serial : String,
+    /// The acquisition's `Labels`, as JSON
+    pub labels : String,
+}
+
+/// One block read from a spool
+#[derive(Debug, Clone, PartialEq)]
+pub enum SpoolBlock {
+    Segment(SegmentHeader),
+    Records(Vec<u32>),
+    /// Records of one FIFO read, with its `Batch::index` and when it was
+    /// read, since the start of the segment on the host's monotonic clock
+    TimedRecords { index : u64, host_time : Duration, records : Vec<u32> },
+}
+
+fn invalid<T>(msg : String) -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+fn read_u32<R : Read>(r : &mut R) -> io::Result<u32> {
+    let mut b = [0u8; 4];
+    r.read_exact(&mut b)?;
+    Ok(u32::from_le_bytes(b))
+}
+
+fn read_u64<R : Read>(r : &mut R) -> io::Result<u64> {
+    let mut b = [0u8; 8];
+    r.read_exact(&mut b)?;
+    Ok(u64::from_le_bytes(b))
+}
+
+fn read_string<R : Read>(r : &mut R) -> io::Result<String> {
+    let len = read_u32(r)?;
+    if len > MAX_STRING_LEN {
+        return invalid(format!("String of {} bytes exceeds the maximum of {}", len, MAX_STRING_LEN));
+    }
+    let mut b = vec![0u8; len as usize];
+    r.read_exact(&mut b)?;
+    String::from_utf8(b).or_else(|e| invalid(e.to_string()))
+}
+
+fn mode_to_u8(mode : MeasurementMode) -> u8 {
+    mode as u8
+}
+
+fn mode_from_u8(mode : u8) -> io::Result<MeasurementMode> {
+    match mode {
+        0 => Ok(MeasurementMode::Histogramming),
+        2 => Ok(MeasurementMode::T2),
+        3 => Ok(MeasurementMode::T3),
+        _ => invalid(format!("Invalid measurement mode {}", mode)),
+    }
+}
+
+/// Reads the blocks of a spool in order.
+///
+/// ## Example
+///
+/// ```no_run
+/// use multi_harp_patina::*;
+///
+/// let mut reader = SpoolReader::open("overnight.spool").unwrap();
+/// while let Some(block) = reader.next_block().unwrap() {
+///     match block {
+///         SpoolBlock::Segment(header) => println!("Segment {}", header.number),
+///         SpoolBlock::Records(records) => println!("{} records", records.len()),
+///         SpoolBlock::TimedRecords { index, host_time, records } =>
+///             println!("Batch {} at {:?}: {} records", index, host_time, records.len()),
+///     }
+/// }
+/// ```
+pub struct SpoolReader<R : Read> {
+    reader : R,
+    /// Bytes consumed up to the end of the last complete block
+    position : u64,
+}
+
+impl SpoolReader<BufReader<File>> {
+    pub fn open<P : AsRef<Path>>(path : P) -> io::Result<Self> {
+        SpoolReader::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R : Read> SpoolReader<R> {
+    /// Reads and checks the file header from `reader`.
+    pub fn new(mut reader : R) -> io::Result<Self> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != SPOOL_MAGIC {
+            return invalid("Not a MultiHarp spool".to_string());
+        }
+        let version = read_u32(&mut reader)?;
+        if version != SPOOL_VERSION {
+            return invalid(format!("Unsupported spool version {}", version));
+        }
+        Ok(SpoolReader { reader, position : 12 })
+    }
+
+    /// Offset of the end of the last complete block
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// The next block, or `None` at a clean end of file. A block cut off
+    /// partway (e.g. by a crash) is an `UnexpectedEof` error.
+    pub fn next_block(&mut self) -> io::Result<Option<SpoolBlock>> {
+        let mut tag = [0u8; 4];
+        // Distinguish a clean end from a truncated tag
+        let mut read = 0;
+        while read < 4 {
+            match self.reader.read(&mut tag[read..])? {
+                0 if read == 0 => return Ok(None),
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                n => read += n,
+            }
+        }
+
+        let (block, len) = match u32::from_le_bytes(tag) {
+            SEGMENT_TAG => {
+                let number = read_u32(&mut self.reader)?;
+                let mut mode = [0u8; 1];
+                self.reader.read_exact(&mut mode)?;
+                let resolution = f64::from_bits(read_u64(&mut self.reader)?);
+                let start_time_ms = read_u64(&mut self.reader)?;
+                let serial = read_string(&mut self.reader)?;
+                let labels = read_string(&mut self.reader)?;
+                let len = 4 + 4 + 1 + 8 + 8 + 4 + serial.len() + 4 + labels.len();
+                (SpoolBlock::Segment(SegmentHeader {
+                    number, mode : mode_from_u8(mode[0])?, resolution, start_time_ms, serial, labels
+                }), len)
+            },
+            RECORDS_TAG => {
+                let records = self.read_records()?;
+                let len = 8 + 4 * records.len();
+                (SpoolBlock::Records(records), len)
+            },
+            TIMED_RECORDS_TAG => {
+                let index = read_u64(&mut self.reader)?;
+                let host_time = Duration::from_nanos(read_u64(&mut self.reader)?);
+                let records = self.read_records()?;
+                let len = 24 + 4 * records.len();
+                (SpoolBlock::TimedRecords { index, host_time, records }, len)
+            },
+            tag => return invalid(format!("Unknown block tag {:#010x}", tag)),
+        };
+        self.position += len as u64;
+        Ok(Some(block))
+    }
+
+    /// Reads a record count and that many records
+    fn read_records(&mut self) -> io::Result<Vec<u32>> {
+        let n = read_u32(&mut self.reader)?;
+        if n > MAX_BLOCK_RECORDS {
+            return invalid(format!("Block of {} records exceeds the maximum of {}", n, MAX_BLOCK_RECORDS));
+        }
+        let mut bytes = vec![0u8; 4 * n as usize];
+        self.reader.read_exact(&mut bytes)?;
+        Ok(bytes.chunks_exact(4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect())
+    }
+}
+
+/// Writes acquisitions to a spool, one segment per `begin_segment`
+/// (or per `Pipeline::run`, when used as a `StreamProcessor`).
+///
+/// ## Example
+///
+/// ```no_run
+/// use multi_harp_patina::*;
+///
+/// let mut mh = DebugMultiHarp150::default();
+/// let mut pipeline = Pipeline::new();
+/// // Continues the numbering of any segments already in the file
+/// pipeline.add(SpoolWriter::append("overnight.spool").unwrap());
+/// pipeline.run(&mut mh, 60_000).unwrap();
+/// ```
+pub struct SpoolWriter {
+    writer : BufWriter<File>,
+    next_segment : u32,
+    timestamps : bool,
+    segment_started : Instant,
+}
+
+impl SpoolWriter {
+    /// Creates a new spool at `path`, replacing any existing file.
+    pub fn create<P : AsRef<Path>>(path : P) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(SPOOL_MAGIC)?;
+        writer.write_all(&SPOOL_VERSION.to_le_bytes())?;
+        writer.flush()?;
+        Ok(SpoolWriter { writer, next_segment : 0, timestamps : false, segment_started : Instant::now() })
+    }
+
+    /// Opens an existing spool to add segments after those already in
+    /// it, or creates it if it doesn't exist. A partial block left at the
+    /// end by an interrupted run is discarded.
+    pub fn append<P : AsRef<Path>>(path : P) -> io::Result<Self> {
+        if !path.as_ref().exists() {
+            return Self::create(path);
+        }
+
+        let mut reader = SpoolReader::open(path.as_ref())?;
+        let mut next_segment = 0;
+        loop {
+            match reader.next_block() {
+                Ok(Some(SpoolBlock::Segment(header))) => next_segment = header.number + 1,
+                Ok(Some(_)) => {},
+                Ok(None) => break,
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            }
+        }
+        let end = reader.position();
+
+        let mut file = OpenOptions::new().write(true).open(path)?;
+        file.set_len(end)?;
+        file.seek(SeekFrom::Start(end))?;
+        Ok(SpoolWriter { writer : BufWriter::new(file), next_segment, timestamps : false, segment_started : Instant::now() })
+    }
+
+    /// Writes each batch as a `TIMED_RECORDS_TAG` block carrying its
+    /// sequence number and read time, when used as a `StreamProcessor`.
+    pub fn with_timestamps(mut self) -> Self {
+        self.timestamps = true;
+        self
+    }
+
+    /// Number the next segment will be given
+    pub fn next_segment(&self) -> u32 {
+        self.next_segment
+    }
+
+    /// Starts a new segment described by `info`, returning its number.
+    pub fn begin_segment(&mut self, info : &StreamInfo) -> io::Result<u32> {
+        let number = self.next_segment;
+        let start_time_ms = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        let labels = info.labels.to_json();
+
+        let w = &mut self.writer;
+        w.write_all(&SEGMENT_TAG.to_le_bytes())?;
+        w.write_all(&number.to_le_bytes())?;
+        w.write_all(&[mode_to_u8(info.mode)])?;
+        w.write_all(&info.resolution.to_bits().to_le_bytes())?;
+        w.write_all(&start_time_ms.to_le_bytes())?;
+        for s in [info.serial.as_str(), labels.as_str()] {
+            w.write_all(&(s.len() as u32).to_le_bytes())?;
+            w.write_all(s.as_bytes())?;
+        }
+        w.flush()?;
+
+        self.segment_started = Instant::now();
+        self.next_segment += 1;
+        Ok(number)
+    }
+
+    /// Appends a block of raw records to the current segment.
+    pub fn write_records(&mut self, records : &[u32]) -> io::Result<()> {
+        if records.is_empty() {
+            return Ok(());
+        }
+        self.writer.write_all(&RECORDS_TAG.to_le_bytes())?;
+        self.writer.write_all(&(records.len() as u32).to_le_bytes())?;
+        for r in records {
+            self.writer.write_all(&r.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Appends the records of the batch `index`, read at `read_at`, to
+    /// the current segment with their timing (see `TIMED_RECORDS_TAG`).
+    pub fn write_timed_records(&mut self, records : &[u32], index : u64, read_at : Instant) -> io::Result<()> {
+        if records.is_empty() {
+            return Ok(());
+        }
+        let host_time = read_at.saturating_duration_since(self.segment_started).as_nanos() as u64;
+        self.writer.write_all(&TIMED_RECORDS_TAG.to_le_bytes())?;
+        self.writer.write_all(&index.to_le_bytes())?;
+        self.writer.write_all(&host_time.to_le_bytes())?;
+        self.writer.write_all(&(records.len() as u32).to_le_bytes())?;
+        for r in records {
+            self.writer.write_all(&r.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl StreamProcessor for SpoolWriter {
+    fn name(&self) -> &str {
+        "spool_writer"
+    }
+
+    fn init(&mut self, info : &StreamInfo) -> ProcessorResult {
+        self.begin_segment(info).map(|_| ()).map_err(|e| e.to_string())
+    }
+
+    fn process_batch(&mut self, batch : &Batch) -> ProcessorResult {
+        let written = match self.timestamps {
+            true => self.write_timed_records(batch.records, batch.index, batch.read_at),
+            false => self.write_records(batch.records),
+        };
+        written.map_err(|e| e.to_string())
+    }
+
+    fn finalize(&mut self) -> ProcessorResult {
+        self.flush().map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::Labels;
+    use crate::testing::fixtures::stream_info;
+
+    fn info(sample : &str) -> StreamInfo {
+        let mut labels = Labels::new();
+        labels.insert("sample", sample);
+        StreamInfo { labels, ..stream_info(MeasurementMode::T3) }
+    }
+
+    fn read_all(path : &Path) -> Vec<SpoolBlock> {
+        let mut reader = SpoolReader::open(path).unwrap();
+        let mut blocks = Vec::new();
+        while let Some(b) = reader.next_block().unwrap() {
+            blocks.push(b);
+        }
+        blocks
+    }
+
+    #[test]
+    fn test_spool_append() {
+        let path = std::env::temp_dir().join(format!("mhp_spool_test_{}.spool", std::process::id()));
+
+        let mut writer = SpoolWriter::create(&path).unwrap();
+        assert_eq!(writer.begin_segment(&info("a")).unwrap(), 0);
+        writer.write_records(&[1, 2, 3]).unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+
+        // Simulate a crash partway through a block
+        {
+            let mut f = OpenOptions::new().append(true).open(&path).unwrap();
+            f.write_all(&RECORDS_TAG.to_le_bytes()).unwrap();
+            f.write_all(&100u32.to_le_bytes()).unwrap();
+            f.write_all(&[0u8; 6]).unwrap();
+        }
+
+        let mut writer = SpoolWriter::append(&path).unwrap();
+        assert_eq!(writer.next_segment(), 1);
+        assert_eq!(writer.begin_segment(&info("b")).unwrap(), 1);
+        writer.write_records(&[4, 5]).unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+
+        let blocks = read_all(&path);
+        assert_eq!(blocks.len(), 4);
+        assert!(matches!(&blocks[0], SpoolBlock::Segment(h) if h.number == 0 && h.labels == r#"{"sample":"a"}"#));
+        assert_eq!(blocks[1], SpoolBlock::Records(vec![1, 2, 3]));
+        assert!(matches!(&blocks[2], SpoolBlock::Segment(h) if h.number == 1 && h.serial == "1044272"));
+        assert_eq!(blocks[3], SpoolBlock::Records(vec![4, 5]));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_spool_timestamps() {
+        let path = std::env::temp_dir().join(format!("mhp_spool_timed_{}.spool", std::process::id()));
+        let mut writer = SpoolWriter::create(&path).unwrap().with_timestamps();
+        writer.init(&info("a")).unwrap();
+        let start = Instant::now();
+        for (index, records) in [vec![1, 2], vec![], vec![3]].iter().enumerate() {
+            let read_at = start + Duration::from_millis(10 * index as u64);
+            writer.process_batch(&Batch { index : index as u64, read_at, records, mode : MeasurementMode::T3, sync_lost : false }).unwrap();
+        }
+        writer.finalize().unwrap();
+        drop(writer);
+
+        let blocks = read_all(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(blocks.len(), 3);
+        let timed = blocks[1..].iter().map(|b| match b {
+            SpoolBlock::TimedRecords { index, host_time, records } => (*index, *host_time, records.clone()),
+            other => panic!("Expected timed records, got {:?}", other),
+        }).collect::<Vec<_>>();
+        assert_eq!((timed[0].0, timed[1].0), (0, 2));
+        assert_eq!(timed[1].2, vec![3]);
+        assert!(timed[1].1 - timed[0].1 == Duration::from_millis(20));
+    }
+
+    #[test]
+    fn test_spool_rejects_garbage() {
+        assert!(SpoolReader::new(&b"NOTASPOOL..."[..]).is_err());
+
+        let mut bytes = SPOOL_MAGIC.to_vec();
+        bytes.extend(SPOOL_VERSION.to_le_bytes());
+        bytes.extend(RECORDS_TAG.to_le_bytes());
+        bytes.extend(u32::MAX.to_le_bytes());
+        let mut reader = SpoolReader::new(&bytes[..]).unwrap();
+        assert_eq!(reader.next_block().unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
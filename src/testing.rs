@@ -1,4 +1,12 @@
 //! For testing functionality without physically
 //! connecting to a MultiHarp. Dangerous!
 
-pub mod debug_multiharp;
\ No newline at end of file
+pub mod debug_multiharp;
+#[cfg(any(test, feature = "proptest"))]
+pub mod records;
+pub mod conformance;
+pub mod soak;
+#[cfg(test)]
+mod golden;
+#[cfg(test)]
+pub(crate) mod fixtures;
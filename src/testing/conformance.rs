@@ -0,0 +1,283 @@
+//! A conformance suite for `MultiHarpDevice` implementations, run
+//! against `DebugMultiHarp150` in this crate's tests and against real
+//! hardware on demand, so the simulated device keeps behaving like the
+//! one it stands in for.
+//!
+//! Each check configures the device through the trait alone and
+//! compares what it returns with what `MultiHarp150` documents: valid
+//! settings are accepted, out of range ones rejected with an
+//! `ArgumentError`, measurements start and end, the FIFO yields records
+//! of the configured channels and histograms have the length set.
+//!
+//! ## Example
+//!
+//! ```
+//! use multi_harp_patina::*;
+//!
+//! multiharp_conformance::run::<DebugMultiHarp150>().unwrap();
+//! ```
+
+use std::fmt::{Debug, Display};
+use std::time::{Duration, Instant};
+
+use crate::error::{CheckedResult, MultiHarpResult, PatinaError};
+use crate::mhconsts::{self, MeasurementMode, ReferenceClock, TriggerEdge};
+use crate::multiharp::MultiHarpDevice;
+use crate::tttr::{Decoder, EventKind};
+
+/// Acquisition time of the measurements the suite makes, in ms
+pub const ACQUISITION_TIME : i32 = 100;
+
+/// Longest a measurement of `ACQUISITION_TIME` may take to end
+const MEASUREMENT_TIMEOUT : Duration = Duration::from_secs(5);
+
+/// A check of the suite that failed, with what went wrong
+#[derive(Debug, Clone, PartialEq)]
+pub struct Failure {
+    pub check : &'static str,
+    pub message : String,
+}
+
+impl Display for Failure {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}: {}", self.check, self.message)
+    }
+}
+
+type Check<M> = fn(&mut M) -> Result<(), String>;
+
+/// The checks of the suite, in the order `run_on` applies them
+fn checks<M : MultiHarpDevice>() -> [(&'static str, Check<M>); 4] {
+    [
+        ("configuration", check_configuration),
+        ("measurement_lifecycle", check_measurement_lifecycle),
+        ("fifo", check_fifo),
+        ("histogram", check_histogram),
+    ]
+}
+
+/// Opens the first available device of type `M` and runs every check on it.
+///
+/// ## Errors
+///
+/// Every check that failed, or a single `open` failure if no device
+/// could be opened
+pub fn run<M : MultiHarpDevice>() -> Result<(), Vec<Failure>> {
+    let mut mh = M::open(None)
+        .map_err(|e| vec![Failure { check : "open", message : e.to_string() }])?;
+    run_on(&mut mh)
+}
+
+/// Runs every check on an open device, leaving it initialized in T3 mode
+/// with the internal clock. Settings made by the checks are not restored.
+///
+/// ## Errors
+///
+/// Every check that failed
+pub fn run_on<M : MultiHarpDevice>(mh : &mut M) -> Result<(), Vec<Failure>> {
+    let mut failures = checks::<M>().into_iter()
+        .filter_map(|(check, run)| run(mh).err().map(|message| Failure { check, message }))
+        .collect::<Vec<_>>();
+    if let Err(e) = mh.init(MeasurementMode::T3, ReferenceClock::Internal) {
+        failures.push(Failure { check : "init", message : e.to_string() });
+    }
+    if failures.is_empty() { Ok(()) } else { Err(failures) }
+}
+
+fn ensure(condition : bool, message : impl FnOnce() -> String) -> Result<(), String> {
+    if condition { Ok(()) } else { Err(message()) }
+}
+
+fn accepts<R, T : Display + Debug>(call : &str, result : CheckedResult<R, T>) -> Result<R, String> {
+    result.map_err(|e| format!("`{}` failed: {}", call, e))
+}
+
+fn device<R>(call : &str, result : MultiHarpResult<R>) -> Result<R, String> {
+    result.map_err(|e| format!("`{}` failed: {}", call, e))
+}
+
+/// Passes only for the `ArgumentError` out of range settings must return
+fn rejects<R, T : Display + Debug>(call : &str, result : CheckedResult<R, T>) -> Result<(), String> {
+    match result {
+        Err(PatinaError::ArgumentError(..)) => Ok(()),
+        Err(e) => Err(format!("`{}` returned {} instead of an argument error", call, e)),
+        Ok(_) => Err(format!("`{}` accepted an out of range argument", call)),
+    }
+}
+
+fn init<M : MultiHarpDevice>(mh : &mut M, mode : MeasurementMode) -> Result<(), String> {
+    mh.init(mode, ReferenceClock::Internal).map_err(|e| format!("`init` in {:?} failed: {}", mode, e))
+}
+
+/// Waits for the running measurement to end, passing each FIFO read to
+/// `records` in the TTTR modes, then stops it.
+fn finish_measurement<M : MultiHarpDevice>(mh : &mut M, mut records : impl FnMut(&[u32]) -> Result<(), String>) -> Result<(), String> {
+    let tttr = mh.get_measurement_mode() != MeasurementMode::Histogramming;
+    let mut buffer = vec![0u32; mhconsts::TTREADMAX];
+    let deadline = Instant::now() + MEASUREMENT_TIMEOUT;
+    loop {
+        let running = device("ctc_status", mh.ctc_status())?;
+        if tttr {
+            let read = accepts("read_fifo", mh.read_fifo(&mut buffer))?;
+            ensure((0..=mhconsts::TTREADMAX as i32).contains(&read), || format!("`read_fifo` returned {} records", read))?;
+            records(&buffer[..read as usize])?;
+            if !running && read == 0 {
+                break;
+            }
+        } else if !running {
+            break;
+        }
+        if Instant::now() > deadline {
+            let _ = mh.stop_measurement();
+            return Err(format!("A {} ms measurement did not end within {:?}", ACQUISITION_TIME, MEASUREMENT_TIMEOUT));
+        }
+        std::thread::sleep(Duration::from_millis(if tttr { 1 } else { 10 }));
+    }
+    device("stop_measurement", mh.stop_measurement())
+}
+
+fn check_configuration<M : MultiHarpDevice>(mh : &mut M) -> Result<(), String> {
+    init(mh, MeasurementMode::T3)?;
+    let limits = device("limits", mh.limits())?;
+    let channels = device("num_input_channels", mh.num_input_channels())?;
+    ensure(channels >= 1 && channels <= limits.max_channels, || format!(
+        "{} input channels, but the model has at most {}", channels, limits.max_channels
+    ))?;
+
+    accepts("set_sync_div", mh.set_sync_div(*limits.sync_div.start()))?;
+    rejects("set_sync_div", mh.set_sync_div(limits.sync_div.end() + 1))?;
+    accepts("set_sync_edge_trigger", mh.set_sync_edge_trigger(-100, TriggerEdge::Falling))?;
+    rejects("set_sync_edge_trigger", mh.set_sync_edge_trigger(limits.trigger_level.end() + 1, TriggerEdge::Falling))?;
+    accepts("set_sync_channel_offset", mh.set_sync_channel_offset(0))?;
+    rejects("set_sync_channel_offset", mh.set_sync_channel_offset(limits.channel_offset.start() - 1))?;
+
+    for channel in 0..channels {
+        accepts("set_input_edge_trigger", mh.set_input_edge_trigger(channel, -100, TriggerEdge::Falling))?;
+        accepts("set_input_channel_offset", mh.set_input_channel_offset(channel, 0))?;
+        accepts("set_input_channel_enable", mh.set_input_channel_enable(channel, true))?;
+    }
+    rejects("set_input_edge_trigger", mh.set_input_edge_trigger(channels, -100, TriggerEdge::Falling))?;
+    rejects("set_input_edge_trigger", mh.set_input_edge_trigger(0, limits.trigger_level.start() - 1, TriggerEdge::Falling))?;
+    rejects("set_input_channel_offset", mh.set_input_channel_offset(-1, 0))?;
+    rejects("set_input_channel_offset", mh.set_input_channel_offset(0, limits.channel_offset.end() + 1))?;
+    rejects("set_input_channel_enable", mh.set_input_channel_enable(channels, true))?;
+
+    accepts("set_binning", mh.set_binning(*limits.binning.start()))?;
+    rejects("set_binning", mh.set_binning(limits.binning.end() + 1))?;
+    accepts("set_offset", mh.set_offset(100))?;
+    ensure(mh.get_offset() == 100, || format!("`get_offset` returned {} after `set_offset(100)`", mh.get_offset()))?;
+    rejects("set_offset", mh.set_offset(limits.offset.start() - 1))?;
+    ensure(mh.get_offset() == 100, || "A rejected `set_offset` changed the offset".to_string())?;
+    accepts("set_offset", mh.set_offset(0))?;
+
+    #[cfg(feature = "MHLv3_1_0")]
+    {
+        let hold_time = device("snapshot_config", mh.snapshot_config())?.ofl_compression;
+        accepts("set_overflow_compression", mh.set_overflow_compression(mhconsts::HOLDTIMEMAX))?;
+        let recorded = mh.current_settings().ofl_compression;
+        ensure(recorded == Some(mhconsts::HOLDTIMEMAX), || format!(
+            "`current_settings` has an overflow hold time of {:?} after `set_overflow_compression({})`", recorded, mhconsts::HOLDTIMEMAX
+        ))?;
+        rejects("set_overflow_compression", mh.set_overflow_compression(mhconsts::HOLDTIMEMAX + 1))?;
+        // Back to the MHLib 3.1 default of 2 ms if it wasn't known
+        accepts("set_overflow_compression", mh.set_overflow_compression(hold_time.unwrap_or(2)))?;
+    }
+    Ok(())
+}
+
+fn check_measurement_lifecycle<M : MultiHarpDevice>(mh : &mut M) -> Result<(), String> {
+    init(mh, MeasurementMode::T3)?;
+    let limits = device("limits", mh.limits())?;
+    rejects("start_measurement", mh.start_measurement(limits.acquisition_time.start() - 1))?;
+
+    accepts("start_measurement", mh.start_measurement(ACQUISITION_TIME))?;
+    let running = device("ctc_status", mh.ctc_status())?;
+    ensure(running, || "`ctc_status` reports the measurement ended right after it started".to_string())?;
+    finish_measurement(mh, |_| Ok(()))?;
+
+    // Stopped before the acquisition time is up
+    accepts("start_measurement", mh.start_measurement(60_000))?;
+    device("stop_measurement", mh.stop_measurement())?;
+    let running = device("ctc_status", mh.ctc_status())?;
+    ensure(!running, || "`ctc_status` reports a stopped measurement still running".to_string())
+}
+
+fn check_fifo<M : MultiHarpDevice>(mh : &mut M) -> Result<(), String> {
+    init(mh, MeasurementMode::T3)?;
+    let limits = device("limits", mh.limits())?;
+    let channels = device("num_input_channels", mh.num_input_channels())?;
+    rejects("read_fifo", mh.read_fifo(&mut vec![0u32; mhconsts::TTREADMAX - 1]))?;
+
+    let mut decoder = Decoder::new(MeasurementMode::T3)?;
+    accepts("start_measurement", mh.start_measurement(ACQUISITION_TIME))?;
+    finish_measurement(mh, |records| {
+        for event in decoder.decode_all(records) {
+            if event.kind == EventKind::Photon {
+                ensure((event.channel as i32) < channels, || format!("Photon on channel {} of {}", event.channel, channels))?;
+                ensure((event.dtime as usize) < limits.max_histogram_len, || format!(
+                    "Photon `dtime` {} beyond the {} bins of T3 mode", event.dtime, limits.max_histogram_len
+                ))?;
+            }
+        }
+        Ok(())
+    })?;
+
+    init(mh, MeasurementMode::Histogramming)?;
+    match mh.read_fifo(&mut vec![0u32; mhconsts::TTREADMAX]) {
+        Err(PatinaError::WrongMode(..)) => Ok(()),
+        other => Err(format!("`read_fifo` in Histogramming mode returned {:?} instead of a wrong mode error", other)),
+    }
+}
+
+fn check_histogram<M : MultiHarpDevice>(mh : &mut M) -> Result<(), String> {
+    init(mh, MeasurementMode::Histogramming)?;
+    let limits = device("limits", mh.limits())?;
+    let channels = device("num_input_channels", mh.num_input_channels())?;
+
+    let length = accepts("set_histogram_len", mh.set_histogram_len(*limits.length_code.end()))?;
+    ensure(length as usize == limits.max_histogram_len, || format!(
+        "`set_histogram_len({})` returned {} bins instead of {}", limits.length_code.end(), length, limits.max_histogram_len
+    ))?;
+    rejects("set_histogram_len", mh.set_histogram_len(limits.length_code.end() + 1))?;
+    device("clear_histogram", mh.clear_histogram())?;
+
+    accepts("start_measurement", mh.start_measurement(ACQUISITION_TIME))?;
+    finish_measurement(mh, |_| Ok(()))?;
+    let histogram = accepts("get_histogram_by_copy", mh.get_histogram_by_copy(0))?;
+    ensure(histogram.len() == mhconsts::MAXHISTLEN, || format!("`get_histogram_by_copy` returned {} bins", histogram.len()))?;
+    rejects("get_histogram_by_copy", mh.get_histogram_by_copy(channels))?;
+    let all = device("get_all_histograms_by_copy", mh.get_all_histograms_by_copy())?;
+    ensure(all.len() == mhconsts::MAXHISTLEN * channels as usize, || format!(
+        "`get_all_histograms_by_copy` returned {} bins for {} channels", all.len(), channels
+    ))?;
+
+    init(mh, MeasurementMode::T3)?;
+    match mh.get_histogram_by_copy(0) {
+        Err(PatinaError::WrongMode(..)) => Ok(()),
+        other => Err(format!("`get_histogram_by_copy` in T3 mode returned {:?} instead of a wrong mode error", other.map(|h| h.len()))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DebugMultiHarp150;
+
+    #[test]
+    fn test_debug_multiharp_conformance() {
+        if let Err(failures) = run::<DebugMultiHarp150>() {
+            panic!("{}", failures.iter().map(|f| f.to_string()).collect::<Vec<_>>().join("\n"));
+        }
+    }
+
+    /// Needs a connected MultiHarp: `cargo test -- --ignored`
+    #[cfg(feature = "MHLib")]
+    #[test]
+    #[ignore]
+    fn test_multiharp150_conformance() {
+        let _strictness = crate::version::StrictnessGuard::lock();
+        if let Err(failures) = run::<crate::MultiHarp150>() {
+            panic!("{}", failures.iter().map(|f| f.to_string()).collect::<Vec<_>>().join("\n"));
+        }
+    }
+}
@@ -3,17 +3,220 @@ use crate::multiharp::MultiHarpDevice;
 
 #[cfg(feature = "async")]
 use crate::multiharp::AsyncMultiHarpDevice;
+#[cfg(feature = "async")]
+use async_trait::async_trait;
 use crate::TTREADMAX;
-
+use crate::MultiHarpConfig;
+use crate::eventfilter::MainEventFilter;
+use crate::model::{ChannelMask, CHANNELS_PER_ROW};
+use crate::tttr::{pack_t3, OverflowCompression};
+use crate::audit;
+use crate::validation;
+use crate::whiterabbit::WrStatus;
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 use crate::error::{PatinaError, MultiHarpError, MultiHarpResult, CheckedResult};
-use crate::mhconsts::{self, TriggerEdge, MeasurementControlMode, MeasurementMode};
+use crate::mhconsts::{self, TriggerEdge, MeasurementControlMode, MeasurementMode, WRMode, ExtFpgaMode, ExtFpgaLoopback};
 
 use rand_distr::{Distribution, Poisson, Exp};
 
 //#[cfg(not(feature = "MHLib"))]
 static mut OCCUPIED_DEBUG_DEVICES : Vec<i32> = Vec::<i32>::new();
 
+/// How long the simulated White Rabbit link takes to lock by default
+pub const DEBUG_WR_LOCK_TIME : Duration = Duration::from_millis(500);
+
+/// The MAC address of the simulated White Rabbit core until one is set
+pub const DEBUG_WR_MAC : &str = "DEBUG0";
+
+/// How long a record takes from detection to the FIFO of a
+/// `DebugMultiHarp150`, where `read_fifo` can see it
+pub const DEBUG_FIFO_LATENCY : Duration = Duration::from_micros(1300);
+
+/// Records the simulated hardware transfers to the FIFO as soon as
+/// they are ready, without waiting out the hold time
+pub const DEBUG_TRANSFER_BLOCK : usize = 1024;
+
+/// The overflow compression hold time of a new `DebugMultiHarp150`,
+/// in ms, as for MHLib v3.1 and later
+pub const DEBUG_HOLD_TIME : i32 = 2;
+
+/// Records generated within this long of one another share an
+/// arrival time in the `DebugFifo`
+const DEBUG_ARRIVAL_RESOLUTION : Duration = Duration::from_micros(100);
+
+/// The White Rabbit core of a `DebugMultiHarp150`. Once its link is
+/// switched on it steps through the states a real core reports,
+/// spending an equal share of `lock_time` in each, until it is locked
+/// and calibrated.
+struct DebugWRabbit {
+    mode : WRMode,
+    link_on_since : Option<Instant>,
+    lock_time : Duration,
+    /// Seconds since the epoch at `utc.1`, set by `set_wrabbit_time`
+    utc : (u64, Instant),
+    last_status : i32,
+    /// Set by `wrabbit_set_mac`
+    mac : Option<String>,
+    init_script : String,
+}
+
+impl DebugWRabbit {
+    fn new() -> Self {
+        let seconds = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        DebugWRabbit {
+            mode : WRMode::Off,
+            link_on_since : None,
+            lock_time : DEBUG_WR_LOCK_TIME,
+            utc : (seconds, Instant::now()),
+            last_status : 0,
+            mac : None,
+            init_script : String::new(),
+        }
+    }
+
+    /// The status bitfield at `now`, without `WR_STATUS_IS_NEW`.
+    fn status_at(&self, now : Instant) -> i32 {
+        let mut fixed_bits = (self.mode as i32) << 2;
+        if self.mac.is_some() {
+            fixed_bits |= mhconsts::WR_STATUS_MAC_SET;
+        }
+        let since = match (self.mode, self.link_on_since) {
+            (WRMode::Off, _) | (_, None) => return fixed_bits,
+            (_, Some(since)) => since,
+        };
+
+        // Before locking: the link comes up, then PTP and the servo
+        // make their way to tracking the master's phase.
+        let up = mhconsts::WR_STATUS_LINK_UP;
+        let (stages, locked) : (&[i32], i32) = match self.mode {
+            WRMode::Slave => (
+                &[
+                    0,
+                    up | mhconsts::WR_STATUS_PTP_LISTENING,
+                    up | mhconsts::WR_STATUS_PTP_UNCLWRSLCK | mhconsts::WR_STATUS_SERVO_UNINITLZD,
+                    up | mhconsts::WR_STATUS_PTP_SLAVE | mhconsts::WR_STATUS_SERVO_SYNC_SEC,
+                    up | mhconsts::WR_STATUS_PTP_SLAVE | mhconsts::WR_STATUS_SERVO_SYNC_NSEC,
+                    up | mhconsts::WR_STATUS_PTP_SLAVE | mhconsts::WR_STATUS_SERVO_SYNC_PHASE,
+                    up | mhconsts::WR_STATUS_PTP_SLAVE | mhconsts::WR_STATUS_SERVO_WAIT_OFFST,
+                ],
+                up | mhconsts::WR_STATUS_PTP_SLAVE | mhconsts::WR_STATUS_SERVO_TRCK_PHASE,
+            ),
+            _ => (
+                &[
+                    0,
+                    up | mhconsts::WR_STATUS_PTP_LISTENING,
+                    up | mhconsts::WR_STATUS_PTP_MSTRWRMLCK,
+                ],
+                up | mhconsts::WR_STATUS_PTP_MASTER,
+            ),
+        };
+
+        let elapsed = now.saturating_duration_since(since);
+        let stage = if self.lock_time.is_zero() {
+            stages.len()
+        } else {
+            (elapsed.as_secs_f64() / self.lock_time.as_secs_f64() * stages.len() as f64) as usize
+        };
+        let state = stages.get(stage).copied()
+            .unwrap_or(locked | mhconsts::WR_STATUS_LOCKED_CALIBD);
+        fixed_bits | mhconsts::WR_STATUS_LINK_ON | state
+    }
+}
+
+/// The external FPGA interface of a `DebugMultiHarp150`: links that are
+/// up as soon as they are switched on, and user commands reading and
+/// writing a register file.
+#[derive(Debug)]
+struct DebugExtFpga {
+    links_on : HashSet<i32>,
+    mode : (ExtFpgaMode, ExtFpgaLoopback),
+    registers : HashMap<u32, u32>,
+}
+
+impl Default for DebugExtFpga {
+    fn default() -> Self {
+        DebugExtFpga {
+            links_on : HashSet::new(),
+            mode : (ExtFpgaMode::Off, ExtFpgaLoopback::Off),
+            registers : HashMap::new(),
+        }
+    }
+}
+
+/// The records of a `DebugMultiHarp150`, from the acquisition thread
+/// to `read_fifo`. Records stay on the simulated hardware until they
+/// are `DEBUG_FIFO_LATENCY` old and either a `DEBUG_TRANSFER_BLOCK` of
+/// them is waiting or the oldest has been held for the hold time, so at
+/// low rates reads come back empty then in bursts, as on a real device.
+struct DebugFifo {
+    records : Vec<u32>,
+    /// Runs of `records` by when they were generated, as (time, end)
+    arrivals : VecDeque<(Instant, usize)>,
+    /// How many of `records` are in the FIFO, readable by `read_fifo`
+    transferred : usize,
+}
+
+impl DebugFifo {
+    fn with_capacity(capacity : usize) -> Self {
+        DebugFifo { records : Vec::with_capacity(capacity), arrivals : VecDeque::new(), transferred : 0 }
+    }
+
+    fn clear(&mut self) {
+        self.records.clear();
+        self.arrivals.clear();
+        self.transferred = 0;
+    }
+
+    /// Marks the records pushed since the last call as generated at `at`
+    fn arrived(&mut self, at : Instant) {
+        let end = self.records.len();
+        match self.arrivals.back_mut() {
+            Some((_, last)) if *last == end => {},
+            Some((time, last)) if at.saturating_duration_since(*time) < DEBUG_ARRIVAL_RESOLUTION => *last = end,
+            _ => self.arrivals.push_back((at, end)),
+        }
+    }
+
+    /// Moves the records due by `now` into the FIFO. With `flush`, e.g.
+    /// once the measurement has ended, everything is due.
+    fn transfer(&mut self, now : Instant, hold_time : Duration, flush : bool) {
+        if flush {
+            self.transferred = self.records.len();
+            return;
+        }
+        let ready = self.arrivals.iter()
+            .take_while(|(at, _)| now.saturating_duration_since(*at) >= DEBUG_FIFO_LATENCY)
+            .last()
+            .map_or(self.transferred, |(_, end)| (*end).max(self.transferred));
+        let held_since = self.arrivals.iter()
+            .find(|(_, end)| *end > self.transferred)
+            .map_or(now, |(at, _)| *at);
+        if ready - self.transferred >= DEBUG_TRANSFER_BLOCK
+        || now.saturating_duration_since(held_since) >= hold_time {
+            self.transferred = ready;
+        }
+    }
+
+    /// Removes the first `n` records, which must be in the FIFO
+    fn take(&mut self, n : usize) {
+        self.records.drain(..n);
+        self.transferred -= n;
+        while self.arrivals.front().map_or(false, |(_, end)| *end <= n) {
+            self.arrivals.pop_front();
+        }
+        for (_, end) in self.arrivals.iter_mut() {
+            *end -= n;
+        }
+    }
+}
+
 /// A Debug struct used for testing the logic of
 /// functions that use a MultiHarp device. Most
 /// methods return `Ok(())` and do nothing.
@@ -46,6 +249,20 @@ pub struct DebugMultiHarp150 {
     _measurement_control : MeasurementControlMode,
     _measurement_mode : MeasurementMode,
     _reference_clock : mhconsts::ReferenceClock,
+    _flags : i32,
+    /// Bitmask of `FeatureMasks`, see `set_features`
+    _features : i32,
+    _trigger_output : i32,
+    _marker_edges : [TriggerEdge; 4],
+    _marker_enables : [bool; 4],
+    /// ns
+    _marker_holdoff : i32,
+    /// Overflow compression hold time in ms, see `set_overflow_compression`
+    _hold_time : i32,
+    /// Rate error of the device clock, ppm
+    _clock_drift_ppm : f64,
+    _wrabbit : RefCell<DebugWRabbit>,
+    _ext_fpga : DebugExtFpga,
     _resolution : f64,
 
     _base_resolution : f64,
@@ -57,12 +274,20 @@ pub struct DebugMultiHarp150 {
     // the `DebugMultiHarp150` in principle. In practice
     // those threads are joined before the `DebugMultiHarp150`
     // is dropped, but it is potentially dangerous.
-    _internal_buffer : Arc<RwLock<(Vec<u32>, usize)>>,
+    _internal_buffer : Arc<RwLock<DebugFifo>>,
     _last_tick : std::time::SystemTime,
     _acq_thread : Option<std::thread::JoinHandle<()>>,
     _start_time : std::time::SystemTime,
     _acquisition_time : i32,
     _acquiring : Arc<std::sync::atomic::AtomicBool>,
+    /// When `stop_measurement` was last called, freezing the elapsed time
+    _stopped_at : Option<std::time::SystemTime>,
+    _main_filter : Option<MainEventFilter>,
+    _main_filter_enabled : bool,
+    /// The Row Filters by row, as the channels they use and pass
+    _row_filters : HashMap<i32, MainEventFilter>,
+    _row_filters_enabled : HashSet<i32>,
+    _filter_test_mode : bool,
     
     /// Generation method should be `Send` so that the
     /// `MultiHarp` can be passed around between threads.
@@ -74,6 +299,13 @@ pub struct DebugMultiHarp150 {
     // _generation_method : F,
 }
 
+impl std::fmt::Display for DebugMultiHarp150 {
+    fn fmt(&self, f : &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (model, _, firmware) = self.get_hardware_info().map_err(|_| std::fmt::Error)?;
+        crate::multiharp::fmt_identity(f, &model, &self.serial, self._num_channels, &firmware)
+    }
+}
+
 impl Default for DebugMultiHarp150 {
 
     fn default() -> Self {
@@ -103,6 +335,16 @@ impl Default for DebugMultiHarp150 {
             _measurement_control : MeasurementControlMode::SingleShotCtc,
             _measurement_mode : MeasurementMode::T3,
             _reference_clock : mhconsts::ReferenceClock::Internal,
+            _flags : 0,
+            _features : mhconsts::ALL_FEATURES,
+            _trigger_output : 0,
+            _marker_edges : [TriggerEdge::Rising; 4],
+            _marker_enables : [false; 4],
+            _marker_holdoff : 0,
+            _hold_time : DEBUG_HOLD_TIME,
+            _clock_drift_ppm : 0.0,
+            _wrabbit : RefCell::new(DebugWRabbit::new()),
+            _ext_fpga : DebugExtFpga::default(),
 
             _base_resolution : 5.0,
             _resolution : 5.0,
@@ -110,7 +352,7 @@ impl Default for DebugMultiHarp150 {
             _last_tick : std::time::SystemTime::now(),
             // Big buffer with lots of space.
             _internal_buffer : Arc::new(RwLock::new(
-                (Vec::<u32>::with_capacity(500*mhconsts::TTREADMAX), 0)
+                DebugFifo::with_capacity(500*mhconsts::TTREADMAX)
             )),
             // _generation_method : F
             _generation_method : Box::new(Self::_default_tick),
@@ -118,6 +360,12 @@ impl Default for DebugMultiHarp150 {
             _start_time : std::time::SystemTime::now(),
             _acquisition_time : 0,
             _acquiring : Arc::new(std::sync::atomic::AtomicBool::new(false)),
+            _stopped_at : None,
+            _main_filter : None,
+            _main_filter_enabled : false,
+            _row_filters : HashMap::new(),
+            _row_filters_enabled : HashSet::new(),
+            _filter_test_mode : false,
         }
     }
 }
@@ -135,6 +383,64 @@ impl DebugMultiHarp150 {
         self._mean_count_rate
     }
 
+    /// The period last passed to `set_trigger_output`, in units of 100 ns
+    pub fn get_trigger_output(&self) -> i32 {
+        self._trigger_output
+    }
+
+    /// Sets the bitmask returned by `get_flags`, e.g. `FLAG_REF_LOST`
+    /// to simulate a disconnected GPS receiver.
+    pub fn set_flags(&mut self, flags : i32) {
+        self._flags = flags;
+    }
+
+    /// Sets the bitmask of `FeatureMasks` returned by `get_features`, to
+    /// simulate a device without e.g. a trigger output. Settings that need
+    /// a missing feature then fail with `FeatureNotAvailable`.
+    pub fn set_features(&mut self, features : i32) {
+        self._features = features;
+    }
+
+    /// Sets how far the device clock runs from true time, in parts per
+    /// million (positive is fast). Affects the acquisition timer, the
+    /// measured sync rate and the White Rabbit time, except while the
+    /// device is a locked White Rabbit slave, when it follows its master.
+    pub fn set_clock_drift_ppm(&mut self, ppm : f64) {
+        self._clock_drift_ppm = ppm;
+    }
+
+    /// The drift of the device clock currently in effect, in ppm.
+    /// 0 while locked to a White Rabbit master.
+    pub fn clock_drift_ppm(&self) -> f64 {
+        let wrabbit = self._wrabbit.borrow();
+        let status = wrabbit.status_at(Instant::now());
+        if wrabbit.mode == WRMode::Slave && status & mhconsts::WR_STATUS_LOCKED_CALIBD != 0 {
+            0.0
+        } else {
+            self._clock_drift_ppm
+        }
+    }
+
+    /// Converts a true duration to the one measured by the device clock.
+    pub fn device_duration(&self, duration : Duration) -> Duration {
+        duration.mul_f64(1.0 + self.clock_drift_ppm() * 1e-6)
+    }
+
+    /// Sets how long the White Rabbit link takes to lock after it is
+    /// switched on (default `DEBUG_WR_LOCK_TIME`).
+    pub fn set_wrabbit_lock_time(&mut self, lock_time : Duration) {
+        self._wrabbit.borrow_mut().lock_time = lock_time;
+    }
+
+    /// Simulates losing the White Rabbit lock: the link stays on, but
+    /// has to lock again from the start.
+    pub fn lose_wrabbit_lock(&self) {
+        let mut wrabbit = self._wrabbit.borrow_mut();
+        if wrabbit.link_on_since.is_some() {
+            wrabbit.link_on_since = Some(Instant::now());
+        }
+    }
+
     /// Create a new DebugMultiHarp150 with a mean count rate and sync rate
     /// defined in seconds and the exponential(s) from which the photons are
     /// drawn.
@@ -176,22 +482,50 @@ impl DebugMultiHarp150 {
             _measurement_control : MeasurementControlMode::SingleShotCtc,
             _measurement_mode : MeasurementMode::T3,
             _reference_clock : mhconsts::ReferenceClock::Internal,
+            _flags : 0,
+            _features : mhconsts::ALL_FEATURES,
+            _trigger_output : 0,
+            _marker_edges : [TriggerEdge::Rising; 4],
+            _marker_enables : [false; 4],
+            _marker_holdoff : 0,
+            _hold_time : DEBUG_HOLD_TIME,
+            _clock_drift_ppm : 0.0,
+            _wrabbit : RefCell::new(DebugWRabbit::new()),
+            _ext_fpga : DebugExtFpga::default(),
 
             _base_resolution : 5.0,
             _resolution : 5.0,
             _ctc_status : false,
             _last_tick : std::time::SystemTime::now(),
             _internal_buffer : Arc::new(RwLock::new(
-                (Vec::<u32>::with_capacity(500*mhconsts::TTREADMAX), 0)
+                DebugFifo::with_capacity(500*mhconsts::TTREADMAX)
             )),
             _generation_method : Box::new(Self::_default_tick),
             _acq_thread : None,
             _start_time : std::time::SystemTime::now(),
             _acquisition_time : 0,
             _acquiring : Arc::new(std::sync::atomic::AtomicBool::new(false)),
+            _stopped_at : None,
+            _main_filter : None,
+            _main_filter_enabled : false,
+            _row_filters : HashMap::new(),
+            _row_filters_enabled : HashSet::new(),
+            _filter_test_mode : false,
         }
     }
 
+    /// The `ArgumentError` `MultiHarp150` returns for a channel it doesn't have
+    fn check_channel(&self, channel : i32) -> CheckedResult<(), i32> {
+        if channel < 0 || channel >= self._num_channels {
+            return Err(PatinaError::ArgumentError(
+                "channel".to_string(),
+                channel,
+                format!("Channel must be between 0 and {}", self._num_channels - 1))
+            );
+        }
+        Ok(())
+    }
+
     /// Set the exponential(s) from which the photon arrival times
     /// are drawn. Units are in nanoseconds.
     pub fn set_taus(&mut self, taus : Vec<f64>) -> () {
@@ -227,9 +561,13 @@ impl DebugMultiHarp150 {
 
 #[allow(dead_code, unused_variables)]
 impl MultiHarpDevice for DebugMultiHarp150 {
+    /// With `None`, opens the lowest index not already open, as
+    /// `MultiHarp150` opens the first available device.
     fn open(index : Option<i32>) -> Result<Self, PatinaError<i32>> {
         if index.is_none() {
-            return Err(PatinaError::NoDeviceAvailable);
+            return (0..mhconsts::MAXDEVNUM)
+                .find_map(|free| Self::open(Some(free)).ok())
+                .ok_or(PatinaError::NoDeviceAvailable);
         }
         let index = index.unwrap();
         if index < 0 || index > mhconsts::MAXDEVNUM {
@@ -277,19 +615,35 @@ impl MultiHarpDevice for DebugMultiHarp150 {
             _measurement_control : MeasurementControlMode::SingleShotCtc,
             _measurement_mode : MeasurementMode::T3,
             _reference_clock : mhconsts::ReferenceClock::Internal,
+            _flags : 0,
+            _features : mhconsts::ALL_FEATURES,
+            _trigger_output : 0,
+            _marker_edges : [TriggerEdge::Rising; 4],
+            _marker_enables : [false; 4],
+            _marker_holdoff : 0,
+            _hold_time : DEBUG_HOLD_TIME,
+            _clock_drift_ppm : 0.0,
+            _wrabbit : RefCell::new(DebugWRabbit::new()),
+            _ext_fpga : DebugExtFpga::default(),
 
             _last_tick : std::time::SystemTime::now(),
             _base_resolution : 5.0,
             _resolution : 5.0,
             _ctc_status : false,
             _internal_buffer : Arc::new(RwLock::new(
-                (Vec::<u32>::with_capacity(500*mhconsts::TTREADMAX),0)
+                DebugFifo::with_capacity(500*mhconsts::TTREADMAX)
             )),
             _generation_method : Box::new(Self::_default_tick),
             _acq_thread : None,
             _start_time : std::time::SystemTime::now(),
             _acquisition_time : 0,
             _acquiring : Arc::new(std::sync::atomic::AtomicBool::new(false)),
+            _stopped_at : None,
+            _main_filter : None,
+            _main_filter_enabled : false,
+            _row_filters : HashMap::new(),
+            _row_filters_enabled : HashSet::new(),
+            _filter_test_mode : false,
         })
     }
 
@@ -303,7 +657,7 @@ impl MultiHarpDevice for DebugMultiHarp150 {
         }
         Ok(DebugMultiHarp150 {
             index: 0,
-            serial: "1044272".to_string(),
+            serial: serial.trim_start_matches('0').to_string(),
             _taus : vec![2.0],
             _mean_count_rate: 1.0e5,
             _sync_div : 1,
@@ -327,19 +681,35 @@ impl MultiHarpDevice for DebugMultiHarp150 {
             _measurement_control : MeasurementControlMode::SingleShotCtc,
             _measurement_mode : MeasurementMode::T3,
             _reference_clock : mhconsts::ReferenceClock::Internal,
+            _flags : 0,
+            _features : mhconsts::ALL_FEATURES,
+            _trigger_output : 0,
+            _marker_edges : [TriggerEdge::Rising; 4],
+            _marker_enables : [false; 4],
+            _marker_holdoff : 0,
+            _hold_time : DEBUG_HOLD_TIME,
+            _clock_drift_ppm : 0.0,
+            _wrabbit : RefCell::new(DebugWRabbit::new()),
+            _ext_fpga : DebugExtFpga::default(),
 
             _last_tick : std::time::SystemTime::now(),
             _base_resolution : 5.0,
             _resolution : 5.0,
             _ctc_status : false,
             _internal_buffer : Arc::new(RwLock::new(
-                (Vec::<u32>::with_capacity(500*mhconsts::TTREADMAX), 0)
+                DebugFifo::with_capacity(500*mhconsts::TTREADMAX)
             )),
             _generation_method : Box::new(Self::_default_tick),
             _acq_thread : None,
             _start_time : std::time::SystemTime::now(),
             _acquisition_time : 0,
             _acquiring : Arc::new(std::sync::atomic::AtomicBool::new(false)),
+            _stopped_at : None,
+            _main_filter : None,
+            _main_filter_enabled : false,
+            _row_filters : HashMap::new(),
+            _row_filters_enabled : HashSet::new(),
+            _filter_test_mode : false,
         })
     }
 
@@ -348,96 +718,299 @@ impl MultiHarpDevice for DebugMultiHarp150 {
         mode : mhconsts::MeasurementMode,
         reference_clock : mhconsts::ReferenceClock
     ) -> Result<(), MultiHarpError> {
+        self._measurement_mode = mode;
+        self._reference_clock = reference_clock;
         Ok(())
     }
 
+    /// Reports itself as a 4 channel MultiHarp 150 with the
+    /// 5 ps base resolution of the `P` models.
+    fn get_hardware_info(&self) -> MultiHarpResult<(String, String, String)> {
+        Ok(("MultiHarp 150 4P".to_string(), "Debug".to_string(), "0.0".to_string()))
+    }
+
     fn get_base_resolution(&self) -> crate::error::MultiHarpResult<(f64, i32)> {
         Ok((self._base_resolution, 2500))
     }
 
     fn set_sync_div(&mut self, sync_div : i32) -> CheckedResult<(), i32> {
+        let limits = self.limits()?;
+        let sync_div = limits.validate(&self.serial, "sync_div", sync_div, &limits.sync_div)?;
+        let old = self._sync_div;
         self._sync_div = sync_div;
+        audit::log_setting(&self.serial, "sync_div", Some(&old), sync_div);
         Ok(())
     }
 
     fn set_sync_edge_trigger(&mut self, level : i32, edge : TriggerEdge) -> CheckedResult<(), i32> {
+        let limits = self.limits()?;
+        let level = limits.validate(&self.serial, "level", level, &limits.trigger_level)?;
+        let old = (self._sync_level, self._sync_edge);
         self._sync_edge = edge;
         self._sync_level = level;
+        audit::log_setting(&self.serial, "sync_edge_trigger", Some(&old), (level, edge));
         Ok(())
     }
 
     fn set_sync_channel_offset(&mut self, offset : i32) -> CheckedResult<(), i32> {
+        let limits = self.limits()?;
+        let offset = limits.validate(&self.serial, "offset", offset, &limits.channel_offset)?;
+        let old = self._sync_offset;
         self._sync_offset = offset;
+        audit::log_setting(&self.serial, "sync_channel_offset", Some(&old), offset);
         Ok(())
     }
 
     fn set_sync_dead_time(&mut self, on : bool, dead_time : i32) -> CheckedResult<(), i32> {
+        if (self._features & (mhconsts::FeatureMasks::ProgTd as i32)) == 0 {
+            return Err(PatinaError::FeatureNotAvailable("Programmable dead time".to_string()));
+        }
+        let limits = self.limits()?;
+        let dead_time = limits.validate(&self.serial, "deadtime", dead_time, &limits.dead_time)?;
         self._sync_dead_time = dead_time;
+        audit::log_setting(&self.serial, "sync_dead_time", None, (on, dead_time));
         Ok(())
     }
 
     fn set_input_edge_trigger(&mut self, channel : i32, level : i32, edge : TriggerEdge) -> CheckedResult<(), i32> {
+        self.check_channel(channel)?;
+        let limits = self.limits()?;
+        let level = limits.validate(&self.serial, "level", level, &limits.trigger_level)?;
+        let old = (self._input_levels[channel as usize], self._input_edges[channel as usize]);
         self._input_edges[channel as usize] = edge;
         self._input_levels[channel as usize] = level;
+        audit::log_channel_setting(&self.serial, "input_edge_trigger", channel, Some(&old), (level, edge));
         Ok(())
     }
 
     fn set_input_channel_offset(&mut self, channel : i32, offset : i32) -> CheckedResult<(), i32> {
+        self.check_channel(channel)?;
+        let limits = self.limits()?;
+        let offset = limits.validate(&self.serial, "offset", offset, &limits.channel_offset)?;
+        let old = self._input_offsets[channel as usize];
         self._input_offsets[channel as usize] = offset;
+        audit::log_channel_setting(&self.serial, "input_channel_offset", channel, Some(&old), offset);
         Ok(())
     }
 
     fn set_input_dead_time(&mut self, channel : i32, on : bool, dead_time : i32) -> CheckedResult<(), i32> {
+        self.check_channel(channel)?;
+        let limits = self.limits()?;
+        let dead_time = limits.validate(&self.serial, "deadtime", dead_time, &limits.dead_time)?;
         self._input_dead_times[channel as usize] = dead_time;
+        audit::log_channel_setting(&self.serial, "input_dead_time", channel, None, (on, dead_time));
         Ok(())
     }
 
     fn set_input_channel_enable(&mut self, channel : i32, enable : bool) -> CheckedResult<(), i32> {
+        self.check_channel(channel)?;
+        let old = self._input_enables[channel as usize];
         self._input_enables[channel as usize] = enable;
+        audit::log_channel_setting(&self.serial, "input_channel_enable", channel, Some(&old), enable);
         Ok(())
     }
 
     fn set_binning(&mut self, binning : i32) -> CheckedResult<(), i32> {
+        let limits = self.limits()?;
+        let binning = limits.validate(&self.serial, "binning", binning, &limits.binning)?;
+        let old = self._binning;
         self._binning = binning;
+        audit::log_setting(&self.serial, "binning", Some(&old), binning);
         Ok(())
     }
 
     fn set_offset(&mut self, offset : i32) -> CheckedResult<(), i32> {
+        let limits = self.limits()?;
+        let offset = limits.validate(&self.serial, "offset", offset, &limits.offset)?;
+        let old = self._offset;
         self._offset = offset;
+        audit::log_setting(&self.serial, "offset", Some(&old), offset);
         Ok(())
     }
 
-    /// TODO not right just a dummy!!
+    /// Returns the histogram length, 1024 bins doubled `len_code` times
     fn set_histogram_len(&mut self, len_code : i32) -> CheckedResult<i32, i32> {
+        let limits = self.limits()?;
+        let len_code = limits.validate(&self.serial, "lencode", len_code, &limits.length_code)?;
+        let old = self._histogram_len;
         self._histogram_len = len_code;
-        Ok(5)
+        audit::log_setting(&self.serial, "histogram_len", Some(&old), len_code);
+        Ok(1024 << len_code)
     }
 
     fn set_measurement_control_mode(&mut self, control : MeasurementControlMode, start_edge : Option<TriggerEdge>, stop_edge : Option<TriggerEdge>) -> CheckedResult<(), String> {
         self._measurement_control = control;
+        audit::log_setting(&self.serial, "measurement_control_mode", None, (control, start_edge, stop_edge));
+        Ok(())
+    }
+
+    fn set_main_event_filter(&mut self, filter : &MainEventFilter) -> CheckedResult<(), i32> {
+        validation::validate(&self.serial, "time_range", filter.time_range, mhconsts::TIMERANGEMIN..=mhconsts::TIMERANGEMAX, "Time range")?;
+        validation::validate(&self.serial, "match_cnt", filter.match_count, mhconsts::MATCHCNTMIN..=mhconsts::MATCHCNTMAX, "Match count")?;
+        if let Some(channel) = filter.use_channels.iter().chain(&filter.pass_channels).find(|c| **c < 0 || **c >= self._num_channels) {
+            return Err(PatinaError::ArgumentError(
+                "channel".to_string(),
+                *channel,
+                format!("Channel must be between 0 and {}", self._num_channels - 1))
+            );
+        }
+        self._main_filter = Some(filter.clone());
+        audit::log_setting(&self.serial, "main_event_filter", None, filter);
+        Ok(())
+    }
+
+    fn enable_main_event_filter(&mut self, enable : bool) -> CheckedResult<(), i32> {
+        self._main_filter_enabled = enable;
+        Ok(())
+    }
+
+    fn set_filter_test_mode(&mut self, test_mode : bool) -> CheckedResult<(), i32> {
+        self._filter_test_mode = test_mode;
         Ok(())
     }
 
+    /// The rates the filter is expected to pass from uncorrelated inputs
+    /// after the Row Filters, see `MainEventFilter::estimate`
+    fn get_main_filtered_rates(&self) -> CheckedResult<(i32, Vec<i32>), i32> {
+        let (sync, rates) = self.get_row_filtered_rates()?;
+        match (&self._main_filter, self._main_filter_enabled) {
+            (Some(filter), true) => {
+                let inputs = rates.iter().map(|rate| *rate as f64).collect::<Vec<_>>();
+                Ok((sync, filter.estimate(&inputs).into_iter().map(|rate| rate as i32).collect()))
+            },
+            _ => Ok((sync, rates)),
+        }
+    }
+
+    fn set_row_event_filter(
+        &mut self, row : i32, time_range : i32, match_cnt : i32, inverse : bool,
+        use_channels : ChannelMask, pass_channels : ChannelMask,
+    ) -> CheckedResult<(), i32> {
+        let row = validation::validate(&self.serial, "row", row, mhconsts::ROWIDXMIN..=mhconsts::ROWIDXMAX, "Row")?;
+        let time_range = validation::validate(&self.serial, "time_range", time_range, mhconsts::TIMERANGEMIN..=mhconsts::TIMERANGEMAX, "Time range")?;
+        let match_count = validation::validate(&self.serial, "match_cnt", match_cnt, mhconsts::MATCHCNTMIN..=mhconsts::MATCHCNTMAX, "Match count")?;
+        if use_channels.has_sync() || pass_channels.has_sync() {
+            return Err(PatinaError::ArgumentError(
+                "use_channels".to_string(),
+                use_channels.bits() | pass_channels.bits(),
+                "The Row Filters don't act on the sync channel".to_string())
+            );
+        }
+        let channels = |mask : ChannelMask| mask.channels().map(|bit| row * CHANNELS_PER_ROW + bit).collect::<Vec<_>>();
+        let filter = MainEventFilter {
+            time_range,
+            match_count,
+            inverse,
+            use_channels : channels(use_channels),
+            pass_channels : channels(pass_channels),
+            use_sync : false,
+            pass_sync : false,
+        };
+        audit::log_setting(&self.serial, &format!("row_event_filter.{}", row), None, &filter);
+        self._row_filters.insert(row, filter);
+        Ok(())
+    }
+
+    fn enable_row_event_filter(&mut self, row : i32, enable : bool) -> CheckedResult<(), i32> {
+        let row = validation::validate(&self.serial, "row", row, mhconsts::ROWIDXMIN..=mhconsts::ROWIDXMAX, "Row")?;
+        if enable {
+            self._row_filters_enabled.insert(row);
+        } else {
+            self._row_filters_enabled.remove(&row);
+        }
+        Ok(())
+    }
+
+    /// The rates the enabled Row Filters are expected to pass from
+    /// uncorrelated inputs. Channels of a filtered row that the filter
+    /// neither uses nor passes are removed.
+    fn get_row_filtered_rates(&self) -> CheckedResult<(i32, Vec<i32>), i32> {
+        let (sync, rates) = self.get_all_count_rates().map_err(PatinaError::MultiHarpError)?;
+        let mut rates = rates.into_iter().map(|rate| rate as f64).collect::<Vec<_>>();
+        for row in self._row_filters_enabled.iter() {
+            let filter = match self._row_filters.get(row) {
+                Some(filter) => filter,
+                None => continue,
+            };
+            rates = filter.estimate(&rates);
+            let in_row = (row * CHANNELS_PER_ROW..(row + 1) * CHANNELS_PER_ROW)
+                .filter(|channel| !filter.use_channels.contains(channel) && !filter.pass_channels.contains(channel));
+            for channel in in_row {
+                if let Some(rate) = rates.get_mut(channel as usize) {
+                    *rate = 0.0;
+                }
+            }
+        }
+        Ok((sync, rates.into_iter().map(|rate| rate as i32).collect()))
+    }
+
     fn set_trigger_output(&mut self, period : i32) -> CheckedResult<(), i32> {
+        if (self._features & (mhconsts::FeatureMasks::TrigOut as i32)) == 0 {
+            return Err(PatinaError::FeatureNotAvailable("Trigger Output".to_string()));
+        }
+        let period = validation::validate(&self.serial, "period", period, mhconsts::TRIGOUTMIN..=mhconsts::TRIGOUTMAX, "Period")?;
+        let old = self._trigger_output;
+        self._trigger_output = period;
+        audit::log_setting(&self.serial, "trigger_output", Some(&old), period);
+        Ok(())
+    }
+
+    fn set_marker_edges(&mut self, marker1 : TriggerEdge, marker2 : TriggerEdge, marker3 : TriggerEdge, marker4 : TriggerEdge) -> MultiHarpResult<()> {
+        self._marker_edges = [marker1, marker2, marker3, marker4];
+        audit::log_setting(&self.serial, "marker_edges", None, self._marker_edges);
+        Ok(())
+    }
+
+    fn set_marker_enable(&mut self, enable1 : bool, enable2 : bool, enable3 : bool, enable4 : bool) -> MultiHarpResult<()> {
+        self._marker_enables = [enable1, enable2, enable3, enable4];
+        audit::log_setting(&self.serial, "marker_enable", None, self._marker_enables);
+        Ok(())
+    }
+
+    fn set_marker_holdoff_time(&mut self, holdoff_time : i32) -> CheckedResult<(), i32> {
+        let holdoff_time = validation::validate(&self.serial, "holdofftime", holdoff_time, mhconsts::HOLDOFFMIN..=mhconsts::HOLDOFFMAX, "Holdoff time")?;
+        let old = self._marker_holdoff;
+        self._marker_holdoff = holdoff_time;
+        audit::log_setting(&self.serial, "marker_holdoff_time", Some(&old), holdoff_time);
+        Ok(())
+    }
+
+    /// Sets how long the simulated hardware holds records back before
+    /// transferring them to the FIFO, so that at low rates `read_fifo`
+    /// stutters as on a real device (see `DebugFifo`).
+    fn set_overflow_compression(&mut self, hold_time : i32) -> CheckedResult<(), i32> {
+        let hold_time = validation::validate(&self.serial, "hold_time", hold_time, mhconsts::HOLDTIMEMIN..=mhconsts::HOLDTIMEMAX, "Hold time")?;
+        let old = self._hold_time;
+        self._hold_time = hold_time;
+        audit::log_setting(&self.serial, "overflow_compression", Some(&old), hold_time);
         Ok(())
     }
 
     fn start_measurement(&mut self, acquisition_time : i32) -> Result<(), PatinaError<i32>> {
+        let limits = self.limits()?;
+        let acquisition_time = limits.validate(&self.serial, "acquisition_time", acquisition_time, &limits.acquisition_time)?;
+        self.check_system_error()?;
+        self.check_reference_clock()?;
         self._ctc_status = true;
         self._last_tick = std::time::SystemTime::now();
+        self._start_time = self._last_tick;
+        self._stopped_at = None;
         self._acquisition_time = acquisition_time;
         self._acquiring.store(true, std::sync::atomic::Ordering::SeqCst);
 
         let acq_pt = Arc::clone(&self._acquiring);
 
         // Reset the internal buffer pointer
-        let mut internal = self._internal_buffer.as_ref().write().unwrap();
-        internal.0.clear();
-        internal.1 = 0;
+        self._internal_buffer.as_ref().write().unwrap().clear();
         
         // Create cloned variables for the thread
         let buf = Arc::clone(&self._internal_buffer);
-        let mean_rate = self._mean_count_rate.clone();
+        // Disabled channels produce no photons
+        let enabled = (0..self._input_enables.len() as u8)
+            .filter(|channel| self._input_enables[*channel as usize])
+            .collect::<Vec<_>>();
+        let mean_rate = self._mean_count_rate * enabled.len() as f64 / self._input_enables.len().max(1) as f64;
+        let clock_rate = 1.0 + self.clock_drift_ppm() * 1e-6;
         let exponentials = self._taus.iter().map(|tau| Exp::new(1.0/tau).unwrap())
         .to_owned();
 
@@ -452,27 +1025,26 @@ impl MultiHarpDevice for DebugMultiHarp150 {
             let mut rng = rand::thread_rng();
 
             while acq_pt.load(std::sync::atomic::Ordering::SeqCst)
-            && start_time.elapsed().unwrap().as_millis() < acquisition_time as u128 {
+            && start_time.elapsed().unwrap().mul_f64(clock_rate).as_millis() < acquisition_time as u128 {
 
                 let mut guard = buf.as_ref().write().unwrap();
 
                 let tick = std::time::Instant::now();
                 // println!("Expected {} photons for an interval of {}", expected_photons, tick.duration_since(last_tick).as_secs_f64());
-                let n_photons = Poisson::new(
-                    mean_rate * tick.duration_since(last_tick).as_secs_f64()
-                ).unwrap().sample(&mut rng) as usize;
+                let n_photons = match enabled.len() {
+                    0 => 0,
+                    _ => Poisson::new(
+                        mean_rate * tick.duration_since(last_tick).as_secs_f64()
+                    ).unwrap().sample(&mut rng) as usize,
+                };
                 
                 for _ in 0..n_photons as usize {
                     let arrival_time = rand::random::<u16>() % (1<<14);
-                    let channel = rand::random::<u8>() % 4;
+                    let channel = enabled[rand::random::<usize>() % enabled.len()];
                     let syncs = rand::random::<u16>() % (1<<10);
-                    guard.0.push(
-                        ((channel as u32) << 26)
-                        | ((arrival_time as u32) << 10)
-                        | (syncs as u32)
-                    );
+                    guard.records.push(pack_t3(false, channel, arrival_time, syncs));
                 }
-                guard.1 += n_photons as usize;
+                guard.arrived(tick);
                 last_tick = tick;
             }
         }));
@@ -482,13 +1054,23 @@ impl MultiHarpDevice for DebugMultiHarp150 {
 
     fn stop_measurement(&mut self) -> Result<(), MultiHarpError> {
         self._ctc_status = false;
+        self._stopped_at = Some(std::time::SystemTime::now());
         self._acquiring.store(false, std::sync::atomic::Ordering::SeqCst);
         self._acq_thread.take()
             .ok_or(MultiHarpError::NotInitialized)?.join().unwrap();
         Ok(())
     }
 
+    /// Returns the records that have reached the FIFO: none until they
+    /// are `DEBUG_FIFO_LATENCY` old, and at low rates none until the
+    /// hold time set with `set_overflow_compression` has passed. Once
+    /// the measurement ends everything left is returned. As from the
+    /// device, a read returns at most `TTREADMAX` records, leaving the
+    /// rest for the next.
     fn read_fifo<'a, 'b>(&'a self, buffer : &'b mut Vec<u32>) -> CheckedResult<i32, u32> {
+        if self._measurement_mode == MeasurementMode::Histogramming {
+            return Err(PatinaError::WrongMode("read_fifo".to_string(), self._measurement_mode));
+        }
         if buffer.len() < mhconsts::TTREADMAX {
             return Err(PatinaError::ArgumentError(
                 "buffer".to_string(),
@@ -501,25 +1083,31 @@ impl MultiHarpDevice for DebugMultiHarp150 {
             PatinaError::MultiHarpError(MultiHarpError::ThreadStateFail)
         )?;
         
-        if read.1 > TTREADMAX {
-            return Err(PatinaError::MultiHarpError(MultiHarpError::FIFOResetFail));
-        }
-
-        buffer[..read.1].clone_from_slice(&read.0[..read.1]);
-        let returned = read.1;
-        read.1 = 0;
+        let finished = self._acq_thread.as_ref().map_or(true, |t| t.is_finished());
+        read.transfer(Instant::now(), Duration::from_millis(self._hold_time as u64), finished);
+        let returned = read.transferred.min(TTREADMAX);
+        buffer[..returned].clone_from_slice(&read.records[..returned]);
+        read.take(returned);
         Ok(returned as i32)
     } 
 
     fn get_histogram_by_copy(&mut self, channel : i32) -> CheckedResult<Vec<u32>, i32> {
-        Ok(vec![0])
+        if self._measurement_mode != MeasurementMode::Histogramming {
+            return Err(PatinaError::WrongMode("get_histogram_by_copy".to_string(), self._measurement_mode));
+        }
+        self.check_channel(channel)?;
+        Ok(vec![0; mhconsts::MAXHISTLEN])
     }
 
     fn get_all_histograms_by_copy(&mut self) -> MultiHarpResult<Vec<u32>>{
-        Ok(vec![0])
+        Ok(vec![0; mhconsts::MAXHISTLEN * self._num_channels as usize])
     }
 
     fn fill_histogram<'a, 'b>(&'a mut self, histogram : &'b mut Vec<u32>, channel : i32) -> CheckedResult<(), i32> {
+        if self._measurement_mode != MeasurementMode::Histogramming {
+            return Err(PatinaError::WrongMode("fill_histogram".to_string(), self._measurement_mode));
+        }
+        self.check_channel(channel)?;
         Ok(())
     }
 
@@ -531,8 +1119,27 @@ impl MultiHarpDevice for DebugMultiHarp150 {
         Ok(self._base_resolution)
     }
 
+    /// Counted over a gate timed by the device clock, so scaled by its drift
+    fn get_sync_rate(&self) -> MultiHarpResult<i32> {
+        Ok((self._sync_rate / (1.0 + self.clock_drift_ppm() * 1e-6)) as i32)
+    }
+
+    /// Timed by the device clock since `start_measurement`, up to the
+    /// acquisition time or until the measurement was stopped. 0 before
+    /// the first measurement.
+    fn get_elapsed_measurement_time(&self) -> MultiHarpResult<f64> {
+        if self._acquisition_time == 0 {
+            return Ok(0.0);
+        }
+        let end = self._stopped_at.unwrap_or_else(std::time::SystemTime::now);
+        let elapsed = end.duration_since(self._last_tick).unwrap_or_default();
+        let elapsed_ms = self.device_duration(elapsed).as_secs_f64() * 1e3;
+        Ok(elapsed_ms.min(self._acquisition_time as f64))
+    }
+
+    /// `true` until the acquisition time has elapsed or the measurement is stopped
     fn ctc_status(&self) -> Result<bool, MultiHarpError> {
-        Ok(self._ctc_status)
+        Ok(self._ctc_status && self._acq_thread.as_ref().map(|t| !t.is_finished()).unwrap_or(false))
     }
 
     fn get_index(&self) -> i32 {
@@ -542,6 +1149,221 @@ impl MultiHarpDevice for DebugMultiHarp150 {
     fn get_serial(&self) -> String {
         self.serial.clone()
     }
+
+    fn get_measurement_mode(&self) -> MeasurementMode {
+        self._measurement_mode
+    }
+
+    fn get_reference_clock(&self) -> mhconsts::ReferenceClock {
+        self._reference_clock
+    }
+
+    fn get_offset(&self) -> i32 {
+        self._offset
+    }
+
+    fn get_input_channel_enables(&self) -> MultiHarpResult<Vec<bool>> {
+        Ok(self._input_enables.clone())
+    }
+
+    /// Counted overflows, like a device on `MHLib` 3.1 with a hold time
+    fn overflow_compression(&self) -> OverflowCompression {
+        OverflowCompression::Compressed
+    }
+
+    /// Every setting the debug device keeps. Dead times, the measurement
+    /// control mode and settings it ignores are unknown.
+    fn current_settings(&self) -> MultiHarpConfig {
+        let channels = 0..self._num_channels;
+        MultiHarpConfig {
+            sync_div : Some(self._sync_div),
+            sync_trigger_edge : Some((self._sync_level, self._sync_edge)),
+            sync_channel_offset : Some(self._sync_offset),
+            input_edges : Some(channels.clone()
+                .map(|c| (c, self._input_levels[c as usize], self._input_edges[c as usize])).collect()),
+            input_offsets : Some(channels.clone().map(|c| (c, self._input_offsets[c as usize])).collect()),
+            input_enables : Some(channels.map(|c| (c, self._input_enables[c as usize])).collect()),
+            binning : Some(self._binning),
+            offset : Some(self._offset),
+            histo_len : Some(self._histogram_len),
+            trigger_output : Some(self._trigger_output),
+            #[cfg(feature = "MHLv3_1_0")]
+            ofl_compression : Some(self._hold_time),
+            marker_edges : Some(self._marker_edges),
+            marker_enable : Some(self._marker_enables),
+            marker_holdoff : Some(self._marker_holdoff),
+            ..Default::default()
+        }
+    }
+
+    fn get_flags(&self) -> MultiHarpResult<i32> {
+        Ok(self._flags)
+    }
+
+    fn get_features(&self) -> MultiHarpResult<i32> {
+        Ok(self._features)
+    }
+
+    /// The host time at which the last measurement started
+    fn get_start_time(&self) -> MultiHarpResult<(u32, u32, u32)> {
+        let ps = self._start_time.duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_nanos() * 1000;
+        Ok(((ps >> 64) as u32, (ps >> 32) as u32, ps as u32))
+    }
+
+    /// Describes the simulated state, in place of the device's
+    /// firmware log
+    fn get_debug_info(&self) -> MultiHarpResult<String> {
+        Ok(format!(
+            "DebugMultiHarp150 #{}: flags {:#06x}, {:?} mode, measuring: {}",
+            self.serial, self._flags, self._measurement_mode, self._ctc_status
+        ))
+    }
+    /// Stores the MAC address, which is reported with `WR_STATUS_MAC_SET`
+    /// from then on.
+    fn wrabbit_set_mac(&mut self, mac : &str) -> CheckedResult<(), usize> {
+        if mac.len() != mhconsts::WR_MAC_LEN {
+            return Err(PatinaError::ArgumentError(
+                "mac".to_string(),
+                mac.len(),
+                format!("MAC address must be {} characters long", mhconsts::WR_MAC_LEN))
+            );
+        }
+        self._wrabbit.get_mut().mac = Some(mac.to_string());
+        Ok(())
+    }
+
+    /// The MAC address set by `wrabbit_set_mac`, or `DEBUG_WR_MAC`
+    fn wrabbit_get_mac(&self) -> CheckedResult<String, i32> {
+        Ok(self._wrabbit.borrow().mac.clone().unwrap_or(DEBUG_WR_MAC.to_string()))
+    }
+
+    fn wrabbit_set_init_script(&mut self, script : &str) -> CheckedResult<(), i32> {
+        if script.len() >= mhconsts::WR_SCRIPT_LEN {
+            return Err(PatinaError::ArgumentError(
+                "script".to_string(),
+                script.len() as i32,
+                format!("Script must be shorter than {} characters", mhconsts::WR_SCRIPT_LEN))
+            );
+        }
+        self._wrabbit.get_mut().init_script = script.to_string();
+        Ok(())
+    }
+
+    fn wrabbit_get_init_script(&self) -> CheckedResult<String, i32> {
+        Ok(self._wrabbit.borrow().init_script.clone())
+    }
+
+    /// Switches the simulated White Rabbit link on or off.
+    fn set_wrabbit_link(&mut self, on : bool) -> CheckedResult<(), i32> {
+        let wrabbit = self._wrabbit.get_mut();
+        match (on, wrabbit.link_on_since) {
+            (true, None) => wrabbit.link_on_since = Some(Instant::now()),
+            (false, _) => wrabbit.link_on_since = None,
+            _ => {},
+        }
+        Ok(())
+    }
+
+    /// Sets the mode of the simulated White Rabbit core. Changing the
+    /// mode restarts locking. `boot_from_script` is ignored.
+    fn set_wrabbit_mode(&mut self, _boot_from_script : bool, reinit_with_mode : bool, mode : WRMode) -> CheckedResult<(), i32> {
+        if !reinit_with_mode {
+            return Ok(());
+        }
+        let wrabbit = self._wrabbit.get_mut();
+        wrabbit.mode = mode;
+        if wrabbit.link_on_since.is_some() {
+            wrabbit.link_on_since = Some(Instant::now());
+        }
+        Ok(())
+    }
+
+    /// Sets the White Rabbit time in seconds since the epoch.
+    fn set_wrabbit_time(&mut self, time_high_dw : u32, time_low_dw : u32) -> CheckedResult<(), i32> {
+        self._wrabbit.get_mut().utc = (
+            ((time_high_dw as u64) << 32) | time_low_dw as u64,
+            Instant::now(),
+        );
+        Ok(())
+    }
+
+    /// The White Rabbit time, advancing with the (drifting) device clock.
+    fn get_wrabbit_time(&self) -> CheckedResult<(u32, u32, u32), i32> {
+        let (seconds, since) = self._wrabbit.borrow().utc;
+        let elapsed = self.device_duration(since.elapsed());
+        let seconds = seconds + elapsed.as_secs();
+        let subsec_16_ns = elapsed.subsec_nanos() / 16;
+        Ok(((seconds >> 32) as u32, seconds as u32, subsec_16_ns))
+    }
+
+    fn ext_fpga_init_link(&mut self, link : i32, on : bool) -> CheckedResult<(), i32> {
+        if on {
+            self._ext_fpga.links_on.insert(link);
+        } else {
+            self._ext_fpga.links_on.remove(&link);
+        }
+        audit::log_setting(&self.serial, &format!("ext_fpga_link_{}", link), None, on);
+        Ok(())
+    }
+
+    /// 1 while the link is on, 0 if not
+    fn ext_fpga_link_status(&self, link : i32) -> CheckedResult<u32, i32> {
+        Ok(self._ext_fpga.links_on.contains(&link) as u32)
+    }
+
+    fn ext_fpga_set_mode(&mut self, mode : ExtFpgaMode, loopback : ExtFpgaLoopback) -> CheckedResult<(), i32> {
+        self._ext_fpga.mode = (mode, loopback);
+        audit::log_setting(&self.serial, "ext_fpga_mode", None, (mode, loopback));
+        Ok(())
+    }
+
+    fn ext_fpga_reset_stream_fifos(&mut self) -> CheckedResult<(), i32> {
+        Ok(())
+    }
+
+    /// Writes `data` to the register `addr`, or reads it (0 if never
+    /// written), returning the register's value.
+    fn ext_fpga_user_command(&mut self, write : bool, addr : u32, data : u32) -> CheckedResult<u32, i32> {
+        let register = self._ext_fpga.registers.entry(addr).or_insert(0);
+        if write {
+            *register = data;
+        }
+        Ok(*register)
+    }
+
+    /// Once the init script ends with `gui` and the core is on, redraws
+    /// a status screen on every call, with the escape sequences of the
+    /// real core.
+    fn get_wrabbit_term_output(&self) -> CheckedResult<String, i32> {
+        let wrabbit = self._wrabbit.borrow();
+        let gui = wrabbit.init_script.lines().last().map(|l| l.trim() == "gui").unwrap_or(false);
+        if !gui || wrabbit.mode == WRMode::Off {
+            return Ok(String::new());
+        }
+        Ok(format!(
+            "\x1b[2J\x1b[1;1H\x1b[1;34mWR PTP Core Sync Monitor (DebugMultiHarp150 #{})\x1b[0m\r\n\r\nStatus: {}\r\n",
+            self.serial, WrStatus::from_bits(wrabbit.status_at(Instant::now()))
+        ))
+    }
+
+    /// `WR_STATUS_IS_NEW` is set if the status changed since the last call.
+    fn get_wrabbit_status(&self) -> CheckedResult<WrStatus, i32> {
+        let mut wrabbit = self._wrabbit.borrow_mut();
+        let status = wrabbit.status_at(Instant::now());
+        let is_new = status != wrabbit.last_status;
+        wrabbit.last_status = status;
+        Ok(WrStatus::from_bits(if is_new { status | mhconsts::WR_STATUS_IS_NEW as i32 } else { status }))
+    }
+}
+
+/// Reading the simulated FIFO only copies from memory, so it doesn't
+/// need the blocking pool.
+#[cfg(feature = "async")]
+#[async_trait]
+impl AsyncMultiHarpDevice for DebugMultiHarp150 {
+    async fn read_fifo_async(&mut self, buffer : &mut Vec<u32>) -> CheckedResult<i32, u32> {
+        self.read_fifo(buffer)
+    }
 }
 
 impl Drop for DebugMultiHarp150 {
@@ -554,9 +1376,246 @@ impl Drop for DebugMultiHarp150 {
 
 #[cfg(test)]
 mod tests {
-    use crate::MultiHarpDevice;
+    use std::time::Duration;
+
+    use crate::{mhconsts, ChannelMask, MultiHarpDevice, PatinaError, MeasurementMode, ReferenceClock, WRMode, WrServoState};
+
+    use super::{DebugMultiHarp150, DebugFifo, DEBUG_FIFO_LATENCY, DEBUG_TRANSFER_BLOCK};
 
-    use super::DebugMultiHarp150;
+    #[test]
+    fn test_debug_model() {
+        let mh = DebugMultiHarp150::default();
+        let model = mh.model().unwrap();
+        assert_eq!(model, crate::DeviceModel::MultiHarp150_4P);
+        assert_eq!(model.capabilities().unwrap().max_channels, mh.num_input_channels().unwrap());
+        assert_eq!(mh.to_string(), "MultiHarp 150 4P #1044272 (4 channels, firmware 0.0)");
+        assert_eq!(mh.limits().unwrap().max_channels, 4);
+    }
+
+    #[test]
+    fn test_wrong_mode_guards() {
+        let mut mh = DebugMultiHarp150::default();
+        let mut buffer = vec![0u32; crate::TTREADMAX];
+        let mut histogram = vec![0u32; crate::MAXHISTLEN];
+
+        // Opens in T3
+        assert!(matches!(
+            mh.fill_histogram(&mut histogram, 0),
+            Err(PatinaError::WrongMode(_, MeasurementMode::T3))
+        ));
+        assert!(mh.read_fifo(&mut buffer).is_ok());
+
+        mh.init(MeasurementMode::Histogramming, ReferenceClock::Internal).unwrap();
+        assert_eq!(mh.get_measurement_mode(), MeasurementMode::Histogramming);
+        assert!(matches!(
+            mh.read_fifo(&mut buffer),
+            Err(PatinaError::WrongMode(_, MeasurementMode::Histogramming))
+        ));
+        assert!(mh.fill_histogram(&mut histogram, 0).is_ok());
+    }
+
+    #[test]
+    fn test_event_filters() {
+        let mut mh = DebugMultiHarp150::default();
+        mh.init(MeasurementMode::T2, ReferenceClock::Internal).unwrap();
+        let (_, unfiltered) = mh.get_all_count_rates().unwrap();
+
+        // Set but not enabled
+        let (used, passed) = (ChannelMask::from_channels(&[0, 1]).unwrap(), ChannelMask::from_channels(&[2]).unwrap());
+        mh.set_row_event_filter(0, 1000, 1, false, used, passed).unwrap();
+        assert_eq!(mh.get_row_filtered_rates().unwrap().1, unfiltered);
+
+        mh.enable_row_event_filter(0, true).unwrap();
+        let (_, rows) = mh.get_row_filtered_rates().unwrap();
+        assert!(rows[0] < unfiltered[0]);
+        assert_eq!(rows[2], unfiltered[2]);
+        assert_eq!(rows[3], 0);
+        assert!(mh.set_row_event_filter(9, 1000, 1, false, used, passed).is_err());
+        assert!(mh.set_row_event_filter(0, 1000, 1, false, used.with_sync(), passed).is_err());
+
+        // The Main Filter sees what the Row Filters pass
+        mh.set_main_event_filter(&crate::MainEventFilter::coincidences(&[3], 1000)).unwrap();
+        mh.enable_main_event_filter(true).unwrap();
+        assert_eq!(mh.get_main_filtered_rates().unwrap().1[3], 0);
+
+        mh.set_row_event_filters(&crate::MainEventFilter::coincidences(&[], 1000)).unwrap();
+        assert_eq!(mh.get_row_filtered_rates().unwrap().1, unfiltered);
+    }
+
+    #[test]
+    fn test_reference_lost() {
+        let mut mh = DebugMultiHarp150::default();
+        mh.init(MeasurementMode::T3, ReferenceClock::PpsGps).unwrap();
+        mh.set_flags(crate::FLAG_REF_LOST);
+        assert!(!mh.clock_status().unwrap().is_locked());
+        assert_eq!(
+            mh.start_measurement(100),
+            Err(PatinaError::ReferenceLost(ReferenceClock::PpsGps))
+        );
+
+        // Irrelevant to the internal clock
+        mh.init(MeasurementMode::T3, ReferenceClock::Internal).unwrap();
+        mh.start_measurement(100).unwrap();
+        mh.stop_measurement().unwrap();
+    }
+
+    #[test]
+    fn test_system_error() {
+        let mut mh = DebugMultiHarp150::default();
+        mh.set_flags(crate::FLAG_SYSERROR);
+        let error = mh.start_measurement(100).unwrap_err();
+        assert!(matches!(error, PatinaError::HardwareError(None, _)));
+        assert!(error.debug_info().unwrap().contains("flags 0x0010"));
+
+        mh.set_flags(0);
+        mh.start_measurement(100).unwrap();
+        mh.stop_measurement().unwrap();
+    }
+
+    #[test]
+    fn test_missing_feature_policy() {
+        use crate::{FeatureMasks, MissingFeaturePolicy, MultiHarpConfig};
+
+        let mut mh = DebugMultiHarp150::default();
+        mh.set_features(mhconsts::ALL_FEATURES & !(FeatureMasks::ProgTd as i32) & !(FeatureMasks::TrigOut as i32));
+        let config = MultiHarpConfig {
+            binning : Some(3),
+            sync_dead_time : Some((true, 800)),
+            input_dead_times : Some(vec![(0, true, 800)]),
+            trigger_output : Some(100),
+            ..Default::default()
+        };
+
+        match mh.set_from_config_with_policy(&config, MissingFeaturePolicy::Error) {
+            Err(PatinaError::FeatureNotAvailable(message)) => {
+                assert!(message.contains("ProgTd (for `sync_dead_time`)"));
+                assert!(message.contains("TrigOut (for `trigger_output`)"));
+            },
+            other => panic!("Expected FeatureNotAvailable, got {:?}", other),
+        }
+        assert_eq!(mh.current_settings().binning, Some(0));
+
+        let skipped = mh.set_from_config_with_policy(&config, MissingFeaturePolicy::SkipWithWarning).unwrap();
+        assert_eq!(skipped, vec!["sync_dead_time", "input_dead_times", "trigger_output"]);
+        assert_eq!(mh.current_settings().binning, Some(3));
+        assert_eq!(mh.get_trigger_output(), 0);
+        assert!(mh.recent_events().iter().any(|event| event.message.contains("Skipping `trigger_output`")));
+
+        mh.set_features(mhconsts::ALL_FEATURES);
+        assert!(mh.set_from_config_with_policy(&config, MissingFeaturePolicy::Error).unwrap().is_empty());
+        assert_eq!(mh.get_trigger_output(), 100);
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_read_fifo_async() {
+        use crate::multiharp::AsyncMultiHarpDevice;
+
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let mut mh = DebugMultiHarp150::default();
+        let mut buffer = vec![0u32; crate::TTREADMAX];
+        mh.start_measurement(200).unwrap();
+        let mut read = 0;
+        runtime.block_on(async {
+            while mh.ctc_status().unwrap() {
+                read += mh.read_fifo_async(&mut buffer).await.unwrap();
+            }
+            read += mh.read_fifo_async(&mut buffer).await.unwrap();
+        });
+        assert!(read > 0);
+
+        let mut short = vec![0u32; 8];
+        assert!(matches!(
+            runtime.block_on(mh.read_fifo_async(&mut short)),
+            Err(PatinaError::ArgumentError(..))
+        ));
+    }
+
+    #[test]
+    fn test_wrabbit_lock_and_drift() {
+        let mut mh = DebugMultiHarp150::default();
+        mh.set_sync_rate(80e6);
+        mh.set_clock_drift_ppm(50.0);
+        assert_eq!(mh.get_sync_rate().unwrap(), (80e6 / (1.0 + 50e-6)) as i32);
+
+        mh.set_wrabbit_lock_time(Duration::from_millis(70));
+        mh.set_wrabbit_mode(false, true, WRMode::Slave).unwrap();
+        assert_eq!(
+            mh.get_wrabbit_status().unwrap().bits,
+            mhconsts::WR_STATUS_MODE_SLAVE | mhconsts::WR_STATUS_IS_NEW as i32
+        );
+
+        mh.set_wrabbit_link(true).unwrap();
+        let status = mh.get_wrabbit_status().unwrap();
+        assert!(status.is_new);
+        assert!(status.link_on);
+        assert!(!status.locked);
+        assert_eq!(mh.clock_drift_ppm(), 50.0);
+
+        std::thread::sleep(Duration::from_millis(100));
+        let status = mh.get_wrabbit_status().unwrap();
+        assert!(status.locked);
+        assert_eq!(status.servo, WrServoState::TrackPhase);
+        // Unchanged since the last read
+        assert!(!mh.get_wrabbit_status().unwrap().is_new);
+        assert_eq!(mh.clock_drift_ppm(), 0.0);
+
+        mh.lose_wrabbit_lock();
+        assert!(!mh.get_wrabbit_status().unwrap().locked);
+        assert_eq!(mh.clock_drift_ppm(), 50.0);
+
+        mh.set_wrabbit_time(0, 1_000).unwrap();
+        let (high, low, _) = mh.get_wrabbit_time().unwrap();
+        assert_eq!((high, low), (0, 1_000));
+    }
+
+    #[test]
+    fn test_fifo_hold_time() {
+        let hold = Duration::from_millis(20);
+        let start = std::time::Instant::now();
+        let at = |ms : u64| start + Duration::from_millis(ms);
+        let mut fifo = DebugFifo::with_capacity(0);
+
+        // A trickle is held until the oldest record has waited `hold`...
+        for ms in [0, 5, 10] {
+            fifo.records.push(ms as u32);
+            fifo.arrived(at(ms));
+        }
+        fifo.transfer(at(1), hold, false);
+        assert_eq!(fifo.transferred, 0);
+        fifo.transfer(at(19), hold, false);
+        assert_eq!(fifo.transferred, 0);
+        // ...then everything past the latency goes at once
+        fifo.transfer(at(20), hold, false);
+        assert_eq!(fifo.transferred, 3);
+        fifo.take(3);
+        assert!(fifo.records.is_empty() && fifo.arrivals.is_empty());
+
+        // Records younger than the latency never go, whether held or not
+        fifo.records.push(30);
+        fifo.arrived(at(30));
+        fifo.transfer(at(30) + DEBUG_FIFO_LATENCY / 2, Duration::ZERO, false);
+        assert_eq!(fifo.transferred, 0);
+        fifo.transfer(at(30) + DEBUG_FIFO_LATENCY, Duration::ZERO, false);
+        assert_eq!(fifo.transferred, 1);
+        fifo.take(1);
+
+        // A full block doesn't wait
+        fifo.records.extend(0..DEBUG_TRANSFER_BLOCK as u32);
+        fifo.arrived(at(40));
+        fifo.records.push(0);
+        fifo.arrived(at(45));
+        fifo.transfer(at(42), hold, false);
+        assert_eq!(fifo.transferred, DEBUG_TRANSFER_BLOCK);
+        fifo.take(DEBUG_TRANSFER_BLOCK);
+        assert_eq!(fifo.arrivals.len(), 1);
+        fifo.transfer(at(46), hold, true);
+        assert_eq!(fifo.transferred, 1);
+
+        let mut mh = DebugMultiHarp150::default();
+        assert!(mh.set_overflow_compression(mhconsts::HOLDTIMEMAX + 1).is_err());
+        mh.set_overflow_compression(0).unwrap();
+    }
 
     #[test]
     fn test_basic_debug_multiharp(){
@@ -0,0 +1,24 @@
+//! Values shared by the tests of the crate, so that a change to one of
+//! the types they build only has to be made here.
+
+use crate::mhconsts::MeasurementMode;
+use crate::pipeline::StreamInfo;
+use crate::tttr::OverflowCompression;
+
+/// Serial number of the devices the test streams come from
+pub(crate) const SERIAL : &str = "1044272";
+
+/// A `mode` stream from a 4 channel device with every channel enabled,
+/// 5 ps bins, compressed overflows and no labels. Tests needing
+/// something else change it with struct update syntax.
+pub(crate) fn stream_info(mode : MeasurementMode) -> StreamInfo {
+    StreamInfo {
+        serial : SERIAL.to_string(),
+        mode,
+        resolution : 5.0,
+        num_channels : 4,
+        enabled_channels : vec![true; 4],
+        overflow_compression : OverflowCompression::Compressed,
+        labels : Default::default(),
+    }
+}
@@ -0,0 +1,242 @@
+//! Golden files guarding the decoding and analysis stack against
+//! regressions. Each case is a small record stream in
+//! `testdata/golden/<case>.bin` (little-endian `u32`s, as read from the
+//! FIFO) generated by the `Encoder` from a seeded event list, with the
+//! decoded events in `<case>.events.csv` and the output of an analysis
+//! processor run on it through a `Pipeline`: the sparse histograms for
+//! T3, the marker timestamps for T2.
+//!
+//! Every output is compared byte for byte. After an intended change in
+//! the output, regenerate the files with
+//! `MHP_BLESS_GOLDEN=1 cargo test golden` and review the diff.
+
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::histogram::{SparseHistogrammer, write_sparse_histograms_csv};
+use crate::markers::MarkerCounter;
+use crate::mhconsts::MeasurementMode;
+use crate::pipeline::{Batch, Pipeline, StreamInfo, StreamProcessor, ProcessorResult};
+use crate::tttr::{Decoder, Encoder, EventKind, TttrEvent};
+use crate::testing::fixtures::stream_info;
+
+/// Records per batch passed through the pipeline, small enough that
+/// overflows carry across batches
+const BATCH_LEN : usize = 97;
+
+fn golden_path(file : &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("testdata").join("golden").join(file)
+}
+
+/// Compares `actual` with the golden `file`, or replaces the file with
+/// it when `MHP_BLESS_GOLDEN` is set.
+fn check_golden(file : &str, actual : &[u8]) {
+    let path = golden_path(file);
+    if std::env::var_os("MHP_BLESS_GOLDEN").is_some() {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, actual).unwrap();
+        return;
+    }
+    let expected = std::fs::read(&path)
+        .unwrap_or_else(|e| panic!("{}: {} (set MHP_BLESS_GOLDEN to create it)", path.display(), e));
+    if expected != actual {
+        let first = expected.iter().zip(actual).position(|(e, a)| e != a)
+            .unwrap_or(expected.len().min(actual.len()));
+        panic!(
+            "{} differs from the output at byte {} ({} bytes expected, {} produced)",
+            path.display(), first, expected.len(), actual.len()
+        );
+    }
+}
+
+/// xorshift64, so the event lists never depend on `rand`'s algorithms
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n : u64) -> u64 {
+        self.next() % n
+    }
+}
+
+/// Photons on four channels with a two-exponential decay, markers every
+/// few hundred syncs, and gaps long enough for compressed overflows.
+fn t3_events() -> Vec<TttrEvent> {
+    let mut rng = Rng(0x5EED_0003);
+    let mut events = Vec::new();
+    let mut nsync = 0u64;
+    for i in 0..2000u64 {
+        nsync += 1 + rng.below(40);
+        if i % 500 == 499 {
+            // Several rollovers at once
+            nsync += 3 * 1024 + rng.below(1024);
+        }
+        if i % 150 == 0 {
+            events.push(TttrEvent::marker(1 + rng.below(15) as u8, nsync));
+        }
+        let tau = if rng.below(4) == 0 { 2000.0 } else { 400.0 };
+        let uniform = (rng.below(1 << 20) as f64 + 0.5) / (1 << 20) as f64;
+        let dtime = (100.0 - tau * uniform.ln()).min(32767.0) as u16;
+        events.push(TttrEvent::photon(rng.below(4) as u8, nsync, dtime));
+    }
+    events
+}
+
+/// Syncs every 2500 bins with jittered photons and markers, crossing
+/// several timetag rollovers.
+fn t2_events() -> Vec<TttrEvent> {
+    let mut rng = Rng(0x5EED_0002);
+    let mut events = Vec::new();
+    let mut sync = 0u64;
+    for i in 0..3000u64 {
+        sync += 2500;
+        if i % 1000 == 999 {
+            sync += 40_000_000 + rng.below(1 << 24);
+        }
+        events.push(TttrEvent::sync(sync));
+        if rng.below(3) == 0 {
+            events.push(TttrEvent::photon(rng.below(4) as u8, sync + 100 + rng.below(2000), 0));
+        }
+        if i % 400 == 0 {
+            events.push(TttrEvent::marker(1 << rng.below(4), sync + 2400));
+        }
+    }
+    events
+}
+
+fn to_bytes(records : &[u32]) -> Vec<u8> {
+    records.iter().flat_map(|r| r.to_le_bytes()).collect()
+}
+
+fn from_bytes(bytes : &[u8]) -> Vec<u32> {
+    bytes.chunks_exact(4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect()
+}
+
+fn events_csv(events : &[TttrEvent]) -> Vec<u8> {
+    let mut csv = String::from("kind,channel,time,dtime\n");
+    for event in events {
+        let kind = match event.kind {
+            EventKind::Photon => "photon",
+            EventKind::Marker => "marker",
+            EventKind::Sync => "sync",
+        };
+        csv.push_str(&format!("{},{},{},{}\n", kind, event.channel, event.time, event.dtime));
+    }
+    csv.into_bytes()
+}
+
+/// Decodes each batch it is given, as an acquisition program would
+#[derive(Default)]
+struct DecodedEvents {
+    decoder : Option<Decoder>,
+    events : Vec<TttrEvent>,
+}
+
+impl StreamProcessor for DecodedEvents {
+    fn name(&self) -> &str { "decoded_events" }
+
+    fn init(&mut self, info : &StreamInfo) -> ProcessorResult {
+        self.decoder = Some(Decoder::for_stream(info)?);
+        self.events.clear();
+        Ok(())
+    }
+
+    fn process_batch(&mut self, batch : &Batch) -> ProcessorResult {
+        let decoder = self.decoder.as_mut().ok_or("process_batch called before init")?;
+        self.events.extend(decoder.decode_all(batch.records));
+        Ok(())
+    }
+}
+
+/// A processor added to a `Pipeline` that can still be read afterwards
+struct Shared<P> {
+    name : String,
+    inner : Arc<Mutex<P>>,
+}
+
+impl<P : StreamProcessor> Shared<P> {
+    fn new(processor : P) -> (Self, Arc<Mutex<P>>) {
+        let inner = Arc::new(Mutex::new(processor));
+        let name = inner.lock().unwrap().name().to_string();
+        (Shared { name, inner : Arc::clone(&inner) }, inner)
+    }
+}
+
+impl<P : StreamProcessor> StreamProcessor for Shared<P> {
+    fn name(&self) -> &str { &self.name }
+
+    fn init(&mut self, info : &StreamInfo) -> ProcessorResult {
+        self.inner.lock().unwrap().init(info)
+    }
+
+    fn process_batch(&mut self, batch : &Batch) -> ProcessorResult {
+        self.inner.lock().unwrap().process_batch(batch)
+    }
+
+    fn finalize(&mut self) -> ProcessorResult {
+        self.inner.lock().unwrap().finalize()
+    }
+}
+
+/// Feeds `records` through `pipeline` in batches of `BATCH_LEN`.
+fn run_pipeline(pipeline : &mut Pipeline, info : &StreamInfo, records : &[u32]) {
+    pipeline.init(info).unwrap();
+    for (index, chunk) in records.chunks(BATCH_LEN).enumerate() {
+        pipeline.process_batch(&Batch {
+            index : index as u64,
+            read_at : Instant::now(),
+            records : chunk,
+            mode : info.mode,
+            sync_lost : false,
+        }).unwrap();
+    }
+    pipeline.finalize().unwrap();
+}
+
+/// Checks the encoding of `events`, then runs the golden records through
+/// a pipeline with `processor` and checks the events it decodes. Returns
+/// `processor` for its own output to be checked.
+fn check_case<P>(case : &str, mode : MeasurementMode, events : &[TttrEvent], processor : P) -> Arc<Mutex<P>>
+where P : StreamProcessor + 'static {
+    let records = Encoder::new(mode).unwrap().encode_all(events).unwrap();
+    check_golden(&format!("{}.bin", case), &to_bytes(&records));
+    check_golden(&format!("{}.events.csv", case), &events_csv(events));
+
+    // Decode the file rather than the fresh encoding, so a change in the
+    // encoder can't hide a change in the decoder
+    let golden = from_bytes(&std::fs::read(golden_path(&format!("{}.bin", case))).unwrap());
+    let (decoded, decoded_events) = Shared::new(DecodedEvents::default());
+    let (processor, output) = Shared::new(processor);
+    let mut pipeline = Pipeline::new();
+    pipeline.add(decoded).add(processor);
+    run_pipeline(&mut pipeline, &StreamInfo { serial : "golden".to_string(), ..stream_info(mode) }, &golden);
+    check_golden(&format!("{}.events.csv", case), &events_csv(&decoded_events.lock().unwrap().events));
+    output
+}
+
+#[test]
+fn test_golden_t3() {
+    let histogrammer = check_case("t3_basic", MeasurementMode::T3, &t3_events(), SparseHistogrammer::new());
+
+    // The acquisition time is the only nondeterministic output
+    let mut histograms = histogrammer.lock().unwrap().histograms().to_vec();
+    histograms.iter_mut().for_each(|h| h.acquired_for = Duration::ZERO);
+    let mut csv = Vec::new();
+    write_sparse_histograms_csv(&histograms, &mut csv).unwrap();
+    check_golden("t3_basic.histograms.csv", &csv);
+}
+
+#[test]
+fn test_golden_t2() {
+    let markers = check_case("t2_basic", MeasurementMode::T2, &t2_events(), MarkerCounter::new(1).unwrap());
+    let mut csv = Vec::new();
+    markers.lock().unwrap().write_csv(&mut csv).unwrap();
+    check_golden("t2_basic.marker1.csv", &csv);
+}
@@ -0,0 +1,304 @@
+//! `proptest` strategies generating MultiHarp TTTR records, for
+//! checking that code consuming `read_fifo` buffers handles
+//! everything the hardware can emit (and some things it shouldn't).
+//!
+//! Each strategy produces a `Vec` of record specs (`T3Spec` or `T2Spec`)
+//! that know both the fields they were built from and their raw `u32`
+//! encoding, so decoded output can be compared against the ground truth.
+//! Available with the `proptest` feature.
+//!
+//! ## Example
+//!
+//! ```ignore
+//! use multi_harp_patina::records::*;
+//! use proptest::prelude::*;
+//!
+//! proptest! {
+//!     #[test]
+//!     fn my_decoder_never_panics(stream in t3_adversarial_stream(4, 512)) {
+//!         let raw = t3_to_raw(&stream);
+//!         my_decoder(&raw);
+//!     }
+//! }
+//! ```
+
+use proptest::prelude::*;
+use proptest::collection::vec;
+
+pub use crate::tttr::{OVERFLOW_CHANNEL, MAX_MARKER_CHANNEL, T3_WRAPAROUND, T2_WRAPAROUND};
+use crate::tttr::{pack_t2, pack_t3};
+
+/// A single T3 record, described by the fields it encodes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum T3Spec {
+    /// Photon on input `channel` (0-indexed), `dtime` bins after
+    /// the `nsync`th sync since the last overflow.
+    Photon { channel : u8, dtime : u16, nsync : u16 },
+    /// Marker bitfield (1..=15) at `nsync`
+    Marker { markers : u8, nsync : u16 },
+    /// `count` sync rollovers. Overflow compression (v3.1) packs several
+    /// into one record, a count of 0 is emitted by older firmware and
+    /// means a single rollover.
+    Overflow { count : u16 },
+    /// A special record with a channel code the hardware never
+    /// emits (16..=62). Decoders should reject or skip these.
+    Reserved { channel : u8, dtime : u16, nsync : u16 },
+}
+
+impl T3Spec {
+    /// The raw record as read from the FIFO
+    pub fn to_raw(&self) -> u32 {
+        match *self {
+            T3Spec::Photon { channel, dtime, nsync } => pack_t3(false, channel, dtime, nsync),
+            T3Spec::Marker { markers, nsync } => pack_t3(true, markers, 0, nsync),
+            T3Spec::Overflow { count } => pack_t3(true, OVERFLOW_CHANNEL, 0, count),
+            T3Spec::Reserved { channel, dtime, nsync } => pack_t3(true, channel, dtime, nsync),
+        }
+    }
+
+    /// Number of sync rollovers this record represents
+    pub fn overflows(&self) -> u64 {
+        match *self {
+            T3Spec::Overflow { count : 0 } => 1,
+            T3Spec::Overflow { count } => count as u64,
+            _ => 0,
+        }
+    }
+}
+
+/// A single T2 record, described by the fields it encodes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum T2Spec {
+    /// Photon on input `channel` (0-indexed) at `timetag` since the last overflow
+    Photon { channel : u8, timetag : u32 },
+    /// Sync event, recorded as a special record on channel 0
+    Sync { timetag : u32 },
+    /// Marker bitfield (1..=15) at `timetag`
+    Marker { markers : u8, timetag : u32 },
+    /// `count` timetag rollovers, 0 meaning a single rollover
+    Overflow { count : u32 },
+    /// A special record with a channel code the hardware never emits
+    Reserved { channel : u8, timetag : u32 },
+}
+
+impl T2Spec {
+    /// The raw record as read from the FIFO
+    pub fn to_raw(&self) -> u32 {
+        match *self {
+            T2Spec::Photon { channel, timetag } => pack_t2(false, channel, timetag),
+            T2Spec::Sync { timetag } => pack_t2(true, 0, timetag),
+            T2Spec::Marker { markers, timetag } => pack_t2(true, markers, timetag),
+            T2Spec::Overflow { count } => pack_t2(true, OVERFLOW_CHANNEL, count),
+            T2Spec::Reserved { channel, timetag } => pack_t2(true, channel, timetag),
+        }
+    }
+
+    /// Number of timetag rollovers this record represents
+    pub fn overflows(&self) -> u64 {
+        match *self {
+            T2Spec::Overflow { count : 0 } => 1,
+            T2Spec::Overflow { count } => count as u64,
+            _ => 0,
+        }
+    }
+}
+
+/// Raw FIFO words for a generated T3 stream
+pub fn t3_to_raw(stream : &[T3Spec]) -> Vec<u32> {
+    stream.iter().map(T3Spec::to_raw).collect()
+}
+
+/// Raw FIFO words for a generated T2 stream
+pub fn t2_to_raw(stream : &[T2Spec]) -> Vec<u32> {
+    stream.iter().map(T2Spec::to_raw).collect()
+}
+
+/// For each record, the sync count since the start of the stream
+/// (`overflows * 1024 + nsync`) for photons and markers, `None` otherwise.
+pub fn t3_absolute_nsync(stream : &[T3Spec]) -> Vec<Option<u64>> {
+    let mut base = 0u64;
+    stream.iter().map(|record| {
+        base += record.overflows() * T3_WRAPAROUND;
+        match *record {
+            T3Spec::Photon { nsync, .. } | T3Spec::Marker { nsync, .. } => Some(base + nsync as u64),
+            _ => None,
+        }
+    }).collect()
+}
+
+/// For each record, the timetag since the start of the stream for
+/// photons, syncs and markers, `None` otherwise.
+pub fn t2_absolute_timetag(stream : &[T2Spec]) -> Vec<Option<u64>> {
+    let mut base = 0u64;
+    stream.iter().map(|record| {
+        base += record.overflows() * T2_WRAPAROUND;
+        match *record {
+            T2Spec::Photon { timetag, .. }
+            | T2Spec::Sync { timetag }
+            | T2Spec::Marker { timetag, .. } => Some(base + timetag as u64),
+            _ => None,
+        }
+    }).collect()
+}
+
+/// Photon on one of the first `num_channels` inputs
+pub fn t3_photon(num_channels : u8) -> impl Strategy<Value = T3Spec> {
+    (0..num_channels.max(1), 0..(1u16 << 15), 0..(1u16 << 10))
+        .prop_map(|(channel, dtime, nsync)| T3Spec::Photon { channel, dtime, nsync })
+}
+
+/// Any record a correctly operating device can emit in T3 mode,
+/// mostly photons.
+pub fn t3_record(num_channels : u8) -> impl Strategy<Value = T3Spec> {
+    prop_oneof![
+        8 => t3_photon(num_channels),
+        1 => (1..=MAX_MARKER_CHANNEL, 0..(1u16 << 10))
+            .prop_map(|(markers, nsync)| T3Spec::Marker { markers, nsync }),
+        1 => (0..(1u16 << 10)).prop_map(|count| T3Spec::Overflow { count }),
+    ]
+}
+
+/// A valid T3 stream of up to `max_len` records
+pub fn t3_stream(num_channels : u8, max_len : usize) -> impl Strategy<Value = Vec<T3Spec>> {
+    vec(t3_record(num_channels), 0..max_len)
+}
+
+/// T3 streams that stress decoders: long runs of overflows, bursts of
+/// markers at the same sync, photons on channels above `num_channels`
+/// and reserved special channel codes.
+pub fn t3_adversarial_stream(num_channels : u8, max_len : usize) -> impl Strategy<Value = Vec<T3Spec>> {
+    let chunk = prop_oneof![
+        4 => vec(t3_record(num_channels), 1..16),
+        1 => vec((0..(1u16 << 10)).prop_map(|count| T3Spec::Overflow { count }), 1..64),
+        1 => (0..(1u16 << 10), vec(1..=MAX_MARKER_CHANNEL, 1..32))
+            .prop_map(|(nsync, markers)| markers.into_iter()
+                .map(|markers| T3Spec::Marker { markers, nsync })
+                .collect::<Vec<_>>()),
+        1 => vec((0..64u8, 0..(1u16 << 15), 0..(1u16 << 10))
+            .prop_map(|(channel, dtime, nsync)| T3Spec::Photon { channel, dtime, nsync }), 1..8),
+        1 => vec((MAX_MARKER_CHANNEL + 1..OVERFLOW_CHANNEL, 0..(1u16 << 15), 0..(1u16 << 10))
+            .prop_map(|(channel, dtime, nsync)| T3Spec::Reserved { channel, dtime, nsync }), 1..4),
+    ];
+    vec(chunk, 0..(max_len / 8).max(1))
+        .prop_map(move |chunks| chunks.into_iter().flatten().take(max_len).collect())
+}
+
+/// Photon on one of the first `num_channels` inputs
+pub fn t2_photon(num_channels : u8) -> impl Strategy<Value = T2Spec> {
+    (0..num_channels.max(1), 0..(1u32 << 25))
+        .prop_map(|(channel, timetag)| T2Spec::Photon { channel, timetag })
+}
+
+/// Any record a correctly operating device can emit in T2 mode
+pub fn t2_record(num_channels : u8) -> impl Strategy<Value = T2Spec> {
+    prop_oneof![
+        6 => t2_photon(num_channels),
+        2 => (0..(1u32 << 25)).prop_map(|timetag| T2Spec::Sync { timetag }),
+        1 => (1..=MAX_MARKER_CHANNEL, 0..(1u32 << 25))
+            .prop_map(|(markers, timetag)| T2Spec::Marker { markers, timetag }),
+        1 => (0..(1u32 << 25)).prop_map(|count| T2Spec::Overflow { count }),
+    ]
+}
+
+/// A valid T2 stream of up to `max_len` records
+pub fn t2_stream(num_channels : u8, max_len : usize) -> impl Strategy<Value = Vec<T2Spec>> {
+    vec(t2_record(num_channels), 0..max_len)
+}
+
+/// T2 counterpart of `t3_adversarial_stream`
+pub fn t2_adversarial_stream(num_channels : u8, max_len : usize) -> impl Strategy<Value = Vec<T2Spec>> {
+    let chunk = prop_oneof![
+        4 => vec(t2_record(num_channels), 1..16),
+        1 => vec((0..1024u32).prop_map(|count| T2Spec::Overflow { count }), 1..64),
+        1 => (0..(1u32 << 25), vec(1..=MAX_MARKER_CHANNEL, 1..32))
+            .prop_map(|(timetag, markers)| markers.into_iter()
+                .map(|markers| T2Spec::Marker { markers, timetag })
+                .collect::<Vec<_>>()),
+        1 => vec((0..64u8, 0..(1u32 << 25))
+            .prop_map(|(channel, timetag)| T2Spec::Photon { channel, timetag }), 1..8),
+        1 => vec((MAX_MARKER_CHANNEL + 1..OVERFLOW_CHANNEL, 0..(1u32 << 25))
+            .prop_map(|(channel, timetag)| T2Spec::Reserved { channel, timetag }), 1..4),
+    ];
+    vec(chunk, 0..(max_len / 8).max(1))
+        .prop_map(move |chunks| chunks.into_iter().flatten().take(max_len).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::multiharp::{
+        photon_special, photon_to_channel, photon_to_arrival_t2,
+        photon_to_arrival_t3, photon_to_sync_counter
+    };
+
+    /// Reconstructs a spec from a raw record using the crate's bit accessors
+    fn decode_t3(raw : u32) -> T3Spec {
+        let (channel, dtime, nsync) = (photon_to_channel(raw), photon_to_arrival_t3(raw), photon_to_sync_counter(raw));
+        if !photon_special(raw) {
+            T3Spec::Photon { channel, dtime, nsync }
+        } else if channel == OVERFLOW_CHANNEL {
+            T3Spec::Overflow { count : nsync }
+        } else if (1..=MAX_MARKER_CHANNEL).contains(&channel) && dtime == 0 {
+            T3Spec::Marker { markers : channel, nsync }
+        } else {
+            T3Spec::Reserved { channel, dtime, nsync }
+        }
+    }
+
+    fn decode_t2(raw : u32) -> T2Spec {
+        let (channel, timetag) = (photon_to_channel(raw), photon_to_arrival_t2(raw));
+        if !photon_special(raw) {
+            T2Spec::Photon { channel, timetag }
+        } else if channel == OVERFLOW_CHANNEL {
+            T2Spec::Overflow { count : timetag }
+        } else if channel == 0 {
+            T2Spec::Sync { timetag }
+        } else if channel <= MAX_MARKER_CHANNEL {
+            T2Spec::Marker { markers : channel, timetag }
+        } else {
+            T2Spec::Reserved { channel, timetag }
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn t3_round_trip(stream in t3_adversarial_stream(16, 256)) {
+            for spec in stream.iter() {
+                prop_assert_eq!(decode_t3(spec.to_raw()), *spec);
+            }
+        }
+
+        #[test]
+        fn t2_round_trip(stream in t2_adversarial_stream(16, 256)) {
+            for spec in stream.iter() {
+                prop_assert_eq!(decode_t2(spec.to_raw()), *spec);
+            }
+        }
+
+        #[test]
+        fn valid_streams_stay_in_range(stream in t3_stream(4, 256)) {
+            for spec in stream.iter() {
+                if let T3Spec::Photon { channel, .. } = spec {
+                    prop_assert!(*channel < 4);
+                }
+                let reserved = matches!(spec, T3Spec::Reserved { .. });
+                prop_assert!(!reserved);
+            }
+        }
+    }
+
+    #[test]
+    fn test_absolute_nsync() {
+        let stream = [
+            T3Spec::Photon { channel : 0, dtime : 5, nsync : 10 },
+            T3Spec::Overflow { count : 0 },
+            T3Spec::Marker { markers : 1, nsync : 3 },
+            T3Spec::Overflow { count : 2 },
+            T3Spec::Photon { channel : 1, dtime : 5, nsync : 1 },
+        ];
+        assert_eq!(
+            t3_absolute_nsync(&stream),
+            vec![Some(10), None, Some(1024 + 3), None, Some(3 * 1024 + 1)]
+        );
+    }
+}
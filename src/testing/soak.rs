@@ -0,0 +1,417 @@
+//! Long-running acquisitions against a `DebugMultiHarp150`, to catch
+//! leaks and drift before they reach real experiments. A `SoakTest`
+//! reads the device with a `ContinuousAcquisition` on a thread of its
+//! own and hands every batch, through a bounded queue, to the pipeline
+//! under test on the calling thread, as an application would. It tracks
+//! the resident memory of the process, the batches dropped because the
+//! pipeline fell behind, and the latency from each read to the end of
+//! its processing.
+//!
+//! The soak test itself is ignored by default:
+//! `MHP_SOAK_SECS=14400 cargo test --release soak -- --ignored --nocapture`
+//! runs it for four hours (`MHP_SOAK_RATE` sets the count rate).
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::continuous::ContinuousAcquisition;
+use crate::mhconsts::{MeasurementMode, ReferenceClock};
+use crate::pipeline::{Batch, Pipeline, PipelineError, ProcessorResult, StreamInfo, StreamProcessor};
+use crate::testing::debug_multiharp::DebugMultiHarp150;
+use crate::MultiHarpDevice;
+
+/// Octaves of microseconds a `LatencyHistogram` spans
+const LATENCY_OCTAVES : usize = 40;
+/// Bins per octave, for percentiles to within about 9%
+const BINS_PER_OCTAVE : usize = 8;
+
+/// Latencies in logarithmic bins, so that hours of batches take a
+/// fixed amount of memory.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LatencyHistogram {
+    /// Bin 0 holds latencies under 1 us, bin `i` those up to `2^(i/8)` us
+    bins : Vec<u64>,
+    count : u64,
+    max : Duration,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LatencyHistogram {
+    pub fn new() -> Self {
+        LatencyHistogram { bins : vec![0; LATENCY_OCTAVES * BINS_PER_OCTAVE + 1], count : 0, max : Duration::ZERO }
+    }
+
+    pub fn record(&mut self, latency : Duration) {
+        let micros = latency.as_secs_f64() * 1e6;
+        let bin = if micros < 1.0 {
+            0
+        } else {
+            ((micros.log2() * BINS_PER_OCTAVE as f64).ceil() as usize).clamp(1, self.bins.len() - 1)
+        };
+        self.bins[bin] += 1;
+        self.count += 1;
+        self.max = self.max.max(latency);
+    }
+
+    /// Latencies recorded
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    /// The latency `quantile` (from 0 to 1) of those recorded exceed,
+    /// rounded up to the edge of its bin. Zero if none were recorded.
+    pub fn percentile(&self, quantile : f64) -> Duration {
+        let rank = ((quantile.clamp(0.0, 1.0) * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bin, &n) in self.bins.iter().enumerate() {
+            seen += n;
+            if seen >= rank && n > 0 {
+                let edge = Duration::from_secs_f64(2f64.powf(bin as f64 / BINS_PER_OCTAVE as f64) * 1e-6);
+                return edge.min(self.max);
+            }
+        }
+        Duration::ZERO
+    }
+}
+
+/// The state of a soak test at one time
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SoakSample {
+    /// Since the test started
+    pub elapsed : Duration,
+    /// Resident memory of the process, where known (Linux)
+    pub resident_bytes : Option<u64>,
+    /// Records processed so far
+    pub records : u64,
+    /// Batches waiting in the queue for the pipeline
+    pub queued : usize,
+    pub dropped_batches : u64,
+}
+
+/// What a `SoakTest` saw.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SoakReport {
+    pub duration : Duration,
+    /// Measurements the acquisition was made of
+    pub measurements : usize,
+    /// Batches passed to the pipeline
+    pub batches : u64,
+    /// Records passed to the pipeline
+    pub records : u64,
+    /// Batches of records read while the queue was full, never processed
+    pub dropped_batches : u64,
+    /// Records in the dropped batches
+    pub dropped_records : u64,
+    /// From each read to the end of its processing
+    pub latency : LatencyHistogram,
+    /// Taken every sample interval, the first just after the start
+    pub samples : Vec<SoakSample>,
+}
+
+impl SoakReport {
+    /// Change in resident memory from the first sample to the last.
+    /// `None` if it isn't known or fewer than two samples were taken.
+    pub fn memory_growth(&self) -> Option<i64> {
+        if self.samples.len() < 2 {
+            return None;
+        }
+        let first = self.samples.first()?.resident_bytes?;
+        let last = self.samples.last()?.resident_bytes?;
+        Some(last as i64 - first as i64)
+    }
+}
+
+impl std::fmt::Display for SoakReport {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "{:?} in {} measurements : {} records in {} batches",
+            self.duration, self.measurements, self.records, self.batches)?;
+        writeln!(f, "Dropped {} batches ({} records)", self.dropped_batches, self.dropped_records)?;
+        writeln!(f, "Latency p50 {:?}, p99 {:?}, p99.9 {:?}, max {:?}",
+            self.latency.percentile(0.5), self.latency.percentile(0.99),
+            self.latency.percentile(0.999), self.latency.max())?;
+        match self.memory_growth() {
+            Some(growth) => write!(f, "Resident memory grew by {} kB", growth / 1024),
+            None => write!(f, "Resident memory unknown"),
+        }
+    }
+}
+
+/// Resident memory of this process, from `/proc/self/status`. `None`
+/// where that isn't available.
+pub fn resident_memory() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kilobytes = line.split_whitespace().nth(1)?.parse::<u64>().ok()?;
+    Some(kilobytes * 1024)
+}
+
+/// A full acquisition against a simulated device, for hours if need be.
+///
+/// ## Example
+///
+/// ```
+/// use std::time::Duration;
+/// use multi_harp_patina::*;
+///
+/// let mut pipeline = Pipeline::new();
+/// pipeline.add(MarkerCounter::new(1).unwrap());
+///
+/// let report = SoakTest::new(Duration::from_millis(300))
+///     .with_segment_time(100)
+///     .with_sample_interval(Duration::from_millis(100))
+///     .run(&mut pipeline)
+///     .unwrap();
+/// assert!(report.records > 0);
+/// println!("{}", report);
+/// ```
+#[derive(Debug, Clone)]
+pub struct SoakTest {
+    duration : Duration,
+    count_rate : f64,
+    sync_rate : f64,
+    mode : MeasurementMode,
+    segment_time : i32,
+    queue_depth : usize,
+    sample_interval : Duration,
+}
+
+impl SoakTest {
+    /// A T3 acquisition lasting `duration`, at 5 Mcps with an 80 MHz sync
+    pub fn new(duration : Duration) -> Self {
+        SoakTest {
+            duration,
+            count_rate : 5e6,
+            sync_rate : 80e6,
+            mode : MeasurementMode::T3,
+            segment_time : 60_000,
+            queue_depth : 1024,
+            sample_interval : Duration::from_secs(10),
+        }
+    }
+
+    /// Mean photon rate of the simulated device, per second
+    pub fn with_count_rate(mut self, count_rate : f64) -> Self {
+        self.count_rate = count_rate;
+        self
+    }
+
+    pub fn with_sync_rate(mut self, sync_rate : f64) -> Self {
+        self.sync_rate = sync_rate;
+        self
+    }
+
+    pub fn with_mode(mut self, mode : MeasurementMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Length of each measurement in ms, to exercise restarts
+    pub fn with_segment_time(mut self, segment_time : i32) -> Self {
+        self.segment_time = segment_time;
+        self
+    }
+
+    /// Batches the queue holds before new ones are dropped
+    pub fn with_queue_depth(mut self, queue_depth : usize) -> Self {
+        self.queue_depth = queue_depth.max(1);
+        self
+    }
+
+    /// Time between `SoakSample`s
+    pub fn with_sample_interval(mut self, sample_interval : Duration) -> Self {
+        self.sample_interval = sample_interval;
+        self
+    }
+
+    /// Runs the acquisition, feeding every batch through `pipeline`.
+    ///
+    /// ## Errors
+    ///
+    /// As `ContinuousAcquisition::run`. A processor error stops the
+    /// acquisition, with the processors still finalized.
+    pub fn run(&self, pipeline : &mut Pipeline) -> Result<SoakReport, PipelineError> {
+        let (queue, batches) = flume::bounded(self.queue_depth);
+        let dropped = Arc::new(Dropped::default());
+        let acquisition = ContinuousAcquisition::new(self.duration).with_segment_time(self.segment_time);
+
+        let (count_rate, sync_rate, mode) = (self.count_rate, self.sync_rate, self.mode);
+        let forwarder = Forwarder { queue, dropped : Arc::clone(&dropped) };
+        let reader = std::thread::spawn(move || {
+            let mut mh = DebugMultiHarp150::new(count_rate, sync_rate, None);
+            mh.init(mode, ReferenceClock::Internal)?;
+            let mut reading = Pipeline::new();
+            reading.add(forwarder);
+            acquisition.run(&mut reading, &mut mh)
+        });
+
+        let started = Instant::now();
+        let mut report = SoakReport {
+            duration : Duration::ZERO,
+            measurements : 0,
+            batches : 0,
+            records : 0,
+            dropped_batches : 0,
+            dropped_records : 0,
+            latency : LatencyHistogram::new(),
+            samples : Vec::new(),
+        };
+        let mut next_sample = started;
+        let mut processed = Ok(());
+        // Ends when the reader is done and drops the queue
+        while let Ok(message) = batches.recv() {
+            match message {
+                Message::Init(info) => processed = pipeline.init(&info),
+                Message::Batch { index, read_at, records, mode, sync_lost } if processed.is_ok() => {
+                    processed = pipeline.process_batch(&Batch { index, read_at, records : &records, mode, sync_lost });
+                    report.latency.record(read_at.elapsed());
+                    report.batches += 1;
+                    report.records += records.len() as u64;
+                },
+                Message::Batch { .. } => {},
+            }
+            if processed.is_err() {
+                break;
+            }
+            if Instant::now() >= next_sample {
+                report.samples.push(SoakSample {
+                    elapsed : started.elapsed(),
+                    resident_bytes : resident_memory(),
+                    records : report.records,
+                    queued : batches.len(),
+                    dropped_batches : dropped.batches.load(Ordering::Relaxed),
+                });
+                next_sample += self.sample_interval;
+            }
+        }
+        // Stops the reader, if a processor failed
+        drop(batches);
+        let finalized = pipeline.finalize();
+        let run = reader.join()
+            .map_err(|_| PipelineError::Panicked("SoakTest".to_string(), "The reading thread panicked".to_string()));
+        processed.and(finalized)?;
+        let run = run??;
+
+        report.duration = started.elapsed();
+        report.measurements = run.segment_starts.len();
+        report.dropped_batches = dropped.batches.load(Ordering::Relaxed);
+        report.dropped_records = dropped.records.load(Ordering::Relaxed);
+        report.samples.push(SoakSample {
+            elapsed : report.duration,
+            resident_bytes : resident_memory(),
+            records : report.records,
+            queued : 0,
+            dropped_batches : report.dropped_batches,
+        });
+        Ok(report)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Dropped {
+    batches : AtomicU64,
+    records : AtomicU64,
+}
+
+/// From the reading thread to the pipeline under test
+enum Message {
+    Init(StreamInfo),
+    Batch { index : u64, read_at : Instant, records : Vec<u32>, mode : MeasurementMode, sync_lost : bool },
+}
+
+/// Moves the batches of the reading thread onto the queue, dropping
+/// those that don't fit. Empty reads that don't fit are skipped without
+/// counting as drops.
+struct Forwarder {
+    queue : flume::Sender<Message>,
+    dropped : Arc<Dropped>,
+}
+
+impl StreamProcessor for Forwarder {
+    fn name(&self) -> &str {
+        "soak_forwarder"
+    }
+
+    fn init(&mut self, info : &StreamInfo) -> ProcessorResult {
+        self.queue.send(Message::Init(info.clone())).map_err(|_| "The soak test stopped".to_string())
+    }
+
+    fn process_batch(&mut self, batch : &Batch) -> ProcessorResult {
+        let message = Message::Batch {
+            index : batch.index,
+            read_at : batch.read_at,
+            records : batch.records.to_vec(),
+            mode : batch.mode,
+            sync_lost : batch.sync_lost,
+        };
+        match self.queue.try_send(message) {
+            Ok(()) => Ok(()),
+            // An empty read loses nothing
+            Err(flume::TrySendError::Full(_)) if batch.records.is_empty() => Ok(()),
+            Err(flume::TrySendError::Full(_)) => {
+                self.dropped.batches.fetch_add(1, Ordering::Relaxed);
+                self.dropped.records.fetch_add(batch.records.len() as u64, Ordering::Relaxed);
+                Ok(())
+            },
+            Err(flume::TrySendError::Disconnected(_)) => Err("The soak test stopped".to_string()),
+        }
+    }
+
+    fn finalize(&mut self) -> ProcessorResult {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MarkerCounter;
+
+    #[test]
+    fn test_latency_histogram() {
+        let mut latency = LatencyHistogram::new();
+        assert_eq!(latency.percentile(0.5), Duration::ZERO);
+        for micros in 1..=100 {
+            latency.record(Duration::from_micros(micros));
+        }
+        assert_eq!(latency.count(), 100);
+        assert_eq!(latency.max(), Duration::from_micros(100));
+        assert_eq!(latency.percentile(1.0), Duration::from_micros(100));
+        let median = latency.percentile(0.5).as_secs_f64() * 1e6;
+        assert!((50.0..=50.0 * 1.1).contains(&median), "{}", median);
+    }
+
+    /// Hours long: `MHP_SOAK_SECS=14400 cargo test --release soak -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn test_soak() {
+        let secs = std::env::var("MHP_SOAK_SECS").ok().and_then(|s| s.parse().ok()).unwrap_or(600);
+        let rate = std::env::var("MHP_SOAK_RATE").ok().and_then(|s| s.parse().ok()).unwrap_or(5e6);
+        let mut pipeline = Pipeline::new();
+        pipeline.add(MarkerCounter::new(1).unwrap());
+
+        let report = SoakTest::new(Duration::from_secs(secs))
+            .with_count_rate(rate)
+            .run(&mut pipeline)
+            .unwrap();
+        println!("{}", report);
+        for sample in &report.samples {
+            println!("{:?}", sample);
+        }
+        assert!(report.records > 0);
+        assert_eq!(report.dropped_batches, 0);
+        // Memory shouldn't grow with the length of the run
+        if let Some(growth) = report.memory_growth() {
+            assert!(growth < 64 << 20, "Resident memory grew by {} bytes", growth);
+        }
+    }
+}
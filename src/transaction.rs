@@ -0,0 +1,256 @@
+//! Applies a batch of settings all or nothing. `set_from_config` carries
+//! on past a failing setter, leaving the device half configured; a
+//! transaction instead stops at the first failure and puts every setting
+//! it already changed back to its value from before the batch.
+//!
+//! The values restored come from `MultiHarpDevice::current_settings`, so
+//! a setting the device has no record of can't be rolled back. Those are
+//! listed in the `TransactionError`.
+
+use std::fmt::{Debug, Display};
+
+use crate::error::PatinaError;
+use crate::multiharp::MultiHarpDevice;
+use crate::MultiHarpConfig;
+
+/// Why `with_settings_transaction` didn't apply its batch.
+#[derive(Debug)]
+pub struct TransactionError {
+    /// The setting that failed, named as in the settings log
+    /// (e.g. `input_channel_offset[2]`)
+    pub setting : String,
+    pub error : PatinaError<String>,
+    /// Settings changed before the failure that couldn't be put back:
+    /// with `None` if their earlier value is unknown, or the error the
+    /// device returned restoring it
+    pub not_restored : Vec<(String, Option<PatinaError<String>>)>,
+}
+
+impl TransactionError {
+    /// Whether the device was left exactly as it was before the batch
+    pub fn rolled_back(&self) -> bool {
+        self.not_restored.is_empty()
+    }
+}
+
+impl Display for TransactionError {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Setting {} failed: {}", self.setting, self.error)?;
+        for (setting, error) in self.not_restored.iter() {
+            match error {
+                Some(error) => write!(f, "; restoring {} failed: {}", setting, error)?,
+                None => write!(f, "; {} not restored (earlier value unknown)", setting)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for TransactionError {}
+
+fn stringly<T : Display + Debug>(error : PatinaError<T>) -> PatinaError<String> {
+    error.map_argument(|argument| argument.to_string())
+}
+
+/// Replaces the entry of `entries` for the same channel as `entry`, or adds it.
+pub(crate) fn upsert<E, F>(entries : &mut Option<Vec<E>>, entry : E, same_channel : F)
+where F : Fn(&E) -> bool {
+    let entries = entries.get_or_insert_with(Vec::new);
+    match entries.iter_mut().find(|e| same_channel(e)) {
+        Some(existing) => *existing = entry,
+        None => entries.push(entry),
+    }
+}
+
+/// Applies each setting of `config` in the order of `set_from_config`.
+/// `failed` is called with each failure and returns whether to go on.
+/// Returns the settings applied successfully, in order.
+pub(crate) fn apply_config<M, F>(multiharp : &mut M, config : &MultiHarpConfig, mut failed : F) -> Vec<String>
+where M : MultiHarpDevice, F : FnMut(String, PatinaError<String>) -> bool {
+    let mut applied = Vec::new();
+    macro_rules! apply {
+        ($setting : expr, $result : expr) => {
+            match $result {
+                Ok(_) => applied.push($setting),
+                Err(e) => if !failed($setting, e) { return applied; },
+            }
+        };
+    }
+
+    if let Some(sync_div) = config.sync_div {
+        apply!("sync_div".to_string(), multiharp.set_sync_div(sync_div).map_err(stringly));
+    }
+    if let Some((level, edge)) = config.sync_trigger_edge {
+        apply!("sync_edge_trigger".to_string(), multiharp.set_sync_edge_trigger(level, edge).map_err(stringly));
+    }
+    if let Some(offset) = config.sync_channel_offset {
+        apply!("sync_channel_offset".to_string(), multiharp.set_sync_channel_offset(offset).map_err(stringly));
+    }
+    #[cfg(feature = "MHLv3_1_0")]
+    if let Some(enable) = config.sync_channel_enable {
+        apply!("sync_channel_enable".to_string(), multiharp.set_sync_channel_enable(enable).map_err(stringly));
+    }
+    if let Some((on, dead_time)) = config.sync_dead_time {
+        apply!("sync_dead_time".to_string(), multiharp.set_sync_dead_time(on, dead_time).map_err(stringly));
+    }
+    for (channel, level, edge) in config.input_edges.iter().flatten() {
+        apply!(format!("input_edge_trigger[{}]", channel),
+            multiharp.set_input_edge_trigger(*channel, *level, *edge).map_err(stringly));
+    }
+    for (channel, offset) in config.input_offsets.iter().flatten() {
+        apply!(format!("input_channel_offset[{}]", channel),
+            multiharp.set_input_channel_offset(*channel, *offset).map_err(stringly));
+    }
+    for (channel, enable) in config.input_enables.iter().flatten() {
+        apply!(format!("input_channel_enable[{}]", channel),
+            multiharp.set_input_channel_enable(*channel, *enable).map_err(stringly));
+    }
+    for (channel, on, dead_time) in config.input_dead_times.iter().flatten() {
+        apply!(format!("input_dead_time[{}]", channel),
+            multiharp.set_input_dead_time(*channel, *on, *dead_time).map_err(stringly));
+    }
+    #[cfg(feature = "MHLv3_0_0")]
+    if let Some(hysteresis) = config.input_hysteresis {
+        apply!("input_hysteresis".to_string(), multiharp.set_input_hysteresis(hysteresis).map_err(stringly));
+    }
+    if let Some((on, stop_count)) = config.stop_overflow {
+        apply!("stop_overflow".to_string(), multiharp.set_stop_overflow(on, stop_count).map_err(stringly));
+    }
+    if let Some(binning) = config.binning {
+        apply!("binning".to_string(), multiharp.set_binning(binning).map_err(stringly));
+    }
+    if let Some(offset) = config.offset {
+        apply!("offset".to_string(), multiharp.set_offset(offset).map_err(stringly));
+    }
+    if let Some(len_code) = config.histo_len {
+        apply!("histogram_len".to_string(), multiharp.set_histogram_len(len_code).map_err(stringly));
+    }
+    if let Some((control, start_edge, stop_edge)) = config.meas_control {
+        apply!("measurement_control_mode".to_string(),
+            multiharp.set_measurement_control_mode(control, start_edge, stop_edge));
+    }
+    if let Some(period) = config.trigger_output {
+        apply!("trigger_output".to_string(), multiharp.set_trigger_output(period).map_err(stringly));
+    }
+    #[cfg(feature = "MHLv3_1_0")]
+    if let Some(hold_time) = config.ofl_compression {
+        apply!("overflow_compression".to_string(), multiharp.set_overflow_compression(hold_time).map_err(stringly));
+    }
+    if let Some([e1, e2, e3, e4]) = config.marker_edges {
+        apply!("marker_edges".to_string(),
+            multiharp.set_marker_edges(e1, e2, e3, e4).map_err(PatinaError::MultiHarpError));
+    }
+    if let Some([e1, e2, e3, e4]) = config.marker_enable {
+        apply!("marker_enable".to_string(),
+            multiharp.set_marker_enable(e1, e2, e3, e4).map_err(PatinaError::MultiHarpError));
+    }
+    if let Some(holdoff) = config.marker_holdoff {
+        apply!("marker_holdoff_time".to_string(), multiharp.set_marker_holdoff_time(holdoff).map_err(stringly));
+    }
+    applied
+}
+
+/// The values in `snapshot` of the settings named in `changed`, and the
+/// names of those `snapshot` has no value for.
+fn restore_point(snapshot : &MultiHarpConfig, changed : &[String]) -> (MultiHarpConfig, Vec<String>) {
+    let mut restore = MultiHarpConfig::default();
+    let mut unknown = Vec::new();
+    for setting in changed {
+        let (name, channel) = match setting.split_once('[') {
+            Some((name, channel)) => (name, channel.trim_end_matches(']').parse::<i32>().ok()),
+            None => (setting.as_str(), None),
+        };
+        let known = match (name, channel) {
+            ("sync_div", _) => snapshot.sync_div.map(|v| restore.sync_div = Some(v)),
+            ("sync_edge_trigger", _) => snapshot.sync_trigger_edge.map(|v| restore.sync_trigger_edge = Some(v)),
+            ("sync_channel_offset", _) => snapshot.sync_channel_offset.map(|v| restore.sync_channel_offset = Some(v)),
+            #[cfg(feature = "MHLv3_1_0")]
+            ("sync_channel_enable", _) => snapshot.sync_channel_enable.map(|v| restore.sync_channel_enable = Some(v)),
+            ("sync_dead_time", _) => snapshot.sync_dead_time.map(|v| restore.sync_dead_time = Some(v)),
+            ("input_edge_trigger", Some(c)) => snapshot.input_edges.iter().flatten().find(|e| e.0 == c)
+                .map(|e| upsert(&mut restore.input_edges, *e, |r| r.0 == c)),
+            ("input_channel_offset", Some(c)) => snapshot.input_offsets.iter().flatten().find(|e| e.0 == c)
+                .map(|e| upsert(&mut restore.input_offsets, *e, |r| r.0 == c)),
+            ("input_channel_enable", Some(c)) => snapshot.input_enables.iter().flatten().find(|e| e.0 == c)
+                .map(|e| upsert(&mut restore.input_enables, *e, |r| r.0 == c)),
+            ("input_dead_time", Some(c)) => snapshot.input_dead_times.iter().flatten().find(|e| e.0 == c)
+                .map(|e| upsert(&mut restore.input_dead_times, *e, |r| r.0 == c)),
+            #[cfg(feature = "MHLv3_0_0")]
+            ("input_hysteresis", _) => snapshot.input_hysteresis.map(|v| restore.input_hysteresis = Some(v)),
+            ("stop_overflow", _) => snapshot.stop_overflow.map(|v| restore.stop_overflow = Some(v)),
+            ("binning", _) => snapshot.binning.map(|v| restore.binning = Some(v)),
+            ("offset", _) => snapshot.offset.map(|v| restore.offset = Some(v)),
+            ("histogram_len", _) => snapshot.histo_len.map(|v| restore.histo_len = Some(v)),
+            ("measurement_control_mode", _) => snapshot.meas_control.map(|v| restore.meas_control = Some(v)),
+            ("trigger_output", _) => snapshot.trigger_output.map(|v| restore.trigger_output = Some(v)),
+            #[cfg(feature = "MHLv3_1_0")]
+            ("overflow_compression", _) => snapshot.ofl_compression.map(|v| restore.ofl_compression = Some(v)),
+            ("marker_edges", _) => snapshot.marker_edges.map(|v| restore.marker_edges = Some(v)),
+            ("marker_enable", _) => snapshot.marker_enable.map(|v| restore.marker_enable = Some(v)),
+            ("marker_holdoff_time", _) => snapshot.marker_holdoff.map(|v| restore.marker_holdoff = Some(v)),
+            _ => None,
+        };
+        if known.is_none() {
+            unknown.push(setting.clone());
+        }
+    }
+    (restore, unknown)
+}
+
+/// Implements `MultiHarpDevice::with_settings_transaction`
+pub(crate) fn run<M, F>(multiharp : &mut M, build : F) -> Result<(), TransactionError>
+where M : MultiHarpDevice, F : FnOnce(&mut MultiHarpConfig) {
+    let mut batch = MultiHarpConfig::default();
+    build(&mut batch);
+    let snapshot = multiharp.current_settings();
+
+    let mut failure = None;
+    let applied = apply_config(multiharp, &batch, |setting, error| {
+        failure = Some((setting, error));
+        false
+    });
+    let (setting, error) = match failure {
+        Some(failure) => failure,
+        None => return Ok(()),
+    };
+
+    let (restore, unknown) = restore_point(&snapshot, &applied);
+    let mut not_restored = unknown.into_iter().map(|setting| (setting, None)).collect::<Vec<_>>();
+    apply_config(multiharp, &restore, |setting, error| {
+        not_restored.push((setting, Some(error)));
+        true
+    });
+    Err(TransactionError { setting, error, not_restored })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{DebugMultiHarp150, MultiHarpDevice, TriggerEdge};
+
+    #[test]
+    fn test_settings_transaction() {
+        let mut mh = DebugMultiHarp150::default();
+        mh.with_settings_transaction(|config| {
+            config.sync_div = Some(2);
+            config.input_offsets = Some(vec![(1, 500)]);
+        }).unwrap();
+        let before = mh.current_settings();
+        assert_eq!(before.sync_div, Some(2));
+
+        // The bad trigger output comes after the others, which are undone
+        let error = mh.with_settings_transaction(|config| {
+            config.sync_div = Some(4);
+            config.sync_trigger_edge = Some((-100, TriggerEdge::Rising));
+            config.input_offsets = Some(vec![(1, 700), (3, 900)]);
+            config.binning = Some(3);
+            config.trigger_output = Some(-1);
+        }).unwrap_err();
+        assert_eq!(error.setting, "trigger_output");
+        assert!(error.rolled_back(), "{}", error);
+        assert_eq!(mh.current_settings(), before);
+
+        let error = mh.with_settings_transaction(|config| config.input_offsets = Some(vec![(0, 10), (7, 10)])).unwrap_err();
+        assert_eq!(error.setting, "input_channel_offset[7]");
+        assert_eq!(mh.current_settings(), before);
+    }
+}
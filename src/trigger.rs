@@ -0,0 +1,241 @@
+//! Timed on/off patterns for the trigger output, run on a background
+//! thread. `set_trigger_output` only sets a period, so stimulation
+//! protocols (e.g. 10 kHz for 5 s, off for 1 s, repeated) need
+//! something to switch it at the right times.
+
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::error::{CheckedResult, PatinaError, MultiHarpError};
+use crate::mhconsts;
+use crate::MultiHarpDevice;
+
+/// One step of a `TriggerSequence`: a trigger period held for a duration.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TriggerStep {
+    /// Trigger output period in units of 100 ns, 0 for off
+    pub period : i32,
+    pub duration : Duration,
+}
+
+/// How many times a `TriggerSequence` runs through its steps
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Repeat {
+    Times(u32),
+    Forever,
+}
+
+/// A pattern of trigger output periods.
+///
+/// ## Example
+///
+/// ```
+/// use std::time::Duration;
+/// use multi_harp_patina::*;
+///
+/// // 10 kHz for 5 s, off for 1 s, ten times
+/// let sequence = TriggerSequence::new()
+///     .on_hz(10e3, Duration::from_secs(5)).unwrap()
+///     .off(Duration::from_secs(1))
+///     .repeat(Repeat::Times(10));
+/// assert_eq!(sequence.total_duration(), Some(Duration::from_secs(60)));
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct TriggerSequence {
+    steps : Vec<TriggerStep>,
+    repeat : Repeat,
+}
+
+impl Default for TriggerSequence {
+    fn default() -> Self {
+        TriggerSequence { steps : Vec::new(), repeat : Repeat::Times(1) }
+    }
+}
+
+impl TriggerSequence {
+    /// An empty sequence, run once
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends a step with the trigger period given in units of 100 ns.
+    pub fn period(mut self, period : i32, duration : Duration) -> CheckedResult<Self, i32> {
+        if !(mhconsts::TRIGOUTMIN..=mhconsts::TRIGOUTMAX).contains(&period) {
+            return Err(PatinaError::ArgumentError(
+                "period".to_string(),
+                period,
+                format!("Period must be between {} and {}", mhconsts::TRIGOUTMIN, mhconsts::TRIGOUTMAX))
+            );
+        }
+        self.steps.push(TriggerStep { period, duration });
+        Ok(self)
+    }
+
+    /// Appends a step triggering at `frequency` Hz, rounded to the
+    /// nearest 100 ns period.
+    pub fn on_hz(self, frequency : f64, duration : Duration) -> CheckedResult<Self, i32> {
+        let period = if frequency > 0.0 { (1e7 / frequency).round() } else { 0.0 };
+        if period < 1.0 || period > mhconsts::TRIGOUTMAX as f64 {
+            return Err(PatinaError::ArgumentError(
+                "frequency".to_string(),
+                frequency as i32,
+                format!("Frequency must be between {:.3} Hz and 10 MHz", 1e7 / mhconsts::TRIGOUTMAX as f64))
+            );
+        }
+        self.period(period as i32, duration)
+    }
+
+    /// Appends a step with the trigger output off.
+    pub fn off(mut self, duration : Duration) -> Self {
+        self.steps.push(TriggerStep { period : mhconsts::TRIGOUTMIN, duration });
+        self
+    }
+
+    pub fn repeat(mut self, repeat : Repeat) -> Self {
+        self.repeat = repeat;
+        self
+    }
+
+    pub fn steps(&self) -> &[TriggerStep] {
+        &self.steps
+    }
+
+    /// Total time to run the sequence, or `None` if it repeats forever.
+    pub fn total_duration(&self) -> Option<Duration> {
+        let once = self.steps.iter().map(|s| s.duration).sum::<Duration>();
+        match self.repeat {
+            Repeat::Times(n) => Some(once * n),
+            Repeat::Forever => None,
+        }
+    }
+}
+
+/// Runs a `TriggerSequence` on a background thread. The trigger output
+/// is switched off when the sequence ends, when `stop` is called, or
+/// when the `TriggerSequencer` is dropped.
+///
+/// The device is shared through a `Mutex` and only locked briefly at
+/// each step, so it can still be read from other threads.
+pub struct TriggerSequencer {
+    stop : Option<mpsc::Sender<()>>,
+    thread : Option<JoinHandle<CheckedResult<(), i32>>>,
+}
+
+impl TriggerSequencer {
+    /// Starts running `sequence` on `multiharp`.
+    pub fn start<MH>(multiharp : Arc<Mutex<MH>>, sequence : TriggerSequence) -> Self
+    where MH : MultiHarpDevice + Send + 'static {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = std::thread::spawn(move || {
+            let set_period = |period : i32| {
+                multiharp.lock()
+                    .map_err(|_| PatinaError::MultiHarpError(MultiHarpError::ThreadStateFail))
+                    .and_then(|mut mh| mh.set_trigger_output(period))
+            };
+
+            let mut result = Ok(());
+            let mut iteration = 0u32;
+            'outer: while !sequence.steps.is_empty() {
+                if let Repeat::Times(n) = sequence.repeat {
+                    if iteration >= n { break; }
+                }
+                for step in sequence.steps.iter() {
+                    let started = Instant::now();
+                    if let Err(e) = set_period(step.period) {
+                        result = Err(e);
+                        break 'outer;
+                    }
+                    // Wakes early if `stop` is called or the sequencer is dropped
+                    match stopped.recv_timeout(step.duration.saturating_sub(started.elapsed())) {
+                        Err(mpsc::RecvTimeoutError::Timeout) => {},
+                        _ => break 'outer,
+                    }
+                }
+                iteration += 1;
+            }
+
+            // Always leave the trigger output off
+            result.and(set_period(mhconsts::TRIGOUTMIN))
+        });
+
+        TriggerSequencer { stop : Some(stop), thread : Some(thread) }
+    }
+
+    /// Whether the sequence is still running
+    pub fn is_running(&self) -> bool {
+        self.thread.as_ref().map(|t| !t.is_finished()).unwrap_or(false)
+    }
+
+    /// Stops the sequence, turns the trigger output off, and returns
+    /// the first error encountered while setting the trigger output.
+    pub fn stop(mut self) -> CheckedResult<(), i32> {
+        self.teardown()
+    }
+
+    /// Waits for a finite sequence to finish.
+    pub fn wait(mut self) -> CheckedResult<(), i32> {
+        let result = self.thread.take()
+            .map(|t| t.join().unwrap_or(Err(PatinaError::MultiHarpError(MultiHarpError::ThreadStateFail))))
+            .unwrap_or(Ok(()));
+        self.stop.take();
+        result
+    }
+
+    fn teardown(&mut self) -> CheckedResult<(), i32> {
+        // Dropping the sender wakes the thread
+        self.stop.take();
+        self.thread.take()
+            .map(|t| t.join().unwrap_or(Err(PatinaError::MultiHarpError(MultiHarpError::ThreadStateFail))))
+            .unwrap_or(Ok(()))
+    }
+}
+
+impl Drop for TriggerSequencer {
+    fn drop(&mut self) {
+        let _ = self.teardown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DebugMultiHarp150;
+
+    #[test]
+    fn test_sequence_builder() {
+        let sequence = TriggerSequence::new()
+            .on_hz(10e3, Duration::from_millis(50)).unwrap()
+            .off(Duration::from_millis(10))
+            .repeat(Repeat::Times(3));
+        assert_eq!(sequence.steps()[0].period, 1000);
+        assert_eq!(sequence.total_duration(), Some(Duration::from_millis(180)));
+
+        assert!(TriggerSequence::new().on_hz(0.0, Duration::from_secs(1)).is_err());
+        assert!(TriggerSequence::new().period(mhconsts::TRIGOUTMAX + 1, Duration::from_secs(1)).is_err());
+        assert_eq!(TriggerSequence::new().repeat(Repeat::Forever).total_duration(), None);
+    }
+
+    #[test]
+    fn test_sequencer_teardown() {
+        let mh = Arc::new(Mutex::new(DebugMultiHarp150::default()));
+        let sequence = TriggerSequence::new()
+            .on_hz(1e3, Duration::from_millis(20)).unwrap()
+            .repeat(Repeat::Times(2));
+        TriggerSequencer::start(Arc::clone(&mh), sequence).wait().unwrap();
+        assert_eq!(mh.lock().unwrap().get_trigger_output(), 0);
+
+        let sequence = TriggerSequence::new()
+            .on_hz(1e3, Duration::from_secs(60)).unwrap()
+            .repeat(Repeat::Forever);
+        let sequencer = TriggerSequencer::start(Arc::clone(&mh), sequence);
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(sequencer.is_running());
+        assert_eq!(mh.lock().unwrap().get_trigger_output(), 10000);
+
+        let started = Instant::now();
+        sequencer.stop().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(mh.lock().unwrap().get_trigger_output(), 0);
+    }
+}
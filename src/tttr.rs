@@ -0,0 +1,1133 @@
+//! Conversion between the raw `u32` records read from the FIFO and
+//! `TttrEvent`s with absolute times.
+//!
+//! A `Decoder` unpacks records, accumulating overflows into absolute
+//! times, and an `Encoder` does the reverse, inserting the overflow
+//! records needed to reach each event's time. Together they make it
+//! possible to generate bit-exact synthetic data for tests and debug
+//! devices. A `T3Record` or `T2Record` unpacks a single record as it
+//! is, for code that keeps its own time base. A `SyncDivider` recovers
+//! the pulses a sync divider skipped in T3 mode.
+
+use crate::mhconsts::{self, MeasurementMode};
+use crate::pipeline::StreamInfo;
+use crate::version::parse_version;
+
+/// The channel code of a special record marking an overflow
+/// (sync or timetag rollover)
+pub const OVERFLOW_CHANNEL : u8 = 0x3F;
+/// Highest channel code used for markers in special records
+pub const MAX_MARKER_CHANNEL : u8 = 15;
+/// Number of syncs counted before an `nsync` rollover in T3 mode
+pub const T3_WRAPAROUND : u64 = 1024;
+/// Number of timetag units before a rollover in T2 mode
+pub const T2_WRAPAROUND : u64 = 33554432;
+
+/// Packs the fields of a T3 record. Out of range fields are truncated.
+pub fn pack_t3(special : bool, channel : u8, dtime : u16, nsync : u16) -> u32 {
+    ((special as u32) << 31)
+    | (((channel as u32) << 25) & mhconsts::CHANNEL)
+    | (((dtime as u32) << 10) & mhconsts::HISTOTAG_T3)
+    | ((nsync as u32) & mhconsts::SYNCTAG)
+}
+
+/// Packs the fields of a T2 record. Out of range fields are truncated.
+pub fn pack_t2(special : bool, channel : u8, timetag : u32) -> u32 {
+    ((special as u32) << 31)
+    | (((channel as u32) << 25) & mhconsts::CHANNEL)
+    | (timetag & mhconsts::HISTOTAG_T2)
+}
+
+/// One T3 record read from the FIFO, unpacked but without the overflow
+/// bookkeeping of a `Decoder`: `nsync` is the raw 10-bit sync count,
+/// which rolls over every `T3_WRAPAROUND` syncs.
+///
+/// ## Example
+///
+/// ```
+/// use multi_harp_patina::*;
+///
+/// let record = T3Record::from(pack_t3(false, 2, 1500, 17));
+/// assert_eq!(record, T3Record::Photon { channel : 2, dtime : 1500, nsync : 17 });
+/// assert_eq!(record.channel(), Some(2));
+/// assert_eq!(record.dtime(), Some(1500));
+/// assert_eq!(record.nsync(), Some(17));
+///
+/// assert_eq!(T3Record::from(pack_t3(true, 0x3F, 0, 3)), T3Record::Overflow { count : 3 });
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum T3Record {
+    /// A photon on input `channel` (from 0), `dtime` resolution units
+    /// after the sync
+    Photon { channel : u8, dtime : u16, nsync : u16 },
+    /// One or more markers, as a bitfield (1 to 15)
+    Marker { markers : u8, nsync : u16 },
+    /// An `nsync` rollover. How many rollovers `count` stands for depends
+    /// on the `OverflowCompression` of the stream.
+    Overflow { count : u16 },
+    /// A special record with a channel code `MHLib` doesn't use
+    Reserved { channel : u8, nsync : u16 },
+}
+
+impl T3Record {
+    /// The input channel of a photon
+    pub fn channel(&self) -> Option<u8> {
+        match self {
+            T3Record::Photon { channel, .. } => Some(*channel),
+            _ => None,
+        }
+    }
+
+    /// The arrival time after the sync of a photon, in units of the resolution
+    pub fn dtime(&self) -> Option<u16> {
+        match self {
+            T3Record::Photon { dtime, .. } => Some(*dtime),
+            _ => None,
+        }
+    }
+
+    /// The sync count of a photon, marker or reserved record
+    pub fn nsync(&self) -> Option<u16> {
+        match self {
+            T3Record::Photon { nsync, .. }
+            | T3Record::Marker { nsync, .. }
+            | T3Record::Reserved { nsync, .. } => Some(*nsync),
+            T3Record::Overflow { .. } => None,
+        }
+    }
+
+    pub fn is_special(&self) -> bool {
+        !matches!(self, T3Record::Photon { .. })
+    }
+
+    /// Packs the record again, see `pack_t3`
+    pub fn to_raw(&self) -> u32 {
+        match *self {
+            T3Record::Photon { channel, dtime, nsync } => pack_t3(false, channel, dtime, nsync),
+            T3Record::Marker { markers, nsync } => pack_t3(true, markers, 0, nsync),
+            T3Record::Overflow { count } => pack_t3(true, OVERFLOW_CHANNEL, 0, count),
+            T3Record::Reserved { channel, nsync } => pack_t3(true, channel, 0, nsync),
+        }
+    }
+}
+
+impl From<u32> for T3Record {
+    fn from(record : u32) -> Self {
+        let channel = ((record & mhconsts::CHANNEL) >> 25) as u8;
+        let nsync = (record & mhconsts::SYNCTAG) as u16;
+        if record & mhconsts::SPECIAL == 0 {
+            let dtime = ((record & mhconsts::HISTOTAG_T3) >> 10) as u16;
+            return T3Record::Photon { channel, dtime, nsync };
+        }
+        match channel {
+            OVERFLOW_CHANNEL => T3Record::Overflow { count : nsync },
+            1..=MAX_MARKER_CHANNEL => T3Record::Marker { markers : channel, nsync },
+            _ => T3Record::Reserved { channel, nsync },
+        }
+    }
+}
+
+/// One T2 record read from the FIFO, unpacked but without the overflow
+/// bookkeeping of a `Decoder`: `timetag` is the raw 25-bit time in units
+/// of the resolution, which rolls over every `T2_WRAPAROUND` units.
+///
+/// ## Example
+///
+/// ```
+/// use multi_harp_patina::*;
+///
+/// let record = T2Record::from(pack_t2(false, 3, 123_456));
+/// assert_eq!(record, T2Record::Photon { channel : 3, timetag : 123_456 });
+/// assert_eq!(record.channel(), Some(3));
+/// assert_eq!(record.timetag(), Some(123_456));
+///
+/// assert_eq!(T2Record::from(pack_t2(true, 0, 99)), T2Record::Sync { timetag : 99 });
+/// assert_eq!(T2Record::from(pack_t2(true, 0x3F, 1)), T2Record::Overflow { count : 1 });
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum T2Record {
+    /// A photon on input `channel` (from 0)
+    Photon { channel : u8, timetag : u32 },
+    /// A sync pulse, recorded like the inputs in T2 mode
+    Sync { timetag : u32 },
+    /// One or more markers, as a bitfield (1 to 15)
+    Marker { markers : u8, timetag : u32 },
+    /// A timetag rollover. How many rollovers `count` stands for depends
+    /// on the `OverflowCompression` of the stream.
+    Overflow { count : u32 },
+    /// A special record with a channel code `MHLib` doesn't use
+    Reserved { channel : u8, timetag : u32 },
+}
+
+impl T2Record {
+    /// The input channel of a photon
+    pub fn channel(&self) -> Option<u8> {
+        match self {
+            T2Record::Photon { channel, .. } => Some(*channel),
+            _ => None,
+        }
+    }
+
+    /// The time tag of any record but an overflow
+    pub fn timetag(&self) -> Option<u32> {
+        match self {
+            T2Record::Photon { timetag, .. }
+            | T2Record::Sync { timetag }
+            | T2Record::Marker { timetag, .. }
+            | T2Record::Reserved { timetag, .. } => Some(*timetag),
+            T2Record::Overflow { .. } => None,
+        }
+    }
+
+    pub fn is_special(&self) -> bool {
+        !matches!(self, T2Record::Photon { .. })
+    }
+
+    /// Packs the record again, see `pack_t2`
+    pub fn to_raw(&self) -> u32 {
+        match *self {
+            T2Record::Photon { channel, timetag } => pack_t2(false, channel, timetag),
+            T2Record::Sync { timetag } => pack_t2(true, 0, timetag),
+            T2Record::Marker { markers, timetag } => pack_t2(true, markers, timetag),
+            T2Record::Overflow { count } => pack_t2(true, OVERFLOW_CHANNEL, count),
+            T2Record::Reserved { channel, timetag } => pack_t2(true, channel, timetag),
+        }
+    }
+}
+
+impl From<u32> for T2Record {
+    fn from(record : u32) -> Self {
+        let channel = ((record & mhconsts::CHANNEL) >> 25) as u8;
+        let timetag = record & mhconsts::HISTOTAG_T2;
+        if record & mhconsts::SPECIAL == 0 {
+            return T2Record::Photon { channel, timetag };
+        }
+        match channel {
+            OVERFLOW_CHANNEL => T2Record::Overflow { count : timetag },
+            0 => T2Record::Sync { timetag },
+            1..=MAX_MARKER_CHANNEL => T2Record::Marker { markers : channel, timetag },
+            _ => T2Record::Reserved { channel, timetag },
+        }
+    }
+}
+
+/// What a `TttrEvent` records
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum EventKind {
+    Photon,
+    Marker,
+    /// A sync pulse. Only recorded in T2 mode.
+    Sync,
+}
+
+/// A photon, marker or sync with its absolute time since the start of
+/// the acquisition. Laid out in 16 bytes, so four fit a cache line; see
+/// `EventColumns` for the same events by field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct TttrEvent {
+    pub kind : EventKind,
+    /// Input channel (0-indexed) for photons, marker bitfield (1 to 15)
+    /// for markers, 0 for syncs
+    pub channel : u8,
+    /// Arrival time after the sync in units of the resolution. T3 photons only.
+    pub dtime : u16,
+    /// Syncs since the start (T3), or timetag in units of the resolution (T2)
+    pub time : u64,
+}
+
+impl TttrEvent {
+    pub fn photon(channel : u8, time : u64, dtime : u16) -> Self {
+        TttrEvent { kind : EventKind::Photon, channel, dtime, time }
+    }
+
+    pub fn marker(markers : u8, time : u64) -> Self {
+        TttrEvent { kind : EventKind::Marker, channel : markers, dtime : 0, time }
+    }
+
+    pub fn sync(time : u64) -> Self {
+        TttrEvent { kind : EventKind::Sync, channel : 0, dtime : 0, time }
+    }
+}
+
+const _ : () = assert!(std::mem::size_of::<TttrEvent>() == 16);
+
+/// Events stored by field rather than by event, so that code reading only
+/// some fields -- the times for a correlation, the `dtime`s for a
+/// histogram -- reads only those, contiguously.
+///
+/// ## Example
+///
+/// ```
+/// use multi_harp_patina::*;
+///
+/// let mut decoder = Decoder::new(MeasurementMode::T3).unwrap();
+/// let mut columns = EventColumns::new();
+/// decoder.decode_into(&[pack_t3(false, 0, 100, 1), pack_t3(false, 2, 300, 4)], &mut columns);
+/// assert_eq!(columns.dtime, vec![100, 300]);
+/// assert_eq!(columns.get(1), Some(TttrEvent::photon(2, 4, 300)));
+/// assert_eq!(EventColumns::from_events(&columns.to_events()), columns);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct EventColumns {
+    pub kinds : Vec<EventKind>,
+    /// As `TttrEvent::channel`
+    pub channels : Vec<u8>,
+    /// Syncs since the start (T3), or timetag (T2), as `TttrEvent::time`
+    pub time : Vec<u64>,
+    pub dtime : Vec<u16>,
+}
+
+impl EventColumns {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Columns with room for `capacity` events each
+    pub fn with_capacity(capacity : usize) -> Self {
+        EventColumns {
+            kinds : Vec::with_capacity(capacity),
+            channels : Vec::with_capacity(capacity),
+            time : Vec::with_capacity(capacity),
+            dtime : Vec::with_capacity(capacity),
+        }
+    }
+
+    pub fn from_events(events : &[TttrEvent]) -> Self {
+        let mut columns = Self::with_capacity(events.len());
+        columns.extend_from_events(events);
+        columns
+    }
+
+    /// Appends `events`, one column at a time
+    pub fn extend_from_events(&mut self, events : &[TttrEvent]) {
+        self.kinds.extend(events.iter().map(|e| e.kind));
+        self.channels.extend(events.iter().map(|e| e.channel));
+        self.time.extend(events.iter().map(|e| e.time));
+        self.dtime.extend(events.iter().map(|e| e.dtime));
+    }
+
+    /// Appends the events of `other` in `range`
+    pub fn extend_from_range(&mut self, other : &EventColumns, range : std::ops::Range<usize>) {
+        self.kinds.extend_from_slice(&other.kinds[range.clone()]);
+        self.channels.extend_from_slice(&other.channels[range.clone()]);
+        self.time.extend_from_slice(&other.time[range.clone()]);
+        self.dtime.extend_from_slice(&other.dtime[range]);
+    }
+
+    pub fn push(&mut self, event : TttrEvent) {
+        self.kinds.push(event.kind);
+        self.channels.push(event.channel);
+        self.time.push(event.time);
+        self.dtime.push(event.dtime);
+    }
+
+    pub fn len(&self) -> usize {
+        self.kinds.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.kinds.is_empty()
+    }
+
+    /// Empties the columns, keeping their allocations
+    pub fn clear(&mut self) {
+        self.kinds.clear();
+        self.channels.clear();
+        self.time.clear();
+        self.dtime.clear();
+    }
+
+    pub fn get(&self, index : usize) -> Option<TttrEvent> {
+        Some(TttrEvent {
+            kind : *self.kinds.get(index)?,
+            channel : self.channels[index],
+            dtime : self.dtime[index],
+            time : self.time[index],
+        })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = TttrEvent> + '_ {
+        (0..self.len()).map(move |index| TttrEvent {
+            kind : self.kinds[index],
+            channel : self.channels[index],
+            dtime : self.dtime[index],
+            time : self.time[index],
+        })
+    }
+
+    pub fn to_events(&self) -> Vec<TttrEvent> {
+        self.iter().collect()
+    }
+
+    /// The time of each event since the start of the acquisition in ps,
+    /// rounded: `time` syncs of `sync_period` ps and `dtime` bins of
+    /// `resolution` ps in T3, `time` bins of `resolution` ps in T2.
+    pub fn times_ps(&self, mode : MeasurementMode, resolution : f64, sync_period : f64) -> Vec<u64> {
+        match mode {
+            MeasurementMode::T3 => self.time.iter().zip(self.dtime.iter())
+                .map(|(time, dtime)| (*time as f64 * sync_period + *dtime as f64 * resolution).round() as u64)
+                .collect(),
+            _ => self.time.iter().map(|time| (*time as f64 * resolution).round() as u64).collect(),
+        }
+    }
+}
+
+impl FromIterator<TttrEvent> for EventColumns {
+    fn from_iter<I : IntoIterator<Item = TttrEvent>>(events : I) -> Self {
+        let mut columns = EventColumns::new();
+        events.into_iter().for_each(|event| columns.push(event));
+        columns
+    }
+}
+
+fn wraparound(mode : MeasurementMode) -> Result<u64, String> {
+    match mode {
+        MeasurementMode::T2 => Ok(T2_WRAPAROUND),
+        MeasurementMode::T3 => Ok(T3_WRAPAROUND),
+        MeasurementMode::Histogramming => Err("Histogramming mode has no TTTR records".to_string()),
+    }
+}
+
+/// How the overflow records of a stream count rollovers. Which applies
+/// depends on the `MHLib` the records were read through, so it is part
+/// of the `StreamInfo` rather than guessed from the records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowCompression {
+    /// The low bits of an overflow record hold the number of rollovers
+    /// it stands for, as from `MHLib` 3.1 on. A count of 0 is one rollover.
+    #[default]
+    Compressed,
+    /// Every overflow record is a single rollover, whatever its low bits,
+    /// as before `MHLib` 3.1.
+    Single,
+}
+
+impl OverflowCompression {
+    /// The format of the records read through `MHLib` `version`, or
+    /// `None` if it isn't a version number.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// use multi_harp_patina::*;
+    ///
+    /// assert_eq!(OverflowCompression::for_library_version("3.1"), Some(OverflowCompression::Compressed));
+    /// assert_eq!(OverflowCompression::for_library_version("3.0"), Some(OverflowCompression::Single));
+    /// assert_eq!(OverflowCompression::for_library_version(""), None);
+    /// ```
+    pub fn for_library_version(version : &str) -> Option<Self> {
+        match parse_version(version)[..] {
+            [major, minor, ..] if (major, minor) >= (3, 1) => Some(OverflowCompression::Compressed),
+            [_, _, ..] => Some(OverflowCompression::Single),
+            _ => None,
+        }
+    }
+
+    /// The rollovers an overflow record with low bits `count` stands for
+    pub fn rollovers(self, count : u64) -> u64 {
+        match self {
+            OverflowCompression::Compressed => count.max(1),
+            OverflowCompression::Single => 1,
+        }
+    }
+}
+
+/// Per-channel time shifts applied to photons by a `Decoder`, for delays
+/// outside the range of `set_input_channel_offset` (±99999 ps). Offsets
+/// are in picoseconds and need not be a whole number of bins: the
+/// remainder is rounded, or with `with_dither`, randomly rounded up or
+/// down so that it's correct on average and doesn't leave a comb in
+/// histograms.
+///
+/// Shifted photons can end up out of time order with their neighbours.
+///
+/// ## Example
+///
+/// ```
+/// use multi_harp_patina::*;
+///
+/// let mut offsets = ChannelOffsets::new(5.0);
+/// offsets.set(1, 250_000.0); // 250 ns more cable on channel 1
+/// let mut decoder = Decoder::new(MeasurementMode::T2).unwrap().with_channel_offsets(offsets);
+/// let event = decoder.decode(pack_t2(false, 1, 1000)).unwrap();
+/// assert_eq!(event.time, 1000 + 50_000);
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelOffsets {
+    resolution : f64,
+    offsets : Vec<f64>,
+    sync_period : Option<f64>,
+    dither : Option<u64>,
+}
+
+impl ChannelOffsets {
+    /// No offsets, for a stream with bins of `resolution` ps
+    pub fn new(resolution : f64) -> Self {
+        ChannelOffsets { resolution, offsets : Vec::new(), sync_period : None, dither : None }
+    }
+
+    /// Shifts photons on `channel` later by `offset` ps (earlier, if negative).
+    pub fn set(&mut self, channel : u8, offset : f64) -> &mut Self {
+        if self.offsets.len() <= channel as usize {
+            self.offsets.resize(channel as usize + 1, 0.0);
+        }
+        self.offsets[channel as usize] = offset;
+        self
+    }
+
+    /// The offset of `channel` in ps
+    pub fn get(&self, channel : u8) -> f64 {
+        self.offsets.get(channel as usize).copied().unwrap_or(0.0)
+    }
+
+    /// Rounds fractional bins randomly, seeded with `seed` so decoding
+    /// is reproducible.
+    pub fn with_dither(mut self, seed : u64) -> Self {
+        // xorshift gets stuck at 0
+        self.dither = Some(seed | 1);
+        self
+    }
+
+    /// The sync period in ps, in T3 mode. Photons shifted past it move to
+    /// the next sync (or before 0, to the previous one). Without it, the
+    /// shifted `dtime` is clamped to the range of the record.
+    pub fn with_sync_period(mut self, period : f64) -> Self {
+        self.sync_period = Some(period);
+        self
+    }
+
+    /// The offset of `channel` in whole bins
+    fn shift_bins(&mut self, channel : u8) -> i64 {
+        let bins = self.get(channel) / self.resolution;
+        let whole = bins.floor();
+        let fraction = bins - whole;
+        let round_up = match self.dither.as_mut() {
+            Some(state) => {
+                *state ^= *state << 13;
+                *state ^= *state >> 7;
+                *state ^= *state << 17;
+                ((*state >> 11) as f64 / (1u64 << 53) as f64) < fraction
+            },
+            None => fraction >= 0.5,
+        };
+        whole as i64 + round_up as i64
+    }
+
+    /// Applies the offset to a photon decoded in `mode`.
+    fn apply(&mut self, event : &mut TttrEvent, mode : MeasurementMode) {
+        let shift = self.shift_bins(event.channel);
+        if shift == 0 {
+            return;
+        }
+        match (mode, self.sync_period) {
+            (MeasurementMode::T2, _) => {
+                event.time = (event.time as i64).saturating_add(shift).max(0) as u64;
+            },
+            (_, Some(period)) => {
+                let period = ((period / self.resolution).round() as i64).max(1);
+                let dtime = event.dtime as i64 + shift;
+                let time = (event.time as i64).saturating_add(dtime.div_euclid(period)).max(0);
+                event.time = time as u64;
+                event.dtime = dtime.rem_euclid(period).min(DTIME_MAX as i64) as u16;
+            },
+            (_, None) => {
+                event.dtime = (event.dtime as i64 + shift).clamp(0, DTIME_MAX as i64) as u16;
+            },
+        }
+    }
+}
+
+/// Where a T3 photon falls among the pulses of a divided sync
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DividedPulse {
+    /// Pulses since the start, counting every pulse the divider skipped
+    pub pulse : u64,
+    /// Arrival time after `pulse`, in units of the resolution
+    pub dtime : u16,
+    /// Whether the photon arrived within the ambiguity window of a pulse,
+    /// or later than the last pulse before the next counted sync, so that
+    /// it may belong to a neighbouring pulse
+    pub ambiguous : bool,
+}
+
+/// Undoes the sync divider of a T3 stream. With `sync_div` above 1 the
+/// device counts only every `divider`-th sync in `nsync` and measures
+/// `dtime` from it, so a `dtime` spans `divider` pulses. Knowing the
+/// pulse period, the pulse a photon follows is the whole number of periods
+/// in its `dtime`. Pulse indices count from the counted sync, whichever of
+/// the pulses the divider happened to start on.
+///
+/// ## Example
+///
+/// ```
+/// use multi_harp_patina::*;
+///
+/// // Every 4th pulse of 12.5 ns counted, at 5 ps bins
+/// let divider = SyncDivider::new(4, 12_500.0, 5.0);
+/// // 2.4 periods after the 10th counted sync
+/// let pulse = divider.pulse(10, 6000);
+/// assert_eq!((pulse.pulse, pulse.dtime, pulse.ambiguous), (42, 1000, false));
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyncDivider {
+    divider : u64,
+    pulse_period : f64,
+    resolution : f64,
+    ambiguity : f64,
+}
+
+impl SyncDivider {
+    /// Reconstructs pulses `pulse_period` ps apart from a stream with bins
+    /// of `resolution` ps and `divider` pulses per counted sync. Photons
+    /// within one bin of a pulse are ambiguous.
+    pub fn new(divider : u32, pulse_period : f64, resolution : f64) -> Self {
+        SyncDivider { divider : divider.max(1) as u64, pulse_period, resolution, ambiguity : resolution }
+    }
+
+    /// Flags photons within `window` ps either side of a pulse as
+    /// ambiguous, e.g. the timing jitter of the laser and detector
+    pub fn with_ambiguity_window(mut self, window : f64) -> Self {
+        self.ambiguity = window;
+        self
+    }
+
+    pub fn divider(&self) -> u32 {
+        self.divider as u32
+    }
+
+    /// The pulse period in bins of the resolution
+    pub fn pulse_period_bins(&self) -> f64 {
+        self.pulse_period / self.resolution
+    }
+
+    /// The pulse of a photon `dtime` bins after counted sync `nsync`
+    pub fn pulse(&self, nsync : u64, dtime : u16) -> DividedPulse {
+        let arrival = dtime as f64 * self.resolution;
+        let periods = (arrival / self.pulse_period).floor().max(0.0) as u64;
+        let after_pulse = arrival - periods as f64 * self.pulse_period;
+        let ambiguous = after_pulse < self.ambiguity
+            || self.pulse_period - after_pulse < self.ambiguity
+            || periods >= self.divider;
+        DividedPulse {
+            pulse : nsync.saturating_mul(self.divider).saturating_add(periods),
+            dtime : (after_pulse / self.resolution).floor() as u16,
+            ambiguous,
+        }
+    }
+
+    /// The first pulse counted as sync `nsync`, where markers go
+    pub fn sync_pulse(&self, nsync : u64) -> u64 {
+        nsync.saturating_mul(self.divider)
+    }
+}
+
+/// Largest `dtime` a T3 record can hold
+const DTIME_MAX : u16 = (1 << 15) - 1;
+
+/// Turns raw records into `TttrEvent`s, keeping track of overflows
+/// across calls so consecutive FIFO reads can be decoded one by one.
+#[derive(Debug, Clone)]
+pub struct Decoder {
+    mode : MeasurementMode,
+    wraparound : u64,
+    overflow_base : u64,
+    compression : OverflowCompression,
+    offsets : Option<ChannelOffsets>,
+    /// Channels photons are accepted on, if restricted
+    enabled : Option<Vec<bool>>,
+    anomalies : u64,
+    divider : Option<SyncDivider>,
+    ambiguous : u64,
+}
+
+impl Decoder {
+    /// A decoder for `T2` or `T3` records with compressed overflows.
+    pub fn new(mode : MeasurementMode) -> Result<Self, String> {
+        Ok(Decoder {
+            mode,
+            wraparound : wraparound(mode)?,
+            overflow_base : 0,
+            compression : OverflowCompression::Compressed,
+            offsets : None,
+            enabled : None,
+            anomalies : 0,
+            divider : None,
+            ambiguous : 0,
+        })
+    }
+
+    /// A decoder for the stream described by `info`, accepting photons
+    /// only on its enabled channels.
+    pub fn for_stream(info : &StreamInfo) -> Result<Self, String> {
+        Ok(Self::new(info.mode)?
+            .with_overflow_compression(info.overflow_compression)
+            .with_enabled_channels(&info.enabled_channels))
+    }
+
+    /// Counts the rollovers of each overflow record as `compression` says.
+    pub fn with_overflow_compression(mut self, compression : OverflowCompression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Accepts photons only on the channels for which `enabled` is `true`.
+    /// Photon records on any other channel are dropped and counted in
+    /// `anomalies`.
+    pub fn with_enabled_channels(mut self, enabled : &[bool]) -> Self {
+        self.enabled = Some(enabled.to_vec());
+        self
+    }
+
+    /// Photon records dropped since the start of the acquisition for
+    /// claiming a channel that isn't enabled
+    pub fn anomalies(&self) -> u64 {
+        self.anomalies
+    }
+
+    /// Shifts the time of photons on each channel by `offsets`.
+    pub fn with_channel_offsets(mut self, offsets : ChannelOffsets) -> Self {
+        self.offsets = Some(offsets);
+        self
+    }
+
+    /// Counts T3 times in pulses rather than counted syncs, as
+    /// `SyncDivider::pulse`, for streams acquired with a sync divider.
+    /// Photons are moved to the pulse they follow before any channel
+    /// offsets are applied, so offsets should wrap at the pulse period
+    /// (`ChannelOffsets::with_sync_period`). Ignored in T2 mode.
+    pub fn with_sync_divider(mut self, divider : SyncDivider) -> Self {
+        self.divider = Some(divider);
+        self
+    }
+
+    /// Photons decoded since the start of the acquisition whose pulse is
+    /// ambiguous, see `DividedPulse::ambiguous`
+    pub fn ambiguous(&self) -> u64 {
+        self.ambiguous
+    }
+
+    /// Returns to the start of an acquisition.
+    pub fn reset(&mut self) {
+        self.overflow_base = 0;
+        self.anomalies = 0;
+        self.ambiguous = 0;
+    }
+
+    /// Decodes one record. Overflows only update the time base and
+    /// return `None`, as do special records with reserved channel codes
+    /// and photons on channels that aren't enabled.
+    pub fn decode(&mut self, record : u32) -> Option<TttrEvent> {
+        let special = record & mhconsts::SPECIAL != 0;
+        let channel = ((record & mhconsts::CHANNEL) >> 25) as u8;
+        let (low, dtime) = match self.mode {
+            MeasurementMode::T2 => ((record & mhconsts::HISTOTAG_T2) as u64, 0),
+            _ => (
+                (record & mhconsts::SYNCTAG) as u64,
+                ((record & mhconsts::HISTOTAG_T3) >> 10) as u16,
+            ),
+        };
+
+        if !special {
+            if let Some(enabled) = self.enabled.as_ref() {
+                if !enabled.get(channel as usize).copied().unwrap_or(false) {
+                    self.anomalies += 1;
+                    return None;
+                }
+            }
+            let mut photon = TttrEvent::photon(channel, self.overflow_base.saturating_add(low), dtime);
+            if let (Some(divider), MeasurementMode::T3) = (self.divider.as_ref(), self.mode) {
+                let pulse = divider.pulse(photon.time, photon.dtime);
+                photon.time = pulse.pulse;
+                photon.dtime = pulse.dtime;
+                self.ambiguous += pulse.ambiguous as u64;
+            }
+            if let Some(offsets) = self.offsets.as_mut() {
+                offsets.apply(&mut photon, self.mode);
+            }
+            return Some(photon);
+        }
+        let mut time = self.overflow_base.saturating_add(low);
+        if let (Some(divider), MeasurementMode::T3) = (self.divider.as_ref(), self.mode) {
+            time = divider.sync_pulse(time);
+        }
+        match channel {
+            OVERFLOW_CHANNEL => {
+                let rollovers = self.compression.rollovers(low);
+                self.overflow_base = self.overflow_base.saturating_add(rollovers * self.wraparound);
+                None
+            },
+            0 if self.mode == MeasurementMode::T2 => Some(TttrEvent::sync(time)),
+            1..=MAX_MARKER_CHANNEL => Some(TttrEvent::marker(channel, time)),
+            _ => None,
+        }
+    }
+
+    /// Decodes a buffer of records, e.g. one FIFO read.
+    pub fn decode_all(&mut self, records : &[u32]) -> Vec<TttrEvent> {
+        records.iter().filter_map(|r| self.decode(*r)).collect()
+    }
+
+    /// Decodes a buffer of records, appending the events to `columns`.
+    /// Reusing `columns` between reads (see `EventColumns::clear`) saves
+    /// reallocating them.
+    pub fn decode_into(&mut self, records : &[u32], columns : &mut EventColumns) {
+        columns.kinds.reserve(records.len());
+        columns.channels.reserve(records.len());
+        columns.time.reserve(records.len());
+        columns.dtime.reserve(records.len());
+        for record in records {
+            if let Some(event) = self.decode(*record) {
+                columns.push(event);
+            }
+        }
+    }
+}
+
+/// Packs `TttrEvent`s into records, inserting overflows as the time
+/// advances. Events must be passed in time order.
+///
+/// ## Example
+///
+/// ```
+/// use multi_harp_patina::*;
+///
+/// let mut encoder = Encoder::new(MeasurementMode::T3).unwrap();
+/// let records = encoder.encode_all(&[
+///     TttrEvent::photon(0, 10, 200),
+///     TttrEvent::marker(1, 5000),
+/// ]).unwrap();
+/// // The marker needs an overflow record first
+/// assert_eq!(records.len(), 3);
+///
+/// let mut decoder = Decoder::new(MeasurementMode::T3).unwrap();
+/// assert_eq!(decoder.decode_all(&records)[1], TttrEvent::marker(1, 5000));
+/// ```
+#[derive(Debug, Clone)]
+pub struct Encoder {
+    mode : MeasurementMode,
+    wraparound : u64,
+    overflow_base : u64,
+    last_time : u64,
+    compression : OverflowCompression,
+}
+
+impl Encoder {
+    /// An encoder for `T2` or `T3` records.
+    pub fn new(mode : MeasurementMode) -> Result<Self, String> {
+        Ok(Encoder {
+            mode,
+            wraparound : wraparound(mode)?,
+            overflow_base : 0,
+            last_time : 0,
+            compression : OverflowCompression::Compressed,
+        })
+    }
+
+    /// Emits one overflow record per rollover, as firmware without
+    /// overflow compression does, instead of packing several into one.
+    pub fn with_single_overflows(self) -> Self {
+        self.with_overflow_compression(OverflowCompression::Single)
+    }
+
+    /// Writes overflow records in the format of `compression`.
+    pub fn with_overflow_compression(mut self, compression : OverflowCompression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Returns to the start of an acquisition.
+    pub fn reset(&mut self) {
+        self.overflow_base = 0;
+        self.last_time = 0;
+    }
+
+    fn check(&self, event : &TttrEvent) -> Result<(), String> {
+        if event.time < self.last_time {
+            return Err(format!("Event at {} is before the previous one at {}", event.time, self.last_time));
+        }
+        match event.kind {
+            EventKind::Photon if event.channel >= 64 =>
+                Err(format!("Photon channel must be below 64, got {}", event.channel)),
+            EventKind::Photon if self.mode == MeasurementMode::T3 && event.dtime > DTIME_MAX =>
+                Err(format!("dtime must be at most {}, got {}", DTIME_MAX, event.dtime)),
+            EventKind::Marker if !(1..=MAX_MARKER_CHANNEL).contains(&event.channel) =>
+                Err(format!("Markers must be between 1 and {}, got {}", MAX_MARKER_CHANNEL, event.channel)),
+            EventKind::Sync if self.mode != MeasurementMode::T2 =>
+                Err("Syncs are only recorded in T2 mode".to_string()),
+            _ => Ok(()),
+        }
+    }
+
+    fn overflow_record(&self, count : u64) -> u32 {
+        match self.mode {
+            MeasurementMode::T2 => pack_t2(true, OVERFLOW_CHANNEL, count as u32),
+            _ => pack_t3(true, OVERFLOW_CHANNEL, 0, count as u16),
+        }
+    }
+
+    /// Appends the records for `event`, preceded by any overflows, to `out`.
+    pub fn encode(&mut self, event : &TttrEvent, out : &mut Vec<u32>) -> Result<(), String> {
+        self.check(event)?;
+
+        let mut overflows = event.time / self.wraparound - self.overflow_base / self.wraparound;
+        let max_count = match self.compression {
+            OverflowCompression::Compressed => self.wraparound - 1,
+            OverflowCompression::Single => 1,
+        };
+        while overflows > 0 {
+            let count = overflows.min(max_count);
+            out.push(self.overflow_record(count));
+            overflows -= count;
+        }
+        self.overflow_base = event.time - event.time % self.wraparound;
+        self.last_time = event.time;
+
+        let low = event.time % self.wraparound;
+        out.push(match (self.mode, event.kind) {
+            (MeasurementMode::T2, EventKind::Photon) => pack_t2(false, event.channel, low as u32),
+            (MeasurementMode::T2, _) => pack_t2(true, event.channel, low as u32),
+            (_, EventKind::Photon) => pack_t3(false, event.channel, event.dtime, low as u16),
+            (_, _) => pack_t3(true, event.channel, 0, low as u16),
+        });
+        Ok(())
+    }
+
+    /// Encodes every event in `events`.
+    pub fn encode_all(&mut self, events : &[TttrEvent]) -> Result<Vec<u32>, String> {
+        let mut out = Vec::with_capacity(events.len());
+        events.iter().try_for_each(|e| self.encode(e, &mut out))?;
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::stream_info;
+
+    #[test]
+    fn test_t3_round_trip() {
+        let events = [
+            TttrEvent::photon(0, 10, 200),
+            TttrEvent::photon(3, 10, 32767),
+            TttrEvent::marker(0b0101, 1024),
+            TttrEvent::photon(1, 5 * 1024 * 1024 + 7, 3),
+        ];
+        let records = Encoder::new(MeasurementMode::T3).unwrap().encode_all(&events).unwrap();
+        // 1023 overflows fit in one record
+        assert_eq!(records.len(), events.len() + 1 + 6);
+        assert_eq!(Decoder::new(MeasurementMode::T3).unwrap().decode_all(&records), events);
+
+        let single = Encoder::new(MeasurementMode::T3).unwrap()
+            .with_single_overflows()
+            .encode_all(&events[..3]).unwrap();
+        assert_eq!(single, vec![
+            pack_t3(false, 0, 200, 10),
+            pack_t3(false, 3, 32767, 10),
+            pack_t3(true, OVERFLOW_CHANNEL, 0, 1),
+            pack_t3(true, 0b0101, 0, 0),
+        ]);
+    }
+
+    #[test]
+    fn test_t3_record() {
+        let records = [
+            T3Record::Photon { channel : 7, dtime : DTIME_MAX, nsync : 1023 },
+            T3Record::Marker { markers : 0b1010, nsync : 4 },
+            T3Record::Overflow { count : 0 },
+            T3Record::Reserved { channel : 40, nsync : 9 },
+        ];
+        for record in records {
+            assert_eq!(T3Record::from(record.to_raw()), record);
+        }
+        assert!(!records[0].is_special() && records[1].is_special());
+        assert_eq!(records[1].channel(), None);
+        assert_eq!(records[1].nsync(), Some(4));
+        assert_eq!(records[2].nsync(), None);
+
+        // Agrees with the decoder before any overflow
+        let mut decoder = Decoder::new(MeasurementMode::T3).unwrap();
+        assert_eq!(decoder.decode(records[0].to_raw()), Some(TttrEvent::photon(7, 1023, DTIME_MAX)));
+        assert_eq!(decoder.decode(records[1].to_raw()), Some(TttrEvent::marker(0b1010, 4)));
+    }
+
+    #[test]
+    fn test_t2_record() {
+        let records = [
+            T2Record::Photon { channel : 7, timetag : (T2_WRAPAROUND - 1) as u32 },
+            T2Record::Sync { timetag : 12 },
+            T2Record::Marker { markers : 0b0110, timetag : 4 },
+            T2Record::Overflow { count : 2 },
+            T2Record::Reserved { channel : 40, timetag : 9 },
+        ];
+        for record in records {
+            assert_eq!(T2Record::from(record.to_raw()), record);
+        }
+        assert!(!records[0].is_special() && records[1].is_special());
+        assert_eq!(records[1].channel(), None);
+        assert_eq!(records[3].timetag(), None);
+
+        let mut decoder = Decoder::new(MeasurementMode::T2).unwrap();
+        assert_eq!(decoder.decode(records[1].to_raw()), Some(TttrEvent::sync(12)));
+        decoder.decode(records[3].to_raw());
+        assert_eq!(decoder.decode(records[2].to_raw()), Some(TttrEvent::marker(0b0110, 2 * T2_WRAPAROUND + 4)));
+    }
+
+    #[test]
+    fn test_t2_round_trip() {
+        let events = [
+            TttrEvent::sync(0),
+            TttrEvent::photon(2, 100, 0),
+            TttrEvent::sync(T2_WRAPAROUND * 3 + 1),
+            TttrEvent::marker(2, T2_WRAPAROUND * 3 + 1),
+        ];
+        let mut encoder = Encoder::new(MeasurementMode::T2).unwrap();
+        let records = encoder.encode_all(&events).unwrap();
+        assert_eq!(records[2], pack_t2(true, OVERFLOW_CHANNEL, 3));
+        assert_eq!(Decoder::new(MeasurementMode::T2).unwrap().decode_all(&records), events);
+
+        assert!(encoder.encode(&TttrEvent::photon(0, 5, 0), &mut Vec::new()).is_err());
+        assert!(Encoder::new(MeasurementMode::T3).unwrap().encode(&TttrEvent::sync(0), &mut Vec::new()).is_err());
+        assert!(Encoder::new(MeasurementMode::Histogramming).is_err());
+    }
+
+    #[test]
+    fn test_overflow_compression() {
+        // Before MHLib 3.1 the low bits of an overflow record aren't a count
+        let records = [pack_t3(true, OVERFLOW_CHANNEL, 0, 5), pack_t3(false, 0, 10, 3)];
+        assert_eq!(
+            Decoder::new(MeasurementMode::T3).unwrap().decode_all(&records),
+            vec![TttrEvent::photon(0, 5 * T3_WRAPAROUND + 3, 10)]
+        );
+        let info = StreamInfo { overflow_compression : OverflowCompression::Single, ..stream_info(MeasurementMode::T3) };
+        assert_eq!(
+            Decoder::for_stream(&info).unwrap().decode_all(&records),
+            vec![TttrEvent::photon(0, T3_WRAPAROUND + 3, 10)]
+        );
+
+        let events = [TttrEvent::sync(4 * T2_WRAPAROUND + 2)];
+        let records = Encoder::new(MeasurementMode::T2).unwrap()
+            .with_overflow_compression(OverflowCompression::Single)
+            .encode_all(&events).unwrap();
+        assert_eq!(records.len(), 5);
+        let mut decoder = Decoder::new(MeasurementMode::T2).unwrap()
+            .with_overflow_compression(OverflowCompression::Single);
+        assert_eq!(decoder.decode_all(&records), events);
+
+        assert_eq!(OverflowCompression::for_library_version("3.1.0.2"), Some(OverflowCompression::Compressed));
+        assert_eq!(OverflowCompression::for_library_version("4.0"), Some(OverflowCompression::Compressed));
+        assert_eq!(OverflowCompression::for_library_version("2.0"), Some(OverflowCompression::Single));
+        assert_eq!(OverflowCompression::for_library_version("3"), None);
+    }
+
+    #[test]
+    fn test_channel_offsets() {
+        let mut offsets = ChannelOffsets::new(5.0);
+        offsets.set(0, -12.0).set(2, 40_007.0);
+        let offsets = offsets.with_sync_period(12_500.0);
+        let mut decoder = Decoder::new(MeasurementMode::T3).unwrap().with_channel_offsets(offsets.clone());
+        // -2.4 bins rounds to -2, moving the photon to the previous sync
+        assert_eq!(decoder.decode(pack_t3(false, 0, 1, 7)).unwrap(), TttrEvent::photon(0, 6, 2499));
+        // 8001.4 bins is 3 periods of 2500 bins and 501 bins
+        assert_eq!(decoder.decode(pack_t3(false, 2, 10, 7)).unwrap(), TttrEvent::photon(2, 10, 511));
+        assert_eq!(decoder.decode(pack_t3(false, 1, 10, 7)).unwrap(), TttrEvent::photon(1, 7, 10));
+
+        // Dithering gets the fraction right on average
+        let mut offsets = ChannelOffsets::new(5.0);
+        offsets.set(0, 1.0);
+        let mut decoder = Decoder::new(MeasurementMode::T2).unwrap().with_channel_offsets(offsets.with_dither(42));
+        let shifted = (0..10_000).map(|_| decoder.decode(pack_t2(false, 0, 100)).unwrap().time - 100).sum::<u64>();
+        assert!((1800..2200).contains(&shifted), "{}", shifted);
+    }
+
+    #[test]
+    fn test_enabled_channels() {
+        let mut decoder = Decoder::new(MeasurementMode::T3).unwrap().with_enabled_channels(&[true, false, true]);
+        let records = [
+            pack_t3(false, 0, 10, 1),
+            pack_t3(false, 1, 10, 1),
+            pack_t3(true, 1, 0, 2),
+            pack_t3(false, 5, 10, 3),
+            pack_t3(false, 2, 10, 3),
+        ];
+        assert_eq!(decoder.decode_all(&records), vec![
+            TttrEvent::photon(0, 1, 10), TttrEvent::marker(1, 2), TttrEvent::photon(2, 3, 10),
+        ]);
+        assert_eq!(decoder.anomalies(), 2);
+        decoder.reset();
+        assert_eq!(decoder.anomalies(), 0);
+    }
+
+    #[test]
+    fn test_event_columns() {
+        assert_eq!(std::mem::size_of::<TttrEvent>(), 16);
+        let events = vec![
+            TttrEvent::photon(1, 7, 300),
+            TttrEvent::sync(8),
+            TttrEvent::marker(4, 9),
+        ];
+        let mut columns = EventColumns::from_events(&events);
+        assert_eq!(columns.len(), 3);
+        assert_eq!(columns.kinds, vec![EventKind::Photon, EventKind::Sync, EventKind::Marker]);
+        assert_eq!(columns.time, vec![7, 8, 9]);
+        assert_eq!(columns.to_events(), events);
+        assert_eq!(events.iter().copied().collect::<EventColumns>(), columns);
+        assert_eq!(columns.get(3), None);
+        assert_eq!(columns.times_ps(MeasurementMode::T3, 5.0, 1000.0), vec![8500, 8000, 9000]);
+        assert_eq!(columns.times_ps(MeasurementMode::T2, 5.0, 0.0), vec![35, 40, 45]);
+
+        columns.clear();
+        assert!(columns.is_empty());
+        let mut decoder = Decoder::new(MeasurementMode::T2).unwrap();
+        let records = [pack_t2(false, 0, 10), pack_t2(true, OVERFLOW_CHANNEL, 0), pack_t2(true, 0, 5)];
+        decoder.decode_into(&records, &mut columns);
+        assert_eq!(columns.to_events(), vec![TttrEvent::photon(0, 10, 0), TttrEvent::sync(T2_WRAPAROUND + 5)]);
+    }
+
+    #[test]
+    fn test_sync_divider() {
+        // 8 pulses of 12.5 ns per counted sync, 100 ps bins
+        let divider = SyncDivider::new(8, 12_500.0, 100.0).with_ambiguity_window(200.0);
+        assert_eq!(divider.pulse_period_bins(), 125.0);
+        assert_eq!(divider.pulse(3, 10), DividedPulse { pulse : 24, dtime : 10, ambiguous : false });
+        assert_eq!(divider.pulse(3, 7 * 125 + 60).pulse, 31);
+        // Just before and just after a pulse
+        assert!(divider.pulse(3, 124).ambiguous);
+        assert!(divider.pulse(3, 251).ambiguous);
+        // Past the 8th pulse, the next counted sync went missing
+        assert!(divider.pulse(3, 8 * 125 + 60).ambiguous);
+
+        let mut decoder = Decoder::new(MeasurementMode::T3).unwrap()
+            .with_sync_divider(divider);
+        let records = [
+            pack_t3(true, OVERFLOW_CHANNEL, 0, 0),
+            pack_t3(false, 1, 2 * 125 + 30, 5),
+            pack_t3(true, 2, 0, 5),
+            pack_t3(false, 0, 1, 6),
+        ];
+        assert_eq!(decoder.decode_all(&records), vec![
+            TttrEvent::photon(1, (T3_WRAPAROUND + 5) * 8 + 2, 30),
+            TttrEvent::marker(2, (T3_WRAPAROUND + 5) * 8),
+            TttrEvent::photon(0, (T3_WRAPAROUND + 6) * 8, 1),
+        ]);
+        assert_eq!(decoder.ambiguous(), 1);
+        decoder.reset();
+        assert_eq!(decoder.ambiguous(), 0);
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn decoder_matches_absolute_nsync(stream in crate::records::t3_adversarial_stream(4, 256)) {
+            let mut decoder = Decoder::new(MeasurementMode::T3).unwrap();
+            let expected = crate::records::t3_absolute_nsync(&stream);
+            for (spec, nsync) in stream.iter().zip(expected) {
+                let event = decoder.decode(spec.to_raw());
+                if let (Some(event), Some(nsync)) = (event, nsync) {
+                    proptest::prop_assert_eq!(event.time, nsync);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,375 @@
+//! A device whose type records the mode it was initialized in, so that
+//! histogram calls can't be made on a device reading TTTR records and the
+//! records of a T2 device aren't decoded as T3. `open_histogramming`,
+//! `open_t2` and `open_t3` find, open and initialize a device in one call.
+//!
+//! The states of a device's life are types as well: an `Opened` device
+//! is initialized into a `MultiHarp<M>`, which `start`s a `Measuring`
+//! device. Only a `Measuring` device reads records, and it only lends
+//! the device out immutably, so settings can't be changed mid-measurement
+//! (which `MHLib` rejects with an unhelpful error code):
+//!
+//! ```compile_fail
+//! use multi_harp_patina::*;
+//!
+//! let mh = open_t3(None).unwrap();
+//! let mut measuring = mh.start(100).map_err(|(_, e)| e).unwrap();
+//! measuring.set_binning(2).unwrap();
+//! ```
+
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+
+use crate::builder::{DeviceBuilder, OpenError};
+use crate::error::{CheckedResult, MultiHarpError, PatinaError};
+use crate::histogram::{acquire_histogram, Histogram};
+use crate::mhconsts::{MeasurementMode, ReferenceClock};
+use crate::multiharp::MultiHarpDevice;
+use crate::tttr::{T2Record, T3Record};
+use crate::MultiHarpConfig;
+
+/// The device the `open_*` functions return
+#[cfg(feature = "MHLib")]
+pub type DefaultDevice = crate::multiharp::MultiHarp150;
+/// The device the `open_*` functions return
+#[cfg(feature = "nolib")]
+pub type DefaultDevice = crate::testing::debug_multiharp::DebugMultiHarp150;
+
+/// A measurement mode as a type
+pub trait Mode {
+    const MODE : MeasurementMode;
+}
+
+/// A mode whose FIFO holds TTTR records
+pub trait TttrMode : Mode {
+    type Record : From<u32>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Histogramming;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct T2;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct T3;
+
+impl Mode for Histogramming { const MODE : MeasurementMode = MeasurementMode::Histogramming; }
+impl Mode for T2 { const MODE : MeasurementMode = MeasurementMode::T2; }
+impl Mode for T3 { const MODE : MeasurementMode = MeasurementMode::T3; }
+
+impl TttrMode for T2 { type Record = T2Record; }
+impl TttrMode for T3 { type Record = T3Record; }
+
+/// A device initialized in mode `M`. Dereferences to the device for
+/// everything that isn't specific to a mode.
+///
+/// Calling `init` through it changes the mode without changing the type;
+/// open the device again with the other mode instead.
+///
+/// ## Example
+///
+/// ```
+/// use multi_harp_patina::*;
+///
+/// let mh = MultiHarp::<T2, DebugMultiHarp150>::open(Some("1044272")).unwrap();
+/// assert_eq!(mh.get_measurement_mode(), MeasurementMode::T2);
+///
+/// // Only a device in T3 mode takes `MultiHarp<T3>`
+/// let device = mh.into_inner();
+/// let device = match MultiHarp::<T3, _>::from_device(device) {
+///     Err(device) => device,
+///     Ok(_) => panic!("The device is in T2 mode"),
+/// };
+/// assert!(MultiHarp::<T2, _>::from_device(device).is_ok());
+/// ```
+pub struct MultiHarp<M : Mode, MH : MultiHarpDevice = DefaultDevice> {
+    device : MH,
+    mode : PhantomData<M>,
+}
+
+impl<M : Mode, MH : MultiHarpDevice> MultiHarp<M, MH> {
+    /// Opens the device with serial number `serial`, or the first
+    /// available device, and initializes it in mode `M` with the internal
+    /// clock.
+    pub fn open(serial : Option<&str>) -> Result<Self, OpenError> {
+        Self::open_with(serial, ReferenceClock::Internal, None)
+    }
+
+    /// `open`, with the reference clock and the settings to apply after
+    /// `init`, as `DeviceBuilder::config`
+    pub fn open_with(
+        serial : Option<&str>,
+        clock : ReferenceClock,
+        config : Option<MultiHarpConfig>,
+    ) -> Result<Self, OpenError> {
+        let mut builder = DeviceBuilder::<MH>::new().mode(M::MODE).clock(clock);
+        if let Some(serial) = serial {
+            builder = builder.serial(serial);
+        }
+        if let Some(config) = config {
+            builder = builder.config(config);
+        }
+        Ok(MultiHarp { device : builder.open()?, mode : PhantomData })
+    }
+
+    /// Wraps a device already initialized in mode `M`, or returns it if
+    /// it is in another mode.
+    pub fn from_device(device : MH) -> Result<Self, MH> {
+        if device.get_measurement_mode() != M::MODE {
+            return Err(device);
+        }
+        Ok(MultiHarp { device, mode : PhantomData })
+    }
+
+    pub fn into_inner(self) -> MH {
+        self.device
+    }
+
+    /// Starts a measurement of `acquisition_time` ms, see
+    /// `start_measurement`. Returns the device with the error if it
+    /// fails to start.
+    pub fn start(mut self, acquisition_time : i32) -> Result<Measuring<M, MH>, (Self, PatinaError<i32>)> {
+        match self.device.start_measurement(acquisition_time) {
+            Ok(()) => Ok(Measuring { device : Some(self) }),
+            Err(e) => Err((self, e)),
+        }
+    }
+}
+
+impl<M : Mode, MH : MultiHarpDevice> Deref for MultiHarp<M, MH> {
+    type Target = MH;
+
+    fn deref(&self) -> &MH {
+        &self.device
+    }
+}
+
+impl<M : Mode, MH : MultiHarpDevice> DerefMut for MultiHarp<M, MH> {
+    fn deref_mut(&mut self) -> &mut MH {
+        &mut self.device
+    }
+}
+
+impl<MH : MultiHarpDevice> MultiHarp<Histogramming, MH> {
+    /// Measures for `acquisition_time` ms and copies the histogram of
+    /// `channel`, see `acquire_histogram`
+    pub fn acquire_histogram(&mut self, channel : i32, acquisition_time : i32) -> CheckedResult<Histogram, i32> {
+        acquire_histogram(&mut self.device, channel, acquisition_time)
+    }
+}
+
+impl<M : TttrMode, MH : MultiHarpDevice> MultiHarp<M, MH> {
+    /// Reads the FIFO into `buffer`, as `read_fifo`, and unpacks the
+    /// records read as records of mode `M`. `Measuring::read_records`
+    /// makes sure a measurement is running.
+    pub fn read_records(&self, buffer : &mut Vec<u32>) -> CheckedResult<Vec<M::Record>, u32> {
+        let read = self.device.read_fifo(buffer)?;
+        Ok(buffer[..read as usize].iter().map(|record| M::Record::from(*record)).collect())
+    }
+}
+
+/// A device opened but not yet initialized in a mode.
+///
+/// ## Example
+///
+/// ```
+/// use multi_harp_patina::*;
+///
+/// let opened = Opened::<DebugMultiHarp150>::open(Some("1044272")).unwrap();
+/// let mut mh = opened.init::<T3>(ReferenceClock::Internal).map_err(|(_, e)| e).unwrap();
+/// mh.set_sync_div(2).unwrap();
+///
+/// let measuring = mh.start(100).map_err(|(_, e)| e).unwrap();
+/// let mut buffer = vec![0u32; TTREADMAX];
+/// while measuring.is_running().unwrap() {
+///     let records = measuring.read_records(&mut buffer).unwrap();
+/// }
+/// let mh = measuring.stop().map_err(|(_, e)| e).unwrap();
+/// ```
+pub struct Opened<MH : MultiHarpDevice = DefaultDevice> {
+    device : MH,
+}
+
+impl<MH : MultiHarpDevice> Opened<MH> {
+    /// Opens the device with serial number `serial`, or the first
+    /// available device.
+    pub fn open(serial : Option<&str>) -> CheckedResult<Self, i32> {
+        let device = match serial {
+            Some(serial) => MH::open_by_serial(serial)?,
+            None => MH::open(None)?,
+        };
+        Ok(Opened { device })
+    }
+
+    /// Initializes the device in mode `M` with `clock`. Returns the
+    /// device with the error if it fails.
+    pub fn init<M : Mode>(mut self, clock : ReferenceClock) -> Result<MultiHarp<M, MH>, (Self, MultiHarpError)> {
+        match self.device.init(M::MODE, clock) {
+            Ok(()) => Ok(MultiHarp { device : self.device, mode : PhantomData }),
+            Err(e) => Err((self, e)),
+        }
+    }
+
+    pub fn into_inner(self) -> MH {
+        self.device
+    }
+}
+
+impl<MH : MultiHarpDevice> Deref for Opened<MH> {
+    type Target = MH;
+
+    fn deref(&self) -> &MH {
+        &self.device
+    }
+}
+
+/// A device in mode `M` with a measurement running. It dereferences to
+/// the device immutably only, so nothing can be set until `stop` returns
+/// the `MultiHarp<M>`. Dropping it stops the measurement.
+pub struct Measuring<M : Mode, MH : MultiHarpDevice = DefaultDevice> {
+    /// Only taken by `stop`
+    device : Option<MultiHarp<M, MH>>,
+}
+
+impl<M : Mode, MH : MultiHarpDevice> Measuring<M, MH> {
+    fn multiharp(&self) -> &MultiHarp<M, MH> {
+        self.device.as_ref().expect("Only `stop` takes the device")
+    }
+
+    /// Whether the measurement is still running, see `ctc_status`
+    pub fn is_running(&self) -> Result<bool, MultiHarpError> {
+        self.multiharp().ctc_status()
+    }
+
+    /// Stops the measurement and returns the device, with the error if
+    /// it fails to stop.
+    pub fn stop(mut self) -> Result<MultiHarp<M, MH>, (MultiHarp<M, MH>, MultiHarpError)> {
+        let mut multiharp = self.device.take().expect("Only `stop` takes the device");
+        match multiharp.device.stop_measurement() {
+            Ok(()) => Ok(multiharp),
+            Err(e) => Err((multiharp, e)),
+        }
+    }
+}
+
+impl<M : TttrMode, MH : MultiHarpDevice> Measuring<M, MH> {
+    /// Reads the FIFO into `buffer` and unpacks the records read as
+    /// records of mode `M`, see `MultiHarp::read_records`.
+    pub fn read_records(&self, buffer : &mut Vec<u32>) -> CheckedResult<Vec<M::Record>, u32> {
+        self.multiharp().read_records(buffer)
+    }
+}
+
+impl<M : Mode, MH : MultiHarpDevice> Deref for Measuring<M, MH> {
+    type Target = MH;
+
+    fn deref(&self) -> &MH {
+        &self.multiharp().device
+    }
+}
+
+impl<M : Mode, MH : MultiHarpDevice> Drop for Measuring<M, MH> {
+    fn drop(&mut self) {
+        if let Some(multiharp) = self.device.as_mut() {
+            let _ = multiharp.device.stop_measurement();
+        }
+    }
+}
+
+/// Finds and opens the device with serial number `serial`, or the first
+/// available device, in Histogramming mode with the internal clock.
+///
+/// ## Example
+///
+/// ```
+/// use multi_harp_patina::*;
+///
+/// let mut mh = open_histogramming(None).unwrap();
+/// mh.set_binning(2).unwrap();
+/// let histogram = mh.acquire_histogram(0, 100).unwrap();
+/// assert_eq!(histogram.channel, 0);
+/// ```
+pub fn open_histogramming(serial : Option<&str>) -> Result<MultiHarp<Histogramming>, OpenError> {
+    MultiHarp::open(serial)
+}
+
+/// Finds and opens the device with serial number `serial`, or the first
+/// available device, in T2 mode with the internal clock.
+pub fn open_t2(serial : Option<&str>) -> Result<MultiHarp<T2>, OpenError> {
+    MultiHarp::open(serial)
+}
+
+/// Finds and opens the device with serial number `serial`, or the first
+/// available device, in T3 mode with the internal clock.
+///
+/// ## Example
+///
+/// ```
+/// use multi_harp_patina::*;
+///
+/// let mut mh = open_t3(Some("1044272")).unwrap();
+/// mh.start_measurement(100).unwrap();
+/// let mut buffer = vec![0u32; TTREADMAX];
+/// for record in mh.read_records(&mut buffer).unwrap() {
+///     if let T3Record::Photon { channel, dtime, .. } = record {
+///         println!("Channel {} : {}", channel, dtime);
+///     }
+/// }
+/// ```
+pub fn open_t3(serial : Option<&str>) -> Result<MultiHarp<T3>, OpenError> {
+    MultiHarp::open(serial)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::debug_multiharp::DebugMultiHarp150;
+
+    #[test]
+    fn test_typed_open() {
+        let mut mh = MultiHarp::<T3, DebugMultiHarp150>::open_with(
+            Some("1044272"),
+            ReferenceClock::External,
+            Some(MultiHarpConfig { sync_div : Some(2), ..Default::default() }),
+        ).unwrap();
+        assert_eq!(mh.get_measurement_mode(), MeasurementMode::T3);
+        assert_eq!(mh.get_serial(), "1044272");
+        assert_eq!(mh.current_settings().sync_div, Some(2));
+
+        let mut buffer = vec![0u32; crate::mhconsts::TTREADMAX];
+        mh.start_measurement(100).unwrap();
+        let records = mh.read_records(&mut buffer).unwrap();
+        assert!(records.iter().all(|record| !matches!(record, T3Record::Reserved { .. })));
+
+        let opened = MultiHarp::<T2, DebugMultiHarp150>::open_with(
+            None,
+            ReferenceClock::Internal,
+            Some(MultiHarpConfig { binning : Some(1000), ..Default::default() }),
+        );
+        assert!(matches!(opened, Err(OpenError::Configure(_))));
+    }
+
+    #[test]
+    fn test_measurement_states() {
+        let opened = Opened::<DebugMultiHarp150>::open(None).unwrap();
+        let mut mh = opened.init::<T2>(ReferenceClock::Internal).map_err(|(_, e)| e).unwrap();
+        mh.set_sync_div(2).unwrap();
+
+        // Refused by the device, it comes back to try again
+        let (mh, e) = mh.start(-1).err().unwrap();
+        assert!(matches!(e, PatinaError::ArgumentError(..)));
+
+        let measuring = mh.start(50).map_err(|(_, e)| e).unwrap();
+        assert!(measuring.is_running().unwrap());
+        assert_eq!(measuring.current_settings().sync_div, Some(2));
+        let mut buffer = vec![0u32; crate::mhconsts::TTREADMAX];
+        while measuring.is_running().unwrap() {
+            measuring.read_records(&mut buffer).unwrap();
+        }
+        let mh = measuring.stop().map_err(|(_, e)| e).unwrap();
+        assert!(!mh.ctc_status().unwrap());
+
+        // Dropped mid-measurement, it is stopped
+        let measuring = mh.start(10_000).map_err(|(_, e)| e).unwrap();
+        drop(measuring);
+    }
+}
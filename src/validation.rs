@@ -0,0 +1,170 @@
+//! How setters treat arguments outside the ranges in `mhconsts`: by
+//! default they fail with `PatinaError::ArgumentError`, but with
+//! `ValidationPolicy::Clamp` they apply the nearest value in range and
+//! report an `ArgumentClamped` warning, which suits interactive tuning
+//! better than hard failures.
+//!
+//! Channel and device indices are always checked strictly, as there is
+//! no nearest channel to clamp to.
+
+use std::fmt::{self, Debug, Display};
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Mutex;
+
+use crate::error::{CheckedResult, PatinaError};
+use crate::events::{self, DeviceEventKind};
+
+/// What setters do with an out of range argument.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValidationPolicy {
+    /// Fail with `PatinaError::ArgumentError`
+    Strict = 0,
+    /// Apply the nearest value in range and report an `ArgumentClamped`
+    Clamp = 1,
+}
+
+static POLICY : AtomicU8 = AtomicU8::new(ValidationPolicy::Strict as u8);
+
+type ClampSink = Box<dyn FnMut(&ArgumentClamped) + Send>;
+static SINK : Mutex<Option<ClampSink>> = Mutex::new(None);
+
+/// Sets how setters of every device treat out of range arguments from
+/// now on. Defaults to `ValidationPolicy::Strict`.
+pub fn set_validation_policy(policy : ValidationPolicy) {
+    POLICY.store(policy as u8, Ordering::Relaxed);
+}
+
+/// The policy set by `set_validation_policy`.
+pub fn validation_policy() -> ValidationPolicy {
+    match POLICY.load(Ordering::Relaxed) {
+        1 => ValidationPolicy::Clamp,
+        _ => ValidationPolicy::Strict,
+    }
+}
+
+/// An argument that was clamped into range under `ValidationPolicy::Clamp`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArgumentClamped {
+    pub serial : String,
+    /// The argument's name, as in the `ArgumentError` it would have raised
+    pub argument : String,
+    pub requested : i64,
+    pub applied : i64,
+}
+
+impl Display for ArgumentClamped {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} of {} clamped from {} to {}", self.argument, self.serial, self.requested, self.applied)
+    }
+}
+
+/// Sends every `ArgumentClamped` to `sink`, replacing the previous sink.
+/// Without a sink the warnings are printed on stderr. `sink` is called
+/// on the thread calling the setter.
+///
+/// ## Example
+///
+/// ```
+/// use std::sync::{Arc, Mutex};
+/// use multi_harp_patina::*;
+///
+/// let clamped = Arc::new(Mutex::new(Vec::new()));
+/// let log = Arc::clone(&clamped);
+/// set_clamp_warnings(move |warning| log.lock().unwrap().push(warning.clone()));
+/// set_validation_policy(ValidationPolicy::Clamp);
+///
+/// let mut mh = DebugMultiHarp150::default();
+/// mh.init(MeasurementMode::T3, ReferenceClock::Internal).unwrap();
+/// mh.set_sync_div(100).unwrap();
+/// set_validation_policy(ValidationPolicy::Strict);
+/// clear_clamp_warnings();
+///
+/// assert!(clamped.lock().unwrap().iter()
+///     .any(|w| w.argument == "sync_div" && w.requested == 100 && w.applied == 16));
+/// ```
+pub fn set_clamp_warnings<F>(sink : F) where F : FnMut(&ArgumentClamped) + Send + 'static {
+    *SINK.lock().unwrap_or_else(|e| e.into_inner()) = Some(Box::new(sink));
+}
+
+/// Drops the sink set with `set_clamp_warnings`, going back to stderr.
+pub fn clear_clamp_warnings() {
+    SINK.lock().unwrap_or_else(|e| e.into_inner()).take();
+}
+
+/// Checks the argument `name` of the device `serial` against `range`
+/// under the current `ValidationPolicy`, returning the value to apply.
+/// `what` begins the message of the `ArgumentError`, e.g. "Sync divider".
+pub(crate) fn validate<T>(
+    serial : &str,
+    name : &str,
+    value : T,
+    range : RangeInclusive<T>,
+    what : &str,
+) -> CheckedResult<T, T> where T : PartialOrd + Copy + Display + Debug + Into<i64> {
+    validate_with(validation_policy(), serial, name, value, range, what)
+}
+
+fn validate_with<T>(
+    policy : ValidationPolicy,
+    serial : &str,
+    name : &str,
+    value : T,
+    range : RangeInclusive<T>,
+    what : &str,
+) -> CheckedResult<T, T> where T : PartialOrd + Copy + Display + Debug + Into<i64> {
+    if range.contains(&value) {
+        return Ok(value);
+    }
+    if policy == ValidationPolicy::Strict {
+        events::record(serial, DeviceEventKind::Error, format!("{} = {} out of range {:?}", name, value, range));
+        return Err(PatinaError::ArgumentError(
+            name.to_string(),
+            value,
+            format!("{} must be between {} and {}", what, range.start(), range.end()))
+        );
+    }
+
+    let applied = if value < *range.start() { *range.start() } else { *range.end() };
+    let warning = ArgumentClamped {
+        serial : serial.to_string(),
+        argument : name.to_string(),
+        requested : value.into(),
+        applied : applied.into(),
+    };
+    events::record(serial, DeviceEventKind::Warning, warning.to_string());
+    match SINK.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        Some(sink) => sink(&warning),
+        None => eprintln!("Warning: {}", warning),
+    }
+    Ok(applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        // The policy is process-wide, so leave it alone while other
+        // tests rely on strict checks
+        let strict = |value| validate_with(ValidationPolicy::Strict, "1044272", "sync_div", value, 1..=16, "Sync divider");
+        assert_eq!(validation_policy(), ValidationPolicy::Strict);
+        assert_eq!(strict(4).unwrap(), 4);
+        match strict(0) {
+            Err(PatinaError::ArgumentError(name, 0, message)) => {
+                assert_eq!(name, "sync_div");
+                assert_eq!(message, "Sync divider must be between 1 and 16");
+            },
+            other => panic!("Expected an argument error, got {:?}", other),
+        }
+
+        let clamp = ValidationPolicy::Clamp;
+        assert_eq!(validate_with(clamp, "1044272", "sync_div", 0, 1..=16, "Sync divider").unwrap(), 1);
+        assert_eq!(validate_with(clamp, "1044272", "stopcount", 0u32, 1..=u32::MAX, "Stop count").unwrap(), 1);
+        assert_eq!(validate_with(clamp, "1044272", "level", 5000, -1200..=1200, "Level").unwrap(), 1200);
+
+        let warning = ArgumentClamped { serial : "1044272".to_string(), argument : "level".to_string(), requested : 5000, applied : 1200 };
+        assert_eq!(warning.to_string(), "level of 1044272 clamped from 5000 to 1200");
+    }
+}
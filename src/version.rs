@@ -0,0 +1,184 @@
+//! Checks the installed `MHLib` and the device hardware against
+//! the versions this crate was built for. `MultiHarp150::open` runs
+//! the check with the process-wide strictness set by
+//! `set_version_strictness`.
+
+use std::fmt::Display;
+use std::sync::atomic::{AtomicU8, Ordering};
+#[cfg(test)]
+use std::sync::{Mutex, MutexGuard};
+
+/// `MHLib` version (major.minor) the crate was compiled against,
+/// selected by the `MHLv*` features.
+#[cfg(feature = "MHLv3_1_0")]
+pub const BUILT_FOR_LIBRARY_VERSION : &str = "3.1";
+#[cfg(all(feature = "MHLv3_0_0", not(feature = "MHLv3_1_0")))]
+pub const BUILT_FOR_LIBRARY_VERSION : &str = "3.0";
+#[cfg(not(feature = "MHLv3_0_0"))]
+pub const BUILT_FOR_LIBRARY_VERSION : &str = "2.0";
+
+/// Oldest device hardware version (the version reported by
+/// `get_hardware_info`) the crate has been used with.
+pub const MIN_HARDWARE_VERSION : &str = "1.0";
+
+/// Which version was out of line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VersionComponent {
+    Library,
+    /// The hardware version from `get_hardware_info`
+    Hardware,
+}
+
+/// A version that differs from what the crate expects.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionMismatch {
+    pub component : VersionComponent,
+    /// Version (or, for the hardware, minimum version) expected
+    pub expected : String,
+    pub found : String,
+}
+
+impl Display for VersionMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.component {
+            VersionComponent::Library => write!(
+                f, "MHLib version {} does not match the version {} this crate was built for",
+                self.found, self.expected
+            ),
+            VersionComponent::Hardware => write!(
+                f, "Hardware version {} is older than the minimum supported version {}",
+                self.found, self.expected
+            ),
+        }
+    }
+}
+
+/// What to do about a `VersionMismatch` when opening a device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VersionStrictness {
+    /// Don't check
+    Ignore = 0,
+    /// Open anyway, recording the mismatch (see `MultiHarpDevice::version_mismatches`)
+    Warn = 1,
+    /// Fail to open with `PatinaError::VersionMismatch`
+    Error = 2,
+}
+
+static STRICTNESS : AtomicU8 = AtomicU8::new(VersionStrictness::Warn as u8);
+
+/// Sets how devices opened from now on treat version mismatches.
+/// Defaults to `VersionStrictness::Warn`.
+pub fn set_version_strictness(strictness : VersionStrictness) {
+    STRICTNESS.store(strictness as u8, Ordering::Relaxed);
+}
+
+/// The strictness set by `set_version_strictness`.
+pub fn version_strictness() -> VersionStrictness {
+    match STRICTNESS.load(Ordering::Relaxed) {
+        0 => VersionStrictness::Ignore,
+        2 => VersionStrictness::Error,
+        _ => VersionStrictness::Warn,
+    }
+}
+
+/// Serializes the tests that set the strictness, or open devices that
+/// check it
+#[cfg(test)]
+static STRICTNESS_LOCK : Mutex<()> = Mutex::new(());
+
+/// Holds `STRICTNESS_LOCK` and restores the strictness it was taken
+/// with when dropped.
+#[cfg(test)]
+pub(crate) struct StrictnessGuard {
+    previous : VersionStrictness,
+    _lock : MutexGuard<'static, ()>,
+}
+
+#[cfg(test)]
+impl StrictnessGuard {
+    pub(crate) fn lock() -> Self {
+        let _lock = STRICTNESS_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        StrictnessGuard { previous : version_strictness(), _lock }
+    }
+}
+
+#[cfg(test)]
+impl Drop for StrictnessGuard {
+    fn drop(&mut self) {
+        set_version_strictness(self.previous);
+    }
+}
+
+/// Splits a dotted version into its numeric components, ignoring
+/// anything after the first non-numeric component.
+pub(crate) fn parse_version(version : &str) -> Vec<u32> {
+    version.trim()
+        .split('.')
+        .map_while(|part| part.trim().parse::<u32>().ok())
+        .collect()
+}
+
+/// Compares the `MHLib` version reported by `get_library_version`
+/// and the hardware version from `get_hardware_info` to those the
+/// crate was built for. The library must match in major and minor
+/// version, the hardware must be at least `MIN_HARDWARE_VERSION`.
+///
+/// ## Example
+///
+/// ```
+/// use multi_harp_patina::*;
+///
+/// let mismatches = check_versions(BUILT_FOR_LIBRARY_VERSION, "9.9");
+/// assert!(mismatches.is_empty());
+/// ```
+pub fn check_versions(library : &str, hardware : &str) -> Vec<VersionMismatch> {
+    let mut mismatches = Vec::new();
+
+    let found_lib = parse_version(library);
+    let expected_lib = parse_version(BUILT_FOR_LIBRARY_VERSION);
+    if found_lib.len() < 2 || found_lib[..2] != expected_lib[..2] {
+        mismatches.push(VersionMismatch {
+            component : VersionComponent::Library,
+            expected : BUILT_FOR_LIBRARY_VERSION.to_string(),
+            found : library.trim().to_string(),
+        });
+    }
+
+    if parse_version(hardware) < parse_version(MIN_HARDWARE_VERSION) {
+        mismatches.push(VersionMismatch {
+            component : VersionComponent::Hardware,
+            expected : MIN_HARDWARE_VERSION.to_string(),
+            found : hardware.trim().to_string(),
+        });
+    }
+
+    mismatches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_versions() {
+        assert!(check_versions(BUILT_FOR_LIBRARY_VERSION, MIN_HARDWARE_VERSION).is_empty());
+        assert!(check_versions(&format!("{}.2", BUILT_FOR_LIBRARY_VERSION), "1.2").is_empty());
+
+        let mismatches = check_versions("1.0", "0.9");
+        assert_eq!(mismatches.len(), 2);
+        assert_eq!(mismatches[0].component, VersionComponent::Library);
+        assert_eq!(mismatches[1].found, "0.9");
+
+        // Garbage from the library is a mismatch, not a panic
+        assert_eq!(check_versions("", "abc").len(), 2);
+    }
+
+    #[test]
+    fn test_strictness_round_trip() {
+        let _guard = StrictnessGuard::lock();
+        for strictness in [VersionStrictness::Error, VersionStrictness::Ignore, VersionStrictness::Warn] {
+            set_version_strictness(strictness);
+            assert_eq!(version_strictness(), strictness);
+        }
+    }
+}
@@ -0,0 +1,532 @@
+//! A handle on the White Rabbit (WR) core of a MultiHarp, from
+//! `MultiHarpDevice::white_rabbit`.
+//!
+//! The `wrabbit_` methods of `MultiHarpDevice` map one to one onto
+//! `MHLib` and leave the order of the calls to the caller. `WhiteRabbit`
+//! puts them in order: the MAC address and init script are stored on the
+//! device first, then `start` sets the mode (reinitializing the core) and
+//! only then switches the link on, and `wait_for_lock` polls the status
+//! until the core is locked and calibrated.
+
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::error::{CheckedResult, PatinaError};
+use crate::mhconsts::{self, WRMode};
+use crate::multiharp::MultiHarpDevice;
+
+/// How often `WhiteRabbit::wait_for_lock` reads the status
+pub const WR_POLL_INTERVAL : Duration = Duration::from_millis(10);
+
+/// How often a `WrTerminal` reads the terminal output while waiting
+/// for a line
+pub const WR_TERM_POLL_INTERVAL : Duration = Duration::from_millis(100);
+
+/// The PTP state of the White Rabbit core, from `WR_STATUS_PTP_BITMASK`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WrPtpState {
+    /// No PTP state reported, e.g. with the link off
+    Idle,
+    Listening,
+    /// `WR_STATUS_PTP_UNCLWRSLCK` -- uncalibrated, locking as a WR slave
+    Uncalibrated,
+    Slave,
+    /// `WR_STATUS_PTP_MSTRWRMLCK` -- locking as a WR master
+    MasterLocking,
+    Master,
+    /// A value without a constant in `mhconsts`
+    Other(i32),
+}
+
+impl WrPtpState {
+    fn from_bits(bits : i32) -> Self {
+        match bits & mhconsts::WR_STATUS_PTP_BITMASK {
+            0 => WrPtpState::Idle,
+            mhconsts::WR_STATUS_PTP_LISTENING => WrPtpState::Listening,
+            mhconsts::WR_STATUS_PTP_UNCLWRSLCK => WrPtpState::Uncalibrated,
+            mhconsts::WR_STATUS_PTP_SLAVE => WrPtpState::Slave,
+            mhconsts::WR_STATUS_PTP_MSTRWRMLCK => WrPtpState::MasterLocking,
+            mhconsts::WR_STATUS_PTP_MASTER => WrPtpState::Master,
+            other => WrPtpState::Other(other),
+        }
+    }
+}
+
+impl Display for WrPtpState {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            WrPtpState::Idle => f.write_str("idle"),
+            WrPtpState::Listening => f.write_str("listening"),
+            WrPtpState::Uncalibrated => f.write_str("uncalibrated"),
+            WrPtpState::Slave => f.write_str("slave"),
+            WrPtpState::MasterLocking => f.write_str("master locking"),
+            WrPtpState::Master => f.write_str("master"),
+            WrPtpState::Other(bits) => write!(f, "unknown ({:#x})", bits),
+        }
+    }
+}
+
+/// The servo state of the White Rabbit core, from `WR_STATUS_SERVO_BITMASK`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WrServoState {
+    /// No servo state reported, e.g. on a master
+    Idle,
+    Uninitialized,
+    /// Synchronizing the seconds
+    SyncSec,
+    /// Synchronizing the nanoseconds
+    SyncNsec,
+    /// Synchronizing the phase
+    SyncPhase,
+    /// Waiting for the offset to settle
+    WaitOffset,
+    /// Tracking the master's phase, as when locked
+    TrackPhase,
+    /// A value without a constant in `mhconsts`
+    Other(i32),
+}
+
+impl WrServoState {
+    fn from_bits(bits : i32) -> Self {
+        match bits & mhconsts::WR_STATUS_SERVO_BITMASK {
+            0 => WrServoState::Idle,
+            mhconsts::WR_STATUS_SERVO_UNINITLZD => WrServoState::Uninitialized,
+            mhconsts::WR_STATUS_SERVO_SYNC_SEC => WrServoState::SyncSec,
+            mhconsts::WR_STATUS_SERVO_SYNC_NSEC => WrServoState::SyncNsec,
+            mhconsts::WR_STATUS_SERVO_SYNC_PHASE => WrServoState::SyncPhase,
+            mhconsts::WR_STATUS_SERVO_WAIT_OFFST => WrServoState::WaitOffset,
+            mhconsts::WR_STATUS_SERVO_TRCK_PHASE => WrServoState::TrackPhase,
+            other => WrServoState::Other(other),
+        }
+    }
+}
+
+impl Display for WrServoState {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            WrServoState::Idle => f.write_str("idle"),
+            WrServoState::Uninitialized => f.write_str("uninitialized"),
+            WrServoState::SyncSec => f.write_str("syncing seconds"),
+            WrServoState::SyncNsec => f.write_str("syncing nanoseconds"),
+            WrServoState::SyncPhase => f.write_str("syncing phase"),
+            WrServoState::WaitOffset => f.write_str("waiting for offset"),
+            WrServoState::TrackPhase => f.write_str("tracking phase"),
+            WrServoState::Other(bits) => write!(f, "unknown ({:#x})", bits),
+        }
+    }
+}
+
+/// The status of the White Rabbit core, as returned by
+/// `get_wrabbit_status`.
+///
+/// ## Example
+///
+/// ```
+/// use multi_harp_patina::*;
+///
+/// let status = WrStatus::from_bits(
+///     WR_STATUS_LINK_ON | WR_STATUS_LINK_UP | WR_STATUS_MODE_SLAVE
+///     | WR_STATUS_PTP_SLAVE | WR_STATUS_SERVO_SYNC_PHASE
+/// );
+/// assert_eq!(status.mode, WRMode::Slave);
+/// assert_eq!(status.servo, WrServoState::SyncPhase);
+/// assert!(!status.is_locked());
+/// assert_eq!(status.to_string(), "Slave, link up, PTP slave, servo syncing phase");
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WrStatus {
+    /// The raw bitfield
+    pub bits : i32,
+    pub mode : WRMode,
+    /// `WR_STATUS_LINK_ON` -- the link was switched on
+    pub link_on : bool,
+    /// `WR_STATUS_LINK_UP` -- the link is established
+    pub link_up : bool,
+    pub ptp : WrPtpState,
+    pub servo : WrServoState,
+    /// `WR_STATUS_LOCKED_CALIBD` -- locked and calibrated
+    pub locked : bool,
+    /// `WR_STATUS_MAC_SET` -- a user defined MAC address is in use
+    pub mac_set : bool,
+    /// `WR_STATUS_IS_NEW` -- the status changed since the last read
+    pub is_new : bool,
+}
+
+impl WrStatus {
+    /// Decodes the bitfield of `MH_WRabbitGetStatus`.
+    pub fn from_bits(bits : i32) -> Self {
+        let mode = match bits & mhconsts::WR_STATUS_MODE_BITMASK {
+            mhconsts::WR_STATUS_MODE_SLAVE => WRMode::Slave,
+            mhconsts::WR_STATUS_MODE_MASTER => WRMode::Master,
+            mhconsts::WR_STATUS_MODE_GMASTER => WRMode::Grandmaster,
+            _ => WRMode::Off,
+        };
+        WrStatus {
+            bits,
+            mode,
+            link_on : bits & mhconsts::WR_STATUS_LINK_ON != 0,
+            link_up : bits & mhconsts::WR_STATUS_LINK_UP != 0,
+            ptp : WrPtpState::from_bits(bits),
+            servo : WrServoState::from_bits(bits),
+            locked : bits & mhconsts::WR_STATUS_LOCKED_CALIBD != 0,
+            mac_set : bits & mhconsts::WR_STATUS_MAC_SET != 0,
+            is_new : bits as u32 & mhconsts::WR_STATUS_IS_NEW != 0,
+        }
+    }
+
+    /// Whether the core is locked and calibrated
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+}
+
+/// The mode and link, then the PTP and servo states if not idle, e.g.
+/// "Slave, link up, PTP slave, servo tracking phase, locked".
+impl Display for WrStatus {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        let link = match (self.link_on, self.link_up) {
+            (false, _) => "link off",
+            (true, false) => "link down",
+            (true, true) => "link up",
+        };
+        write!(f, "{}, {}", self.mode, link)?;
+        if self.ptp != WrPtpState::Idle {
+            write!(f, ", PTP {}", self.ptp)?;
+        }
+        if self.servo != WrServoState::Idle {
+            write!(f, ", servo {}", self.servo)?;
+        }
+        if self.locked {
+            f.write_str(", locked")?;
+        }
+        Ok(())
+    }
+}
+
+/// The White Rabbit core of `multiharp`, borrowed from it by
+/// `MultiHarpDevice::white_rabbit`.
+///
+/// ## Example
+///
+/// ```
+/// use multi_harp_patina::*;
+/// use std::time::Duration;
+///
+/// let mut mh = DebugMultiHarp150::default();
+/// mh.set_wrabbit_lock_time(Duration::ZERO);
+///
+/// let mut wr = mh.white_rabbit();
+/// wr.upload_init_script("mode slave\ngui").unwrap();
+/// wr.start(WRMode::Slave).unwrap();
+/// assert!(wr.wait_for_lock(Duration::from_secs(1)).unwrap());
+/// wr.stop().unwrap();
+/// assert!(!wr.status().unwrap().link_on);
+/// ```
+pub struct WhiteRabbit<'a, MH : MultiHarpDevice> {
+    multiharp : &'a mut MH,
+    boot_from_script : bool,
+}
+
+impl<'a, MH : MultiHarpDevice> WhiteRabbit<'a, MH> {
+    /// See `MultiHarpDevice::white_rabbit`.
+    pub fn new(multiharp : &'a mut MH) -> Self {
+        WhiteRabbit { multiharp, boot_from_script : false }
+    }
+
+    /// The MAC address of the core.
+    pub fn mac(&self) -> CheckedResult<String, i32> {
+        self.multiharp.wrabbit_get_mac()
+    }
+
+    /// Stores the MAC address, which must be `WR_MAC_LEN` characters long
+    /// and unique within the network. Set it before `start`.
+    pub fn set_mac(&mut self, mac : &str) -> CheckedResult<(), usize> {
+        self.multiharp.wrabbit_set_mac(mac)
+    }
+
+    /// The init script stored on the device.
+    pub fn init_script(&self) -> CheckedResult<String, i32> {
+        self.multiharp.wrabbit_get_init_script()
+    }
+
+    /// Stores the init script (lines separated by `\n`, shorter than
+    /// `WR_SCRIPT_LEN`) and has `start` boot the core from it.
+    pub fn upload_init_script(&mut self, script : &str) -> CheckedResult<(), i32> {
+        if script.len() >= mhconsts::WR_SCRIPT_LEN {
+            return Err(PatinaError::ArgumentError(
+                "script".to_string(),
+                script.len() as i32,
+                format!("Script must be shorter than {} characters", mhconsts::WR_SCRIPT_LEN))
+            );
+        }
+        self.multiharp.wrabbit_set_init_script(script)?;
+        self.boot_from_script = true;
+        Ok(())
+    }
+
+    /// Whether `start` boots the core from the stored init script. Set by
+    /// `upload_init_script`, or here for a script already on the device.
+    pub fn with_boot_from_script(mut self, boot_from_script : bool) -> Self {
+        self.boot_from_script = boot_from_script;
+        self
+    }
+
+    /// Reinitializes the core in `mode` and then switches the link on,
+    /// restarting the lock. `WRMode::Off` is the same as `stop`.
+    pub fn start(&mut self, mode : WRMode) -> CheckedResult<(), i32> {
+        if mode == WRMode::Off {
+            return self.stop();
+        }
+        self.multiharp.set_wrabbit_mode(self.boot_from_script, true, mode)?;
+        self.multiharp.set_wrabbit_link(true)
+    }
+
+    /// Switches the link off and then the core.
+    pub fn stop(&mut self) -> CheckedResult<(), i32> {
+        self.multiharp.set_wrabbit_link(false)?;
+        self.multiharp.set_wrabbit_mode(self.boot_from_script, true, WRMode::Off)
+    }
+
+    /// The current status of the core.
+    pub fn status(&self) -> CheckedResult<WrStatus, i32> {
+        self.multiharp.get_wrabbit_status()
+    }
+
+    /// Polls the status every `WR_POLL_INTERVAL` until the core is locked
+    /// and calibrated, returning `false` if it isn't within `timeout`
+    /// or if it was never `start`ed.
+    pub fn wait_for_lock(&self, timeout : Duration) -> CheckedResult<bool, i32> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let status = self.status()?;
+            if status.is_locked() {
+                return Ok(true);
+            }
+            if !status.link_on || status.mode == WRMode::Off || Instant::now() >= deadline {
+                return Ok(false);
+            }
+            std::thread::sleep(WR_POLL_INTERVAL.min(deadline.saturating_duration_since(Instant::now())));
+        }
+    }
+
+    /// The UTC time of the core, to 16 ns.
+    pub fn time(&self) -> CheckedResult<SystemTime, i32> {
+        let (high, low, subsec_16_ns) = self.multiharp.get_wrabbit_time()?;
+        let seconds = ((high as u64) << 32) | low as u64;
+        Ok(UNIX_EPOCH + Duration::new(seconds, subsec_16_ns.saturating_mul(16).min(999_999_999)))
+    }
+
+    /// Sets the UTC time of the core, to the second, and of any slave
+    /// connected to it. Only a master or grandmaster can set the time.
+    pub fn set_time(&mut self, time : SystemTime) -> CheckedResult<(), i32> {
+        let mode = self.status()?.mode;
+        if !matches!(mode, WRMode::Master | WRMode::Grandmaster) {
+            return Err(PatinaError::FeatureNotAvailable(
+                format!("Setting the White Rabbit time in mode {}", mode)
+            ));
+        }
+        let seconds = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        self.multiharp.set_wrabbit_time((seconds >> 32) as u32, seconds as u32)
+    }
+
+    /// The terminal output of the core, if the init script ends
+    /// with `gui`.
+    pub fn term_output(&self) -> CheckedResult<String, i32> {
+        self.multiharp.get_wrabbit_term_output()
+    }
+
+    /// The terminal output as lines, see `MultiHarpDevice::wrabbit_terminal`.
+    pub fn terminal(&self) -> WrTerminal<'_, MH> {
+        WrTerminal::new(self.multiharp)
+    }
+}
+
+/// Splits the terminal output of the WR core into lines of plain text.
+/// Escape sequences (colors, cursor movement) are dropped, clearing the
+/// screen or moving the cursor ends the current line, and a lone `\r`
+/// starts it over, as it would be overwritten on a terminal.
+#[derive(Debug, Default)]
+struct TermLines {
+    line : String,
+    /// An escape sequence split across two reads
+    escape : Option<String>,
+}
+
+impl TermLines {
+    /// Adds `output`, returning the lines it completes that aren't blank.
+    fn push(&mut self, output : &str) -> Vec<String> {
+        let mut lines = Vec::new();
+        let mut chars = output.chars().peekable();
+        while let Some(c) = chars.next() {
+            if let Some(escape) = self.escape.as_mut() {
+                escape.push(c);
+                // `ESC [` params and a final byte, or `ESC` and one character
+                let done = match escape.as_bytes() {
+                    [b'['] => false,
+                    [b'[', .., last] => (0x40..=0x7E).contains(last),
+                    _ => true,
+                };
+                if done {
+                    let escape = self.escape.take().unwrap_or_default();
+                    // Cursor positioning and clearing start a new line
+                    if escape.ends_with(['H', 'f', 'J', 'K']) {
+                        self.end_line(&mut lines);
+                    }
+                }
+                continue;
+            }
+            match c {
+                '\u{1b}' => self.escape = Some(String::new()),
+                '\n' => self.end_line(&mut lines),
+                '\r' if chars.peek() == Some(&'\n') => {},
+                '\r' => self.line.clear(),
+                '\t' => self.line.push(c),
+                c if c.is_control() => {},
+                c => self.line.push(c),
+            }
+        }
+        lines
+    }
+
+    fn end_line(&mut self, lines : &mut Vec<String>) {
+        let line = std::mem::take(&mut self.line);
+        if !line.trim().is_empty() {
+            lines.push(line.trim_end().to_string());
+        }
+    }
+}
+
+/// The terminal output of the White Rabbit core, from
+/// `MultiHarpDevice::wrabbit_terminal`, as an endless iterator of lines of
+/// plain text. `next` polls `get_wrabbit_term_output` every
+/// `WR_TERM_POLL_INTERVAL` until a line is complete, so blocks while the
+/// core is quiet; `poll` reads once without waiting.
+///
+/// The core only writes to its terminal once its init script has
+/// ended with `gui`.
+///
+/// ## Example
+///
+/// ```
+/// use multi_harp_patina::*;
+/// use std::time::Duration;
+///
+/// let mut mh = DebugMultiHarp150::default();
+/// mh.set_wrabbit_lock_time(Duration::ZERO);
+/// let mut wr = mh.white_rabbit();
+/// wr.upload_init_script("mode slave\ngui").unwrap();
+/// wr.start(WRMode::Slave).unwrap();
+///
+/// for line in mh.wrabbit_terminal().take(2) {
+///     println!("WR: {}", line.unwrap());
+/// }
+/// ```
+pub struct WrTerminal<'a, MH : MultiHarpDevice> {
+    multiharp : &'a MH,
+    lines : TermLines,
+    pending : VecDeque<String>,
+    poll_interval : Duration,
+}
+
+impl<'a, MH : MultiHarpDevice> WrTerminal<'a, MH> {
+    pub fn new(multiharp : &'a MH) -> Self {
+        WrTerminal {
+            multiharp,
+            lines : TermLines::default(),
+            pending : VecDeque::new(),
+            poll_interval : WR_TERM_POLL_INTERVAL,
+        }
+    }
+
+    /// How long `next` waits between reads (default `WR_TERM_POLL_INTERVAL`)
+    pub fn with_poll_interval(mut self, poll_interval : Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    /// Reads the terminal output once, returning the lines it completes
+    /// along with any not yet taken by `next`.
+    pub fn poll(&mut self) -> CheckedResult<Vec<String>, i32> {
+        let output = self.multiharp.get_wrabbit_term_output()?;
+        self.pending.extend(self.lines.push(&output));
+        Ok(self.pending.drain(..).collect())
+    }
+}
+
+impl<MH : MultiHarpDevice> Iterator for WrTerminal<'_, MH> {
+    type Item = CheckedResult<String, i32>;
+
+    /// The next line, or the error of a failed read. Never `None`.
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(line) = self.pending.pop_front() {
+                return Some(Ok(line));
+            }
+            match self.multiharp.get_wrabbit_term_output() {
+                Ok(output) => self.pending.extend(self.lines.push(&output)),
+                Err(e) => return Some(Err(e)),
+            }
+            if self.pending.is_empty() {
+                std::thread::sleep(self.poll_interval);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DebugMultiHarp150;
+
+    #[test]
+    fn test_white_rabbit() {
+        let mut mh = DebugMultiHarp150::default();
+        mh.set_wrabbit_lock_time(Duration::from_millis(50));
+
+        let mut wr = mh.white_rabbit();
+        assert!(matches!(wr.set_mac("short"), Err(PatinaError::ArgumentError(..))));
+        assert_eq!(wr.mac().unwrap(), crate::DEBUG_WR_MAC);
+        wr.set_mac("PATINA").unwrap();
+        assert_eq!(wr.mac().unwrap(), "PATINA");
+
+        // Never started, so never locks
+        assert!(!wr.wait_for_lock(Duration::from_secs(1)).unwrap());
+        assert!(wr.set_time(UNIX_EPOCH).is_err());
+
+        wr.start(WRMode::Master).unwrap();
+        let status = wr.status().unwrap();
+        assert_eq!((status.mode, status.link_on, status.locked), (WRMode::Master, true, false));
+        assert!(status.mac_set);
+        assert!(!wr.wait_for_lock(Duration::from_millis(5)).unwrap());
+        assert!(wr.wait_for_lock(Duration::from_secs(1)).unwrap());
+        assert_eq!(wr.status().unwrap().to_string(), "Master, link up, PTP master, locked");
+
+        wr.set_time(UNIX_EPOCH + Duration::from_secs(1_000)).unwrap();
+        let elapsed = wr.time().unwrap().duration_since(UNIX_EPOCH).unwrap();
+        assert_eq!(elapsed.as_secs(), 1_000);
+
+        wr.stop().unwrap();
+        assert_eq!(wr.status().unwrap().to_string(), "Off, link off");
+    }
+
+    #[test]
+    fn test_term_lines() {
+        let mut lines = TermLines::default();
+        assert_eq!(lines.push("\x1b[2J\x1b[1;1H\x1b[1;34mWR Monitor\x1b[0m\r\n\r\nmode: "), vec!["WR Monitor"]);
+        // An escape sequence split across reads, and a line overwritten
+        assert_eq!(lines.push("slave\x1b["), Vec::<String>::new());
+        assert_eq!(lines.push("32mtracking\x1b[0m\rmode: slave  \n"), vec!["mode: slave"]);
+        assert_eq!(lines.push("\x1b[Hdone\x1b[K"), vec!["done"]);
+
+        let mut mh = DebugMultiHarp150::default();
+        assert!(mh.wrabbit_terminal().poll().unwrap().is_empty());
+        mh.set_wrabbit_lock_time(Duration::ZERO);
+        let mut wr = mh.white_rabbit();
+        wr.upload_init_script("mode master\ngui").unwrap();
+        wr.start(WRMode::Master).unwrap();
+        let screen = wr.terminal().with_poll_interval(Duration::ZERO)
+            .take(2).collect::<CheckedResult<Vec<_>, i32>>().unwrap();
+        assert!(screen[1].ends_with("Master, link up, PTP master, locked"));
+    }
+}